@@ -0,0 +1,471 @@
+//! Per-user resource usage for chargeback (`agentman admin usage`).
+//!
+//! Shared gateway hosts are paid for by one team and used by many, so admins need to know who
+//! used what. A background sampler adds each user's usage to monthly totals (UTC calendar
+//! months) in `<dir>/<github_user>.json`:
+//!
+//! - CPU seconds, from the containers' cumulative CPU counters. Like the egress ledger, the
+//!   record keeps each workspace's container ID and last counter, so a restarted or recreated
+//!   container is counted from zero again.
+//! - Memory GB-hours: the memory in use at each sample, counted for `interval_secs`.
+//! - Disk GB-days: the workspace's size ([`workspace_size`]) at each sample, counted for
+//!   `interval_secs` whether its sandbox runs or not.
+//! - Egress and ingress bytes, copied from the `[egress]` ledger (zero without it).
+//!
+//! GB are 10^9 bytes. Usage between the last sample and a container's removal isn't counted.
+//! Once a month is over, its report is written to `<dir>/reports/<YYYY-MM>.csv` and `.json`
+//! for billing pipelines to pick up.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use bollard::query_parameters::StatsOptionsBuilder;
+use chrono::{Months, NaiveDate};
+use futures::StreamExt;
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::config::AccountingConfig;
+use crate::docker::ContainerManager;
+use crate::egress;
+use crate::gateway_control::workspace_container_status_with_running;
+use crate::notify;
+use crate::workspace_size;
+
+const GB: f64 = 1e9;
+
+/// How `agentman admin usage` prints a report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Table,
+    Csv,
+    Json,
+}
+
+/// A user's usage in one month.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Usage {
+    pub cpu_seconds: f64,
+    pub memory_gb_hours: f64,
+    pub disk_gb_days: f64,
+    pub egress_bytes: u64,
+    pub ingress_bytes: u64,
+}
+
+/// A user's usage per month, and what the sampler needs to count CPU time.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Record {
+    /// Totals by month (`YYYY-MM`, UTC).
+    pub months: BTreeMap<String, Usage>,
+    /// CPU counters of the last sample, per project.
+    pub workspaces: BTreeMap<String, CpuCounter>,
+}
+
+/// A workspace's container and its cumulative CPU time (ns) at the last sample.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CpuCounter {
+    pub container_id: Option<String>,
+    pub last_ns: u64,
+}
+
+impl CpuCounter {
+    /// Record the raw counter of `container_id`; returns the CPU time (ns) since the previous
+    /// sample.
+    fn record(&mut self, container_id: &str, ns: u64) -> u64 {
+        let same = self.container_id.as_deref() == Some(container_id) && ns >= self.last_ns;
+        let delta = if same { ns - self.last_ns } else { ns };
+        self.container_id = Some(container_id.to_string());
+        self.last_ns = ns;
+        delta
+    }
+}
+
+/// What one sample saw of a workspace.
+struct Observed {
+    project: String,
+    disk_bytes: Option<u64>,
+    /// Container ID, cumulative CPU time (ns) and memory in use, when running.
+    running: Option<(String, u64, u64)>,
+}
+
+impl Record {
+    /// Add one sample, counted for `interval_secs`, to the totals of `month`.
+    fn add(&mut self, month: &str, interval_secs: u64, observed: &[Observed]) {
+        let usage = self.months.entry(month.to_string()).or_default();
+        for ws in observed {
+            if let Some(bytes) = ws.disk_bytes {
+                usage.disk_gb_days += bytes as f64 / GB * interval_secs as f64 / 86400.0;
+            }
+            let Some((ref container_id, cpu_ns, mem_bytes)) = ws.running else {
+                continue;
+            };
+            let counter = self.workspaces.entry(ws.project.clone()).or_default();
+            usage.cpu_seconds += counter.record(container_id, cpu_ns) as f64 / 1e9;
+            usage.memory_gb_hours += mem_bytes as f64 / GB * interval_secs as f64 / 3600.0;
+        }
+        // Projects that are gone don't need their counters.
+        self.workspaces
+            .retain(|project, _| observed.iter().any(|ws| ws.project == *project));
+    }
+
+    /// Forget months before the last `retention_months` up to `month` (0 keeps them all).
+    fn prune(&mut self, month: &str, retention_months: u32) {
+        if retention_months == 0 {
+            return;
+        }
+        if let Some(oldest) = add_months(month, -(retention_months as i32 - 1)) {
+            self.months.retain(|m, _| *m >= oldest);
+        }
+    }
+}
+
+/// `YYYY-MM` if `s` is a month like that.
+pub fn parse_month(s: &str) -> Option<String> {
+    let date = NaiveDate::parse_from_str(&format!("{s}-01"), "%Y-%m-%d").ok()?;
+    Some(date.format("%Y-%m").to_string())
+}
+
+/// The month `n` months after `month` (before it when negative).
+fn add_months(month: &str, n: i32) -> Option<String> {
+    let date = NaiveDate::parse_from_str(&format!("{month}-01"), "%Y-%m-%d").ok()?;
+    let months = Months::new(n.unsigned_abs());
+    let date = if n < 0 {
+        date.checked_sub_months(months)?
+    } else {
+        date.checked_add_months(months)?
+    };
+    Some(date.format("%Y-%m").to_string())
+}
+
+fn record_path(config: &AccountingConfig, github_user: &str) -> PathBuf {
+    config.dir.join(format!("{github_user}.json"))
+}
+
+fn reports_dir(config: &AccountingConfig) -> PathBuf {
+    config.dir.join("reports")
+}
+
+async fn load(config: &AccountingConfig, github_user: &str) -> Result<Record> {
+    let path = record_path(config, github_user);
+    match tokio::fs::read(&path).await {
+        Ok(data) => serde_json::from_slice(&data)
+            .with_context(|| format!("Failed to parse {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Record::default()),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+/// Write `path` through a temporary file, so readers never see half of it.
+async fn write_atomic(path: &std::path::Path, data: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    tokio::fs::write(&partial, data)
+        .await
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    tokio::fs::rename(&partial, path)
+        .await
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Every user's usage in `month`, by user; users without usage that month are left out.
+pub async fn month_usage(config: &AccountingConfig, month: &str) -> Result<Vec<(String, Usage)>> {
+    let mut entries = match tokio::fs::read_dir(&config.dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read {}", config.dir.display()));
+        }
+    };
+    let mut rows = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        let Some(github_user) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        let mut record = load(config, github_user).await?;
+        if let Some(usage) = record.months.remove(month) {
+            rows.push((github_user.to_string(), usage));
+        }
+    }
+    rows.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(rows)
+}
+
+/// The report as CSV, one row per user.
+pub fn to_csv(month: &str, rows: &[(String, Usage)]) -> String {
+    let mut out = String::from(
+        "month,github_user,cpu_seconds,memory_gb_hours,disk_gb_days,egress_bytes,ingress_bytes\n",
+    );
+    for (github_user, u) in rows {
+        out.push_str(&format!(
+            "{month},{github_user},{:.3},{:.3},{:.3},{},{}\n",
+            u.cpu_seconds, u.memory_gb_hours, u.disk_gb_days, u.egress_bytes, u.ingress_bytes
+        ));
+    }
+    out
+}
+
+/// The report as JSON: `{"month": ..., "users": [{"github_user": ..., ...}]}`.
+pub fn to_json(month: &str, rows: &[(String, Usage)]) -> String {
+    #[derive(Serialize)]
+    struct Row<'a> {
+        github_user: &'a str,
+        #[serde(flatten)]
+        usage: &'a Usage,
+    }
+    #[derive(Serialize)]
+    struct Report<'a> {
+        month: &'a str,
+        users: Vec<Row<'a>>,
+    }
+    let report = Report {
+        month,
+        users: rows
+            .iter()
+            .map(|(github_user, usage)| Row { github_user, usage })
+            .collect(),
+    };
+    let mut out = serde_json::to_string_pretty(&report).unwrap_or_default();
+    out.push('\n');
+    out
+}
+
+/// Write the report of the month before `month`, unless it was written already.
+async fn write_previous_report(config: &AccountingConfig, month: &str) -> Result<()> {
+    let Some(previous) = add_months(month, -1) else {
+        return Ok(());
+    };
+    let csv_path = reports_dir(config).join(format!("{previous}.csv"));
+    if tokio::fs::try_exists(&csv_path).await.unwrap_or(false) {
+        return Ok(());
+    }
+    let rows = month_usage(config, &previous).await?;
+    if rows.is_empty() {
+        return Ok(());
+    }
+    let json_path = reports_dir(config).join(format!("{previous}.json"));
+    write_atomic(&json_path, to_json(&previous, &rows).as_bytes()).await?;
+    // The CSV goes last: it marks the report as written.
+    write_atomic(&csv_path, to_csv(&previous, &rows).as_bytes()).await?;
+    info!("Wrote the usage report for {} to {}", previous, csv_path.display());
+    Ok(())
+}
+
+/// Cumulative CPU time (ns) and memory in use of a container.
+async fn cpu_and_memory(
+    container_manager: &ContainerManager,
+    container: &str,
+) -> Option<(u64, u64)> {
+    let mut stream = container_manager.docker().stats(
+        container,
+        Some(
+            StatsOptionsBuilder::new()
+                .stream(false)
+                .one_shot(true)
+                .build(),
+        ),
+    );
+    let stats = tokio::time::timeout(Duration::from_secs(5), stream.next())
+        .await
+        .ok()??
+        .ok()?;
+    let cpu_ns = stats.cpu_stats?.cpu_usage?.total_usage?;
+    let mem_bytes = stats.memory_stats.and_then(|m| m.usage).unwrap_or(0);
+    Some((cpu_ns, mem_bytes))
+}
+
+/// Add one sample of the user's workspaces to their record.
+async fn sample_user(container_manager: &ContainerManager, github_user: &str) -> Result<()> {
+    let gateway_config = container_manager.config();
+    let config = &gateway_config.accounting;
+    let workspaces = container_manager.state().list_workspaces(github_user).await;
+    let observed = join_all(workspaces.iter().map(|ws| async move {
+        let disk_bytes =
+            workspace_size::get(&gateway_config.workspace_size, &ws.host_workspace_path).await;
+        let target = ws.container_id.clone().unwrap_or(ws.container_name.clone());
+        let (_, _, running) =
+            workspace_container_status_with_running(container_manager, &target).await;
+        let running = match (running, &ws.container_id) {
+            (true, Some(id)) => cpu_and_memory(container_manager, &target)
+                .await
+                .map(|(cpu_ns, mem_bytes)| (id.clone(), cpu_ns, mem_bytes)),
+            _ => None,
+        };
+        Observed {
+            project: ws.project.clone(),
+            disk_bytes,
+            running,
+        }
+    }))
+    .await;
+    if observed.is_empty() {
+        return Ok(());
+    }
+
+    let month = egress::current_month();
+    let mut record = load(config, github_user).await?;
+    record.add(&month, config.interval_secs, &observed);
+    if gateway_config.egress.enabled {
+        let ledger = egress::load(&gateway_config.egress, github_user).await?;
+        let usage = record.months.entry(month.clone()).or_default();
+        usage.egress_bytes = ledger.month_tx_bytes;
+        usage.ingress_bytes = ledger.month_rx_bytes;
+    }
+    record.prune(&month, config.retention_months);
+    write_atomic(
+        &record_path(config, github_user),
+        &serde_json::to_vec_pretty(&record)?,
+    )
+    .await
+}
+
+/// Sample every user's workspaces every `interval_secs` (no-op when disabled).
+pub fn spawn_sampler(container_manager: Arc<ContainerManager>) {
+    let config = container_manager.config().accounting.clone();
+    if !config.enabled {
+        return;
+    }
+    info!("Accounting resource usage every {}s", config.interval_secs);
+
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(Duration::from_secs(config.interval_secs));
+        tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            tick.tick().await;
+            let cm = container_manager.as_ref();
+            let mut last_error = None;
+            for github_user in cm.state().list_github_users().await {
+                if let Err(e) = sample_user(cm, &github_user).await {
+                    warn!("Failed to account usage of {}: {:#}", github_user, e);
+                    last_error = Some(e);
+                }
+            }
+            if let Err(e) = write_previous_report(&config, &egress::current_month()).await {
+                warn!("Failed to write the usage report: {:#}", e);
+                last_error = Some(e);
+            }
+            let notify_config = &cm.config().notify;
+            match last_error {
+                Some(e) => {
+                    notify::task_failed(notify_config, "accounting", &format!("{e:#}")).await
+                }
+                None => notify::task_succeeded(notify_config, "accounting").await,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn observed(project: &str, disk_gb: u64, running: Option<(&str, u64, u64)>) -> Observed {
+        Observed {
+            project: project.to_string(),
+            disk_bytes: Some(disk_gb * 1_000_000_000),
+            running: running.map(|(id, cpu_ns, mem)| (id.to_string(), cpu_ns, mem)),
+        }
+    }
+
+    #[test]
+    fn test_record_add() {
+        let mut record = Record::default();
+        // One hour at 2 GB of memory, a 24 GB workspace and a stopped one of 48 GB.
+        let samples = |cpu_ns, id| {
+            vec![
+                observed("api", 24, Some((id, cpu_ns, 2_000_000_000))),
+                observed("old", 48, None),
+            ]
+        };
+        record.add("2025-01", 3600, &samples(5_000_000_000, "c1"));
+        record.add("2025-01", 3600, &samples(7_000_000_000, "c1"));
+        // Recreated: the new container's counter starts from zero.
+        record.add("2025-01", 3600, &samples(1_000_000_000, "c2"));
+        let usage = &record.months["2025-01"];
+        assert_eq!(usage.cpu_seconds, 8.0);
+        assert_eq!(usage.memory_gb_hours, 6.0);
+        assert_eq!(usage.disk_gb_days, 9.0);
+
+        // The counter carries over into the next month.
+        record.add("2025-02", 3600, &[observed("api", 0, Some(("c2", 4_000_000_000, 0)))]);
+        assert_eq!(record.months["2025-02"].cpu_seconds, 3.0);
+        assert_eq!(record.workspaces.keys().collect::<Vec<_>>(), ["api"]);
+
+        record.months.insert("2023-03".to_string(), Usage::default());
+        record.prune("2025-02", 2);
+        assert_eq!(record.months.keys().collect::<Vec<_>>(), ["2025-01", "2025-02"]);
+    }
+
+    #[test]
+    fn test_months() {
+        assert_eq!(parse_month("2025-01").as_deref(), Some("2025-01"));
+        assert_eq!(parse_month("2025-1").as_deref(), Some("2025-01"));
+        assert_eq!(parse_month("2025-13"), None);
+        assert_eq!(parse_month("january"), None);
+        assert_eq!(add_months("2025-01", -1).as_deref(), Some("2024-12"));
+        assert_eq!(add_months("2024-12", 1).as_deref(), Some("2025-01"));
+    }
+
+    #[tokio::test]
+    async fn test_reports() {
+        let dir = std::env::temp_dir().join(format!("agentman-usage-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = AccountingConfig {
+            enabled: true,
+            dir: dir.clone(),
+            ..Default::default()
+        };
+        let usage = Usage {
+            cpu_seconds: 3600.5,
+            memory_gb_hours: 12.0,
+            disk_gb_days: 0.25,
+            egress_bytes: 1_500_000,
+            ingress_bytes: 42,
+        };
+        let users = [("octocat", "2025-01"), ("hubot", "2025-01"), ("ci", "2024-12")];
+        for (github_user, month) in users {
+            let mut record = Record::default();
+            record.months.insert(month.to_string(), usage.clone());
+            write_atomic(&record_path(&config, github_user), &serde_json::to_vec(&record).unwrap())
+                .await
+                .unwrap();
+        }
+
+        let rows = month_usage(&config, "2025-01").await.unwrap();
+        assert_eq!(rows.iter().map(|r| r.0.as_str()).collect::<Vec<_>>(), ["hubot", "octocat"]);
+        assert_eq!(
+            to_csv("2025-01", &rows[..1]),
+            "month,github_user,cpu_seconds,memory_gb_hours,disk_gb_days,egress_bytes,\
+             ingress_bytes\n2025-01,hubot,3600.500,12.000,0.250,1500000,42\n"
+        );
+        let json: serde_json::Value = serde_json::from_str(&to_json("2025-01", &rows)).unwrap();
+        assert_eq!(json["month"], "2025-01");
+        assert_eq!(json["users"][1]["github_user"], "octocat");
+        assert_eq!(json["users"][1]["egress_bytes"], 1_500_000);
+
+        write_previous_report(&config, "2025-02").await.unwrap();
+        let csv = std::fs::read_to_string(dir.join("reports/2025-01.csv")).unwrap();
+        assert_eq!(csv, to_csv("2025-01", &rows));
+        assert!(dir.join("reports/2025-01.json").exists());
+        // A month without usage gets no report.
+        write_previous_report(&config, "2024-12").await.unwrap();
+        assert!(!dir.join("reports/2024-11.csv").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
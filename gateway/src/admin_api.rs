@@ -0,0 +1,453 @@
+//! HTTP admin API (`[admin_api]`).
+//!
+//! The operations admins otherwise reach through `agentman admin` or by editing state, for
+//! scripts and dashboards that have no SSH key:
+//!
+//! - `GET /v1/workspaces` lists every workspace with its container status;
+//! - `GET /v1/workspaces/<user>/<project>` shows one;
+//! - `DELETE /v1/workspaces/<user>/<project>` force-destroys the sandbox and its workspace
+//!   directory (`?keep_workspace=true` keeps the directory), or answers 404 if there is none;
+//! - `DELETE /v1/keys` flushes the GitHub key cache (`?github_user=<user>` only that user's
+//!   keys, along with their guest invites and preview links).
+//!
+//! Requests carry `Authorization: Bearer <token>`. Responses are JSON; changes are audited as
+//! `control` events with `via=admin_api`. Requests are small and answered one per connection,
+//! like the metrics endpoint.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use serde_json::{Value, json};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+use crate::audit::{AuditEvent, AuditEventKind, AuditLogger, AuditOutcome};
+use crate::docker::{ContainerManager, DestroyOptions};
+use crate::gateway_control::workspace_container_status_with_running;
+use crate::github::{validate_github_username, validate_project_name};
+use crate::metrics;
+use crate::secret::constant_time_eq;
+use crate::state::WorkspaceInfo;
+
+/// Upper bound for a request; the API takes no bodies.
+const MAX_REQUEST: usize = 16 * 1024;
+
+/// Time a client gets to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Containers inspected at once when listing, like `agentman list`.
+const LIST_CONCURRENCY: usize = 8;
+
+/// What a request asks for.
+#[derive(Debug, PartialEq, Eq)]
+enum Route {
+    ListWorkspaces,
+    GetWorkspace { github_user: String, project: String },
+    DestroyWorkspace {
+        github_user: String,
+        project: String,
+        keep_workspace: bool,
+    },
+    FlushKeys { github_user: Option<String> },
+}
+
+/// A parsed request line and the headers the API cares about.
+#[derive(Debug)]
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    bearer: Option<String>,
+}
+
+fn parse_request(head: &str) -> Option<Request> {
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next()?.split_whitespace();
+    let method = request_line.next()?.to_string();
+    let target = request_line.next()?;
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (key.to_string(), value.to_string())
+        })
+        .collect();
+    let bearer = lines.find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if !name.trim().eq_ignore_ascii_case("authorization") {
+            return None;
+        }
+        let (scheme, token) = value.trim().split_once(' ')?;
+        scheme
+            .eq_ignore_ascii_case("bearer")
+            .then(|| token.trim().to_string())
+    });
+    Some(Request {
+        method,
+        path: path.to_string(),
+        query,
+        bearer,
+    })
+}
+
+impl Request {
+    fn query(&self, key: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The route, or the HTTP status to answer with.
+    fn route(&self) -> Result<Route, &'static str> {
+        let segments: Vec<&str> = self.path.trim_end_matches('/').split('/').skip(1).collect();
+        let workspace = |github_user: &str, project: &str| {
+            if validate_github_username(github_user).is_err()
+                || validate_project_name(project).is_err()
+            {
+                return Err("404 Not Found");
+            }
+            Ok((github_user.to_string(), project.to_string()))
+        };
+        match (self.method.as_str(), segments.as_slice()) {
+            ("GET", ["v1", "workspaces"]) => Ok(Route::ListWorkspaces),
+            ("GET", ["v1", "workspaces", github_user, project]) => {
+                let (github_user, project) = workspace(github_user, project)?;
+                Ok(Route::GetWorkspace {
+                    github_user,
+                    project,
+                })
+            }
+            ("DELETE", ["v1", "workspaces", github_user, project]) => {
+                let (github_user, project) = workspace(github_user, project)?;
+                Ok(Route::DestroyWorkspace {
+                    github_user,
+                    project,
+                    keep_workspace: self.query("keep_workspace") == Some("true"),
+                })
+            }
+            ("DELETE", ["v1", "keys"]) => match self.query("github_user") {
+                Some(user) if validate_github_username(user).is_err() => Err("400 Bad Request"),
+                user => Ok(Route::FlushKeys {
+                    github_user: user.map(str::to_string),
+                }),
+            },
+            (_, ["v1", "workspaces"] | ["v1", "workspaces", _, _] | ["v1", "keys"]) => {
+                Err("405 Method Not Allowed")
+            }
+            _ => Err("404 Not Found"),
+        }
+    }
+}
+
+/// Whether `given` is the configured token.
+fn authorized(token: &str, given: Option<&str>) -> bool {
+    let Some(given) = given else {
+        return false;
+    };
+    constant_time_eq(token.as_bytes(), given.as_bytes())
+}
+
+/// Start the API if `[admin_api] enabled = true`.
+pub async fn serve(container_manager: Arc<ContainerManager>, audit: Arc<AuditLogger>) -> Result<()> {
+    let config = &container_manager.config().admin_api;
+    if !config.enabled {
+        return Ok(());
+    }
+    let token_file = config
+        .token_file
+        .as_ref()
+        .context("admin_api: token_file is required")?;
+    let token = tokio::fs::read_to_string(token_file)
+        .await
+        .with_context(|| format!("Failed to read {}", token_file.display()))?
+        .trim()
+        .to_string();
+    if token.is_empty() {
+        anyhow::bail!("{} is empty", token_file.display());
+    }
+    let listener = TcpListener::bind(&config.listen_addr)
+        .await
+        .with_context(|| format!("Failed to bind admin API on {}", config.listen_addr))?;
+    info!("Admin API listening on http://{}/v1/", config.listen_addr);
+
+    let token: Arc<str> = token.into();
+    tokio::spawn(async move {
+        loop {
+            let Ok((stream, peer)) = listener.accept().await else {
+                continue;
+            };
+            let container_manager = container_manager.clone();
+            let audit = audit.clone();
+            let token = token.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_client(stream, peer, &container_manager, &audit, &token).await {
+                    debug!("Admin API connection from {} ended: {:#}", peer, e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+fn count_request(route: &str, status: &str) {
+    let code = status.split_whitespace().next().unwrap_or(status);
+    metrics::inc(
+        "agentman_admin_api_requests_total",
+        "Admin API requests by route and HTTP status.",
+        &[("route", route), ("status", code)],
+    );
+}
+
+async fn respond(client: &mut TcpStream, status: &str, body: &Value) -> Result<()> {
+    let body = format!("{body:#}\n");
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\nCache-Control: no-store\r\n\r\n{body}",
+        body.len()
+    );
+    client.write_all(response.as_bytes()).await?;
+    client.shutdown().await?;
+    Ok(())
+}
+
+fn error(message: impl std::fmt::Display) -> Value {
+    json!({ "error": message.to_string() })
+}
+
+async fn handle_client(
+    mut client: TcpStream,
+    peer: std::net::SocketAddr,
+    container_manager: &ContainerManager,
+    audit: &AuditLogger,
+    token: &str,
+) -> Result<()> {
+    let head = match tokio::time::timeout(REQUEST_TIMEOUT, read_request_head(&mut client)).await {
+        Ok(head) => head?,
+        Err(_) => anyhow::bail!("Timed out waiting for a request"),
+    };
+    let Some(request) = parse_request(&head) else {
+        count_request("unknown", "400");
+        return respond(&mut client, "400 Bad Request", &error("malformed request")).await;
+    };
+    if !authorized(token, request.bearer.as_deref()) {
+        count_request("unknown", "401");
+        warn!("Admin API: unauthorized {} {} from {}", request.method, request.path, peer);
+        return respond(&mut client, "401 Unauthorized", &error("missing or wrong bearer token")).await;
+    }
+    let route = match request.route() {
+        Ok(route) => route,
+        Err(status) => {
+            count_request("unknown", status);
+            return respond(&mut client, status, &error(status)).await;
+        }
+    };
+
+    let (name, status, body) = match route {
+        Route::ListWorkspaces => {
+            let state = container_manager.state();
+            let mut workspaces = Vec::new();
+            for github_user in state.known_github_users().await {
+                workspaces.extend(state.list_workspaces(&github_user).await);
+            }
+            workspaces.sort_by(|a, b| (&a.github_user, &a.project).cmp(&(&b.github_user, &b.project)));
+            let entries: Vec<Value> = futures::stream::iter(workspaces)
+                .map(|ws| workspace_json(container_manager, ws))
+                .buffered(LIST_CONCURRENCY)
+                .collect()
+                .await;
+            ("list_workspaces", "200 OK", json!({ "workspaces": entries }))
+        }
+        Route::GetWorkspace {
+            github_user,
+            project,
+        } => match container_manager.get_workspace(&github_user, &project).await {
+            Some(ws) => ("get_workspace", "200 OK", workspace_json(container_manager, ws).await),
+            None => (
+                "get_workspace",
+                "404 Not Found",
+                error(format!("no workspace {github_user}/{project}")),
+            ),
+        },
+        Route::DestroyWorkspace {
+            github_user,
+            project,
+            keep_workspace,
+        } => {
+            let opts = DestroyOptions {
+                keep_workspace,
+                force: true,
+                dry_run: false,
+            };
+            // Destroying also cleans up after workspaces with no state entry, but a client
+            // naming one it doesn't know should hear so.
+            let exists = container_manager.get_workspace(&github_user, &project).await.is_some();
+            let result = if exists {
+                container_manager
+                    .destroy_workspace(&github_user, &project, opts)
+                    .await
+            } else {
+                Err(anyhow::anyhow!("no workspace {github_user}/{project}"))
+            };
+            let outcome = if result.is_ok() {
+                AuditOutcome::Success
+            } else {
+                AuditOutcome::Failure
+            };
+            audit.record(
+                AuditEvent::new(AuditEventKind::Control, outcome)
+                    .github_user(Some(&github_user))
+                    .project(Some(&project))
+                    .peer(peer)
+                    .detail(if keep_workspace {
+                        "destroy --keep-workspace --force"
+                    } else {
+                        "destroy --yes --force"
+                    })
+                    .field("via", "admin_api"),
+            );
+            match result {
+                Ok(result) => {
+                    info!("Admin API destroyed {}/{} for {}", github_user, project, peer);
+                    ("destroy_workspace", "200 OK", json!(result))
+                }
+                Err(e) => (
+                    "destroy_workspace",
+                    if exists { "500 Internal Server Error" } else { "404 Not Found" },
+                    error(format!("{e:#}")),
+                ),
+            }
+        }
+        Route::FlushKeys { github_user } => {
+            let state = container_manager.state();
+            let result = match github_user {
+                Some(ref user) => state.revoke_keys(user).await,
+                None => state.flush_keys().await,
+            };
+            let outcome = if result.is_ok() {
+                AuditOutcome::Success
+            } else {
+                AuditOutcome::Failure
+            };
+            audit.record(
+                AuditEvent::new(AuditEventKind::Control, outcome)
+                    .github_user(github_user.as_deref())
+                    .peer(peer)
+                    .detail("flush key cache")
+                    .field("via", "admin_api"),
+            );
+            match result {
+                Ok(removed) => {
+                    info!(
+                        "Admin API flushed {} cached key(s){} for {}",
+                        removed,
+                        github_user
+                            .as_deref()
+                            .map(|user| format!(" of {user}"))
+                            .unwrap_or_default(),
+                        peer
+                    );
+                    ("flush_keys", "200 OK", json!({ "removed_keys": removed }))
+                }
+                Err(e) => (
+                    "flush_keys",
+                    "500 Internal Server Error",
+                    error(format!("{e:#}")),
+                ),
+            }
+        }
+    };
+    count_request(name, status);
+    respond(&mut client, status, &body).await
+}
+
+/// The workspace's state entry with its container's status.
+async fn workspace_json(container_manager: &ContainerManager, ws: WorkspaceInfo) -> Value {
+    let (status, container_id, running) =
+        workspace_container_status_with_running(container_manager, &ws.container_name).await;
+    let mut value = json!(ws);
+    if let Some(object) = value.as_object_mut() {
+        object.insert(
+            "container".to_string(),
+            json!({ "status": status, "id": container_id, "running": running }),
+        );
+    }
+    value
+}
+
+/// Read until the request headers are complete.
+async fn read_request_head(stream: &mut TcpStream) -> Result<String> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            anyhow::bail!("Client closed before sending a request");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            return Ok(String::from_utf8_lossy(&buf[..end]).into_owned());
+        }
+        if buf.len() > MAX_REQUEST {
+            anyhow::bail!("Request headers too large");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(head: &str) -> Result<Route, &'static str> {
+        parse_request(head).unwrap().route()
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let request =
+            parse_request("DELETE /v1/keys?github_user=octocat HTTP/1.1\r\nHost: x\r\nAuthorization: Bearer s3cret ")
+                .unwrap();
+        assert_eq!(request.method, "DELETE");
+        assert_eq!(request.path, "/v1/keys");
+        assert_eq!(request.query("github_user"), Some("octocat"));
+        assert_eq!(request.bearer.as_deref(), Some("s3cret"));
+        assert!(authorized("s3cret", request.bearer.as_deref()));
+        assert!(!authorized("s3cret", Some("s3cre")));
+        assert!(!authorized("s3cret", None));
+        assert!(parse_request("").is_none());
+    }
+
+    #[test]
+    fn test_route() {
+        assert_eq!(route("GET /v1/workspaces HTTP/1.1"), Ok(Route::ListWorkspaces));
+        assert_eq!(route("GET /v1/workspaces/ HTTP/1.1"), Ok(Route::ListWorkspaces));
+        assert_eq!(
+            route("GET /v1/workspaces/octocat/web HTTP/1.1"),
+            Ok(Route::GetWorkspace {
+                github_user: "octocat".to_string(),
+                project: "web".to_string()
+            })
+        );
+        assert_eq!(
+            route("DELETE /v1/workspaces/octocat/web?keep_workspace=true HTTP/1.1"),
+            Ok(Route::DestroyWorkspace {
+                github_user: "octocat".to_string(),
+                project: "web".to_string(),
+                keep_workspace: true
+            })
+        );
+        assert_eq!(
+            route("DELETE /v1/keys HTTP/1.1"),
+            Ok(Route::FlushKeys { github_user: None })
+        );
+        assert_eq!(route("DELETE /v1/keys?github_user=-bad HTTP/1.1"), Err("400 Bad Request"));
+        assert_eq!(route("POST /v1/workspaces HTTP/1.1"), Err("405 Method Not Allowed"));
+        assert_eq!(route("GET /v1/workspaces/octocat/.. HTTP/1.1"), Err("404 Not Found"));
+        assert_eq!(route("GET /metrics HTTP/1.1"), Err("404 Not Found"));
+    }
+}
@@ -0,0 +1,267 @@
+//! Admission control for container creation (`[admission]`).
+//!
+//! Every container creation takes a [`Slot`] first. At most `max_concurrent` are held at a
+//! time and at most `max_per_minute` are handed out per minute; the rest wait in arrival
+//! order, so a burst of logins reaches dockerd a few at a time instead of all at once. SSH
+//! sessions whose sandbox is waiting poll [`position`] to tell their user where it is.
+
+use std::collections::VecDeque;
+use std::pin::pin;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, bail};
+use tokio::sync::Notify;
+
+use crate::config::AdmissionConfig;
+use crate::metrics;
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+const WAIT_BUCKETS: &[f64] = &[0.1, 1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0];
+
+/// The gateway's creation queue.
+static QUEUE: LazyLock<Queue> = LazyLock::new(Queue::default);
+
+#[derive(Default)]
+struct Queue {
+    inner: Mutex<Inner>,
+    /// Woken when a slot is freed or handed out, or a waiter gives up.
+    changed: Notify,
+}
+
+#[derive(Default)]
+struct Inner {
+    running: usize,
+    next_ticket: u64,
+    /// `(ticket, workspace)` in arrival order.
+    waiting: VecDeque<(u64, String)>,
+    /// When the slots of the last minute were handed out.
+    started: VecDeque<Instant>,
+}
+
+/// Permission to create a container; frees the slot on drop.
+pub struct Slot<'a> {
+    queue: Option<&'a Queue>,
+}
+
+/// A place in the queue; given up on drop.
+struct Waiting<'a> {
+    queue: &'a Queue,
+    ticket: u64,
+}
+
+/// Wait for a slot to create a container for `workspace` (`github_user/project`).
+pub async fn acquire(config: &AdmissionConfig, workspace: &str) -> Result<Slot<'static>> {
+    if !config.enabled {
+        return Ok(Slot { queue: None });
+    }
+    QUEUE.acquire(config, workspace).await
+}
+
+/// How many creations are ahead of `workspace`'s, while it waits for a slot.
+pub fn position(workspace: &str) -> Option<usize> {
+    QUEUE.position(workspace)
+}
+
+impl Inner {
+    /// Hand out a slot if one is free and the per-minute limit allows it. Otherwise returns
+    /// how long until the limit allows one (`None`: until a slot is freed).
+    fn start(&mut self, config: &AdmissionConfig, now: Instant) -> Result<(), Option<Duration>> {
+        while self
+            .started
+            .front()
+            .is_some_and(|t| now.duration_since(*t) >= MINUTE)
+        {
+            self.started.pop_front();
+        }
+        if self.running >= config.max_concurrent {
+            return Err(None);
+        }
+        if config.max_per_minute > 0 && self.started.len() >= config.max_per_minute as usize {
+            return Err(Some(MINUTE - now.duration_since(self.started[0])));
+        }
+        self.running += 1;
+        self.started.push_back(now);
+        Ok(())
+    }
+
+    fn record_length(&self) {
+        metrics::set(
+            "agentman_container_creates_queued",
+            "Container creations waiting for an admission slot.",
+            &[],
+            self.waiting.len() as f64,
+        );
+    }
+}
+
+impl Queue {
+    async fn acquire(&self, config: &AdmissionConfig, workspace: &str) -> Result<Slot<'_>> {
+        let ticket = {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.waiting.is_empty() && inner.start(config, Instant::now()).is_ok() {
+                return Ok(Slot { queue: Some(self) });
+            }
+            if inner.waiting.len() >= config.max_queue {
+                metrics::inc(
+                    "agentman_container_creates_refused_total",
+                    "Container creations refused because the admission queue was full.",
+                    &[],
+                );
+                bail!(
+                    "{} sandboxes are waiting to be created; try again in a few minutes",
+                    inner.waiting.len()
+                );
+            }
+            let ticket = inner.next_ticket;
+            inner.next_ticket += 1;
+            inner.waiting.push_back((ticket, workspace.to_string()));
+            inner.record_length();
+            ticket
+        };
+        let waiting = Waiting {
+            queue: self,
+            ticket,
+        };
+
+        let queued = Instant::now();
+        let limit = Duration::from_secs(config.queue_timeout_secs);
+        let result = tokio::time::timeout(limit, waiting.turn(config)).await;
+        metrics::observe(
+            "agentman_container_create_queue_seconds",
+            "Time container creations waited for an admission slot.",
+            WAIT_BUCKETS,
+            &[("outcome", if result.is_ok() { "admitted" } else { "timeout" })],
+            queued.elapsed().as_secs_f64(),
+        );
+        match result {
+            Ok(slot) => Ok(slot),
+            Err(_) => bail!(
+                "gave up after waiting {}s for the sandbox to be created; the gateway is busy",
+                config.queue_timeout_secs
+            ),
+        }
+    }
+
+    fn position(&self, workspace: &str) -> Option<usize> {
+        let inner = self.inner.lock().unwrap();
+        inner.waiting.iter().position(|(_, w)| w == workspace)
+    }
+}
+
+impl<'a> Waiting<'a> {
+    async fn turn(&self, config: &AdmissionConfig) -> Slot<'a> {
+        loop {
+            let mut changed = pin!(self.queue.changed.notified());
+            changed.as_mut().enable();
+            let retry = {
+                let mut inner = self.queue.inner.lock().unwrap();
+                if inner.waiting.front().map(|(t, _)| *t) != Some(self.ticket) {
+                    None
+                } else {
+                    match inner.start(config, Instant::now()) {
+                        Ok(()) => {
+                            inner.waiting.pop_front();
+                            inner.record_length();
+                            // The next in line may be able to start as well.
+                            self.queue.changed.notify_waiters();
+                            return Slot {
+                                queue: Some(self.queue),
+                            };
+                        }
+                        Err(retry) => retry,
+                    }
+                }
+            };
+            match retry {
+                Some(after) => {
+                    tokio::select! {
+                        _ = changed => {}
+                        _ = tokio::time::sleep(after) => {}
+                    }
+                }
+                None => changed.await,
+            }
+        }
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        let mut inner = self.queue.inner.lock().unwrap();
+        if let Some(index) = inner.waiting.iter().position(|(t, _)| *t == self.ticket) {
+            inner.waiting.remove(index);
+            inner.record_length();
+            self.queue.changed.notify_waiters();
+        }
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        if let Some(queue) = self.queue {
+            queue.inner.lock().unwrap().running -= 1;
+            queue.changed.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(max_concurrent: usize, max_per_minute: u32, max_queue: usize) -> AdmissionConfig {
+        AdmissionConfig {
+            max_concurrent,
+            max_per_minute,
+            max_queue,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_queue_order_and_limit() {
+        let queue = Queue::default();
+        let config = config(1, 0, 2);
+        let first = queue.acquire(&config, "octocat/a").await.unwrap();
+
+        let mut second = Box::pin(queue.acquire(&config, "octocat/b"));
+        let mut third = Box::pin(queue.acquire(&config, "octocat/c"));
+        assert!(futures::poll!(second.as_mut()).is_pending());
+        assert!(futures::poll!(third.as_mut()).is_pending());
+        assert_eq!(queue.position("octocat/b"), Some(0));
+        assert_eq!(queue.position("octocat/c"), Some(1));
+        assert_eq!(queue.position("octocat/a"), None);
+        let err = queue.acquire(&config, "octocat/d").await.err().unwrap();
+        assert!(err.to_string().contains("2 sandboxes are waiting"));
+
+        drop(first);
+        assert!(futures::poll!(third.as_mut()).is_pending());
+        let second = second.await.unwrap();
+        assert_eq!(queue.position("octocat/c"), Some(0));
+
+        // A waiter that gives up makes room for the ones behind it.
+        drop(third);
+        assert_eq!(queue.position("octocat/c"), None);
+        drop(second);
+        assert_eq!(queue.inner.lock().unwrap().running, 0);
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let limits = config(10, 2, 10);
+        let mut inner = Inner::default();
+        let now = Instant::now();
+        assert!(inner.start(&limits, now).is_ok());
+        assert!(inner.start(&limits, now + Duration::from_secs(20)).is_ok());
+        assert_eq!(
+            inner.start(&limits, now + Duration::from_secs(30)),
+            Err(Some(Duration::from_secs(30)))
+        );
+        assert!(inner.start(&limits, now + MINUTE).is_ok());
+        assert_eq!(inner.running, 3);
+
+        assert_eq!(inner.start(&config(3, 0, 10), now + MINUTE), Err(None));
+    }
+}
@@ -0,0 +1,877 @@
+//! Audit/event logging.
+//!
+//! The gateway records security-relevant events (authentication, shells, execs, control
+//! commands, port forwards, offboarding, kernel security events from containers) as structured
+//! [`AuditEvent`]s. Events are handed to a
+//! background task that fans them out to the sinks configured in the `[logging]` section:
+//! - `file`: JSON lines appended to a local file, optionally rotated by size
+//! - `directory`: JSON lines in one file per user and day, with size caps and retention (see
+//!   [`crate::audit_store`])
+//! - `syslog`: RFC 5424 messages over UDP, TCP (octet-counted framing, for SIEMs) or a Unix
+//!   datagram socket (e.g. `/dev/log`)
+//! - `journald`: native journal protocol with structured `AGENTMAN_*` fields
+//! - `loki`: batched HTTP push to Grafana Loki
+//!
+//! Recording never blocks the SSH session: if the queue is full, events are dropped with a warning.
+//! With `max_events_per_minute`, a user's events past that rate are dropped too, and a
+//! `suppressed` event with their count is recorded once the minute is over.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpStream, UdpSocket, UnixDatagram};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use crate::audit_store::DirectorySink;
+use crate::config::{AuditRetentionConfig, AuditSinkConfig, LoggingConfig};
+use crate::metrics;
+
+/// Capacity of the queue between the SSH handlers and the sink task.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// Rate limiting window for `max_events_per_minute`.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// How often `[logging.retention]` is applied to directory sinks.
+const RETENTION_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Events dropped per user (rate limit or size cap) since the gateway started.
+static DROPPED: LazyLock<Mutex<HashMap<String, u64>>> = LazyLock::new(Default::default);
+
+/// What happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEventKind {
+    Auth,
+    Shell,
+    Exec,
+    Control,
+    LocalForward,
+    RemoteForward,
+    AgentForward,
+    Offboard,
+    /// An admin logged in to another user's project (see [`crate::impersonation`]).
+    Impersonation,
+    /// Kernel audit record from a container (see [`crate::security_events`]).
+    Security,
+    /// How many of a user's events `max_events_per_minute` dropped in the last minute.
+    Suppressed,
+}
+
+impl AuditEventKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Auth => "auth",
+            Self::Shell => "shell",
+            Self::Exec => "exec",
+            Self::Control => "control",
+            Self::LocalForward => "local_forward",
+            Self::RemoteForward => "remote_forward",
+            Self::AgentForward => "agent_forward",
+            Self::Offboard => "offboard",
+            Self::Impersonation => "impersonation",
+            Self::Security => "security",
+            Self::Suppressed => "suppressed",
+        }
+    }
+}
+
+/// Whether the action was allowed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Success,
+    Failure,
+    Denied,
+}
+
+impl AuditOutcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Success => "success",
+            Self::Failure => "failure",
+            Self::Denied => "denied",
+        }
+    }
+}
+
+/// A single structured audit record.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEvent {
+    pub timestamp: DateTime<Utc>,
+    pub event: AuditEventKind,
+    pub outcome: AuditOutcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub github_user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub project: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer: Option<String>,
+    /// Free-form detail: the command string, forward target, failure reason, ...
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// Extra structured fields (syslog structured data, `AGENTMAN_*` journal fields).
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<String, String>,
+}
+
+impl AuditEvent {
+    pub fn new(event: AuditEventKind, outcome: AuditOutcome) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            event,
+            outcome,
+            github_user: None,
+            project: None,
+            peer: None,
+            detail: None,
+            fields: BTreeMap::new(),
+        }
+    }
+
+    pub fn github_user(mut self, user: Option<&str>) -> Self {
+        self.github_user = user.map(str::to_string);
+        self
+    }
+
+    pub fn project(mut self, project: Option<&str>) -> Self {
+        self.project = project.map(str::to_string);
+        self
+    }
+
+    pub fn peer(mut self, peer: impl ToString) -> Self {
+        self.peer = Some(peer.to_string());
+        self
+    }
+
+    pub fn detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    /// Add a structured field. `key` must be a lowercase identifier (`[a-z0-9_]`).
+    pub fn field(mut self, key: &str, value: impl Into<String>) -> Self {
+        self.fields.insert(key.to_string(), value.into());
+        self
+    }
+
+    /// One-line human-readable summary (used as the syslog/journald message).
+    pub fn summary(&self) -> String {
+        let mut out = format!("{} {}", self.event.as_str(), self.outcome.as_str());
+        if let Some(ref user) = self.github_user {
+            out.push_str(&format!(" user={user}"));
+        }
+        if let Some(ref project) = self.project {
+            out.push_str(&format!(" project={project}"));
+        }
+        if let Some(ref peer) = self.peer {
+            out.push_str(&format!(" peer={peer}"));
+        }
+        for (key, value) in &self.fields {
+            out.push_str(&format!(" {key}={value:?}"));
+        }
+        if let Some(ref detail) = self.detail {
+            out.push_str(&format!(" detail={detail:?}"));
+        }
+        out
+    }
+}
+
+/// Handle used by the rest of the gateway to record audit events.
+pub struct AuditLogger {
+    tx: Option<mpsc::Sender<AuditEvent>>,
+}
+
+impl AuditLogger {
+    /// Build the configured sinks and spawn the background fan-out task.
+    ///
+    /// With no sinks configured, recording is a no-op.
+    pub async fn start(config: &LoggingConfig) -> Result<Self> {
+        if config.sinks.is_empty() {
+            return Ok(Self { tx: None });
+        }
+
+        let mut sinks = Vec::with_capacity(config.sinks.len());
+        for sink in &config.sinks {
+            sinks.push(Sink::open(sink, &config.retention).await?);
+        }
+
+        let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+        let limiter = config.max_events_per_minute.map(RateLimiter::new);
+        tokio::spawn(run_sinks(rx, sinks, limiter));
+        Ok(Self { tx: Some(tx) })
+    }
+
+    /// Queue an event for all sinks.
+    pub fn record(&self, event: AuditEvent) {
+        let Some(ref tx) = self.tx else {
+            return;
+        };
+        if let Err(mpsc::error::TrySendError::Full(ev)) = tx.try_send(event) {
+            warn!("Audit queue full, dropping event: {}", ev.summary());
+        }
+    }
+}
+
+/// Events dropped for `user` so far (see [`dropped_events`]).
+pub(crate) fn note_dropped(user: &str, count: u64) {
+    *DROPPED.lock().unwrap().entry(user.to_string()).or_default() += count;
+    metrics::add(
+        "agentman_audit_events_dropped_total",
+        "Audit events dropped by the per-user rate limit or size cap.",
+        &[],
+        count as f64,
+    );
+}
+
+/// Events dropped per user by the rate limit or a directory sink's size cap since startup.
+pub fn dropped_events() -> HashMap<String, u64> {
+    DROPPED.lock().unwrap().clone()
+}
+
+async fn run_sinks(
+    mut rx: mpsc::Receiver<AuditEvent>,
+    mut sinks: Vec<Sink>,
+    mut limiter: Option<RateLimiter>,
+) {
+    let mut flush = tokio::time::interval(Duration::from_secs(1));
+    let mut retention = tokio::time::interval(RETENTION_INTERVAL);
+    loop {
+        tokio::select! {
+            ev = rx.recv() => {
+                let Some(ev) = ev else { break };
+                // Logins are throttled by the auth guard, and failed ones with someone's name
+                // must not use up that user's budget.
+                if let (Some(limiter), Some(user)) = (&mut limiter, &ev.github_user)
+                    && ev.event != AuditEventKind::Auth
+                    && !limiter.allow(user, Instant::now())
+                {
+                    note_dropped(user, 1);
+                    continue;
+                }
+                write_all(&mut sinks, &ev).await;
+            }
+            _ = flush.tick() => {
+                if let Some(ref mut limiter) = limiter {
+                    for (user, count) in limiter.expired(Instant::now()) {
+                        write_all(&mut sinks, &suppressed_event(&user, count)).await;
+                    }
+                }
+                for sink in &mut sinks {
+                    if let Err(e) = sink.flush().await {
+                        warn!("Audit sink {} flush failed: {:#}", sink.name(), e);
+                    }
+                }
+            }
+            _ = retention.tick() => {
+                for sink in &mut sinks {
+                    if let Sink::Directory(s) = sink
+                        && let Err(e) = s.maintain().await
+                    {
+                        warn!("Audit log retention failed: {:#}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    for sink in &mut sinks {
+        let _ = sink.flush().await;
+    }
+}
+
+async fn write_all(sinks: &mut [Sink], ev: &AuditEvent) {
+    for sink in sinks {
+        if let Err(e) = sink.write(ev).await {
+            warn!("Audit sink {} failed: {:#}", sink.name(), e);
+        }
+    }
+}
+
+fn suppressed_event(github_user: &str, count: u64) -> AuditEvent {
+    AuditEvent::new(AuditEventKind::Suppressed, AuditOutcome::Denied)
+        .github_user(Some(github_user))
+        .detail(format!("{count} audit event(s) dropped by rate limit"))
+        .field("count", count.to_string())
+}
+
+/// Per-user limit of recorded events per [`RATE_WINDOW`].
+struct RateLimiter {
+    max: u32,
+    /// Window start, events recorded and events dropped, by user.
+    windows: HashMap<String, (Instant, u32, u64)>,
+}
+
+impl RateLimiter {
+    fn new(max: u32) -> Self {
+        Self {
+            max,
+            windows: HashMap::new(),
+        }
+    }
+
+    /// Count an event of `user`; false once the window's budget is spent.
+    fn allow(&mut self, user: &str, now: Instant) -> bool {
+        let (_, recorded, dropped) = self
+            .windows
+            .entry(user.to_string())
+            .or_insert((now, 0, 0));
+        if *recorded < self.max {
+            *recorded += 1;
+            true
+        } else {
+            *dropped += 1;
+            false
+        }
+    }
+
+    /// Drop finished windows, returning the users whose events were dropped, with the count.
+    fn expired(&mut self, now: Instant) -> Vec<(String, u64)> {
+        let mut out = Vec::new();
+        self.windows.retain(|user, (start, _, dropped)| {
+            if now.duration_since(*start) < RATE_WINDOW {
+                return true;
+            }
+            if *dropped > 0 {
+                out.push((user.clone(), *dropped));
+            }
+            false
+        });
+        out
+    }
+}
+
+enum Sink {
+    File(FileSink),
+    Directory(DirectorySink),
+    Syslog(SyslogSink),
+    Journald(JournaldSink),
+    Loki(LokiSink),
+}
+
+impl Sink {
+    async fn open(config: &AuditSinkConfig, retention: &AuditRetentionConfig) -> Result<Self> {
+        Ok(match config {
+            AuditSinkConfig::File {
+                path,
+                max_size_mb,
+                max_files,
+            } => {
+                let rotation = max_size_mb.map(|mb| (mb * 1024 * 1024, *max_files));
+                Sink::File(FileSink::open(path.clone(), rotation).await?)
+            }
+            AuditSinkConfig::Directory { path } => {
+                Sink::Directory(DirectorySink::open(path.clone(), retention.clone()).await?)
+            }
+            AuditSinkConfig::Syslog { address, facility } => {
+                Sink::Syslog(SyslogSink::open(address, facility).await?)
+            }
+            AuditSinkConfig::Journald { socket } => {
+                Sink::Journald(JournaldSink::open(socket.clone())?)
+            }
+            AuditSinkConfig::Loki {
+                url,
+                labels,
+                batch_size,
+            } => Sink::Loki(LokiSink::new(url, labels.clone(), *batch_size)),
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Sink::File(_) => "file",
+            Sink::Directory(_) => "directory",
+            Sink::Syslog(_) => "syslog",
+            Sink::Journald(_) => "journald",
+            Sink::Loki(_) => "loki",
+        }
+    }
+
+    async fn write(&mut self, ev: &AuditEvent) -> Result<()> {
+        match self {
+            Sink::File(s) => s.write(ev).await,
+            Sink::Directory(s) => s.write(ev).await,
+            Sink::Syslog(s) => s.write(ev).await,
+            Sink::Journald(s) => s.write(ev).await,
+            Sink::Loki(s) => s.write(ev).await,
+        }
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        match self {
+            Sink::File(s) => s.file.flush().await.context("Failed to flush audit file"),
+            Sink::Directory(s) => s.flush().await,
+            Sink::Loki(s) => s.flush().await,
+            Sink::Syslog(_) | Sink::Journald(_) => Ok(()),
+        }
+    }
+}
+
+/// JSON lines appended to a local file.
+struct FileSink {
+    path: PathBuf,
+    file: tokio::fs::File,
+    /// Bytes in the current file.
+    size: u64,
+    /// Size at which the file is rotated, and how many rotated files are kept.
+    rotation: Option<(u64, usize)>,
+}
+
+impl FileSink {
+    async fn open(path: PathBuf, rotation: Option<(u64, usize)>) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .with_context(|| format!("Failed to create audit log directory: {}", parent.display()))?;
+        }
+        let file = Self::open_file(&path).await?;
+        let size = file.metadata().await.map(|m| m.len()).unwrap_or(0);
+        Ok(Self {
+            path,
+            file,
+            size,
+            rotation,
+        })
+    }
+
+    async fn open_file(path: &Path) -> Result<tokio::fs::File> {
+        tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .with_context(|| format!("Failed to open audit log: {}", path.display()))
+    }
+
+    async fn write(&mut self, ev: &AuditEvent) -> Result<()> {
+        let mut line = serde_json::to_string(ev).context("Failed to serialize audit event")?;
+        line.push('\n');
+        if let Some((max_size, max_files)) = self.rotation
+            && self.size > 0
+            && self.size + line.len() as u64 > max_size
+        {
+            self.rotate(max_files).await?;
+        }
+        self.file
+            .write_all(line.as_bytes())
+            .await
+            .with_context(|| format!("Failed to write audit log: {}", self.path.display()))?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Shift `<path>.N` to `<path>.N+1` (dropping the oldest), move the file to `<path>.1` and
+    /// start a new one.
+    async fn rotate(&mut self, max_files: usize) -> Result<()> {
+        self.file.flush().await.context("Failed to flush audit file")?;
+        let rotated = |n: usize| PathBuf::from(format!("{}.{n}", self.path.display()));
+        for n in (1..max_files).rev() {
+            // Not there yet until that many rotations happened.
+            let _ = tokio::fs::rename(rotated(n), rotated(n + 1)).await;
+        }
+        tokio::fs::rename(&self.path, rotated(1))
+            .await
+            .with_context(|| format!("Failed to rotate audit log: {}", self.path.display()))?;
+        self.file = Self::open_file(&self.path).await?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+enum SyslogTransport {
+    Udp(UdpSocket),
+    /// Connected lazily and re-established after a failed write.
+    Tcp(Option<TcpStream>, String),
+    Unix(UnixDatagram, PathBuf),
+}
+
+/// RFC 5424 syslog messages.
+struct SyslogSink {
+    transport: SyslogTransport,
+    facility: u8,
+    hostname: String,
+}
+
+impl SyslogSink {
+    async fn open(address: &str, facility: &str) -> Result<Self> {
+        let facility = syslog_facility_code(facility)
+            .ok_or_else(|| anyhow!("Unknown syslog facility: {}", facility))?;
+
+        let transport = if let Some(path) = address.strip_prefix("unix://") {
+            let sock = UnixDatagram::unbound().context("Failed to create syslog socket")?;
+            SyslogTransport::Unix(sock, PathBuf::from(path))
+        } else if let Some(target) = address.strip_prefix("tcp://") {
+            SyslogTransport::Tcp(None, target.to_string())
+        } else {
+            let target = address.strip_prefix("udp://").unwrap_or(address);
+            let sock = UdpSocket::bind("0.0.0.0:0")
+                .await
+                .context("Failed to bind syslog UDP socket")?;
+            sock.connect(target)
+                .await
+                .with_context(|| format!("Failed to resolve syslog address: {}", target))?;
+            SyslogTransport::Udp(sock)
+        };
+
+        Ok(Self {
+            transport,
+            facility,
+            hostname: local_hostname(),
+        })
+    }
+
+    async fn write(&mut self, ev: &AuditEvent) -> Result<()> {
+        let msg = format_syslog(ev, self.facility, &self.hostname);
+        match &mut self.transport {
+            SyslogTransport::Udp(sock) => {
+                sock.send(msg.as_bytes()).await.context("Failed to send syslog message")?;
+            }
+            SyslogTransport::Tcp(stream, target) => {
+                // RFC 6587 octet counting, so messages may contain newlines.
+                let frame = format!("{} {msg}", msg.len());
+                if stream.is_none() {
+                    let connect = TcpStream::connect(target.as_str());
+                    let conn = tokio::time::timeout(Duration::from_secs(5), connect)
+                        .await
+                        .map_err(|_| anyhow!("Timed out connecting to syslog at {}", target))?
+                        .with_context(|| format!("Failed to connect to syslog at {}", target))?;
+                    *stream = Some(conn);
+                }
+                if let Some(conn) = stream
+                    && let Err(e) = conn.write_all(frame.as_bytes()).await
+                {
+                    *stream = None;
+                    return Err(e).with_context(|| format!("Failed to send syslog message to {}", target));
+                }
+            }
+            SyslogTransport::Unix(sock, path) => {
+                sock.send_to(msg.as_bytes(), &*path)
+                    .await
+                    .with_context(|| format!("Failed to send syslog message to {}", path.display()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Map a syslog facility name to its numeric code.
+fn syslog_facility_code(name: &str) -> Option<u8> {
+    Some(match name {
+        "user" => 1,
+        "daemon" => 3,
+        "auth" => 4,
+        "authpriv" => 10,
+        "local0" => 16,
+        "local1" => 17,
+        "local2" => 18,
+        "local3" => 19,
+        "local4" => 20,
+        "local5" => 21,
+        "local6" => 22,
+        "local7" => 23,
+        _ => return None,
+    })
+}
+
+fn syslog_severity(ev: &AuditEvent) -> u8 {
+    match (ev.event, ev.outcome) {
+        (AuditEventKind::Security | AuditEventKind::Suppressed, _) => 4, // warning
+        (AuditEventKind::Impersonation, _) => 5, // notice
+        (_, AuditOutcome::Success) => 6, // informational
+        (_, AuditOutcome::Failure | AuditOutcome::Denied) => 4, // warning
+    }
+}
+
+/// Escape a value for an RFC 5424 structured-data parameter.
+fn sd_escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+fn format_syslog(ev: &AuditEvent, facility: u8, hostname: &str) -> String {
+    let pri = facility * 8 + syslog_severity(ev);
+    let mut sd = format!(
+        "[agentman event=\"{}\" outcome=\"{}\"",
+        ev.event.as_str(),
+        ev.outcome.as_str()
+    );
+    for (key, value) in [
+        ("user", &ev.github_user),
+        ("project", &ev.project),
+        ("peer", &ev.peer),
+    ] {
+        if let Some(v) = value {
+            sd.push_str(&format!(" {key}=\"{}\"", sd_escape(v)));
+        }
+    }
+    for (key, value) in &ev.fields {
+        sd.push_str(&format!(" {key}=\"{}\"", sd_escape(value)));
+    }
+    sd.push(']');
+
+    format!(
+        "<{pri}>1 {} {hostname} agentman-gateway {} - {sd} {}",
+        ev.timestamp.to_rfc3339_opts(SecondsFormat::Millis, true),
+        std::process::id(),
+        ev.summary()
+    )
+}
+
+/// systemd-journald native protocol.
+struct JournaldSink {
+    sock: UnixDatagram,
+    path: PathBuf,
+}
+
+impl JournaldSink {
+    fn open(path: PathBuf) -> Result<Self> {
+        let sock = UnixDatagram::unbound().context("Failed to create journald socket")?;
+        Ok(Self { sock, path })
+    }
+
+    async fn write(&mut self, ev: &AuditEvent) -> Result<()> {
+        let payload = format_journald(ev);
+        self.sock
+            .send_to(&payload, &self.path)
+            .await
+            .with_context(|| format!("Failed to send to journald at {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+fn journald_field(out: &mut Vec<u8>, key: &str, value: &str) {
+    out.extend_from_slice(key.as_bytes());
+    if value.contains('\n') {
+        // Binary-safe form: KEY\n<u64 LE length><value>\n
+        out.push(b'\n');
+        out.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        out.extend_from_slice(value.as_bytes());
+    } else {
+        out.push(b'=');
+        out.extend_from_slice(value.as_bytes());
+    }
+    out.push(b'\n');
+}
+
+fn format_journald(ev: &AuditEvent) -> Vec<u8> {
+    let mut out = Vec::new();
+    journald_field(&mut out, "MESSAGE", &ev.summary());
+    journald_field(&mut out, "PRIORITY", &syslog_severity(ev).to_string());
+    journald_field(&mut out, "SYSLOG_IDENTIFIER", "agentman-gateway");
+    journald_field(&mut out, "AGENTMAN_EVENT", ev.event.as_str());
+    journald_field(&mut out, "AGENTMAN_OUTCOME", ev.outcome.as_str());
+    for (key, value) in [
+        ("AGENTMAN_GITHUB_USER", &ev.github_user),
+        ("AGENTMAN_PROJECT", &ev.project),
+        ("AGENTMAN_PEER", &ev.peer),
+        ("AGENTMAN_DETAIL", &ev.detail),
+    ] {
+        if let Some(v) = value {
+            journald_field(&mut out, key, v);
+        }
+    }
+    for (key, value) in &ev.fields {
+        journald_field(&mut out, &format!("AGENTMAN_{}", key.to_ascii_uppercase()), value);
+    }
+    out
+}
+
+/// Batched push to Grafana Loki (`/loki/api/v1/push`).
+struct LokiSink {
+    client: reqwest::Client,
+    push_url: String,
+    labels: HashMap<String, String>,
+    batch_size: usize,
+    pending: Vec<AuditEvent>,
+}
+
+impl LokiSink {
+    fn new(url: &str, labels: HashMap<String, String>, batch_size: usize) -> Self {
+        let client = reqwest::Client::builder()
+            .user_agent("agentman-gateway/0.1")
+            .timeout(Duration::from_secs(10))
+            .build()
+            .expect("Failed to create HTTP client");
+        Self {
+            client,
+            push_url: format!("{}/loki/api/v1/push", url.trim_end_matches('/')),
+            labels,
+            batch_size: batch_size.max(1),
+            pending: Vec::new(),
+        }
+    }
+
+    async fn write(&mut self, ev: &AuditEvent) -> Result<()> {
+        self.pending.push(ev.clone());
+        if self.pending.len() >= self.batch_size {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let events = std::mem::take(&mut self.pending);
+        let body = loki_push_body(&events, &self.labels)?;
+
+        let response = self
+            .client
+            .post(&self.push_url)
+            .header("Content-Type", "application/json")
+            .body(body)
+            .send()
+            .await
+            .with_context(|| format!("Failed to push {} event(s) to Loki", events.len()))?;
+        if !response.status().is_success() {
+            return Err(anyhow!("Loki returned {}", response.status()));
+        }
+        Ok(())
+    }
+}
+
+/// Build a Loki push payload, grouping events into one stream per event kind.
+fn loki_push_body(events: &[AuditEvent], labels: &HashMap<String, String>) -> Result<String> {
+    let mut streams: HashMap<&'static str, Vec<[String; 2]>> = HashMap::new();
+    for ev in events {
+        let ts = ev
+            .timestamp
+            .timestamp_nanos_opt()
+            .unwrap_or_default()
+            .to_string();
+        let line = serde_json::to_string(ev).context("Failed to serialize audit event")?;
+        streams.entry(ev.event.as_str()).or_default().push([ts, line]);
+    }
+
+    let streams: Vec<serde_json::Value> = streams
+        .into_iter()
+        .map(|(kind, values)| {
+            let mut stream = labels.clone();
+            stream.insert("job".to_string(), "agentman-gateway".to_string());
+            stream.insert("event".to_string(), kind.to_string());
+            serde_json::json!({ "stream": stream, "values": values })
+        })
+        .collect();
+
+    serde_json::to_string(&serde_json::json!({ "streams": streams }))
+        .context("Failed to serialize Loki payload")
+}
+
+fn local_hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> AuditEvent {
+        AuditEvent::new(AuditEventKind::Exec, AuditOutcome::Success)
+            .github_user(Some("octocat"))
+            .project(Some("demo"))
+            .peer("10.0.0.1:5555")
+            .detail("echo \"hi\"")
+    }
+
+    #[test]
+    fn test_format_syslog() {
+        let msg = format_syslog(&sample(), 16, "gw");
+        // local0 (16) * 8 + informational (6)
+        assert!(msg.starts_with("<134>1 "));
+        assert!(msg.contains(" gw agentman-gateway "));
+        assert!(msg.contains("[agentman event=\"exec\" outcome=\"success\" user=\"octocat\" project=\"demo\" peer=\"10.0.0.1:5555\"]"));
+    }
+
+    #[test]
+    fn test_format_syslog_fields() {
+        let ev = AuditEvent::new(AuditEventKind::Security, AuditOutcome::Denied)
+            .github_user(Some("octocat"))
+            .field("category", "ptrace")
+            .field("comm", "gdb \"x\"");
+        let msg = format_syslog(&ev, 10, "gw");
+        // authpriv (10) * 8 + warning (4)
+        assert!(msg.starts_with("<84>1 "));
+        assert!(msg.contains("user=\"octocat\" category=\"ptrace\" comm=\"gdb \\\"x\\\"\"]"));
+        let journal = String::from_utf8_lossy(&format_journald(&ev)).to_string();
+        assert!(journal.contains("AGENTMAN_CATEGORY=ptrace\n"));
+    }
+
+    #[test]
+    fn test_format_journald() {
+        let payload = format_journald(&sample().detail("line1\nline2"));
+        let text = String::from_utf8_lossy(&payload);
+        assert!(text.contains("AGENTMAN_GITHUB_USER=octocat\n"));
+        assert!(text.contains("SYSLOG_IDENTIFIER=agentman-gateway\n"));
+        // Multi-line values use the length-prefixed form.
+        let needle = b"AGENTMAN_DETAIL\n\x0b\x00\x00\x00\x00\x00\x00\x00line1\nline2\n";
+        assert!(payload.windows(needle.len()).any(|w| w == needle));
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(2);
+        assert!(limiter.allow("octocat", start));
+        assert!(limiter.allow("octocat", start));
+        assert!(!limiter.allow("octocat", start));
+        assert!(!limiter.allow("octocat", start));
+        assert!(limiter.allow("hubot", start));
+
+        assert!(limiter.expired(start + Duration::from_secs(30)).is_empty());
+        let mut expired = limiter.expired(start + RATE_WINDOW);
+        expired.sort();
+        assert_eq!(expired, vec![("octocat".to_string(), 2)]);
+        assert!(limiter.allow("octocat", start + RATE_WINDOW));
+    }
+
+    #[test]
+    fn test_loki_push_body() {
+        let labels = HashMap::from([("env".to_string(), "test".to_string())]);
+        let body = loki_push_body(&[sample()], &labels).unwrap();
+        let v: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let stream = &v["streams"][0];
+        assert_eq!(stream["stream"]["env"], "test");
+        assert_eq!(stream["stream"]["event"], "exec");
+        assert_eq!(stream["values"][0].as_array().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_file_rotation() {
+        let dir = std::env::temp_dir().join(format!("agentman-audit-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("audit.jsonl");
+        let line_len = serde_json::to_string(&sample()).unwrap().len() as u64 + 1;
+        // Two events per file, two rotated files kept.
+        let mut sink = FileSink::open(path.clone(), Some((2 * line_len, 2))).await.unwrap();
+        for _ in 0..7 {
+            sink.write(&sample()).await.unwrap();
+        }
+        sink.file.flush().await.unwrap();
+
+        let lines = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap().lines().count();
+        assert_eq!(lines("audit.jsonl"), 1);
+        assert_eq!(lines("audit.jsonl.1"), 2);
+        assert_eq!(lines("audit.jsonl.2"), 2);
+        assert!(!dir.join("audit.jsonl.3").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,380 @@
+//! Per-user audit log files (`directory` sinks) and their retention (`[logging.retention]`).
+//!
+//! Events go to `<root>/<github-user>/<YYYY-MM-DD>.jsonl`, one file per user and (UTC) day, so
+//! that each user's logs can be capped, compressed and expired on their own. Once an hour, days
+//! older than `compress_after_days` are gzipped (`gzip -n`, to `.jsonl.gz`), days older than
+//! `delete_after_days` are deleted, and users over `max_mb_per_user` lose their oldest days.
+//! Today's file is never touched; when it alone reaches the cap, the user's events are dropped
+//! (and counted) until the next day.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use chrono::{NaiveDate, Utc};
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+
+use crate::audit::{self, AuditEvent, AuditEventKind, AuditOutcome};
+use crate::config::AuditRetentionConfig;
+
+/// Directory for events that aren't about a GitHub user: no user, an invalid name, or a failed
+/// login.
+pub const GATEWAY_DIR: &str = "_gateway";
+
+const DAY_FORMAT: &str = "%Y-%m-%d";
+
+/// One day of a user's log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DayFile {
+    day: NaiveDate,
+    path: PathBuf,
+    bytes: u64,
+    compressed: bool,
+}
+
+/// Disk usage of one user's logs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserUsage {
+    pub user: String,
+    pub bytes: u64,
+    pub days: usize,
+    pub compressed: usize,
+    pub oldest: Option<NaiveDate>,
+}
+
+pub struct DirectorySink {
+    root: PathBuf,
+    retention: AuditRetentionConfig,
+    /// Today's open file per user directory.
+    open: HashMap<String, (NaiveDate, tokio::fs::File)>,
+    /// Bytes per user directory, measured on first write and kept up to date after.
+    usage: HashMap<String, u64>,
+}
+
+impl DirectorySink {
+    pub async fn open(root: PathBuf, retention: AuditRetentionConfig) -> Result<Self> {
+        tokio::fs::create_dir_all(&root)
+            .await
+            .with_context(|| format!("Failed to create audit log directory: {}", root.display()))?;
+        Ok(Self {
+            root,
+            retention,
+            open: HashMap::new(),
+            usage: HashMap::new(),
+        })
+    }
+
+    pub async fn write(&mut self, ev: &AuditEvent) -> Result<()> {
+        // Failed logins name whoever the client claims to be; they are the gateway's business.
+        let user = match (ev.event, ev.outcome) {
+            (AuditEventKind::Auth, AuditOutcome::Failure | AuditOutcome::Denied) => None,
+            _ => ev.github_user.as_deref(),
+        };
+        let dir = user_dir(user).to_string();
+        let day = ev.timestamp.date_naive();
+        let mut line = serde_json::to_string(ev).context("Failed to serialize audit event")?;
+        line.push('\n');
+        let len = line.len() as u64;
+
+        if let Some(cap) = self.cap() {
+            let used = match self.usage.get(&dir) {
+                Some(used) => *used,
+                None => {
+                    let used = scan(&self.root.join(&dir)).iter().map(|f| f.bytes).sum();
+                    *self.usage.entry(dir.clone()).or_insert(used)
+                }
+            };
+            if used + len > cap {
+                let freed = prune(&self.root.join(&dir), cap.saturating_sub(len), day);
+                let used = used.saturating_sub(freed);
+                self.usage.insert(dir.clone(), used);
+                if used + len > cap {
+                    audit::note_dropped(&dir, 1);
+                    return Ok(());
+                }
+            }
+        }
+
+        let file = match self.open.get_mut(&dir) {
+            Some((open_day, file)) if *open_day == day => file,
+            _ => {
+                let path = self.root.join(&dir).join(day_file_name(day));
+                let file = open_append(&path).await?;
+                &mut self.open.entry(dir.clone()).insert_entry((day, file)).into_mut().1
+            }
+        };
+        file.write_all(line.as_bytes())
+            .await
+            .with_context(|| format!("Failed to write audit log for {dir}"))?;
+        *self.usage.entry(dir).or_default() += len;
+        Ok(())
+    }
+
+    pub async fn flush(&mut self) -> Result<()> {
+        for (dir, (_, file)) in &mut self.open {
+            file.flush()
+                .await
+                .with_context(|| format!("Failed to flush audit log for {dir}"))?;
+        }
+        Ok(())
+    }
+
+    /// Apply the retention settings to every user's logs.
+    pub async fn maintain(&mut self) -> Result<()> {
+        let today = Utc::now().date_naive();
+        // Earlier days are final; their files may be compressed or deleted now.
+        self.flush().await?;
+        self.open.retain(|_, (day, _)| *day == today);
+
+        let root = self.root.clone();
+        let retention = self.retention.clone();
+        tokio::task::spawn_blocking(move || maintain(&root, &retention, today))
+            .await
+            .context("Audit retention task failed")??;
+        self.usage.clear();
+        Ok(())
+    }
+
+    fn cap(&self) -> Option<u64> {
+        self.retention.max_mb_per_user.map(|mb| mb * 1024 * 1024)
+    }
+}
+
+async fn open_append(path: &Path) -> Result<tokio::fs::File> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .with_context(|| format!("Failed to open audit log: {}", path.display()))
+}
+
+/// The directory for a user's events; anything that isn't a GitHub username (including
+/// unauthenticated attempts with made-up names) shares [`GATEWAY_DIR`].
+fn user_dir(github_user: Option<&str>) -> &str {
+    match github_user {
+        Some(user)
+            if !user.is_empty()
+                && user.len() <= 39
+                && !user.starts_with('-')
+                && user.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') =>
+        {
+            user
+        }
+        _ => GATEWAY_DIR,
+    }
+}
+
+fn day_file_name(day: NaiveDate) -> String {
+    format!("{}.jsonl", day.format(DAY_FORMAT))
+}
+
+fn parse_day_file(name: &str) -> Option<(NaiveDate, bool)> {
+    let (stem, compressed) = match name.strip_suffix(".jsonl.gz") {
+        Some(stem) => (stem, true),
+        None => (name.strip_suffix(".jsonl")?, false),
+    };
+    let day = NaiveDate::parse_from_str(stem, DAY_FORMAT).ok()?;
+    Some((day, compressed))
+}
+
+/// A user's day files, oldest first (a day can briefly have both forms while compressing).
+fn scan(dir: &Path) -> Vec<DayFile> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut files: Vec<DayFile> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let (day, compressed) = parse_day_file(&entry.file_name().to_string_lossy())?;
+            let metadata = entry.metadata().ok().filter(|m| m.is_file())?;
+            Some(DayFile {
+                day,
+                path: entry.path(),
+                bytes: metadata.len(),
+                compressed,
+            })
+        })
+        .collect();
+    files.sort_by_key(|f| (f.day, f.compressed));
+    files
+}
+
+/// Delete a user's oldest days (never `keep`) until at most `target` bytes are left; returns
+/// the bytes freed.
+fn prune(dir: &Path, target: u64, keep: NaiveDate) -> u64 {
+    let files = scan(dir);
+    let mut used: u64 = files.iter().map(|f| f.bytes).sum();
+    let mut freed = 0;
+    for file in files.iter().filter(|f| f.day != keep) {
+        if used <= target {
+            break;
+        }
+        match std::fs::remove_file(&file.path) {
+            Ok(()) => {
+                used -= file.bytes;
+                freed += file.bytes;
+            }
+            Err(e) => warn!("Failed to remove {}: {}", file.path.display(), e),
+        }
+    }
+    if freed > 0 {
+        info!("Audit logs of {}: removed {} bytes over the cap", dir.display(), freed);
+    }
+    freed
+}
+
+fn maintain(root: &Path, retention: &AuditRetentionConfig, today: NaiveDate) -> Result<()> {
+    let entries = std::fs::read_dir(root)
+        .with_context(|| format!("Failed to read audit log directory: {}", root.display()))?;
+    for entry in entries.flatten() {
+        if !entry.file_type().is_ok_and(|t| t.is_dir()) {
+            continue;
+        }
+        let dir = entry.path();
+        for file in scan(&dir) {
+            let age = (today - file.day).num_days();
+            if retention.delete_after_days.is_some_and(|days| age > i64::from(days)) {
+                if let Err(e) = std::fs::remove_file(&file.path) {
+                    warn!("Failed to remove {}: {}", file.path.display(), e);
+                }
+            } else if !file.compressed
+                && file.day < today
+                && retention.compress_after_days.is_some_and(|days| age > i64::from(days))
+                && let Err(e) = gzip(&file.path)
+            {
+                warn!("{:#}", e);
+            }
+        }
+        if let Some(mb) = retention.max_mb_per_user {
+            prune(&dir, mb * 1024 * 1024, today);
+        }
+    }
+    Ok(())
+}
+
+/// Compress `path` to `<path>.gz` (replacing it). A plain file is kept alongside a compressed
+/// one only while `gzip` runs.
+fn gzip(path: &Path) -> Result<()> {
+    let output = std::process::Command::new("gzip")
+        .arg("-n")
+        .arg("-f")
+        .arg("--")
+        .arg(path)
+        .output()
+        .context("Failed to run gzip")?;
+    if !output.status.success() {
+        bail!(
+            "gzip of {} failed: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Disk usage per user directory under `root`, largest first.
+pub fn usage(root: &Path) -> Result<Vec<UserUsage>> {
+    let entries = std::fs::read_dir(root)
+        .with_context(|| format!("Failed to read audit log directory: {}", root.display()))?;
+    let mut out: Vec<UserUsage> = entries
+        .flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_dir()))
+        .map(|entry| {
+            let files = scan(&entry.path());
+            UserUsage {
+                user: entry.file_name().to_string_lossy().into_owned(),
+                bytes: files.iter().map(|f| f.bytes).sum(),
+                days: files.len(),
+                compressed: files.iter().filter(|f| f.compressed).count(),
+                oldest: files.first().map(|f| f.day),
+            }
+        })
+        .collect();
+    out.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.user.cmp(&b.user)));
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn day(s: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(s, DAY_FORMAT).unwrap()
+    }
+
+    #[test]
+    fn test_user_dir() {
+        assert_eq!(user_dir(Some("octocat")), "octocat");
+        assert_eq!(user_dir(Some("mona-lisa")), "mona-lisa");
+        assert_eq!(user_dir(Some("../etc")), GATEWAY_DIR);
+        assert_eq!(user_dir(Some("-x")), GATEWAY_DIR);
+        assert_eq!(user_dir(None), GATEWAY_DIR);
+    }
+
+    #[test]
+    fn test_parse_day_file() {
+        assert_eq!(parse_day_file("2026-10-15.jsonl"), Some((day("2026-10-15"), false)));
+        assert_eq!(parse_day_file("2026-10-15.jsonl.gz"), Some((day("2026-10-15"), true)));
+        assert_eq!(parse_day_file("2026-10-15.jsonl.tmp"), None);
+        assert_eq!(parse_day_file("notes.jsonl"), None);
+    }
+
+    #[tokio::test]
+    async fn test_retention() {
+        let root = std::env::temp_dir().join(format!("agentman-audit-test-{}", std::process::id()));
+        let user = root.join("octocat");
+        std::fs::create_dir_all(&user).unwrap();
+        let today = Utc::now().date_naive();
+        let days_ago = |n: i64| today - chrono::Duration::days(n);
+        for n in [0, 2, 10, 40] {
+            std::fs::write(user.join(day_file_name(days_ago(n))), vec![b'x'; 1000]).unwrap();
+        }
+
+        let retention = AuditRetentionConfig {
+            max_mb_per_user: None,
+            compress_after_days: Some(7),
+            delete_after_days: Some(30),
+        };
+        maintain(&root, &retention, today).unwrap();
+        let files: Vec<_> = scan(&user).into_iter().map(|f| (f.day, f.compressed)).collect();
+        if std::process::Command::new("gzip").arg("--version").output().is_ok() {
+            assert_eq!(
+                files,
+                vec![(days_ago(10), true), (days_ago(2), false), (today, false)]
+            );
+        }
+        assert!(files.iter().all(|(d, _)| *d != days_ago(40)));
+
+        // Over the cap, the oldest days go first, and today's file is the last thing kept.
+        let mut sink = DirectorySink::open(
+            root.clone(),
+            AuditRetentionConfig {
+                max_mb_per_user: Some(1),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        std::fs::write(user.join(day_file_name(today)), vec![b'x'; 1024 * 1024 - 10]).unwrap();
+        let ev = AuditEvent::new(AuditEventKind::Exec, AuditOutcome::Success)
+            .github_user(Some("octocat"))
+            .detail("make");
+        sink.write(&ev).await.unwrap();
+        sink.flush().await.unwrap();
+        let left = scan(&user);
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].day, today);
+        assert_eq!(left[0].bytes, 1024 * 1024 - 10);
+        assert!(audit::dropped_events().get("octocat").is_some_and(|n| *n >= 1));
+
+        assert_eq!(usage(&root).unwrap()[0].days, 1);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
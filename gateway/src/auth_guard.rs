@@ -0,0 +1,332 @@
+//! Brute-force resistance for SSH authentication.
+//!
+//! Unlike a plain connection rate limiter, the guard decides *how* to answer an auth attempt:
+//! - rejections are delayed, doubling with each recent failure from the same IP or aimed at
+//!   the same GitHub user;
+//! - IPs with many recent failures only get `publickey` offered (no keyboard-interactive,
+//!   which lets a client make us look up arbitrary GitHub users);
+//! - uncached keys are only verified against GitHub while the IP is under its failure limit and
+//!   lookup budget, so an attacker can't turn the gateway into a GitHub request amplifier.
+//!   Cached keys never touch GitHub and keep working.
+//!
+//! Outcomes are exported as `agentman_auth_*` metrics.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::AuthGuardConfig;
+use crate::metrics;
+
+/// Stop tracking new keys beyond this many; stale entries are pruned first.
+const MAX_TRACKED: usize = 10_000;
+
+/// Recent events for one IP or GitHub user.
+#[derive(Debug, Default)]
+struct Tracker {
+    failures: VecDeque<Instant>,
+    github_lookups: VecDeque<Instant>,
+}
+
+impl Tracker {
+    fn prune(&mut self, cutoff: Instant) {
+        while self.failures.front().is_some_and(|t| *t < cutoff) {
+            self.failures.pop_front();
+        }
+        while self.github_lookups.front().is_some_and(|t| *t < cutoff) {
+            self.github_lookups.pop_front();
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.failures.is_empty() && self.github_lookups.is_empty()
+    }
+}
+
+#[derive(Debug, Default)]
+struct GuardState {
+    by_ip: HashMap<IpAddr, Tracker>,
+    by_user: HashMap<String, Tracker>,
+}
+
+/// What the auth handlers are allowed to do for a given attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuthPolicy {
+    /// Extra delay to apply before sending a rejection.
+    pub reject_delay: Duration,
+
+    /// Only offer `publickey` (withdraw keyboard-interactive).
+    pub restrict_methods: bool,
+
+    /// Whether an uncached key may be verified against GitHub.
+    pub allow_github_lookup: bool,
+}
+
+impl AuthPolicy {
+    fn permissive() -> Self {
+        Self {
+            reject_delay: Duration::ZERO,
+            restrict_methods: false,
+            allow_github_lookup: true,
+        }
+    }
+}
+
+/// Shared auth failure tracker and decision engine.
+pub struct AuthGuard {
+    config: AuthGuardConfig,
+    state: Mutex<GuardState>,
+}
+
+impl AuthGuard {
+    pub fn new(config: AuthGuardConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(GuardState::default()),
+        }
+    }
+
+    /// Decide how to treat an attempt from `ip`, optionally claiming `github_user`.
+    pub fn policy(&self, ip: IpAddr, github_user: Option<&str>) -> AuthPolicy {
+        if !self.config.enabled {
+            return AuthPolicy::permissive();
+        }
+        let now = Instant::now();
+        let mut state = self.lock();
+        let cutoff = self.cutoff(now);
+
+        let (ip_failures, ip_lookups) = match state.by_ip.get_mut(&ip) {
+            Some(t) => {
+                t.prune(cutoff);
+                (t.failures.len() as u32, t.github_lookups.len() as u32)
+            }
+            None => (0, 0),
+        };
+        let user_failures = match github_user.and_then(|u| state.by_user.get_mut(u)) {
+            Some(t) => {
+                t.prune(cutoff);
+                t.failures.len() as u32
+            }
+            None => 0,
+        };
+
+        decide(&self.config, ip_failures, user_failures, ip_lookups)
+    }
+
+    /// Record a failed or denied attempt.
+    pub fn record_failure(&self, ip: IpAddr, github_user: Option<&str>) {
+        if !self.config.enabled {
+            return;
+        }
+        let now = Instant::now();
+        let mut state = self.lock();
+        self.make_room(&mut state, now);
+        state.by_ip.entry(ip).or_default().failures.push_back(now);
+        if let Some(user) = github_user {
+            state
+                .by_user
+                .entry(user.to_string())
+                .or_default()
+                .failures
+                .push_back(now);
+        }
+        self.export_gauges(&state);
+    }
+
+    /// Record a successful login: the IP's failure history is forgiven.
+    pub fn record_success(&self, ip: IpAddr) {
+        if !self.config.enabled {
+            return;
+        }
+        let mut state = self.lock();
+        if let Some(t) = state.by_ip.get_mut(&ip) {
+            t.failures.clear();
+        }
+        self.export_gauges(&state);
+    }
+
+    /// Record `count` GitHub key lookups made on behalf of `ip`.
+    pub fn record_github_lookups(&self, ip: IpAddr, count: usize) {
+        metrics::add(
+            "agentman_auth_github_lookups_total",
+            "GitHub key lookups performed or skipped during authentication.",
+            &[("result", "performed")],
+            count as f64,
+        );
+        if !self.config.enabled {
+            return;
+        }
+        let now = Instant::now();
+        let mut state = self.lock();
+        self.make_room(&mut state, now);
+        let tracker = state.by_ip.entry(ip).or_default();
+        tracker.github_lookups.extend(std::iter::repeat_n(now, count));
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, GuardState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn cutoff(&self, now: Instant) -> Instant {
+        now.checked_sub(Duration::from_secs(self.config.window_secs))
+            .unwrap_or(now)
+    }
+
+    /// Drop trackers with nothing left in the window once the maps grow large.
+    fn make_room(&self, state: &mut GuardState, now: Instant) {
+        if state.by_ip.len() + state.by_user.len() < MAX_TRACKED {
+            return;
+        }
+        let cutoff = self.cutoff(now);
+        state.by_ip.retain(|_, t| {
+            t.prune(cutoff);
+            !t.is_empty()
+        });
+        state.by_user.retain(|_, t| {
+            t.prune(cutoff);
+            !t.is_empty()
+        });
+    }
+
+    fn export_gauges(&self, state: &GuardState) {
+        let suspicious = state
+            .by_ip
+            .values()
+            .filter(|t| t.failures.len() as u32 >= self.config.delay_after_failures)
+            .count();
+        metrics::set(
+            "agentman_auth_suspicious_ips",
+            "Client IPs currently past the auth failure delay threshold.",
+            &[],
+            suspicious as f64,
+        );
+    }
+}
+
+/// Pure decision function, separated from the clock and maps for testing.
+fn decide(
+    config: &AuthGuardConfig,
+    ip_failures: u32,
+    user_failures: u32,
+    ip_lookups: u32,
+) -> AuthPolicy {
+    let failures = ip_failures.max(user_failures);
+    let reject_delay = if failures >= config.delay_after_failures {
+        let doublings = (failures - config.delay_after_failures).min(16);
+        Duration::from_millis(
+            config
+                .base_delay_ms
+                .saturating_mul(1 << doublings)
+                .min(config.max_delay_ms),
+        )
+    } else {
+        Duration::ZERO
+    };
+
+    AuthPolicy {
+        reject_delay,
+        restrict_methods: ip_failures >= config.restrict_methods_after,
+        allow_github_lookup: ip_failures < config.github_failure_limit
+            && ip_lookups < config.github_lookup_budget,
+    }
+}
+
+/// Count an auth outcome (`success`, `failure`, `denied`).
+pub fn record_outcome_metric(outcome: &str) {
+    metrics::inc(
+        "agentman_auth_attempts_total",
+        "Authentication attempts by outcome.",
+        &[("outcome", outcome)],
+    );
+}
+
+/// Count a GitHub lookup that the guard refused to make.
+pub fn record_skipped_lookup_metric() {
+    metrics::inc(
+        "agentman_auth_github_lookups_total",
+        "GitHub key lookups performed or skipped during authentication.",
+        &[("result", "skipped")],
+    );
+}
+
+/// Count a delayed rejection and the time spent delaying.
+pub fn record_delay_metric(delay: Duration) {
+    metrics::inc(
+        "agentman_auth_delayed_rejections_total",
+        "Authentication rejections that were delayed.",
+        &[],
+    );
+    metrics::add(
+        "agentman_auth_delay_seconds_total",
+        "Total time spent delaying authentication rejections.",
+        &[],
+        delay.as_secs_f64(),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decide_thresholds() {
+        let config = AuthGuardConfig::default();
+
+        let clean = decide(&config, 0, 0, 0);
+        assert_eq!(clean, AuthPolicy::permissive());
+
+        let delayed = decide(&config, 3, 0, 0);
+        assert_eq!(delayed.reject_delay, Duration::from_millis(250));
+        assert!(!delayed.restrict_methods);
+
+        let doubled = decide(&config, 0, 5, 0);
+        assert_eq!(doubled.reject_delay, Duration::from_millis(1000));
+        // Failures aimed at a user slow everyone down but don't restrict an innocent IP.
+        assert!(!doubled.restrict_methods);
+        assert!(doubled.allow_github_lookup);
+
+        let attacked = decide(&config, 40, 0, 0);
+        assert_eq!(attacked.reject_delay, Duration::from_millis(8000));
+        assert!(attacked.restrict_methods);
+        assert!(!attacked.allow_github_lookup);
+
+        let over_budget = decide(&config, 0, 0, 60);
+        assert!(!over_budget.allow_github_lookup);
+    }
+
+    #[test]
+    fn test_guard_tracks_and_forgives() {
+        let guard = AuthGuard::new(AuthGuardConfig::default());
+        let ip: IpAddr = "192.0.2.7".parse().unwrap();
+
+        for _ in 0..5 {
+            guard.record_failure(ip, Some("octocat"));
+        }
+        let policy = guard.policy(ip, None);
+        assert!(policy.restrict_methods);
+        assert!(policy.reject_delay > Duration::ZERO);
+
+        // Another IP claiming the same user is delayed but keeps all methods.
+        let other: IpAddr = "198.51.100.1".parse().unwrap();
+        let policy = guard.policy(other, Some("octocat"));
+        assert!(policy.reject_delay > Duration::ZERO);
+        assert!(!policy.restrict_methods);
+
+        guard.record_success(ip);
+        assert_eq!(guard.policy(ip, None), AuthPolicy::permissive());
+    }
+
+    #[test]
+    fn test_disabled_guard_is_permissive() {
+        let guard = AuthGuard::new(AuthGuardConfig {
+            enabled: false,
+            ..Default::default()
+        });
+        let ip: IpAddr = "192.0.2.7".parse().unwrap();
+        for _ in 0..50 {
+            guard.record_failure(ip, None);
+        }
+        assert_eq!(guard.policy(ip, None), AuthPolicy::permissive());
+    }
+}
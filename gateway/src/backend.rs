@@ -0,0 +1,834 @@
+//! Container backend abstraction.
+//!
+//! The SSH server only needs a handful of container operations: provision a sandbox, run execs
+//! in it, and dispatch control commands. [`ContainerBackend`] captures exactly that surface so
+//! `ssh.rs` can run against the Docker-backed [`ContainerManager`] in production and against
+//! the in-memory [`mock::MockBackend`] in tests, without a Docker daemon.
+
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::atomic::AtomicBool;
+
+use anyhow::Result;
+use bollard::exec::StartExecResults;
+use tokio::sync::mpsc;
+
+use crate::docker::ContainerManager;
+use crate::inspect_cache::InspectCache;
+use crate::migration::{self, Manifest};
+use crate::provision;
+use crate::template::{self, Answers, Template};
+use crate::gateway_control::{
+    GatewayControlCommand, GatewayControlExecution, execute_gateway_control_command,
+    render_sandbox_stats_fast, run_backup_action, run_build, run_checkpoint_action, run_migrate,
+    run_ping, run_recreate, run_restart, run_resume_operation, run_scan, run_search,
+    run_stack_action, wait_until_ready,
+};
+
+/// Container operations used by the SSH server.
+pub trait ContainerBackend: Send + Sync + 'static {
+    /// Return the running container for a workspace, creating or starting it if needed.
+    fn get_or_create_container(
+        &self,
+        github_user: &str,
+        project: &str,
+    ) -> impl Future<Output = Result<String>> + Send;
+
+    /// Record the image picked when connecting (`None` = the gateway default) for the
+    /// workspace's containers; returns whether its running container is on another image.
+    fn select_image(
+        &self,
+        github_user: &str,
+        project: &str,
+        image: Option<String>,
+    ) -> impl Future<Output = Result<bool>> + Send;
+
+    /// Clone `url` (from `AGENTMAN_CLONE_URL`) into the workspace if it is new (`[clone]`).
+    fn request_clone(
+        &self,
+        github_user: &str,
+        project: &str,
+        url: &str,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Stop the workspace's container; one that is already stopped or gone is fine. Returns the
+    /// container's name, or `None` if the user has no such sandbox.
+    fn stop_container(
+        &self,
+        github_user: &str,
+        project: &str,
+    ) -> impl Future<Output = Result<Option<String>>> + Send;
+
+    /// Create and start a throwaway sandbox (`[ephemeral]`); returns its container ID.
+    fn create_ephemeral(&self, github_user: &str) -> impl Future<Output = Result<String>> + Send;
+
+    /// Remove a throwaway sandbox created by [`ContainerBackend::create_ephemeral`].
+    fn remove_ephemeral(&self, container_id: &str) -> impl Future<Output = Result<()>> + Send;
+
+    /// Create an exec instance in a container and return its ID.
+    fn create_exec(
+        &self,
+        container_id: &str,
+        cmd: Vec<String>,
+        tty: bool,
+        env: Option<Vec<String>>,
+    ) -> impl Future<Output = Result<String>> + Send;
+
+    /// Start an exec and attach to its stdin/stdout/stderr.
+    fn start_exec(
+        &self,
+        exec_id: &str,
+        tty: bool,
+    ) -> impl Future<Output = Result<StartExecResults>> + Send;
+
+    /// Resize an exec's TTY.
+    fn resize_exec(
+        &self,
+        exec_id: &str,
+        width: u16,
+        height: u16,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Exit code of an exec whose output has ended (`-1` if it never reported one).
+    fn exec_exit_code(&self, exec_id: &str) -> impl Future<Output = Result<i64>> + Send;
+
+    /// The container's IP address, for connecting to it from the gateway host.
+    fn container_ip(&self, container_id: &str) -> impl Future<Output = Result<IpAddr>> + Send;
+
+    /// Signal every process an exec started (tagged with `EXEC_MARKER_ENV=marker`).
+    fn signal_exec_processes(
+        &self,
+        container_id: &str,
+        marker: &str,
+        signal: &str,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Run a gateway control command, or decide how it should be deferred; `inspections` are
+    /// the connection's recent container inspections.
+    fn execute_control(
+        &self,
+        command: GatewayControlCommand,
+        github_user: &str,
+        project: &str,
+        inspections: &InspectCache,
+    ) -> impl Future<Output = GatewayControlExecution> + Send;
+
+    /// Run a deferred control command (`Wait`, `Backup`, `Recreate`, `Restart`) to completion.
+    fn run_deferred_control(
+        &self,
+        execution: GatewayControlExecution,
+        github_user: &str,
+        project: &str,
+        cancelled: &AtomicBool,
+    ) -> impl Future<Output = (u32, String)> + Send;
+
+    /// Render one frame of `agentman stats --watch`.
+    fn render_stats(
+        &self,
+        github_user: &str,
+        project: &str,
+        current: bool,
+    ) -> impl Future<Output = (u32, String)> + Send;
+
+    /// Create a new project and clone a template into it (`agentman new --from`).
+    fn prepare_template(
+        &self,
+        github_user: &str,
+        project: &str,
+        url: &str,
+    ) -> impl Future<Output = Result<Template>> + Send;
+
+    /// Render a prepared template with the collected answers; returns a summary.
+    fn render_template(
+        &self,
+        github_user: &str,
+        project: &str,
+        template: Template,
+        answers: Answers,
+    ) -> impl Future<Output = Result<String>> + Send;
+
+    /// Remove a prepared template that won't be rendered.
+    fn discard_template(&self, template: &Template) -> impl Future<Output = ()> + Send;
+
+    /// Take in a workspace another gateway streams to `upload` (`agentman admin migrate`);
+    /// returns a report for the sending gateway.
+    fn receive_migration(
+        &self,
+        from: &str,
+        manifest: Manifest,
+        upload: mpsc::Receiver<Vec<u8>>,
+    ) -> impl Future<Output = Result<String>> + Send;
+}
+
+impl ContainerBackend for ContainerManager {
+    async fn get_or_create_container(&self, github_user: &str, project: &str) -> Result<String> {
+        ContainerManager::get_or_create_container(self, github_user, project).await
+    }
+
+    async fn select_image(
+        &self,
+        github_user: &str,
+        project: &str,
+        image: Option<String>,
+    ) -> Result<bool> {
+        ContainerManager::select_image(self, github_user, project, image).await
+    }
+
+    async fn request_clone(&self, github_user: &str, project: &str, url: &str) -> Result<()> {
+        provision::plan(self, github_user, project, Some(url)).await
+    }
+
+    async fn stop_container(&self, github_user: &str, project: &str) -> Result<Option<String>> {
+        self.stop_workspace_container(github_user, project).await
+    }
+
+    async fn create_ephemeral(&self, github_user: &str) -> Result<String> {
+        self.create_ephemeral_container(github_user).await
+    }
+
+    async fn remove_ephemeral(&self, container_id: &str) -> Result<()> {
+        self.remove_ephemeral_container(container_id).await
+    }
+
+    async fn create_exec(
+        &self,
+        container_id: &str,
+        cmd: Vec<String>,
+        tty: bool,
+        env: Option<Vec<String>>,
+    ) -> Result<String> {
+        ContainerManager::create_exec(self, container_id, cmd, tty, env).await
+    }
+
+    async fn start_exec(&self, exec_id: &str, tty: bool) -> Result<StartExecResults> {
+        ContainerManager::start_exec(self, exec_id, tty).await
+    }
+
+    async fn resize_exec(&self, exec_id: &str, width: u16, height: u16) -> Result<()> {
+        ContainerManager::resize_exec(self, exec_id, width, height).await
+    }
+
+    async fn exec_exit_code(&self, exec_id: &str) -> Result<i64> {
+        self.wait_exec_exit_code(exec_id).await
+    }
+
+    async fn container_ip(&self, container_id: &str) -> Result<IpAddr> {
+        Ok(self.get_container_ip(container_id).await?.parse()?)
+    }
+
+    async fn signal_exec_processes(
+        &self,
+        container_id: &str,
+        marker: &str,
+        signal: &str,
+    ) -> Result<()> {
+        ContainerManager::signal_exec_processes(self, container_id, marker, signal).await
+    }
+
+    async fn execute_control(
+        &self,
+        command: GatewayControlCommand,
+        github_user: &str,
+        project: &str,
+        inspections: &InspectCache,
+    ) -> GatewayControlExecution {
+        execute_gateway_control_command(command, self, github_user, project, inspections).await
+    }
+
+    async fn run_deferred_control(
+        &self,
+        execution: GatewayControlExecution,
+        github_user: &str,
+        project: &str,
+        cancelled: &AtomicBool,
+    ) -> (u32, String) {
+        match execution {
+            GatewayControlExecution::Wait { timeout, port } => {
+                wait_until_ready(self, github_user, project, timeout, port, cancelled).await
+            }
+            GatewayControlExecution::Backup { action } => {
+                run_backup_action(self, github_user, project, action).await
+            }
+            GatewayControlExecution::Recreate { keep_running, pull } => {
+                run_recreate(self, github_user, project, keep_running, pull).await
+            }
+            GatewayControlExecution::Restart => run_restart(self, github_user, project).await,
+            GatewayControlExecution::Scan => run_scan(self, github_user, project).await,
+            GatewayControlExecution::Search {
+                pattern,
+                project: other,
+                all,
+                ignore_case,
+            } => {
+                let other = other.as_deref();
+                run_search(self, github_user, project, &pattern, other, all, ignore_case).await
+            }
+            GatewayControlExecution::Build {
+                file,
+                no_cache,
+                use_image,
+            } => run_build(self, github_user, project, file.as_deref(), no_cache, use_image).await,
+            GatewayControlExecution::Ping { count, client } => {
+                run_ping(self, github_user, project, count, &client).await
+            }
+            GatewayControlExecution::Checkpoint { action } => {
+                run_checkpoint_action(self, github_user, project, action).await
+            }
+            GatewayControlExecution::Migrate {
+                owner,
+                project: target,
+                node,
+                checkpoint,
+            } => run_migrate(self, github_user, &owner, &target, &node, checkpoint).await,
+            GatewayControlExecution::ResumeOperation {
+                owner,
+                project: target,
+            } => run_resume_operation(self, github_user, &owner, &target).await,
+            GatewayControlExecution::Stack { action } => {
+                run_stack_action(self, github_user, project, action).await
+            }
+            GatewayControlExecution::Immediate {
+                exit_status,
+                output,
+            } => (exit_status, output),
+            GatewayControlExecution::WatchStats { current, .. } => {
+                render_sandbox_stats_fast(self, github_user, project, current).await
+            }
+            // Streamed by the SSH layer.
+            GatewayControlExecution::FollowJob { .. } => {
+                (1, "agentman: jobs logs --follow needs an SSH channel\n".to_string())
+            }
+            GatewayControlExecution::FollowFile { .. } => {
+                (1, "agentman: tail -f needs an SSH channel\n".to_string())
+            }
+        }
+    }
+
+    async fn render_stats(&self, github_user: &str, project: &str, current: bool) -> (u32, String) {
+        render_sandbox_stats_fast(self, github_user, project, current).await
+    }
+
+    async fn prepare_template(
+        &self,
+        github_user: &str,
+        project: &str,
+        url: &str,
+    ) -> Result<Template> {
+        template::prepare(self, github_user, project, url).await
+    }
+
+    async fn render_template(
+        &self,
+        github_user: &str,
+        project: &str,
+        template: Template,
+        answers: Answers,
+    ) -> Result<String> {
+        template::render(self, github_user, project, template, answers).await
+    }
+
+    async fn discard_template(&self, template: &Template) {
+        template::discard(self, template).await
+    }
+
+    async fn receive_migration(
+        &self,
+        from: &str,
+        manifest: Manifest,
+        upload: mpsc::Receiver<Vec<u8>>,
+    ) -> Result<String> {
+        migration::receive(self, from, manifest, upload).await
+    }
+}
+
+/// In-memory backend for tests.
+///
+/// Containers are just IDs in a map. Execs behave like tiny programs chosen by their command:
+/// `bash -c <cmd>` and login shells echo stdin back (so does `socat`, which makes
+/// direct-tcpip channels echo servers), while `bash -c` commands registered with
+/// [`MockBackend::script`] print fixed output and exit with a fixed code.
+#[cfg(test)]
+pub(crate) mod mock {
+    use std::collections::{HashMap, HashSet};
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use anyhow::bail;
+    use bollard::container::LogOutput;
+    use futures::StreamExt;
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::gateway_control::gateway_control_help_text;
+    use crate::state::StateManager;
+    use crate::template::TemplateSpec;
+
+    #[derive(Debug, Clone)]
+    struct Script {
+        stdout: String,
+        stderr: String,
+        exit_code: i64,
+    }
+
+    /// One recorded exec.
+    #[derive(Debug, Clone)]
+    pub(crate) struct MockExec {
+        pub container_id: String,
+        pub cmd: Vec<String>,
+        pub tty: bool,
+        pub env: Vec<String>,
+        pub resized_to: Option<(u16, u16)>,
+        exit_code: i64,
+    }
+
+    pub(crate) struct MockBackend {
+        state: Arc<StateManager>,
+        next_id: AtomicU64,
+        /// (github_user, project) -> container ID.
+        containers: Mutex<HashMap<(String, String), String>>,
+        /// (github_user, project) -> image picked with `select_image`.
+        images: Mutex<HashMap<(String, String), Option<String>>>,
+        /// Container ID -> image picked when it was created.
+        created_with: Mutex<HashMap<String, Option<String>>>,
+        /// (github_user, project) -> URL from `request_clone`.
+        clone_urls: Mutex<HashMap<(String, String), String>>,
+        execs: Mutex<HashMap<String, MockExec>>,
+        scripts: Mutex<HashMap<String, Script>>,
+        /// (container ID, marker, signal) for every `signal_exec_processes` call.
+        signals: Mutex<Vec<(String, String, String)>>,
+        /// Ephemeral sandboxes created and not removed yet.
+        ephemeral: Mutex<Vec<String>>,
+        /// Containers stopped with `stop_container` and not started again yet.
+        stopped: Mutex<HashSet<String>>,
+        /// "stop <id>" and "start <id>" for every container stopped and started again.
+        lifecycle: Mutex<Vec<String>>,
+        /// Make `get_or_create_container` fail (e.g. Docker unavailable).
+        pub fail_create: AtomicBool,
+        /// Make `get_or_create_container` refuse the sandbox with this quota reason.
+        pub over_quota: Mutex<Option<String>>,
+        /// What `prepare_template` returns (an error when unset).
+        template_spec: Mutex<Option<TemplateSpec>>,
+        /// (project, answers) for every rendered template.
+        rendered: Mutex<Vec<(String, Answers)>>,
+        discarded: AtomicU64,
+        /// What `container_ip` returns for every container (an error when unset).
+        pub container_ip: Mutex<Option<IpAddr>>,
+    }
+
+    impl MockBackend {
+        pub fn new(state: Arc<StateManager>) -> Self {
+            Self {
+                state,
+                next_id: AtomicU64::new(1),
+                containers: Mutex::new(HashMap::new()),
+                images: Mutex::new(HashMap::new()),
+                created_with: Mutex::new(HashMap::new()),
+                clone_urls: Mutex::new(HashMap::new()),
+                execs: Mutex::new(HashMap::new()),
+                scripts: Mutex::new(HashMap::new()),
+                signals: Mutex::new(Vec::new()),
+                ephemeral: Mutex::new(Vec::new()),
+                stopped: Mutex::new(HashSet::new()),
+                lifecycle: Mutex::new(Vec::new()),
+                fail_create: AtomicBool::new(false),
+                over_quota: Mutex::new(None),
+                template_spec: Mutex::new(None),
+                rendered: Mutex::new(Vec::new()),
+                discarded: AtomicU64::new(0),
+                container_ip: Mutex::new(None),
+            }
+        }
+
+        /// Make `prepare_template` succeed with this spec, whatever the URL.
+        pub fn set_template(&self, spec: TemplateSpec) {
+            *self.template_spec.lock().unwrap() = Some(spec);
+        }
+
+        pub fn rendered(&self) -> Vec<(String, Answers)> {
+            self.rendered.lock().unwrap().clone()
+        }
+
+        pub fn discarded(&self) -> u64 {
+            self.discarded.load(Ordering::Relaxed)
+        }
+
+        /// Make `bash -c <command>` print `stdout`/`stderr` and exit with `exit_code`.
+        pub fn script(&self, command: &str, stdout: &str, stderr: &str, exit_code: i64) {
+            self.scripts.lock().unwrap().insert(
+                command.to_string(),
+                Script {
+                    stdout: stdout.to_string(),
+                    stderr: stderr.to_string(),
+                    exit_code,
+                },
+            );
+        }
+
+        pub fn container(&self, github_user: &str, project: &str) -> Option<String> {
+            self.containers
+                .lock()
+                .unwrap()
+                .get(&(github_user.to_string(), project.to_string()))
+                .cloned()
+        }
+
+        /// The image a workspace's new containers are created from (`None` = the default).
+        pub fn image(&self, github_user: &str, project: &str) -> Option<String> {
+            let key = (github_user.to_string(), project.to_string());
+            self.images.lock().unwrap().get(&key).cloned().flatten()
+        }
+
+        /// The repository requested for a workspace.
+        pub fn clone_url(&self, github_user: &str, project: &str) -> Option<String> {
+            let key = (github_user.to_string(), project.to_string());
+            self.clone_urls.lock().unwrap().get(&key).cloned()
+        }
+
+        pub fn execs(&self) -> Vec<MockExec> {
+            let mut execs: Vec<_> = self
+                .execs
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(id, e)| (id.clone(), e.clone()))
+                .collect();
+            execs.sort_by_key(|(id, _)| id.trim_start_matches("exec-").parse::<u64>().unwrap_or(0));
+            execs.into_iter().map(|(_, e)| e).collect()
+        }
+
+        pub fn signals(&self) -> Vec<(String, String, String)> {
+            self.signals.lock().unwrap().clone()
+        }
+
+        pub fn ephemeral(&self) -> Vec<String> {
+            self.ephemeral.lock().unwrap().clone()
+        }
+
+        pub fn lifecycle(&self) -> Vec<String> {
+            self.lifecycle.lock().unwrap().clone()
+        }
+
+        fn next_id(&self, prefix: &str) -> String {
+            format!("{prefix}-{}", self.next_id.fetch_add(1, Ordering::Relaxed))
+        }
+    }
+
+    impl ContainerBackend for MockBackend {
+        async fn get_or_create_container(
+            &self,
+            github_user: &str,
+            project: &str,
+        ) -> Result<String> {
+            if self.fail_create.load(Ordering::Relaxed) {
+                bail!("Failed to create container: mock backend is unavailable");
+            }
+            if let Some(reason) = self.over_quota.lock().unwrap().clone() {
+                return Err(crate::docker::QuotaExceeded(reason).into());
+            }
+            let key = (github_user.to_string(), project.to_string());
+            let mut containers = self.containers.lock().unwrap();
+            if let Some(id) = containers.get(&key) {
+                if self.stopped.lock().unwrap().remove(id) {
+                    self.lifecycle.lock().unwrap().push(format!("start {id}"));
+                }
+                return Ok(id.clone());
+            }
+            let id = self.next_id("container");
+            let image = self.images.lock().unwrap().get(&key).cloned().flatten();
+            self.created_with.lock().unwrap().insert(id.clone(), image);
+            containers.insert(key, id.clone());
+            Ok(id)
+        }
+
+        async fn select_image(
+            &self,
+            github_user: &str,
+            project: &str,
+            image: Option<String>,
+        ) -> Result<bool> {
+            let key = (github_user.to_string(), project.to_string());
+            self.images.lock().unwrap().insert(key.clone(), image.clone());
+            let container = self.containers.lock().unwrap().get(&key).cloned();
+            let created_with = self.created_with.lock().unwrap();
+            Ok(container.is_some_and(|id| created_with.get(&id) != Some(&image)))
+        }
+
+        async fn request_clone(&self, github_user: &str, project: &str, url: &str) -> Result<()> {
+            crate::template::validate_url(url)?;
+            let key = (github_user.to_string(), project.to_string());
+            if !self.containers.lock().unwrap().contains_key(&key) {
+                self.clone_urls.lock().unwrap().insert(key, url.to_string());
+            }
+            Ok(())
+        }
+
+        async fn stop_container(
+            &self,
+            github_user: &str,
+            project: &str,
+        ) -> Result<Option<String>> {
+            let Some(ws) = self.state.get_workspace(github_user, project).await else {
+                return Ok(None);
+            };
+            if let Some(id) = self.container(github_user, project)
+                && self.stopped.lock().unwrap().insert(id.clone())
+            {
+                self.lifecycle.lock().unwrap().push(format!("stop {id}"));
+            }
+            Ok(Some(ws.container_name))
+        }
+
+        async fn create_ephemeral(&self, _github_user: &str) -> Result<String> {
+            if self.fail_create.load(Ordering::Relaxed) {
+                bail!("Failed to create container: mock backend is unavailable");
+            }
+            let id = self.next_id("ephemeral");
+            self.ephemeral.lock().unwrap().push(id.clone());
+            Ok(id)
+        }
+
+        async fn remove_ephemeral(&self, container_id: &str) -> Result<()> {
+            self.ephemeral.lock().unwrap().retain(|id| id != container_id);
+            Ok(())
+        }
+
+        async fn create_exec(
+            &self,
+            container_id: &str,
+            cmd: Vec<String>,
+            tty: bool,
+            env: Option<Vec<String>>,
+        ) -> Result<String> {
+            if !self
+                .containers
+                .lock()
+                .unwrap()
+                .values()
+                .any(|c| c == container_id)
+                && !self.ephemeral.lock().unwrap().iter().any(|c| c == container_id)
+            {
+                bail!("Failed to create exec: no such container {container_id}");
+            }
+            let id = self.next_id("exec");
+            self.execs.lock().unwrap().insert(
+                id.clone(),
+                MockExec {
+                    container_id: container_id.to_string(),
+                    cmd,
+                    tty,
+                    env: env.unwrap_or_default(),
+                    resized_to: None,
+                    exit_code: 0,
+                },
+            );
+            Ok(id)
+        }
+
+        async fn start_exec(&self, exec_id: &str, _tty: bool) -> Result<StartExecResults> {
+            let cmd = match self.execs.lock().unwrap().get(exec_id) {
+                Some(exec) => exec.cmd.clone(),
+                None => bail!("Failed to start exec: no such exec {exec_id}"),
+            };
+
+            let script = match cmd.as_slice() {
+                [bash, flag, command] if bash == "/bin/bash" && flag == "-c" => {
+                    self.scripts.lock().unwrap().get(command).cloned()
+                }
+                _ => None,
+            };
+
+            if let Some(script) = script {
+                if let Some(exec) = self.execs.lock().unwrap().get_mut(exec_id) {
+                    exec.exit_code = script.exit_code;
+                }
+                let mut chunks = Vec::new();
+                if !script.stdout.is_empty() {
+                    chunks.push(Ok(LogOutput::StdOut {
+                        message: script.stdout.into_bytes().into(),
+                    }));
+                }
+                if !script.stderr.is_empty() {
+                    chunks.push(Ok(LogOutput::StdErr {
+                        message: script.stderr.into_bytes().into(),
+                    }));
+                }
+                return Ok(StartExecResults::Attached {
+                    output: futures::stream::iter(chunks).boxed(),
+                    input: Box::pin(tokio::io::sink()),
+                });
+            }
+
+            // Everything else echoes stdin until EOF.
+            let (input, reader) = tokio::io::duplex(64 * 1024);
+            let output = futures::stream::unfold(reader, |mut reader| async move {
+                let mut buf = vec![0u8; 8192];
+                match reader.read(&mut buf).await {
+                    Ok(0) | Err(_) => None,
+                    Ok(n) => {
+                        buf.truncate(n);
+                        Some((
+                            Ok(LogOutput::StdOut {
+                                message: buf.into(),
+                            }),
+                            reader,
+                        ))
+                    }
+                }
+            });
+            Ok(StartExecResults::Attached {
+                output: output.boxed(),
+                input: Box::pin(input),
+            })
+        }
+
+        async fn resize_exec(&self, exec_id: &str, width: u16, height: u16) -> Result<()> {
+            match self.execs.lock().unwrap().get_mut(exec_id) {
+                Some(exec) => {
+                    exec.resized_to = Some((width, height));
+                    Ok(())
+                }
+                None => bail!("Failed to resize exec: no such exec {exec_id}"),
+            }
+        }
+
+        async fn exec_exit_code(&self, exec_id: &str) -> Result<i64> {
+            match self.execs.lock().unwrap().get(exec_id) {
+                Some(exec) => Ok(exec.exit_code),
+                None => bail!("Failed to inspect exec: no such exec {exec_id}"),
+            }
+        }
+
+        async fn container_ip(&self, container_id: &str) -> Result<IpAddr> {
+            match *self.container_ip.lock().unwrap() {
+                Some(ip) => Ok(ip),
+                None => bail!("Container {container_id} has no IP address"),
+            }
+        }
+
+        async fn signal_exec_processes(
+            &self,
+            container_id: &str,
+            marker: &str,
+            signal: &str,
+        ) -> Result<()> {
+            self.signals.lock().unwrap().push((
+                container_id.to_string(),
+                marker.to_string(),
+                signal.to_string(),
+            ));
+            Ok(())
+        }
+
+        async fn execute_control(
+            &self,
+            command: GatewayControlCommand,
+            github_user: &str,
+            project: &str,
+            _inspections: &InspectCache,
+        ) -> GatewayControlExecution {
+            match command {
+                GatewayControlCommand::Help => GatewayControlExecution::Immediate {
+                    exit_status: 0,
+                    output: gateway_control_help_text(),
+                },
+                GatewayControlCommand::Destroy {
+                    yes: false,
+                    dry_run: false,
+                    keep_workspace: false,
+                    ..
+                } => GatewayControlExecution::Immediate {
+                    exit_status: 2,
+                    output: "agentman: refusing to delete the workspace without --yes\n"
+                        .to_string(),
+                },
+                GatewayControlCommand::Destroy { project: target, .. } => {
+                    let project = target.as_deref().unwrap_or(project);
+                    let removed = self
+                        .containers
+                        .lock()
+                        .unwrap()
+                        .remove(&(github_user.to_string(), project.to_string()));
+                    let _ = self.state.remove_workspace(github_user, project).await;
+                    GatewayControlExecution::Immediate {
+                        exit_status: 0,
+                        output: match removed {
+                            Some(id) => format!("agentman: destroyed {id}\n"),
+                            None => "agentman: nothing to destroy\n".to_string(),
+                        },
+                    }
+                }
+                other => GatewayControlExecution::Immediate {
+                    exit_status: 1,
+                    output: format!("agentman: {other:?} is not supported by the mock backend\n"),
+                },
+            }
+        }
+
+        async fn run_deferred_control(
+            &self,
+            _execution: GatewayControlExecution,
+            _github_user: &str,
+            _project: &str,
+            _cancelled: &AtomicBool,
+        ) -> (u32, String) {
+            (
+                1,
+                "agentman: not supported by the mock backend\n".to_string(),
+            )
+        }
+
+        async fn render_stats(
+            &self,
+            _github_user: &str,
+            _project: &str,
+            _current: bool,
+        ) -> (u32, String) {
+            (0, String::new())
+        }
+
+        async fn prepare_template(
+            &self,
+            github_user: &str,
+            project: &str,
+            url: &str,
+        ) -> Result<Template> {
+            let Some(spec) = self.template_spec.lock().unwrap().clone() else {
+                bail!("git clone {url} failed: repository not found");
+            };
+            let container_id = self.get_or_create_container(github_user, project).await?;
+            Ok(Template {
+                url: url.to_string(),
+                container_id,
+                root: std::path::PathBuf::from("/nonexistent"),
+                spec,
+            })
+        }
+
+        async fn render_template(
+            &self,
+            _github_user: &str,
+            project: &str,
+            _template: Template,
+            answers: Answers,
+        ) -> Result<String> {
+            self.rendered
+                .lock()
+                .unwrap()
+                .push((project.to_string(), answers));
+            Ok(format!("agentman: created {project}\n"))
+        }
+
+        async fn discard_template(&self, _template: &Template) {
+            self.discarded.fetch_add(1, Ordering::Relaxed);
+        }
+
+        async fn receive_migration(
+            &self,
+            _from: &str,
+            _manifest: Manifest,
+            _upload: mpsc::Receiver<Vec<u8>>,
+        ) -> Result<String> {
+            bail!("not supported by the mock backend")
+        }
+    }
+}
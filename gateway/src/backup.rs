@@ -0,0 +1,423 @@
+//! Incremental workspace backups.
+//!
+//! Two backends, both differential so repeated backups of large workspaces are cheap:
+//! - `rsync`: one directory per snapshot under `<repository>/<github_user>/<project>/`, created
+//!   with `--link-dest` against the previous snapshot so unchanged files are hard links.
+//! - `restic`: content-defined chunking and deduplication in a restic repository; snapshots
+//!   are tagged with the workspace so each user only sees their own.
+//!
+//! Snapshots can be restored by id or to a point in time (the latest snapshot at or before it).
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use tokio::process::Command;
+use tracing::{info, warn};
+
+use crate::config::{BackupBackend, BackupConfig};
+use crate::docker::ContainerManager;
+use crate::notify;
+
+/// Snapshot directory names for the rsync backend (sortable, filesystem-safe).
+const RSYNC_SNAPSHOT_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// One point-in-time copy of a workspace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    /// Backend identifier (rsync directory name or restic snapshot id).
+    pub id: String,
+    pub time: DateTime<Utc>,
+}
+
+/// Take a snapshot of `workspace_path`.
+pub async fn create_snapshot(
+    config: &BackupConfig,
+    github_user: &str,
+    project: &str,
+    workspace_path: &Path,
+) -> Result<Snapshot> {
+    if !workspace_path.is_dir() {
+        bail!("workspace directory does not exist: {}", workspace_path.display());
+    }
+    match backend(config)? {
+        BackupBackend::Rsync => rsync_create(config, github_user, project, workspace_path).await,
+        BackupBackend::Restic => restic_create(config, github_user, project, workspace_path).await,
+    }
+}
+
+/// List a workspace's snapshots, oldest first.
+pub async fn list_snapshots(
+    config: &BackupConfig,
+    github_user: &str,
+    project: &str,
+) -> Result<Vec<Snapshot>> {
+    let mut snapshots = match backend(config)? {
+        BackupBackend::Rsync => rsync_list(config, github_user, project).await?,
+        BackupBackend::Restic => restic_list(config, github_user, project).await?,
+    };
+    snapshots.sort_by_key(|s| s.time);
+    Ok(snapshots)
+}
+
+/// Replace the contents of `workspace_path` with `snapshot`.
+pub async fn restore_snapshot(
+    config: &BackupConfig,
+    github_user: &str,
+    project: &str,
+    workspace_path: &Path,
+    snapshot: &Snapshot,
+) -> Result<()> {
+    tokio::fs::create_dir_all(workspace_path)
+        .await
+        .with_context(|| format!("Failed to create {}", workspace_path.display()))?;
+    match backend(config)? {
+        BackupBackend::Rsync => {
+            if snapshot.id.contains('/') || snapshot.id.starts_with('.') {
+                bail!("invalid snapshot id: {}", snapshot.id);
+            }
+            let src = rsync_dir(config, github_user, project).join(&snapshot.id);
+            run(Command::new("rsync")
+                .arg("-a")
+                .arg("--delete")
+                .arg(format!("{}/", src.display()))
+                .arg(format!("{}/", workspace_path.display())))
+            .await
+            .map(|_| ())
+        }
+        BackupBackend::Restic => {
+            // Snapshots store the absolute workspace path; restore just that subtree in place.
+            run(restic(config)
+                .arg("restore")
+                .arg(format!("{}:{}", snapshot.id, workspace_path.display()))
+                .arg("--target")
+                .arg(workspace_path)
+                .arg("--delete"))
+            .await
+            .map(|_| ())
+        }
+    }
+}
+
+/// Pick a snapshot: `latest`, an id (or unique id prefix), or a point in time
+/// (RFC 3339, `YYYY-MM-DDTHH:MM`, or `YYYY-MM-DD` meaning end of that day, all UTC).
+pub fn select_snapshot<'a>(snapshots: &'a [Snapshot], spec: &str) -> Result<&'a Snapshot> {
+    if spec == "latest" {
+        return snapshots.last().ok_or_else(|| anyhow!("no snapshots yet"));
+    }
+
+    if let Some(at) = parse_point_in_time(spec) {
+        return snapshots
+            .iter()
+            .rev()
+            .find(|s| s.time <= at)
+            .ok_or_else(|| anyhow!("no snapshot at or before {}", at.to_rfc3339()));
+    }
+
+    let matches: Vec<&Snapshot> = snapshots.iter().filter(|s| s.id.starts_with(spec)).collect();
+    match matches.as_slice() {
+        [one] => Ok(one),
+        [] => Err(anyhow!("no snapshot matches '{spec}'")),
+        _ => Err(anyhow!("'{spec}' matches {} snapshots; use a longer id", matches.len())),
+    }
+}
+
+fn parse_point_in_time(spec: &str) -> Option<DateTime<Utc>> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(spec) {
+        return Some(t.with_timezone(&Utc));
+    }
+    if let Ok(t) = NaiveDateTime::parse_from_str(spec, "%Y-%m-%dT%H:%M") {
+        return Some(Utc.from_utc_datetime(&t));
+    }
+    if let Ok(d) = NaiveDate::parse_from_str(spec, "%Y-%m-%d") {
+        return d.and_hms_opt(23, 59, 59).map(|t| Utc.from_utc_datetime(&t));
+    }
+    None
+}
+
+/// Back up every known workspace every `interval_hours`, if configured.
+pub fn spawn_scheduler(container_manager: Arc<ContainerManager>) {
+    let config = &container_manager.config().backup;
+    let (Some(_), Some(hours)) = (config.backend, config.interval_hours) else {
+        return;
+    };
+    let interval = Duration::from_secs(hours.max(1) * 3600);
+    info!("Scheduled workspace backups every {}h", hours.max(1));
+
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(interval);
+        tick.tick().await;
+        loop {
+            tick.tick().await;
+            let state = container_manager.state();
+            let config = container_manager.config();
+            let (mut total, mut failed, mut last_error) = (0, 0, String::new());
+            for github_user in state.list_github_users().await {
+                for ws in state.list_workspaces(&github_user).await {
+                    let path = config.workspace_path(&ws.github_user, &ws.project);
+                    total += 1;
+                    match create_snapshot(&config.backup, &ws.github_user, &ws.project, &path).await
+                    {
+                        Ok(s) => info!(
+                            "Backed up {}/{} as snapshot {}",
+                            ws.github_user, ws.project, s.id
+                        ),
+                        Err(e) => {
+                            warn!(
+                                "Scheduled backup of {}/{} failed: {:#}",
+                                ws.github_user, ws.project, e
+                            );
+                            failed += 1;
+                            last_error = format!("{}/{}: {:#}", ws.github_user, ws.project, e);
+                        }
+                    }
+                }
+            }
+            if failed > 0 {
+                let error =
+                    format!("{failed} of {total} workspace backups failed (last: {last_error})");
+                notify::task_failed(&config.notify, "backup", &error).await;
+            } else {
+                notify::task_succeeded(&config.notify, "backup").await;
+            }
+        }
+    });
+}
+
+fn backend(config: &BackupConfig) -> Result<BackupBackend> {
+    config
+        .backend
+        .ok_or_else(|| anyhow!("backups are not enabled on this gateway"))
+}
+
+// --- rsync backend ---
+
+fn rsync_dir(config: &BackupConfig, github_user: &str, project: &str) -> PathBuf {
+    Path::new(&config.repository).join(github_user).join(project)
+}
+
+async fn rsync_create(
+    config: &BackupConfig,
+    github_user: &str,
+    project: &str,
+    workspace_path: &Path,
+) -> Result<Snapshot> {
+    let dir = rsync_dir(config, github_user, project);
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .with_context(|| format!("Failed to create backup directory {}", dir.display()))?;
+
+    let previous = rsync_list(config, github_user, project).await?;
+    let time = Utc::now();
+    let id = time.format(RSYNC_SNAPSHOT_FORMAT).to_string();
+    let partial = dir.join(format!(".{id}.partial"));
+    let dest = dir.join(&id);
+
+    let mut cmd = Command::new("rsync");
+    cmd.arg("-a").arg("--delete");
+    if let Some(prev) = previous.iter().max_by_key(|s| s.time) {
+        cmd.arg(format!("--link-dest={}", dir.join(&prev.id).display()));
+    }
+    cmd.arg(format!("{}/", workspace_path.display()))
+        .arg(format!("{}/", partial.display()));
+    run(&mut cmd).await?;
+
+    tokio::fs::rename(&partial, &dest)
+        .await
+        .with_context(|| format!("Failed to finalize snapshot {}", dest.display()))?;
+
+    if let Some(keep) = config.keep_last {
+        let mut all = rsync_list(config, github_user, project).await?;
+        all.sort_by_key(|s| s.time);
+        let excess = all.len().saturating_sub(keep.max(1));
+        for old in &all[..excess] {
+            if let Err(e) = tokio::fs::remove_dir_all(dir.join(&old.id)).await {
+                warn!("Failed to prune snapshot {}: {}", old.id, e);
+            }
+        }
+    }
+
+    Ok(Snapshot { id, time })
+}
+
+async fn rsync_list(config: &BackupConfig, github_user: &str, project: &str) -> Result<Vec<Snapshot>> {
+    let dir = rsync_dir(config, github_user, project);
+    let mut entries = match tokio::fs::read_dir(&dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", dir.display())),
+    };
+    let mut out = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if let Ok(t) = NaiveDateTime::parse_from_str(&name, RSYNC_SNAPSHOT_FORMAT) {
+            out.push(Snapshot {
+                id: name,
+                time: Utc.from_utc_datetime(&t),
+            });
+        }
+    }
+    Ok(out)
+}
+
+// --- restic backend ---
+
+fn restic_tag(github_user: &str, project: &str) -> String {
+    format!("agentman:{github_user}/{project}")
+}
+
+fn restic(config: &BackupConfig) -> Command {
+    let mut cmd = Command::new("restic");
+    cmd.arg("--repo").arg(&config.repository);
+    if let Some(ref pw) = config.password_file {
+        cmd.env("RESTIC_PASSWORD_FILE", pw);
+    }
+    cmd
+}
+
+async fn restic_create(
+    config: &BackupConfig,
+    github_user: &str,
+    project: &str,
+    workspace_path: &Path,
+) -> Result<Snapshot> {
+    // Initialize the repository on first use.
+    if run(restic(config).arg("cat").arg("config")).await.is_err() {
+        run(restic(config).arg("init"))
+            .await
+            .context("Failed to initialize restic repository")?;
+    }
+
+    let tag = restic_tag(github_user, project);
+    let out = run(restic(config)
+        .arg("backup")
+        .arg("--json")
+        .arg("--host")
+        .arg("agentman")
+        .arg("--tag")
+        .arg(&tag)
+        .arg(workspace_path))
+    .await?;
+
+    // The last JSON line is the summary with the new snapshot id.
+    let id = out
+        .lines()
+        .rev()
+        .filter_map(|l| serde_json::from_str::<serde_json::Value>(l).ok())
+        .find(|v| v["message_type"] == "summary")
+        .and_then(|v| v["snapshot_id"].as_str().map(str::to_string))
+        .ok_or_else(|| anyhow!("restic backup did not report a snapshot id"))?;
+
+    if let Some(keep) = config.keep_last {
+        let res = run(restic(config)
+            .arg("forget")
+            .arg("--tag")
+            .arg(&tag)
+            .arg("--keep-last")
+            .arg(keep.max(1).to_string())
+            .arg("--prune"))
+        .await;
+        if let Err(e) = res {
+            warn!("Failed to prune restic snapshots for {}: {:#}", tag, e);
+        }
+    }
+
+    Ok(Snapshot {
+        id: id.chars().take(8).collect(),
+        time: Utc::now(),
+    })
+}
+
+async fn restic_list(config: &BackupConfig, github_user: &str, project: &str) -> Result<Vec<Snapshot>> {
+    let out = run(restic(config)
+        .arg("snapshots")
+        .arg("--json")
+        .arg("--tag")
+        .arg(restic_tag(github_user, project)))
+    .await?;
+    parse_restic_snapshots(&out)
+}
+
+fn parse_restic_snapshots(json: &str) -> Result<Vec<Snapshot>> {
+    let values: Vec<serde_json::Value> =
+        serde_json::from_str(json).context("Failed to parse restic snapshot list")?;
+    Ok(values
+        .iter()
+        .filter_map(|v| {
+            let id = v["short_id"].as_str()?.to_string();
+            let time = DateTime::parse_from_rfc3339(v["time"].as_str()?).ok()?;
+            Some(Snapshot {
+                id,
+                time: time.with_timezone(&Utc),
+            })
+        })
+        .collect())
+}
+
+/// Run a command to completion, returning stdout or an error with its stderr.
+async fn run(cmd: &mut Command) -> Result<String> {
+    let program = cmd.as_std().get_program().to_string_lossy().to_string();
+    let output = cmd
+        .output()
+        .await
+        .with_context(|| format!("Failed to run {program} (is it installed?)"))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!(
+            "{program} exited with {}: {}",
+            output.status,
+            stderr.trim().lines().last().unwrap_or("")
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snap(id: &str, time: &str) -> Snapshot {
+        Snapshot {
+            id: id.to_string(),
+            time: DateTime::parse_from_rfc3339(time).unwrap().with_timezone(&Utc),
+        }
+    }
+
+    #[test]
+    fn test_select_snapshot() {
+        let snaps = vec![
+            snap("a1b2c3d4", "2026-10-01T10:00:00Z"),
+            snap("a1ffee00", "2026-10-02T10:00:00Z"),
+            snap("9f8e7d6c", "2026-10-03T10:00:00Z"),
+        ];
+
+        assert_eq!(select_snapshot(&snaps, "latest").unwrap().id, "9f8e7d6c");
+        assert_eq!(select_snapshot(&snaps, "9f8e").unwrap().id, "9f8e7d6c");
+        assert!(select_snapshot(&snaps, "a1").is_err());
+        assert!(select_snapshot(&snaps, "zzz").is_err());
+
+        // Point in time: latest snapshot at or before.
+        assert_eq!(select_snapshot(&snaps, "2026-10-02T12:00").unwrap().id, "a1ffee00");
+        assert_eq!(select_snapshot(&snaps, "2026-10-02").unwrap().id, "a1ffee00");
+        assert_eq!(
+            select_snapshot(&snaps, "2026-10-03T10:00:00+00:00").unwrap().id,
+            "9f8e7d6c"
+        );
+        assert!(select_snapshot(&snaps, "2026-09-30").is_err());
+        assert!(select_snapshot(&[], "latest").is_err());
+    }
+
+    #[test]
+    fn test_parse_restic_snapshots() {
+        let json = r#"[
+            {"time":"2026-10-01T10:00:00.123456789+02:00","short_id":"4f1a2b3c","id":"4f1a2b3c99"},
+            {"short_id":"missing-time"}
+        ]"#;
+        let snaps = parse_restic_snapshots(json).unwrap();
+        assert_eq!(snaps.len(), 1);
+        assert_eq!(snaps[0].id, "4f1a2b3c");
+        assert_eq!(snaps[0].time.to_rfc3339(), "2026-10-01T08:00:00.123456789+00:00");
+    }
+}
@@ -0,0 +1,412 @@
+//! Sandbox benchmark (`agentman bench`).
+//!
+//! Each phase is a separate exec in the container, timed by the gateway; the time of an empty
+//! exec is subtracted so that Docker's overhead doesn't count. The figures are for comparing
+//! sandboxes and hosts rather than absolute: reads usually come from the page cache, and the
+//! network phase depends on the endpoint.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, bail};
+use chrono::Utc;
+use serde::Serialize;
+
+use crate::config::BenchConfig;
+use crate::docker::ContainerManager;
+use crate::gateway_control::format_bytes;
+
+const MIB: f64 = 1024.0 * 1024.0;
+
+/// Small files created by the file phase.
+const FILE_COUNT: u32 = 2000;
+
+/// The benchmark phases; argument 1 names the phase, the rest are its arguments.
+const SCRIPT: &str = r#"
+phase=$1
+shift
+case "$phase" in
+noop) ;;
+cpus) getconf _NPROCESSORS_ONLN 2>/dev/null || nproc ;;
+cpu)
+    command -v sha256sum >/dev/null 2>&1 || { echo "sha256sum not found" >&2; exit 1; }
+    i=0
+    while [ "$i" -lt "$1" ]; do
+        dd if=/dev/zero bs=1048576 count="$2" 2>/dev/null | sha256sum >/dev/null &
+        i=$((i + 1))
+    done
+    wait ;;
+write)
+    mkdir -p "$(dirname "$1")" || exit 1
+    dd if=/dev/zero of="$1" bs=1048576 count="$2" conv=fsync 2>/dev/null ;;
+read)
+    if dd if="$1" of=/dev/null bs=1048576 iflag=direct 2>/dev/null; then
+        echo direct
+    else
+        dd if="$1" of=/dev/null bs=1048576 2>/dev/null && echo cached
+    fi ;;
+files)
+    mkdir -p "$1" && cd "$1" || exit 1
+    i=0
+    while [ "$i" -lt "$2" ]; do
+        : > "f$i" || exit 1
+        i=$((i + 1))
+    done ;;
+clean) rm -rf "$@" ;;
+net)
+    command -v curl >/dev/null 2>&1 || exit 127
+    curl -sS -o /dev/null --max-time "$2" \
+        -w '%{http_code} %{time_connect} %{time_starttransfer} %{time_total} %{size_download}\n' "$1" ;;
+*) exit 2 ;;
+esac
+"#;
+
+/// The Docker host the sandbox runs on.
+#[derive(Debug, Clone, Serialize)]
+pub struct HostInfo {
+    pub name: String,
+    pub os: String,
+    pub kernel: String,
+    pub cpus: i64,
+    pub memory_bytes: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NetworkResult {
+    pub url: String,
+    pub http_status: u16,
+    pub connect_ms: f64,
+    pub first_byte_ms: f64,
+    pub bytes: u64,
+    pub bytes_per_sec: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<HostInfo>,
+    /// CPUs the sandbox sees (its cpuset; a CPU quota shows in the all-CPU rate instead).
+    pub cpus: u32,
+    pub cpu_single_bytes_per_sec: u64,
+    pub cpu_all_bytes_per_sec: u64,
+    pub disk_write_bytes_per_sec: u64,
+    pub disk_read_bytes_per_sec: u64,
+    /// The read was served by the page cache (the filesystem doesn't support direct I/O).
+    pub disk_read_cached: bool,
+    pub files_per_sec: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub network: Option<NetworkResult>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub network_error: Option<String>,
+}
+
+fn command(phase: &str, args: &[String]) -> Vec<String> {
+    let mut cmd = vec![
+        "sh".to_string(),
+        "-c".to_string(),
+        SCRIPT.to_string(),
+        "sh".to_string(),
+        phase.to_string(),
+    ];
+    cmd.extend(args.iter().cloned());
+    cmd
+}
+
+/// Run one phase; returns how long it took, its exit code and what it printed. Only the
+/// network phase may exit with 127 (no curl).
+async fn phase(
+    container_manager: &ContainerManager,
+    container_id: &str,
+    name: &str,
+    args: &[String],
+) -> Result<(Duration, i64, String)> {
+    let start = Instant::now();
+    let output = container_manager
+        .run_exec(container_id, command(name, args))
+        .await?;
+    let elapsed = start.elapsed();
+    let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if output.exit_code != 0 && !(name == "net" && output.exit_code == 127) {
+        bail!(
+            "{name} phase exited with {}: {}",
+            output.exit_code,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok((elapsed, output.exit_code, stdout))
+}
+
+/// `amount` per second of `elapsed` minus the exec `overhead`.
+fn rate(amount: f64, elapsed: Duration, overhead: Duration) -> u64 {
+    let secs = elapsed
+        .saturating_sub(overhead)
+        .max(Duration::from_millis(1))
+        .as_secs_f64();
+    (amount / secs) as u64
+}
+
+async fn host_info(container_manager: &ContainerManager) -> Option<HostInfo> {
+    let info = container_manager.docker().info().await.ok()?;
+    Some(HostInfo {
+        name: info.name.unwrap_or_default(),
+        os: info.operating_system.unwrap_or_default(),
+        kernel: info.kernel_version.unwrap_or_default(),
+        cpus: info.ncpu.unwrap_or_default(),
+        memory_bytes: info.mem_total.unwrap_or_default(),
+    })
+}
+
+/// Benchmark the container; its scratch files in `/workspace/.agentman` are removed afterwards,
+/// also when the time limit is hit.
+pub async fn run(
+    container_manager: &ContainerManager,
+    config: &BenchConfig,
+    container_id: &str,
+) -> Result<Report> {
+    let scratch = format!(
+        "/workspace/.agentman/bench-{}",
+        Utc::now().timestamp_millis()
+    );
+    let limit = Duration::from_secs(config.timeout_secs);
+    let result = tokio::time::timeout(
+        limit,
+        run_phases(container_manager, config, container_id, &scratch),
+    )
+    .await;
+    let clean = [scratch.clone(), format!("{scratch}.files")];
+    if let Err(e) = phase(container_manager, container_id, "clean", &clean).await {
+        tracing::warn!(
+            "Failed to remove benchmark files in {}: {:#}",
+            container_id,
+            e
+        );
+    }
+    result.map_err(|_| anyhow::anyhow!("timed out after {}s", config.timeout_secs))?
+}
+
+async fn run_phases(
+    container_manager: &ContainerManager,
+    config: &BenchConfig,
+    container_id: &str,
+    scratch: &str,
+) -> Result<Report> {
+    let run = |name: &'static str, args: Vec<String>| async move {
+        phase(container_manager, container_id, name, &args).await
+    };
+
+    // The quickest of a few empty execs is Docker's share of every phase.
+    let mut overhead = Duration::MAX;
+    for _ in 0..3 {
+        overhead = overhead.min(run("noop", vec![]).await?.0);
+    }
+
+    let (_, _, cpus) = run("cpus", vec![]).await?;
+    let cpus: u32 = cpus.parse().unwrap_or(1).max(1);
+    let cpu_mb = config.cpu_mb.to_string();
+    let (single, _, _) = run("cpu", vec!["1".to_string(), cpu_mb.clone()]).await?;
+    let (all, _, _) = run("cpu", vec![cpus.to_string(), cpu_mb]).await?;
+    let cpu_bytes = config.cpu_mb as f64 * MIB;
+
+    let disk_bytes = config.disk_mb as f64 * MIB;
+    let (write, _, _) = run(
+        "write",
+        vec![scratch.to_string(), config.disk_mb.to_string()],
+    )
+    .await?;
+    let (read, _, how) = run("read", vec![scratch.to_string()]).await?;
+    let (files, _, _) = run(
+        "files",
+        vec![format!("{scratch}.files"), FILE_COUNT.to_string()],
+    )
+    .await?;
+
+    let mut report = Report {
+        host: host_info(container_manager).await,
+        cpus,
+        cpu_single_bytes_per_sec: rate(cpu_bytes, single, overhead),
+        cpu_all_bytes_per_sec: rate(cpu_bytes * cpus as f64, all, overhead),
+        disk_write_bytes_per_sec: rate(disk_bytes, write, overhead),
+        disk_read_bytes_per_sec: rate(disk_bytes, read, overhead),
+        disk_read_cached: how != "direct",
+        files_per_sec: rate(FILE_COUNT as f64, files, overhead),
+        network: None,
+        network_error: None,
+    };
+
+    if let Some(url) = &config.network_url {
+        let max_time = config.timeout_secs.min(30).to_string();
+        match run("net", vec![url.clone(), max_time]).await {
+            Ok((_, 127, _)) => {
+                report.network_error = Some("curl is not installed in the sandbox".to_string())
+            }
+            Ok((_, _, out)) => match parse_curl(url, &out) {
+                Ok(network) => report.network = Some(network),
+                Err(e) => report.network_error = Some(format!("{e:#}")),
+            },
+            Err(e) => report.network_error = Some(format!("{e:#}")),
+        }
+    }
+    Ok(report)
+}
+
+/// Parse curl's `-w '%{http_code} %{time_connect} %{time_starttransfer} %{time_total}
+/// %{size_download}'`.
+fn parse_curl(url: &str, out: &str) -> Result<NetworkResult> {
+    let fields: Vec<&str> = out.split_whitespace().collect();
+    let [status, connect, first_byte, total, bytes] = fields[..] else {
+        bail!("unexpected curl output {out:?}");
+    };
+    let secs = |v: &str| {
+        v.parse::<f64>()
+            .with_context(|| format!("bad curl time {v:?}"))
+    };
+    let http_status: u16 = status.parse().context("bad curl status")?;
+    if !(200..400).contains(&http_status) {
+        bail!("{url} answered HTTP {http_status}");
+    }
+    let total = secs(total)?;
+    let first_byte = secs(first_byte)?;
+    let bytes = bytes.parse::<f64>().context("bad curl size")? as u64;
+    Ok(NetworkResult {
+        url: url.to_string(),
+        http_status,
+        connect_ms: secs(connect)? * 1000.0,
+        first_byte_ms: first_byte * 1000.0,
+        bytes,
+        bytes_per_sec: (bytes as f64 / total.max(0.001)) as u64,
+    })
+}
+
+fn per_sec(bytes: u64) -> String {
+    format!("{}/s", format_bytes(bytes))
+}
+
+/// Human-readable report.
+pub fn format_report(report: &Report) -> String {
+    let mut out = String::new();
+    if let Some(host) = &report.host {
+        out.push_str(&format!(
+            "host:     {} ({}, kernel {}, {} CPUs, {})\n",
+            host.name,
+            host.os,
+            host.kernel,
+            host.cpus,
+            format_bytes(host.memory_bytes.max(0) as u64)
+        ));
+    }
+    let cpus = if report.cpus == 1 { "CPU" } else { "CPUs" };
+    out.push_str(&format!(
+        "cpu:      sha256 {} on 1 CPU, {} on {} {cpus}\n",
+        per_sec(report.cpu_single_bytes_per_sec),
+        per_sec(report.cpu_all_bytes_per_sec),
+        report.cpus
+    ));
+    out.push_str(&format!(
+        "disk:     write {} (fsync), read {}{} in /workspace\n",
+        per_sec(report.disk_write_bytes_per_sec),
+        per_sec(report.disk_read_bytes_per_sec),
+        if report.disk_read_cached {
+            " (page cache)"
+        } else {
+            ""
+        }
+    ));
+    out.push_str(&format!(
+        "files:    {} small files created per second\n",
+        report.files_per_sec
+    ));
+    match (&report.network, &report.network_error) {
+        (Some(net), _) => out.push_str(&format!(
+            "network:  {} (HTTP {}): connect {:.0} ms, first byte {:.0} ms, {} ({})\n",
+            net.url,
+            net.http_status,
+            net.connect_ms,
+            net.first_byte_ms,
+            per_sec(net.bytes_per_sec),
+            format_bytes(net.bytes)
+        )),
+        (None, Some(e)) => out.push_str(&format!("network:  failed: {e}\n")),
+        (None, None) => out.push_str("network:  skipped (no [bench] network_url)\n"),
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_curl() {
+        let url = "https://example.com/10MB";
+        let net = parse_curl(url, "200 0.012 0.048 0.148 10485760").unwrap();
+        assert_eq!(net.http_status, 200);
+        assert_eq!(net.connect_ms.round(), 12.0);
+        assert_eq!(net.first_byte_ms.round(), 48.0);
+        assert_eq!(net.bytes, 10 * 1024 * 1024);
+        assert_eq!(net.bytes_per_sec, (10.0 * MIB / 0.148) as u64);
+
+        assert!(parse_curl(url, "404 0.01 0.02 0.02 9").is_err());
+        assert!(parse_curl(url, "000 0 0 0").is_err());
+    }
+
+    #[test]
+    fn test_rate() {
+        let ms = Duration::from_millis;
+        assert_eq!(rate(1000.0, ms(1100), ms(100)), 1000);
+        // Phases quicker than the overhead estimate don't divide by zero.
+        assert_eq!(rate(1.0, ms(50), ms(100)), 1000);
+    }
+
+    #[test]
+    fn test_script_phases() {
+        let dir = std::env::temp_dir().join(format!("agentman-bench-test-{}", std::process::id()));
+        let scratch = dir.join("bench").to_string_lossy().to_string();
+        let files = format!("{scratch}.files");
+        let run = |name: &str, args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+            let cmd = command(name, &args);
+            let output = std::process::Command::new(&cmd[0])
+                .args(&cmd[1..])
+                .output()
+                .unwrap();
+            assert!(output.status.success(), "{name}: {output:?}");
+            String::from_utf8_lossy(&output.stdout).trim().to_string()
+        };
+
+        assert!(run("cpus", &[]).parse::<u32>().unwrap() >= 1);
+        run("cpu", &["2", "1"]);
+        run("write", &[&scratch, "2"]);
+        assert_eq!(std::fs::metadata(&scratch).unwrap().len(), 2 * 1024 * 1024);
+        assert!(matches!(
+            run("read", &[&scratch]).as_str(),
+            "direct" | "cached"
+        ));
+        run("files", &[&files, "10"]);
+        assert_eq!(std::fs::read_dir(&files).unwrap().count(), 10);
+        run("clean", &[&scratch, &files]);
+        assert!(std::fs::read_dir(&dir).unwrap().next().is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_format_report() {
+        let report = Report {
+            host: None,
+            cpus: 4,
+            cpu_single_bytes_per_sec: 512 * 1024 * 1024,
+            cpu_all_bytes_per_sec: 2048 * 1024 * 1024,
+            disk_write_bytes_per_sec: 300 * 1024 * 1024,
+            disk_read_bytes_per_sec: 3 * 1024 * 1024 * 1024,
+            disk_read_cached: true,
+            files_per_sec: 45000,
+            network: None,
+            network_error: None,
+        };
+        assert_eq!(
+            format_report(&report),
+            "cpu:      sha256 512.0 MiB/s on 1 CPU, 2.0 GiB/s on 4 CPUs\n\
+             disk:     write 300.0 MiB/s (fsync), read 3.0 GiB/s (page cache) in /workspace\n\
+             files:    45000 small files created per second\n\
+             network:  skipped (no [bench] network_url)\n"
+        );
+    }
+}
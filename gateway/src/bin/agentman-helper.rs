@@ -0,0 +1,520 @@
+//! `agentman-helper`: a small, statically linked binary the gateway copies into every sandbox
+//! (see `helper.rs`), so forwarding and friends don't depend on what the image ships.
+//!
+//! Only uses the standard library (plus a few libc calls for the PTY), to keep it small and easy
+//! to link statically:
+//!
+//! ```text
+//! RUSTFLAGS="-C target-feature=+crt-static" \
+//!     cargo build --release --bin agentman-helper --target x86_64-unknown-linux-gnu
+//! ```
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitCode, ExitStatus};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+const USAGE: &str = "usage: agentman-helper <command> [args...]
+
+commands:
+  tcp <host> <port>
+      Bridge stdin/stdout to a TCP connection (half-closes it when stdin ends).
+  unix <path>
+      Bridge stdin/stdout to a Unix socket connection, like tcp.
+  pty [--size <cols>x<rows>] -- <command...>
+      Run a command on a new pseudo-terminal, relaying it over stdin/stdout.
+  watch [--interval-ms <ms>] <path...>
+      Print `created|modified|removed <path>` for files under the paths as they change.
+  supervise [--max-restarts <n>] [--backoff-ms <ms>] -- <command...>
+      Run a command, restarting it (with doubling backoff) until it exits 0.
+  notify <message...>
+      Send a message to the sandbox owner's notifier (`-` or no message: read it from stdin).
+  exec <project> -- <command...>
+      Run a command in the owner's sandbox of another project (`agentman mesh allow`).
+  version
+      Print the helper version.";
+
+/// Exit status for usage errors.
+const USAGE_EXIT: u8 = 2;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("tcp") => tcp(&args[1..]),
+        Some("unix") => unix(&args[1..]),
+        Some("pty") => pty(&args[1..]),
+        Some("watch") => watch(&args[1..]),
+        Some("supervise") => supervise(&args[1..]),
+        Some("notify") => notify(&args[1..]),
+        Some("exec") => exec(&args[1..]),
+        Some("version" | "--version") => {
+            println!("agentman-helper {}", env!("CARGO_PKG_VERSION"));
+            Ok(0)
+        }
+        _ => Err(Error::Usage),
+    };
+    match result {
+        Ok(code) => ExitCode::from(code),
+        Err(Error::Usage) => {
+            eprintln!("{USAGE}");
+            ExitCode::from(USAGE_EXIT)
+        }
+        Err(Error::Failed(message)) => {
+            eprintln!("agentman-helper: {message}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+enum Error {
+    Usage,
+    Failed(String),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Failed(e.to_string())
+    }
+}
+
+/// Split `[options...] -- <command...>`; the command must not be empty.
+fn split_command(args: &[String]) -> Result<(&[String], &[String]), Error> {
+    match args.iter().position(|a| a == "--") {
+        Some(i) if i + 1 < args.len() => Ok((&args[..i], &args[i + 1..])),
+        _ => Err(Error::Usage),
+    }
+}
+
+/// Value of `--name <value>` in `options`, parsed.
+fn option<T: std::str::FromStr>(options: &[String], name: &str) -> Result<Option<T>, Error> {
+    match options.iter().position(|a| a == name) {
+        Some(i) => match options.get(i + 1).map(|v| v.parse()) {
+            Some(Ok(value)) => Ok(Some(value)),
+            _ => Err(Error::Usage),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Copy `from` to `to` until EOF, flushing after every read so interactive streams aren't held
+/// back by buffering.
+fn relay(mut from: impl Read, mut to: impl Write) -> io::Result<()> {
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = match from.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        to.write_all(&buf[..n])?;
+        to.flush()?;
+    }
+}
+
+/// Exit code to report for a child's status (128 + signal, like shells).
+fn exit_code(status: ExitStatus) -> u8 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code as u8,
+        (None, Some(signal)) => 128u8.saturating_add(signal as u8),
+        (None, None) => 1,
+    }
+}
+
+fn tcp(args: &[String]) -> Result<u8, Error> {
+    let [host, port] = args else {
+        return Err(Error::Usage);
+    };
+    let port: u16 = port.parse().map_err(|_| Error::Usage)?;
+    let stream = TcpStream::connect((host.as_str(), port))
+        .map_err(|e| Error::Failed(format!("connect to {host}:{port}: {e}")))?;
+    let upstream = stream.try_clone()?;
+    // Not joined: stdin may stay open after the peer closes, and the process exits then anyway.
+    thread::spawn(move || {
+        let _ = relay(io::stdin().lock(), &upstream);
+        let _ = upstream.shutdown(Shutdown::Write);
+    });
+    relay(&stream, io::stdout().lock())?;
+    Ok(0)
+}
+
+fn unix(args: &[String]) -> Result<u8, Error> {
+    let [path] = args else {
+        return Err(Error::Usage);
+    };
+    let stream = UnixStream::connect(path)
+        .map_err(|e| Error::Failed(format!("connect to {path}: {e}")))?;
+    let upstream = stream.try_clone()?;
+    thread::spawn(move || {
+        let _ = relay(io::stdin().lock(), &upstream);
+        let _ = upstream.shutdown(Shutdown::Write);
+    });
+    relay(&stream, io::stdout().lock())?;
+    Ok(0)
+}
+
+mod sys {
+    use std::os::raw::{c_char, c_int, c_ulong};
+
+    pub const O_RDWR: c_int = 0o2;
+    pub const O_NOCTTY: c_int = 0o400;
+    pub const TIOCSCTTY: c_ulong = 0x540E;
+    pub const TIOCSWINSZ: c_ulong = 0x5414;
+
+    #[repr(C)]
+    pub struct Winsize {
+        pub ws_row: u16,
+        pub ws_col: u16,
+        pub ws_xpixel: u16,
+        pub ws_ypixel: u16,
+    }
+
+    unsafe extern "C" {
+        pub fn posix_openpt(flags: c_int) -> c_int;
+        pub fn grantpt(fd: c_int) -> c_int;
+        pub fn unlockpt(fd: c_int) -> c_int;
+        pub fn ptsname_r(fd: c_int, buf: *mut c_char, buflen: usize) -> c_int;
+        pub fn setsid() -> c_int;
+        pub fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+    }
+}
+
+/// Open a PTY pair: the master and the slave's path.
+fn open_pty() -> io::Result<(File, PathBuf)> {
+    // SAFETY: plain libc calls on a descriptor we own; `name` outlives `ptsname_r`.
+    unsafe {
+        let fd = sys::posix_openpt(sys::O_RDWR | sys::O_NOCTTY);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let master = File::from_raw_fd(fd);
+        if sys::grantpt(fd) != 0 || sys::unlockpt(fd) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut name = [0 as std::os::raw::c_char; 128];
+        let rc = sys::ptsname_r(fd, name.as_mut_ptr(), name.len());
+        if rc != 0 {
+            return Err(io::Error::from_raw_os_error(rc));
+        }
+        let name = std::ffi::CStr::from_ptr(name.as_ptr());
+        Ok((master, PathBuf::from(name.to_string_lossy().into_owned())))
+    }
+}
+
+fn pty(args: &[String]) -> Result<u8, Error> {
+    let (options, command) = split_command(args)?;
+    let size: Option<String> = option(options, "--size")?;
+    let (master, slave_path) = open_pty()?;
+
+    if let Some(size) = size {
+        let (cols, rows) = size.split_once('x').ok_or(Error::Usage)?;
+        let winsize = sys::Winsize {
+            ws_col: cols.parse().map_err(|_| Error::Usage)?,
+            ws_row: rows.parse().map_err(|_| Error::Usage)?,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        // SAFETY: TIOCSWINSZ reads a `struct winsize` from the pointer.
+        if unsafe {
+            sys::ioctl(
+                master.as_raw_fd(),
+                sys::TIOCSWINSZ,
+                &winsize as *const sys::Winsize,
+            )
+        } != 0
+        {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
+
+    let slave = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(sys::O_NOCTTY)
+        .open(&slave_path)?;
+    let mut cmd = Command::new(&command[0]);
+    cmd.args(&command[1..])
+        .stdin(slave.try_clone()?)
+        .stdout(slave.try_clone()?)
+        .stderr(slave);
+    // SAFETY: only async-signal-safe calls between fork and exec.
+    unsafe {
+        cmd.pre_exec(|| {
+            // New session with the PTY (already on fd 0) as its controlling terminal.
+            if sys::setsid() < 0 || sys::ioctl(0, sys::TIOCSCTTY, 0) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = cmd
+        .spawn()
+        .map_err(|e| Error::Failed(format!("{}: {e}", command[0])))?;
+    // Close our copies of the slave, so reads on the master fail once the child is gone.
+    drop(cmd);
+
+    let input = master.try_clone()?;
+    thread::spawn(move || relay(io::stdin().lock(), input));
+    // EIO on the master just means every slave descriptor is closed.
+    let _ = relay(&master, io::stdout().lock());
+    Ok(exit_code(child.wait()?))
+}
+
+type Snapshot = BTreeMap<PathBuf, (SystemTime, u64)>;
+
+/// Modification time and size of every file under `path` (symlinks are not followed).
+fn scan(path: &Path, snapshot: &mut Snapshot) {
+    let Ok(meta) = std::fs::symlink_metadata(path) else {
+        return;
+    };
+    if meta.is_dir() {
+        if let Ok(entries) = std::fs::read_dir(path) {
+            for entry in entries.flatten() {
+                scan(&entry.path(), snapshot);
+            }
+        }
+    } else {
+        let mtime = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        snapshot.insert(path.to_path_buf(), (mtime, meta.len()));
+    }
+}
+
+/// Changes between two snapshots, as `(kind, path)`.
+fn diff<'a>(before: &'a Snapshot, after: &'a Snapshot) -> Vec<(&'static str, &'a Path)> {
+    let mut changes = Vec::new();
+    for (path, stamp) in after {
+        match before.get(path) {
+            None => changes.push(("created", path.as_path())),
+            Some(old) if old != stamp => changes.push(("modified", path.as_path())),
+            Some(_) => {}
+        }
+    }
+    for path in before.keys() {
+        if !after.contains_key(path) {
+            changes.push(("removed", path.as_path()));
+        }
+    }
+    changes
+}
+
+/// Polls rather than using inotify, so it also works on bind mounts from other hosts and
+/// needs no watch limits raised.
+fn watch(args: &[String]) -> Result<u8, Error> {
+    let interval: u64 = option(args, "--interval-ms")?.unwrap_or(500);
+    let paths: Vec<&String> = {
+        let skip = args
+            .iter()
+            .position(|a| a == "--interval-ms")
+            .map(|i| [i, i + 1]);
+        args.iter()
+            .enumerate()
+            .filter(|(i, _)| skip.is_none_or(|s| !s.contains(i)))
+            .map(|(_, a)| a)
+            .collect()
+    };
+    if paths.is_empty() {
+        return Err(Error::Usage);
+    }
+    let snapshot = || {
+        let mut snapshot = Snapshot::new();
+        for path in &paths {
+            scan(Path::new(path), &mut snapshot);
+        }
+        snapshot
+    };
+
+    let mut before = snapshot();
+    let mut out = io::stdout().lock();
+    loop {
+        thread::sleep(Duration::from_millis(interval));
+        let after = snapshot();
+        for (kind, path) in diff(&before, &after) {
+            writeln!(out, "{kind} {}", path.display())?;
+        }
+        out.flush()?;
+        before = after;
+    }
+}
+
+/// Backoff resets once a run lasted at least this long.
+const SUPERVISE_STABLE_RUN: Duration = Duration::from_secs(60);
+const SUPERVISE_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+fn supervise(args: &[String]) -> Result<u8, Error> {
+    let (options, command) = split_command(args)?;
+    let max_restarts: Option<u32> = option(options, "--max-restarts")?;
+    let initial = Duration::from_millis(option(options, "--backoff-ms")?.unwrap_or(1000));
+
+    let mut backoff = initial;
+    let mut restarts = 0u32;
+    loop {
+        let started = Instant::now();
+        let status = Command::new(&command[0])
+            .args(&command[1..])
+            .status()
+            .map_err(|e| Error::Failed(format!("{}: {e}", command[0])))?;
+        if status.success() {
+            return Ok(0);
+        }
+        if max_restarts.is_some_and(|max| restarts >= max) {
+            eprintln!("agentman-helper: {} {status}; giving up", command[0]);
+            return Ok(exit_code(status));
+        }
+        if started.elapsed() >= SUPERVISE_STABLE_RUN {
+            backoff = initial;
+        }
+        eprintln!(
+            "agentman-helper: {} {status}; restarting in {}ms",
+            command[0],
+            backoff.as_millis()
+        );
+        thread::sleep(backoff);
+        backoff = (backoff * 2).min(SUPERVISE_MAX_BACKOFF);
+        restarts += 1;
+    }
+}
+
+/// The gateway's notify socket, unless `AGENTMAN_NOTIFY_SOCK` says otherwise.
+const NOTIFY_SOCKET: &str = "/workspace/.agentman-notify.sock";
+
+fn notify(args: &[String]) -> Result<u8, Error> {
+    let message = match args {
+        [] => String::new(),
+        [dash] if dash == "-" => String::new(),
+        words => words.join(" "),
+    };
+    let message = if message.is_empty() {
+        let mut input = String::new();
+        io::stdin().read_to_string(&mut input)?;
+        input
+    } else {
+        message
+    };
+    let path = std::env::var("AGENTMAN_NOTIFY_SOCK").unwrap_or_else(|_| NOTIFY_SOCKET.to_string());
+    let mut stream = UnixStream::connect(&path)
+        .map_err(|e| Error::Failed(format!("connect to {path}: {e}")))?;
+    stream.write_all(message.as_bytes())?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    match reply.trim_end() {
+        "ok" => Ok(0),
+        reply => Err(Error::Failed(
+            reply.strip_prefix("error: ").unwrap_or(reply).to_string(),
+        )),
+    }
+}
+
+const MESH_SOCKET: &str = "/workspace/.agentman-mesh.sock";
+
+/// Reply frame kinds of the mesh socket (see the gateway's `mesh.rs`).
+const FRAME_STDOUT: u8 = 1;
+const FRAME_STDERR: u8 = 2;
+const FRAME_EXIT: u8 = 3;
+const FRAME_ERROR: u8 = 4;
+
+fn exec(args: &[String]) -> Result<u8, Error> {
+    let (options, command) = split_command(args)?;
+    let [project] = options else {
+        return Err(Error::Usage);
+    };
+    let mut request = Vec::new();
+    for field in std::iter::once(project).chain(command) {
+        if field.contains('\0') {
+            return Err(Error::Failed("arguments can't contain NUL bytes".to_string()));
+        }
+        request.extend_from_slice(field.as_bytes());
+        request.push(0);
+    }
+    request.push(0);
+
+    let path = std::env::var("AGENTMAN_MESH_SOCK").unwrap_or_else(|_| MESH_SOCKET.to_string());
+    let mut stream = UnixStream::connect(&path)
+        .map_err(|e| Error::Failed(format!("connect to {path}: {e}")))?;
+    stream.write_all(&request)?;
+    let upstream = stream.try_clone()?;
+    // Not joined, as in `tcp`.
+    thread::spawn(move || {
+        let _ = relay(io::stdin().lock(), &upstream);
+        let _ = upstream.shutdown(Shutdown::Write);
+    });
+
+    loop {
+        let mut header = [0u8; 5];
+        if let Err(e) = stream.read_exact(&mut header) {
+            return Err(Error::Failed(format!("the gateway closed the connection: {e}")));
+        }
+        let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        let mut payload = vec![0u8; len];
+        stream.read_exact(&mut payload)?;
+        match header[0] {
+            FRAME_STDOUT => {
+                let mut stdout = io::stdout().lock();
+                stdout.write_all(&payload)?;
+                stdout.flush()?;
+            }
+            FRAME_STDERR => io::stderr().write_all(&payload)?,
+            FRAME_EXIT => {
+                let status = payload
+                    .try_into()
+                    .map(i32::from_be_bytes)
+                    .map_err(|_| Error::Failed("malformed exit status".to_string()))?;
+                return Ok(u8::try_from(status).unwrap_or(1));
+            }
+            FRAME_ERROR => {
+                return Err(Error::Failed(String::from_utf8_lossy(&payload).into_owned()));
+            }
+            kind => return Err(Error::Failed(format!("unknown reply frame {kind}"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let t0 = SystemTime::UNIX_EPOCH;
+        let t1 = t0 + Duration::from_secs(1);
+        let before = Snapshot::from([
+            (PathBuf::from("/w/a"), (t0, 1)),
+            (PathBuf::from("/w/b"), (t0, 1)),
+        ]);
+        let after = Snapshot::from([
+            (PathBuf::from("/w/a"), (t1, 1)),
+            (PathBuf::from("/w/c"), (t0, 1)),
+        ]);
+        assert_eq!(
+            diff(&before, &after),
+            vec![
+                ("modified", Path::new("/w/a")),
+                ("created", Path::new("/w/c")),
+                ("removed", Path::new("/w/b")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_command() {
+        let args: Vec<String> = ["--size", "80x24", "--", "bash", "-l"]
+            .map(String::from)
+            .to_vec();
+        let (options, command) = split_command(&args).ok().unwrap();
+        assert_eq!(options, ["--size", "80x24"]);
+        assert_eq!(command, ["bash", "-l"]);
+        assert_eq!(
+            option::<String>(options, "--size").ok().unwrap().as_deref(),
+            Some("80x24")
+        );
+        assert!(split_command(&args[..3]).is_err());
+    }
+}
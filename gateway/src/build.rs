@@ -0,0 +1,442 @@
+//! Sandbox images built from a workspace Dockerfile (`agentman build`).
+//!
+//! The directory holding the Dockerfile (`.agentman/` by default) is archived on the gateway
+//! host, without following symlinks, and sent to the Docker daemon as the build context. The
+//! build runs with `[build]`'s memory, CPU, network and time limits, and the image is tagged
+//! `<repository>/<github_user>:<project>`: that tag is only ever allowed for its own workspace,
+//! so one user's build can't end up in someone else's sandbox. `--use` records it as the
+//! workspace's image choice, like `agentman image set`.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, anyhow, bail};
+use bollard::body_full;
+use bollard::query_parameters::{BuildImageOptionsBuilder, RemoveImageOptions};
+use futures::StreamExt;
+use tokio::io::AsyncReadExt;
+use tokio::process::Command;
+use tracing::{info, warn};
+
+use crate::config::BuildConfig;
+use crate::docker::ContainerManager;
+use crate::host_fs::{self, Handle};
+use crate::metrics;
+
+/// Label on built images, holding `<github_user>/<project>`.
+pub const BUILD_LABEL: &str = "agentman.build";
+
+/// Build log lines shown when a build fails.
+const FAILURE_TAIL_LINES: usize = 40;
+
+const DURATION_BUCKETS: &[f64] = &[
+    10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1200.0, 1800.0, 3600.0,
+];
+
+/// Workspaces with a build in progress.
+static RUNNING: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(Default::default);
+
+/// A successful build.
+pub struct Built {
+    pub tag: String,
+    pub image_id: String,
+    pub size_bytes: Option<i64>,
+    /// `Step n/m : ...` lines of the build log.
+    pub steps: Vec<String>,
+    pub elapsed: Duration,
+}
+
+/// The workspace's build in progress; ends it when dropped.
+struct Slot(String);
+
+impl Slot {
+    fn claim(config: &BuildConfig, workspace: String) -> Result<Self> {
+        let mut running = RUNNING.lock().unwrap();
+        if running.contains(&workspace) {
+            bail!("a build of this project is already running");
+        }
+        if running.len() >= config.max_concurrent {
+            bail!("too many builds are running on this gateway; try again later");
+        }
+        running.insert(workspace.clone());
+        Ok(Slot(workspace))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        RUNNING.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Tag of the workspace's built image. GitHub logins are case-insensitive; Docker repository
+/// names must be lowercase.
+pub fn tag(config: &BuildConfig, github_user: &str, project: &str) -> String {
+    format!(
+        "{}/{}:{}",
+        config.repository,
+        github_user.to_lowercase(),
+        project
+    )
+}
+
+/// Whether `image` is the workspace's own built image.
+pub fn is_own_image(config: &BuildConfig, image: &str, github_user: &str, project: &str) -> bool {
+    config.enabled && image == tag(config, github_user, project)
+}
+
+/// A Dockerfile path relative to `/workspace` (a leading `/workspace/` is accepted).
+pub fn relative_path(file: &str) -> Result<PathBuf> {
+    let file = file.strip_prefix("/workspace/").unwrap_or(file);
+    let path = Path::new(file);
+    let plain = path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    if file.is_empty() || !plain {
+        bail!("{file:?} must be a path inside /workspace");
+    }
+    Ok(path.iter().filter(|part| *part != ".").collect())
+}
+
+/// Check that every `FROM` names an allowed base image or an earlier stage.
+pub fn check_base_images(config: &BuildConfig, dockerfile: &str) -> Result<()> {
+    if config.base_images.is_empty() {
+        return Ok(());
+    }
+    let mut stages: Vec<String> = Vec::new();
+    for line in dockerfile.lines() {
+        let mut words = line.split_whitespace();
+        if !words.next().is_some_and(|w| w.eq_ignore_ascii_case("FROM")) {
+            continue;
+        }
+        let mut words = words.skip_while(|w| w.starts_with("--"));
+        let Some(image) = words.next() else {
+            bail!("FROM without an image");
+        };
+        let allowed = config.base_images.iter().any(|b| b == image)
+            || stages.iter().any(|s| s.eq_ignore_ascii_case(image));
+        if !allowed {
+            bail!(
+                "base image {image} is not allowed on this gateway (allowed: {})",
+                config.base_images.join(", ")
+            );
+        }
+        if let (Some(as_), Some(name)) = (words.next(), words.next())
+            && as_.eq_ignore_ascii_case("AS")
+        {
+            stages.push(name.to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Build the workspace's Dockerfile (`file`, or `[build] dockerfile`) and tag the image.
+pub async fn build(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    file: Option<&str>,
+    no_cache: bool,
+) -> Result<Built> {
+    let config = &container_manager.config().build;
+    let ws = container_manager
+        .get_workspace(github_user, project)
+        .await
+        .ok_or_else(|| anyhow!("no sandbox found for {github_user}/{project}"))?;
+    let relative = relative_path(file.unwrap_or(&config.dockerfile))?;
+    let _slot = Slot::claim(config, format!("{github_user}/{project}"))?;
+
+    let Some(name) = relative.file_name().map(|name| name.to_string_lossy().into_owned()) else {
+        bail!("/workspace/{} is not a file", relative.display());
+    };
+    let workspace = ws.host_workspace_path.clone();
+    let (context_dir, content) =
+        tokio::task::spawn_blocking(move || open_dockerfile(&workspace, &relative))
+            .await
+            .context("open task failed")??;
+    check_base_images(config, &content)?;
+    let archive = context_archive(&context_dir, config.max_context_mb * 1024 * 1024).await?;
+
+    let tag = tag(config, github_user, project);
+    let previous = image_id(container_manager, &tag).await;
+    let labels = HashMap::from([(BUILD_LABEL, format!("{github_user}/{project}"))]);
+    let memory = (config.memory_mb * 1024 * 1024) as i32;
+    let options = BuildImageOptionsBuilder::new()
+        .dockerfile(&name)
+        .t(&tag)
+        .rm(true)
+        .forcerm(true)
+        .nocache(no_cache)
+        .memory(memory)
+        .memswap(memory)
+        .cpuperiod(100_000)
+        .cpuquota((config.cpus * 100_000.0) as i32)
+        .networkmode(if config.network { "default" } else { "none" })
+        .labels(&labels)
+        .build();
+
+    info!("Building {} for {}/{}", tag, github_user, project);
+    let started = Instant::now();
+    let mut log = String::new();
+    let docker = container_manager.docker();
+    let stream = async {
+        let mut stream = docker.build_image(options, None, Some(body_full(archive.into())));
+        while let Some(item) = stream.next().await {
+            let info = item.context("Docker build failed")?;
+            if let Some(text) = info.stream {
+                log.push_str(&text);
+            }
+            let error = info.error_detail.and_then(|d| d.message).or(info.error);
+            if let Some(error) = error {
+                return Err(anyhow!("{}", error.trim()));
+            }
+        }
+        Ok(())
+    };
+    let result = match tokio::time::timeout(Duration::from_secs(config.timeout_secs), stream).await
+    {
+        Ok(result) => result,
+        Err(_) => Err(anyhow!("timed out after {}s", config.timeout_secs)),
+    };
+    let outcome = if result.is_ok() { "success" } else { "failure" };
+    metrics::inc(
+        "agentman_image_builds_total",
+        "Image builds from workspace Dockerfiles (agentman build), by outcome.",
+        &[("outcome", outcome)],
+    );
+    if let Err(e) = result {
+        warn!("Build of {} failed: {:#}", tag, e);
+        return Err(anyhow!("{e:#}\n{}", tail(&log, FAILURE_TAIL_LINES)));
+    }
+    metrics::observe(
+        "agentman_image_build_duration_seconds",
+        "Time taken by successful image builds.",
+        DURATION_BUCKETS,
+        &[],
+        started.elapsed().as_secs_f64(),
+    );
+
+    let image = docker
+        .inspect_image(&tag)
+        .await
+        .with_context(|| format!("Failed to inspect {tag}"))?;
+    let image_id = image.id.unwrap_or_default();
+    info!(
+        "Built {} ({}) for {}/{}",
+        tag, image_id, github_user, project
+    );
+    // The previous build lost its tag; containers still using it keep it alive.
+    if let Some(previous) = previous.filter(|p| *p != image_id) {
+        let _ = docker
+            .remove_image(&previous, None::<RemoveImageOptions>, None)
+            .await;
+    }
+    Ok(Built {
+        tag,
+        image_id,
+        size_bytes: image.size,
+        steps: log
+            .lines()
+            .filter(|l| l.starts_with("Step "))
+            .map(str::to_string)
+            .collect(),
+        elapsed: started.elapsed(),
+    })
+}
+
+/// Remove the workspace's built image (on destroy); a container still using it keeps it.
+pub async fn remove_image(container_manager: &ContainerManager, github_user: &str, project: &str) {
+    let tag = tag(&container_manager.config().build, github_user, project);
+    match container_manager
+        .docker()
+        .remove_image(&tag, None::<RemoveImageOptions>, None)
+        .await
+    {
+        Ok(_) => info!("Removed built image {}", tag),
+        Err(bollard::errors::Error::DockerResponseServerError {
+            status_code: 404, ..
+        }) => {}
+        Err(e) => warn!("Failed to remove built image {}: {}", tag, e),
+    }
+}
+
+async fn image_id(container_manager: &ContainerManager, image: &str) -> Option<String> {
+    container_manager
+        .docker()
+        .inspect_image(image)
+        .await
+        .ok()
+        .and_then(|i| i.id)
+}
+
+/// The directory of the Dockerfile `relative` in `workspace`, and the Dockerfile's content.
+/// The sandbox can swap any part of the path for a symlink, so it is walked without following
+/// them ([`host_fs`]) and the directory is only used through the handle afterwards.
+fn open_dockerfile(workspace: &Path, relative: &Path) -> Result<(Handle, String)> {
+    let shown = format!("/workspace/{}", relative.display());
+    let (Some(parent), Some(name)) = (relative.parent(), relative.file_name()) else {
+        bail!("{shown} is not a file");
+    };
+    let opened = host_fs::walk(workspace, parent).and_then(|dir| {
+        let file = dir.child(name)?;
+        Ok((dir, file))
+    });
+    let (dir, file) = match opened {
+        Ok(opened) => opened,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => bail!("no Dockerfile at {shown}"),
+        Err(e) => {
+            let context = format!("Failed to open {shown} (symlinks aren't followed)");
+            return Err(e).context(context);
+        }
+    };
+    if !dir.metadata()?.is_dir() {
+        bail!("{shown} goes through a symlink; name the directory it points to");
+    }
+    if !file.metadata()?.is_file() {
+        bail!("{shown} is not a regular file");
+    }
+    let mut content = String::new();
+    file.reopen()
+        .and_then(|mut f| f.read_to_string(&mut content))
+        .with_context(|| format!("Failed to read {shown}"))?;
+    Ok((dir, content))
+}
+
+/// The build context: `dir` as a tar archive, refused past `max_bytes`.
+async fn context_archive(dir: &Handle, max_bytes: u64) -> Result<Vec<u8>> {
+    // Started in the directory the handle holds, not wherever its path leads by now.
+    let mut tar = Command::new("tar")
+        .current_dir(dir.proc_path())
+        .args(["-cf", "-", "--numeric-owner", "."])
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .context("Failed to run tar (is it installed?)")?;
+    let stdout = tar.stdout.take().context("tar has no stdout")?;
+    let mut archive = Vec::new();
+    stdout
+        .take(max_bytes + 1)
+        .read_to_end(&mut archive)
+        .await
+        .context("Failed to read the build context")?;
+    if archive.len() as u64 > max_bytes {
+        bail!(
+            "the build context (the Dockerfile's directory) is larger than {} MiB; keep only \
+             what the image needs next to the Dockerfile",
+            max_bytes / (1024 * 1024)
+        );
+    }
+    let output = tar.wait_with_output().await?;
+    if !output.status.success() {
+        bail!(
+            "tar exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(archive)
+}
+
+fn tail(log: &str, lines: usize) -> String {
+    let all: Vec<&str> = log.lines().collect();
+    all[all.len().saturating_sub(lines)..].join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tag_and_paths() {
+        let config = BuildConfig {
+            enabled: true,
+            ..BuildConfig::default()
+        };
+        assert_eq!(
+            tag(&config, "OctoCat", "Web_1"),
+            "agentman-build/octocat:Web_1"
+        );
+        assert!(is_own_image(
+            &config,
+            "agentman-build/octocat:web",
+            "octocat",
+            "web"
+        ));
+        assert!(!is_own_image(
+            &config,
+            "agentman-build/octocat:web",
+            "octo",
+            "web"
+        ));
+
+        assert_eq!(
+            relative_path(".agentman/Dockerfile").unwrap(),
+            Path::new(".agentman/Dockerfile")
+        );
+        assert_eq!(
+            relative_path("/workspace/Dockerfile").unwrap(),
+            Path::new("Dockerfile")
+        );
+        assert_eq!(relative_path("./Dockerfile").unwrap(), Path::new("Dockerfile"));
+        assert!(relative_path("../other/Dockerfile").is_err());
+        assert!(relative_path("/etc/passwd").is_err());
+        assert!(relative_path("").is_err());
+    }
+
+    #[test]
+    fn test_check_base_images() {
+        let mut config = BuildConfig::default();
+        let dockerfile = "\
+FROM --platform=linux/amd64 agentman-base:dev AS base
+RUN apt-get update
+from base
+COPY --from=base /etc/hosts /tmp/hosts
+";
+        assert!(check_base_images(&config, dockerfile).is_ok());
+        config.base_images = vec!["agentman-base:dev".to_string()];
+        assert!(check_base_images(&config, dockerfile).is_ok());
+        assert!(check_base_images(&config, "FROM ubuntu:24.04\n").is_err());
+        assert!(check_base_images(&config, "ARG BASE=agentman-base:dev\nFROM ${BASE}\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dockerfile_and_context_do_not_follow_symlinks() {
+        let dir = std::env::temp_dir().join(format!("agentman-build-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let ws = dir.join("ws");
+        std::fs::create_dir_all(ws.join(".agentman")).unwrap();
+        std::fs::create_dir_all(dir.join("host")).unwrap();
+        std::fs::write(ws.join(".agentman/Dockerfile"), "FROM agentman-base:dev\n").unwrap();
+        std::fs::write(dir.join("host/secret"), "host secret").unwrap();
+        std::os::unix::fs::symlink("../host/secret", ws.join("Dockerfile")).unwrap();
+        std::os::unix::fs::symlink("../host", ws.join("linked")).unwrap();
+
+        assert!(open_dockerfile(&ws, Path::new("Dockerfile")).is_err());
+        assert!(open_dockerfile(&ws, Path::new("linked/secret")).is_err());
+        assert!(open_dockerfile(&ws, Path::new("missing/Dockerfile")).is_err());
+        let (context, content) = open_dockerfile(&ws, Path::new(".agentman/Dockerfile")).unwrap();
+        assert_eq!(content, "FROM agentman-base:dev\n");
+
+        // The archived directory is the one that was checked, even once its path leads elsewhere.
+        std::fs::rename(ws.join(".agentman"), ws.join("moved")).unwrap();
+        std::os::unix::fs::symlink("../host", ws.join(".agentman")).unwrap();
+        let archive = context_archive(&context, 1024 * 1024).await.unwrap();
+        let text = String::from_utf8_lossy(&archive);
+        assert!(text.contains("./Dockerfile"));
+        assert!(!text.contains("host secret"));
+        assert!(context_archive(&context, 100).await.is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_tail() {
+        let log = (1..=50).map(|i| format!("line {i}\n")).collect::<String>();
+        let tail = tail(&log, 3);
+        assert_eq!(tail, "line 48\nline 49\nline 50");
+    }
+}
@@ -0,0 +1,279 @@
+//! Experimental checkpoint/restore of running sandboxes (`agentman checkpoint`,
+//! `agentman resume`).
+//!
+//! The Docker daemon freezes the sandbox's processes and dumps them with CRIU into
+//! `<dir>/<github_user>/<project>/<checkpoint>`; resuming starts the stopped container from
+//! that dump instead of from scratch, so long-running agents survive a host reboot, or move to
+//! another host that sees the same directory and has a stopped container for the workspace.
+//! bollard has no checkpoint API, so this drives the `docker` CLI (which honors `DOCKER_HOST`
+//! like the gateway does). CRIU fails for many reasons (unsupported kernel features, open TCP
+//! connections, GPU devices, ...); its error is passed on as is.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow, bail};
+use bollard::errors::Error as BollardError;
+use bollard::query_parameters::InspectContainerOptions;
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use tokio::process::Command;
+use tracing::{info, warn};
+
+use crate::config::CheckpointConfig;
+use crate::docker::ContainerManager;
+use crate::{metrics, stack};
+
+/// Checkpoint names (sortable, and valid as Docker checkpoint names).
+const NAME_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// Upper bounds (seconds) of the checkpoint duration histogram buckets.
+const DURATION_BUCKETS: &[f64] = &[1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0];
+
+/// A stored checkpoint of a workspace's sandbox.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub name: String,
+    pub time: DateTime<Utc>,
+}
+
+/// Directory holding a workspace's checkpoints (`--checkpoint-dir`).
+pub fn workspace_dir(config: &CheckpointConfig, github_user: &str, project: &str) -> PathBuf {
+    config.dir.join(github_user).join(project)
+}
+
+/// The newest checkpoint in `dir`, if any.
+pub async fn latest(dir: &Path) -> Result<Option<Checkpoint>> {
+    Ok(list(dir).await?.into_iter().max_by_key(|c| c.time))
+}
+
+async fn list(dir: &Path) -> Result<Vec<Checkpoint>> {
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", dir.display())),
+    };
+    let mut out = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if let Ok(t) = NaiveDateTime::parse_from_str(&name, NAME_FORMAT) {
+            out.push(Checkpoint {
+                name,
+                time: Utc.from_utc_datetime(&t),
+            });
+        }
+    }
+    Ok(out)
+}
+
+/// Checkpoint the workspace's running sandbox. Unless `leave_running`, the sandbox is stopped
+/// afterwards. Older checkpoints of the workspace are removed once the new one is complete.
+pub async fn create(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    leave_running: bool,
+) -> Result<Checkpoint> {
+    let started = tokio::time::Instant::now();
+    let result = try_create(container_manager, github_user, project, leave_running).await;
+    record("create", started, &result);
+    result
+}
+
+async fn try_create(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    leave_running: bool,
+) -> Result<Checkpoint> {
+    let config = &container_manager.config().checkpoint;
+    ensure_supported(container_manager).await?;
+    let container = sandbox_container(container_manager, github_user, project).await?;
+    if !is_running(container_manager, &container).await? {
+        bail!("sandbox {project} is not running");
+    }
+
+    let dir = workspace_dir(config, github_user, project);
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .with_context(|| format!("Failed to create {}", dir.display()))?;
+    let previous = list(&dir).await?;
+    let time = Utc::now();
+    let name = time.format(NAME_FORMAT).to_string();
+
+    let mut cmd = Command::new("docker");
+    cmd.args(["checkpoint", "create", "--checkpoint-dir"])
+        .arg(&dir);
+    if leave_running {
+        cmd.arg("--leave-running");
+    }
+    cmd.arg(&container).arg(&name);
+    run(&mut cmd, config.timeout_secs).await?;
+    info!(
+        "Checkpointed {}/{} as {} (leave running: {})",
+        github_user, project, name, leave_running
+    );
+
+    // A checkpoint holds the sandbox's memory; only the newest is worth its disk space.
+    for old in previous.iter().filter(|c| c.name != name) {
+        if let Err(e) = tokio::fs::remove_dir_all(dir.join(&old.name)).await {
+            warn!("Failed to remove checkpoint {}: {}", old.name, e);
+        }
+    }
+    Ok(Checkpoint { name, time })
+}
+
+/// Start the workspace's stopped sandbox from its latest checkpoint.
+pub async fn resume(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+) -> Result<Checkpoint> {
+    let started = tokio::time::Instant::now();
+    let result = try_resume(container_manager, github_user, project).await;
+    record("resume", started, &result);
+    result
+}
+
+async fn try_resume(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+) -> Result<Checkpoint> {
+    let config = &container_manager.config().checkpoint;
+    ensure_supported(container_manager).await?;
+    let container = sandbox_container(container_manager, github_user, project).await?;
+    if is_running(container_manager, &container).await? {
+        bail!(
+            "sandbox {project} is running; stop it with `agentman stop` first \
+             (its current processes are replaced by the checkpoint's)"
+        );
+    }
+    let dir = workspace_dir(config, github_user, project);
+    let checkpoint = latest(&dir).await?.ok_or_else(|| {
+        anyhow!(
+            "no checkpoint of {github_user}/{project} in {}",
+            dir.display()
+        )
+    })?;
+
+    // Sidecars first, as on a regular start.
+    stack::bring_up(container_manager, github_user, project, Some(&container)).await;
+    run(
+        Command::new("docker")
+            .args(["start", "--checkpoint"])
+            .arg(&checkpoint.name)
+            .arg("--checkpoint-dir")
+            .arg(&dir)
+            .arg(&container),
+        config.timeout_secs,
+    )
+    .await?;
+    info!(
+        "Resumed {}/{} from checkpoint {}",
+        github_user, project, checkpoint.name
+    );
+    Ok(checkpoint)
+}
+
+/// Fails unless checkpoints are enabled and the daemon can take them.
+async fn ensure_supported(container_manager: &ContainerManager) -> Result<()> {
+    if !container_manager.config().checkpoint.enabled {
+        bail!("checkpoints are not enabled on this gateway");
+    }
+    let info = container_manager
+        .docker()
+        .info()
+        .await
+        .context("Failed to query the Docker daemon")?;
+    if info.experimental_build != Some(true) {
+        bail!(
+            "the Docker daemon does not have experimental features enabled, which checkpoints \
+             need (\"experimental\": true in daemon.json)"
+        );
+    }
+    Ok(())
+}
+
+/// Name of the workspace's container.
+async fn sandbox_container(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+) -> Result<String> {
+    container_manager
+        .get_workspace(github_user, project)
+        .await
+        .map(|ws| ws.container_name)
+        .ok_or_else(|| anyhow!("no sandbox found for {github_user}/{project}"))
+}
+
+async fn is_running(container_manager: &ContainerManager, container: &str) -> Result<bool> {
+    match container_manager
+        .docker()
+        .inspect_container(container, None::<InspectContainerOptions>)
+        .await
+    {
+        Ok(info) => Ok(info.state.and_then(|s| s.running).unwrap_or(false)),
+        Err(BollardError::DockerResponseServerError {
+            status_code: 404, ..
+        }) => bail!(
+            "container {container} does not exist; on a new host, create it with \
+             `agentman recreate` and `agentman stop` first"
+        ),
+        Err(e) => Err(e).context("Failed to inspect container"),
+    }
+}
+
+fn record<T>(op: &str, started: tokio::time::Instant, result: &Result<T>) {
+    let outcome = if result.is_ok() { "ok" } else { "failed" };
+    metrics::inc(
+        "agentman_checkpoint_operations_total",
+        "Sandbox checkpoints taken and resumed, by outcome.",
+        &[("op", op), ("outcome", outcome)],
+    );
+    if result.is_ok() {
+        metrics::observe(
+            "agentman_checkpoint_duration_seconds",
+            "Time taken by successful checkpoints and resumes.",
+            DURATION_BUCKETS,
+            &[("op", op)],
+            started.elapsed().as_secs_f64(),
+        );
+    }
+}
+
+/// Run a docker CLI command with a time limit, returning an error with its whole stderr
+/// (CRIU failures span several lines and name the log file with the details).
+async fn run(cmd: &mut Command, timeout_secs: u64) -> Result<()> {
+    cmd.kill_on_drop(true);
+    let output = tokio::time::timeout(Duration::from_secs(timeout_secs), cmd.output())
+        .await
+        .map_err(|_| anyhow!("docker did not finish within {timeout_secs}s"))?
+        .context("Failed to run docker (is the CLI installed?)")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("docker exited with {}: {}", output.status, stderr.trim());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_latest() {
+        let dir =
+            std::env::temp_dir().join(format!("agentman-checkpoint-test-{}", std::process::id()));
+        assert_eq!(latest(&dir).await.unwrap(), None);
+
+        for name in ["20260101T000000Z", "20260301T120000Z", "not-a-checkpoint"] {
+            std::fs::create_dir_all(dir.join(name)).unwrap();
+        }
+        let newest = latest(&dir).await.unwrap().unwrap();
+        assert_eq!(newest.name, "20260301T120000Z");
+        assert_eq!(newest.time.to_rfc3339(), "2026-03-01T12:00:00+00:00");
+        assert_eq!(list(&dir).await.unwrap().len(), 2);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,341 @@
+//! Wire-level SSH compression (`[compression]`).
+//!
+//! When enabled, `zlib@openssh.com` and `zlib` are offered during key exchange, so clients that
+//! ask for compression (`ssh -C`) get it. russh doesn't report the algorithm it negotiated, so
+//! each connection's socket is wrapped in a [`CountingStream`] that reads it from the client's
+//! first `SSH_MSG_KEXINIT` (sent in the clear) and counts the bytes on the wire. Compared with
+//! the channel data the handler moves, that gives the compression ratio.
+
+use std::borrow::Cow;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::config::CompressionConfig;
+use crate::metrics;
+
+/// The client's key exchange is expected within this many bytes of the connection.
+const MAX_SNIFF: usize = 64 * 1024;
+
+const SSH_MSG_KEXINIT: u8 = 20;
+
+const RATIO_BOUNDS: &[f64] = &[1.0, 1.5, 2.0, 3.0, 4.0, 6.0, 8.0, 12.0, 16.0];
+
+const OFFERED: &[russh::compression::Name] = &[
+    russh::compression::ZLIB_LEGACY,
+    russh::compression::ZLIB,
+    russh::compression::NONE,
+];
+
+const DISABLED: &[russh::compression::Name] = &[russh::compression::NONE];
+
+/// Compression algorithms offered to clients, in order of preference. `none` comes last, so
+/// clients that don't ask for compression (no `ssh -C`) go without.
+fn offered(config: &CompressionConfig) -> &'static [russh::compression::Name] {
+    if config.enabled { OFFERED } else { DISABLED }
+}
+
+/// Algorithm preferences for the server.
+pub fn preferred(config: &CompressionConfig) -> russh::Preferred {
+    russh::Preferred {
+        compression: Cow::Borrowed(offered(config)),
+        ..Default::default()
+    }
+}
+
+/// Byte counts of one connection.
+#[derive(Debug, Default)]
+pub struct Traffic {
+    /// Server-to-client compression, once the client's key exchange has been read.
+    algorithm: OnceLock<String>,
+    wire_in: AtomicU64,
+    wire_out: AtomicU64,
+    payload_in: AtomicU64,
+    payload_out: AtomicU64,
+}
+
+impl Traffic {
+    /// Count channel data received from the client.
+    pub fn received(&self, bytes: usize) {
+        self.payload_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Count channel data sent to the client.
+    pub fn sent(&self, bytes: usize) {
+        self.payload_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Channel data received from and sent to the client so far.
+    pub fn payload(&self) -> (u64, u64) {
+        (
+            self.payload_in.load(Ordering::Relaxed),
+            self.payload_out.load(Ordering::Relaxed),
+        )
+    }
+
+    /// The negotiated server-to-client compression (`"none"` before key exchange).
+    pub fn algorithm(&self) -> &str {
+        self.algorithm.get().map_or("none", String::as_str)
+    }
+
+    /// Record the connection's metrics once it has ended. Connections that sent less channel
+    /// data than `threshold_bytes` only count towards the negotiated algorithms: a few bytes
+    /// of an interactive shell say nothing about the ratio.
+    pub fn record(&self, config: &CompressionConfig) {
+        let algorithm = self.algorithm();
+        metrics::inc(
+            "agentman_ssh_compression_connections_total",
+            "SSH connections by negotiated server-to-client compression.",
+            &[("algorithm", algorithm)],
+        );
+        let payload_out = self.payload_out.load(Ordering::Relaxed);
+        if payload_out < config.threshold_bytes {
+            return;
+        }
+        for (direction, payload, wire) in [
+            ("in", &self.payload_in, &self.wire_in),
+            ("out", &self.payload_out, &self.wire_out),
+        ] {
+            let labels = [("algorithm", algorithm), ("direction", direction)];
+            metrics::add(
+                "agentman_ssh_payload_bytes_total",
+                "Channel data of SSH connections above the compression threshold.",
+                &labels,
+                payload.load(Ordering::Relaxed) as f64,
+            );
+            metrics::add(
+                "agentman_ssh_wire_bytes_total",
+                "Bytes on the wire of SSH connections above the compression threshold.",
+                &labels,
+                wire.load(Ordering::Relaxed) as f64,
+            );
+        }
+        let wire_out = self.wire_out.load(Ordering::Relaxed).max(1);
+        let ratio = payload_out as f64 / wire_out as f64;
+        metrics::observe(
+            "agentman_ssh_compression_ratio",
+            "Channel data sent per byte on the wire, per SSH connection above the threshold.",
+            RATIO_BOUNDS,
+            &[("algorithm", algorithm)],
+            ratio,
+        );
+        tracing::debug!(
+            "SSH connection sent {} bytes of channel data in {} bytes ({}, ratio {:.2})",
+            payload_out,
+            wire_out,
+            algorithm,
+            ratio
+        );
+    }
+}
+
+/// A connection's socket, counting bytes both ways and reading the client's key exchange.
+pub struct CountingStream<S> {
+    inner: S,
+    traffic: Arc<Traffic>,
+    /// What the client sent so far, until its key exchange has been read.
+    sniffed: Option<Vec<u8>>,
+    offered: &'static [russh::compression::Name],
+}
+
+impl<S> CountingStream<S> {
+    pub fn new(inner: S, traffic: Arc<Traffic>, config: &CompressionConfig) -> Self {
+        Self {
+            inner,
+            traffic,
+            sniffed: Some(Vec::new()),
+            offered: offered(config),
+        }
+    }
+
+    fn sniff(&mut self, data: &[u8]) {
+        let Some(sniffed) = self.sniffed.as_mut() else {
+            return;
+        };
+        sniffed.extend_from_slice(data);
+        match client_compression(sniffed) {
+            Some(Some(client)) => {
+                // The server picks the client's first choice that it offers.
+                let algorithm = client
+                    .into_iter()
+                    .find(|name| self.offered.iter().any(|o| o.as_ref() == *name))
+                    .unwrap_or("none");
+                let _ = self.traffic.algorithm.set(algorithm.to_string());
+                self.sniffed = None;
+            }
+            Some(None) => self.sniffed = None,
+            None if sniffed.len() > MAX_SNIFF => self.sniffed = None,
+            None => {}
+        }
+    }
+}
+
+/// The server-to-client compression list of the client's `SSH_MSG_KEXINIT`, from the start of
+/// what it sent. `None` while incomplete; `Some(None)` if it isn't a key exchange.
+fn client_compression(data: &[u8]) -> Option<Option<Vec<&str>>> {
+    // Skip the identification string (and any lines before it).
+    let mut rest = data;
+    loop {
+        let end = rest.iter().position(|&b| b == b'\n')?;
+        let line = &rest[..end];
+        rest = &rest[end + 1..];
+        if line.starts_with(b"SSH-") {
+            break;
+        }
+    }
+    if rest.len() < 5 {
+        return None;
+    }
+    let length = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+    let padding = rest[4] as usize;
+    if length > MAX_SNIFF || padding + 1 > length {
+        return Some(None);
+    }
+    let packet = rest.get(4..4 + length)?;
+    let payload = &packet[1..length - padding];
+    if payload.first() != Some(&SSH_MSG_KEXINIT) || payload.len() < 17 {
+        return Some(None);
+    }
+    // kex, host key, ciphers and MACs both ways, then compression client-to-server and
+    // server-to-client.
+    let mut fields = &payload[17..];
+    for index in 0..8 {
+        let Some(len) = fields.get(..4) else {
+            return Some(None);
+        };
+        let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+        let Some(list) = fields.get(4..4 + len) else {
+            return Some(None);
+        };
+        if index == 7 {
+            let Ok(list) = std::str::from_utf8(list) else {
+                return Some(None);
+            };
+            return Some(Some(list.split(',').collect()));
+        }
+        fields = &fields[4 + len..];
+    }
+    Some(None)
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountingStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = &buf.filled()[before..];
+        if !read.is_empty() {
+            self.traffic
+                .wire_in
+                .fetch_add(read.len() as u64, Ordering::Relaxed);
+            let read = read.to_vec();
+            self.sniff(&read);
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountingStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            self.traffic.wire_out.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An identification string and a `SSH_MSG_KEXINIT` with the given compression lists.
+    fn client_hello(c2s: &str, s2c: &str) -> Vec<u8> {
+        let mut payload = vec![SSH_MSG_KEXINIT];
+        payload.extend_from_slice(&[0; 16]);
+        let lists = [
+            "curve25519-sha256",
+            "ssh-ed25519",
+            "aes128-ctr",
+            "aes128-ctr",
+            "hmac-sha2-256",
+            "hmac-sha2-256",
+            c2s,
+            s2c,
+            "",
+            "",
+        ];
+        for list in lists {
+            payload.extend_from_slice(&(list.len() as u32).to_be_bytes());
+            payload.extend_from_slice(list.as_bytes());
+        }
+        payload.extend_from_slice(&[0, 0, 0, 0, 0]);
+        let padding = 4u8;
+        let mut data = b"SSH-2.0-OpenSSH_9.6\r\n".to_vec();
+        data.extend_from_slice(&((payload.len() + padding as usize + 1) as u32).to_be_bytes());
+        data.push(padding);
+        data.extend_from_slice(&payload);
+        data.extend_from_slice(&[0; 4]);
+        data
+    }
+
+    #[test]
+    fn test_client_compression() {
+        let hello = client_hello("none", "zlib@openssh.com,none");
+        assert_eq!(
+            client_compression(&hello),
+            Some(Some(vec!["zlib@openssh.com", "none"]))
+        );
+        assert_eq!(client_compression(&hello[..hello.len() - 10]), None);
+        assert_eq!(client_compression(b"SSH-2.0-x\r\n"), None);
+        assert_eq!(
+            client_compression(b"SSH-2.0-x\r\n\0\0\0\x0c\x04\x15.........."),
+            Some(None)
+        );
+    }
+
+    #[test]
+    fn test_negotiated_algorithm() {
+        let negotiate = |enabled: bool, s2c: &str| {
+            let traffic = Arc::new(Traffic::default());
+            let config = CompressionConfig {
+                enabled,
+                ..Default::default()
+            };
+            let mut stream = CountingStream::new(io::empty(), traffic.clone(), &config);
+            let hello = client_hello("none", s2c);
+            // Arrives in pieces.
+            for chunk in hello.chunks(7) {
+                stream.sniff(chunk);
+            }
+            assert!(stream.sniffed.is_none());
+            traffic.algorithm().to_string()
+        };
+        assert_eq!(
+            negotiate(true, "zlib@openssh.com,zlib,none"),
+            "zlib@openssh.com"
+        );
+        assert_eq!(negotiate(true, "zlib,none"), "zlib");
+        assert_eq!(negotiate(true, "none,zlib@openssh.com"), "none");
+        assert_eq!(negotiate(false, "zlib@openssh.com,zlib,none"), "none");
+    }
+}
@@ -2,6 +2,7 @@
 
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
 /// Interactive shell/session configuration.
@@ -15,6 +16,13 @@ pub struct ShellConfig {
     ///
     /// The session lives inside each agent container and enables reconnect/resume.
     pub tmux_session: String,
+
+    /// Size of the shared tmux window when several terminals are attached to it.
+    pub tmux_window_size: TmuxWindowSize,
+
+    /// Character starting gateway escape sequences in PTY sessions (`~.`, `~C`, ...), or
+    /// `"none"` to disable them.
+    pub escape_char: String,
 }
 
 impl Default for ShellConfig {
@@ -22,23 +30,91 @@ impl Default for ShellConfig {
         Self {
             mode: ShellMode::Tmux,
             tmux_session: "agentman".to_string(),
+            tmux_window_size: TmuxWindowSize::default(),
+            escape_char: "~".to_string(),
+        }
+    }
+}
+
+impl ShellConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.escape_char != "none" && self.escape_byte().is_none() {
+            anyhow::bail!("shell: escape_char must be a single printable ASCII character or \"none\"");
+        }
+        Ok(())
+    }
+
+    /// The escape character as typed, if escapes are enabled.
+    pub fn escape_byte(&self) -> Option<u8> {
+        match self.escape_char.as_bytes() {
+            [b] if b.is_ascii_graphic() => Some(*b),
+            _ => None,
+        }
+    }
+}
+
+/// tmux `window-size` policy for sessions with more than one attached client.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TmuxWindowSize {
+    /// Fit the smallest attached terminal, so every client sees the whole window.
+    Smallest,
+    /// Follow the terminal that was used (typed in or resized) most recently.
+    #[default]
+    Latest,
+}
+
+impl TmuxWindowSize {
+    /// The value of tmux's `window-size` option.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TmuxWindowSize::Smallest => "smallest",
+            TmuxWindowSize::Latest => "latest",
         }
     }
 }
 
 /// How to start an interactive shell when the user connects.
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum ShellMode {
     /// Start a plain login shell (`bash -l`).
     Bash,
     /// Attach to (or create) a persistent tmux session.
+    #[default]
     Tmux,
 }
 
-impl Default for ShellMode {
+/// Limits and cleanup for exec requests (`ssh host cmd`, `agentman run`) and plain shells.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExecConfig {
+    /// Maximum run time of an exec request, in seconds; `agentman run --timeout` can only
+    /// shorten it. When exceeded, the command's processes are killed and the exec exits with
+    /// status 124. Unset = no limit.
+    ///
+    /// Editors run their remote servers as long-lived execs; leave this unset if users
+    /// connect with VS Code, Zed and the like.
+    pub max_duration_secs: Option<u64>,
+
+    /// When an SSH channel closes while its command is still running, send SIGHUP to every
+    /// process the command started, then SIGKILL to whatever is left after `kill_grace_secs`
+    /// (sshd hangs up a session the same way). Applies to exec requests and plain `bash`
+    /// shells; tmux shells are never signalled since the session is meant to outlive the
+    /// connection.
+    pub kill_on_disconnect: bool,
+
+    /// Seconds between SIGHUP and SIGKILL for `kill_on_disconnect`.
+    pub kill_grace_secs: u64,
+}
+
+impl Default for ExecConfig {
     fn default() -> Self {
-        Self::Tmux
+        Self {
+            max_duration_secs: None,
+            kill_on_disconnect: true,
+            kill_grace_secs: 5,
+        }
     }
 }
 
@@ -60,6 +136,40 @@ impl Default for AgentForwardingConfig {
     }
 }
 
+/// Source of the SSH host key.
+///
+/// The SSH library signs key exchanges in-process, so the key has to be in the gateway's memory;
+/// signing through ssh-agent or a PKCS#11 token isn't possible. `command` keeps it off the
+/// gateway's disk instead: the key is fetched at startup from a secret manager, or decrypted
+/// with a cloud KMS/HSM-held key (envelope encryption), and never written anywhere.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HostKeyBackend {
+    /// OpenSSH private key file at `host_key_path`, generated on first start.
+    #[default]
+    File,
+    /// Output of `host_key_command`, held in memory only.
+    Command,
+}
+
+/// `host[:port]` (`[v6]:port` for IPv6 addresses) as host and port, 22 if there is none.
+pub fn split_host_port(address: &str) -> Option<(&str, u16)> {
+    let (host, port) = match address.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') || host.ends_with(']') => {
+            (host, port.parse().ok().filter(|p| *p != 0)?)
+        }
+        _ => (address, 22),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() || host.contains(char::is_whitespace) || host.contains('/') {
+        return None;
+    }
+    Some((host, port))
+}
+
+/// Default `container_name_template`.
+pub const DEFAULT_CONTAINER_NAME_TEMPLATE: &str = "agentman-{user}-{project}";
+
 /// Main gateway configuration.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
@@ -67,57 +177,3233 @@ pub struct GatewayConfig {
     /// SSH server listen address (default: "0.0.0.0:2222")
     pub listen_addr: String,
 
+    /// Host (and port, if not 22) users connect to, for the snippets `agentman open` prints;
+    /// unset: a placeholder host with the listen_addr port
+    #[serde(default)]
+    pub public_address: Option<String>,
+
     /// Docker image to use for agent containers
     pub docker_image: String,
 
+    /// Container name template; `{user}` and `{project}` are substituted (default:
+    /// "agentman-{user}-{project}"). The name is stable across recreations and doubles as the
+    /// container hostname.
+    pub container_name_template: String,
+
     /// Root path for persistent workspaces
     pub workspace_root: PathBuf,
 
-    /// Path to the state file (key cache, container mappings)
-    pub state_file: PathBuf,
+    /// Path to the state file (key cache, container mappings)
+    pub state_file: PathBuf,
+
+    /// Path to the SSH host key
+    pub host_key_path: PathBuf,
+
+    /// Where the SSH host key comes from (default: "file", i.e. `host_key_path`)
+    #[serde(default)]
+    pub host_key_backend: HostKeyBackend,
+
+    /// Command (argv, no shell) printing the OpenSSH-format host key on stdout, for
+    /// `host_key_backend = "command"`
+    #[serde(default)]
+    pub host_key_command: Vec<String>,
+
+    /// Bootstrap GitHub usernames for auto-matching keys
+    #[serde(default)]
+    pub bootstrap_github_users: Vec<String>,
+
+    /// GitHub usernames allowed to run `agentman admin` commands
+    #[serde(default)]
+    pub admin_github_users: Vec<String>,
+
+    /// GitHub organizations whose members may use the gateway; empty lets in every GitHub user
+    /// with a verified key. Admins are always let in.
+    #[serde(default)]
+    pub allowed_github_orgs: Vec<String>,
+
+    /// File with a GitHub token that can read the memberships of `allowed_github_orgs`
+    /// (`read:org`)
+    #[serde(default)]
+    pub github_org_token_file: Option<PathBuf>,
+
+    /// Projects users may have, as names or prefixes ending in `*`; empty allows any. Usually
+    /// set for individual users in `users_dir`.
+    #[serde(default)]
+    pub allowed_projects: Vec<String>,
+
+    /// Directory of per-user overrides, one `<github_user>.toml` each (see [`UserOverrides`])
+    #[serde(default)]
+    pub users_dir: Option<PathBuf>,
+
+    /// How long a keyboard-interactive login keeps re-checking GitHub for a key that isn't
+    /// listed there yet (newly added keys can take a while to show up); 0 disables
+    pub key_propagation_wait_secs: u64,
+
+    /// Port forwarding configuration
+    #[serde(default)]
+    pub port_forwarding: PortForwardingConfig,
+
+    /// OpenSSH agent forwarding configuration
+    #[serde(default)]
+    pub agent_forwarding: AgentForwardingConfig,
+
+    /// Interactive shell/session configuration
+    #[serde(default)]
+    pub shell: ShellConfig,
+
+    /// Exec request limits (timeouts)
+    #[serde(default)]
+    pub exec: ExecConfig,
+
+    /// Container security configuration
+    #[serde(default)]
+    pub container_security: ContainerSecurityConfig,
+
+    /// Named overrides of `container_security` for selected users and GitHub teams
+    #[serde(default)]
+    pub security_profiles: SecurityProfilesConfig,
+
+    /// Host files/directories bind-mounted read-only into every container
+    /// (corporate CA bundles, proxy certs, known_hosts, mirror configs).
+    #[serde(default)]
+    pub readonly_mounts: Vec<ReadOnlyMount>,
+
+    /// Host devices passed into selected users' containers (opt-in, e.g. /dev/kvm)
+    #[serde(default)]
+    pub devices: Vec<DeviceAccess>,
+
+    /// Audit/event log shipping configuration
+    #[serde(default)]
+    pub logging: LoggingConfig,
+
+    /// Startup command run inside each container when it starts
+    #[serde(default)]
+    pub init: InitConfig,
+
+    /// Images users may switch their sandboxes to (`agentman image`)
+    #[serde(default)]
+    pub images: ImagesConfig,
+
+    /// Extra readiness checks used by `agentman wait`
+    #[serde(default)]
+    pub readiness: ReadinessConfig,
+
+    /// Adaptive delays and GitHub lookup budgets for failed authentication
+    #[serde(default)]
+    pub auth_guard: AuthGuardConfig,
+
+    /// Prometheus metrics endpoint
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+
+    /// Incremental workspace backups
+    #[serde(default)]
+    pub backup: BackupConfig,
+
+    /// Per-user cache for editor remote servers (VS Code, Zed, Cursor, ...)
+    #[serde(default)]
+    pub editor_cache: EditorCacheConfig,
+
+    /// Who owns workspace directories on the host, and how the gateway fixes that up
+    #[serde(default)]
+    pub workspace_permissions: WorkspacePermissionsConfig,
+
+    /// Static helper binary copied into every container
+    #[serde(default)]
+    pub helper: HelperConfig,
+
+    /// Outbound reverse tunnel to a public SSH bastion, for gateways behind NAT
+    #[serde(default)]
+    pub relay: RelayConfig,
+
+    /// Periodic CPU/memory samples for `agentman stats --history`
+    #[serde(default)]
+    pub stats_history: StatsHistoryConfig,
+
+    /// Cached workspace disk usage for `agentman stats` and `agentman admin users`
+    #[serde(default)]
+    pub workspace_size: WorkspaceSizeConfig,
+
+    /// HTTP CONNECT egress proxy for containers
+    #[serde(default)]
+    pub proxy: ProxyConfig,
+
+    /// CA trusted by containers, for TLS-inspecting proxies
+    #[serde(default)]
+    pub proxy_ca: ProxyCaConfig,
+
+    /// Where admin notifications are sent
+    #[serde(default)]
+    pub notify: NotifyConfig,
+
+    /// Archive and remove the workspaces of users who stopped logging in
+    #[serde(default)]
+    pub offboarding: OffboardingConfig,
+
+    /// Sidecar containers declared by a compose file
+    #[serde(default)]
+    pub stack: StackConfig,
+
+    /// Forward kernel audit events (seccomp, capabilities, ptrace) from containers
+    #[serde(default)]
+    pub security_events: SecurityEventsConfig,
+
+    /// Active/passive failover between two gateways sharing a Docker host and state
+    #[serde(default)]
+    pub ha: HaConfig,
+
+    /// Configure git commit signing with the user's GitHub SSH signing keys
+    #[serde(default)]
+    pub git_signing: GitSigningConfig,
+
+    /// Throwaway sandboxes with an in-memory workspace, removed when the connection ends
+    #[serde(default)]
+    pub ephemeral: EphemeralConfig,
+
+    /// zlib compression of SSH traffic, for clients that ask for it (`ssh -C`)
+    #[serde(default)]
+    pub compression: CompressionConfig,
+
+    /// SSH protocol limits: auth attempts, banner, rekeying, windows, handshake timeout
+    #[serde(default)]
+    pub ssh: SshConfig,
+
+    /// Sizes and network endpoint of `agentman bench`
+    #[serde(default)]
+    pub bench: BenchConfig,
+
+    /// One public TLS port shared by all sandboxes, routed by server name
+    #[serde(default)]
+    pub sni_router: SniRouterConfig,
+
+    /// Experimental CRIU checkpoint/restore of running sandboxes
+    #[serde(default)]
+    pub checkpoint: CheckpointConfig,
+
+    /// Other gateways workspaces can be moved to with `agentman admin migrate`
+    #[serde(default)]
+    pub migration: MigrationConfig,
+
+    /// Look for escaping symlinks, writable setuid files and large files in workspaces
+    #[serde(default)]
+    pub scan: ScanConfig,
+
+    /// Time-limited guest access to a user's project (`agentman guest`)
+    #[serde(default)]
+    pub guests: GuestsConfig,
+
+    /// Audited admin access to other users' projects (`agentman admin access`)
+    #[serde(default)]
+    pub impersonation: ImpersonationConfig,
+
+    /// Stop sandboxes nobody has been connected to for a while
+    #[serde(default)]
+    pub idle: IdleConfig,
+
+    /// Expiring, unauthenticated HTTP preview links (`agentman expose --preview`)
+    #[serde(default)]
+    pub preview: PreviewConfig,
+
+    /// HTTP API for operators: list and destroy workspaces, flush the key cache
+    #[serde(default)]
+    pub admin_api: AdminApiConfig,
+
+    /// Language of the gateway's messages for users who haven't picked one
+    #[serde(default)]
+    pub i18n: I18nConfig,
+
+    /// Back-pressure on command output, and what to do with clients that stop reading it
+    #[serde(default)]
+    pub slow_clients: SlowClientsConfig,
+
+    /// Per-user limits on running sandboxes and the memory and CPUs they may reserve
+    #[serde(default)]
+    pub quotas: QuotaConfig,
+
+    /// Limits on the gateway's own memory and task count
+    #[serde(default)]
+    pub memory_guard: MemoryGuardConfig,
+
+    /// Per-workspace dotenv files injected into execs and containers (`agentman env-file`)
+    #[serde(default)]
+    pub env_file: EnvFileConfig,
+
+    /// Sandbox images built from a workspace Dockerfile (`agentman build`)
+    #[serde(default)]
+    pub build: BuildConfig,
+
+    /// Network traffic accounting and monthly egress quotas
+    #[serde(default)]
+    pub egress: EgressConfig,
+
+    /// Per-user resource usage for chargeback (`agentman admin usage`)
+    #[serde(default)]
+    pub accounting: AccountingConfig,
+
+    /// Messages from sandboxes to their owners (`agentman notify`)
+    #[serde(default)]
+    pub user_notify: UserNotifyConfig,
+
+    /// Queueing and rate limits for container creation
+    #[serde(default)]
+    pub admission: AdmissionConfig,
+
+    /// Where GitHub keys, profiles and team memberships are looked up
+    #[serde(default)]
+    pub github: GitHubConfig,
+
+    /// GitHub display names and avatars for listings, greetings and notifications
+    #[serde(default)]
+    pub github_metadata: GitHubMetadataConfig,
+
+    /// Commands run from one sandbox in another of the same user's projects (`agentman mesh`)
+    #[serde(default)]
+    pub mesh: MeshConfig,
+
+    /// Git repository cloned into new workspaces
+    #[serde(default)]
+    pub clone: CloneConfig,
+
+    /// Keys and addresses users log in from, and alerts for new ones (`agentman trust`)
+    #[serde(default)]
+    pub trust: TrustConfig,
+}
+
+impl Default for GatewayConfig {
+    fn default() -> Self {
+        let data_dir = dirs::data_local_dir()
+            .unwrap_or_else(|| PathBuf::from("/var/lib"))
+            .join("agentman");
+
+        Self {
+            listen_addr: "0.0.0.0:2222".to_string(),
+            public_address: None,
+            docker_image: "agentman-base:dev".to_string(),
+            container_name_template: DEFAULT_CONTAINER_NAME_TEMPLATE.to_string(),
+            workspace_root: data_dir.join("workspaces"),
+            workspace_permissions: WorkspacePermissionsConfig::default(),
+            state_file: data_dir.join("state.json"),
+            host_key_path: data_dir.join("host_key"),
+            host_key_backend: HostKeyBackend::default(),
+            host_key_command: Vec::new(),
+            bootstrap_github_users: Vec::new(),
+            admin_github_users: Vec::new(),
+            allowed_github_orgs: Vec::new(),
+            github_org_token_file: None,
+            allowed_projects: Vec::new(),
+            users_dir: None,
+            key_propagation_wait_secs: 30,
+            port_forwarding: PortForwardingConfig::default(),
+            agent_forwarding: AgentForwardingConfig::default(),
+            shell: ShellConfig::default(),
+            exec: ExecConfig::default(),
+            container_security: ContainerSecurityConfig::default(),
+            security_profiles: SecurityProfilesConfig::default(),
+            readonly_mounts: Vec::new(),
+            devices: Vec::new(),
+            logging: LoggingConfig::default(),
+            init: InitConfig::default(),
+            images: ImagesConfig::default(),
+            readiness: ReadinessConfig::default(),
+            auth_guard: AuthGuardConfig::default(),
+            metrics: MetricsConfig::default(),
+            backup: BackupConfig::default(),
+            editor_cache: EditorCacheConfig::default(),
+            helper: HelperConfig::default(),
+            relay: RelayConfig::default(),
+            stats_history: StatsHistoryConfig::default(),
+            workspace_size: WorkspaceSizeConfig::default(),
+            proxy: ProxyConfig::default(),
+            proxy_ca: ProxyCaConfig::default(),
+            notify: NotifyConfig::default(),
+            offboarding: OffboardingConfig::default(),
+            stack: StackConfig::default(),
+            security_events: SecurityEventsConfig::default(),
+            ha: HaConfig::default(),
+            git_signing: GitSigningConfig::default(),
+            ephemeral: EphemeralConfig::default(),
+            compression: CompressionConfig::default(),
+            ssh: SshConfig::default(),
+            bench: BenchConfig::default(),
+            sni_router: SniRouterConfig::default(),
+            checkpoint: CheckpointConfig::default(),
+            migration: MigrationConfig::default(),
+            scan: ScanConfig::default(),
+            guests: GuestsConfig::default(),
+            impersonation: ImpersonationConfig::default(),
+            idle: IdleConfig::default(),
+            preview: PreviewConfig::default(),
+            admin_api: AdminApiConfig::default(),
+            i18n: I18nConfig::default(),
+            slow_clients: SlowClientsConfig::default(),
+            quotas: QuotaConfig::default(),
+            memory_guard: MemoryGuardConfig::default(),
+            env_file: EnvFileConfig::default(),
+            build: BuildConfig::default(),
+            egress: EgressConfig::default(),
+            accounting: AccountingConfig::default(),
+            user_notify: UserNotifyConfig::default(),
+            admission: AdmissionConfig::default(),
+            github: GitHubConfig::default(),
+            github_metadata: GitHubMetadataConfig::default(),
+            mesh: MeshConfig::default(),
+            clone: CloneConfig::default(),
+            trust: TrustConfig::default(),
+        }
+    }
+}
+
+/// Brute-force protection for authentication.
+///
+/// Failures are tracked per client IP and per GitHub user over a sliding window. Past the
+/// thresholds below, rejections are delayed, keyboard-interactive is withdrawn, and uncached
+/// keys are no longer verified against GitHub (cached keys keep working).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuthGuardConfig {
+    pub enabled: bool,
+
+    /// Sliding window over which failures and GitHub lookups are counted.
+    pub window_secs: u64,
+
+    /// Failures (per IP or per GitHub user) before rejections start being delayed.
+    pub delay_after_failures: u32,
+
+    /// First delay; doubles with each further failure up to `max_delay_ms`.
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+
+    /// IP failures after which only publickey auth is offered.
+    pub restrict_methods_after: u32,
+
+    /// IP failures after which uncached keys are not checked against GitHub.
+    pub github_failure_limit: u32,
+
+    /// GitHub key lookups allowed per IP per window.
+    pub github_lookup_budget: u32,
+}
+
+impl Default for AuthGuardConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            window_secs: 600,
+            delay_after_failures: 3,
+            base_delay_ms: 250,
+            max_delay_ms: 8000,
+            restrict_methods_after: 5,
+            github_failure_limit: 10,
+            github_lookup_budget: 60,
+        }
+    }
+}
+
+/// How the gateway makes workspace directories writable for the container user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WorkspacePermissionsConfig {
+    pub chown_mode: ChownMode,
+
+    /// Container user as `uid[:gid]`. Unset = the image's `USER`, looked up in the image's
+    /// `/etc/passwd` when it is a name.
+    pub container_user: Option<String>,
+
+    /// Offset between container and host IDs under `userns-remap`. Unset = detected: when the
+    /// daemon reports user namespaces, the start of `userns_remap_user`'s range in
+    /// /etc/subuid and /etc/subgid.
+    pub userns_offset: Option<u32>,
+
+    /// The daemon's `userns-remap` user (`default` in daemon.json means `dockremap`).
+    pub userns_remap_user: String,
+
+    /// Let users opt their workspaces in to the ownership reconciler (`agentman ownership
+    /// watch`), which hands files created by the container's root to the container user as
+    /// they appear. Needs Docker's `userns-remap`.
+    pub watch_ownership: bool,
+}
+
+impl Default for WorkspacePermissionsConfig {
+    fn default() -> Self {
+        Self {
+            chown_mode: ChownMode::default(),
+            container_user: None,
+            userns_offset: None,
+            userns_remap_user: "dockremap".to_string(),
+            watch_ownership: false,
+        }
+    }
+}
+
+impl WorkspacePermissionsConfig {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(ref user) = self.container_user
+            && parse_container_user(user).is_none()
+        {
+            anyhow::bail!(
+                "workspace_permissions: container_user must be `uid` or `uid:gid`, got {:?}",
+                user
+            );
+        }
+        Ok(())
+    }
+
+    /// `container_user` as numeric IDs (a missing gid means the same as the uid).
+    pub fn container_ids(&self) -> Option<(u32, u32)> {
+        self.container_user.as_deref().and_then(parse_container_user)
+    }
+}
+
+fn parse_container_user(user: &str) -> Option<(u32, u32)> {
+    match user.split_once(':') {
+        Some((uid, gid)) => Some((uid.parse().ok()?, gid.parse().ok()?)),
+        None => user.parse().ok().map(|uid| (uid, uid)),
+    }
+}
+
+/// How far the gateway goes to hand workspace directories to the container user.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChownMode {
+    /// Never change ownership or permissions (ACLs, idmapped mounts, or pre-provisioned dirs).
+    None,
+    /// Chown just the workspace root when the container user can't write to it.
+    #[default]
+    RootOnly,
+    /// Chown the whole tree when its root isn't owned by the container user (e.g. after the
+    /// image's user changed). Can be slow on large workspaces.
+    Recursive,
+}
+
+/// The `agentman-helper` binary (tcp bridge, pty wrapper, file watch, process supervision),
+/// copied into each container when it is created.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HelperConfig {
+    pub enabled: bool,
+
+    /// Statically linked helper on the gateway host. Unset = `agentman-helper` next to the
+    /// gateway executable, skipped quietly when there is none.
+    pub path: Option<PathBuf>,
+}
+
+impl Default for HelperConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            path: None,
+        }
+    }
+}
+
+impl HelperConfig {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(ref path) = self.path
+            && !path.is_absolute()
+        {
+            anyhow::bail!("helper: path must be an absolute path");
+        }
+        Ok(())
+    }
+}
+
+/// Per-user editor server cache.
+///
+/// Editors bootstrap over non-PTY exec with `HOME=/workspace`, so their servers land in
+/// `/workspace/<dir>`. When enabled, each of `dirs` is bind-mounted from
+/// `<root>/<github_user>/<dir>`, shared by all of the user's containers, so a recreated or new
+/// project sandbox doesn't re-download hundreds of MB.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EditorCacheConfig {
+    pub enabled: bool,
+
+    /// Host directory holding one cache directory per GitHub user.
+    pub root: PathBuf,
+
+    /// Editor server directories (relative to `/workspace`) to share.
+    pub dirs: Vec<String>,
+}
+
+impl EditorCacheConfig {
+    fn with_root(root: PathBuf) -> Self {
+        Self {
+            enabled: false,
+            root,
+            dirs: [
+                ".vscode-server",
+                ".vscode-server-insiders",
+                ".cursor-server",
+                ".windsurf-server",
+                ".zed_server",
+            ]
+            .map(String::from)
+            .to_vec(),
+        }
+    }
+
+    /// Validate that every cache directory is a single plain path component.
+    pub fn validate(&self) -> Result<()> {
+        for dir in &self.dirs {
+            if dir.is_empty() || dir.contains('/') || dir == "." || dir == ".." || dir.contains(':') {
+                anyhow::bail!("editor_cache: dirs entries must be plain directory names: {dir:?}");
+            }
+        }
+        if self.enabled && !self.root.is_absolute() {
+            anyhow::bail!("editor_cache: root must be an absolute path");
+        }
+        Ok(())
+    }
+}
+
+impl Default for EditorCacheConfig {
+    fn default() -> Self {
+        let data_dir = dirs::data_local_dir()
+            .unwrap_or_else(|| PathBuf::from("/var/lib"))
+            .join("agentman");
+        Self::with_root(data_dir.join("editor-cache"))
+    }
+}
+
+/// Resource sampling for `agentman stats --history`.
+///
+/// Every `interval_secs`, CPU and memory of each running container are appended to a
+/// fixed-size ring buffer file per workspace under `dir`, holding `retention_hours` of samples.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StatsHistoryConfig {
+    pub enabled: bool,
+
+    /// Host directory holding `<github_user>/<project>.stats` ring buffers.
+    pub dir: PathBuf,
+
+    /// Seconds between samples.
+    pub interval_secs: u64,
+
+    /// How far back history goes; older samples are overwritten.
+    pub retention_hours: u64,
+}
+
+impl Default for StatsHistoryConfig {
+    fn default() -> Self {
+        let data_dir = dirs::data_local_dir()
+            .unwrap_or_else(|| PathBuf::from("/var/lib"))
+            .join("agentman");
+        Self {
+            enabled: true,
+            dir: data_dir.join("stats-history"),
+            interval_secs: 60,
+            retention_hours: 24,
+        }
+    }
+}
+
+impl StatsHistoryConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        if self.interval_secs < 5 {
+            anyhow::bail!("stats_history: interval_secs must be at least 5");
+        }
+        if self.retention_hours == 0 || self.retention_hours > 24 * 90 {
+            anyhow::bail!("stats_history: retention_hours must be between 1 and 2160");
+        }
+        if !self.dir.is_absolute() {
+            anyhow::bail!("stats_history: dir must be an absolute path");
+        }
+        Ok(())
+    }
+
+    /// Number of samples each ring buffer holds.
+    pub fn capacity(&self) -> u32 {
+        (self.retention_hours * 3600 / self.interval_secs.max(1)).clamp(1, u32::MAX as u64) as u32
+    }
+}
+
+/// Network traffic accounting and monthly egress quotas.
+///
+/// Every `interval_secs`, the network counters of each running container (all interfaces, as
+/// reported by `docker stats`) are added to per-workspace totals kept in a ledger per user under
+/// `dir`, so they survive container restarts and recreation. Bytes sent by a user's containers
+/// count against their monthly egress (UTC calendar months). Admins are notified when a user
+/// passes `warn_percent` of their quota and when they exceed it; `hook_command` runs at that
+/// point, and with `action = "stop"` the user's containers are stopped and not started again
+/// until the next month or a higher quota.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EgressConfig {
+    pub enabled: bool,
+
+    /// Host directory holding `<github_user>.json` ledgers.
+    pub dir: PathBuf,
+
+    /// Seconds between samples.
+    pub interval_secs: u64,
+
+    /// Monthly egress per user, in GiB (0 = unlimited).
+    pub monthly_quota_gb: u64,
+
+    /// Quotas of individual users, in GiB (0 = unlimited), overriding `monthly_quota_gb`.
+    pub user_quotas_gb: HashMap<String, u64>,
+
+    /// Share of the quota (percent) at which admins are warned.
+    pub warn_percent: u8,
+
+    /// What happens to a user over their quota.
+    pub action: EgressAction,
+
+    /// Shell command run (`sh -c`) when a user exceeds their quota, with `AGENTMAN_GITHUB_USER`,
+    /// `AGENTMAN_EGRESS_MONTH`, `AGENTMAN_EGRESS_BYTES` and `AGENTMAN_EGRESS_QUOTA_BYTES` set.
+    pub hook_command: Option<String>,
+}
+
+impl Default for EgressConfig {
+    fn default() -> Self {
+        let data_dir = dirs::data_local_dir()
+            .unwrap_or_else(|| PathBuf::from("/var/lib"))
+            .join("agentman");
+        Self {
+            enabled: true,
+            dir: data_dir.join("egress"),
+            interval_secs: 60,
+            monthly_quota_gb: 0,
+            user_quotas_gb: HashMap::new(),
+            warn_percent: 80,
+            action: EgressAction::default(),
+            hook_command: None,
+        }
+    }
+}
+
+impl EgressConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        if !self.dir.is_absolute() {
+            anyhow::bail!("egress: dir must be an absolute path");
+        }
+        if self.interval_secs < 5 {
+            anyhow::bail!("egress: interval_secs must be at least 5");
+        }
+        if !(1..=100).contains(&self.warn_percent) {
+            anyhow::bail!("egress: warn_percent must be between 1 and 100");
+        }
+        if self.hook_command.as_deref().is_some_and(|c| c.trim().is_empty()) {
+            anyhow::bail!("egress: hook_command must not be empty");
+        }
+        Ok(())
+    }
+
+    /// `github_user`'s monthly quota in bytes, `None` when unlimited.
+    pub fn quota_bytes(&self, github_user: &str) -> Option<u64> {
+        let gb = self
+            .user_quotas_gb
+            .iter()
+            .find(|(user, _)| user.eq_ignore_ascii_case(github_user))
+            .map_or(self.monthly_quota_gb, |(_, gb)| *gb);
+        (gb > 0).then(|| gb.saturating_mul(1024 * 1024 * 1024))
+    }
+}
+
+/// Per-user resource usage for chargeback.
+///
+/// Every `interval_secs`, the CPU time, memory and workspace size of each user's workspaces are
+/// added to their monthly totals (UTC calendar months) in `<dir>/<github_user>.json`, together
+/// with their egress from `[egress]`. Once a month is over, its report is written to
+/// `<dir>/reports/<YYYY-MM>.csv` and `.json`; `agentman admin usage` shows any month.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccountingConfig {
+    pub enabled: bool,
+
+    /// Host directory holding the per-user totals and the monthly reports.
+    pub dir: PathBuf,
+
+    /// Seconds between samples; each sample counts memory and disk for this long.
+    pub interval_secs: u64,
+
+    /// Months of totals kept per user (0 = forever).
+    pub retention_months: u32,
+}
+
+impl Default for AccountingConfig {
+    fn default() -> Self {
+        let data_dir = dirs::data_local_dir()
+            .unwrap_or_else(|| PathBuf::from("/var/lib"))
+            .join("agentman");
+        Self {
+            enabled: false,
+            dir: data_dir.join("usage"),
+            interval_secs: 300,
+            retention_months: 24,
+        }
+    }
+}
+
+impl AccountingConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        if !self.dir.is_absolute() {
+            anyhow::bail!("accounting: dir must be an absolute path");
+        }
+        if self.interval_secs < 30 {
+            anyhow::bail!("accounting: interval_secs must be at least 30");
+        }
+        Ok(())
+    }
+}
+
+/// What happens to a user over their monthly egress quota.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EgressAction {
+    /// Notify admins (and run `hook_command`) only.
+    #[default]
+    Notify,
+    /// Also stop the user's containers and refuse to start them until the month ends.
+    Stop,
+}
+
+/// Gateway-run HTTP CONNECT proxy for sandbox egress (`agentman proxy`).
+///
+/// Containers get `HTTPS_PROXY` pointing at `listen_addr`. The proxy only serves connections
+/// coming from agentman containers, only tunnels to hosts matching `allow`, and counts bytes
+/// per workspace.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProxyConfig {
+    pub enabled: bool,
+
+    /// Address the proxy listens on; must be reachable from containers (default: the docker0
+    /// bridge gateway, "172.17.0.1:3128").
+    pub listen_addr: String,
+
+    /// Allowed destinations: `example.com` (exact), `*.example.com` (subdomains only), or `*`
+    /// (any host). A pattern may carry its own port, e.g. `registry.local:5000`. Loopback,
+    /// private and link-local addresses are only reached through patterns without wildcards.
+    pub allow: Vec<String>,
+
+    /// Destination ports allowed for patterns without an explicit port.
+    pub allowed_ports: Vec<u16>,
+
+    /// Timeout for connecting to the destination.
+    pub connect_timeout_secs: u64,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen_addr: "172.17.0.1:3128".to_string(),
+            allow: Vec::new(),
+            allowed_ports: vec![443],
+            connect_timeout_secs: 10,
+        }
+    }
+}
+
+impl ProxyConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let addr: std::net::SocketAddr = self
+            .listen_addr
+            .parse()
+            .with_context(|| format!("proxy: invalid listen_addr {:?}", self.listen_addr))?;
+        if addr.ip().is_unspecified() || addr.ip().is_loopback() {
+            anyhow::bail!(
+                "proxy: listen_addr must be an address containers can reach (e.g. the docker0 bridge IP), not {}",
+                addr.ip()
+            );
+        }
+        if self.allow.is_empty() {
+            anyhow::bail!("proxy: allow must list at least one destination");
+        }
+        for pattern in &self.allow {
+            let (host, port) = match pattern.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (pattern.as_str(), None),
+            };
+            let wildcard_ok = host == "*" || !host.trim_start_matches("*.").contains('*');
+            if host.is_empty() || !wildcard_ok || port.is_some_and(|p| p.parse::<u16>().is_err()) {
+                anyhow::bail!("proxy: invalid allow pattern {:?}", pattern);
+            }
+        }
+        if self.connect_timeout_secs == 0 {
+            anyhow::bail!("proxy: connect_timeout_secs must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+/// CA for TLS-inspecting egress proxies, trusted by every sandbox.
+///
+/// The gateway generates the CA (with `openssl`), mounts its certificates into containers and
+/// installs them into the system trust store on create, and rotates it before it expires. The
+/// intercepting proxy signs with `<dir>/private/current.key`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProxyCaConfig {
+    pub enabled: bool,
+
+    /// Host directory for CA keys (`private/`) and the certificates mounted into containers
+    /// (`trust/`).
+    pub dir: PathBuf,
+
+    /// Lifetime of each generated CA.
+    pub validity_days: u32,
+
+    /// Generate the next CA this many days before the current one expires; both are trusted
+    /// until the old one expires.
+    pub rotate_before_days: u32,
+}
+
+impl Default for ProxyCaConfig {
+    fn default() -> Self {
+        let data_dir = dirs::data_local_dir()
+            .unwrap_or_else(|| PathBuf::from("/var/lib"))
+            .join("agentman");
+        Self {
+            enabled: false,
+            dir: data_dir.join("proxy-ca"),
+            validity_days: 365,
+            rotate_before_days: 30,
+        }
+    }
+}
+
+impl ProxyCaConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        if !self.dir.is_absolute() {
+            anyhow::bail!("proxy_ca: dir must be an absolute path");
+        }
+        if self.validity_days < 2 {
+            anyhow::bail!("proxy_ca: validity_days must be at least 2");
+        }
+        if self.rotate_before_days == 0 || self.rotate_before_days >= self.validity_days {
+            anyhow::bail!("proxy_ca: rotate_before_days must be between 1 and validity_days - 1");
+        }
+        Ok(())
+    }
+}
+
+/// Admin notifications.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NotifyConfig {
+    /// URL that receives a JSON POST per notification (`{"text": ..., "event": ...}`, which
+    /// Slack/Mattermost incoming webhooks accept as-is). Without it, notifications are only
+    /// logged.
+    pub webhook_url: Option<String>,
+
+    /// Consecutive failures of a background task (scheduled backups, offboarding checks, ...)
+    /// before admins are alerted.
+    pub failure_threshold: u32,
+
+    /// Minimum time between two alerts about the same failing task; failures in between are
+    /// counted and reported with the next alert.
+    pub failure_cooldown_secs: u64,
+}
+
+impl Default for NotifyConfig {
+    fn default() -> Self {
+        Self {
+            webhook_url: None,
+            failure_threshold: 3,
+            failure_cooldown_secs: 3600,
+        }
+    }
+}
+
+impl NotifyConfig {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(ref url) = self.webhook_url
+            && !url.starts_with("http://")
+            && !url.starts_with("https://")
+        {
+            anyhow::bail!("notify: webhook_url must be an http(s) URL");
+        }
+        if self.failure_threshold == 0 {
+            anyhow::bail!("notify: failure_threshold must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+/// Offboarding of inactive GitHub users.
+///
+/// When enabled, users with no login for `inactive_days` are flagged and admins notified.
+/// `archive_after_days` after the flag their containers are stopped, their workspaces archived
+/// to `archive_dir` and their cached keys revoked; `destroy_after_days` after the flag their
+/// containers and workspaces are destroyed. Logging in before then cancels it.
+/// `agentman admin offboard <user>` starts the same workflow by hand (archiving right away),
+/// even when `enabled` is false.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OffboardingConfig {
+    /// Flag inactive users automatically.
+    pub enabled: bool,
+
+    /// Days without a login before a user is flagged.
+    pub inactive_days: u64,
+
+    /// Days after the flag to stop containers, archive workspaces and revoke keys.
+    pub archive_after_days: u64,
+
+    /// Write a `.tar.gz` of each workspace when archiving.
+    pub archive: bool,
+
+    /// Revoke the user's cached SSH keys when archiving.
+    pub revoke_keys: bool,
+
+    /// Days after the flag to destroy containers and workspaces (unset: never destroy).
+    pub destroy_after_days: Option<u64>,
+
+    /// Host directory for workspace archives (`<user>/<project>-<time>.tar.gz`).
+    pub archive_dir: PathBuf,
+
+    /// How often the policy runs.
+    pub check_interval_secs: u64,
+}
+
+impl Default for OffboardingConfig {
+    fn default() -> Self {
+        let data_dir = dirs::data_local_dir()
+            .unwrap_or_else(|| PathBuf::from("/var/lib"))
+            .join("agentman");
+        Self {
+            enabled: false,
+            inactive_days: 90,
+            archive_after_days: 7,
+            archive: true,
+            revoke_keys: true,
+            destroy_after_days: Some(30),
+            archive_dir: data_dir.join("archives"),
+            check_interval_secs: 3600,
+        }
+    }
+}
+
+impl OffboardingConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.inactive_days == 0 {
+            anyhow::bail!("offboarding: inactive_days must be at least 1");
+        }
+        if !self.archive_dir.is_absolute() {
+            anyhow::bail!("offboarding: archive_dir must be an absolute path");
+        }
+        if self
+            .destroy_after_days
+            .is_some_and(|destroy| destroy < self.archive_after_days)
+        {
+            anyhow::bail!("offboarding: destroy_after_days must not be less than archive_after_days");
+        }
+        if self.check_interval_secs < 60 {
+            anyhow::bail!("offboarding: check_interval_secs must be at least 60");
+        }
+        Ok(())
+    }
+}
+
+/// Multi-container projects (`agentman stack`).
+///
+/// A workspace can declare a compose file at `/workspace/.agentman/compose.yaml`; its services
+/// (a database, a cache, ...) run as sidecar containers on the project network, next to the
+/// sandbox, which stays the SSH target.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StackConfig {
+    pub enabled: bool,
+
+    /// Compose file for workspaces that don't have their own.
+    pub default_compose_file: Option<PathBuf>,
+
+    /// Images sidecars may use: exact references, or prefixes ending in `*`
+    /// (e.g. `postgres:*`, `docker.io/library/*`). Empty allows any image.
+    pub allowed_images: Vec<String>,
+
+    /// Most sidecar services per workspace.
+    pub max_services: usize,
+
+    /// Host directory for sidecar volumes (`<dir>/<github_user>/<project>/<volume>`). Kept
+    /// outside the workspace so sandbox users can't swap them for symlinks to host paths.
+    pub volumes_dir: PathBuf,
+}
+
+impl Default for StackConfig {
+    fn default() -> Self {
+        let data_dir = dirs::data_local_dir()
+            .unwrap_or_else(|| PathBuf::from("/var/lib"))
+            .join("agentman");
+        Self {
+            enabled: false,
+            default_compose_file: None,
+            allowed_images: Vec::new(),
+            max_services: 5,
+            volumes_dir: data_dir.join("stack-volumes"),
+        }
+    }
+}
+
+impl StackConfig {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(ref path) = self.default_compose_file
+            && !path.is_absolute()
+        {
+            anyhow::bail!("stack: default_compose_file must be an absolute path");
+        }
+        if !self.volumes_dir.is_absolute() {
+            anyhow::bail!("stack: volumes_dir must be an absolute path");
+        }
+        if self.max_services == 0 {
+            anyhow::bail!("stack: max_services must be at least 1");
+        }
+        Ok(())
+    }
+
+    /// Whether sidecars may use `image`.
+    pub fn allows_image(&self, image: &str) -> bool {
+        self.allowed_images.is_empty()
+            || self.allowed_images.iter().any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => image.starts_with(prefix),
+                None => image == pattern,
+            })
+    }
+}
+
+/// Active/passive failover.
+///
+/// Two gateways run against the same Docker host and state file (on shared storage), and
+/// coordinate through a lease in `lock_file`, which must be on storage both can reach. The
+/// holder is active; the other waits without loading state or listening, and takes over once
+/// the lease has gone unrenewed for `lease_secs`. The hosts' clocks must be in sync (NTP).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HaConfig {
+    pub enabled: bool,
+
+    /// Lease file on shared storage.
+    pub lock_file: PathBuf,
+
+    /// This instance's name in the lease (default: the hostname). Must differ between the two.
+    pub node_id: Option<String>,
+
+    /// How long a lease stays valid without renewal; the takeover delay after a crash.
+    pub lease_secs: u64,
+
+    /// How often the active instance renews (and the passive one checks) the lease.
+    pub renew_secs: u64,
+}
+
+impl Default for HaConfig {
+    fn default() -> Self {
+        let data_dir = dirs::data_local_dir()
+            .unwrap_or_else(|| PathBuf::from("/var/lib"))
+            .join("agentman");
+        Self {
+            enabled: false,
+            lock_file: data_dir.join("gateway.lease"),
+            node_id: None,
+            lease_secs: 15,
+            renew_secs: 5,
+        }
+    }
+}
+
+impl HaConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !self.lock_file.is_absolute() {
+            anyhow::bail!("ha: lock_file must be an absolute path");
+        }
+        if self.renew_secs == 0 {
+            anyhow::bail!("ha: renew_secs must be at least 1");
+        }
+        if self.lease_secs <= 2 * self.renew_secs {
+            anyhow::bail!("ha: lease_secs must be more than twice renew_secs");
+        }
+        if let Some(ref id) = self.node_id
+            && (id.is_empty() || id.contains(['/', '\0']))
+        {
+            anyhow::bail!("ha: node_id must be non-empty and contain no '/'");
+        }
+        Ok(())
+    }
+}
+
+/// Git commit signing with the user's GitHub SSH signing keys.
+///
+/// On container creation the gateway fetches the user's signing keys from GitHub and sets up
+/// git inside the container to sign with one of them (`gpg.format = ssh`) and to verify against
+/// all of them (`gpg.ssh.allowedSignersFile`). The private key never leaves the laptop: git signs
+/// through the forwarded agent (`ssh -A`), which must hold that key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GitSigningConfig {
+    pub enabled: bool,
+
+    /// Also set `commit.gpgsign = true`, unless the user configured signing themselves.
+    pub sign_commits: bool,
+
+    /// How long fetched keys are reused before asking GitHub again.
+    pub cache_secs: u64,
+}
+
+impl Default for GitSigningConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            sign_commits: true,
+            cache_secs: 3600,
+        }
+    }
+}
+
+/// Throwaway sandboxes (`ssh tmp+<user>@gateway` or `agentman ephemeral`).
+///
+/// An ephemeral sandbox is a fresh container whose `/workspace` is a tmpfs of `workspace_size`.
+/// It belongs to one SSH connection: every session and forward of that connection uses it, and
+/// it is removed (with everything in it) when the connection ends. It is not recorded as a
+/// workspace, so it doesn't show up in `agentman list` or count against workspace limits.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EphemeralConfig {
+    pub enabled: bool,
+
+    /// Project name in the SSH user that asks for an ephemeral sandbox (reserved while enabled).
+    pub project: String,
+
+    /// Size of the in-memory `/workspace` (e.g. "512m", "1g"); counts against the memory limit.
+    pub workspace_size: String,
+
+    /// Most ephemeral sandboxes a user can have at once.
+    pub max_per_user: usize,
+}
+
+impl Default for EphemeralConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            project: "tmp".to_string(),
+            workspace_size: "1g".to_string(),
+            max_per_user: 3,
+        }
+    }
+}
+
+impl EphemeralConfig {
+    pub fn validate(&self) -> Result<()> {
+        if let Err(e) = crate::github::validate_project_name(&self.project) {
+            anyhow::bail!("ephemeral: project is not a valid project name: {e}");
+        }
+        let digits = self.workspace_size.trim_end_matches(['k', 'm', 'g']);
+        if digits.is_empty()
+            || !digits.bytes().all(|b| b.is_ascii_digit())
+            || self.workspace_size.len() > digits.len() + 1
+        {
+            anyhow::bail!(
+                "ephemeral: workspace_size must be a number with an optional k, m or g suffix"
+            );
+        }
+        if self.max_per_user == 0 {
+            anyhow::bail!("ephemeral: max_per_user must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+/// SSH server tuning and hardening.
+///
+/// The defaults match russh's, except for `handshake_timeout_secs`, which closes connections
+/// that haven't authenticated in time (like OpenSSH's `LoginGraceTime`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SshConfig {
+    /// Authentication attempts a connection may make before it is closed.
+    pub max_auth_attempts: usize,
+
+    /// Text clients show before authentication (e.g. a usage policy).
+    pub banner: Option<String>,
+
+    /// Re-key after this much traffic in either direction (MiB, at most 1024).
+    pub rekey_limit_mb: usize,
+
+    /// Re-key after this long.
+    pub rekey_interval_secs: u64,
+
+    /// Initial flow-control window of each channel (bytes).
+    pub window_size: u32,
+
+    /// Largest packet the gateway accepts on a channel (bytes).
+    pub maximum_packet_size: u32,
+
+    /// Close connections that haven't authenticated within this long; 0 = no limit.
+    pub handshake_timeout_secs: u64,
+
+    /// Serve the `sftp` subsystem (`sftp`, `scp -s`, editor file browsers) by running
+    /// OpenSSH's `sftp-server` in the sandbox, starting in `/workspace`.
+    pub sftp: bool,
+
+    /// Path of `sftp-server` in the sandbox image; unset = look in the usual distro locations.
+    pub sftp_server: Option<String>,
+}
+
+impl Default for SshConfig {
+    fn default() -> Self {
+        Self {
+            max_auth_attempts: 10,
+            banner: None,
+            rekey_limit_mb: 1024,
+            rekey_interval_secs: 3600,
+            window_size: 2 * 1024 * 1024,
+            maximum_packet_size: 32768,
+            handshake_timeout_secs: 120,
+            sftp: true,
+            sftp_server: None,
+        }
+    }
+}
+
+impl SshConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.max_auth_attempts == 0 {
+            anyhow::bail!("ssh: max_auth_attempts must be at least 1");
+        }
+        // Larger limits could reuse nonces before the next key exchange.
+        if !(1..=1024).contains(&self.rekey_limit_mb) {
+            anyhow::bail!("ssh: rekey_limit_mb must be between 1 and 1024");
+        }
+        if self.rekey_interval_secs < 60 {
+            anyhow::bail!("ssh: rekey_interval_secs must be at least 60");
+        }
+        if !(4096..=262144).contains(&self.maximum_packet_size) {
+            anyhow::bail!("ssh: maximum_packet_size must be between 4096 and 262144");
+        }
+        if self.window_size < self.maximum_packet_size {
+            anyhow::bail!("ssh: window_size must be at least maximum_packet_size");
+        }
+        Ok(())
+    }
+}
+
+/// Wire-level SSH compression.
+///
+/// When enabled, `zlib@openssh.com` and `zlib` are offered during key exchange; clients that ask
+/// for compression (`ssh -C`, `Compression yes`) then have all their traffic compressed, which
+/// helps on slow links carrying lots of agent output. Clients that don't ask are unaffected.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+
+    /// Connections that send less channel data than this are left out of the compression ratio
+    /// metrics (they still count towards the negotiated algorithms).
+    pub threshold_bytes: u64,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            threshold_bytes: 64 * 1024,
+        }
+    }
+}
+
+/// Sandbox benchmark (`agentman bench`).
+///
+/// The benchmark hashes zeros with SHA-256 on one and on all CPUs, writes and reads back a file
+/// in `/workspace`, creates small files there, and downloads `network_url` with curl. It is
+/// meant to finish within seconds, so sizes are kept small.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BenchConfig {
+    pub enabled: bool,
+
+    /// MiB hashed per CPU.
+    pub cpu_mb: u64,
+
+    /// MiB written to and read back from `/workspace`.
+    pub disk_mb: u64,
+
+    /// Downloaded to measure network latency and throughput (skipped when unset).
+    pub network_url: Option<String>,
+
+    /// Time limit for the whole benchmark.
+    pub timeout_secs: u64,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            cpu_mb: 256,
+            disk_mb: 256,
+            network_url: None,
+            timeout_secs: 120,
+        }
+    }
+}
+
+impl BenchConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.cpu_mb == 0 || self.disk_mb == 0 {
+            anyhow::bail!("bench: cpu_mb and disk_mb must be at least 1");
+        }
+        if self.timeout_secs == 0 {
+            anyhow::bail!("bench: timeout_secs must be at least 1");
+        }
+        if let Some(url) = &self.network_url
+            && !(url.starts_with("http://") || url.starts_with("https://"))
+        {
+            anyhow::bail!("bench: network_url must be an http:// or https:// URL");
+        }
+        Ok(())
+    }
+}
+
+/// TCP router for TLS services in sandboxes.
+///
+/// The router listens on one port and reads the server name (SNI) of each connection's TLS
+/// ClientHello without terminating TLS: `<project>--<github-user>.<domain>` is relayed, bytes
+/// untouched, to the port the workspace exposed with `agentman tls`. Point a wildcard DNS record
+/// (`*.<domain>`) at the gateway; certificates are the services' own business.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SniRouterConfig {
+    pub enabled: bool,
+
+    /// Address to listen on, e.g. "0.0.0.0:443".
+    pub listen_addr: String,
+
+    /// Parent domain of the sandbox hostnames, e.g. "tls.example.com".
+    pub domain: String,
+
+    /// Time a client gets to send its ClientHello.
+    pub client_hello_timeout_secs: u64,
+}
+
+impl Default for SniRouterConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen_addr: "0.0.0.0:443".to_string(),
+            domain: String::new(),
+            client_hello_timeout_secs: 10,
+        }
+    }
+}
+
+impl SniRouterConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        if self.listen_addr.parse::<std::net::SocketAddr>().is_err() {
+            anyhow::bail!(
+                "sni_router: listen_addr must be ip:port, got {:?}",
+                self.listen_addr
+            );
+        }
+        if !is_dns_name(&self.domain) {
+            anyhow::bail!(
+                "sni_router: domain must be a lowercase DNS name like \"tls.example.com\", got {:?}",
+                self.domain
+            );
+        }
+        if self.client_hello_timeout_secs == 0 {
+            anyhow::bail!("sni_router: client_hello_timeout_secs must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+/// Whether `domain` is a lowercase DNS name like "tls.example.com".
+fn is_dns_name(domain: &str) -> bool {
+    domain.split('.').all(|label| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+    })
+}
+
+/// Experimental checkpoint/restore of running sandboxes (`agentman checkpoint` / `resume`).
+///
+/// Checkpoints are taken by the Docker daemon with CRIU, which has to be installed on the
+/// Docker host, and need the daemon's experimental features (`"experimental": true` in
+/// `daemon.json`). The gateway drives them through the `docker` CLI, since the API client has
+/// no checkpoint support. A checkpoint holds the memory of every process in the sandbox, so
+/// only the latest one per workspace is kept.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CheckpointConfig {
+    pub enabled: bool,
+
+    /// Directory holding `<github_user>/<project>/<checkpoint>`, at the same path on the
+    /// gateway and the Docker host. Put it on storage shared with other hosts to resume
+    /// sandboxes there.
+    pub dir: PathBuf,
+
+    /// Time limit for taking or restoring a checkpoint.
+    pub timeout_secs: u64,
+}
+
+impl Default for CheckpointConfig {
+    fn default() -> Self {
+        let data_dir = dirs::data_local_dir()
+            .unwrap_or_else(|| PathBuf::from("/var/lib"))
+            .join("agentman");
+        Self {
+            enabled: false,
+            dir: data_dir.join("checkpoints"),
+            timeout_secs: 600,
+        }
+    }
+}
+
+impl CheckpointConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        if !self.dir.is_absolute() {
+            anyhow::bail!("checkpoint: dir must be an absolute path");
+        }
+        if self.timeout_secs == 0 {
+            anyhow::bail!("checkpoint: timeout_secs must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+/// Workspace content checks (`agentman scan`, and periodically with `enabled`).
+///
+/// Workspaces are bind mounts, so a symlink in one is followed by anything on the host that
+/// reads the workspace. The scan reports symlinks pointing outside `/workspace`, setuid or
+/// setgid files anyone can write, and files of `large_file_mb` or more. The periodic scan
+/// notifies admins and the owner when a workspace's findings change.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScanConfig {
+    /// Scan every workspace in the background.
+    pub enabled: bool,
+
+    /// Time between background scans.
+    pub interval_secs: u64,
+
+    /// Files at least this large (MiB) are reported; 0 = don't report sizes.
+    pub large_file_mb: u64,
+
+    /// Stop walking a workspace after this many entries.
+    pub max_entries: u64,
+
+    /// Findings listed per workspace (the rest are counted).
+    pub max_findings: usize,
+}
+
+impl Default for ScanConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_secs: 86400,
+            large_file_mb: 1024,
+            max_entries: 1_000_000,
+            max_findings: 50,
+        }
+    }
+}
+
+impl ScanConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.interval_secs < 300 {
+            anyhow::bail!("scan: interval_secs must be at least 300");
+        }
+        if self.max_entries == 0 || self.max_findings == 0 {
+            anyhow::bail!("scan: max_entries and max_findings must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+/// Guest invites (`agentman guest invite`).
+///
+/// An invite is an SSH login that lets someone without an account reach the inviter's project
+/// until it expires: the first key used with it is bound to it, and the guest's activity is
+/// audited under the inviter. Read-only guests only watch the shared tmux session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GuestsConfig {
+    pub enabled: bool,
+
+    /// Lifetime of an invite when `--ttl` isn't given.
+    pub default_ttl_secs: u64,
+
+    /// Longest lifetime an invite may have.
+    pub max_ttl_secs: u64,
+
+    /// Unexpired invites a user may have at once.
+    pub max_per_user: usize,
+}
+
+impl Default for GuestsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            default_ttl_secs: 3600,
+            max_ttl_secs: 86400,
+            max_per_user: 5,
+        }
+    }
+}
+
+impl GuestsConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.default_ttl_secs == 0 || self.default_ttl_secs > self.max_ttl_secs {
+            anyhow::bail!("guests: default_ttl_secs must be between 1 and max_ttl_secs");
+        }
+        if self.max_per_user == 0 {
+            anyhow::bail!("guests: max_per_user must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+/// Admin access to other users' projects (`agentman admin access`).
+///
+/// An admin names the project and a reason, then logs in to it with their own key for
+/// `ttl_secs`. The owner is told at their next login and through their notifier, and the
+/// sessions are audited under the owner with an `impersonation` record per login.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImpersonationConfig {
+    pub enabled: bool,
+
+    /// How long a grant lets the admin log in.
+    pub ttl_secs: u64,
+}
+
+impl Default for ImpersonationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ttl_secs: 3600,
+        }
+    }
+}
+
+impl ImpersonationConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.ttl_secs == 0 {
+            anyhow::bail!("impersonation: ttl_secs must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+/// Stopping idle sandboxes.
+///
+/// A sandbox is idle once no SSH connection (shell, command, forward) has used it for
+/// `timeout_secs` and it has no running `agentman run --detach` job. Its container is stopped,
+/// not removed; the next connection starts it again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IdleConfig {
+    /// Stop sandboxes idle this long. Unset = never.
+    pub timeout_secs: Option<u64>,
+
+    /// How often to look for idle sandboxes.
+    pub check_interval_secs: u64,
+}
+
+impl Default for IdleConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: None,
+            check_interval_secs: 60,
+        }
+    }
+}
+
+impl IdleConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.timeout_secs == Some(0) {
+            anyhow::bail!("idle: timeout_secs must be at least 1 (leave it unset to never stop)");
+        }
+        if self.check_interval_secs == 0 {
+            anyhow::bail!("idle: check_interval_secs must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+/// Expiring HTTP preview links (`agentman expose <port> --preview <duration>`).
+///
+/// Each link is a hostname under `domain` carrying a signature made with the key at
+/// `key_path`; anyone with the link reaches the port in the sandbox without logging in until
+/// it expires or is revoked. The gateway serves plain HTTP on `listen_addr` and relays requests
+/// unchanged. Point a wildcard DNS record (`*.<domain>`) at it, usually through a reverse
+/// proxy that terminates TLS for the wildcard certificate. Removing the key file revokes every
+/// link.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PreviewConfig {
+    pub enabled: bool,
+
+    /// Address to listen on, e.g. "127.0.0.1:8080" behind a TLS-terminating proxy.
+    pub listen_addr: String,
+
+    /// Parent domain of the preview hostnames, e.g. "preview.example.com".
+    pub domain: String,
+
+    /// Scheme of the printed links: "https" behind a TLS-terminating proxy, else "http".
+    pub scheme: String,
+
+    /// Signing key, generated on first use.
+    pub key_path: PathBuf,
+
+    /// Longest lifetime a link may have.
+    pub max_ttl_secs: u64,
+
+    /// Unexpired links a user may have at once.
+    pub max_per_user: usize,
+
+    /// Time a client gets to send its request headers.
+    pub request_timeout_secs: u64,
+}
+
+impl Default for PreviewConfig {
+    fn default() -> Self {
+        let data_dir = dirs::data_local_dir()
+            .unwrap_or_else(|| PathBuf::from("/var/lib"))
+            .join("agentman");
+        Self {
+            enabled: false,
+            listen_addr: "127.0.0.1:8080".to_string(),
+            domain: String::new(),
+            scheme: "https".to_string(),
+            key_path: data_dir.join("preview_key"),
+            max_ttl_secs: 7 * 86400,
+            max_per_user: 10,
+            request_timeout_secs: 10,
+        }
+    }
+}
+
+impl PreviewConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        if self.listen_addr.parse::<std::net::SocketAddr>().is_err() {
+            anyhow::bail!(
+                "preview: listen_addr must be ip:port, got {:?}",
+                self.listen_addr
+            );
+        }
+        if !is_dns_name(&self.domain) {
+            anyhow::bail!(
+                "preview: domain must be a lowercase DNS name like \"preview.example.com\", got {:?}",
+                self.domain
+            );
+        }
+        if self.scheme != "http" && self.scheme != "https" {
+            anyhow::bail!("preview: scheme must be \"http\" or \"https\"");
+        }
+        if self.max_ttl_secs == 0 {
+            anyhow::bail!("preview: max_ttl_secs must be at least 1");
+        }
+        if self.max_per_user == 0 {
+            anyhow::bail!("preview: max_per_user must be at least 1");
+        }
+        if self.request_timeout_secs == 0 {
+            anyhow::bail!("preview: request_timeout_secs must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+/// HTTP admin API (`[admin_api]`).
+///
+/// Lists workspaces with their container status, force-destroys sandboxes and flushes the
+/// GitHub key cache without an SSH session. Every request needs `Authorization: Bearer <token>`
+/// with the token in `token_file`. The API speaks plain HTTP; keep it on a private address.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AdminApiConfig {
+    pub enabled: bool,
+
+    /// Address to listen on, e.g. "127.0.0.1:9090".
+    pub listen_addr: String,
+
+    /// File holding the bearer token (surrounding whitespace is ignored).
+    pub token_file: Option<PathBuf>,
+}
+
+impl Default for AdminApiConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen_addr: "127.0.0.1:9090".to_string(),
+            token_file: None,
+        }
+    }
+}
+
+impl AdminApiConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        if self.listen_addr.parse::<std::net::SocketAddr>().is_err() {
+            anyhow::bail!(
+                "admin_api: listen_addr must be ip:port, got {:?}",
+                self.listen_addr
+            );
+        }
+        if self.token_file.is_none() {
+            anyhow::bail!("admin_api: token_file is required");
+        }
+        Ok(())
+    }
+}
+
+/// Translated gateway messages (`agentman lang`).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct I18nConfig {
+    /// Language before login, for guests and for users who haven't run `agentman lang set`:
+    /// "en", "tr" or "de".
+    pub default_lang: crate::i18n::Lang,
+}
+
+/// Clients that stop reading command output.
+///
+/// The gateway hands an exec's output to the SSH connection as the client's window allows.
+/// Once `max_buffered_bytes` of a channel's output is waiting for a client that doesn't read
+/// it, the gateway stops reading the command's output, so the command blocks on its next write
+/// instead of the gateway buffering without limit. A channel stalled for `warn_after_secs` is
+/// logged; with `close_after_secs`, it is closed and its command hung up.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SlowClientsConfig {
+    /// Output held for one channel before the command's output is no longer read.
+    pub max_buffered_bytes: u64,
+
+    /// Log a channel whose client has read nothing for this long.
+    pub warn_after_secs: u64,
+
+    /// Close a channel whose client has read nothing for this long. Unset = wait forever.
+    pub close_after_secs: Option<u64>,
+
+    /// Tell the user (through `agentman notify`) when one of their channels is closed.
+    pub notify: bool,
+}
+
+impl Default for SlowClientsConfig {
+    fn default() -> Self {
+        Self {
+            max_buffered_bytes: 8 * 1024 * 1024,
+            warn_after_secs: 30,
+            close_after_secs: None,
+            notify: false,
+        }
+    }
+}
+
+impl SlowClientsConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.max_buffered_bytes < 64 * 1024 {
+            anyhow::bail!("slow_clients: max_buffered_bytes must be at least 65536");
+        }
+        if self.warn_after_secs == 0 {
+            anyhow::bail!("slow_clients: warn_after_secs must be at least 1");
+        }
+        if let Some(close) = self.close_after_secs
+            && close < self.warn_after_secs
+        {
+            anyhow::bail!(
+                "slow_clients: close_after_secs ({close}) must be at least warn_after_secs ({})",
+                self.warn_after_secs
+            );
+        }
+        if self.notify && self.close_after_secs.is_none() {
+            anyhow::bail!("slow_clients: notify needs close_after_secs");
+        }
+        Ok(())
+    }
+}
+
+/// Commands run from inside one sandbox in another project of the same user, for agents that
+/// orchestrate several sandboxes. A sandbox talks to a Unix socket the gateway serves in its
+/// workspace (`/workspace/.agentman-mesh.sock`, used by `agentman-helper exec`); the gateway
+/// runs the command in the target's container, so Docker is never exposed. Each project only
+/// reaches the projects its owner allowed with `agentman mesh allow`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MeshConfig {
+    pub enabled: bool,
+
+    /// Longest a command may run before its processes are killed (exit status 124).
+    pub max_duration_secs: u64,
+
+    /// Commands a workspace may have running in other sandboxes at once.
+    pub max_concurrent: usize,
+}
+
+impl Default for MeshConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_duration_secs: 3600,
+            max_concurrent: 4,
+        }
+    }
+}
+
+impl MeshConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.max_duration_secs == 0 {
+            anyhow::bail!("mesh: max_duration_secs must be at least 1");
+        }
+        if self.max_concurrent == 0 {
+            anyhow::bail!("mesh: max_concurrent must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+/// Repository cloned into `/workspace` when a workspace is created (`[clone]`).
+///
+/// The URL comes from the user's `AGENTMAN_CLONE_URL` SSH environment variable (with
+/// `allow_env`), else from `url_template`; without either, workspaces start empty. A clone that
+/// fails or is cut short is retried each time the sandbox starts, up to `max_attempts` times.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CloneConfig {
+    /// URL of the repository for a new workspace, with `{user}` and `{project}` replaced, e.g.
+    /// `https://github.com/{user}/{project}.git`.
+    pub url_template: Option<String>,
+
+    /// Let users pick the repository with `AGENTMAN_CLONE_URL` (`ssh -o
+    /// SetEnv=AGENTMAN_CLONE_URL=<url>`).
+    pub allow_env: bool,
+
+    /// Longest a clone may take before it counts as failed.
+    pub timeout_secs: u64,
+
+    /// Clones tried for a workspace before giving up.
+    pub max_attempts: u32,
+}
+
+impl Default for CloneConfig {
+    fn default() -> Self {
+        Self {
+            url_template: None,
+            allow_env: true,
+            timeout_secs: 600,
+            max_attempts: 3,
+        }
+    }
+}
+
+impl CloneConfig {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(template) = &self.url_template {
+            let url = template.replace("{user}", "user").replace("{project}", "project");
+            if crate::template::validate_url(&url).is_err() {
+                anyhow::bail!(
+                    "clone: url_template must be an https://, ssh:// or git@host:path git URL"
+                );
+            }
+        }
+        if self.timeout_secs == 0 {
+            anyhow::bail!("clone: timeout_secs must be at least 1");
+        }
+        if self.max_attempts == 0 {
+            anyhow::bail!("clone: max_attempts must be at least 1");
+        }
+        Ok(())
+    }
+
+    /// The template's repository for a new workspace.
+    pub fn template_url(&self, github_user: &str, project: &str) -> Option<String> {
+        let template = self.url_template.as_ref()?;
+        Some(template.replace("{user}", github_user).replace("{project}", project))
+    }
+}
+
+/// Devices users log in from (`[trust]`, `agentman trust`).
+///
+/// Each key a user logs in with is a device, recorded with the addresses it connected from.
+/// The first login with a new key (other than a user's very first) is reported to the user at
+/// that login and through their notifier, and users can revoke a device so the gateway refuses
+/// its key even while GitHub still lists it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TrustConfig {
+    pub enabled: bool,
+
+    /// Tell users about logins from new devices.
+    pub notify: bool,
+
+    /// Devices remembered per user; beyond it the one unused the longest is forgotten
+    /// (revoked ones are always kept).
+    pub max_devices: usize,
+
+    /// Addresses remembered per device.
+    pub max_addresses: usize,
+}
+
+impl Default for TrustConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            notify: true,
+            max_devices: 20,
+            max_addresses: 5,
+        }
+    }
+}
+
+impl TrustConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.max_devices == 0 {
+            anyhow::bail!("trust: max_devices must be at least 1");
+        }
+        if self.max_addresses == 0 {
+            anyhow::bail!("trust: max_addresses must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+/// Per-user resource quotas.
+///
+/// A sandbox isn't created or started when the user's running sandboxes would then be more
+/// than `max_containers`, or reserve more than `max_memory` or `max_cpus` between them.
+/// Memory and CPUs are counted from the containers' limits (`[container_security]` and
+/// security profiles), so a container without a limit counts as none. Sandboxes already
+/// running are never stopped.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QuotaConfig {
+    pub enabled: bool,
+
+    /// Running sandboxes per user. Unset = unlimited.
+    pub max_containers: Option<usize>,
+
+    /// Memory limits of a user's running sandboxes added up (e.g., "16g"). Unset = unlimited.
+    pub max_memory: Option<String>,
+
+    /// CPU limits of a user's running sandboxes added up. Unset = unlimited.
+    pub max_cpus: Option<f64>,
+
+    /// Quotas of individual users; what a user's entry leaves unset falls back to the above.
+    pub users: HashMap<String, UserQuota>,
+}
+
+/// One user's quota overrides (`[quotas.users.<github_user>]`).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UserQuota {
+    pub max_containers: Option<usize>,
+    pub max_memory: Option<String>,
+    pub max_cpus: Option<f64>,
+}
+
+impl QuotaConfig {
+    pub fn validate(&self) -> Result<()> {
+        let defaults = UserQuota {
+            max_containers: self.max_containers,
+            max_memory: self.max_memory.clone(),
+            max_cpus: self.max_cpus,
+        };
+        let users = self.users.iter().map(|(user, quota)| (format!("user {user}"), quota));
+        let all = std::iter::once(("the default quota".to_string(), &defaults)).chain(users);
+        for (whose, quota) in all {
+            if quota.max_containers == Some(0) {
+                anyhow::bail!("quotas: max_containers of {whose} must be at least 1");
+            }
+            if let Some(memory) = &quota.max_memory {
+                crate::docker::parse_memory_limit(memory).map_err(|_| {
+                    anyhow::anyhow!("quotas: max_memory of {whose} must be like \"16g\"")
+                })?;
+            }
+            if quota.max_cpus.is_some_and(|cpus| cpus <= 0.0) {
+                anyhow::bail!("quotas: max_cpus of {whose} must be positive");
+            }
+        }
+        Ok(())
+    }
+
+    /// `github_user`'s quota: their entry in `users` (matched case-insensitively), with the
+    /// defaults for what it leaves unset. `None` when quotas are disabled.
+    pub fn for_user(&self, github_user: &str) -> Option<UserQuota> {
+        if !self.enabled {
+            return None;
+        }
+        let own = self
+            .users
+            .iter()
+            .find(|(user, _)| user.eq_ignore_ascii_case(github_user))
+            .map(|(_, quota)| quota.clone())
+            .unwrap_or_default();
+        Some(UserQuota {
+            max_containers: own.max_containers.or(self.max_containers),
+            max_memory: own.max_memory.or_else(|| self.max_memory.clone()),
+            max_cpus: own.max_cpus.or(self.max_cpus),
+        })
+    }
+}
+
+/// Self-monitoring of the gateway process.
+///
+/// Over `max_rss_mb` or `max_tasks`, the gateway refuses new SSH channels and forwards (open
+/// ones keep working) until it is back under `resume_percent` of the limits. With
+/// `restart_after_secs`, it exits with status 75 once it has been over a limit that long, for
+/// its supervisor to restart it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MemoryGuardConfig {
+    pub enabled: bool,
+
+    /// Time between samples.
+    pub interval_secs: u64,
+
+    /// Resident memory of the gateway process (MiB); 0 = no limit.
+    pub max_rss_mb: u64,
+
+    /// Live tokio tasks; 0 = no limit.
+    pub max_tasks: usize,
+
+    /// Load shedding stops below this percentage of the limits.
+    pub resume_percent: u8,
+
+    /// Exit after this long over a limit; 0 = never.
+    pub restart_after_secs: u64,
+}
+
+impl Default for MemoryGuardConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_secs: 5,
+            max_rss_mb: 2048,
+            max_tasks: 100_000,
+            resume_percent: 90,
+            restart_after_secs: 0,
+        }
+    }
+}
+
+impl MemoryGuardConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.interval_secs == 0 {
+            anyhow::bail!("memory_guard: interval_secs must be at least 1");
+        }
+        if !(1..=100).contains(&self.resume_percent) {
+            anyhow::bail!("memory_guard: resume_percent must be between 1 and 100");
+        }
+        if self.enabled && self.max_rss_mb == 0 && self.max_tasks == 0 {
+            anyhow::bail!("memory_guard: set max_rss_mb or max_tasks (or disable the guard)");
+        }
+        Ok(())
+    }
+}
+
+/// Messages from sandboxes to their owners (`agentman notify`).
+///
+/// Users pick where messages go (`agentman notify set webhook|email`); scripts send them with
+/// `agentman notify <message>` over SSH, or from inside the sandbox through a Unix socket the
+/// gateway serves in each workspace (`/workspace/.agentman-notify.sock`, used by
+/// `agentman-helper notify`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UserNotifyConfig {
+    pub enabled: bool,
+
+    /// Serve the notify socket in each workspace.
+    pub socket: bool,
+
+    /// sendmail-compatible binary used for email notifiers (run as `sendmail -t -i`). Unset =
+    /// only webhooks.
+    pub sendmail: Option<PathBuf>,
+
+    /// Sender address of notification emails.
+    pub from: String,
+
+    /// Longest message accepted.
+    pub max_message_bytes: usize,
+
+    /// Messages a workspace may send per hour.
+    pub max_per_hour: u32,
+
+    /// Allow webhooks on loopback, private and link-local addresses (off: the gateway would
+    /// otherwise POST into its own network on users' behalf).
+    pub allow_private_webhooks: bool,
+}
+
+impl Default for UserNotifyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            socket: true,
+            sendmail: None,
+            from: "agentman@localhost".to_string(),
+            max_message_bytes: 2000,
+            max_per_hour: 30,
+            allow_private_webhooks: false,
+        }
+    }
+}
+
+impl UserNotifyConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        if let Some(ref sendmail) = self.sendmail
+            && !sendmail.is_absolute()
+        {
+            anyhow::bail!("user_notify: sendmail must be an absolute path");
+        }
+        if !self.from.contains('@') || self.from.chars().any(|c| c.is_whitespace()) {
+            anyhow::bail!("user_notify: from must be an email address");
+        }
+        if !(1..=65536).contains(&self.max_message_bytes) {
+            anyhow::bail!("user_notify: max_message_bytes must be between 1 and 65536");
+        }
+        if self.max_per_hour == 0 {
+            anyhow::bail!("user_notify: max_per_hour must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+/// Admission control for container creation.
+///
+/// Creating a sandbox is the expensive part of a first login (image layers, the container's
+/// filesystem, `/workspace` setup). Creations beyond `max_concurrent` wait in a queue, served
+/// in arrival order, and their SSH clients are told their place in it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AdmissionConfig {
+    pub enabled: bool,
+
+    /// Containers created at the same time.
+    pub max_concurrent: usize,
+
+    /// Containers created per minute. 0 = no limit.
+    pub max_per_minute: u32,
+
+    /// Creations allowed to wait; more are refused.
+    pub max_queue: usize,
+
+    /// How long a creation may wait before it is given up.
+    pub queue_timeout_secs: u64,
+}
+
+impl Default for AdmissionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_concurrent: 4,
+            max_per_minute: 0,
+            max_queue: 200,
+            queue_timeout_secs: 600,
+        }
+    }
+}
+
+impl AdmissionConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        if self.max_concurrent == 0 {
+            anyhow::bail!("admission: max_concurrent must be at least 1");
+        }
+        if self.queue_timeout_secs == 0 {
+            anyhow::bail!("admission: queue_timeout_secs must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+/// Base URLs of GitHub, for GitHub Enterprise Server or for tests against a fake server
+/// (`--fake-github` in builds with the `fake-github` feature).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GitHubConfig {
+    /// Serves `/<user>.keys`.
+    pub keys_url: String,
+
+    /// Serves the REST API (`/users/<user>`, `/orgs/...`).
+    pub api_url: String,
+}
+
+impl Default for GitHubConfig {
+    fn default() -> Self {
+        Self {
+            keys_url: "https://github.com".to_string(),
+            api_url: "https://api.github.com".to_string(),
+        }
+    }
+}
+
+impl GitHubConfig {
+    pub fn validate(&self) -> Result<()> {
+        for (name, url) in [("keys_url", &self.keys_url), ("api_url", &self.api_url)] {
+            let rest = url
+                .strip_prefix("https://")
+                .or_else(|| url.strip_prefix("http://"));
+            if rest.is_none_or(|rest| rest.is_empty() || rest.contains(['?', '#'])) {
+                anyhow::bail!("github: {name} must be an http(s) URL without a query, not {url:?}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether lookups go somewhere other than github.com.
+    pub fn is_custom(&self) -> bool {
+        let default = Self::default();
+        self.keys_url != default.keys_url || self.api_url != default.api_url
+    }
+}
+
+/// Display metadata of GitHub users (name, avatar URL), from the unauthenticated API.
+///
+/// Lookups go through a cache, so the API's limit of 60 requests an hour per address is
+/// rarely reached; when it is, users are shown by login until it resets.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GitHubMetadataConfig {
+    pub enabled: bool,
+
+    /// How long a fetched profile is used before it is fetched again.
+    pub cache_secs: u64,
+
+    /// Greet users by name at interactive logins.
+    pub greeting: bool,
+}
+
+impl Default for GitHubMetadataConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            cache_secs: 86400,
+            greeting: false,
+        }
+    }
+}
+
+impl GitHubMetadataConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.enabled && self.cache_secs < 60 {
+            anyhow::bail!("github_metadata: cache_secs must be at least 60");
+        }
+        Ok(())
+    }
+}
+
+/// Per-workspace dotenv files held by the gateway (`agentman env-file`).
+///
+/// `agentman env-file set < .env` stores the file under `dir`, outside the workspace, so an
+/// agent can't commit it; its variables go into every exec and new container of the workspace.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EnvFileConfig {
+    pub enabled: bool,
+
+    /// Host directory holding `<github_user>/<project>.env` (readable only by the gateway).
+    pub dir: PathBuf,
+
+    /// Largest file accepted.
+    pub max_bytes: usize,
+}
+
+impl Default for EnvFileConfig {
+    fn default() -> Self {
+        let data_dir = dirs::data_local_dir()
+            .unwrap_or_else(|| PathBuf::from("/var/lib"))
+            .join("agentman");
+        Self {
+            enabled: true,
+            dir: data_dir.join("env-files"),
+            max_bytes: 64 * 1024,
+        }
+    }
+}
+
+impl EnvFileConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        if !self.dir.is_absolute() {
+            anyhow::bail!("env_file: dir must be an absolute path");
+        }
+        if !(1..=1024 * 1024).contains(&self.max_bytes) {
+            anyhow::bail!("env_file: max_bytes must be between 1 and 1048576");
+        }
+        Ok(())
+    }
+}
+
+/// Sandbox images built from a workspace Dockerfile (`agentman build`).
+///
+/// The directory holding the Dockerfile is sent to the Docker daemon as the build context and
+/// built with the limits below; the result is tagged `<repository>/<github_user>:<project>` and
+/// only that workspace may switch to it (`agentman build --use`). Containers from built images
+/// get the same security settings as any other sandbox.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BuildConfig {
+    pub enabled: bool,
+
+    /// Dockerfile built when `--file` isn't given, relative to `/workspace`.
+    pub dockerfile: String,
+
+    /// Repository of built images.
+    pub repository: String,
+
+    /// Time limit for a build.
+    pub timeout_secs: u64,
+
+    /// Memory limit of the build's containers (MiB, at most 2047).
+    pub memory_mb: u32,
+
+    /// CPUs the build's containers may use.
+    pub cpus: f64,
+
+    /// Largest build context accepted (the Dockerfile's directory, as a tar archive).
+    pub max_context_mb: u64,
+
+    /// Whether `RUN` steps have network access (package installs usually need it).
+    pub network: bool,
+
+    /// Images `FROM` may name; empty = any.
+    pub base_images: Vec<String>,
+
+    /// Builds running at once on the gateway.
+    pub max_concurrent: usize,
+}
+
+impl Default for BuildConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dockerfile: ".agentman/Dockerfile".to_string(),
+            repository: "agentman-build".to_string(),
+            timeout_secs: 1800,
+            memory_mb: 1024,
+            cpus: 2.0,
+            max_context_mb: 100,
+            network: true,
+            base_images: Vec::new(),
+            max_concurrent: 2,
+        }
+    }
+}
+
+impl BuildConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        if self.dockerfile.is_empty() || self.dockerfile.starts_with('/') {
+            anyhow::bail!("build: dockerfile must be a path relative to /workspace");
+        }
+        let valid_component = |c: &str| {
+            !c.is_empty()
+                && c.chars()
+                    .all(|ch| ch.is_ascii_lowercase() || ch.is_ascii_digit() || "._-".contains(ch))
+        };
+        if !self.repository.split('/').all(valid_component) {
+            anyhow::bail!(
+                "build: repository must be made of lowercase letters, digits, '.', '_', '-' and '/'"
+            );
+        }
+        if self.timeout_secs == 0 {
+            anyhow::bail!("build: timeout_secs must be at least 1");
+        }
+        // The build API takes the limit as a 32-bit byte count.
+        if !(64..=2047).contains(&self.memory_mb) {
+            anyhow::bail!("build: memory_mb must be between 64 and 2047");
+        }
+        if !(self.cpus > 0.0 && self.cpus <= 1024.0) {
+            anyhow::bail!("build: cpus must be greater than 0 and at most 1024");
+        }
+        if self.max_context_mb == 0 {
+            anyhow::bail!("build: max_context_mb must be at least 1");
+        }
+        if self.max_concurrent == 0 {
+            anyhow::bail!("build: max_concurrent must be at least 1");
+        }
+        if let Some(image) = self.base_images.iter().find(|i| i.is_empty() || i.contains(' ')) {
+            anyhow::bail!("build: invalid base image {image:?}");
+        }
+        Ok(())
+    }
+}
+
+/// Gateways that move workspaces between each other (`agentman admin migrate`).
+///
+/// The source gateway connects to the target's SSH listener as `agentman-migrate`,
+/// authenticating with its own host key, and streams the workspace to it; the target recreates
+/// the container. The node list serves both directions: it pins the target's host key, and it
+/// is how the target recognizes the source. List every participating gateway (including
+/// itself, which is ignored) on each of them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MigrationConfig {
+    /// Time limit for a whole migration, including the transfer.
+    pub timeout_secs: u64,
+
+    pub nodes: Vec<MigrationNode>,
+}
+
+impl Default for MigrationConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 3600,
+            nodes: Vec::new(),
+        }
+    }
+}
+
+/// A gateway workspaces can be migrated to or from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MigrationNode {
+    /// Name used in `agentman admin migrate ... --to <name>`.
+    pub name: String,
+
+    /// The node's SSH listener as "host:port".
+    pub address: String,
+
+    /// The node's host key fingerprint ("SHA256:...").
+    pub host_key_fingerprint: String,
+
+    /// Address users connect to, shown to them after their workspace moved (default: address).
+    #[serde(default)]
+    pub public_address: Option<String>,
+}
+
+impl MigrationNode {
+    /// Address users should connect to.
+    pub fn public_address(&self) -> &str {
+        self.public_address.as_deref().unwrap_or(&self.address)
+    }
+}
+
+impl MigrationConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.timeout_secs == 0 {
+            anyhow::bail!("migration: timeout_secs must be at least 1");
+        }
+        let mut names = std::collections::HashSet::new();
+        for node in &self.nodes {
+            if node.name.is_empty()
+                || !node
+                    .name
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.')
+            {
+                anyhow::bail!(
+                    "migration: node name {:?} must be letters, digits, '-' and '.'",
+                    node.name
+                );
+            }
+            if !names.insert(node.name.as_str()) {
+                anyhow::bail!("migration: duplicate node {:?}", node.name);
+            }
+            if !node.address.rsplit_once(':').is_some_and(|(host, port)| {
+                !host.is_empty() && port.parse::<u16>().is_ok_and(|p| p > 0)
+            }) {
+                anyhow::bail!(
+                    "migration: node {}: address must be host:port, got {:?}",
+                    node.name,
+                    node.address
+                );
+            }
+            if !node.host_key_fingerprint.starts_with("SHA256:") {
+                anyhow::bail!(
+                    "migration: node {}: host_key_fingerprint (\"SHA256:...\") is required",
+                    node.name
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Forwarding of container security events from the kernel audit subsystem.
+///
+/// When enabled, the gateway follows the auditd log and turns records caused by processes in
+/// agent containers into `security` audit events: seccomp actions that are logged (`SECCOMP`),
+/// capability denials reported by AppArmor or SELinux (`AVC`), and ptrace attempts, either
+/// denied by an LSM or matched by an audit rule tagged with `ptrace_key`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SecurityEventsConfig {
+    pub enabled: bool,
+
+    /// auditd log to follow (read from the end; rotation is detected).
+    pub audit_log: PathBuf,
+
+    /// Key of the audit rule watching ptrace, e.g.
+    /// `auditctl -a always,exit -F arch=b64 -S ptrace -k agentman-ptrace`.
+    pub ptrace_key: String,
+
+    /// Most events forwarded per container per minute; the rest are counted and summarised.
+    pub max_events_per_minute: u32,
+
+    /// Destinations for security events (same format as `[[logging.sinks]]`). Empty sends
+    /// them to the `[logging]` sinks.
+    pub sinks: Vec<AuditSinkConfig>,
+}
+
+impl Default for SecurityEventsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            audit_log: PathBuf::from("/var/log/audit/audit.log"),
+            ptrace_key: "agentman-ptrace".to_string(),
+            max_events_per_minute: 60,
+            sinks: Vec::new(),
+        }
+    }
+}
+
+impl SecurityEventsConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !self.audit_log.is_absolute() {
+            anyhow::bail!("security_events: audit_log must be an absolute path");
+        }
+        if self.max_events_per_minute == 0 {
+            anyhow::bail!("security_events: max_events_per_minute must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+/// Workspace disk usage measurement.
+///
+/// Sizes are cached for `interval_secs`: a background indexer re-measures every workspace on
+/// that schedule, and a workspace missing from the cache is measured once on demand.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WorkspaceSizeConfig {
+    /// Run the background indexer (without it, sizes are measured on demand and cached).
+    pub index: bool,
+
+    /// How long a measured size is reused.
+    pub interval_secs: u64,
+
+    /// Where sizes come from; the quota sources fall back to `du` for workspaces that aren't
+    /// their own subvolume/dataset.
+    pub source: WorkspaceSizeSource,
+}
+
+impl Default for WorkspaceSizeConfig {
+    fn default() -> Self {
+        Self {
+            index: true,
+            interval_secs: 900,
+            source: WorkspaceSizeSource::default(),
+        }
+    }
+}
+
+impl WorkspaceSizeConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.interval_secs < 60 {
+            anyhow::bail!("workspace_size: interval_secs must be at least 60");
+        }
+        Ok(())
+    }
+}
+
+/// How workspace sizes are measured.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WorkspaceSizeSource {
+    /// Walk the tree with `du`.
+    #[default]
+    Du,
+    /// Referenced bytes of the workspace's btrfs subvolume qgroup (`btrfs qgroup show`).
+    Btrfs,
+    /// `used` of the ZFS dataset mounted at the workspace (`zfs list`).
+    Zfs,
+}
+
+/// Incremental backup backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackupBackend {
+    /// Hard-linked snapshot directories (`rsync --link-dest`).
+    Rsync,
+    /// Deduplicated restic repository.
+    Restic,
+}
+
+/// Workspace backup configuration.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BackupConfig {
+    /// Backend to use. Unset disables `agentman backup`.
+    pub backend: Option<BackupBackend>,
+
+    /// rsync: host directory holding snapshots. restic: repository (path or URL).
+    pub repository: String,
+
+    /// restic: file with the repository password (passed as `RESTIC_PASSWORD_FILE`).
+    pub password_file: Option<PathBuf>,
+
+    /// Back up every workspace on this interval. Unset = on demand only.
+    pub interval_hours: Option<u64>,
+
+    /// Keep only this many snapshots per workspace. Unset = keep all.
+    pub keep_last: Option<usize>,
+}
+
+impl BackupConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.backend.is_some() && self.repository.trim().is_empty() {
+            anyhow::bail!("backup: repository is required when a backend is set");
+        }
+        if self.backend == Some(BackupBackend::Rsync) && !Path::new(&self.repository).is_absolute() {
+            anyhow::bail!("backup: rsync repository must be an absolute path");
+        }
+        Ok(())
+    }
+}
+
+/// Prometheus metrics endpoint configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    /// Address to serve `GET /metrics` on, e.g. "127.0.0.1:9464". Unset disables the endpoint.
+    pub listen_addr: Option<String>,
+
+    /// Log a warning for Docker API calls slower than this many milliseconds (0 = never).
+    /// Latencies are always recorded in `agentman_docker_request_duration_seconds`.
+    pub docker_slow_call_ms: u64,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: None,
+            docker_slow_call_ms: 2000,
+        }
+    }
+}
+
+/// Reverse tunnel registration with a relay.
+///
+/// The gateway dials out to an SSH bastion, authenticates with `key_path` (default: the
+/// gateway's own host key) and asks it to forward `remote_bind:remote_port` back over the
+/// connection. Clients then `ssh -p <remote_port> user@<bastion>` and reach this gateway without
+/// any inbound firewall hole. The bastion needs `GatewayPorts clientspecified` (or `yes`) for
+/// a non-loopback bind.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RelayConfig {
+    /// Bastion address as "host:port". Unset disables the relay.
+    pub address: Option<String>,
+
+    /// User to log in as on the bastion.
+    pub user: String,
+
+    /// Private key to authenticate with. Defaults to the gateway host key.
+    pub key_path: Option<PathBuf>,
+
+    /// Expected bastion host key fingerprint ("SHA256:..."). Required: the tunnel carries
+    /// every client connection, so the bastion must not be trusted on first use.
+    pub server_key_fingerprint: String,
+
+    /// Address the bastion should listen on for clients.
+    pub remote_bind: String,
+
+    /// Port the bastion should listen on for clients.
+    pub remote_port: u16,
+
+    /// SSH keepalive interval towards the bastion; the tunnel is considered dead after
+    /// three unanswered keepalives.
+    pub keepalive_secs: u64,
+
+    /// Upper bound for the reconnect backoff (starts at 1s and doubles).
+    pub max_backoff_secs: u64,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            address: None,
+            user: "agentman".to_string(),
+            key_path: None,
+            server_key_fingerprint: String::new(),
+            remote_bind: "0.0.0.0".to_string(),
+            remote_port: 2222,
+            keepalive_secs: 15,
+            max_backoff_secs: 60,
+        }
+    }
+}
+
+impl RelayConfig {
+    pub fn validate(&self) -> Result<()> {
+        let Some(ref address) = self.address else {
+            return Ok(());
+        };
+        if address.rsplit_once(':').is_none_or(|(host, port)| host.is_empty() || port.parse::<u16>().is_err()) {
+            anyhow::bail!("relay: address must be host:port, got {:?}", address);
+        }
+        if self.user.trim().is_empty() {
+            anyhow::bail!("relay: user is required");
+        }
+        if !self.server_key_fingerprint.starts_with("SHA256:") {
+            anyhow::bail!("relay: server_key_fingerprint (\"SHA256:...\") is required");
+        }
+        if self.remote_port == 0 {
+            anyhow::bail!("relay: remote_port must be set");
+        }
+        if self.keepalive_secs == 0 || self.max_backoff_secs == 0 {
+            anyhow::bail!("relay: keepalive_secs and max_backoff_secs must be positive");
+        }
+        Ok(())
+    }
+}
+
+/// Readiness checks for `agentman wait`, beyond "container running and init succeeded".
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReadinessConfig {
+    /// TCP port (inside the container, on 127.0.0.1) that must accept connections.
+    pub tcp_port: Option<u16>,
+
+    /// Command (run via `bash -lc` in /workspace) that must exit 0.
+    pub health_command: Option<String>,
+}
+
+/// Workspace init command configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InitConfig {
+    /// Default command (run via `bash -lc` in /workspace) each time the gateway starts a
+    /// container. Workspaces can override it with `agentman init set -- <cmd>`.
+    pub command: Option<String>,
+
+    /// Mark the run as failed if it takes longer than this many seconds.
+    pub timeout_secs: u64,
+}
+
+impl Default for InitConfig {
+    fn default() -> Self {
+        Self {
+            command: None,
+            timeout_secs: 600,
+        }
+    }
+}
+
+/// Per-project image selection (`agentman image`).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImagesConfig {
+    /// Images users may pick for their sandboxes, as exact references. `docker_image` (the
+    /// deployment default) is always allowed; empty disables switching.
+    pub allowed: Vec<String>,
+
+    /// Images users may pick by name when connecting (`ssh project.<name>@gateway`), name ->
+    /// image reference. Named images are allowed like `allowed` ones; `default` names
+    /// `docker_image`.
+    pub named: HashMap<String, String>,
+}
+
+impl ImagesConfig {
+    pub fn validate(&self) -> Result<()> {
+        for image in self.allowed.iter().chain(self.named.values()) {
+            if image.is_empty() || image.contains(char::is_whitespace) {
+                anyhow::bail!("images: invalid image reference {:?}", image);
+            }
+        }
+        for name in self.named.keys() {
+            if name.is_empty()
+                || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                anyhow::bail!(
+                    "images: invalid image name {:?} (use letters, digits, - and _)",
+                    name
+                );
+            }
+            if name == "default" {
+                anyhow::bail!("images: \"default\" always names docker_image");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Audit/event log shipping configuration.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    /// Destinations for audit events. Empty disables audit logging.
+    pub sinks: Vec<AuditSinkConfig>,
+
+    /// Most events recorded per GitHub user per minute; the rest are counted and summarised.
+    /// Unset = no limit.
+    pub max_events_per_minute: Option<u32>,
+
+    /// Size caps and retention for `directory` sinks.
+    pub retention: AuditRetentionConfig,
+}
+
+impl LoggingConfig {
+    pub fn validate(&self) -> Result<()> {
+        for sink in &self.sinks {
+            match sink {
+                AuditSinkConfig::Directory { path } if !path.is_absolute() => {
+                    anyhow::bail!("logging: directory sink path must be absolute");
+                }
+                AuditSinkConfig::File {
+                    max_size_mb,
+                    max_files,
+                    ..
+                } => {
+                    if *max_size_mb == Some(0) {
+                        anyhow::bail!("logging: file sink max_size_mb must be at least 1");
+                    }
+                    if *max_files == 0 {
+                        anyhow::bail!("logging: file sink max_files must be at least 1");
+                    }
+                }
+                _ => {}
+            }
+        }
+        if self.max_events_per_minute == Some(0) {
+            anyhow::bail!("logging: max_events_per_minute must be at least 1");
+        }
+        let retention = &self.retention;
+        if retention.max_mb_per_user == Some(0) {
+            anyhow::bail!("logging: retention.max_mb_per_user must be at least 1");
+        }
+        if let (Some(compress), Some(delete)) =
+            (retention.compress_after_days, retention.delete_after_days)
+            && compress >= delete
+        {
+            anyhow::bail!(
+                "logging: retention.compress_after_days must be less than delete_after_days"
+            );
+        }
+        Ok(())
+    }
+
+    /// The first `directory` sink, which `agentman admin audit usage` reports on.
+    pub fn directory(&self) -> Option<&Path> {
+        self.sinks.iter().find_map(|sink| match sink {
+            AuditSinkConfig::Directory { path } => Some(path.as_path()),
+            _ => None,
+        })
+    }
+}
+
+/// Limits for `directory` sinks, which keep one JSON-lines file per user and day. Checked
+/// hourly; unset limits don't apply.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuditRetentionConfig {
+    /// Cap on a user's logs, compressed ones included. Past it, the oldest days are deleted;
+    /// when today's file alone reaches it, the user's events are dropped until tomorrow.
+    pub max_mb_per_user: Option<u64>,
+
+    /// gzip days older than this.
+    pub compress_after_days: Option<u32>,
+
+    /// Delete days older than this.
+    pub delete_after_days: Option<u32>,
+}
+
+/// A single audit event destination.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum AuditSinkConfig {
+    /// Append JSON lines to a local file, rotated to `<path>.1` ... `<path>.<max_files>` once
+    /// it reaches `max_size_mb` (unset = never rotated).
+    File {
+        path: PathBuf,
+        #[serde(default)]
+        max_size_mb: Option<u64>,
+        #[serde(default = "default_audit_max_files")]
+        max_files: usize,
+    },
+
+    /// JSON lines in `<path>/<github-user>/<YYYY-MM-DD>.jsonl` (`_gateway` for events without
+    /// a user), subject to `[logging.retention]`.
+    Directory { path: PathBuf },
+
+    /// RFC 5424 syslog over `udp://host:port`, `tcp://host:port` or `unix:///dev/log`.
+    Syslog {
+        #[serde(default = "default_syslog_address")]
+        address: String,
+        #[serde(default = "default_syslog_facility")]
+        facility: String,
+    },
+
+    /// systemd-journald native protocol with structured `AGENTMAN_*` fields.
+    Journald {
+        #[serde(default = "default_journald_socket")]
+        socket: PathBuf,
+    },
+
+    /// HTTP push to Grafana Loki (base URL, e.g. `http://loki:3100`).
+    Loki {
+        url: String,
+        /// Extra stream labels added to every pushed event.
+        #[serde(default)]
+        labels: HashMap<String, String>,
+        /// Push once this many events are queued (otherwise every second).
+        #[serde(default = "default_loki_batch_size")]
+        batch_size: usize,
+    },
+}
+
+fn default_audit_max_files() -> usize {
+    5
+}
+
+fn default_syslog_address() -> String {
+    "unix:///dev/log".to_string()
+}
+
+fn default_syslog_facility() -> String {
+    "auth".to_string()
+}
+
+fn default_journald_socket() -> PathBuf {
+    PathBuf::from("/run/systemd/journal/socket")
+}
+
+fn default_loki_batch_size() -> usize {
+    100
+}
+
+/// A host path bind-mounted read-only into every agent container.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReadOnlyMount {
+    /// Absolute path on the gateway host.
+    pub host_path: PathBuf,
+
+    /// Absolute path inside the container.
+    pub container_path: String,
+
+    /// Environment variables set to `container_path` (e.g. `NODE_EXTRA_CA_CERTS`,
+    /// `REQUESTS_CA_BUNDLE`, `SSL_CERT_FILE` for CA bundles).
+    #[serde(default)]
+    pub env: Vec<String>,
+}
+
+impl ReadOnlyMount {
+    /// Validate paths so a mount can't shadow the workspace or use relative paths.
+    pub fn validate(&self) -> Result<()> {
+        if !self.host_path.is_absolute() {
+            anyhow::bail!(
+                "readonly_mounts: host_path must be absolute: {}",
+                self.host_path.display()
+            );
+        }
+        if !self.container_path.starts_with('/') {
+            anyhow::bail!(
+                "readonly_mounts: container_path must be absolute: {}",
+                self.container_path
+            );
+        }
+        let container_path = self.container_path.trim_end_matches('/');
+        if container_path.is_empty()
+            || container_path == "/workspace"
+            || container_path.starts_with("/workspace/")
+        {
+            anyhow::bail!(
+                "readonly_mounts: container_path may not be / or inside /workspace: {}",
+                self.container_path
+            );
+        }
+        // Never hand the Docker API to a sandbox, even read-only.
+        if self.host_path.file_name().is_some_and(|n| n == "docker.sock") {
+            anyhow::bail!(
+                "readonly_mounts: refusing to mount the Docker socket: {}",
+                self.host_path.display()
+            );
+        }
+        if self.container_path.contains(':') || self.host_path.to_string_lossy().contains(':') {
+            anyhow::bail!("readonly_mounts: paths may not contain ':'");
+        }
+        Ok(())
+    }
+}
+
+/// A host device exposed to selected users' containers, without privileged mode.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceAccess {
+    /// Device node on the host, e.g. `/dev/kvm`, `/dev/net/tun`, `/dev/ttyUSB0`.
+    pub host_path: PathBuf,
+
+    /// Path inside the container (defaults to `host_path`).
+    #[serde(default)]
+    pub container_path: Option<String>,
+
+    /// cgroup device permissions: any of `r`, `w`, `m`.
+    #[serde(default = "default_device_permissions")]
+    pub permissions: String,
 
-    /// Path to the SSH host key
-    pub host_key_path: PathBuf,
+    /// GitHub users whose containers get the device; `"*"` means everyone.
+    pub users: Vec<String>,
 
-    /// Bootstrap GitHub usernames for auto-matching keys
+    /// Extra cgroup device rules (e.g. `"c 188:* rmw"` so hot-plugged USB serial adapters work).
     #[serde(default)]
-    pub bootstrap_github_users: Vec<String>,
+    pub cgroup_rules: Vec<String>,
 
-    /// Port forwarding configuration
+    /// Capabilities the device needs (e.g. `NET_ADMIN` for `/dev/net/tun`).
     #[serde(default)]
-    pub port_forwarding: PortForwardingConfig,
+    pub cap_add: Vec<String>,
+}
 
-    /// OpenSSH agent forwarding configuration
-    #[serde(default)]
-    pub agent_forwarding: AgentForwardingConfig,
+fn default_device_permissions() -> String {
+    "rwm".to_string()
+}
 
-    /// Interactive shell/session configuration
-    #[serde(default)]
-    pub shell: ShellConfig,
+impl DeviceAccess {
+    /// Whether `github_user`'s containers get this device.
+    pub fn allows(&self, github_user: &str) -> bool {
+        self.users.iter().any(|u| u == "*" || u == github_user)
+    }
 
-    /// Container security configuration
-    #[serde(default)]
-    pub container_security: ContainerSecurityConfig,
+    pub fn container_path(&self) -> String {
+        self.container_path
+            .clone()
+            .unwrap_or_else(|| self.host_path.display().to_string())
+    }
+
+    /// Only device nodes, explicit users, and narrow capabilities are accepted.
+    pub fn validate(&self) -> Result<()> {
+        if !self.host_path.starts_with("/dev/") {
+            anyhow::bail!(
+                "devices: host_path must be under /dev: {}",
+                self.host_path.display()
+            );
+        }
+        if !self.container_path().starts_with("/dev/") {
+            anyhow::bail!(
+                "devices: container_path must be under /dev: {}",
+                self.container_path()
+            );
+        }
+        if self.permissions.is_empty() || !self.permissions.chars().all(|c| "rwm".contains(c)) {
+            anyhow::bail!(
+                "devices: permissions must be a combination of r, w, m: {:?}",
+                self.permissions
+            );
+        }
+        if self.users.is_empty() {
+            anyhow::bail!(
+                "devices: {} has no users; list GitHub users or \"*\"",
+                self.host_path.display()
+            );
+        }
+        for rule in &self.cgroup_rules {
+            let parts: Vec<&str> = rule.split_whitespace().collect();
+            let valid = matches!(parts.as_slice(), [kind, numbers, perms]
+                if matches!(*kind, "a" | "b" | "c")
+                    && numbers.contains(':')
+                    && perms.chars().all(|c| "rwm".contains(c)));
+            if !valid {
+                anyhow::bail!("devices: invalid cgroup rule {rule:?} (expected e.g. \"c 188:* rwm\")");
+            }
+        }
+        for cap in &self.cap_add {
+            let cap = cap.trim_start_matches("CAP_");
+            if matches!(cap, "ALL" | "SYS_ADMIN" | "SYS_MODULE" | "SYS_RAWIO") {
+                anyhow::bail!("devices: refusing to grant capability {cap}; use a narrower one");
+            }
+        }
+        Ok(())
+    }
 }
 
-impl Default for GatewayConfig {
-    fn default() -> Self {
-        let data_dir = dirs::data_local_dir()
-            .unwrap_or_else(|| PathBuf::from("/var/lib"))
-            .join("agentman");
+/// One user's overrides of the global settings, from `<users_dir>/<github_user>.toml`.
+/// Each setting a file has replaces the global one; what it leaves out keeps the global value.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UserOverrides {
+    /// Image of the user's sandboxes (replaces `docker_image`)
+    pub docker_image: Option<String>,
 
-        Self {
-            listen_addr: "0.0.0.0:2222".to_string(),
-            docker_image: "agentman-base:dev".to_string(),
-            workspace_root: data_dir.join("workspaces"),
-            state_file: data_dir.join("state.json"),
-            host_key_path: data_dir.join("host_key"),
-            bootstrap_github_users: Vec::new(),
-            port_forwarding: PortForwardingConfig::default(),
-            agent_forwarding: AgentForwardingConfig::default(),
-            shell: ShellConfig::default(),
-            container_security: ContainerSecurityConfig::default(),
+    /// Replaces `allowed_projects`
+    pub allowed_projects: Option<Vec<String>>,
+
+    pub container_security: UserContainerOverrides,
+
+    pub port_forwarding: UserPortForwardingOverrides,
+}
+
+/// `[container_security]` settings a user's file may override.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UserContainerOverrides {
+    pub memory_limit: Option<String>,
+    pub cpu_limit: Option<f64>,
+}
+
+/// `[port_forwarding]` settings a user's file may override.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UserPortForwardingOverrides {
+    pub allow_local: Option<bool>,
+    pub allow_remote: Option<bool>,
+    pub allow_gateway_ports: Option<bool>,
+    pub allow_nonlocal_destinations: Option<bool>,
+    pub allow_dynamic: Option<bool>,
+    pub streamlocal_paths: Option<Vec<String>>,
+}
+
+impl UserOverrides {
+    /// `config` with these overrides applied.
+    pub fn apply(&self, config: &GatewayConfig) -> GatewayConfig {
+        let mut config = config.clone();
+        if let Some(image) = &self.docker_image {
+            config.docker_image = image.clone();
+        }
+        if let Some(projects) = &self.allowed_projects {
+            config.allowed_projects = projects.clone();
         }
+        let security = &mut config.container_security;
+        let own = &self.container_security;
+        security.memory_limit = own.memory_limit.clone().or(security.memory_limit.take());
+        security.cpu_limit = own.cpu_limit.or(security.cpu_limit);
+        let pf = &mut config.port_forwarding;
+        let own = &self.port_forwarding;
+        pf.allow_local = own.allow_local.unwrap_or(pf.allow_local);
+        pf.allow_remote = own.allow_remote.unwrap_or(pf.allow_remote);
+        pf.allow_gateway_ports = own.allow_gateway_ports.unwrap_or(pf.allow_gateway_ports);
+        pf.allow_nonlocal_destinations =
+            own.allow_nonlocal_destinations.unwrap_or(pf.allow_nonlocal_destinations);
+        pf.allow_dynamic = own.allow_dynamic.unwrap_or(pf.allow_dynamic);
+        if let Some(paths) = &own.streamlocal_paths {
+            pf.streamlocal_paths = paths.clone();
+        }
+        config
     }
 }
 
@@ -136,6 +3422,34 @@ pub struct PortForwardingConfig {
 
     /// Allow forwarding to non-local destinations (beyond localhost/container)
     pub allow_nonlocal_destinations: bool,
+
+    /// Allow dynamic forwarding (ssh -D): the client's SOCKS proxy may open channels to any
+    /// host and port, resolved and connected from the sandbox's network. Also needs
+    /// `allow_local`; `~C -L` gateway forwards still follow `allow_nonlocal_destinations`.
+    pub allow_dynamic: bool,
+
+    /// Connect forwards to the sandbox's localhost from the gateway to the container's IP
+    /// instead of through a bridge exec in the container. Services listening only on the
+    /// container's loopback refuse those connections; they, and containers without a
+    /// reachable IP, still go through the bridge. Needs a gateway host that can reach the
+    /// container networks (not a remote Docker host).
+    pub direct_connect: bool,
+
+    /// Host port range reserved for remote forwards, e.g. `[20000, 29999]`.
+    ///
+    /// When set, each workspace is assigned a fixed block of `remote_ports_per_workspace` ports
+    /// from this range and `ssh -R` may only bind inside that block (`-R 0:...` picks a free
+    /// port from it). Unset keeps the old behaviour of binding whatever port the client asks for.
+    pub remote_port_range: Option<(u16, u16)>,
+
+    /// Size of each workspace's block within `remote_port_range`.
+    pub remote_ports_per_workspace: u16,
+
+    /// Unix sockets in the sandbox that clients may forward to (`ssh -L <local>:<socket>`) or
+    /// create (`ssh -R <socket>:<local>`), as exact paths or directories ending in `/`. Remote
+    /// forwards also need `allow_remote` and can only create sockets directly in `/workspace`.
+    /// Empty (the default) disables Unix socket forwarding.
+    pub streamlocal_paths: Vec<String>,
 }
 
 impl Default for PortForwardingConfig {
@@ -145,7 +3459,46 @@ impl Default for PortForwardingConfig {
             allow_remote: true,
             allow_gateway_ports: false,
             allow_nonlocal_destinations: false,
+            allow_dynamic: false,
+            direct_connect: false,
+            remote_port_range: None,
+            remote_ports_per_workspace: 10,
+            streamlocal_paths: Vec::new(),
+        }
+    }
+}
+
+impl PortForwardingConfig {
+    /// Validate the GatewayPorts setting and the reserved port range, if configured.
+    pub fn validate(&self) -> Result<()> {
+        if self.allow_gateway_ports && !self.allow_remote {
+            anyhow::bail!("port_forwarding: allow_gateway_ports needs allow_remote = true");
+        }
+        if self.allow_dynamic && !self.allow_local {
+            anyhow::bail!("port_forwarding: allow_dynamic needs allow_local = true");
+        }
+        for path in &self.streamlocal_paths {
+            if let Err(reason) = crate::streamlocal::check_path(path) {
+                anyhow::bail!("port_forwarding: streamlocal_paths entry {path:?} {reason}");
+            }
+        }
+        let Some((start, end)) = self.remote_port_range else {
+            return Ok(());
+        };
+        if start == 0 || start > end {
+            anyhow::bail!(
+                "port_forwarding: remote_port_range must be [start, end] with 0 < start <= end (got [{start}, {end}])"
+            );
         }
+        let size = u32::from(end) - u32::from(start) + 1;
+        if self.remote_ports_per_workspace == 0
+            || u32::from(self.remote_ports_per_workspace) > size
+        {
+            anyhow::bail!(
+                "port_forwarding: remote_ports_per_workspace must be between 1 and the range size ({size})"
+            );
+        }
+        Ok(())
     }
 }
 
@@ -174,6 +3527,29 @@ pub struct ContainerSecurityConfig {
 
     /// Use default seccomp profile
     pub use_seccomp: bool,
+
+    /// Size of `/dev/shm` (e.g., "1g"). Omit for Docker's 64m, which browsers and databases
+    /// in the sandbox outgrow.
+    pub shm_size: Option<String>,
+
+    /// Extra tmpfs mounts, container path to size (e.g., `"/cache" = "2g"`).
+    pub tmpfs: HashMap<String, String>,
+
+    /// Open files limit (`ulimit -n`, soft and hard). Omit for Docker's default.
+    pub nofile: Option<u64>,
+
+    /// Processes limit of the container user (`ulimit -u`, soft and hard). Omit for Docker's
+    /// default.
+    pub nproc: Option<u64>,
+
+    /// Largest `/dev/shm` a workspace may ask for with `agentman resize`.
+    pub max_shm_size: String,
+
+    /// Highest open files limit a workspace may ask for with `agentman resize`.
+    pub max_nofile: u64,
+
+    /// Highest processes limit a workspace may ask for with `agentman resize`.
+    pub max_nproc: u64,
 }
 
 impl Default for ContainerSecurityConfig {
@@ -193,7 +3569,197 @@ impl Default for ContainerSecurityConfig {
             memory_limit: None,
             cpu_limit: None,
             use_seccomp: true,
+            shm_size: None,
+            tmpfs: HashMap::new(),
+            nofile: None,
+            nproc: None,
+            max_shm_size: "4g".to_string(),
+            max_nofile: 1_048_576,
+            max_nproc: 65_536,
+        }
+    }
+}
+
+impl ContainerSecurityConfig {
+    pub fn validate(&self) -> Result<()> {
+        let size = |value: &str, what: &str| {
+            crate::docker::parse_memory_limit(value)
+                .ok()
+                .filter(|bytes| *bytes > 0)
+                .map(|_| ())
+                .ok_or_else(|| anyhow::anyhow!("container_security: {what} must be like \"1g\""))
+        };
+        if let Some(shm) = &self.shm_size {
+            size(shm, "shm_size")?;
+        }
+        size(&self.max_shm_size, "max_shm_size")?;
+        for (path, tmpfs) in &self.tmpfs {
+            let reserved = ["/", "/workspace", "/dev", "/dev/shm", "/proc", "/sys"];
+            if !path.starts_with('/') || path.contains(',') || reserved.contains(&path.as_str()) {
+                anyhow::bail!("container_security: tmpfs path {path:?} can't be mounted over");
+            }
+            size(tmpfs, &format!("the size of tmpfs {path}"))?;
+        }
+        if self.nofile == Some(0) || self.max_nofile == 0 {
+            anyhow::bail!("container_security: nofile and max_nofile must be at least 1");
+        }
+        if self.nproc == Some(0) || self.max_nproc == 0 {
+            anyhow::bail!("container_security: nproc and max_nproc must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+/// Per-user security profiles.
+///
+/// Profiles are checked in order when a container is created; the first one listing the user,
+/// or a GitHub team they are an active member of, applies. Settings a profile leaves out come
+/// from `[container_security]`. Users no profile matches get `[container_security]` as is.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SecurityProfilesConfig {
+    /// File with a GitHub token that can read the teams' memberships (`read:org`); needed when
+    /// a profile lists teams.
+    pub github_token_file: Option<PathBuf>,
+
+    /// How long a team membership is reused before asking GitHub again.
+    pub cache_secs: u64,
+
+    pub profiles: Vec<SecurityProfile>,
+}
+
+impl Default for SecurityProfilesConfig {
+    fn default() -> Self {
+        Self {
+            github_token_file: None,
+            cache_secs: 600,
+            profiles: Vec::new(),
+        }
+    }
+}
+
+/// One named set of overrides of `[container_security]`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SecurityProfile {
+    pub name: String,
+
+    /// GitHub users the profile applies to; `"*"` means everyone.
+    #[serde(default)]
+    pub users: Vec<String>,
+
+    /// GitHub teams (`<org>/<team-slug>`) whose members the profile applies to.
+    #[serde(default)]
+    pub teams: Vec<String>,
+
+    /// Capabilities added back after dropping all (replaces `container_security.cap_add`).
+    pub cap_add: Option<Vec<String>>,
+
+    pub no_new_privileges: Option<bool>,
+
+    pub readonly_rootfs: Option<bool>,
+
+    pub memory_limit: Option<String>,
+
+    pub cpu_limit: Option<f64>,
+
+    /// Most processes the container may run.
+    pub pids_limit: Option<i64>,
+
+    /// Whether the container can reach the network. Without egress it is attached to an
+    /// internal Docker network instead of the default bridge, and project links are refused.
+    #[serde(default = "default_egress")]
+    pub egress: bool,
+}
+
+fn default_egress() -> bool {
+    true
+}
+
+impl SecurityProfile {
+    /// Whether the profile lists `github_user` (teams are checked separately).
+    pub fn lists_user(&self, github_user: &str) -> bool {
+        self.users.iter().any(|u| u == "*" || u == github_user)
+    }
+
+    /// `base` with this profile's overrides.
+    pub fn apply(&self, base: &ContainerSecurityConfig) -> ContainerSecurityConfig {
+        let mut security = base.clone();
+        if let Some(caps) = &self.cap_add {
+            security.cap_add = caps.clone();
+        }
+        if let Some(no_new_privileges) = self.no_new_privileges {
+            security.no_new_privileges = no_new_privileges;
+        }
+        if let Some(readonly_rootfs) = self.readonly_rootfs {
+            security.readonly_rootfs = readonly_rootfs;
+        }
+        if let Some(memory) = &self.memory_limit {
+            security.memory_limit = Some(memory.clone());
         }
+        if let Some(cpu) = self.cpu_limit {
+            security.cpu_limit = Some(cpu);
+        }
+        security
+    }
+}
+
+impl SecurityProfilesConfig {
+    pub fn validate(&self) -> Result<()> {
+        let mut names = std::collections::HashSet::new();
+        for profile in &self.profiles {
+            let name = &profile.name;
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                anyhow::bail!(
+                    "security_profiles: profile name {name:?} must be letters, digits, - or _"
+                );
+            }
+            if !names.insert(name.as_str()) {
+                anyhow::bail!("security_profiles: profile {name} is defined twice");
+            }
+            if profile.users.is_empty() && profile.teams.is_empty() {
+                anyhow::bail!("security_profiles: profile {name} has no users or teams");
+            }
+            for team in &profile.teams {
+                let valid = team.split_once('/').is_some_and(|(org, slug)| {
+                    !org.is_empty() && !slug.is_empty() && !slug.contains('/')
+                });
+                if !valid {
+                    anyhow::bail!(
+                        "security_profiles: team {team:?} of profile {name} must be <org>/<team-slug>"
+                    );
+                }
+            }
+            if !profile.teams.is_empty() && self.github_token_file.is_none() {
+                anyhow::bail!(
+                    "security_profiles: github_token_file is required for teams (profile {name})"
+                );
+            }
+            for cap in profile.cap_add.iter().flatten() {
+                if cap.trim_start_matches("CAP_") == "ALL" {
+                    anyhow::bail!(
+                        "security_profiles: profile {name} can't add ALL capabilities; list them"
+                    );
+                }
+            }
+            if let Some(memory) = &profile.memory_limit {
+                crate::docker::parse_memory_limit(memory).map_err(|_| {
+                    anyhow::anyhow!(
+                        "security_profiles: memory_limit of profile {name} must be like \"4g\""
+                    )
+                })?;
+            }
+            if profile.cpu_limit.is_some_and(|cpu| cpu <= 0.0) {
+                anyhow::bail!("security_profiles: cpu_limit of profile {name} must be positive");
+            }
+            if profile.pids_limit.is_some_and(|pids| pids <= 0) {
+                anyhow::bail!("security_profiles: pids_limit of profile {name} must be positive");
+            }
+        }
+        Ok(())
     }
 }
 
@@ -204,9 +3770,129 @@ impl GatewayConfig {
             .with_context(|| format!("Failed to read config file: {}", path.display()))?;
         let config: Self = toml::from_str(&content)
             .with_context(|| format!("Failed to parse config file: {}", path.display()))?;
+        config.validate()?;
         Ok(config)
     }
 
+    /// Check every section, and settings that depend on each other.
+    pub fn validate(&self) -> Result<()> {
+        for mount in &self.readonly_mounts {
+            mount.validate()?;
+        }
+        for device in &self.devices {
+            device.validate()?;
+        }
+        self.shell.validate()?;
+        self.security_profiles.validate()?;
+        self.port_forwarding.validate()?;
+        self.images.validate()?;
+        self.backup.validate()?;
+        self.editor_cache.validate()?;
+        self.helper.validate()?;
+        self.workspace_permissions.validate()?;
+        self.relay.validate()?;
+        self.stats_history.validate()?;
+        self.workspace_size.validate()?;
+        self.proxy.validate()?;
+        self.proxy_ca.validate()?;
+        self.notify.validate()?;
+        self.offboarding.validate()?;
+        self.stack.validate()?;
+        self.security_events.validate()?;
+        self.ha.validate()?;
+        self.ephemeral.validate()?;
+        self.bench.validate()?;
+        self.sni_router.validate()?;
+        self.checkpoint.validate()?;
+        self.migration.validate()?;
+        self.scan.validate()?;
+        self.guests.validate()?;
+        self.impersonation.validate()?;
+        self.idle.validate()?;
+        self.preview.validate()?;
+        self.admin_api.validate()?;
+        self.slow_clients.validate()?;
+        self.quotas.validate()?;
+        self.memory_guard.validate()?;
+        self.ssh.validate()?;
+        self.env_file.validate()?;
+        self.build.validate()?;
+        self.egress.validate()?;
+        self.accounting.validate()?;
+        self.user_notify.validate()?;
+        self.admission.validate()?;
+        self.github.validate()?;
+        self.github_metadata.validate()?;
+        self.mesh.validate()?;
+        self.clone.validate()?;
+        self.trust.validate()?;
+        self.container_security.validate()?;
+        self.logging.validate()?;
+        self.validate_container_name_template()?;
+        self.validate_host_key()?;
+        self.validate_allowed_github_orgs()?;
+        self.validate_allowed_projects()?;
+        crate::user_config::validate_dir(self)?;
+        if let Some(address) = &self.public_address
+            && split_host_port(address).is_none()
+        {
+            anyhow::bail!("public_address must be a host or host:port, not {address:?}");
+        }
+        // The client waits for the whole time without any feedback.
+        if self.key_propagation_wait_secs > 300 {
+            anyhow::bail!("key_propagation_wait_secs must be at most 300");
+        }
+        Ok(())
+    }
+
+    /// Settings that are valid but have no effect as combined, worth a warning at startup.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        let pf = &self.port_forwarding;
+        if pf.remote_port_range.is_some() && !pf.allow_remote {
+            warnings.push(
+                "port_forwarding: remote_port_range is unused with allow_remote = false".to_string(),
+            );
+        }
+        if self.backup.backend.is_none() {
+            if self.backup.interval_hours.is_some() {
+                warnings.push("backup: interval_hours has no effect without a backend".to_string());
+            }
+            if self.backup.keep_last.is_some() {
+                warnings.push("backup: keep_last has no effect without a backend".to_string());
+            }
+        }
+        if !matches!(self.shell.mode, ShellMode::Tmux)
+            && self.shell.tmux_window_size != TmuxWindowSize::default()
+        {
+            warnings.push("shell: tmux_window_size only applies with mode = \"tmux\"".to_string());
+        }
+        let retention = &self.logging.retention;
+        if self.logging.directory().is_none()
+            && (retention.max_mb_per_user.is_some()
+                || retention.compress_after_days.is_some()
+                || retention.delete_after_days.is_some())
+        {
+            warnings.push("logging: retention only applies to directory sinks".to_string());
+        }
+        if self.github.is_custom() {
+            warnings.push(format!(
+                "github: SSH keys are verified against {} instead of github.com",
+                self.github.keys_url
+            ));
+        }
+        if !self.stack.enabled && self.stack.default_compose_file.is_some() {
+            warnings.push("stack: default_compose_file is unused while stack is disabled".to_string());
+        }
+        if !self.sni_router.enabled && !self.sni_router.domain.is_empty() {
+            warnings.push("sni_router: domain is unused while sni_router is disabled".to_string());
+        }
+        if !self.preview.enabled && !self.preview.domain.is_empty() {
+            warnings.push("preview: domain is unused while preview is disabled".to_string());
+        }
+        warnings
+    }
+
     /// Load configuration from a file, or return defaults if the file doesn't exist.
     pub fn load_or_default(path: &Path) -> Result<Self> {
         if path.exists() {
@@ -240,7 +3926,9 @@ impl GatewayConfig {
                 .with_context(|| format!("Failed to create state directory: {}", parent.display()))?;
         }
 
-        if let Some(parent) = self.host_key_path.parent() {
+        if self.host_key_backend == HostKeyBackend::File
+            && let Some(parent) = self.host_key_path.parent()
+        {
             std::fs::create_dir_all(parent)
                 .with_context(|| format!("Failed to create host key directory: {}", parent.display()))?;
         }
@@ -248,8 +3936,514 @@ impl GatewayConfig {
         Ok(())
     }
 
+    /// Stable container name (and hostname) for a workspace.
+    pub fn container_name(&self, github_user: &str, project: &str) -> String {
+        self.container_name_template
+            .replace("{user}", github_user)
+            .replace("{project}", project)
+    }
+
+    /// Whether sandboxes may run `image`: the deployment default or one of `images.allowed`
+    /// and `images.named`.
+    pub fn image_allowed(&self, image: &str) -> bool {
+        image == self.docker_image
+            || self.images.allowed.iter().any(|allowed| allowed == image)
+            || self.images.named.values().any(|named| named == image)
+    }
+
+    /// The image named `name` in `images.named`; `default` is `docker_image`.
+    pub fn named_image(&self, name: &str) -> Option<&str> {
+        if name == "default" {
+            return Some(&self.docker_image);
+        }
+        self.images.named.get(name).map(String::as_str)
+    }
+
+    fn validate_host_key(&self) -> Result<()> {
+        if self.host_key_backend == HostKeyBackend::Command && self.host_key_command.is_empty() {
+            anyhow::bail!("host_key_backend = \"command\" needs host_key_command");
+        }
+        Ok(())
+    }
+
+    fn validate_container_name_template(&self) -> Result<()> {
+        let template = &self.container_name_template;
+        if !template.contains("{user}") || !template.contains("{project}") {
+            anyhow::bail!(
+                "container_name_template must contain both {{user}} and {{project}}, got {:?}",
+                template
+            );
+        }
+        // Docker names: [a-zA-Z0-9][a-zA-Z0-9_.-]*; hostnames additionally exclude '_' and '.',
+        // but those are mapped to '-' for the hostname.
+        let sample = self.container_name("user", "project");
+        let valid = sample.starts_with(|c: char| c.is_ascii_alphanumeric())
+            && sample
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'));
+        if !valid {
+            anyhow::bail!("container_name_template {:?} does not produce a valid container name", template);
+        }
+        Ok(())
+    }
+
+    fn validate_allowed_github_orgs(&self) -> Result<()> {
+        for org in &self.allowed_github_orgs {
+            let valid = !org.is_empty()
+                && !org.starts_with('-')
+                && org.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+            if !valid {
+                anyhow::bail!("allowed_github_orgs: {org:?} is not a GitHub organization name");
+            }
+        }
+        if !self.allowed_github_orgs.is_empty() && self.github_org_token_file.is_none() {
+            anyhow::bail!("allowed_github_orgs needs github_org_token_file");
+        }
+        Ok(())
+    }
+
+    pub(crate) fn validate_allowed_projects(&self) -> Result<()> {
+        for entry in &self.allowed_projects {
+            let name = entry.strip_suffix('*').unwrap_or(entry);
+            // A lone `*` allows every project.
+            if !name.is_empty() && crate::github::validate_project_name(name).is_err() {
+                anyhow::bail!("allowed_projects: {entry:?} is not a project name or prefix*");
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether users may have the project `project` (`allowed_projects`).
+    pub fn project_allowed(&self, project: &str) -> bool {
+        self.allowed_projects.is_empty()
+            || self.allowed_projects.iter().any(|entry| match entry.strip_suffix('*') {
+                Some(prefix) => project.starts_with(prefix),
+                None => project == entry,
+            })
+    }
+
+    /// Whether `github_user` may run `agentman admin` commands (GitHub names are
+    /// case-insensitive).
+    pub fn is_admin(&self, github_user: &str) -> bool {
+        self.admin_github_users
+            .iter()
+            .any(|admin| admin.eq_ignore_ascii_case(github_user))
+    }
+
     /// Get the workspace path for a given GitHub user and project.
     pub fn workspace_path(&self, github_user: &str, project: &str) -> PathBuf {
         self.workspace_root.join(github_user).join(project)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mount(host: &str, container: &str) -> ReadOnlyMount {
+        ReadOnlyMount {
+            host_path: PathBuf::from(host),
+            container_path: container.to_string(),
+            env: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_readonly_mount_validate() {
+        assert!(mount("/etc/ssl/corp-ca.pem", "/etc/ssl/certs/corp-ca.pem")
+            .validate()
+            .is_ok());
+
+        assert!(mount("relative.pem", "/etc/ssl/corp.pem").validate().is_err());
+        assert!(mount("/etc/ssl/corp.pem", "etc/ssl/corp.pem").validate().is_err());
+        assert!(mount("/etc/ssl/corp.pem", "/workspace/corp.pem").validate().is_err());
+        assert!(mount("/etc/ssl/corp.pem", "/workspace").validate().is_err());
+        assert!(mount("/etc/ssl/corp.pem", "/").validate().is_err());
+        assert!(mount("/var/run/docker.sock", "/run/docker.sock").validate().is_err());
+    }
+
+    fn device(host: &str, users: &[&str]) -> DeviceAccess {
+        DeviceAccess {
+            host_path: PathBuf::from(host),
+            container_path: None,
+            permissions: default_device_permissions(),
+            users: users.iter().map(|u| u.to_string()).collect(),
+            cgroup_rules: Vec::new(),
+            cap_add: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_device_access() {
+        let kvm = device("/dev/kvm", &["octocat"]);
+        assert!(kvm.validate().is_ok());
+        assert!(kvm.allows("octocat"));
+        assert!(!kvm.allows("defunkt"));
+        assert!(device("/dev/net/tun", &["*"]).allows("defunkt"));
+
+        assert!(device("/etc/passwd", &["*"]).validate().is_err());
+        assert!(device("/dev/kvm", &[]).validate().is_err());
+
+        let mut bad = device("/dev/kvm", &["*"]);
+        bad.permissions = "rwx".to_string();
+        assert!(bad.validate().is_err());
+
+        let mut serial = device("/dev/ttyUSB0", &["*"]);
+        serial.cgroup_rules = vec!["c 188:* rwm".to_string()];
+        assert!(serial.validate().is_ok());
+        serial.cgroup_rules = vec!["a *:* rwm extra".to_string()];
+        assert!(serial.validate().is_err());
+
+        let mut tun = device("/dev/net/tun", &["*"]);
+        tun.cap_add = vec!["NET_ADMIN".to_string()];
+        assert!(tun.validate().is_ok());
+        tun.cap_add = vec!["CAP_SYS_ADMIN".to_string()];
+        assert!(tun.validate().is_err());
+    }
+
+    #[test]
+    fn test_editor_cache_validate() {
+        let mut cfg = EditorCacheConfig::default();
+        assert!(cfg.validate().is_ok());
+
+        cfg.dirs = vec!["../etc".to_string()];
+        assert!(cfg.validate().is_err());
+        cfg.dirs = vec!["..".to_string()];
+        assert!(cfg.validate().is_err());
+        cfg.dirs = vec![".zed_server".to_string()];
+        cfg.enabled = true;
+        cfg.root = PathBuf::from("relative");
+        assert!(cfg.validate().is_err());
+    }
+
+    #[test]
+    fn test_container_name_template() {
+        let mut cfg = GatewayConfig::default();
+        assert_eq!(cfg.container_name("octocat", "api"), "agentman-octocat-api");
+        assert!(cfg.validate_container_name_template().is_ok());
+
+        cfg.container_name_template = "dev-{project}.{user}".to_string();
+        assert_eq!(cfg.container_name("octocat", "api"), "dev-api.octocat");
+        assert!(cfg.validate_container_name_template().is_ok());
+
+        cfg.container_name_template = "agentman-{project}".to_string();
+        assert!(cfg.validate_container_name_template().is_err());
+        cfg.container_name_template = "-{user}-{project}".to_string();
+        assert!(cfg.validate_container_name_template().is_err());
+        cfg.container_name_template = "{user}/{project}".to_string();
+        assert!(cfg.validate_container_name_template().is_err());
+    }
+
+    #[test]
+    fn test_image_allowed() {
+        let mut cfg = GatewayConfig::default();
+        assert!(cfg.image_allowed("agentman-base:dev"));
+        assert!(!cfg.image_allowed("python:agent-3.12"));
+
+        cfg.images.allowed = vec!["python:agent-3.12".to_string()];
+        assert!(cfg.image_allowed("python:agent-3.12"));
+        assert!(!cfg.image_allowed("python:agent-3.11"));
+        assert!(cfg.images.validate().is_ok());
+
+        cfg.images.allowed.push("bad image".to_string());
+        assert!(cfg.images.validate().is_err());
+        cfg.images.allowed.pop();
+
+        cfg.images.named.insert("node".to_string(), "node:agent-22".to_string());
+        assert!(cfg.image_allowed("node:agent-22"));
+        assert_eq!(cfg.named_image("node"), Some("node:agent-22"));
+        assert_eq!(cfg.named_image("default"), Some("agentman-base:dev"));
+        assert_eq!(cfg.named_image("ruby"), None);
+        assert!(cfg.images.validate().is_ok());
+        cfg.images.named.insert("no.dots".to_string(), "x:1".to_string());
+        assert!(cfg.images.validate().is_err());
+    }
+
+    #[test]
+    fn test_workspace_permissions() {
+        let cfg: WorkspacePermissionsConfig =
+            toml::from_str("chown_mode = \"recursive\"\ncontainer_user = \"1001\"").unwrap();
+        assert_eq!(cfg.chown_mode, ChownMode::Recursive);
+        assert_eq!(cfg.container_ids(), Some((1001, 1001)));
+
+        let mut cfg = WorkspacePermissionsConfig {
+            container_user: Some("1001:50".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(cfg.container_ids(), Some((1001, 50)));
+        cfg.container_user = Some("dev".to_string());
+        assert!(cfg.validate().is_err());
+    }
+
+    #[test]
+    fn test_relay_validate() {
+        let mut cfg = RelayConfig::default();
+        assert!(cfg.validate().is_ok());
+
+        cfg.address = Some("relay.example.com:22".to_string());
+        assert!(cfg.validate().is_err(), "fingerprint must be pinned");
+        cfg.server_key_fingerprint = "SHA256:abc".to_string();
+        assert!(cfg.validate().is_ok());
+
+        cfg.address = Some("relay.example.com".to_string());
+        assert!(cfg.validate().is_err());
+        cfg.address = Some(":22".to_string());
+        assert!(cfg.validate().is_err());
+    }
+
+    #[test]
+    fn test_split_host_port() {
+        assert_eq!(split_host_port("gw.example.com"), Some(("gw.example.com", 22)));
+        assert_eq!(split_host_port("gw.example.com:2222"), Some(("gw.example.com", 2222)));
+        assert_eq!(split_host_port("[2001:db8::1]:2222"), Some(("2001:db8::1", 2222)));
+        assert_eq!(split_host_port("2001:db8::1"), Some(("2001:db8::1", 22)));
+        assert_eq!(split_host_port("gw.example.com:0"), None);
+        assert_eq!(split_host_port(":2222"), None);
+        assert_eq!(split_host_port("gw example"), None);
+    }
+
+    #[test]
+    fn test_migration_validate() {
+        let node = |name: &str, address: &str| MigrationNode {
+            name: name.to_string(),
+            address: address.to_string(),
+            host_key_fingerprint: "SHA256:abc".to_string(),
+            public_address: None,
+        };
+        let mut cfg = MigrationConfig::default();
+        assert!(cfg.validate().is_ok());
+
+        cfg.nodes = vec![node("node-a", "10.0.0.1:22"), node("node-b", "node-b.internal:2222")];
+        assert!(cfg.validate().is_ok());
+        assert_eq!(cfg.nodes[1].public_address(), "node-b.internal:2222");
+
+        cfg.nodes.push(node("node-a", "10.0.0.3:22"));
+        assert!(cfg.validate().is_err(), "duplicate name");
+        cfg.nodes.pop();
+        let bad = [("node/c", "10.0.0.3:22"), ("node-c", "10.0.0.3"), ("node-c", ":22")];
+        for (name, address) in bad {
+            cfg.nodes.push(node(name, address));
+            assert!(cfg.validate().is_err(), "{name} {address}");
+            cfg.nodes.pop();
+        }
+        cfg.nodes[0].host_key_fingerprint = String::new();
+        assert!(cfg.validate().is_err(), "fingerprint must be pinned");
+    }
+
+    #[test]
+    fn test_sni_router_validate() {
+        let mut cfg = SniRouterConfig::default();
+        assert!(cfg.validate().is_ok());
+
+        cfg.enabled = true;
+        assert!(cfg.validate().is_err(), "domain is required");
+        cfg.domain = "tls.example.com".to_string();
+        assert!(cfg.validate().is_ok());
+
+        for domain in ["*.example.com", ".example.com", "TLS.example.com", "tls..example.com"] {
+            cfg.domain = domain.to_string();
+            assert!(cfg.validate().is_err(), "{domain}");
+        }
+        cfg.domain = "tls.example.com".to_string();
+        cfg.listen_addr = "localhost:443".to_string();
+        assert!(cfg.validate().is_err());
+    }
+
+    #[test]
+    fn test_proxy_validate() {
+        let mut cfg = ProxyConfig::default();
+        assert!(cfg.validate().is_ok());
+
+        cfg.enabled = true;
+        assert!(cfg.validate().is_err(), "allowlist must not be empty");
+        cfg.allow = vec!["github.com".to_string(), "*.npmjs.org".to_string()];
+        assert!(cfg.validate().is_ok());
+
+        cfg.allow.push("registry.local:notaport".to_string());
+        assert!(cfg.validate().is_err());
+        cfg.allow.pop();
+        cfg.allow.push("api.*.com".to_string());
+        assert!(cfg.validate().is_err());
+        cfg.allow.pop();
+
+        cfg.listen_addr = "0.0.0.0:3128".to_string();
+        assert!(cfg.validate().is_err(), "containers can't reach a wildcard address");
+    }
+
+    #[test]
+    fn test_security_profiles() {
+        let cfg: GatewayConfig = toml::from_str(
+            r#"
+            [[security_profiles.profiles]]
+            name = "trusted"
+            users = ["octocat"]
+            cap_add = ["CHOWN", "SYS_PTRACE"]
+            memory_limit = "16g"
+
+            [[security_profiles.profiles]]
+            name = "untrusted"
+            users = ["*"]
+            readonly_rootfs = true
+            egress = false
+            "#,
+        )
+        .unwrap();
+        assert!(cfg.validate().is_ok());
+        let profiles = &cfg.security_profiles.profiles;
+        assert!(profiles[0].egress && !profiles[1].egress);
+
+        let trusted = profiles[0].apply(&cfg.container_security);
+        assert_eq!(trusted.cap_add, vec!["CHOWN", "SYS_PTRACE"]);
+        assert_eq!(trusted.memory_limit.as_deref(), Some("16g"));
+        assert!(!trusted.readonly_rootfs && trusted.no_new_privileges);
+        let untrusted = profiles[1].apply(&cfg.container_security);
+        assert_eq!(untrusted.cap_add, cfg.container_security.cap_add);
+        assert!(untrusted.readonly_rootfs);
+
+        let invalid = |edit: fn(&mut SecurityProfilesConfig)| {
+            let mut cfg = cfg.security_profiles.clone();
+            edit(&mut cfg);
+            cfg.validate().is_err()
+        };
+        assert!(invalid(|c| c.profiles[1].name = "trusted".to_string()));
+        assert!(invalid(|c| c.profiles[0].name = "trusted ops".to_string()));
+        assert!(invalid(|c| c.profiles[0].users.clear()));
+        assert!(invalid(|c| c.profiles[0].teams = vec!["acme/platform".to_string()]));
+        assert!(invalid(|c| c.profiles[0].cap_add = Some(vec!["ALL".to_string()])));
+        assert!(invalid(|c| c.profiles[0].memory_limit = Some("lots".to_string())));
+        assert!(invalid(|c| c.profiles[0].pids_limit = Some(0)));
+        assert!(!invalid(|c| {
+            c.profiles[0].teams = vec!["acme/platform".to_string()];
+            c.github_token_file = Some(PathBuf::from("/etc/agentman/github-token"));
+        }));
+        assert!(invalid(|c| {
+            c.profiles[0].teams = vec!["platform".to_string()];
+            c.github_token_file = Some(PathBuf::from("/etc/agentman/github-token"));
+        }));
+    }
+
+    #[test]
+    fn test_offboarding_validate() {
+        let mut cfg = OffboardingConfig::default();
+        assert!(cfg.validate().is_ok());
+        cfg.destroy_after_days = Some(3);
+        assert!(cfg.validate().is_err(), "destroy before archive");
+        cfg.destroy_after_days = None;
+        assert!(cfg.validate().is_ok());
+        cfg.inactive_days = 0;
+        assert!(cfg.validate().is_err());
+    }
+
+    #[test]
+    fn test_github_validate() {
+        let mut config = GitHubConfig::default();
+        assert!(config.validate().is_ok());
+        assert!(!config.is_custom());
+        config.keys_url = "http://127.0.0.1:8099/".to_string();
+        assert!(config.validate().is_ok());
+        assert!(config.is_custom());
+        for url in ["github.com", "ftp://github.com", "https://", "https://h/?x=1"] {
+            config.api_url = url.to_string();
+            assert!(config.validate().is_err(), "{url}");
+        }
+    }
+
+    #[test]
+    fn test_allowed_github_orgs_validate() {
+        let mut config = GatewayConfig {
+            allowed_github_orgs: vec!["acme".to_string()],
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        config.github_org_token_file = Some(PathBuf::from("/etc/agentman/github-token"));
+        assert!(config.validate().is_ok());
+        config.allowed_github_orgs.push("acme/platform".to_string());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_clone_validate() {
+        let mut config = CloneConfig::default();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.template_url("octocat", "web"), None);
+        config.url_template = Some("https://github.com/{user}/{project}.git".to_string());
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.template_url("octocat", "web").as_deref(),
+            Some("https://github.com/octocat/web.git")
+        );
+        config.url_template = Some("/srv/repos/{project}".to_string());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_container_security_validate() {
+        let mut config = ContainerSecurityConfig::default();
+        assert!(config.validate().is_ok());
+        config.shm_size = Some("2g".to_string());
+        config.tmpfs.insert("/cache".to_string(), "512m".to_string());
+        config.nofile = Some(65536);
+        assert!(config.validate().is_ok());
+        config.shm_size = Some("lots".to_string());
+        assert!(config.validate().is_err());
+        config.shm_size = None;
+        config.tmpfs.insert("/workspace".to_string(), "1g".to_string());
+        assert!(config.validate().is_err());
+        config.tmpfs.remove("/workspace");
+        config.nproc = Some(0);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_remote_port_range_validate() {
+        let pf = |range, per| PortForwardingConfig {
+            remote_port_range: range,
+            remote_ports_per_workspace: per,
+            ..Default::default()
+        };
+        assert!(pf(None, 0).validate().is_ok());
+        assert!(pf(Some((20000, 29999)), 10).validate().is_ok());
+        assert!(pf(Some((20000, 20000)), 1).validate().is_ok());
+
+        assert!(pf(Some((0, 100)), 10).validate().is_err());
+        assert!(pf(Some((30000, 20000)), 10).validate().is_err());
+        assert!(pf(Some((20000, 20004)), 10).validate().is_err());
+        assert!(pf(Some((20000, 29999)), 0).validate().is_err());
+
+        let gateway_ports = PortForwardingConfig {
+            allow_remote: false,
+            allow_gateway_ports: true,
+            ..Default::default()
+        };
+        assert!(gateway_ports.validate().is_err());
+    }
+
+    #[test]
+    fn test_quotas() {
+        let mut cfg = QuotaConfig {
+            max_containers: Some(3),
+            max_memory: Some("16g".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(cfg.for_user("alice"), None, "disabled");
+        cfg.enabled = true;
+        cfg.users.insert(
+            "Alice".to_string(),
+            UserQuota {
+                max_containers: Some(10),
+                ..Default::default()
+            },
+        );
+        assert!(cfg.validate().is_ok());
+        let alice = cfg.for_user("alice").unwrap();
+        assert_eq!(alice.max_containers, Some(10));
+        assert_eq!(alice.max_memory.as_deref(), Some("16g"));
+        assert_eq!(cfg.for_user("bob").unwrap().max_containers, Some(3));
+
+        cfg.users.get_mut("Alice").unwrap().max_memory = Some("lots".to_string());
+        assert!(cfg.validate().is_err());
+        cfg.users.clear();
+        cfg.max_containers = Some(0);
+        assert!(cfg.validate().is_err());
+    }
+}
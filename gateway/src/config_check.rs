@@ -0,0 +1,250 @@
+//! `--check-config`: validate a config file without starting the gateway.
+//!
+//! Reports TOML syntax and type errors, failed validations (including settings that conflict
+//! with each other) and settings that have no effect, each at the line and column it refers to
+//! when that can be found. Keys the gateway doesn't know are ignored when it starts, which hides
+//! typos, so they are reported as warnings, or as errors with `--strict`.
+
+use std::ops::Range;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use toml::de::{DeTable, DeValue};
+
+use crate::config::GatewayConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// One problem found in a config file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub severity: Severity,
+    /// 1-based line and column.
+    pub location: Option<(usize, usize)>,
+    pub message: String,
+}
+
+/// Check the config file at `path` and print the findings. Returns whether it passed.
+pub fn run(path: &Path, strict: bool) -> Result<bool> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file: {}", path.display()))?;
+    let findings = check(&content, strict);
+    for finding in &findings {
+        let severity = match finding.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        match finding.location {
+            Some((line, col)) => println!(
+                "{}:{line}:{col}: {severity}: {}",
+                path.display(),
+                finding.message
+            ),
+            None => println!("{}: {severity}: {}", path.display(), finding.message),
+        }
+    }
+    let errors = findings
+        .iter()
+        .filter(|f| f.severity == Severity::Error)
+        .count();
+    let warnings = findings.len() - errors;
+    if findings.is_empty() {
+        println!("{}: OK", path.display());
+    } else {
+        println!(
+            "{}: {errors} error(s), {warnings} warning(s)",
+            path.display()
+        );
+    }
+    Ok(errors == 0)
+}
+
+/// Check a config file's contents.
+pub fn check(content: &str, strict: bool) -> Vec<Finding> {
+    let doc = match DeTable::parse(content) {
+        Ok(doc) => doc,
+        Err(e) => return vec![toml_error(content, &e)],
+    };
+    let config: GatewayConfig = match toml::from_str(content) {
+        Ok(config) => config,
+        Err(e) => return vec![toml_error(content, &e)],
+    };
+    let doc = doc.get_ref();
+    let mut findings = Vec::new();
+
+    // Whatever the gateway understood serializes back; anything else in the file was ignored.
+    if let Ok(toml::Value::Table(known)) = toml::Value::try_from(&config) {
+        let mut unknown = Vec::new();
+        unknown_keys(doc, &known, "", &mut unknown);
+        for (key, span) in unknown {
+            findings.push(Finding {
+                severity: if strict {
+                    Severity::Error
+                } else {
+                    Severity::Warning
+                },
+                location: Some(line_col(content, span.start)),
+                message: format!("unknown key `{key}`"),
+            });
+        }
+    }
+
+    if let Err(e) = config.validate() {
+        let message = format!("{e:#}");
+        findings.push(Finding {
+            severity: Severity::Error,
+            location: locate(doc, &message).map(|span| line_col(content, span.start)),
+            message,
+        });
+    }
+    for message in config.warnings() {
+        findings.push(Finding {
+            severity: Severity::Warning,
+            location: locate(doc, &message).map(|span| line_col(content, span.start)),
+            message,
+        });
+    }
+
+    findings.sort_by_key(|f| f.location);
+    findings
+}
+
+fn toml_error(content: &str, e: &toml::de::Error) -> Finding {
+    Finding {
+        severity: Severity::Error,
+        location: e.span().map(|span| line_col(content, span.start)),
+        message: e.message().trim_end().to_string(),
+    }
+}
+
+/// Keys of `doc` missing from `known`, by dotted path.
+fn unknown_keys(
+    doc: &DeTable<'_>,
+    known: &toml::Table,
+    path: &str,
+    out: &mut Vec<(String, Range<usize>)>,
+) {
+    for (key, value) in doc {
+        let name = key.get_ref();
+        let full = if path.is_empty() {
+            name.to_string()
+        } else {
+            format!("{path}.{name}")
+        };
+        match (known.get(name.as_ref()), value.get_ref()) {
+            (None, _) => out.push((full, key.span())),
+            (Some(toml::Value::Table(known)), DeValue::Table(doc)) => {
+                unknown_keys(doc, known, &full, out)
+            }
+            (Some(toml::Value::Array(known)), DeValue::Array(doc)) => {
+                for (i, (known, doc)) in known.iter().zip(doc).enumerate() {
+                    if let (toml::Value::Table(known), DeValue::Table(doc)) = (known, doc.get_ref())
+                    {
+                        unknown_keys(doc, known, &format!("{full}[{i}]"), out);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Where a validation message points. Messages name their section and key the way the config
+/// spells them (`"port_forwarding: remote_port_range must be ..."`, or a top-level key first);
+/// falls back to the section header when the key isn't in the file.
+fn locate(doc: &DeTable<'_>, message: &str) -> Option<Range<usize>> {
+    if let Some((section, rest)) = message.split_once(": ")
+        && let Some((section_key, value)) = doc.get_key_value(section)
+    {
+        let key = match value.get_ref() {
+            DeValue::Table(table) => table.get_key_value(leading_key(rest)),
+            _ => None,
+        };
+        return Some(key.unwrap_or((section_key, value)).0.span());
+    }
+    doc.get_key_value(leading_key(message))
+        .map(|(key, _)| key.span())
+}
+
+fn leading_key(s: &str) -> &str {
+    let end = s
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(s.len());
+    &s[..end]
+}
+
+fn line_col(content: &str, offset: usize) -> (usize, usize) {
+    let before = &content[..offset.min(content.len())];
+    let line = before.matches('\n').count() + 1;
+    let col = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+    (line, col)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_example_config_is_clean() {
+        assert_eq!(
+            check(include_str!("../examples/gateway.toml"), true),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_findings_have_locations() {
+        let content = "listen_addr = \"0.0.0.0:2222\"\n\
+                       dokcer_image = \"ubuntu\"\n\
+                       \n\
+                       [port_forwarding]\n\
+                       allow_remote = false\n\
+                       allow_gateway_ports = true\n\
+                       \n\
+                       [shell]\n\
+                       tmux_sesion = \"work\"\n";
+        let findings = check(content, false);
+        assert_eq!(
+            findings,
+            vec![
+                Finding {
+                    severity: Severity::Warning,
+                    location: Some((2, 1)),
+                    message: "unknown key `dokcer_image`".to_string(),
+                },
+                Finding {
+                    severity: Severity::Error,
+                    location: Some((6, 1)),
+                    message: "port_forwarding: allow_gateway_ports needs allow_remote = true"
+                        .to_string(),
+                },
+                Finding {
+                    severity: Severity::Warning,
+                    location: Some((9, 1)),
+                    message: "unknown key `shell.tmux_sesion`".to_string(),
+                },
+            ]
+        );
+        assert!(
+            check(content, true)
+                .iter()
+                .all(|f| f.severity == Severity::Error)
+        );
+    }
+
+    #[test]
+    fn test_parse_and_type_errors() {
+        let findings = check("[shell]\nmode = \"tmux\"\nescape_char = \n", false);
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].location.map(|(line, _)| line), Some(3));
+
+        let findings = check("[exec]\nkill_grace_secs = \"soon\"\n", false);
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].location, Some((2, 19)));
+        assert!(findings[0].message.contains("invalid type"));
+    }
+}
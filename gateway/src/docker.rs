@@ -1,28 +1,70 @@
 //! Docker container provisioning and management.
 //!
 //! Handles:
-//! - Creating agent containers with unique names
+//! - Creating agent containers with stable, per-workspace names
 //! - Bind-mounting persistent workspaces
 //! - Applying security hardening
 //! - Container lifecycle (start, stop, exec)
 
 use anyhow::{anyhow, Context, Result};
 use bollard::exec::{CreateExecOptions, ResizeExecOptions, StartExecOptions, StartExecResults};
-use bollard::models::{ContainerCreateBody, HostConfig};
+use bollard::models::{
+    ContainerCreateBody, DeviceMapping, EndpointSettings, HostConfig, NetworkConnectRequest,
+    NetworkCreateRequest, NetworkDisconnectRequest, ResourcesUlimits,
+};
 use bollard::query_parameters::{
-    CreateContainerOptionsBuilder, InspectContainerOptions, ListContainersOptionsBuilder,
-    RemoveContainerOptionsBuilder, StartContainerOptions, StopContainerOptionsBuilder,
+    CreateContainerOptionsBuilder, CreateImageOptionsBuilder, DownloadFromContainerOptionsBuilder,
+    InspectContainerOptions, InspectNetworkOptions,
+    ListContainersOptionsBuilder, RemoveContainerOptionsBuilder, RenameContainerOptionsBuilder,
+    StartContainerOptions,
+    StopContainerOptionsBuilder,
 };
+use bollard::container::LogOutput;
 use bollard::Docker;
 use chrono::Utc;
 use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::Read;
+use std::net::IpAddr;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
+use futures::StreamExt;
+use tokio::io::AsyncWriteExt;
 use tokio::process::Command;
-use tracing::{info, warn};
-
-use crate::config::GatewayConfig;
-use crate::state::{StateManager, WorkspaceInfo};
+use tracing::{debug, info, warn};
+
+use crate::admission;
+use crate::build;
+use crate::config::{ChownMode, GatewayConfig, SecurityProfile, UserQuota};
+use crate::docker_transport;
+use crate::editor_cache;
+use crate::egress;
+use crate::env_file;
+use crate::ephemeral;
+use crate::gateway_control::format_bytes;
+use crate::git_identity;
+use crate::git_signing;
+use crate::ha;
+use crate::helper;
+use crate::host_fs::{self, Handle};
+use crate::journal;
+use crate::mesh;
+use crate::ownership;
+use crate::preferences;
+use crate::provision;
+use crate::proxy;
+use crate::proxy_ca;
+use crate::security_profiles::{self, NO_EGRESS_NETWORK, PROFILE_LABEL};
+use crate::stack;
+use crate::stats_history;
+use crate::tar;
+use crate::user_config;
+use crate::user_notify;
+use crate::state::{
+    InitState, InitStatus, OperationKind, StateManager, WorkspaceInfo, WorkspaceResources,
+};
+use crate::workspace_guard;
 
 /// Options for destroying a workspace (container(s) + persistent data).
 #[derive(Debug, Clone, Copy)]
@@ -35,8 +77,55 @@ pub struct DestroyOptions {
     pub dry_run: bool,
 }
 
-/// Summary of a destroy operation.
+/// How long a blue/green recreate waits for the new container to become healthy.
+const RECREATE_HEALTH_TIMEOUT_SECS: u64 = 60;
+
+/// Container label recording the `agentman image` choice it was created with (absent for the
+/// deployment default).
+pub(crate) const IMAGE_CHOICE_LABEL: &str = "agentman.image";
+
+/// Options for recreating a workspace's container.
+#[derive(Debug, Clone, Copy)]
+pub struct RecreateOptions {
+    /// Keep the old container serving until the new one is healthy.
+    pub keep_running: bool,
+    /// Pull the configured image before creating the new container.
+    pub pull: bool,
+}
+
+/// Result of recreating a workspace's container.
+#[derive(Debug)]
+pub struct RecreateResult {
+    pub old_container: Option<String>,
+    pub new_container: String,
+    pub warnings: Vec<String>,
+}
+
+/// Captured result of a command run to completion inside a container.
 #[derive(Debug, Clone)]
+pub struct ExecOutput {
+    pub exit_code: i64,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+/// Environment variable tagging every process started by an SSH exec, so the gateway can find
+/// (and kill) an exec's process tree inside the container.
+pub const EXEC_MARKER_ENV: &str = "AGENTMAN_EXEC_ID";
+
+/// `sh -c` script for [`ContainerManager::signal_exec_processes`]: `$1` is the signal, `$2`
+/// the `NAME=value` marker to look for in `/proc/<pid>/environ`.
+const SIGNAL_EXEC_SCRIPT: &str = r#"for d in /proc/[0-9]*; do
+  pid=${d#/proc/}
+  [ "$pid" = "$$" ] && continue
+  if tr '\0' '\n' < "$d/environ" 2>/dev/null | grep -qxF "$2"; then
+    kill -s "$1" "$pid" 2>/dev/null
+  fi
+done
+exit 0"#;
+
+/// Summary of a destroy operation.
+#[derive(Debug, Clone, serde::Serialize)]
 pub struct DestroyResult {
     pub removed_containers: Vec<String>,
     pub workspace_path: PathBuf,
@@ -45,6 +134,15 @@ pub struct DestroyResult {
     pub warnings: Vec<String>,
 }
 
+/// Result of [`ContainerManager::transfer_workspace`].
+#[derive(Debug, Clone)]
+pub struct TransferResult {
+    pub removed_containers: Vec<String>,
+    /// Where the workspace lives now.
+    pub workspace_path: PathBuf,
+    pub warnings: Vec<String>,
+}
+
 impl DestroyResult {
     pub fn format_human(&self) -> String {
         let mut out = String::new();
@@ -82,42 +180,64 @@ impl DestroyResult {
     }
 }
 
+/// Container user assumed when the image's can't be determined (the base image's
+/// USER_UID/USER_GID, see Dockerfile).
+const DEFAULT_CONTAINER_UID: u32 = 1000;
+const DEFAULT_CONTAINER_GID: u32 = 1000;
+
+/// Host UID/GID that container processes run as: the image's user, shifted by the user
+/// namespace offset under `userns-remap`. Workspace directories are handed to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct WorkspaceOwner {
+    pub uid: u32,
+    pub gid: u32,
+}
+
 #[cfg(unix)]
-async fn ensure_workspace_writable(path: &Path) -> Result<()> {
+fn owner_can_write(md: &std::fs::Metadata, owner: WorkspaceOwner) -> bool {
     use std::os::unix::fs::{MetadataExt, PermissionsExt};
 
-    // This matches the default user baked into the base image (see Dockerfile: USER_UID/USER_GID).
-    // If you run a custom image with a different UID/GID, you may need to adjust this logic.
-    const CONTAINER_UID: u32 = 1000;
-    const CONTAINER_GID: u32 = 1000;
+    let mode = md.permissions().mode() & 0o777;
+    if md.uid() == owner.uid {
+        (mode & 0o200 != 0) && (mode & 0o100 != 0)
+    } else if md.gid() == owner.gid {
+        (mode & 0o020 != 0) && (mode & 0o010 != 0)
+    } else {
+        (mode & 0o002 != 0) && (mode & 0o001 != 0)
+    }
+}
+
+/// Create `path` and make it writable for the container user, as far as `mode` allows.
+#[cfg(unix)]
+async fn ensure_workspace_writable(path: &Path, owner: WorkspaceOwner, mode: ChownMode) -> Result<()> {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
 
     // Ensure directory exists.
     tokio::fs::create_dir_all(path)
         .await
         .with_context(|| format!("Failed to create workspace directory: {}", path.display()))?;
+    if mode == ChownMode::None {
+        return Ok(());
+    }
 
     let md = tokio::fs::metadata(path)
         .await
         .with_context(|| format!("Failed to stat workspace directory: {}", path.display()))?;
-    let mode = md.permissions().mode() & 0o777;
-
-    // Check whether UID 1000 can write to this directory with current ownership/mode.
-    let writable = if md.uid() == CONTAINER_UID {
-        (mode & 0o200 != 0) && (mode & 0o100 != 0)
-    } else if md.gid() == CONTAINER_GID {
-        (mode & 0o020 != 0) && (mode & 0o010 != 0)
-    } else {
-        (mode & 0o002 != 0) && (mode & 0o001 != 0)
-    };
-    if writable {
+    let owned = md.uid() == owner.uid && md.gid() == owner.gid;
+    if owner_can_write(&md, owner) && (owned || mode == ChownMode::RootOnly) {
         return Ok(());
     }
 
     // Try to fix ownership and mode. This will succeed when the gateway runs as root.
-    // We do NOT do recursive chown to avoid expensive walks on large workspaces.
-    // The key requirement for editor bootstraps is that the workspace root is writable.
-    match Command::new("chown")
-        .arg(format!("{CONTAINER_UID}:{CONTAINER_GID}"))
+    // Only the recursive mode walks the tree (and only when the root's owner is wrong); the key
+    // requirement for editor bootstraps is that the workspace root is writable.
+    let mut chown = Command::new("chown");
+    if mode == ChownMode::Recursive && !owned {
+        info!("Changing ownership of {} to {}:{} (recursive)", path.display(), owner.uid, owner.gid);
+        chown.arg("-R");
+    }
+    match chown
+        .arg(format!("{}:{}", owner.uid, owner.gid))
         .arg(path)
         .status()
         .await
@@ -125,15 +245,15 @@ async fn ensure_workspace_writable(path: &Path) -> Result<()> {
         Ok(status) if status.success() => {}
         Ok(status) => warn!(
             "chown {}:{} {} exited with status {}",
-            CONTAINER_UID,
-            CONTAINER_GID,
+            owner.uid,
+            owner.gid,
             path.display(),
             status
         ),
         Err(e) => warn!(
             "Failed to run chown {}:{} {}: {}",
-            CONTAINER_UID,
-            CONTAINER_GID,
+            owner.uid,
+            owner.gid,
             path.display(),
             e
         ),
@@ -146,15 +266,7 @@ async fn ensure_workspace_writable(path: &Path) -> Result<()> {
     let md2 = tokio::fs::metadata(path)
         .await
         .with_context(|| format!("Failed to stat workspace directory: {}", path.display()))?;
-    let mode2 = md2.permissions().mode() & 0o777;
-    let writable2 = if md2.uid() == CONTAINER_UID {
-        (mode2 & 0o200 != 0) && (mode2 & 0o100 != 0)
-    } else if md2.gid() == CONTAINER_GID {
-        (mode2 & 0o020 != 0) && (mode2 & 0o010 != 0)
-    } else {
-        (mode2 & 0o002 != 0) && (mode2 & 0o001 != 0)
-    };
-    if !writable2 {
+    if !owner_can_write(&md2, owner) {
         tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(0o777))
             .await
             .with_context(|| {
@@ -169,21 +281,86 @@ async fn ensure_workspace_writable(path: &Path) -> Result<()> {
 }
 
 #[cfg(not(unix))]
-async fn ensure_workspace_writable(_path: &Path) -> Result<()> {
+async fn ensure_workspace_writable(_path: &Path, _owner: WorkspaceOwner, _mode: ChownMode) -> Result<()> {
     Ok(())
 }
 
+/// Resolve a Docker `USER` spec (`user[:group]`, names or IDs) to numeric IDs, the way Docker
+/// does: a missing group is the user's primary group from /etc/passwd, else 0.
+fn resolve_user(spec: &str, passwd: Option<&str>, groups: Option<&str>) -> Result<(u32, u32)> {
+    let (user, group) = match spec.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (spec, None),
+    };
+    let entry = passwd.and_then(|passwd| passwd_entry(passwd, user));
+    let uid = match user {
+        "" => 0,
+        _ => match (user.parse::<u32>(), entry) {
+            (Ok(uid), _) => uid,
+            (Err(_), Some((uid, _))) => uid,
+            (Err(_), None) => anyhow::bail!("user {user:?} is not in the image's /etc/passwd"),
+        },
+    };
+    let gid = match group {
+        Some(group) => match group.parse::<u32>() {
+            Ok(gid) => gid,
+            Err(_) => groups
+                .and_then(|groups| group_id(groups, group))
+                .ok_or_else(|| anyhow!("group {group:?} is not in the image's /etc/group"))?,
+        },
+        None => entry.map(|(_, gid)| gid).unwrap_or(0),
+    };
+    Ok((uid, gid))
+}
+
+/// `(uid, gid)` of `user` (a name or UID) in an /etc/passwd file.
+fn passwd_entry(passwd: &str, user: &str) -> Option<(u32, u32)> {
+    passwd.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        let [name, _, uid, gid, ..] = fields[..] else {
+            return None;
+        };
+        (name == user || uid == user).then(|| Some((uid.parse().ok()?, gid.parse().ok()?)))?
+    })
+}
+
+/// GID of group `name` in an /etc/group file.
+fn group_id(groups: &str, name: &str) -> Option<u32> {
+    groups.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        (fields.next() == Some(name))
+            .then(|| fields.nth(1)?.parse().ok())
+            .flatten()
+    })
+}
+
+/// First ID of `user`'s range in an /etc/subuid or /etc/subgid file.
+fn subid_start(contents: &str, user: &str) -> Option<u32> {
+    contents.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        (fields.next() == Some(user))
+            .then(|| fields.next()?.parse().ok())
+            .flatten()
+    })
+}
+
 /// Docker container manager.
 pub struct ContainerManager {
     docker: Docker,
     config: Arc<GatewayConfig>,
     state: Arc<StateManager>,
+    /// Container user IDs per image ID (see [`ContainerManager::workspace_owner`]).
+    image_users: std::sync::Mutex<HashMap<String, (u32, u32)>>,
+    /// Host UID/GID offsets under `userns-remap`, detected once.
+    userns_offset: tokio::sync::OnceCell<(u32, u32)>,
+    /// Held from a user's quota check until the sandbox it allowed has started.
+    quota_locks: UserLocks,
 }
 
 impl ContainerManager {
     /// Create a new container manager.
     pub async fn new(config: Arc<GatewayConfig>, state: Arc<StateManager>) -> Result<Self> {
-        let docker = Docker::connect_with_local_defaults()
+        let docker = docker_transport::connect(config.metrics.docker_slow_call_ms)
             .context("Failed to connect to Docker daemon")?;
 
         // Verify connection
@@ -198,6 +375,9 @@ impl ContainerManager {
             docker,
             config,
             state,
+            image_users: std::sync::Mutex::new(HashMap::new()),
+            userns_offset: tokio::sync::OnceCell::new(),
+            quota_locks: UserLocks::default(),
         })
     }
 
@@ -209,19 +389,71 @@ impl ContainerManager {
         github_user: &str,
         project: &str,
     ) -> Result<String> {
+        if let Some(reason) = egress::blocked(&self.config.egress, github_user).await {
+            anyhow::bail!("{reason}");
+        }
+        let config = self.user_config(github_user).await?;
+        if let Some(refusal) = user_config::project_refusal(&config, github_user, project) {
+            anyhow::bail!("{refusal}");
+        }
+        journal::check_usable(&self.state, github_user, project).await?;
         // Ensure the host workspace directory is writable by the container user (needed for Zed/VS Code bootstraps).
         let workspace_path = self.config.workspace_path(github_user, project);
-        ensure_workspace_writable(&workspace_path).await?;
+        let workspace = self.state.get_workspace(github_user, project).await;
+        let image = self.effective_image(github_user, workspace.as_ref()).await;
+        self.prepare_workspace_dir(&workspace_path, &image).await?;
+        user_notify::ensure_listener(
+            &self.config,
+            &self.state,
+            github_user,
+            project,
+            &workspace_path,
+        );
+        if let Err(e) = self.ensure_ownership_watcher(github_user, project).await {
+            debug!("Not watching ownership in {}/{}: {:#}", github_user, project, e);
+        }
 
         // Check if we already have a container for this workspace
         if let Some(workspace) = self.state.get_workspace(github_user, project).await {
             // Check if container still exists and is usable
-            if let Some(ref container_id) = workspace.container_id {
-                if self.container_exists(container_id).await? {
-                    // Ensure it's running
-                    self.ensure_running(container_id).await?;
-                    return Ok(container_id.clone());
+            if let Some(ref container_id) = workspace.container_id
+                && self.container_exists(container_id).await?
+            {
+                // A stopped container still on the old image is replaced instead of started
+                // (`agentman image set`).
+                if let Some(image) = self.pending_image_switch(&workspace, container_id).await? {
+                    info!(
+                        "Switching {}/{} to image {}",
+                        github_user, project, image
+                    );
+                    let _quota = self.check_quota(github_user, None).await?;
+                    let opts = RecreateOptions {
+                        keep_running: false,
+                        pull: false,
+                    };
+                    self.recreate_container(github_user, project, opts).await?;
+                    return self
+                        .state
+                        .get_workspace(github_user, project)
+                        .await
+                        .and_then(|ws| ws.container_id)
+                        .ok_or_else(|| anyhow!("Recreated container was not recorded"));
                 }
+                let quota = self.check_quota(github_user, Some(container_id)).await?;
+                // Ensure it's running (sidecars first, so the init command can use them)
+                let started = self.ensure_running(container_id).await?;
+                drop(quota);
+                if started {
+                    stack::bring_up(self, github_user, project, Some(container_id)).await;
+                    // It may have changed while the container was stopped.
+                    self.apply_git_identity(github_user, project, container_id)
+                        .await;
+                    // A clone that failed or was cut short is tried again.
+                    provision::run(self, github_user, project, container_id).await;
+                    self.spawn_init_command(github_user, project, container_id)
+                        .await;
+                }
+                return Ok(container_id.clone());
             }
             // Container doesn't exist anymore, need to recreate
             warn!(
@@ -230,29 +462,109 @@ impl ContainerManager {
             );
         }
 
-        // Create new container
+        let _quota = self.check_quota(github_user, None).await?;
+        provision::plan(self, github_user, project, None).await?;
+        // Create new container (after its sidecars; it joins their network on activation)
+        stack::bring_up(self, github_user, project, None).await;
         self.create_container(github_user, project).await
     }
 
     /// Create a new container for the given user and project.
     async fn create_container(&self, github_user: &str, project: &str) -> Result<String> {
-        let now = Utc::now();
-        let date_str = now.format("%Y%m%d").to_string();
-        let container_name = format!("{}-{}-{}", project, github_user, date_str);
+        let name = self.claim_stable_name(github_user, project).await?;
+        let (container_name, container_id) = self.create_and_start(github_user, project, &name).await?;
+        self.activate_container(github_user, project, &container_name, &container_id)
+            .await?;
+        Ok(container_id)
+    }
+
+    /// Create the workspace's container without starting it, recorded in state, so that it
+    /// can be started from a checkpoint (`agentman admin migrate --checkpoint`). Nothing runs
+    /// in it before that, including the init command.
+    pub async fn create_stopped_container(
+        &self,
+        github_user: &str,
+        project: &str,
+    ) -> Result<String> {
+        let name = self.claim_stable_name(github_user, project).await?;
+        let _slot = admission::acquire(&self.config.admission, &format!("{github_user}/{project}"))
+            .await?;
+        let (container_name, container_id) =
+            self.create_unstarted(github_user, project, &name).await?;
+        let workspace_path = self.config.workspace_path(github_user, project);
+        let previous = self.state.get_workspace(github_user, project).await;
+        self.state
+            .set_workspace(WorkspaceInfo {
+                github_user: github_user.to_string(),
+                project: project.to_string(),
+                container_name,
+                container_id: Some(container_id.clone()),
+                created_at: Utc::now(),
+                host_workspace_path: workspace_path,
+                linked_projects: Vec::new(),
+                init_command: previous.as_ref().and_then(|ws| ws.init_command.clone()),
+                init_status: None,
+                image: previous.as_ref().and_then(|ws| ws.image.clone()),
+                tls_port: previous.as_ref().and_then(|ws| ws.tls_port),
+                watch_ownership: previous.as_ref().is_some_and(|ws| ws.watch_ownership),
+                clone: previous.as_ref().and_then(|ws| ws.clone.clone()),
+                resources: previous.map(|ws| ws.resources).unwrap_or_default(),
+            })
+            .await?;
+        Ok(container_id)
+    }
+
+    /// Create and start a container named `container_name` for the workspace without
+    /// recording it in state.
+    ///
+    /// Returns `(container_name, container_id)`.
+    async fn create_and_start(
+        &self,
+        github_user: &str,
+        project: &str,
+        container_name: &str,
+    ) -> Result<(String, String)> {
+        let _slot = admission::acquire(&self.config.admission, &format!("{github_user}/{project}"))
+            .await?;
+        let (container_name, container_id) =
+            self.create_unstarted(github_user, project, container_name).await?;
 
-        // Ensure unique name by adding suffix if needed
-        let container_name = self.ensure_unique_name(&container_name).await?;
+        // Start the container
+        self.docker
+            .start_container(&container_id, None::<StartContainerOptions>)
+            .await
+            .with_context(|| format!("Failed to start container {}", container_name))?;
+
+        info!("Started container {}", container_name);
+        Ok((container_name, container_id))
+    }
+
+    /// Create a container named `container_name` for the workspace. The hostname is always
+    /// derived from the stable name, so it doesn't change when a container is created under a
+    /// temporary name.
+    async fn create_unstarted(
+        &self,
+        github_user: &str,
+        project: &str,
+        container_name: &str,
+    ) -> Result<(String, String)> {
+        let container_name = container_name.to_string();
+        let hostname = hostname_for(&self.config.container_name(github_user, project));
 
         info!(
             "Creating container {} for {}/{}",
             container_name, github_user, project
         );
 
+        ha::ensure_active()?;
+
         // Ensure workspace directory exists
         let workspace_path = self.config.workspace_path(github_user, project);
-        ensure_workspace_writable(&workspace_path).await?;
+        let workspace = self.state.get_workspace(github_user, project).await;
+        let image = self.effective_image(github_user, workspace.as_ref()).await;
+        self.prepare_workspace_dir(&workspace_path, &image).await?;
 
-        let labels: HashMap<String, String> = HashMap::from([
+        let mut labels: HashMap<String, String> = HashMap::from([
             ("agentman.managed".to_string(), "true".to_string()),
             ("agentman.github_user".to_string(), github_user.to_string()),
             ("agentman.project".to_string(), project.to_string()),
@@ -261,14 +573,46 @@ impl ContainerManager {
                 workspace_path.display().to_string(),
             ),
         ]);
+        if let Some(choice) = workspace.as_ref().and_then(|ws| ws.image.clone()) {
+            labels.insert(IMAGE_CHOICE_LABEL.to_string(), choice);
+        }
+        let profile = self.security_profile(github_user).await?;
+        if let Some(profile) = profile {
+            labels.insert(PROFILE_LABEL.to_string(), profile.name.clone());
+        }
+
+        // Shared editor server caches; a cache that can't be prepared is skipped, not fatal.
+        let mut extra_binds: Vec<String> = proxy_ca::bind(&self.config.proxy_ca).into_iter().collect();
+        let prefs = self.state.preferences(github_user).await;
+        let editor_binds = if preferences::editor_cache(&self.config, &prefs) {
+            editor_cache::binds(&self.config.editor_cache, github_user)
+        } else {
+            Vec::new()
+        };
+        for (host, container) in editor_binds {
+            match self.prepare_workspace_dir(&host, &image).await {
+                Ok(()) => extra_binds.push(format!("{}:{}", host.display(), container)),
+                Err(e) => warn!("Skipping editor cache {}: {:#}", host.display(), e),
+            }
+        }
 
         // Build container configuration
-        let host_config = self.build_host_config(&workspace_path)?;
-        let env = self.build_env(github_user, project, &container_name);
+        let resources = workspace.as_ref().map(|ws| ws.resources.clone()).unwrap_or_default();
+        let host_config = self.build_host_config(
+            github_user,
+            profile,
+            &resources,
+            Some(&workspace_path),
+            extra_binds,
+        )
+        .await?;
+        let mut env = self.build_env(github_user, project, &container_name);
+        env.extend(stack::primary_env(&self.config, github_user, project).await);
+        env_file::inject(&self.config.env_file, github_user, project, &mut env).await;
 
         let config = ContainerCreateBody {
-            image: Some(self.config.docker_image.clone()),
-            hostname: Some(container_name.clone()),
+            image: Some(image.clone()),
+            hostname: Some(hostname),
             env: Some(env),
             labels: Some(labels),
             host_config: Some(host_config),
@@ -285,155 +629,883 @@ impl ContainerManager {
             .name(&container_name)
             .build();
 
-        let response = self
+        // Images picked with `agentman image set` may not be on the host yet.
+        let response = match self
             .docker
-            .create_container(Some(options), config)
+            .create_container(Some(options.clone()), config.clone())
             .await
-            .with_context(|| format!("Failed to create container {}", container_name))?;
+        {
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => {
+                self.pull_image(&image).await?;
+                self.docker.create_container(Some(options), config).await
+            }
+            other => other,
+        }
+        .with_context(|| format!("Failed to create container {}", container_name))?;
 
         let container_id = response.id;
         info!("Created container {} ({})", container_name, &container_id[..12]);
 
-        // Start the container
-        self.docker
-            .start_container(&container_id, None::<StartContainerOptions>)
-            .await
-            .with_context(|| format!("Failed to start container {}", container_name))?;
+        // Forwards fall back to the image's own tools without it.
+        if let Err(e) = helper::install(&self.docker, &self.config.helper, &container_id).await {
+            warn!("{}: {:#}", container_name, e);
+        }
+        Ok((container_name, container_id))
+    }
 
-        info!("Started container {}", container_name);
+    /// Make `container_id` the workspace's container: record it in state (carrying over
+    /// per-workspace settings), re-apply project links, clone the workspace's repository, and
+    /// run the init command.
+    async fn activate_container(
+        &self,
+        github_user: &str,
+        project: &str,
+        container_name: &str,
+        container_id: &str,
+    ) -> Result<()> {
+        let workspace_path = self.config.workspace_path(github_user, project);
+
+        // Carry over per-workspace settings from a previous container (if any).
+        let previous = self.state.get_workspace(github_user, project).await;
+        let linked_projects = previous
+            .as_ref()
+            .map(|ws| ws.linked_projects.clone())
+            .unwrap_or_default();
 
         // Save workspace info
         let workspace_info = WorkspaceInfo {
             github_user: github_user.to_string(),
             project: project.to_string(),
-            container_name: container_name.clone(),
-            container_id: Some(container_id.clone()),
-            created_at: now,
+            container_name: container_name.to_string(),
+            container_id: Some(container_id.to_string()),
+            created_at: Utc::now(),
             host_workspace_path: workspace_path,
+            linked_projects: linked_projects.clone(),
+            init_command: previous.as_ref().and_then(|ws| ws.init_command.clone()),
+            init_status: previous.as_ref().and_then(|ws| ws.init_status.clone()),
+            image: previous.as_ref().and_then(|ws| ws.image.clone()),
+            tls_port: previous.as_ref().and_then(|ws| ws.tls_port),
+            watch_ownership: previous.as_ref().is_some_and(|ws| ws.watch_ownership),
+            clone: previous.as_ref().and_then(|ws| ws.clone.clone()),
+            resources: previous.map(|ws| ws.resources).unwrap_or_default(),
         };
 
         self.state.set_workspace(workspace_info).await?;
 
-        Ok(container_id)
-    }
+        // Re-apply project network links. Failures here should not prevent the user from
+        // getting a shell, so they are only logged.
+        if let Err(e) = self
+            .apply_links(github_user, project, container_id, &linked_projects)
+            .await
+        {
+            warn!("Failed to re-apply project links for {}/{}: {}", github_user, project, e);
+        }
+
+        // Trust the proxy CA before the user's first command; failures only cost HTTPS through
+        // an intercepting proxy.
+        if self.config.proxy_ca.enabled
+            && let Err(e) = proxy_ca::install(self, container_id).await
+        {
+            warn!("Failed to install proxy CA in {}/{}: {:#}", github_user, project, e);
+        }
 
-    /// Build the HostConfig with security settings and mounts.
-    fn build_host_config(&self, workspace_path: &Path) -> Result<HostConfig> {
-        let security = &self.config.container_security;
+        self.configure_git(github_user, project, container_id).await;
 
-        let mut host_config = HostConfig {
-            // Bind mount the workspace
-            binds: Some(vec![format!(
-                "{}:/workspace",
-                workspace_path.display()
-            )]),
+        // The repository is in place before the init command runs.
+        provision::run(self, github_user, project, container_id).await;
 
-            // Add host.docker.internal for reverse port forwarding
-            extra_hosts: Some(vec!["host.docker.internal:host-gateway".to_string()]),
+        self.spawn_init_command(github_user, project, container_id)
+            .await;
 
-            // Never run privileged
-            privileged: Some(false),
+        Ok(())
+    }
 
-            // No access to Docker socket
-            // (binds is already set, so docker.sock won't be mounted)
+    /// Write the user's git identity and set up commit signing in a new container.
+    async fn configure_git(&self, github_user: &str, project: &str, container_id: &str) {
+        self.apply_git_identity(github_user, project, container_id)
+            .await;
+        // A failed setup only leaves commits unsigned.
+        if self.config.git_signing.enabled
+            && let Err(e) = git_signing::configure(self, github_user, container_id).await
+        {
+            warn!("Failed to set up git signing in {}/{}: {:#}", github_user, project, e);
+        }
+    }
 
-            // Network settings
-            network_mode: Some("bridge".to_string()),
+    /// Write the user's `agentman git-identity` (if any) into the container's git config.
+    async fn apply_git_identity(&self, github_user: &str, project: &str, container_id: &str) {
+        if let Some(identity) = self.state.git_identity(github_user).await
+            && let Err(e) = git_identity::apply(self, container_id, &identity).await
+        {
+            warn!("Failed to set git identity in {}/{}: {:#}", github_user, project, e);
+        }
+    }
 
-            // Init process for proper signal handling
-            init: Some(true),
+    /// Replace the workspace's container with a fresh one from the configured image.
+    ///
+    /// With `keep_running`, the new container is started and health-checked while the old
+    /// one keeps serving; the state mapping is switched only once the new one is healthy, and
+    /// the old one is retired afterwards. If the new container is unhealthy it is removed and
+    /// the old one stays in place.
+    ///
+    /// The recreate is journaled (see [`journal`]) until it has finished.
+    pub async fn recreate_container(
+        &self,
+        github_user: &str,
+        project: &str,
+        opts: RecreateOptions,
+    ) -> Result<RecreateResult> {
+        self.state
+            .begin_operation(github_user, project, OperationKind::Recreate, journal::STEP_STARTING)
+            .await?;
+        let result = self.recreate_steps(github_user, project, opts).await;
+        journal::end(&self.state, github_user, project, &result).await;
+        result
+    }
 
-            ..Default::default()
-        };
+    /// The steps of [`Self::recreate_container`], under an operation already journaled.
+    pub(crate) async fn recreate_steps(
+        &self,
+        github_user: &str,
+        project: &str,
+        opts: RecreateOptions,
+    ) -> Result<RecreateResult> {
+        let workspace_path = self.config.workspace_path(github_user, project);
+        let workspace = self.state.get_workspace(github_user, project).await;
+        let image = self.effective_image(github_user, workspace.as_ref()).await;
+        self.prepare_workspace_dir(&workspace_path, &image).await?;
 
-        // Apply security settings
-        if security.cap_drop_all {
-            host_config.cap_drop = Some(vec!["ALL".to_string()]);
-            if !security.cap_add.is_empty() {
-                host_config.cap_add = Some(security.cap_add.clone());
-            }
-        }
+        let old = self
+            .state
+            .get_workspace(github_user, project)
+            .await
+            .map(|ws| ws.container_id.unwrap_or(ws.container_name));
 
-        if security.no_new_privileges {
-            host_config.security_opt = Some(vec!["no-new-privileges:true".to_string()]);
+        // Pull first: this is the slow part, and the old container is untouched meanwhile.
+        if opts.pull {
+            let workspace = self.state.get_workspace(github_user, project).await;
+            self.pull_image(&self.effective_image(github_user, workspace.as_ref()).await)
+                .await?;
         }
 
-        if security.readonly_rootfs {
-            host_config.readonly_rootfs = Some(true);
-            // Add tmpfs for common writable paths
-            host_config.tmpfs = Some(HashMap::from([
-                ("/tmp".to_string(), "rw,noexec,nosuid,size=1g".to_string()),
-                ("/run".to_string(), "rw,noexec,nosuid,size=64m".to_string()),
-                ("/var/tmp".to_string(), "rw,noexec,nosuid,size=256m".to_string()),
-            ]));
+        let mut warnings = Vec::new();
+
+        if !opts.keep_running {
+            journal::step(&self.state, github_user, project, "removing old container").await;
+            if let Some(ref old) = old
+                && let Err(e) = self.retire_container(old).await
+            {
+                warnings.push(format!("retire old container {old}: {e:#}"));
+            }
+            journal::step(&self.state, github_user, project, "creating container").await;
+            let name = self.claim_stable_name(github_user, project).await?;
+            let (name, id) = self.create_and_start(github_user, project, &name).await?;
+            journal::created(&self.state, github_user, project, &id).await;
+            self.activate_container(github_user, project, &name, &id)
+                .await?;
+            return Ok(RecreateResult {
+                old_container: old,
+                new_container: name,
+                warnings,
+            });
         }
 
-        if let Some(ref memory) = security.memory_limit {
-            // Parse memory limit (e.g., "4g" -> bytes)
-            host_config.memory = Some(parse_memory_limit(memory)?);
+        // Both containers run side by side until the switch, so the new one starts under a
+        // temporary name and takes over the stable name once the old one is gone.
+        let stable_name = self.config.container_name(github_user, project);
+        let staging_name = self.ensure_unique_name(&format!("{stable_name}-next")).await?;
+        // Until the switch the old container serves as before; only the new one is journaled.
+        let (name, id) = self.create_and_start(github_user, project, &staging_name).await?;
+        journal::created(&self.state, github_user, project, &id).await;
+        let linked_projects = self
+            .state
+            .get_workspace(github_user, project)
+            .await
+            .map(|ws| ws.linked_projects)
+            .unwrap_or_default();
+        if let Err(e) = self
+            .apply_links(github_user, project, &id, &linked_projects)
+            .await
+        {
+            warnings.push(format!("apply project links: {e:#}"));
         }
 
-        if let Some(cpu) = security.cpu_limit {
-            // CPU quota in 100ns units (1 CPU = 100000)
-            host_config.nano_cpus = Some((cpu * 1_000_000_000.0) as i64);
+        if let Err(e) = self
+            .wait_healthy(&id, Duration::from_secs(RECREATE_HEALTH_TIMEOUT_SECS))
+            .await
+        {
+            if let Err(rm) = self.retire_container(&id).await {
+                warn!("Failed to remove unhealthy container {}: {:#}", name, rm);
+            }
+            return Err(e.context(format!(
+                "New container {name} failed its health check; the old container was kept"
+            )));
         }
 
-        // Use default seccomp profile (don't set to unconfined)
-        // The default Docker seccomp profile is already applied unless explicitly disabled
+        // Switch the workspace over, then retire the old container.
+        journal::step(&self.state, github_user, project, "switching containers").await;
+        self.activate_container(github_user, project, &name, &id)
+            .await?;
+        let mut name = name;
+        if let Some(ref old) = old
+            && let Err(e) = self.retire_container(old).await
+        {
+            warnings.push(format!("retire old container {old}: {e:#}"));
+        } else {
+            match self.rename_workspace_container(github_user, project, &id, &stable_name).await {
+                Ok(()) => name = stable_name,
+                Err(e) => warnings.push(format!("rename {name} to {stable_name}: {e:#}")),
+            }
+        }
 
-        Ok(host_config)
+        Ok(RecreateResult {
+            old_container: old,
+            new_container: name,
+            warnings,
+        })
     }
 
-    /// Build environment variables for the container.
-    fn build_env(&self, github_user: &str, project: &str, container_name: &str) -> Vec<String> {
-        vec![
-            format!("GITHUB_USERNAME={}", github_user),
-            format!("AGENTMAN_PROJECT={}", project),
-            format!("AGENTMAN_CONTAINER_ID={}", container_name),
-            "TERM=xterm-256color".to_string(),
-        ]
+    /// Pull `image` from its registry.
+    pub(crate) async fn pull_image(&self, image: &str) -> Result<()> {
+        info!("Pulling image {}", image);
+        let options = CreateImageOptionsBuilder::new().from_image(image).build();
+        let mut stream = self.docker.create_image(Some(options), None, None);
+        while let Some(progress) = stream.next().await {
+            progress.with_context(|| format!("Failed to pull image {}", image))?;
+        }
+        info!("Pulled image {}", image);
+        Ok(())
     }
 
-    /// Ensure the container name is unique by adding a suffix if needed.
-    async fn ensure_unique_name(&self, base_name: &str) -> Result<String> {
-        let mut name = base_name.to_string();
-        let mut suffix = 0;
-
+    /// Wait until a new container is running and can execute commands (and passes the
+    /// configured readiness health command, if any).
+    async fn wait_healthy(&self, container_id: &str, timeout: Duration) -> Result<()> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut last_error = anyhow!("timed out");
         loop {
-            let filters: HashMap<String, Vec<String>> = HashMap::from([(
-                "name".to_string(),
-                vec![format!("^{}$", name)],
-            )]);
-
-            let options = ListContainersOptionsBuilder::new()
-                .all(true)
-                .filters(&filters)
-                .build();
-
-            let containers = self
-                .docker
-                .list_containers(Some(options))
-                .await
-                .context("Failed to list containers")?;
-
-            if containers.is_empty() {
-                return Ok(name);
+            match self.check_healthy(container_id).await {
+                Ok(()) => return Ok(()),
+                Err(e) => last_error = e,
             }
+            if tokio::time::Instant::now() >= deadline {
+                return Err(last_error.context(format!(
+                    "Container not healthy after {}s",
+                    timeout.as_secs()
+                )));
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
 
-            suffix += 1;
-            name = format!("{}-{}", base_name, suffix);
+    async fn check_healthy(&self, container_id: &str) -> Result<()> {
+        let info = self
+            .docker
+            .inspect_container(container_id, None::<InspectContainerOptions>)
+            .await
+            .context("Failed to inspect container")?;
+        let state = info.state.unwrap_or_default();
+        if !state.running.unwrap_or(false) {
+            return Err(anyhow!(
+                "container is not running (exit code {})",
+                state.exit_code.unwrap_or_default()
+            ));
+        }
 
-            if suffix > 100 {
-                return Err(anyhow!("Could not find unique container name after 100 attempts"));
+        let probe = self.run_exec(container_id, vec!["true".to_string()]).await?;
+        if probe.exit_code != 0 {
+            return Err(anyhow!("exec probe exited with {}", probe.exit_code));
+        }
+
+        if let Some(ref cmd) = self.config.readiness.health_command {
+            let out = self
+                .run_exec(
+                    container_id,
+                    vec!["/bin/bash".to_string(), "-lc".to_string(), cmd.clone()],
+                )
+                .await?;
+            if out.exit_code != 0 {
+                return Err(anyhow!("health command exited with {}", out.exit_code));
             }
         }
+        Ok(())
     }
 
-    /// Check if a container exists.
-    async fn container_exists(&self, container_id: &str) -> Result<bool> {
+    /// Stop and remove a container; a missing container is not an error.
+    pub(crate) async fn retire_container(&self, target: &str) -> Result<()> {
         match self
             .docker
-            .inspect_container(container_id, None::<InspectContainerOptions>)
+            .stop_container(target, Some(StopContainerOptionsBuilder::new().t(10).build()))
+            .await
+        {
+            Ok(_)
+            | Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 304 | 404,
+                ..
+            }) => {}
+            Err(e) => warn!("Failed to stop container {}: {}", target, e),
+        }
+
+        let rm_opts = RemoveContainerOptionsBuilder::new()
+            .force(true)
+            .v(true)
+            .build();
+        match self.docker.remove_container(target, Some(rm_opts)).await {
+            Ok(_) => {
+                info!("Retired container {}", target);
+                Ok(())
+            }
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => Ok(()),
+            Err(e) => Err(e).with_context(|| format!("Failed to remove container {}", target)),
+        }
+    }
+
+    /// Create and start a throwaway sandbox for `github_user` (`[ephemeral]`): the default
+    /// image with an in-memory `/workspace`, not recorded in state. Returns its container ID.
+    pub async fn create_ephemeral_container(&self, github_user: &str) -> Result<String> {
+        ha::ensure_active()?;
+        let _quota = self.check_quota(github_user, None).await?;
+        // Queued under the configured project, which is what the connection knows.
+        let workspace = format!("{github_user}/{}", self.config.ephemeral.project);
+        let _slot = admission::acquire(&self.config.admission, &workspace).await?;
+
+        let project = ephemeral::sandbox_project(&self.config.ephemeral.project);
+        let container_name = self.config.container_name(github_user, &project);
+        info!("Creating ephemeral container {} for {}", container_name, github_user);
+
+        let mut labels: HashMap<String, String> = HashMap::from([
+            (ephemeral::EPHEMERAL_LABEL.to_string(), "true".to_string()),
+            ("agentman.github_user".to_string(), github_user.to_string()),
+            ("agentman.project".to_string(), project.clone()),
+        ]);
+        let profile = self.security_profile(github_user).await?;
+        if let Some(profile) = profile {
+            labels.insert(PROFILE_LABEL.to_string(), profile.name.clone());
+        }
+        let extra_binds = proxy_ca::bind(&self.config.proxy_ca).into_iter().collect();
+        let resources = WorkspaceResources::default();
+        let host_config =
+            self.build_host_config(github_user, profile, &resources, None, extra_binds).await?;
+        let image = self.user_config(github_user).await?.docker_image.clone();
+        let config = ContainerCreateBody {
+            image: Some(image.clone()),
+            hostname: Some(hostname_for(&container_name)),
+            env: Some(self.build_env(github_user, &project, &container_name)),
+            labels: Some(labels),
+            host_config: Some(host_config),
+            working_dir: Some("/workspace".to_string()),
+            tty: Some(true),
+            open_stdin: Some(true),
+            ..Default::default()
+        };
+        let options = CreateContainerOptionsBuilder::new()
+            .name(&container_name)
+            .build();
+        let response = match self
+            .docker
+            .create_container(Some(options.clone()), config.clone())
+            .await
+        {
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => {
+                self.pull_image(&image).await?;
+                self.docker.create_container(Some(options), config).await
+            }
+            other => other,
+        }
+        .with_context(|| format!("Failed to create container {}", container_name))?;
+        let container_id = response.id;
+
+        if let Err(e) = helper::install(&self.docker, &self.config.helper, &container_id).await {
+            warn!("{}: {:#}", container_name, e);
+        }
+        if let Err(e) = self
+            .docker
+            .start_container(&container_id, None::<StartContainerOptions>)
+            .await
+        {
+            let _ = self.remove_ephemeral_container(&container_id).await;
+            return Err(e).with_context(|| format!("Failed to start container {}", container_name));
+        }
+        info!("Started ephemeral container {}", container_name);
+
+        if self.config.proxy_ca.enabled
+            && let Err(e) = proxy_ca::install(self, &container_id).await
+        {
+            warn!("Failed to install proxy CA in {}: {:#}", container_name, e);
+        }
+        self.configure_git(github_user, &project, &container_id).await;
+        Ok(container_id)
+    }
+
+    /// Remove a throwaway sandbox and its in-memory workspace.
+    pub async fn remove_ephemeral_container(&self, container_id: &str) -> Result<()> {
+        let rm_opts = RemoveContainerOptionsBuilder::new()
+            .force(true)
+            .v(true)
+            .build();
+        match self.docker.remove_container(container_id, Some(rm_opts)).await {
+            Ok(_)
+            | Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => {
+                info!("Removed ephemeral container {}", container_id);
+                Ok(())
+            }
+            Err(e) => {
+                Err(e).with_context(|| format!("Failed to remove container {}", container_id))
+            }
+        }
+    }
+
+    /// Remove throwaway sandboxes left behind by a gateway that didn't shut down cleanly (their
+    /// connections are gone).
+    pub async fn remove_ephemeral_containers(&self) {
+        let filters: HashMap<String, Vec<String>> = HashMap::from([(
+            "label".to_string(),
+            vec![format!("{}=true", ephemeral::EPHEMERAL_LABEL)],
+        )]);
+        let options = ListContainersOptionsBuilder::new()
+            .all(true)
+            .filters(&filters)
+            .build();
+        let containers = match self.docker.list_containers(Some(options)).await {
+            Ok(containers) => containers,
+            Err(e) => {
+                warn!("Failed to list ephemeral containers: {}", e);
+                return;
+            }
+        };
+        for id in containers.into_iter().filter_map(|c| c.id) {
+            if let Err(e) = self.remove_ephemeral_container(&id).await {
+                warn!("{:#}", e);
+            }
+        }
+    }
+
+    /// Refuse to start a sandbox that would put `github_user` over their `[quotas]`.
+    /// `container_id` is the container about to be started, `None` for a new one. Keep the
+    /// returned guard until the sandbox has started: other starts for the user wait for it, so
+    /// they count this one.
+    async fn check_quota(
+        &self,
+        github_user: &str,
+        container_id: Option<&str>,
+    ) -> Result<Option<tokio::sync::OwnedMutexGuard<()>>> {
+        let Some(quota) = self.config.quotas.for_user(github_user) else {
+            return Ok(None);
+        };
+        let guard = self.quota_locks.lock(github_user).await;
+        let adding = match container_id {
+            Some(container_id) => {
+                let info = self
+                    .docker
+                    .inspect_container(container_id, None::<InspectContainerOptions>)
+                    .await
+                    .context("Failed to inspect container")?;
+                if info.state.as_ref().and_then(|s| s.running).unwrap_or(false) {
+                    return Ok(None);
+                }
+                QuotaUsage::of(&info.host_config.unwrap_or_default())
+            }
+            None => {
+                let config = self.user_config(github_user).await?;
+                let profiles = &self.config.security_profiles;
+                let security = match security_profiles::resolve(profiles, github_user).await? {
+                    Some(profile) => profile.apply(&config.container_security),
+                    None => config.container_security.clone(),
+                };
+                QuotaUsage {
+                    containers: 1,
+                    memory: security
+                        .memory_limit
+                        .as_deref()
+                        .map(parse_memory_limit)
+                        .transpose()?
+                        .unwrap_or(0),
+                    nano_cpus: security.cpu_limit.map_or(0, |cpu| (cpu * 1_000_000_000.0) as i64),
+                }
+            }
+        };
+        let used = self.quota_usage(github_user).await?;
+        if let Some(reason) = quota_exceeded(&quota, used, adding) {
+            crate::metrics::inc(
+                "agentman_quota_refusals_total",
+                "Sandboxes not started because their user was at a [quotas] limit.",
+                &[],
+            );
+            info!("Not starting a sandbox for {}: {}", github_user, reason);
+            return Err(QuotaExceeded(reason).into());
+        }
+        Ok(Some(guard))
+    }
+
+    /// What `github_user`'s running sandboxes (ephemeral ones included) reserve.
+    pub async fn quota_usage(&self, github_user: &str) -> Result<QuotaUsage> {
+        let filters: HashMap<String, Vec<String>> = HashMap::from([
+            ("label".to_string(), vec![format!("agentman.github_user={github_user}")]),
+            ("status".to_string(), vec!["running".to_string()]),
+        ]);
+        let options = ListContainersOptionsBuilder::new().filters(&filters).build();
+        let containers = self
+            .docker
+            .list_containers(Some(options))
+            .await
+            .context("Failed to list containers")?;
+
+        let mut usage = QuotaUsage::default();
+        for id in containers.into_iter().filter_map(|c| c.id) {
+            // Gone since it was listed: it no longer counts.
+            let Ok(info) = self
+                .docker
+                .inspect_container(&id, None::<InspectContainerOptions>)
+                .await
+            else {
+                continue;
+            };
+            usage.add(QuotaUsage::of(&info.host_config.unwrap_or_default()));
+        }
+        Ok(usage)
+    }
+
+    /// The user's security profile, with the network it needs created.
+    async fn security_profile(&self, github_user: &str) -> Result<Option<&SecurityProfile>> {
+        let profiles = &self.config.security_profiles;
+        let profile = security_profiles::resolve(profiles, github_user).await?;
+        if profile.is_some_and(|p| !p.egress) {
+            self.ensure_no_egress_network().await?;
+        }
+        Ok(profile)
+    }
+
+    /// Build the HostConfig with security settings and mounts, with `profile`'s and the
+    /// workspace's (`resources`) overrides of `[container_security]`. Without a workspace
+    /// directory, `/workspace` is a tmpfs of `[ephemeral] workspace_size`.
+    async fn build_host_config(
+        &self,
+        github_user: &str,
+        profile: Option<&SecurityProfile>,
+        resources: &WorkspaceResources,
+        workspace_path: Option<&Path>,
+        extra_binds: Vec<String>,
+    ) -> Result<HostConfig> {
+        let config = self.user_config(github_user).await?;
+        let security = &match profile {
+            Some(profile) => profile.apply(&config.container_security),
+            None => config.container_security.clone(),
+        };
+
+        // Bind mount the workspace, plus any configured read-only host files.
+        let mut binds: Vec<String> = workspace_path
+            .map(|path| format!("{}:/workspace", path.display()))
+            .into_iter()
+            .collect();
+        binds.extend(extra_binds);
+        for mount in &self.config.readonly_mounts {
+            if !mount.host_path.exists() {
+                warn!(
+                    "Skipping read-only mount {}: host path does not exist",
+                    mount.host_path.display()
+                );
+                continue;
+            }
+            binds.push(format!(
+                "{}:{}:ro",
+                mount.host_path.display(),
+                mount.container_path
+            ));
+        }
+
+        let mut host_config = HostConfig {
+            binds: Some(binds),
+
+            // Add host.docker.internal for reverse port forwarding
+            extra_hosts: Some(vec!["host.docker.internal:host-gateway".to_string()]),
+
+            // Never run privileged
+            privileged: Some(false),
+
+            // No access to Docker socket
+            // (binds is already set, so docker.sock won't be mounted)
+
+            // Network settings
+            network_mode: Some("bridge".to_string()),
+
+            // Init process for proper signal handling
+            init: Some(true),
+
+            ..Default::default()
+        };
+
+        // Apply security settings
+        if security.cap_drop_all {
+            host_config.cap_drop = Some(vec!["ALL".to_string()]);
+            if !security.cap_add.is_empty() {
+                host_config.cap_add = Some(security.cap_add.clone());
+            }
+        }
+
+        // Opt-in host devices for this user (cgroup device rules instead of --privileged).
+        for device in self.config.devices.iter().filter(|d| d.allows(github_user)) {
+            if !device.host_path.exists() {
+                warn!(
+                    "Skipping device {}: not present on this host",
+                    device.host_path.display()
+                );
+                continue;
+            }
+            host_config.devices.get_or_insert_with(Vec::new).push(DeviceMapping {
+                path_on_host: Some(device.host_path.display().to_string()),
+                path_in_container: Some(device.container_path()),
+                cgroup_permissions: Some(device.permissions.clone()),
+            });
+            if !device.cgroup_rules.is_empty() {
+                host_config
+                    .device_cgroup_rules
+                    .get_or_insert_with(Vec::new)
+                    .extend(device.cgroup_rules.iter().cloned());
+            }
+            if !device.cap_add.is_empty() {
+                let caps = host_config.cap_add.get_or_insert_with(Vec::new);
+                for cap in &device.cap_add {
+                    if !caps.contains(cap) {
+                        caps.push(cap.clone());
+                    }
+                }
+            }
+        }
+
+        if security.no_new_privileges {
+            host_config.security_opt = Some(vec!["no-new-privileges:true".to_string()]);
+        }
+
+        if security.readonly_rootfs {
+            host_config.readonly_rootfs = Some(true);
+            // Add tmpfs for common writable paths
+            host_config.tmpfs = Some(HashMap::from([
+                ("/tmp".to_string(), "rw,noexec,nosuid,size=1g".to_string()),
+                ("/run".to_string(), "rw,noexec,nosuid,size=64m".to_string()),
+                ("/var/tmp".to_string(), "rw,noexec,nosuid,size=256m".to_string()),
+            ]));
+        }
+
+        if workspace_path.is_none() {
+            host_config.tmpfs.get_or_insert_with(HashMap::new).insert(
+                "/workspace".to_string(),
+                format!("rw,exec,nosuid,size={},mode=1777", self.config.ephemeral.workspace_size),
+            );
+        }
+
+        for (path, size) in &security.tmpfs {
+            host_config
+                .tmpfs
+                .get_or_insert_with(HashMap::new)
+                .insert(path.clone(), format!("rw,exec,nosuid,size={size},mode=1777"));
+        }
+
+        // Workspace overrides are held to the gateway's maximums, which may have been lowered
+        // since they were picked.
+        let shm_size = resources.shm_size.as_ref().or(security.shm_size.as_ref());
+        if let Some(shm) = shm_size {
+            let max = parse_memory_limit(&security.max_shm_size)?;
+            host_config.shm_size = Some(parse_memory_limit(shm)?.min(max));
+        }
+        let ulimits = [
+            ("nofile", resources.nofile.map(|n| n.min(security.max_nofile)).or(security.nofile)),
+            ("nproc", resources.nproc.map(|n| n.min(security.max_nproc)).or(security.nproc)),
+        ];
+        for (name, limit) in ulimits {
+            if let Some(limit) = limit {
+                host_config.ulimits.get_or_insert_with(Vec::new).push(ResourcesUlimits {
+                    name: Some(name.to_string()),
+                    soft: Some(limit as i64),
+                    hard: Some(limit as i64),
+                });
+            }
+        }
+
+        if let Some(ref memory) = security.memory_limit {
+            // Parse memory limit (e.g., "4g" -> bytes)
+            host_config.memory = Some(parse_memory_limit(memory)?);
+        }
+
+        if let Some(cpu) = security.cpu_limit {
+            // CPU quota in 100ns units (1 CPU = 100000)
+            host_config.nano_cpus = Some((cpu * 1_000_000_000.0) as i64);
+        }
+
+        if let Some(profile) = profile {
+            host_config.pids_limit = profile.pids_limit;
+            if !profile.egress {
+                host_config.network_mode = Some(NO_EGRESS_NETWORK.to_string());
+            }
+        }
+
+        // Use default seccomp profile (don't set to unconfined)
+        // The default Docker seccomp profile is already applied unless explicitly disabled
+
+        Ok(host_config)
+    }
+
+    /// Build environment variables for the container.
+    fn build_env(&self, github_user: &str, project: &str, container_name: &str) -> Vec<String> {
+        let mut env = vec![
+            format!("GITHUB_USERNAME={}", github_user),
+            format!("AGENTMAN_PROJECT={}", project),
+            format!("AGENTMAN_CONTAINER_ID={}", container_name),
+            "TERM=xterm-256color".to_string(),
+        ];
+        for mount in &self.config.readonly_mounts {
+            if !mount.host_path.exists() {
+                continue;
+            }
+            for var in &mount.env {
+                env.push(format!("{}={}", var, mount.container_path));
+            }
+        }
+        env.extend(proxy::container_env(&self.config.proxy));
+        env.extend(proxy_ca::container_env(&self.config.proxy_ca));
+        env.extend(user_notify::container_env(&self.config.user_notify));
+        env.extend(mesh::container_env(&self.config.mesh));
+        env
+    }
+
+    /// Pick the name for a new workspace container: the stable name from
+    /// `container_name_template`, after removing a leftover container of the same workspace
+    /// that state no longer tracks. If another workspace or tool already owns the name (e.g.
+    /// `a-b` + `c` vs `a` + `b-c`), a numeric suffix is added instead.
+    async fn claim_stable_name(&self, github_user: &str, project: &str) -> Result<String> {
+        let name = self.config.container_name(github_user, project);
+        let labels = match self
+            .docker
+            .inspect_container(&name, None::<InspectContainerOptions>)
+            .await
+        {
+            Ok(info) => info.config.and_then(|c| c.labels).unwrap_or_default(),
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => return Ok(name),
+            Err(e) => return Err(e).context("Failed to inspect container"),
+        };
+
+        let same_workspace = labels.get("agentman.github_user").map(String::as_str) == Some(github_user)
+            && labels.get("agentman.project").map(String::as_str) == Some(project);
+        if same_workspace {
+            warn!("Removing untracked container {} of {}/{}", name, github_user, project);
+            self.retire_container(&name).await?;
+            return Ok(name);
+        }
+
+        warn!(
+            "Container name {} is taken by another container; using a suffixed name for {}/{}",
+            name, github_user, project
+        );
+        self.ensure_unique_name(&name).await
+    }
+
+    /// Rename a workspace's container and record the new name in state.
+    async fn rename_workspace_container(
+        &self,
+        github_user: &str,
+        project: &str,
+        container_id: &str,
+        new_name: &str,
+    ) -> Result<()> {
+        self.docker
+            .rename_container(
+                container_id,
+                RenameContainerOptionsBuilder::new().name(new_name).build(),
+            )
+            .await
+            .with_context(|| format!("Failed to rename container to {}", new_name))?;
+        self.state
+            .update_workspace(github_user, project, |ws| {
+                ws.container_name = new_name.to_string();
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Migrate containers created with an older naming scheme (e.g. the date-suffixed
+    /// `project-user-YYYYMMDD[-n]`) to their stable names. Running containers keep their old
+    /// hostname until they are next recreated. Failures are logged and skipped.
+    pub async fn reconcile_container_names(&self) {
+        for github_user in self.state.list_github_users().await {
+            for ws in self.state.list_workspaces(&github_user).await {
+                let stable = self.config.container_name(&ws.github_user, &ws.project);
+                if ws.container_name == stable {
+                    continue;
+                }
+                let Some(ref id) = ws.container_id else {
+                    continue;
+                };
+                match self.container_exists(id).await {
+                    Ok(true) => {}
+                    // Gone: the next connection creates it under the stable name.
+                    Ok(false) => continue,
+                    Err(e) => {
+                        warn!("Skipping rename of {}: {:#}", ws.container_name, e);
+                        continue;
+                    }
+                }
+                match self
+                    .rename_workspace_container(&ws.github_user, &ws.project, id, &stable)
+                    .await
+                {
+                    Ok(()) => info!("Renamed container {} to {}", ws.container_name, stable),
+                    Err(e) => warn!(
+                        "Could not rename container {} to {}: {:#}",
+                        ws.container_name, stable, e
+                    ),
+                }
+            }
+        }
+    }
+
+    /// Ensure the container name is unique by adding a suffix if needed.
+    async fn ensure_unique_name(&self, base_name: &str) -> Result<String> {
+        let mut name = base_name.to_string();
+        let mut suffix = 0;
+
+        loop {
+            let filters: HashMap<String, Vec<String>> = HashMap::from([(
+                "name".to_string(),
+                vec![format!("^{}$", name)],
+            )]);
+
+            let options = ListContainersOptionsBuilder::new()
+                .all(true)
+                .filters(&filters)
+                .build();
+
+            let containers = self
+                .docker
+                .list_containers(Some(options))
+                .await
+                .context("Failed to list containers")?;
+
+            if containers.is_empty() {
+                return Ok(name);
+            }
+
+            suffix += 1;
+            name = format!("{}-{}", base_name, suffix);
+
+            if suffix > 100 {
+                return Err(anyhow!("Could not find unique container name after 100 attempts"));
+            }
+        }
+    }
+
+    /// Check if a container exists.
+    async fn container_exists(&self, container_id: &str) -> Result<bool> {
+        match self
+            .docker
+            .inspect_container(container_id, None::<InspectContainerOptions>)
             .await
         {
             Ok(_) => Ok(true),
@@ -444,8 +1516,102 @@ impl ContainerManager {
         }
     }
 
+    /// The image a stopped container should switch to, if the workspace's `agentman image`
+    /// choice changed since it was created. Running containers are left alone until they stop,
+    /// and a new `docker_image` default doesn't replace existing containers.
+    async fn pending_image_switch(
+        &self,
+        workspace: &WorkspaceInfo,
+        container_id: &str,
+    ) -> Result<Option<String>> {
+        let info = self
+            .docker
+            .inspect_container(container_id, None::<InspectContainerOptions>)
+            .await
+            .context("Failed to inspect container")?;
+        let running = info.state.as_ref().and_then(|s| s.running).unwrap_or(false);
+        let created_with = info
+            .config
+            .as_ref()
+            .and_then(|c| c.labels.as_ref())
+            .and_then(|labels| labels.get(IMAGE_CHOICE_LABEL));
+        let changed = created_with != workspace.image.as_ref();
+        let github_user = &workspace.github_user;
+        if running || !changed {
+            return Ok(None);
+        }
+        Ok(Some(self.effective_image(github_user, Some(workspace)).await))
+    }
+
+    /// Record `choice` as the workspace's image, as picked when connecting
+    /// (`ssh project.<name>@gateway`). A workspace without a state entry gets one, so its first
+    /// container is created from the image. Returns whether the workspace's container is
+    /// running another image, which it keeps until it stops.
+    pub async fn select_image(
+        &self,
+        github_user: &str,
+        project: &str,
+        choice: Option<String>,
+    ) -> Result<bool> {
+        let Some(workspace) = self.state.get_workspace(github_user, project).await else {
+            self.state
+                .set_workspace(WorkspaceInfo {
+                    github_user: github_user.to_string(),
+                    project: project.to_string(),
+                    container_name: self.config.container_name(github_user, project),
+                    container_id: None,
+                    created_at: Utc::now(),
+                    host_workspace_path: self.config.workspace_path(github_user, project),
+                    linked_projects: Vec::new(),
+                    init_command: None,
+                    init_status: None,
+                    image: choice,
+                    tls_port: None,
+                    watch_ownership: false,
+                    clone: None,
+                    resources: WorkspaceResources::default(),
+                })
+                .await?;
+            return Ok(false);
+        };
+        if workspace.image != choice {
+            info!(
+                "{}/{} picked image {}",
+                github_user,
+                project,
+                choice.as_deref().unwrap_or(&self.config.docker_image)
+            );
+            self.state
+                .update_workspace(github_user, project, |ws| ws.image = choice.clone())
+                .await?;
+        }
+        let Some(container_id) = workspace.container_id else {
+            return Ok(false);
+        };
+        let info = match self
+            .docker
+            .inspect_container(&container_id, None::<InspectContainerOptions>)
+            .await
+        {
+            Ok(info) => info,
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => return Ok(false),
+            Err(e) => return Err(e).context("Failed to inspect container"),
+        };
+        let running = info.state.as_ref().and_then(|s| s.running).unwrap_or(false);
+        let created_with = info
+            .config
+            .as_ref()
+            .and_then(|c| c.labels.as_ref())
+            .and_then(|labels| labels.get(IMAGE_CHOICE_LABEL));
+        Ok(running && created_with != choice.as_ref())
+    }
+
     /// Ensure a container is running.
-    async fn ensure_running(&self, container_id: &str) -> Result<()> {
+    ///
+    /// Returns `true` if the container had to be started.
+    async fn ensure_running(&self, container_id: &str) -> Result<bool> {
         let info = self
             .docker
             .inspect_container(container_id, None::<InspectContainerOptions>)
@@ -481,7 +1647,232 @@ impl ContainerManager {
                 .context("Failed to start container")?;
         }
 
-        Ok(())
+        Ok(!running)
+    }
+
+    /// Image the workspace's container should run: the per-workspace choice while it is still
+    /// allowed (or is the workspace's own `agentman build` image), else the default for
+    /// `github_user` (their `users_dir` override or `docker_image`).
+    pub async fn effective_image(
+        &self,
+        github_user: &str,
+        workspace: Option<&WorkspaceInfo>,
+    ) -> String {
+        let config = user_config::resolve_or_global(&self.config, github_user).await;
+        let own = |image: &str| {
+            workspace.is_some_and(|ws| {
+                build::is_own_image(&config.build, image, &ws.github_user, &ws.project)
+            })
+        };
+        match workspace.and_then(|ws| ws.image.as_deref()) {
+            Some(image) if config.image_allowed(image) || own(image) => image.to_string(),
+            Some(image) => {
+                warn!("Image {} is no longer allowed; using {}", image, config.docker_image);
+                config.docker_image.clone()
+            }
+            None => config.docker_image.clone(),
+        }
+    }
+
+    /// `github_user`'s config, with their `users_dir` overrides applied.
+    pub async fn user_config(&self, github_user: &str) -> Result<Arc<GatewayConfig>> {
+        user_config::resolve(&self.config, github_user).await
+    }
+
+    /// Create `path` (a workspace or cache directory) and make it writable for the user that
+    /// containers from `image` run as, per `[workspace_permissions]`.
+    async fn prepare_workspace_dir(&self, path: &Path, image: &str) -> Result<()> {
+        let owner = self.workspace_owner(image).await;
+        ensure_workspace_writable(path, owner, self.config.workspace_permissions.chown_mode).await
+    }
+
+    /// Run the workspace's ownership watcher if it opted in (and the gateway allows it); fails
+    /// if it can't run here.
+    pub(crate) async fn ensure_ownership_watcher(
+        &self,
+        github_user: &str,
+        project: &str,
+    ) -> Result<()> {
+        if !self.config.workspace_permissions.watch_ownership {
+            return Ok(());
+        }
+        let Some(ws) = self.state.get_workspace(github_user, project).await else {
+            return Ok(());
+        };
+        if !ws.watch_ownership {
+            return Ok(());
+        }
+        let owner = self.workspace_owner(&self.effective_image(github_user, Some(&ws)).await).await;
+        let (root_uid, _) = self.userns_offset().await;
+        ownership::ensure_watcher(github_user, project, &ws.host_workspace_path, root_uid, owner)
+    }
+
+    /// Host IDs that containers from `image` run as: `container_user`, else the image's `USER`
+    /// (1000:1000 when it can't be determined), shifted by the `userns-remap` offset.
+    pub(crate) async fn workspace_owner(&self, image: &str) -> WorkspaceOwner {
+        let (uid, gid) = match self.config.workspace_permissions.container_ids() {
+            Some(ids) => ids,
+            None => match self.image_user_ids(image).await {
+                Ok(ids) => ids,
+                Err(e) => {
+                    warn!(
+                        "Assuming container user {}:{} for {}: {:#}",
+                        DEFAULT_CONTAINER_UID, DEFAULT_CONTAINER_GID, image, e
+                    );
+                    (DEFAULT_CONTAINER_UID, DEFAULT_CONTAINER_GID)
+                }
+            },
+        };
+        let (uid_offset, gid_offset) = self.userns_offset().await;
+        WorkspaceOwner {
+            uid: uid.saturating_add(uid_offset),
+            gid: gid.saturating_add(gid_offset),
+        }
+    }
+
+    /// UID/GID of an image's `USER`; names are looked up in the image's own /etc/passwd and
+    /// /etc/group. Cached per image ID.
+    async fn image_user_ids(&self, image: &str) -> Result<(u32, u32)> {
+        let info = self
+            .docker
+            .inspect_image(image)
+            .await
+            .with_context(|| format!("Failed to inspect image {image}"))?;
+        let id = info.id.unwrap_or_else(|| image.to_string());
+        if let Some(ids) = self.image_users.lock().unwrap().get(&id) {
+            return Ok(*ids);
+        }
+
+        let spec = info.config.and_then(|c| c.user).unwrap_or_default();
+        let (user, group) = match spec.split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (spec.as_str(), None),
+        };
+        let passwd = if user.parse::<u32>().is_err() && !user.is_empty() || group.is_none() {
+            self.read_image_file(&id, "/etc/passwd").await.ok()
+        } else {
+            None
+        };
+        let groups = match group {
+            Some(group) if group.parse::<u32>().is_err() => {
+                Some(self.read_image_file(&id, "/etc/group").await?)
+            }
+            _ => None,
+        };
+        let ids = resolve_user(&spec, passwd.as_deref(), groups.as_deref())?;
+        info!("Container user of {} ({:?}) is {}:{}", image, spec, ids.0, ids.1);
+        self.image_users.lock().unwrap().insert(id, ids);
+        Ok(ids)
+    }
+
+    /// Read a file from an image through a throwaway (never started) container.
+    async fn read_image_file(&self, image: &str, path: &str) -> Result<String> {
+        let config = ContainerCreateBody {
+            image: Some(image.to_string()),
+            cmd: Some(vec!["true".to_string()]),
+            network_disabled: Some(true),
+            ..Default::default()
+        };
+        let probe = self
+            .docker
+            .create_container(None::<bollard::query_parameters::CreateContainerOptions>, config)
+            .await
+            .with_context(|| format!("Failed to create a container from {image}"))?
+            .id;
+        let options = DownloadFromContainerOptionsBuilder::new().path(path).build();
+        let archive: Result<Vec<u8>, _> = self
+            .docker
+            .download_from_container(&probe, Some(options))
+            .fold(Ok(Vec::new()), |acc, chunk| async move {
+                let mut acc = acc?;
+                acc.extend_from_slice(&chunk?);
+                Ok::<_, bollard::errors::Error>(acc)
+            })
+            .await;
+        let remove = RemoveContainerOptionsBuilder::new().force(true).build();
+        if let Err(e) = self.docker.remove_container(&probe, Some(remove)).await {
+            warn!("Failed to remove probe container {}: {}", probe, e);
+        }
+        let archive = archive.with_context(|| format!("Failed to read {path} from {image}"))?;
+        let contents = tar::first_file(&archive)
+            .ok_or_else(|| anyhow!("{path} in {image} is not a regular file"))?;
+        Ok(String::from_utf8_lossy(contents).into_owned())
+    }
+
+    /// Host UID/GID offsets when the daemon remaps user namespaces (`userns-remap`), else 0.
+    async fn userns_offset(&self) -> (u32, u32) {
+        *self
+            .userns_offset
+            .get_or_init(|| async {
+                let config = &self.config.workspace_permissions;
+                if let Some(offset) = config.userns_offset {
+                    return (offset, offset);
+                }
+                let remapped = match self.docker.info().await {
+                    Ok(info) => info
+                        .security_options
+                        .unwrap_or_default()
+                        .iter()
+                        .any(|option| option.contains("name=userns")),
+                    Err(e) => {
+                        warn!("Failed to query Docker for userns-remap: {}", e);
+                        false
+                    }
+                };
+                if !remapped {
+                    return (0, 0);
+                }
+                let start = |path: &str| {
+                    std::fs::read_to_string(path)
+                        .ok()
+                        .and_then(|contents| subid_start(&contents, &config.userns_remap_user))
+                };
+                match (start("/etc/subuid"), start("/etc/subgid")) {
+                    (Some(uid), Some(gid)) => {
+                        info!("Docker remaps user namespaces; host IDs start at {}:{}", uid, gid);
+                        (uid, gid)
+                    }
+                    _ => {
+                        warn!(
+                            "Docker remaps user namespaces, but /etc/subuid or /etc/subgid has no range for {}; set workspace_permissions.userns_offset",
+                            config.userns_remap_user
+                        );
+                        (0, 0)
+                    }
+                }
+            })
+            .await
+    }
+
+    /// Effective init command for a workspace: the per-workspace override, else the config default.
+    pub fn effective_init_command(&self, workspace: Option<&WorkspaceInfo>) -> Option<String> {
+        workspace
+            .and_then(|ws| ws.init_command.clone())
+            .or_else(|| self.config.init.command.clone())
+            .filter(|c| !c.trim().is_empty())
+    }
+
+    /// Start the workspace init command in the background, if one is configured.
+    ///
+    /// Output is written to `/workspace/.agentman/init.log` and the outcome is recorded in
+    /// the workspace's `init_status`.
+    pub async fn spawn_init_command(&self, github_user: &str, project: &str, container_id: &str) {
+        let workspace = self.state.get_workspace(github_user, project).await;
+        let Some(command) = self.effective_init_command(workspace.as_ref()) else {
+            return;
+        };
+
+        info!("Running init command for {}/{}", github_user, project);
+        let run = InitRun {
+            docker: self.docker.clone(),
+            state: self.state.clone(),
+            github_user: github_user.to_string(),
+            project: project.to_string(),
+            container_id: container_id.to_string(),
+            workspace_path: self.config.workspace_path(github_user, project),
+            timeout: Duration::from_secs(self.config.init.timeout_secs.max(1)),
+        };
+        tokio::spawn(run.run(command));
     }
 
     /// List all workspaces for a given GitHub user.
@@ -494,10 +1885,8 @@ impl ContainerManager {
         self.state.get_workspace(github_user, project).await
     }
 
-    /// Get the container's IP address on the bridge network.
-    ///
-    /// Not currently used in the gateway, but kept for future port-forwarding / networking features.
-    #[allow(dead_code)]
+    /// Get the container's IP address: on the default bridge network, else on any network it
+    /// is attached to.
     pub async fn get_container_ip(&self, container_id: &str) -> Result<String> {
         let info = self
             .docker
@@ -518,11 +1907,274 @@ impl ContainerManager {
                     .and_then(|bridge| bridge.ip_address.as_ref())
                     .filter(|ip| !ip.is_empty())
             })
+            .or_else(|| {
+                info.network_settings
+                    .as_ref()
+                    .and_then(|ns| ns.networks.as_ref())
+                    .and_then(|nets| {
+                        nets.values()
+                            .filter_map(|net| net.ip_address.as_ref())
+                            .find(|ip| !ip.is_empty())
+                    })
+            })
             .ok_or_else(|| anyhow!("Container has no IP address"))?;
 
         Ok(ip.clone())
     }
 
+    /// Join `container_id` to its own project network (if one exists because other projects
+    /// link to it) and to the networks of every linked project.
+    async fn apply_links(
+        &self,
+        github_user: &str,
+        project: &str,
+        container_id: &str,
+        linked_projects: &[String],
+    ) -> Result<()> {
+        // Project networks are regular bridges; joining one would give the container egress.
+        if !self.has_egress(container_id).await? {
+            if !linked_projects.is_empty() {
+                warn!(
+                    "Not applying links of {}/{}: its security profile has no egress",
+                    github_user, project
+                );
+            }
+            return Ok(());
+        }
+        let own_network = project_network_name(github_user, project);
+        if self.network_exists(&own_network).await? {
+            self.connect_to_network(&own_network, container_id, project)
+                .await?;
+        }
+
+        for other in linked_projects {
+            let network = self.ensure_project_network(github_user, other).await?;
+            self.connect_to_network(&network, container_id, project)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Attach the current project's container to `other`'s project network.
+    ///
+    /// The other project's container (if it exists) is attached as well, under the alias
+    /// `<other>`, so it is reachable by name from the current container.
+    pub async fn link_project(&self, github_user: &str, project: &str, other: &str) -> Result<String> {
+        if other == project {
+            return Err(anyhow!("Cannot link a project to itself"));
+        }
+
+        let ws = self
+            .state
+            .get_workspace(github_user, project)
+            .await
+            .ok_or_else(|| anyhow!("No sandbox found for {}/{}", github_user, project))?;
+        let other_ws = self
+            .state
+            .get_workspace(github_user, other)
+            .await
+            .ok_or_else(|| anyhow!("No sandbox found for {}/{}", github_user, other))?;
+
+        for (name, id) in [(project, &ws.container_id), (other, &other_ws.container_id)] {
+            if let Some(id) = id
+                && self.container_exists(id).await?
+                && !self.has_egress(id).await?
+            {
+                return Err(anyhow!(
+                    "{}'s sandbox has no network access (security profile), so it can't be linked",
+                    name
+                ));
+            }
+        }
+
+        let network = self.ensure_project_network(github_user, other).await?;
+
+        if let Some(ref other_id) = other_ws.container_id
+            && self.container_exists(other_id).await?
+        {
+            self.connect_to_network(&network, other_id, other).await?;
+        }
+
+        if let Some(ref id) = ws.container_id
+            && self.container_exists(id).await?
+        {
+            self.connect_to_network(&network, id, project).await?;
+        }
+
+        self.state
+            .update_workspace(github_user, project, |ws| {
+                if !ws.linked_projects.iter().any(|p| p == other) {
+                    ws.linked_projects.push(other.to_string());
+                    ws.linked_projects.sort();
+                }
+            })
+            .await?;
+
+        info!("Linked {}/{} to network {}", github_user, project, network);
+        Ok(network)
+    }
+
+    /// Detach the current project's container from `other`'s project network.
+    ///
+    /// Returns `false` if the project was not linked.
+    pub async fn unlink_project(&self, github_user: &str, project: &str, other: &str) -> Result<bool> {
+        let ws = self
+            .state
+            .get_workspace(github_user, project)
+            .await
+            .ok_or_else(|| anyhow!("No sandbox found for {}/{}", github_user, project))?;
+
+        if !ws.linked_projects.iter().any(|p| p == other) {
+            return Ok(false);
+        }
+
+        let network = project_network_name(github_user, other);
+        if let Some(ref id) = ws.container_id {
+            let req = NetworkDisconnectRequest {
+                container: Some(id.clone()),
+                force: Some(true),
+            };
+            match self.docker.disconnect_network(&network, req).await {
+                Ok(_) => {}
+                // Network or container already gone, or container not attached.
+                Err(bollard::errors::Error::DockerResponseServerError {
+                    status_code: 404 | 403,
+                    ..
+                }) => {}
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to disconnect from network {}", network));
+                }
+            }
+        }
+
+        self.state
+            .update_workspace(github_user, project, |ws| {
+                ws.linked_projects.retain(|p| p != other);
+            })
+            .await?;
+
+        info!("Unlinked {}/{} from network {}", github_user, project, network);
+        Ok(true)
+    }
+
+    async fn network_exists(&self, network: &str) -> Result<bool> {
+        match self
+            .docker
+            .inspect_network(network, None::<InspectNetworkOptions>)
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => Ok(false),
+            Err(e) => Err(e).context("Failed to inspect network"),
+        }
+    }
+
+    /// Create the internal network for containers without egress if it does not exist yet.
+    /// Containers on it can't talk to each other either.
+    async fn ensure_no_egress_network(&self) -> Result<()> {
+        if self.network_exists(NO_EGRESS_NETWORK).await? {
+            return Ok(());
+        }
+        let req = NetworkCreateRequest {
+            name: NO_EGRESS_NETWORK.to_string(),
+            driver: Some("bridge".to_string()),
+            internal: Some(true),
+            options: Some(HashMap::from([(
+                "com.docker.network.bridge.enable_icc".to_string(),
+                "false".to_string(),
+            )])),
+            labels: Some(HashMap::from([(
+                "agentman.managed".to_string(),
+                "true".to_string(),
+            )])),
+            ..Default::default()
+        };
+        match self.docker.create_network(req).await {
+            Ok(_) => info!("Created network {}", NO_EGRESS_NETWORK),
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 409, ..
+            }) => {}
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to create network {}", NO_EGRESS_NETWORK));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the container has a route out, i.e. its security profile allows egress.
+    async fn has_egress(&self, container_id: &str) -> Result<bool> {
+        let info = self
+            .docker
+            .inspect_container(container_id, None::<InspectContainerOptions>)
+            .await
+            .context("Failed to inspect container")?;
+        let network_mode = info.host_config.and_then(|h| h.network_mode);
+        Ok(network_mode.as_deref() != Some(NO_EGRESS_NETWORK))
+    }
+
+    /// Create the project network for (github_user, project) if it does not exist yet.
+    pub(crate) async fn ensure_project_network(&self, github_user: &str, project: &str) -> Result<String> {
+        let name = project_network_name(github_user, project);
+        if self.network_exists(&name).await? {
+            return Ok(name);
+        }
+
+        let labels: HashMap<String, String> = HashMap::from([
+            ("agentman.managed".to_string(), "true".to_string()),
+            ("agentman.github_user".to_string(), github_user.to_string()),
+            ("agentman.project".to_string(), project.to_string()),
+        ]);
+        let req = NetworkCreateRequest {
+            name: name.clone(),
+            driver: Some("bridge".to_string()),
+            labels: Some(labels),
+            ..Default::default()
+        };
+        match self.docker.create_network(req).await {
+            Ok(_) => info!("Created project network {}", name),
+            // Lost a race with a concurrent link; the network exists now.
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 409, ..
+            }) => {}
+            Err(e) => return Err(e).with_context(|| format!("Failed to create network {}", name)),
+        }
+        Ok(name)
+    }
+
+    /// Connect a container to a network under `alias`, skipping it if already attached.
+    pub(crate) async fn connect_to_network(&self, network: &str, container_id: &str, alias: &str) -> Result<()> {
+        let info = self
+            .docker
+            .inspect_container(container_id, None::<InspectContainerOptions>)
+            .await
+            .context("Failed to inspect container")?;
+        let attached = info
+            .network_settings
+            .as_ref()
+            .and_then(|ns| ns.networks.as_ref())
+            .is_some_and(|nets| nets.contains_key(network));
+        if attached {
+            return Ok(());
+        }
+
+        let req = NetworkConnectRequest {
+            container: Some(container_id.to_string()),
+            endpoint_config: Some(EndpointSettings {
+                aliases: Some(vec![alias.to_string()]),
+                ..Default::default()
+            }),
+        };
+        self.docker
+            .connect_network(network, req)
+            .await
+            .with_context(|| format!("Failed to connect container to network {}", network))?;
+        Ok(())
+    }
+
     /// Create an exec instance in the container.
     ///
     /// Returns the exec ID.
@@ -550,7 +2202,109 @@ impl ContainerManager {
             .await
             .context("Failed to create exec")?;
 
-        Ok(response.id)
+        Ok(response.id)
+    }
+
+    /// Run a command inside the container to completion and capture its output.
+    pub async fn run_exec(&self, container_id: &str, cmd: Vec<String>) -> Result<ExecOutput> {
+        self.run_exec_as(container_id, cmd, None).await
+    }
+
+    /// [`Self::run_exec`] as a specific container user (default: the image's user).
+    pub async fn run_exec_as(
+        &self,
+        container_id: &str,
+        cmd: Vec<String>,
+        user: Option<&str>,
+    ) -> Result<ExecOutput> {
+        let exec = self
+            .docker
+            .create_exec(
+                container_id,
+                CreateExecOptions {
+                    cmd: Some(cmd),
+                    attach_stdout: Some(true),
+                    attach_stderr: Some(true),
+                    working_dir: Some("/workspace".to_string()),
+                    user: user.map(str::to_string),
+                    ..Default::default()
+                },
+            )
+            .await
+            .context("Failed to create exec")?;
+
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        if let StartExecResults::Attached { mut output, .. } = self
+            .docker
+            .start_exec(&exec.id, None::<StartExecOptions>)
+            .await
+            .context("Failed to start exec")?
+        {
+            while let Some(chunk) = output.next().await {
+                match chunk.context("Exec output error")? {
+                    LogOutput::StdErr { message } => stderr.extend_from_slice(&message),
+                    other => stdout.extend_from_slice(&other.into_bytes()),
+                }
+            }
+        }
+
+        let exit_code = self.wait_exec_exit_code(&exec.id).await?;
+        Ok(ExecOutput {
+            exit_code,
+            stdout,
+            stderr,
+        })
+    }
+
+    /// Send `signal` to every process in the container tagged with `EXEC_MARKER_ENV=marker`.
+    ///
+    /// Docker has no API to signal an exec, and the exec's PID is only known in the host's
+    /// namespace. The marker is inherited through the environment, so this reaches the whole
+    /// process tree the exec started (short of processes that scrub their environment).
+    pub async fn signal_exec_processes(
+        &self,
+        container_id: &str,
+        marker: &str,
+        signal: &str,
+    ) -> Result<()> {
+        let output = self
+            .run_exec(
+                container_id,
+                vec![
+                    "/bin/sh".to_string(),
+                    "-c".to_string(),
+                    SIGNAL_EXEC_SCRIPT.to_string(),
+                    "agentman-signal".to_string(),
+                    signal.to_string(),
+                    format!("{EXEC_MARKER_ENV}={marker}"),
+                ],
+            )
+            .await?;
+        if output.exit_code != 0 {
+            anyhow::bail!(
+                "Failed to signal exec processes: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(())
+    }
+
+    /// Poll for an exec's exit code (it may briefly report Running=true after its output ends).
+    pub async fn wait_exec_exit_code(&self, exec_id: &str) -> Result<i64> {
+        for _ in 0..80 {
+            let info = self
+                .docker
+                .inspect_exec(exec_id)
+                .await
+                .context("Failed to inspect exec")?;
+            if info.running.unwrap_or(false) {
+                tokio::time::sleep(Duration::from_millis(25)).await;
+                continue;
+            }
+            return Ok(info.exit_code.unwrap_or(-1));
+        }
+        Ok(-1)
     }
 
     /// Start an exec instance and return the multiplexed stream.
@@ -590,15 +2344,50 @@ impl ContainerManager {
         &self.docker
     }
 
+    /// Get a reference to the gateway configuration.
+    pub fn config(&self) -> &GatewayConfig {
+        &self.config
+    }
+
+    /// Get a reference to the state manager.
+    pub fn state(&self) -> &Arc<StateManager> {
+        &self.state
+    }
+
     /// Destroy a workspace:
     /// - Stop/remove any managed container(s) for (github_user, project)
     /// - Optionally delete the persistent workspace directory on the host
     /// - Remove the workspace entry from the gateway state file
+    ///
+    /// Unless it is a dry run, the destroy is journaled (see [`journal`]) until it has
+    /// finished.
     pub async fn destroy_workspace(
         &self,
         github_user: &str,
         project: &str,
         opts: DestroyOptions,
+    ) -> Result<DestroyResult> {
+        if opts.dry_run {
+            return self.destroy_steps(github_user, project, opts).await;
+        }
+        let kind = OperationKind::Destroy {
+            keep_workspace: opts.keep_workspace,
+        };
+        self.state
+            .begin_operation(github_user, project, kind, journal::STEP_STARTING)
+            .await?;
+        let result = self.destroy_steps(github_user, project, opts).await;
+        journal::end(&self.state, github_user, project, &result).await;
+        result
+    }
+
+    /// The steps of [`Self::destroy_workspace`], under an operation already journaled (for
+    /// anything but a dry run).
+    pub(crate) async fn destroy_steps(
+        &self,
+        github_user: &str,
+        project: &str,
+        opts: DestroyOptions,
     ) -> Result<DestroyResult> {
         let mut warnings = Vec::new();
 
@@ -609,14 +2398,43 @@ impl ContainerManager {
         // - state-mapped container id/name (works even for older containers without labels)
         // - any currently running/stopped containers labeled as managed for this workspace
         let mut targets: Vec<String> = Vec::new();
-        if let Some(ws) = self.state.get_workspace(github_user, project).await {
-            if let Some(id) = ws.container_id {
-                targets.push(id);
+        let recorded = self.state.get_workspace(github_user, project).await;
+        if let Some(ws) = &recorded {
+            if let Some(id) = &ws.container_id {
+                targets.push(id.clone());
             }
             // Removing by name also works if the ID is stale/missing.
-            targets.push(ws.container_name);
+            targets.push(ws.container_name.clone());
+        }
+        // A state entry naming another workspace's container is corrupt; touch nothing.
+        for target in &targets {
+            if let Some((owner, owner_project)) = self.labeled_owner(target).await
+                && (owner != github_user || owner_project != project)
+            {
+                return Err(anyhow!(
+                    "refusing to destroy {github_user}/{project}: its state entry names container \
+                     {target}, which belongs to {owner}/{owner_project}"
+                ));
+            }
         }
 
+        // Check the directory before anything is removed, so a refusal leaves the workspace
+        // intact.
+        let deletable = if opts.keep_workspace || !workspace_path.exists() {
+            None
+        } else {
+            let recorded_path = recorded.as_ref().map(|ws| ws.host_workspace_path.as_path());
+            Some(
+                workspace_guard::check_deletable(
+                    &self.config.workspace_root,
+                    github_user,
+                    project,
+                    recorded_path,
+                )
+                .await?,
+            )
+        };
+
         // Add labeled containers (newer containers).
         match self.list_labeled_workspace_containers(github_user, project).await {
             Ok(mut ids) => targets.append(&mut ids),
@@ -629,6 +2447,9 @@ impl ContainerManager {
 
         let mut removed_containers = Vec::new();
 
+        if !opts.dry_run {
+            journal::step(&self.state, github_user, project, "removing containers").await;
+        }
         for target in targets {
             if opts.dry_run {
                 removed_containers.push(format!("{target} (dry-run)"));
@@ -680,32 +2501,58 @@ impl ContainerManager {
 
         // Delete persistent workspace directory.
         let mut workspace_deleted = false;
-        if !opts.keep_workspace {
-            if opts.dry_run {
-                if workspace_path.exists() {
-                    workspace_deleted = true;
-                }
-            } else if workspace_path.exists() {
-                tokio::fs::remove_dir_all(&workspace_path)
-                    .await
-                    .with_context(|| {
-                        format!(
-                            "Failed to delete workspace directory: {}",
-                            workspace_path.display()
-                        )
-                    })?;
-                workspace_deleted = true;
+        if let Some(path) = deletable {
+            if !opts.dry_run {
+                journal::step(&self.state, github_user, project, "deleting workspace directory")
+                    .await;
+                tokio::fs::remove_dir_all(&path).await.with_context(|| {
+                    format!("Failed to delete workspace directory: {}", path.display())
+                })?;
             }
+            workspace_deleted = true;
+        }
+
+        // Remove the project network (only exists if other projects linked to it).
+        if !opts.dry_run {
+            journal::step(&self.state, github_user, project, "removing network and data").await;
+            let network = project_network_name(github_user, project);
+            match self.docker.remove_network(&network).await {
+                Ok(_) => info!("Removed project network {}", network),
+                Err(bollard::errors::Error::DockerResponseServerError {
+                    status_code: 404, ..
+                }) => {}
+                Err(e) => warnings.push(format!("remove network {network}: {e}")),
+            }
+        }
+
+        // The built image can be rebuilt from the workspace's Dockerfile.
+        if !opts.dry_run && self.config.build.enabled {
+            build::remove_image(self, github_user, project).await;
+        }
+
+        // Resource history belongs to the workspace; keep it only with --keep-workspace.
+        if !opts.dry_run && !opts.keep_workspace {
+            stats_history::remove(&self.config.stats_history, github_user, project).await;
+            stack::remove_volumes(&self.config.stack, github_user, project).await;
+            if let Err(e) = env_file::remove(&self.config.env_file, github_user, project).await {
+                warnings.push(format!("env file: {e:#}"));
+            }
+            if let Err(e) = egress::remove(&self.config.egress, github_user, project).await {
+                warnings.push(format!("egress ledger: {e:#}"));
+            }
+            user_notify::stop_listener(github_user, project);
+            ownership::stop_watcher(github_user, project);
         }
 
         // Remove the workspace entry from state.
         let state_entry_deleted = if opts.dry_run {
             false
         } else {
-            self.state
-                .remove_workspace(github_user, project)
-                .await?
-                .is_some()
+            journal::step(&self.state, github_user, project, "removing state entry").await;
+            let removed = self.state.remove_workspace(github_user, project).await?;
+            // Its grants, and grants to it, went with the state entry.
+            mesh::refresh();
+            removed.is_some()
         };
 
         Ok(DestroyResult {
@@ -717,6 +2564,181 @@ impl ContainerManager {
         })
     }
 
+    /// Give a workspace to another GitHub user: remove its containers (labels and name are
+    /// per-user, so the recipient gets a fresh container on first connect), move the workspace
+    /// directory, and re-key the state entry. Files and the init command carry over; links and
+    /// the port reservation don't.
+    pub async fn transfer_workspace(
+        &self,
+        from_user: &str,
+        project: &str,
+        to_user: &str,
+    ) -> Result<TransferResult> {
+        let Some(previous) = self.state.get_workspace(from_user, project).await else {
+            return Err(anyhow!("no workspace {from_user}/{project}"));
+        };
+        if from_user == to_user {
+            return Err(anyhow!("{project} already belongs to {to_user}"));
+        }
+        let from_path = self.config.workspace_path(from_user, project);
+        let to_path = self.config.workspace_path(to_user, project);
+        if self.state.get_workspace(to_user, project).await.is_some() || to_path.exists() {
+            return Err(anyhow!(
+                "{to_user} already has a project named {project}; rename or destroy it first"
+            ));
+        }
+
+        let destroyed = self
+            .destroy_workspace(
+                from_user,
+                project,
+                DestroyOptions {
+                    keep_workspace: true,
+                    force: false,
+                    dry_run: false,
+                },
+            )
+            .await?;
+
+        if from_path.exists() {
+            let moved = async {
+                if let Some(parent) = to_path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                tokio::fs::rename(&from_path, &to_path).await
+            }
+            .await;
+            if let Err(e) = moved {
+                // Put the state entry back so the original owner keeps access.
+                self.state
+                    .set_workspace(WorkspaceInfo {
+                        container_id: None,
+                        ..previous
+                    })
+                    .await?;
+                return Err(anyhow!(
+                    "Failed to move {} to {}: {e}",
+                    from_path.display(),
+                    to_path.display()
+                ));
+            }
+        }
+        // Resource history belongs to the old container, and the env file's secrets to the
+        // old owner.
+        stats_history::remove(&self.config.stats_history, from_user, project).await;
+        let mut warnings = destroyed.warnings;
+        if let Err(e) = env_file::remove(&self.config.env_file, from_user, project).await {
+            warnings.push(format!("env file: {e:#}"));
+        }
+        if let Err(e) = stack::move_volumes(&self.config.stack, from_user, project, to_user).await {
+            warnings.push(format!("stack volumes: {e:#}"));
+        }
+
+        self.state
+            .set_workspace(WorkspaceInfo {
+                github_user: to_user.to_string(),
+                project: project.to_string(),
+                container_name: self.config.container_name(to_user, project),
+                container_id: None,
+                created_at: Utc::now(),
+                host_workspace_path: to_path.clone(),
+                linked_projects: Vec::new(),
+                init_command: previous.init_command,
+                init_status: None,
+                image: previous.image,
+                // The hostname changes with the owner; the recipient opts in again.
+                tls_port: None,
+                watch_ownership: previous.watch_ownership,
+                clone: None,
+                resources: previous.resources,
+            })
+            .await?;
+        info!("Transferred workspace {}/{} to {}", from_user, project, to_user);
+
+        Ok(TransferResult {
+            removed_containers: destroyed.removed_containers,
+            workspace_path: to_path,
+            warnings,
+        })
+    }
+
+    /// IP addresses of running agentman containers (on any network, ephemeral ones included),
+    /// mapped to their `(github_user, project)`.
+    pub async fn workspace_addresses(&self) -> Result<HashMap<IpAddr, (String, String)>> {
+        let filters: HashMap<String, Vec<String>> = HashMap::from([
+            ("label".to_string(), vec!["agentman.github_user".to_string()]),
+            ("status".to_string(), vec!["running".to_string()]),
+        ]);
+        let options = ListContainersOptionsBuilder::new().filters(&filters).build();
+        let containers = self
+            .docker
+            .list_containers(Some(options))
+            .await
+            .context("Failed to list containers")?;
+
+        let mut out = HashMap::new();
+        for c in containers {
+            let labels = c.labels.unwrap_or_default();
+            let (Some(github_user), Some(project)) = (
+                labels.get("agentman.github_user"),
+                labels.get("agentman.project"),
+            ) else {
+                continue;
+            };
+            let networks = c
+                .network_settings
+                .and_then(|ns| ns.networks)
+                .unwrap_or_default();
+            for endpoint in networks.values() {
+                for ip in [&endpoint.ip_address, &endpoint.global_ipv6_address]
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|ip| ip.parse::<IpAddr>().ok())
+                {
+                    out.insert(ip, (github_user.clone(), project.clone()));
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// Number of running agentman containers per GitHub user.
+    pub async fn running_containers_per_user(&self) -> Result<HashMap<String, usize>> {
+        let filters: HashMap<String, Vec<String>> = HashMap::from([
+            ("label".to_string(), vec!["agentman.managed=true".to_string()]),
+            ("status".to_string(), vec!["running".to_string()]),
+        ]);
+        let options = ListContainersOptionsBuilder::new().filters(&filters).build();
+        let containers = self
+            .docker
+            .list_containers(Some(options))
+            .await
+            .context("Failed to list containers")?;
+
+        let mut out = HashMap::new();
+        for c in containers {
+            if let Some(github_user) = c.labels.unwrap_or_default().remove("agentman.github_user") {
+                *out.entry(github_user).or_insert(0) += 1;
+            }
+        }
+        Ok(out)
+    }
+
+    /// The workspace a container's ownership labels name (`None` without labels, or if the
+    /// container is gone).
+    async fn labeled_owner(&self, container: &str) -> Option<(String, String)> {
+        let info = self
+            .docker
+            .inspect_container(container, None::<InspectContainerOptions>)
+            .await
+            .ok()?;
+        let labels = info.config?.labels?;
+        Some((
+            labels.get("agentman.github_user")?.clone(),
+            labels.get("agentman.project")?.clone(),
+        ))
+    }
+
     async fn list_labeled_workspace_containers(
         &self,
         github_user: &str,
@@ -752,10 +2774,447 @@ impl ContainerManager {
         }
         Ok(out)
     }
+
+    /// Stop the workspace's container; one that is already stopped or gone is fine. Returns the
+    /// container's name, or `None` if the user has no such sandbox.
+    pub async fn stop_workspace_container(
+        &self,
+        github_user: &str,
+        project: &str,
+    ) -> Result<Option<String>> {
+        let Some(ws) = self.state.get_workspace(github_user, project).await else {
+            return Ok(None);
+        };
+        match self
+            .docker
+            .stop_container(
+                &ws.container_name,
+                Some(StopContainerOptionsBuilder::new().t(10).build()),
+            )
+            .await
+        {
+            // 304: already stopped; 404: gone, so starting creates it again.
+            Ok(_)
+            | Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 304 | 404,
+                ..
+            }) => Ok(Some(ws.container_name)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Stop the workspace's container if nobody has used it for `timeout` (`[idle]`). Returns
+    /// whether it was stopped.
+    async fn stop_if_idle(&self, ws: &WorkspaceInfo, timeout: chrono::Duration) -> Result<bool> {
+        let now = Utc::now();
+        match self.state.idle_for(&ws.github_user, &ws.project, now) {
+            Some(idle) if idle >= timeout => {}
+            _ => return Ok(false),
+        }
+        let Some(ref container_id) = ws.container_id else {
+            return Ok(false);
+        };
+        let info = match self
+            .docker
+            .inspect_container(container_id, None::<InspectContainerOptions>)
+            .await
+        {
+            Ok(info) => info,
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => return Ok(false),
+            Err(e) => return Err(e).context("Failed to inspect container"),
+        };
+        let Some(state) = info.state.filter(|s| s.running == Some(true)) else {
+            return Ok(false);
+        };
+        // Detached jobs keep the sandbox busy.
+        let started = state
+            .started_at
+            .as_deref()
+            .and_then(|t| chrono::DateTime::parse_from_rfc3339(t).ok())
+            .map(|t| t.with_timezone(&Utc));
+        let jobs = crate::jobs::list(&ws.host_workspace_path).await.unwrap_or_default();
+        if jobs.iter().any(|job| crate::jobs::is_running(job, started)) {
+            return Ok(false);
+        }
+
+        match self
+            .docker
+            .stop_container(container_id, Some(StopContainerOptionsBuilder::new().t(10).build()))
+            .await
+        {
+            Ok(_) => {}
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 304 | 404,
+                ..
+            }) => return Ok(false),
+            Err(e) => return Err(e).context("Failed to stop container"),
+        }
+        info!(
+            "Stopped idle sandbox {}/{} ({})",
+            ws.github_user, ws.project, ws.container_name
+        );
+        Ok(true)
+    }
+}
+
+/// The init command log, relative to the workspace (`/workspace/.agentman/init.log`).
+const INIT_LOG: [&str; 2] = [".agentman", "init.log"];
+
+/// Create (or replace) the workspace's init log. The sandbox owns the directories on the way,
+/// so they are opened without following symlinks, and a symlink left in the log's place is
+/// removed rather than written through.
+fn create_init_log(workspace_path: &Path) -> std::io::Result<std::fs::File> {
+    let [dir, name] = INIT_LOG.map(OsStr::new);
+    Handle::open_dir(workspace_path)?
+        .subdir(dir, 0o755)?
+        .replace_file(name, 0o644)
+}
+
+/// Contents of the workspace's init log, read without following symlinks.
+pub fn read_init_log(workspace_path: &Path) -> std::io::Result<Vec<u8>> {
+    let log = host_fs::walk(workspace_path, &INIT_LOG.iter().collect::<PathBuf>())?;
+    if !log.metadata()?.is_file() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "not a regular file",
+        ));
+    }
+    let mut bytes = Vec::new();
+    log.reopen()?.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// A single background run of a workspace init command.
+struct InitRun {
+    docker: Docker,
+    state: Arc<StateManager>,
+    github_user: String,
+    project: String,
+    container_id: String,
+    workspace_path: PathBuf,
+    timeout: Duration,
+}
+
+impl InitRun {
+    async fn run(self, command: String) {
+        let started_at = Utc::now();
+        self.set_status(InitStatus {
+            state: InitState::Running,
+            command: command.clone(),
+            exit_code: None,
+            started_at,
+            finished_at: None,
+            error: None,
+        })
+        .await;
+
+        let (exit_code, error) = match tokio::time::timeout(self.timeout, self.exec(&command)).await {
+            Ok(Ok(code)) => (Some(code), None),
+            Ok(Err(e)) => (None, Some(format!("{e:#}"))),
+            Err(_) => (
+                None,
+                Some(format!("timed out after {}s", self.timeout.as_secs())),
+            ),
+        };
+
+        let state = if exit_code == Some(0) {
+            InitState::Succeeded
+        } else {
+            InitState::Failed
+        };
+        match (&exit_code, &error) {
+            (_, Some(e)) => warn!(
+                "Init command for {}/{} failed: {}",
+                self.github_user, self.project, e
+            ),
+            (Some(code), None) if *code != 0 => warn!(
+                "Init command for {}/{} exited with {}",
+                self.github_user, self.project, code
+            ),
+            _ => info!(
+                "Init command for {}/{} succeeded",
+                self.github_user, self.project
+            ),
+        }
+
+        self.set_status(InitStatus {
+            state,
+            command,
+            exit_code,
+            started_at,
+            finished_at: Some(Utc::now()),
+            error,
+        })
+        .await;
+    }
+
+    /// Run the command to completion, streaming its output into the log file.
+    async fn exec(&self, command: &str) -> Result<i64> {
+        let workspace_path = self.workspace_path.clone();
+        let log = tokio::task::spawn_blocking(move || create_init_log(&workspace_path))
+            .await?
+            .with_context(|| {
+                format!("Failed to create init log in {}", self.workspace_path.display())
+            })?;
+        let mut log = tokio::fs::File::from_std(log);
+        log.write_all(
+            format!(
+                "# agentman init started {}\n# command: {}\n",
+                Utc::now().to_rfc3339(),
+                command
+            )
+            .as_bytes(),
+        )
+        .await?;
+
+        let exec = self
+            .docker
+            .create_exec(
+                &self.container_id,
+                CreateExecOptions {
+                    cmd: Some(vec![
+                        "/bin/bash".to_string(),
+                        "-lc".to_string(),
+                        command.to_string(),
+                    ]),
+                    attach_stdout: Some(true),
+                    attach_stderr: Some(true),
+                    working_dir: Some("/workspace".to_string()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .context("Failed to create init exec")?;
+
+        if let StartExecResults::Attached { mut output, .. } = self
+            .docker
+            .start_exec(&exec.id, None::<StartExecOptions>)
+            .await
+            .context("Failed to start init exec")?
+        {
+            while let Some(chunk) = output.next().await {
+                let chunk = chunk.context("Init exec output error")?;
+                log.write_all(&chunk.into_bytes()).await?;
+            }
+        }
+
+        // The exec may briefly report Running=true after its output stream ends.
+        let mut exit_code = None;
+        for _ in 0..80 {
+            let info = self
+                .docker
+                .inspect_exec(&exec.id)
+                .await
+                .context("Failed to inspect init exec")?;
+            if info.running.unwrap_or(false) {
+                tokio::time::sleep(Duration::from_millis(25)).await;
+                continue;
+            }
+            exit_code = info.exit_code;
+            break;
+        }
+        let exit_code = exit_code.unwrap_or(-1);
+
+        log.write_all(format!("# exit code: {exit_code}\n").as_bytes())
+            .await?;
+        log.flush().await?;
+        Ok(exit_code)
+    }
+
+    async fn set_status(&self, status: InitStatus) {
+        if let Err(e) = self
+            .state
+            .update_workspace(&self.github_user, &self.project, |ws| {
+                ws.init_status = Some(status)
+            })
+            .await
+        {
+            warn!(
+                "Failed to record init status for {}/{}: {}",
+                self.github_user, self.project, e
+            );
+        }
+    }
+}
+
+/// Stop sandboxes that have been idle for `[idle] timeout_secs`, or their owner's shorter
+/// `idle_timeout` preference.
+pub fn spawn_idle_reaper(container_manager: Arc<ContainerManager>) {
+    let config = container_manager.config().idle.clone();
+    match config.timeout_secs {
+        Some(timeout_secs) => info!("Stopping sandboxes idle for {}s", timeout_secs),
+        // Users may still pick a timeout of their own (`agentman config set idle_timeout`).
+        None => debug!("No gateway idle timeout; only users' own timeouts apply"),
+    }
+
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(Duration::from_secs(config.check_interval_secs));
+        tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            tick.tick().await;
+            let cm = container_manager.as_ref();
+            for github_user in cm.state().known_github_users().await {
+                let prefs = cm.state().preferences(&github_user).await;
+                let Some(timeout_secs) = preferences::idle_timeout_secs(cm.config(), &prefs) else {
+                    continue;
+                };
+                let timeout = chrono::Duration::seconds(timeout_secs as i64);
+                for ws in cm.list_workspaces(&github_user).await {
+                    match cm.stop_if_idle(&ws, timeout).await {
+                        Ok(true) => {
+                            crate::metrics::inc(
+                                "agentman_idle_stops_total",
+                                "Sandboxes stopped after their idle timeout without connections.",
+                                &[],
+                            );
+                            if prefs.notify_idle_stop == Some(true) {
+                                notify_idle_stop(cm, &ws, timeout_secs).await;
+                            }
+                        }
+                        Ok(false) => {}
+                        Err(e) => warn!(
+                            "Failed to stop idle sandbox {}/{}: {:#}",
+                            ws.github_user, ws.project, e
+                        ),
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Tell the owner's notifier that their sandbox was stopped (`notify_idle_stop`).
+async fn notify_idle_stop(cm: &ContainerManager, ws: &WorkspaceInfo, timeout_secs: u64) {
+    let config = cm.config();
+    if !config.user_notify.enabled || cm.state().notifier(&ws.github_user).await.is_none() {
+        return;
+    }
+    let text = format!(
+        "stopped your sandbox {} after {} without connections; it starts again at your next login",
+        ws.project,
+        crate::guest::format_ttl(timeout_secs)
+    );
+    if let Err(e) =
+        crate::user_notify::send(config, cm.state(), &ws.github_user, &ws.project, &text).await
+    {
+        warn!("Failed to notify {} of an idle stop: {:#}", ws.github_user, e);
+    }
+}
+
+/// Name of the Docker network owned by (github_user, project), used for `agentman link`.
+/// Container hostname for a stable container name: a single DNS label (no `_` or `.`, at
+/// most 63 characters).
+fn hostname_for(container_name: &str) -> String {
+    let label: String = container_name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .take(63)
+        .collect();
+    label.trim_end_matches('-').to_string()
+}
+
+pub fn project_network_name(github_user: &str, project: &str) -> String {
+    format!("agentman-{}-{}", github_user, project)
+}
+
+/// A sandbox not started because its user is at a `[quotas]` limit; the reason is shown to
+/// the user as is.
+#[derive(Debug)]
+pub struct QuotaExceeded(pub String);
+
+impl std::fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "quota exceeded: {}; stop one of your sandboxes (`agentman stop`) first",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+/// An async lock per user, created on first use.
+#[derive(Debug, Default)]
+struct UserLocks(std::sync::Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>);
+
+impl UserLocks {
+    async fn lock(&self, github_user: &str) -> tokio::sync::OwnedMutexGuard<()> {
+        let lock = self.0.lock().unwrap().entry(github_user.to_string()).or_default().clone();
+        lock.lock_owned().await
+    }
+}
+
+/// What running sandboxes reserve, as counted against `[quotas]`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QuotaUsage {
+    pub containers: usize,
+    /// Memory limits added up, in bytes.
+    pub memory: i64,
+    /// CPU limits added up, in billionths of a CPU.
+    pub nano_cpus: i64,
+}
+
+impl QuotaUsage {
+    /// One container with `host`'s limits (none counts as zero).
+    fn of(host: &HostConfig) -> Self {
+        Self {
+            containers: 1,
+            memory: host.memory.unwrap_or(0).max(0),
+            nano_cpus: host.nano_cpus.unwrap_or(0).max(0),
+        }
+    }
+
+    fn add(&mut self, other: QuotaUsage) {
+        self.containers += other.containers;
+        self.memory += other.memory;
+        self.nano_cpus += other.nano_cpus;
+    }
+}
+
+/// Why one more sandbox reserving `adding` would put a user whose running sandboxes reserve
+/// `used` over `quota`, if it would.
+pub(crate) fn quota_exceeded(
+    quota: &UserQuota,
+    used: QuotaUsage,
+    adding: QuotaUsage,
+) -> Option<String> {
+    if let Some(max) = quota.max_containers
+        && used.containers + adding.containers > max
+    {
+        return Some(format!(
+            "you have {} running sandboxes and may run at most {max}",
+            used.containers
+        ));
+    }
+    let max_memory = quota.max_memory.as_deref().and_then(|m| parse_memory_limit(m).ok());
+    if let Some(max) = max_memory
+        && used.memory + adding.memory > max
+    {
+        return Some(format!(
+            "your running sandboxes reserve {} of memory and this one needs {}, over your {} quota",
+            format_bytes(used.memory as u64),
+            format_bytes(adding.memory as u64),
+            format_bytes(max as u64)
+        ));
+    }
+    if let Some(max) = quota.max_cpus
+        && (used.nano_cpus + adding.nano_cpus) as f64 > max * 1e9
+    {
+        return Some(format!(
+            "your running sandboxes reserve {:.2} CPUs and this one needs {:.2}, over your \
+             {max} CPU quota",
+            used.nano_cpus as f64 / 1e9,
+            adding.nano_cpus as f64 / 1e9
+        ));
+    }
+    None
 }
 
 /// Parse a memory limit string (e.g., "4g", "512m") to bytes.
-fn parse_memory_limit(s: &str) -> Result<i64> {
+pub(crate) fn parse_memory_limit(s: &str) -> Result<i64> {
     let s = s.trim().to_lowercase();
     let (num, mult) = if s.ends_with('g') {
         (s.trim_end_matches('g'), 1024 * 1024 * 1024)
@@ -786,4 +3245,118 @@ mod tests {
         assert_eq!(parse_memory_limit("1000").unwrap(), 1000);
         assert_eq!(parse_memory_limit("2G").unwrap(), 2 * 1024 * 1024 * 1024);
     }
+
+    #[tokio::test]
+    async fn test_user_locks() {
+        let locks = UserLocks::default();
+        let held = locks.lock("octocat").await;
+        // Another user isn't held up.
+        drop(locks.lock("hubot").await);
+        let waiting = tokio::time::timeout(Duration::from_millis(50), locks.lock("octocat"));
+        assert!(waiting.await.is_err());
+        drop(held);
+        drop(locks.lock("octocat").await);
+    }
+
+    #[test]
+    fn test_quota_exceeded() {
+        const GIB: i64 = 1024 * 1024 * 1024;
+        let quota = UserQuota {
+            max_containers: Some(2),
+            max_memory: Some("8g".to_string()),
+            max_cpus: Some(4.0),
+        };
+        let sandbox = QuotaUsage {
+            containers: 1,
+            memory: 4 * GIB,
+            nano_cpus: 2_000_000_000,
+        };
+        assert_eq!(quota_exceeded(&quota, QuotaUsage::default(), sandbox), None);
+        assert_eq!(quota_exceeded(&quota, sandbox, sandbox), None);
+
+        let mut used = sandbox;
+        used.add(sandbox);
+        let one = QuotaUsage {
+            containers: 1,
+            ..Default::default()
+        };
+        let reason = quota_exceeded(&quota, used, one);
+        assert!(reason.unwrap().contains("at most 2"));
+
+        let unlimited_count = UserQuota {
+            max_containers: None,
+            ..quota.clone()
+        };
+        let reason = quota_exceeded(&unlimited_count, used, sandbox).unwrap();
+        assert!(reason.contains("memory"), "{reason}");
+        let no_memory = QuotaUsage {
+            memory: 0,
+            ..sandbox
+        };
+        let cpu_heavy = QuotaUsage {
+            nano_cpus: 3_000_000_000,
+            ..no_memory
+        };
+        let reason = quota_exceeded(&unlimited_count, sandbox, cpu_heavy);
+        assert!(reason.unwrap().contains("CPU"));
+    }
+
+    #[test]
+    fn test_resolve_user() {
+        let passwd = "root:x:0:0:root:/root:/bin/bash\ndev:x:1001:1002::/home/dev:/bin/bash\n";
+        let groups = "root:x:0:\nstaff:x:50:dev\n";
+        assert_eq!(resolve_user("", None, None).unwrap(), (0, 0));
+        assert_eq!(resolve_user("dev", Some(passwd), None).unwrap(), (1001, 1002));
+        assert_eq!(resolve_user("1001", Some(passwd), None).unwrap(), (1001, 1002));
+        assert_eq!(resolve_user("2000", Some(passwd), None).unwrap(), (2000, 0));
+        assert_eq!(resolve_user("2000:3000", None, None).unwrap(), (2000, 3000));
+        assert_eq!(
+            resolve_user("dev:staff", Some(passwd), Some(groups)).unwrap(),
+            (1001, 50)
+        );
+        assert!(resolve_user("nobody", Some(passwd), None).is_err());
+        assert!(resolve_user("dev:wheel", Some(passwd), Some(groups)).is_err());
+
+        let subuid = "alice:100000:65536\ndockremap:165536:65536\n";
+        assert_eq!(subid_start(subuid, "dockremap"), Some(165536));
+        assert_eq!(subid_start(subuid, "bob"), None);
+    }
+
+    #[test]
+    fn test_init_log_does_not_follow_symlinks() {
+        use std::io::Write;
+
+        let base = std::env::temp_dir().join(format!("agentman-init-log-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        let (ws, host) = (base.join("ws"), base.join("host"));
+        std::fs::create_dir_all(ws.join(".agentman")).unwrap();
+        std::fs::create_dir_all(&host).unwrap();
+        std::fs::write(host.join("passwd"), "root:x:0:0\n").unwrap();
+
+        // A symlink in the log's place is replaced, not written through.
+        std::os::unix::fs::symlink(host.join("passwd"), ws.join(".agentman/init.log")).unwrap();
+        assert!(read_init_log(&ws).is_err());
+        create_init_log(&ws).unwrap().write_all(b"make setup\n").unwrap();
+        assert_eq!(read_init_log(&ws).unwrap(), b"make setup\n");
+        assert_eq!(std::fs::read_to_string(host.join("passwd")).unwrap(), "root:x:0:0\n");
+
+        // So is a symlinked directory on the way.
+        std::fs::remove_dir_all(ws.join(".agentman")).unwrap();
+        std::os::unix::fs::symlink(&host, ws.join(".agentman")).unwrap();
+        std::fs::write(host.join("init.log"), "host").unwrap();
+        assert!(create_init_log(&ws).is_err());
+        assert!(read_init_log(&ws).is_err());
+        assert_eq!(std::fs::read_to_string(host.join("init.log")).unwrap(), "host");
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_hostname_for() {
+        assert_eq!(hostname_for("agentman-octocat-api"), "agentman-octocat-api");
+        assert_eq!(hostname_for("agentman-octocat-my_app"), "agentman-octocat-my-app");
+        let long = format!("agentman-octocat-{}", "x".repeat(64));
+        assert_eq!(hostname_for(&long).len(), 63);
+        assert_eq!(hostname_for(&format!("{}-x", "a".repeat(62))), "a".repeat(62));
+    }
 }
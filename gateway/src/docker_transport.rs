@@ -0,0 +1,245 @@
+//! Docker API transport with per-request latency metrics.
+//!
+//! bollard's own Unix socket transport can't be observed, so the gateway connects through a
+//! custom transport doing the same requests over the same socket, timing each one. Every bollard
+//! call shows up in `agentman_docker_request_duration_seconds{op}` (create, start, exec_create,
+//! exec_start, inspect, stats, remove, ...) and calls slower than `[metrics] docker_slow_call_ms`
+//! are logged, so operators can tell a slow gateway from a slow dockerd. For streaming calls
+//! (logs, stats, attach, image pulls) the time is until dockerd starts responding.
+
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, bail};
+use bollard::{API_DEFAULT_VERSION, BollardRequest, Docker};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use hyperlocal::UnixConnector;
+use tracing::warn;
+
+use crate::metrics;
+
+/// Same default as bollard's local connection.
+const DEFAULT_SOCKET: &str = "/var/run/docker.sock";
+
+/// Request timeout in seconds, as in bollard's local defaults.
+const REQUEST_TIMEOUT_SECS: u64 = 120;
+
+/// Upper bounds (seconds) of the latency histogram buckets.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+/// Operations that wait on the container by design (stop grace period, `wait`, attach), so they
+/// are never reported as slow.
+const WAITING_OPS: &[&str] = &[
+    "container_stop",
+    "container_restart",
+    "container_wait",
+    "container_attach",
+];
+
+/// Per-container actions (`/containers/{id}/<action>`, ...) kept as metric labels; anything
+/// else is `other`, keeping label cardinality bounded.
+const ACTIONS: &[&str] = &[
+    "start",
+    "stop",
+    "restart",
+    "kill",
+    "pause",
+    "unpause",
+    "rename",
+    "wait",
+    "resize",
+    "update",
+    "attach",
+    "logs",
+    "stats",
+    "top",
+    "archive",
+    "export",
+    "changes",
+    "connect",
+    "disconnect",
+    "push",
+    "tag",
+    "history",
+];
+
+/// Connect to the local Docker daemon (`DOCKER_HOST=unix://...`, or the default socket) with
+/// request timing. `slow_call_ms` = 0 disables slow-call warnings.
+pub fn connect(slow_call_ms: u64) -> Result<Docker> {
+    let socket = std::env::var("DOCKER_HOST")
+        .ok()
+        .and_then(|host| host.strip_prefix("unix://").map(str::to_string))
+        .unwrap_or_else(|| DEFAULT_SOCKET.to_string());
+    if !Path::new(&socket).exists() {
+        bail!("Docker socket not found: {socket}");
+    }
+    let slow_call = (slow_call_ms > 0).then(|| Duration::from_millis(slow_call_ms));
+
+    let client = Client::builder(TokioExecutor::new())
+        .pool_max_idle_per_host(0)
+        .build(UnixConnector);
+    let transport = move |req: BollardRequest| {
+        let client = client.clone();
+        let method = req.method().to_string();
+        let path = req.uri().path().to_string();
+        async move {
+            let op = docker_op(&method, &path);
+            let start = Instant::now();
+            let result = client.request(req).await;
+            let elapsed = start.elapsed();
+
+            metrics::observe(
+                "agentman_docker_request_duration_seconds",
+                "Latency of Docker API requests (until response headers), by operation",
+                LATENCY_BUCKETS,
+                &[("op", op.as_str())],
+                elapsed.as_secs_f64(),
+            );
+            let failed = match &result {
+                Ok(response) => response.status().is_server_error(),
+                Err(_) => true,
+            };
+            if failed {
+                metrics::inc(
+                    "agentman_docker_request_errors_total",
+                    "Docker API requests that failed to connect or returned a 5xx status",
+                    &[("op", op.as_str())],
+                );
+            }
+            if let Some(threshold) = slow_call
+                && elapsed >= threshold
+                && !WAITING_OPS.contains(&op.as_str())
+            {
+                warn!(
+                    "Slow Docker API call: {} {} ({}) took {}ms",
+                    method,
+                    path,
+                    op,
+                    elapsed.as_millis()
+                );
+            }
+            result.map_err(bollard::errors::Error::from)
+        }
+    };
+
+    // bollard builds `unix://<hex-encoded socket path>/...` URIs, which UnixConnector dials.
+    let host: String = socket.bytes().map(|b| format!("{b:02x}")).collect();
+    Ok(Docker::connect_with_custom_transport(
+        transport,
+        Some(format!("unix://{host}")),
+        REQUEST_TIMEOUT_SECS,
+        API_DEFAULT_VERSION,
+    )?)
+}
+
+/// Metric label for a Docker API request, e.g. `container_create`, `exec_start`.
+fn docker_op(method: &str, path: &str) -> String {
+    let mut segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    // Drop the API version prefix (`/v1.49/...`).
+    if segments
+        .first()
+        .is_some_and(|s| s.starts_with('v') && s[1..].starts_with(|c: char| c.is_ascii_digit()))
+    {
+        segments.remove(0);
+    }
+    let Some((&first, rest)) = segments.split_first() else {
+        return "other".to_string();
+    };
+    let resource = match first {
+        "containers" => "container",
+        "exec" => "exec",
+        "images" => "image",
+        "networks" => "network",
+        "volumes" => "volume",
+        "_ping" => return "ping".to_string(),
+        "version" | "info" | "events" => return first.to_string(),
+        _ => return "other".to_string(),
+    };
+
+    let action = match (method, rest) {
+        (_, []) => "other",
+        ("GET", ["json"]) => "list",
+        (_, ["create"]) => "create",
+        (_, ["prune"]) => "prune",
+        ("DELETE", [..]) => "remove",
+        ("GET", [_]) => "inspect",
+        // `POST /containers/{id}/exec` creates an exec.
+        ("POST", [_, "exec"]) if resource == "container" => return "exec_create".to_string(),
+        (_, [.., "json"]) => "inspect",
+        (_, [_, ..]) => {
+            let last = rest[rest.len() - 1];
+            ACTIONS
+                .iter()
+                .find(|a| **a == last)
+                .copied()
+                .unwrap_or("other")
+        }
+    };
+    format!("{resource}_{action}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_docker_op() {
+        let id = "0123abcd";
+        let cases = [
+            ("POST", "/v1.49/containers/create", "container_create"),
+            (
+                "POST",
+                &format!("/v1.49/containers/{id}/start") as &str,
+                "container_start",
+            ),
+            (
+                "GET",
+                &format!("/v1.49/containers/{id}/json"),
+                "container_inspect",
+            ),
+            (
+                "GET",
+                &format!("/v1.49/containers/{id}/stats"),
+                "container_stats",
+            ),
+            (
+                "DELETE",
+                &format!("/v1.49/containers/{id}"),
+                "container_remove",
+            ),
+            ("GET", "/v1.49/containers/json", "container_list"),
+            (
+                "POST",
+                &format!("/v1.49/containers/{id}/exec"),
+                "exec_create",
+            ),
+            ("POST", &format!("/v1.49/exec/{id}/start"), "exec_start"),
+            ("GET", &format!("/v1.49/exec/{id}/json"), "exec_inspect"),
+            ("POST", "/v1.49/images/create", "image_create"),
+            (
+                "GET",
+                "/v1.49/images/ghcr.io/org/img:1/json",
+                "image_inspect",
+            ),
+            ("GET", "/v1.49/networks/agentman-a-b", "network_inspect"),
+            (
+                "POST",
+                "/v1.49/networks/agentman-a-b/connect",
+                "network_connect",
+            ),
+            ("GET", "/_ping", "ping"),
+            (
+                "POST",
+                &format!("/v1.49/containers/{id}/frobnicate"),
+                "container_other",
+            ),
+            ("GET", "/v1.49/plugins", "other"),
+        ];
+        for (method, path, op) in cases {
+            assert_eq!(docker_op(method, path), op, "{method} {path}");
+        }
+    }
+}
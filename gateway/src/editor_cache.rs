@@ -0,0 +1,204 @@
+//! Per-user cache for editor remote servers.
+//!
+//! VS Code, Cursor, Zed and friends install a server binary (often hundreds of MB) the first
+//! time they connect to a host. With `[editor_cache] enabled = true`, those directories are
+//! bind-mounted from `<root>/<github_user>/<dir>` into every container of that user, so new
+//! projects and recreated containers reuse the download.
+//!
+//! Editors keep old server versions around forever; [`prune`] removes versions that haven't
+//! been touched in a while, always keeping the newest one of each kind.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use tracing::{info, warn};
+
+use crate::config::EditorCacheConfig;
+
+/// Subdirectories (relative to an editor cache dir) whose children are individual server
+/// versions, e.g. `.vscode-server/bin/<commit>` or `.vscode-server/cli/servers/Stable-<commit>`.
+const VERSION_PARENTS: &[&str] = &["bin", "cli/servers"];
+
+/// Host directory holding `github_user`'s editor caches.
+pub fn user_cache_dir(config: &EditorCacheConfig, github_user: &str) -> PathBuf {
+    config.root.join(github_user)
+}
+
+/// `(host path, container path)` pairs to bind-mount for the user's editor caches.
+pub fn binds(config: &EditorCacheConfig, github_user: &str) -> Vec<(PathBuf, String)> {
+    if !config.enabled {
+        return Vec::new();
+    }
+    let base = user_cache_dir(config, github_user);
+    config
+        .dirs
+        .iter()
+        .map(|dir| (base.join(dir), format!("/workspace/{dir}")))
+        .collect()
+}
+
+/// What a prune removed.
+#[derive(Debug, Default)]
+pub struct PruneReport {
+    /// Removed paths, relative to the user's cache directory.
+    pub removed: Vec<String>,
+}
+
+/// Remove cached editor server versions not modified within `older_than`, keeping the newest
+/// version in each group. With `older_than = None`, everything in the user's cache is removed.
+pub async fn prune(
+    config: &EditorCacheConfig,
+    github_user: &str,
+    older_than: Option<Duration>,
+) -> Result<PruneReport> {
+    let base = user_cache_dir(config, github_user);
+    let dirs = config.dirs.clone();
+    tokio::task::spawn_blocking(move || prune_blocking(&base, &dirs, older_than))
+        .await
+        .context("Prune task panicked")?
+}
+
+fn prune_blocking(base: &Path, dirs: &[String], older_than: Option<Duration>) -> Result<PruneReport> {
+    let mut report = PruneReport::default();
+    let now = SystemTime::now();
+
+    for dir in dirs {
+        let cache = base.join(dir);
+        if !cache.is_dir() {
+            continue;
+        }
+
+        let Some(older_than) = older_than else {
+            // --all: empty the directory but keep it, since it is a live mount point.
+            for entry in read_entries(&cache)? {
+                remove(&entry.path)?;
+                report.removed.push(relative(base, &entry.path));
+            }
+            continue;
+        };
+
+        // Version groups: bin/*, cli/servers/*, and plain files at the top level (Zed keeps
+        // one `zed-remote-server-<channel>-<version>` binary per version there).
+        let mut groups: Vec<Vec<Entry>> = VERSION_PARENTS
+            .iter()
+            .map(|p| cache.join(p))
+            .filter(|p| p.is_dir())
+            .map(|p| read_entries(&p))
+            .collect::<Result<_>>()?;
+        groups.push(read_entries(&cache)?.into_iter().filter(|e| e.is_file).collect());
+
+        for group in groups {
+            for entry in stale_entries(group, now, older_than) {
+                remove(&entry.path)?;
+                report.removed.push(relative(base, &entry.path));
+            }
+        }
+    }
+
+    if !report.removed.is_empty() {
+        info!(
+            "Pruned {} editor cache entries in {}",
+            report.removed.len(),
+            base.display()
+        );
+    }
+    Ok(report)
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    path: PathBuf,
+    modified: SystemTime,
+    is_file: bool,
+}
+
+fn read_entries(dir: &Path) -> Result<Vec<Entry>> {
+    let mut out = Vec::new();
+    for entry in std::fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+        let entry = entry?;
+        let Ok(md) = entry.metadata() else {
+            continue;
+        };
+        out.push(Entry {
+            path: entry.path(),
+            modified: md.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            is_file: md.is_file(),
+        });
+    }
+    Ok(out)
+}
+
+/// Entries older than `older_than`, never including the most recently modified one.
+fn stale_entries(mut group: Vec<Entry>, now: SystemTime, older_than: Duration) -> Vec<Entry> {
+    group.sort_by_key(|e| std::cmp::Reverse(e.modified));
+    group
+        .into_iter()
+        .skip(1)
+        .filter(|e| now.duration_since(e.modified).unwrap_or_default() > older_than)
+        .collect()
+}
+
+fn remove(path: &Path) -> Result<()> {
+    let res = if path.is_dir() && !path.is_symlink() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    };
+    match res {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => {
+            warn!("Failed to remove {}: {}", path.display(), e);
+            Err(e).with_context(|| format!("Failed to remove {}", path.display()))
+        }
+    }
+}
+
+fn relative(base: &Path, path: &Path) -> String {
+    path.strip_prefix(base).unwrap_or(path).display().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, age_days: u64, now: SystemTime) -> Entry {
+        Entry {
+            path: PathBuf::from(name),
+            modified: now - Duration::from_secs(age_days * 86400),
+            is_file: false,
+        }
+    }
+
+    #[test]
+    fn test_stale_entries_keeps_newest() {
+        let now = SystemTime::now();
+        let month = Duration::from_secs(30 * 86400);
+
+        let group = vec![entry("a", 90, now), entry("b", 60, now), entry("c", 1, now)];
+        let stale: Vec<_> = stale_entries(group, now, month).into_iter().map(|e| e.path).collect();
+        assert_eq!(stale, vec![PathBuf::from("b"), PathBuf::from("a")]);
+
+        // Even if everything is old, the newest version survives.
+        let group = vec![entry("a", 90, now), entry("b", 60, now)];
+        let stale: Vec<_> = stale_entries(group, now, month).into_iter().map(|e| e.path).collect();
+        assert_eq!(stale, vec![PathBuf::from("a")]);
+    }
+
+    #[test]
+    fn test_binds_disabled() {
+        let mut cfg = EditorCacheConfig::default();
+        assert!(binds(&cfg, "octocat").is_empty());
+        cfg.enabled = true;
+        cfg.root = PathBuf::from("/var/lib/agentman/editor-cache");
+        cfg.dirs = vec![".zed_server".to_string()];
+        assert_eq!(
+            binds(&cfg, "octocat"),
+            vec![(
+                PathBuf::from("/var/lib/agentman/editor-cache/octocat/.zed_server"),
+                "/workspace/.zed_server".to_string()
+            )]
+        );
+    }
+}
@@ -0,0 +1,488 @@
+//! Network traffic accounting and monthly egress quotas.
+//!
+//! A background sampler reads the network counters of every running workspace container and
+//! adds what changed since the last sample to a ledger per user (`<dir>/<github_user>.json`).
+//! Docker's counters start over with each container (and each restart), so the ledger keeps
+//! the container ID and raw counters of the last sample: a different container or a smaller
+//! counter means counting starts again from zero. Per-workspace totals live as long as the
+//! workspace; the user's monthly totals start over with each UTC calendar month.
+//!
+//! Bytes sent (`tx`) are egress and count against the user's `[egress]` quota. Crossing
+//! `warn_percent` and then the quota each notify admins once a month; exceeding it also runs
+//! `hook_command`, and with `action = "stop"` stops the user's containers and keeps them from
+//! starting (see [`blocked`]).
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use bollard::errors::Error as BollardError;
+use bollard::query_parameters::{StatsOptionsBuilder, StopContainerOptionsBuilder};
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tracing::{info, warn};
+
+use crate::config::{EgressAction, EgressConfig};
+use crate::docker::ContainerManager;
+use crate::gateway_control::{format_bytes, workspace_container_status_with_running};
+use crate::metrics;
+use crate::notify;
+
+/// How long `hook_command` may run.
+const HOOK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Serializes ledger updates (the sampler, destroy).
+static LEDGER_LOCK: LazyLock<tokio::sync::Mutex<()>> = LazyLock::new(Default::default);
+
+/// A user's traffic.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Ledger {
+    /// Month (`YYYY-MM`, UTC) the monthly totals belong to.
+    pub month: String,
+    /// Bytes sent by the user's containers this month.
+    pub month_tx_bytes: u64,
+    /// Bytes received by the user's containers this month.
+    pub month_rx_bytes: u64,
+    /// Admins were warned about this month's usage.
+    pub warned: bool,
+    /// The quota was exceeded this month (admins notified, hook run).
+    pub exceeded: bool,
+    /// Totals per project.
+    pub workspaces: BTreeMap<String, Counters>,
+}
+
+/// A workspace's traffic since `since`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Counters {
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub since: Option<DateTime<Utc>>,
+    /// Container the last sample came from, and its raw counters then.
+    pub container_id: Option<String>,
+    pub last_rx: u64,
+    pub last_tx: u64,
+}
+
+impl Counters {
+    /// Record raw counters of `container_id`; returns the bytes received and sent since the
+    /// previous sample.
+    fn record(&mut self, container_id: &str, rx: u64, tx: u64, now: DateTime<Utc>) -> (u64, u64) {
+        let same = self.container_id.as_deref() == Some(container_id)
+            && rx >= self.last_rx
+            && tx >= self.last_tx;
+        let (drx, dtx) = if same {
+            (rx - self.last_rx, tx - self.last_tx)
+        } else {
+            (rx, tx)
+        };
+        self.rx_bytes = self.rx_bytes.saturating_add(drx);
+        self.tx_bytes = self.tx_bytes.saturating_add(dtx);
+        self.since.get_or_insert(now);
+        self.container_id = Some(container_id.to_string());
+        self.last_rx = rx;
+        self.last_tx = tx;
+        (drx, dtx)
+    }
+}
+
+impl Ledger {
+    /// Start the monthly totals over when `month` is a new one.
+    fn roll(&mut self, month: &str) {
+        if self.month != month {
+            self.month = month.to_string();
+            self.month_tx_bytes = 0;
+            self.month_rx_bytes = 0;
+            self.warned = false;
+            self.exceeded = false;
+        }
+    }
+}
+
+/// Quota state after a sample.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Crossed {
+    Warn,
+    Exceeded,
+}
+
+/// Which threshold the user crossed for the first time this month, marking it in `ledger`.
+fn check_quota(ledger: &mut Ledger, quota: Option<u64>, warn_percent: u8) -> Option<Crossed> {
+    let quota = quota?;
+    if ledger.month_tx_bytes > quota {
+        if !ledger.exceeded {
+            ledger.exceeded = true;
+            ledger.warned = true;
+            return Some(Crossed::Exceeded);
+        }
+    } else if ledger.month_tx_bytes >= quota / 100 * warn_percent as u64 && !ledger.warned {
+        ledger.warned = true;
+        return Some(Crossed::Warn);
+    }
+    None
+}
+
+pub fn current_month() -> String {
+    Utc::now().format("%Y-%m").to_string()
+}
+
+pub fn ledger_path(config: &EgressConfig, github_user: &str) -> PathBuf {
+    config.dir.join(format!("{github_user}.json"))
+}
+
+/// `github_user`'s ledger, with this month's totals (empty without a ledger).
+pub async fn load(config: &EgressConfig, github_user: &str) -> Result<Ledger> {
+    let path = ledger_path(config, github_user);
+    let mut ledger = match tokio::fs::read(&path).await {
+        Ok(data) => serde_json::from_slice(&data)
+            .with_context(|| format!("Failed to parse {}", path.display()))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ledger::default(),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    ledger.roll(&current_month());
+    Ok(ledger)
+}
+
+async fn save(config: &EgressConfig, github_user: &str, ledger: &Ledger) -> Result<()> {
+    tokio::fs::create_dir_all(&config.dir)
+        .await
+        .with_context(|| format!("Failed to create {}", config.dir.display()))?;
+    let path = ledger_path(config, github_user);
+    let partial = path.with_extension("json.partial");
+    tokio::fs::write(&partial, serde_json::to_vec_pretty(ledger)?)
+        .await
+        .with_context(|| format!("Failed to write {}", partial.display()))?;
+    tokio::fs::rename(&partial, &path)
+        .await
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Why `github_user`'s containers may not start, when they are over their quota and
+/// `action = "stop"`.
+pub async fn blocked(config: &EgressConfig, github_user: &str) -> Option<String> {
+    if !config.enabled || config.action != EgressAction::Stop {
+        return None;
+    }
+    let quota = config.quota_bytes(github_user)?;
+    let ledger = load(config, github_user).await.ok()?;
+    (ledger.month_tx_bytes > quota).then(|| {
+        format!(
+            "{github_user} used {} of network egress this month, over the {} quota; sandboxes \
+             stay stopped until the month ends (ask an admin to raise the quota)",
+            format_bytes(ledger.month_tx_bytes),
+            format_bytes(quota)
+        )
+    })
+}
+
+/// Forget a workspace's totals (on destroy); the user's monthly totals keep its traffic.
+pub async fn remove(config: &EgressConfig, github_user: &str, project: &str) -> Result<()> {
+    let _lock = LEDGER_LOCK.lock().await;
+    let mut ledger = load(config, github_user).await?;
+    if ledger.workspaces.remove(project).is_some() {
+        save(config, github_user, &ledger).await?;
+    }
+    Ok(())
+}
+
+/// `2026-10: 1.2 GiB out of 50.0 GiB (2%), 3.4 GiB in`, for `agentman stats`.
+pub fn format_month(ledger: &Ledger, quota: Option<u64>) -> String {
+    let out = match quota {
+        Some(quota) => format!(
+            "{} out of {} ({}%)",
+            format_bytes(ledger.month_tx_bytes),
+            format_bytes(quota),
+            ledger.month_tx_bytes.saturating_mul(100) / quota.max(1)
+        ),
+        None => format!("{} out", format_bytes(ledger.month_tx_bytes)),
+    };
+    format!(
+        "{}: {out}, {} in",
+        ledger.month,
+        format_bytes(ledger.month_rx_bytes)
+    )
+}
+
+/// Received and sent bytes of a container, summed over its interfaces.
+async fn network_counters(
+    container_manager: &ContainerManager,
+    container: &str,
+) -> Option<(u64, u64)> {
+    let mut stream = container_manager.docker().stats(
+        container,
+        Some(
+            StatsOptionsBuilder::new()
+                .stream(false)
+                .one_shot(true)
+                .build(),
+        ),
+    );
+    let stats = tokio::time::timeout(Duration::from_secs(5), stream.next())
+        .await
+        .ok()??
+        .ok()?;
+    let networks = stats.networks?;
+    Some(networks.values().fold((0, 0), |(rx, tx), n| {
+        (rx + n.rx_bytes.unwrap_or(0), tx + n.tx_bytes.unwrap_or(0))
+    }))
+}
+
+/// Sample the user's running containers into their ledger.
+async fn sample_user(container_manager: &ContainerManager, github_user: &str) -> Result<()> {
+    let config = &container_manager.config().egress;
+    let workspaces = container_manager.state().list_workspaces(github_user).await;
+    let samples = join_all(workspaces.iter().map(|ws| async move {
+        let target = ws.container_id.clone().unwrap_or(ws.container_name.clone());
+        let (_, _, running) =
+            workspace_container_status_with_running(container_manager, &target).await;
+        if !running {
+            return None;
+        }
+        let id = ws.container_id.clone()?;
+        let (rx, tx) = network_counters(container_manager, &target).await?;
+        Some((ws.project.clone(), id, rx, tx))
+    }))
+    .await;
+    let samples: Vec<_> = samples.into_iter().flatten().collect();
+    if samples.is_empty() {
+        return Ok(());
+    }
+
+    let _lock = LEDGER_LOCK.lock().await;
+    let mut ledger = load(config, github_user).await?;
+    let now = Utc::now();
+    for (project, container_id, rx, tx) in samples {
+        let counters = ledger.workspaces.entry(project.clone()).or_default();
+        let (drx, dtx) = counters.record(&container_id, rx, tx, now);
+        ledger.month_rx_bytes = ledger.month_rx_bytes.saturating_add(drx);
+        ledger.month_tx_bytes = ledger.month_tx_bytes.saturating_add(dtx);
+        for (direction, bytes) in [("received", drx), ("sent", dtx)] {
+            metrics::add(
+                "agentman_network_bytes_total",
+                "Network traffic of workspace containers, per workspace and direction.",
+                &[
+                    ("github_user", github_user),
+                    ("project", &project),
+                    ("direction", direction),
+                ],
+                bytes as f64,
+            );
+        }
+    }
+    let quota = config.quota_bytes(github_user);
+    let crossed = check_quota(&mut ledger, quota, config.warn_percent);
+    save(config, github_user, &ledger).await?;
+    drop(_lock);
+
+    let Some(quota) = quota else {
+        return Ok(());
+    };
+    match crossed {
+        Some(Crossed::Warn) => {
+            let text = format!(
+                "{github_user} has used {} of their {} monthly egress quota",
+                format_bytes(ledger.month_tx_bytes),
+                format_bytes(quota)
+            );
+            notify::send(
+                &container_manager.config().notify,
+                "egress.quota_warning",
+                &text,
+            )
+            .await;
+        }
+        Some(Crossed::Exceeded) => {
+            metrics::inc(
+                "agentman_egress_quota_exceeded_total",
+                "Users who exceeded their monthly egress quota.",
+                &[],
+            );
+            let stopping = config.action == EgressAction::Stop;
+            let text = format!(
+                "{github_user} exceeded their {} monthly egress quota ({} sent){}",
+                format_bytes(quota),
+                format_bytes(ledger.month_tx_bytes),
+                if stopping {
+                    "; stopping their sandboxes"
+                } else {
+                    ""
+                }
+            );
+            notify::send(
+                &container_manager.config().notify,
+                "egress.quota_exceeded",
+                &text,
+            )
+            .await;
+            if let Some(ref command) = config.hook_command {
+                run_hook(command, github_user, &ledger, quota).await;
+            }
+        }
+        None => {}
+    }
+    // Also catches containers started before the quota was lowered.
+    if config.action == EgressAction::Stop && ledger.month_tx_bytes > quota {
+        for ws in &workspaces {
+            stop_container(container_manager, &ws.container_name).await;
+        }
+    }
+    Ok(())
+}
+
+async fn stop_container(container_manager: &ContainerManager, container: &str) {
+    match container_manager
+        .docker()
+        .stop_container(
+            container,
+            Some(StopContainerOptionsBuilder::new().t(10).build()),
+        )
+        .await
+    {
+        Ok(_) => info!("Stopped {} (egress quota exceeded)", container),
+        // 304: already stopped; 404: no container.
+        Err(BollardError::DockerResponseServerError {
+            status_code: 304 | 404,
+            ..
+        }) => {}
+        Err(e) => warn!("Failed to stop {}: {}", container, e),
+    }
+}
+
+async fn run_hook(command: &str, github_user: &str, ledger: &Ledger, quota: u64) {
+    let child = Command::new("sh")
+        .args(["-c", command])
+        .env("AGENTMAN_GITHUB_USER", github_user)
+        .env("AGENTMAN_EGRESS_MONTH", &ledger.month)
+        .env("AGENTMAN_EGRESS_BYTES", ledger.month_tx_bytes.to_string())
+        .env("AGENTMAN_EGRESS_QUOTA_BYTES", quota.to_string())
+        .kill_on_drop(true)
+        .output();
+    match tokio::time::timeout(HOOK_TIMEOUT, child).await {
+        Ok(Ok(output)) if output.status.success() => {
+            info!("Egress hook for {} finished", github_user)
+        }
+        Ok(Ok(output)) => warn!(
+            "Egress hook for {} exited with {}: {}",
+            github_user,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Ok(Err(e)) => warn!("Failed to run the egress hook for {}: {}", github_user, e),
+        Err(_) => warn!(
+            "Egress hook for {} timed out after {}s",
+            github_user,
+            HOOK_TIMEOUT.as_secs()
+        ),
+    }
+}
+
+/// Sample every user's running containers every `interval_secs` (no-op when disabled).
+pub fn spawn_sampler(container_manager: Arc<ContainerManager>) {
+    let config = container_manager.config().egress.clone();
+    if !config.enabled {
+        return;
+    }
+    info!("Accounting network traffic every {}s", config.interval_secs);
+
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(Duration::from_secs(config.interval_secs));
+        tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            tick.tick().await;
+            let cm = container_manager.as_ref();
+            let mut last_error = None;
+            for github_user in cm.state().list_github_users().await {
+                if let Err(e) = sample_user(cm, &github_user).await {
+                    warn!("Failed to account traffic of {}: {:#}", github_user, e);
+                    last_error = Some(e);
+                }
+            }
+            let notify_config = &cm.config().notify;
+            match last_error {
+                Some(e) => notify::task_failed(notify_config, "egress", &format!("{e:#}")).await,
+                None => notify::task_succeeded(notify_config, "egress").await,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters_survive_recreation() {
+        let now = Utc::now();
+        let mut counters = Counters::default();
+        assert_eq!(counters.record("a", 100, 10, now), (100, 10));
+        assert_eq!(counters.record("a", 150, 30, now), (50, 20));
+        // Restarted: the counters went down.
+        assert_eq!(counters.record("a", 5, 1, now), (5, 1));
+        // Recreated: a new container.
+        assert_eq!(counters.record("b", 500, 200, now), (500, 200));
+        assert_eq!((counters.rx_bytes, counters.tx_bytes), (655, 231));
+        assert_eq!(counters.since, Some(now));
+    }
+
+    #[test]
+    fn test_roll_and_quota() {
+        let mut ledger = Ledger {
+            month: "2026-09".to_string(),
+            month_tx_bytes: 900,
+            month_rx_bytes: 50,
+            warned: true,
+            exceeded: true,
+            ..Ledger::default()
+        };
+        ledger.roll("2026-09");
+        assert_eq!(ledger.month_tx_bytes, 900);
+        ledger.roll("2026-10");
+        assert_eq!(
+            (ledger.month_tx_bytes, ledger.warned, ledger.exceeded),
+            (0, false, false)
+        );
+
+        assert_eq!(check_quota(&mut ledger, None, 80), None);
+        ledger.month_tx_bytes = 700;
+        assert_eq!(check_quota(&mut ledger, Some(1000), 80), None);
+        ledger.month_tx_bytes = 800;
+        assert_eq!(
+            check_quota(&mut ledger, Some(1000), 80),
+            Some(Crossed::Warn)
+        );
+        assert_eq!(check_quota(&mut ledger, Some(1000), 80), None);
+        ledger.month_tx_bytes = 1001;
+        assert_eq!(
+            check_quota(&mut ledger, Some(1000), 80),
+            Some(Crossed::Exceeded)
+        );
+        assert_eq!(check_quota(&mut ledger, Some(1000), 80), None);
+        assert_eq!(
+            format_month(&ledger, Some(1000)),
+            format!(
+                "2026-10: {} out of {} (100%), 0 B in",
+                format_bytes(1001),
+                format_bytes(1000)
+            )
+        );
+    }
+
+    #[test]
+    fn test_quota_bytes() {
+        let config = EgressConfig {
+            monthly_quota_gb: 50,
+            user_quotas_gb: [("OctoCat".to_string(), 0), ("mona".to_string(), 100)].into(),
+            ..EgressConfig::default()
+        };
+        assert_eq!(config.quota_bytes("someone"), Some(50 << 30));
+        assert_eq!(config.quota_bytes("octocat"), None);
+        assert_eq!(config.quota_bytes("mona"), Some(100 << 30));
+        assert_eq!(EgressConfig::default().quota_bytes("someone"), None);
+    }
+}
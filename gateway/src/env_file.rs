@@ -0,0 +1,309 @@
+//! Per-workspace dotenv files (`agentman env-file`).
+//!
+//! `agentman env-file set < .env` stores a dotenv file for the current workspace on the gateway
+//! host, at `<dir>/<github_user>/<project>.env` (mode 0600), never in the workspace, so agents
+//! can't commit or upload it by accident. Its variables are added to the environment of every
+//! exec and of each new container of the workspace; variables the gateway sets itself (`HOME`,
+//! `TERM`, `SSH_AUTH_SOCK`, ...) win over the file's.
+//!
+//! The format is the common dotenv subset: `KEY=value` lines, optionally prefixed with `export`,
+//! `#` comments, and single-quoted (literal) or double-quoted (with `\n`, `\t`, `\"`, `\\` and
+//! `\$` escapes) values. Values can't span lines.
+
+use std::path::PathBuf;
+
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+
+use crate::config::EnvFileConfig;
+
+/// A stored env file, for `agentman env-file show`.
+pub struct Summary {
+    /// Variable names, in file order.
+    pub names: Vec<String>,
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+pub fn path(config: &EnvFileConfig, github_user: &str, project: &str) -> PathBuf {
+    config.dir.join(github_user).join(format!("{project}.env"))
+}
+
+/// Parse dotenv `content` into variables, in file order; a variable set twice keeps its last
+/// value (at the position of its first assignment).
+pub fn parse(content: &str) -> Result<Vec<(String, String)>> {
+    let mut vars: Vec<(String, String)> = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line_no = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((key, value)) = line.split_once('=') else {
+            bail!("line {line_no}: expected KEY=VALUE");
+        };
+        let key = key.trim();
+        if !is_name(key) {
+            bail!("line {line_no}: invalid variable name {key:?}");
+        }
+        let value = parse_value(value.trim_start()).with_context(|| format!("line {line_no}"))?;
+        if value.contains('\0') {
+            bail!("line {line_no}: {key} contains a NUL byte");
+        }
+        match vars.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value,
+            None => vars.push((key.to_string(), value)),
+        }
+    }
+    Ok(vars)
+}
+
+fn is_name(key: &str) -> bool {
+    let mut chars = key.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// The value after `=`: quoted, or bare up to a ` #` comment.
+fn parse_value(raw: &str) -> Result<String> {
+    let (value, rest) = match raw.chars().next() {
+        Some('\'') => {
+            let Some(end) = raw[1..].find('\'') else {
+                bail!("unterminated single quote");
+            };
+            (raw[1..end + 1].to_string(), &raw[end + 2..])
+        }
+        Some('"') => {
+            let mut value = String::new();
+            let mut chars = raw[1..].char_indices();
+            let end = loop {
+                match chars.next() {
+                    Some((i, '"')) => break i + 2,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, 'n')) => value.push('\n'),
+                        Some((_, 't')) => value.push('\t'),
+                        Some((_, 'r')) => value.push('\r'),
+                        Some((_, c @ ('"' | '\\' | '$'))) => value.push(c),
+                        Some((_, c)) => {
+                            value.push('\\');
+                            value.push(c);
+                        }
+                        None => bail!("unterminated double quote"),
+                    },
+                    Some((_, c)) => value.push(c),
+                    None => bail!("unterminated double quote"),
+                }
+            };
+            (value, &raw[end..])
+        }
+        _ => {
+            let end = raw
+                .char_indices()
+                .find(|&(i, c)| c == '#' && raw[..i].ends_with([' ', '\t']))
+                .map_or(raw.len(), |(i, _)| i);
+            return Ok(raw[..end].trim_end().to_string());
+        }
+    };
+    let rest = rest.trim_start();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        bail!("unexpected text after the closing quote");
+    }
+    Ok(value)
+}
+
+/// Check `content` and store it as the workspace's env file; returns the number of variables.
+pub async fn store(
+    config: &EnvFileConfig,
+    github_user: &str,
+    project: &str,
+    content: &[u8],
+) -> Result<usize> {
+    if content.len() > config.max_bytes {
+        bail!("the env file is larger than {} bytes", config.max_bytes);
+    }
+    let text = std::str::from_utf8(content).context("the env file is not UTF-8")?;
+    let vars = parse(text)?;
+    if vars.is_empty() {
+        bail!("no variables in the input (use `agentman env-file clear` to remove the file)");
+    }
+
+    let path = path(config, github_user, project);
+    let dir = path.parent().expect("env file path has a parent");
+    create_private_dir(&config.dir).await?;
+    create_private_dir(dir).await?;
+    let partial = path.with_extension("env.partial");
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&partial)
+        .await
+        .with_context(|| format!("Failed to create {}", partial.display()))?;
+    file.write_all(content).await?;
+    file.sync_all().await?;
+    drop(file);
+    tokio::fs::rename(&partial, &path)
+        .await
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    info!(
+        "Stored env file for {}/{} ({} variables)",
+        github_user,
+        project,
+        vars.len()
+    );
+    Ok(vars.len())
+}
+
+async fn create_private_dir(dir: &std::path::Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    tokio::fs::create_dir_all(dir)
+        .await
+        .with_context(|| format!("Failed to create {}", dir.display()))?;
+    tokio::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))
+        .await
+        .with_context(|| format!("Failed to set permissions on {}", dir.display()))
+}
+
+/// The stored file's variable names, or `None` without a file.
+pub async fn summary(
+    config: &EnvFileConfig,
+    github_user: &str,
+    project: &str,
+) -> Result<Option<Summary>> {
+    let path = path(config, github_user, project);
+    let content = match tokio::fs::read_to_string(&path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    let updated_at = tokio::fs::metadata(&path)
+        .await
+        .and_then(|m| m.modified())
+        .ok()
+        .map(DateTime::<Utc>::from);
+    let names = parse(&content)?.into_iter().map(|(k, _)| k).collect();
+    Ok(Some(Summary { names, updated_at }))
+}
+
+/// Delete the workspace's env file; returns whether there was one.
+pub async fn remove(config: &EnvFileConfig, github_user: &str, project: &str) -> Result<bool> {
+    let path = path(config, github_user, project);
+    match tokio::fs::remove_file(&path).await {
+        Ok(()) => {
+            info!("Removed env file {}", path.display());
+            Ok(true)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e).with_context(|| format!("Failed to remove {}", path.display())),
+    }
+}
+
+/// Add the workspace's variables to `env` (`KEY=value` entries), except those already set
+/// there. A missing or unreadable file adds nothing.
+pub async fn inject(
+    config: &EnvFileConfig,
+    github_user: &str,
+    project: &str,
+    env: &mut Vec<String>,
+) {
+    if !config.enabled {
+        return;
+    }
+    let path = path(config, github_user, project);
+    let content = match tokio::fs::read_to_string(&path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+        Err(e) => {
+            warn!("Failed to read {}: {}", path.display(), e);
+            return;
+        }
+    };
+    let vars = match parse(&content) {
+        Ok(vars) => vars,
+        Err(e) => {
+            warn!("Ignoring {}: {:#}", path.display(), e);
+            return;
+        }
+    };
+    for (key, value) in vars {
+        let prefix = format!("{key}=");
+        if !env.iter().any(|e| e.starts_with(&prefix)) {
+            env.push(format!("{prefix}{value}"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let content = "\
+# database
+DATABASE_URL=postgres://app:secret@db/app
+export API_KEY = abc123  # issued 2026-10
+EMPTY=
+SINGLE='literal $HOME \\n # not a comment'
+DOUBLE=\"line one\\nline \\\"two\\\"\" # comment
+HASH=a#b
+API_KEY=rotated
+";
+        let vars = parse(content).unwrap();
+        let get = |key: &str| vars.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
+        assert_eq!(get("DATABASE_URL"), Some("postgres://app:secret@db/app"));
+        assert_eq!(get("API_KEY"), Some("rotated"));
+        assert_eq!(get("EMPTY"), Some(""));
+        assert_eq!(get("SINGLE"), Some("literal $HOME \\n # not a comment"));
+        assert_eq!(get("DOUBLE"), Some("line one\nline \"two\""));
+        assert_eq!(get("HASH"), Some("a#b"));
+        assert_eq!(vars[1].0, "API_KEY");
+        assert_eq!(vars.len(), 6);
+
+        assert!(parse("NO_EQUALS\n").is_err());
+        assert!(parse("1KEY=x\n").is_err());
+        assert!(parse("KEY-NAME=x\n").is_err());
+        assert!(parse("KEY='open\n").is_err());
+        assert!(parse("KEY=\"a\" b\n").is_err());
+        assert!(parse("\n# only a comment\n").unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_store_and_inject() {
+        let dir =
+            std::env::temp_dir().join(format!("agentman-env-file-test-{}", std::process::id()));
+        let config = EnvFileConfig {
+            dir: dir.clone(),
+            ..EnvFileConfig::default()
+        };
+        let stored = store(&config, "octocat", "web", b"HOME=/root\nTOKEN=t0k3n\n").await;
+        assert_eq!(stored.unwrap(), 2);
+        let mode = std::os::unix::fs::PermissionsExt::mode(
+            &std::fs::metadata(path(&config, "octocat", "web"))
+                .unwrap()
+                .permissions(),
+        );
+        assert_eq!(mode & 0o777, 0o600);
+
+        let mut env = vec!["HOME=/workspace".to_string()];
+        inject(&config, "octocat", "web", &mut env).await;
+        assert_eq!(env, ["HOME=/workspace", "TOKEN=t0k3n"]);
+
+        assert!(
+            store(&config, "octocat", "web", b"# nothing\n")
+                .await
+                .is_err()
+        );
+        let too_large = vec![b'#'; config.max_bytes + 1];
+        assert!(store(&config, "octocat", "web", &too_large).await.is_err());
+
+        assert!(remove(&config, "octocat", "web").await.unwrap());
+        assert!(!remove(&config, "octocat", "web").await.unwrap());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,95 @@
+//! Throwaway sandboxes (`[ephemeral]`).
+//!
+//! A connection for the configured project (`ssh tmp+<user>@gateway`), or one that runs
+//! `agentman ephemeral`, gets a fresh container with an in-memory `/workspace` instead of the
+//! workspace container. The container isn't recorded in state; it is labelled
+//! [`EPHEMERAL_LABEL`] so that leftovers of a gateway that stopped uncleanly can be removed at
+//! startup, and it is removed when the connection ends.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+
+use anyhow::{Result, bail};
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+
+/// Label on ephemeral containers (instead of `agentman.managed`).
+pub const EPHEMERAL_LABEL: &str = "agentman.ephemeral";
+
+/// Ephemeral sandboxes per GitHub user, including those still being created.
+static ACTIVE: LazyLock<Mutex<HashMap<String, usize>>> = LazyLock::new(Default::default);
+
+static NEXT_SANDBOX: AtomicU64 = AtomicU64::new(1);
+
+/// A user's slot for one ephemeral sandbox; freed on drop.
+#[derive(Debug)]
+pub struct Lease {
+    github_user: String,
+}
+
+/// Take one of `github_user`'s `max` ephemeral sandbox slots.
+pub fn lease(github_user: &str, max: usize) -> Result<Lease> {
+    let mut active = ACTIVE.lock().unwrap();
+    let count = active.entry(github_user.to_string()).or_default();
+    if *count >= max {
+        bail!("{github_user} already has {count} ephemeral sandbox(es) (the limit is {max})");
+    }
+    *count += 1;
+    Ok(Lease {
+        github_user: github_user.to_string(),
+    })
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        let mut active = ACTIVE.lock().unwrap();
+        if let Some(count) = active.get_mut(&self.github_user) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.github_user);
+            }
+        }
+    }
+}
+
+/// Project name for a new ephemeral sandbox (`<project>-<8 hex digits>`), used for its
+/// container name and `AGENTMAN_PROJECT`.
+pub fn sandbox_project(project: &str) -> String {
+    let seed = format!(
+        "{}-{}-{}",
+        std::process::id(),
+        Utc::now().timestamp_nanos_opt().unwrap_or_default(),
+        NEXT_SANDBOX.fetch_add(1, Ordering::Relaxed)
+    );
+    let suffix: String = Sha256::digest(seed.as_bytes())[..4]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    format!("{project}-{suffix}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lease_limit() {
+        let first = lease("ephemeral-test", 2).unwrap();
+        let second = lease("ephemeral-test", 2).unwrap();
+        assert!(lease("ephemeral-test", 2).is_err());
+        assert!(lease("ephemeral-test-other", 2).is_ok());
+
+        drop(first);
+        let third = lease("ephemeral-test", 2).unwrap();
+        drop((second, third));
+        assert!(!ACTIVE.lock().unwrap().contains_key("ephemeral-test"));
+    }
+
+    #[test]
+    fn test_sandbox_project() {
+        let a = sandbox_project("tmp");
+        assert!(a.starts_with("tmp-") && a.len() == "tmp-".len() + 8);
+        assert_ne!(a, sandbox_project("tmp"));
+    }
+}
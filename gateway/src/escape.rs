@@ -0,0 +1,301 @@
+//! Gateway escape sequences for PTY sessions.
+//!
+//! The client's own escapes (`~.`, `~C` in OpenSSH) can't reach gateway features, so the gateway
+//! watches PTY input for its own escape character (`[shell] escape_char`, `~` by default). As in
+//! OpenSSH it is only special right after a newline, and typing it twice sends it once:
+//!
+//! - `~.` closes the session (a tmux session keeps running, like a detach)
+//! - `~z` suspends: pauses the sandbox and closes the session; the next connection resumes it
+//! - `~C` opens a one-line prompt for forwards from a gateway host port into the sandbox
+//! - `~?` lists the escapes
+//!
+//! OpenSSH clients act on `~` themselves, so their users type it twice (`~~.`), as they would for
+//! a nested session.
+
+use anyhow::{Result, anyhow, bail};
+
+/// What a chunk of PTY input turned into.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Input {
+    /// Bytes for the sandbox.
+    Data(Vec<u8>),
+    /// Bytes to echo back to the client (prompt editing).
+    Echo(Vec<u8>),
+    Close,
+    Suspend,
+    /// The prompt was opened; following input is collected until `Submit` or `Cancel`.
+    Prompt,
+    Submit(String),
+    Cancel,
+    Help,
+}
+
+#[derive(Debug)]
+enum Mode {
+    Normal { line_start: bool },
+    Escape,
+    Prompt { line: String },
+}
+
+/// Splits PTY input into data for the sandbox and escape sequences for the gateway.
+#[derive(Debug)]
+pub struct EscapeParser {
+    escape: u8,
+    mode: Mode,
+}
+
+impl EscapeParser {
+    pub fn new(escape: u8) -> Self {
+        Self {
+            escape,
+            mode: Mode::Normal { line_start: true },
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) -> Vec<Input> {
+        let mut out = Vec::new();
+        for &b in data {
+            match self.mode {
+                Mode::Normal { line_start } => {
+                    if line_start && b == self.escape {
+                        self.mode = Mode::Escape;
+                    } else {
+                        push_data(&mut out, &[b]);
+                        self.mode = Mode::Normal {
+                            line_start: is_newline(b),
+                        };
+                    }
+                }
+                Mode::Escape => {
+                    self.mode = Mode::Normal { line_start: true };
+                    match b {
+                        b'.' => out.push(Input::Close),
+                        b'z' | 0x1a => out.push(Input::Suspend),
+                        b'?' => out.push(Input::Help),
+                        b'C' => {
+                            out.push(Input::Prompt);
+                            self.mode = Mode::Prompt {
+                                line: String::new(),
+                            };
+                        }
+                        _ if b == self.escape => {
+                            push_data(&mut out, &[b]);
+                            self.mode = Mode::Normal { line_start: false };
+                        }
+                        _ => {
+                            push_data(&mut out, &[self.escape, b]);
+                            self.mode = Mode::Normal {
+                                line_start: is_newline(b),
+                            };
+                        }
+                    }
+                }
+                Mode::Prompt { ref mut line } => match b {
+                    b'\r' | b'\n' => {
+                        out.push(Input::Submit(std::mem::take(line)));
+                        self.mode = Mode::Normal { line_start: true };
+                    }
+                    // Ctrl-C, Esc
+                    0x03 | 0x1b => {
+                        out.push(Input::Cancel);
+                        self.mode = Mode::Normal { line_start: true };
+                    }
+                    // Backspace, Delete
+                    0x08 | 0x7f if line.pop().is_some() => {
+                        push_echo(&mut out, b"\x08 \x08");
+                    }
+                    0x20..=0x7e => {
+                        line.push(b as char);
+                        push_echo(&mut out, &[b]);
+                    }
+                    _ => {}
+                },
+            }
+        }
+        out
+    }
+}
+
+fn is_newline(b: u8) -> bool {
+    b == b'\r' || b == b'\n'
+}
+
+fn push_data(out: &mut Vec<Input>, bytes: &[u8]) {
+    match out.last_mut() {
+        Some(Input::Data(data)) => data.extend_from_slice(bytes),
+        _ => out.push(Input::Data(bytes.to_vec())),
+    }
+}
+
+fn push_echo(out: &mut Vec<Input>, bytes: &[u8]) {
+    match out.last_mut() {
+        Some(Input::Echo(echo)) => echo.extend_from_slice(bytes),
+        _ => out.push(Input::Echo(bytes.to_vec())),
+    }
+}
+
+/// Text for `~?`, with the configured escape character.
+pub fn help_text(escape: u8) -> String {
+    let e = escape as char;
+    format!(
+        "Supported gateway escape sequences:\r\n\
+         \x20{e}.  - close this session (tmux keeps running)\r\n\
+         \x20{e}z  - suspend: pause the sandbox and disconnect (reconnect to resume)\r\n\
+         \x20{e}C  - command line (forwards from the gateway host into the sandbox)\r\n\
+         \x20{e}?  - this message\r\n\
+         \x20{e}{e}  - send the escape character\r\n\
+         (Escapes are only recognized right after a newline. With OpenSSH, type {e} twice.)\r\n"
+    )
+}
+
+/// Text for `help` at the `~C` prompt.
+pub const PROMPT_HELP: &str = "Commands:\r\n\
+     \x20-L [bind_address:]port:host:hostport  forward a gateway host port into the sandbox\r\n\
+     \x20-KL [bind_address:]port               cancel such a forward\r\n\
+     \x20-l                                    list them\r\n";
+
+/// A command entered at the `~C` prompt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PromptCommand {
+    /// Listen on `bind:port` on the gateway host and connect to `host:host_port` in the sandbox.
+    Forward {
+        bind: Option<String>,
+        port: u16,
+        host: String,
+        host_port: u16,
+    },
+    CancelForward {
+        bind: Option<String>,
+        port: u16,
+    },
+    List,
+    Help,
+}
+
+/// Parse a `~C` prompt line (`None` for an empty one). Like OpenSSH, the option and its argument
+/// may be written together (`-L8080:localhost:3000`).
+pub fn parse_prompt(line: &str) -> Result<Option<PromptCommand>> {
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if matches!(line, "?" | "help" | "-h") {
+        return Ok(Some(PromptCommand::Help));
+    }
+    if line == "-l" {
+        return Ok(Some(PromptCommand::List));
+    }
+    if let Some(spec) = line.strip_prefix("-KL") {
+        let (bind, port) = match spec.trim().rsplit_once(':') {
+            Some((bind, port)) => (Some(bind.to_string()), port),
+            None => (None, spec.trim()),
+        };
+        return Ok(Some(PromptCommand::CancelForward {
+            bind,
+            port: parse_port(port)?,
+        }));
+    }
+    if let Some(spec) = line.strip_prefix("-L") {
+        let parts: Vec<&str> = spec.trim().split(':').collect();
+        let (bind, port, host, host_port) = match parts[..] {
+            [port, host, host_port] => (None, port, host, host_port),
+            [bind, port, host, host_port] => (Some(bind.to_string()), port, host, host_port),
+            _ => bail!("usage: -L [bind_address:]port:host:hostport"),
+        };
+        if host.is_empty() {
+            bail!("missing host in -L {}", spec.trim());
+        }
+        return Ok(Some(PromptCommand::Forward {
+            bind,
+            port: parse_port(port)?,
+            host: host.to_string(),
+            host_port: parse_port(host_port)?,
+        }));
+    }
+    bail!("unknown command (try help)")
+}
+
+fn parse_port(s: &str) -> Result<u16> {
+    s.parse().map_err(|_| anyhow!("invalid port: {s:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_only_at_line_start() {
+        let mut parser = EscapeParser::new(b'~');
+        assert_eq!(parser.feed(b"~."), vec![Input::Close]);
+
+        let mut parser = EscapeParser::new(b'~');
+        assert_eq!(
+            parser.feed(b"ls ~.\r~~x"),
+            vec![Input::Data(b"ls ~.\r~x".to_vec())]
+        );
+        assert_eq!(
+            parser.feed(b"\n~/bin\r~?"),
+            vec![Input::Data(b"\n~/bin\r".to_vec()), Input::Help]
+        );
+        // Split across reads.
+        assert_eq!(parser.feed(b"\r~"), vec![Input::Data(b"\r".to_vec())]);
+        assert_eq!(parser.feed(b"z"), vec![Input::Suspend]);
+    }
+
+    #[test]
+    fn test_prompt_editing() {
+        let mut parser = EscapeParser::new(b'~');
+        assert_eq!(
+            parser.feed(b"~C-Lx\x7f8080"),
+            vec![Input::Prompt, Input::Echo(b"-Lx\x08 \x088080".to_vec())]
+        );
+        assert_eq!(
+            parser.feed(b"\rls"),
+            vec![
+                Input::Submit("-L8080".to_string()),
+                Input::Data(b"ls".to_vec())
+            ]
+        );
+        assert_eq!(
+            parser.feed(b"\r~C-L\x03"),
+            vec![
+                Input::Data(b"\r".to_vec()),
+                Input::Prompt,
+                Input::Echo(b"-L".to_vec()),
+                Input::Cancel,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_prompt() {
+        assert_eq!(parse_prompt(" ").unwrap(), None);
+        assert_eq!(
+            parse_prompt("-L 8080:localhost:3000").unwrap(),
+            Some(PromptCommand::Forward {
+                bind: None,
+                port: 8080,
+                host: "localhost".to_string(),
+                host_port: 3000,
+            })
+        );
+        assert_eq!(
+            parse_prompt("-L0.0.0.0:0:db:5432").unwrap(),
+            Some(PromptCommand::Forward {
+                bind: Some("0.0.0.0".to_string()),
+                port: 0,
+                host: "db".to_string(),
+                host_port: 5432,
+            })
+        );
+        assert_eq!(
+            parse_prompt("-KL 8080").unwrap(),
+            Some(PromptCommand::CancelForward {
+                bind: None,
+                port: 8080
+            })
+        );
+        assert!(parse_prompt("-L 8080:3000").is_err());
+        assert!(parse_prompt("-R 9000:localhost:9000").is_err());
+    }
+}
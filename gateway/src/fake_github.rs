@@ -0,0 +1,223 @@
+//! A stand-in for the parts of GitHub the gateway uses, for end-to-end tests without
+//! github.com.
+//!
+//! Built into the tests and, with the `fake-github` feature, into the gateway, where
+//! `--fake-github <dir>` starts it on a loopback port and points `[github]` at it. Users are
+//! files in the directory, read on every request so tests can add keys while it runs:
+//!
+//! - `<user>.keys`: authentication keys, one per line, served as `/<user>.keys`;
+//! - `<user>.signing.keys`: signing keys, served as `/users/<user>/ssh_signing_keys`;
+//! - `<user>.orgs`: organizations the user is a member of, served as
+//!   `/orgs/<org>/memberships/<user>`.
+//!
+//! Users with a `.keys` file also have a profile (`/users/<user>`). Team memberships are never
+//! found. Requests are answered one per connection, like the admin API.
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info};
+
+use crate::config::GitHubConfig;
+use crate::github::validate_github_username;
+
+/// Upper bound for a request; only request lines and headers are read.
+const MAX_REQUEST: usize = 16 * 1024;
+
+/// Time a client gets to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A running fake server; stops when dropped.
+pub struct FakeGitHub {
+    addr: SocketAddr,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl FakeGitHub {
+    /// Serve the users in `dir` on `listen` (port 0 picks a free one).
+    pub async fn start(listen: &str, dir: PathBuf) -> Result<Self> {
+        let listener = TcpListener::bind(listen)
+            .await
+            .with_context(|| format!("Failed to bind fake GitHub on {listen}"))?;
+        let addr = listener.local_addr()?;
+        info!("Fake GitHub listening on http://{} (users in {})", addr, dir.display());
+        let task = tokio::spawn(async move {
+            loop {
+                let Ok((mut stream, peer)) = listener.accept().await else {
+                    continue;
+                };
+                let dir = dir.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_client(&mut stream, &dir).await {
+                        debug!("Fake GitHub request from {} failed: {:#}", peer, e);
+                    }
+                });
+            }
+        });
+        Ok(Self { addr, task })
+    }
+
+    /// `[github]` pointing at this server.
+    pub fn config(&self) -> GitHubConfig {
+        let url = format!("http://{}", self.addr);
+        GitHubConfig {
+            keys_url: url.clone(),
+            api_url: url,
+        }
+    }
+}
+
+impl Drop for FakeGitHub {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn handle_client(stream: &mut TcpStream, dir: &Path) -> Result<()> {
+    let head = match tokio::time::timeout(REQUEST_TIMEOUT, read_request_head(stream)).await {
+        Ok(head) => head?,
+        Err(_) => anyhow::bail!("Timed out waiting for a request"),
+    };
+    let mut request_line = head.split_whitespace();
+    let (method, path) = (request_line.next().unwrap_or(""), request_line.next().unwrap_or(""));
+    let (status, content_type, body) = match respond_to(method, path, dir).await {
+        Ok((content_type, body)) => ("200 OK", content_type, body),
+        Err(status) => (status, "text/plain", format!("{status}\n")),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// The content type and body for a request, or the HTTP status to answer with.
+async fn respond_to(
+    method: &str,
+    path: &str,
+    dir: &Path,
+) -> Result<(&'static str, String), &'static str> {
+    if method != "GET" {
+        return Err("405 Method Not Allowed");
+    }
+    let path = path.split('?').next().unwrap_or_default();
+    let segments: Vec<&str> = path.trim_end_matches('/').split('/').skip(1).collect();
+    let user = |name: &str| {
+        validate_github_username(name)
+            .map(|()| name.to_string())
+            .map_err(|_| "404 Not Found")
+    };
+    match segments.as_slice() {
+        [file] if file.ends_with(".keys") => {
+            let keys = read_keys(dir, &user(file.trim_end_matches(".keys"))?, "keys").await?;
+            Ok(("text/plain", keys.iter().map(|key| format!("{key}\n")).collect()))
+        }
+        ["users", name] => {
+            let name = user(name)?;
+            read_keys(dir, &name, "keys").await?;
+            let id = Sha256::digest(name.as_bytes())[..4]
+                .iter()
+                .fold(0u64, |id, byte| id << 8 | u64::from(*byte));
+            Ok(("application/json", json!({ "login": name, "id": id }).to_string()))
+        }
+        ["users", name, "ssh_signing_keys"] => {
+            let name = user(name)?;
+            read_keys(dir, &name, "keys").await?;
+            let keys = read_keys(dir, &name, "signing.keys").await.unwrap_or_default();
+            let keys: Vec<_> = keys.into_iter().map(|key| json!({ "key": key })).collect();
+            Ok(("application/json", serde_json::Value::from(keys).to_string()))
+        }
+        ["orgs", org, "memberships", name] => {
+            let orgs = read_keys(dir, &user(name)?, "orgs").await?;
+            if !orgs.iter().any(|o| o.eq_ignore_ascii_case(org)) {
+                return Err("404 Not Found");
+            }
+            Ok(("application/json", json!({ "state": "active" }).to_string()))
+        }
+        _ => Err("404 Not Found"),
+    }
+}
+
+/// The non-empty lines of `<dir>/<user>.<suffix>`.
+async fn read_keys(dir: &Path, user: &str, suffix: &str) -> Result<Vec<String>, &'static str> {
+    let content = tokio::fs::read_to_string(dir.join(format!("{user}.{suffix}")))
+        .await
+        .map_err(|_| "404 Not Found")?;
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Read until the request headers are complete.
+async fn read_request_head(stream: &mut TcpStream) -> Result<String> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            anyhow::bail!("Client closed before sending a request");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            return Ok(String::from_utf8_lossy(&buf[..end]).into_owned());
+        }
+        if buf.len() > MAX_REQUEST {
+            anyhow::bail!("Request headers too large");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::github::GitHubKeyFetcher;
+
+    #[tokio::test]
+    async fn test_serves_keys_and_profiles() {
+        let dir = std::env::temp_dir().join(format!("agentman-fake-github-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("octocat.keys"), "ssh-ed25519 AAAA one\n\nssh-rsa BBBB\n").unwrap();
+        std::fs::write(dir.join("octocat.signing.keys"), "ssh-ed25519 CCCC\n").unwrap();
+        std::fs::write(dir.join("octocat.orgs"), "acme\n").unwrap();
+        let server = FakeGitHub::start("127.0.0.1:0", dir.clone()).await.unwrap();
+        let fetcher = GitHubKeyFetcher::new(&server.config());
+
+        assert_eq!(
+            fetcher.fetch_keys("octocat").await.unwrap(),
+            vec!["ssh-ed25519 AAAA one", "ssh-rsa BBBB"]
+        );
+        assert_eq!(
+            fetcher.fetch_signing_keys("octocat").await.unwrap(),
+            vec!["ssh-ed25519 CCCC"]
+        );
+        let profile = fetcher.fetch_profile("octocat").await.unwrap();
+        assert_eq!(profile.login, "octocat");
+        assert!(fetcher.fetch_keys("hubot").await.is_err());
+        assert!(fetcher.fetch_keys("..").await.is_err());
+        assert!(!fetcher
+            .fetch_team_membership("org", "team", "octocat", "token")
+            .await
+            .unwrap());
+        assert!(fetcher.fetch_org_membership("acme", "octocat", "token").await.unwrap());
+        assert!(!fetcher.fetch_org_membership("globex", "octocat", "token").await.unwrap());
+
+        // Keys added while it runs are served right away.
+        std::fs::write(dir.join("hubot.keys"), "ssh-ed25519 DDDD\n").unwrap();
+        assert_eq!(fetcher.fetch_keys("hubot").await.unwrap(), vec!["ssh-ed25519 DDDD"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,320 @@
+//! Reading workspace files without a shell (`agentman cat`, `agentman tail`).
+//!
+//! A scripted check or a quick look from a phone needs one file, not a login shell. The gateway
+//! reads the file from the workspace directory on the host, so it works whether the sandbox is
+//! running or not and needs nothing in the image. Paths are given as the sandbox sees them
+//! (`/workspace/...`, or relative to it) and must stay in the workspace: `..` is refused, and so
+//! are symlinks anywhere along the path, since the sandbox controls them and the gateway would
+//! follow them on the host. The path is walked without following symlinks and the file read
+//! through what the walk opened, so a symlink swapped in during the checks is refused too.
+//! Directories, special files (a FIFO would block the read) and other filesystems mounted into
+//! the workspace are refused as well.
+
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::host_fs::Handle;
+
+/// Where the workspace directory is mounted in sandboxes.
+const WORKSPACE_MOUNT: &str = "/workspace";
+
+/// Largest file `agentman cat` shows.
+pub const MAX_CAT_BYTES: u64 = 1024 * 1024;
+
+/// Lines `agentman tail` shows without `-n`.
+pub const DEFAULT_TAIL_LINES: usize = 10;
+
+/// Most lines `agentman tail -n` accepts.
+pub const MAX_TAIL_LINES: usize = 10_000;
+
+/// How far back from the end `agentman tail` looks for lines.
+const TAIL_WINDOW: u64 = 1024 * 1024;
+
+/// Most bytes a follower reads at once.
+const FOLLOW_CHUNK: u64 = 64 * 1024;
+
+/// `path` as the sandbox sees it, for messages.
+pub fn sandbox_path(path: &str) -> String {
+    match relative(path) {
+        Ok(rel) if rel.as_os_str().is_empty() => WORKSPACE_MOUNT.to_string(),
+        Ok(rel) => format!("{WORKSPACE_MOUNT}/{}", rel.display()),
+        Err(_) => path.to_string(),
+    }
+}
+
+/// `path` relative to the workspace, if it stays in it.
+fn relative(path: &str) -> Result<PathBuf> {
+    let rel = if path.starts_with('/') {
+        match path.strip_prefix(WORKSPACE_MOUNT) {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+            _ => bail!("{path} is outside {WORKSPACE_MOUNT}"),
+        }
+    } else {
+        path
+    };
+    let mut out = PathBuf::new();
+    for component in Path::new(rel).components() {
+        match component {
+            Component::Normal(part) => out.push(part),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => bail!("{path} can't contain .."),
+        }
+    }
+    Ok(out)
+}
+
+/// Open the regular file `path` in the workspace directory `workspace` for reading.
+pub async fn open(workspace: &Path, path: &str) -> Result<tokio::fs::File> {
+    let rel = relative(path)?;
+    let shown = sandbox_path(path);
+    let workspace = workspace.to_path_buf();
+    let file = tokio::task::spawn_blocking(move || open_blocking(&workspace, &rel, &shown))
+        .await
+        .context("open task failed")??;
+    Ok(tokio::fs::File::from_std(file))
+}
+
+/// Walks `rel` one component at a time without following symlinks ([`crate::host_fs`]), so
+/// the sandbox can't redirect the file opened after the checks.
+fn open_blocking(workspace: &Path, rel: &Path, shown: &str) -> Result<std::fs::File> {
+    let mut handle = Handle::open_dir(workspace)
+        .with_context(|| format!("{WORKSPACE_MOUNT} doesn't exist yet"))?;
+    let root = handle.metadata()?;
+    let mut meta = root.clone();
+    for part in rel.iter() {
+        handle = match handle.child(part) {
+            Ok(handle) => handle,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => bail!("{shown}: no such file"),
+            Err(e) => return Err(e).with_context(|| format!("Failed to open {shown}")),
+        };
+        meta = handle.metadata()?;
+        if meta.file_type().is_symlink() {
+            let link = part.display();
+            bail!("{shown} goes through a symlink ({link}); name the file it points to");
+        }
+        if meta.dev() != root.dev() {
+            bail!("{shown} is on a filesystem mounted into the workspace");
+        }
+    }
+    if meta.is_dir() {
+        bail!("{shown} is a directory");
+    }
+    if !meta.is_file() {
+        bail!("{shown} is not a regular file");
+    }
+    handle.reopen().with_context(|| format!("Failed to open {shown}"))
+}
+
+/// Contents of the file `path` (`agentman cat`).
+pub async fn cat(workspace: &Path, path: &str) -> Result<String> {
+    let mut file = open(workspace, path).await?;
+    let shown = sandbox_path(path);
+    let mut content = Vec::new();
+    // Read one byte past the limit, so a file that grew after the check is still caught.
+    (&mut file)
+        .take(MAX_CAT_BYTES + 1)
+        .read_to_end(&mut content)
+        .await
+        .with_context(|| format!("Failed to read {shown}"))?;
+    if content.len() as u64 > MAX_CAT_BYTES {
+        bail!(
+            "{shown} is larger than {} MiB; use `agentman tail -n <lines> {path}`",
+            MAX_CAT_BYTES / (1024 * 1024)
+        );
+    }
+    if is_binary(&content) {
+        bail!("{shown} looks like a binary file");
+    }
+    Ok(String::from_utf8_lossy(&content).into_owned())
+}
+
+/// The last `lines` lines of the file `path` (`agentman tail`), and the size they end at.
+pub async fn tail(workspace: &Path, path: &str, lines: usize) -> Result<(String, u64)> {
+    let mut file = open(workspace, path).await?;
+    let shown = sandbox_path(path);
+    let len = file.metadata().await?.len();
+    let start = len.saturating_sub(TAIL_WINDOW);
+    file.seek(std::io::SeekFrom::Start(start)).await?;
+    let mut window = Vec::new();
+    (&mut file)
+        .take(len - start)
+        .read_to_end(&mut window)
+        .await
+        .with_context(|| format!("Failed to read {shown}"))?;
+    if is_binary(&window) {
+        bail!("{shown} looks like a binary file");
+    }
+    let end = start + window.len() as u64;
+    // A final newline ends the last line rather than starting an empty one.
+    let body = window.strip_suffix(b"\n").unwrap_or(&window);
+    let from = body
+        .iter()
+        .enumerate()
+        .rev()
+        .filter(|(_, b)| **b == b'\n')
+        .nth(lines.saturating_sub(1))
+        .map_or(0, |(i, _)| i + 1);
+    let from = if lines == 0 { window.len() } else { from };
+    Ok((String::from_utf8_lossy(&window[from..]).into_owned(), end))
+}
+
+fn is_binary(content: &[u8]) -> bool {
+    content[..content.len().min(8192)].contains(&0)
+}
+
+/// Reads what is appended to a file (`agentman tail -f`). Like `tail -F`, it follows the path:
+/// a file that is replaced (log rotation) or truncated is read again from the start, and one
+/// that disappears is waited for.
+pub struct Follower {
+    workspace: PathBuf,
+    path: String,
+    offset: u64,
+    inode: Option<u64>,
+}
+
+impl Follower {
+    /// Follow `path` from `offset` (where [`tail`] stopped).
+    pub fn new(workspace: PathBuf, path: String, offset: u64) -> Self {
+        Self {
+            workspace,
+            path,
+            offset,
+            inode: None,
+        }
+    }
+
+    /// What was appended since the last call, with a note first if the file was replaced or
+    /// truncated; empty when nothing changed. Errors if the path stops being a regular file in
+    /// the workspace (e.g. it was replaced with a symlink).
+    pub async fn read(&mut self) -> Result<Vec<u8>> {
+        let missing = tokio::fs::symlink_metadata(self.workspace.join(relative(&self.path)?))
+            .await
+            .is_err_and(|e| e.kind() == std::io::ErrorKind::NotFound);
+        if missing {
+            return Ok(Vec::new());
+        }
+        let mut file = open(&self.workspace, &self.path).await?;
+        let shown = sandbox_path(&self.path);
+        let meta = file.metadata().await?;
+        let mut out = Vec::new();
+        if self.inode.is_some_and(|inode| inode != meta.ino()) {
+            out.extend(format!("agentman: {shown} was replaced; following the new file\n").bytes());
+            self.offset = 0;
+        } else if meta.len() < self.offset {
+            out.extend(format!("agentman: {shown} was truncated\n").bytes());
+            self.offset = 0;
+        }
+        self.inode = Some(meta.ino());
+        if meta.len() > self.offset {
+            file.seek(std::io::SeekFrom::Start(self.offset)).await?;
+            let read = (&mut file)
+                .take(FOLLOW_CHUNK)
+                .read_to_end(&mut out)
+                .await
+                .with_context(|| format!("Failed to read {shown}"))?;
+            self.offset += read as u64;
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_workspace(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("agentman-file-view-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("logs")).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_open() {
+        let ws = temp_workspace("open");
+        std::fs::write(ws.join("logs/app.log"), "ok\n").unwrap();
+        std::fs::write(ws.join("secret"), "host\n").unwrap();
+        std::os::unix::fs::symlink("/etc", ws.join("etc")).unwrap();
+        std::os::unix::fs::symlink("app.log", ws.join("logs/current")).unwrap();
+        std::os::unix::fs::symlink("../secret", ws.join("logs/secret")).unwrap();
+        std::os::unix::fs::symlink("logs", ws.join("logs-link")).unwrap();
+
+        let ino = std::fs::metadata(ws.join("logs/app.log")).unwrap().ino();
+        for path in ["/workspace/logs/app.log", "logs/./app.log"] {
+            let file = open(&ws, path).await.unwrap();
+            assert_eq!(file.metadata().await.unwrap().ino(), ino, "{path}");
+        }
+        for (path, error) in [
+            ("/etc/passwd", "outside /workspace"),
+            ("/workspacex/app.log", "outside /workspace"),
+            ("logs/../../secret", "can't contain .."),
+            // A symlinked intermediate component, to a host directory or inside the workspace.
+            ("etc/passwd", "goes through a symlink (etc)"),
+            ("logs-link/app.log", "goes through a symlink (logs-link)"),
+            // A symlinked final component.
+            ("logs/current", "goes through a symlink (current)"),
+            ("logs/secret", "goes through a symlink (secret)"),
+            ("logs", "is a directory"),
+            ("logs/missing.log", "no such file"),
+        ] {
+            let e = open(&ws, path).await.unwrap_err().to_string();
+            assert!(e.contains(error), "{path}: {e}");
+        }
+        std::fs::remove_dir_all(&ws).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cat_and_tail() {
+        let ws = temp_workspace("tail");
+        let lines: String = (1..=20).map(|i| format!("line {i}\n")).collect();
+        std::fs::write(ws.join("logs/app.log"), &lines).unwrap();
+        std::fs::write(ws.join("blob.bin"), b"\0\x01\x02").unwrap();
+
+        assert_eq!(cat(&ws, "logs/app.log").await.unwrap(), lines);
+        let e = cat(&ws, "blob.bin").await.unwrap_err().to_string();
+        assert!(e.contains("binary"), "{e}");
+
+        let (text, end) = tail(&ws, "logs/app.log", 2).await.unwrap();
+        assert_eq!(text, "line 19\nline 20\n");
+        assert_eq!(end, lines.len() as u64);
+        assert_eq!(tail(&ws, "logs/app.log", 50).await.unwrap().0, lines);
+        assert_eq!(tail(&ws, "logs/app.log", 0).await.unwrap().0, "");
+        std::fs::remove_dir_all(&ws).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_follower() {
+        let ws = temp_workspace("follow");
+        let log = ws.join("logs/app.log");
+        std::fs::write(&log, "one\n").unwrap();
+        let mut follower = Follower::new(ws.clone(), "logs/app.log".to_string(), 4);
+        assert_eq!(follower.read().await.unwrap(), b"");
+
+        std::fs::write(&log, "one\ntwo\n").unwrap();
+        assert_eq!(follower.read().await.unwrap(), b"two\n");
+
+        std::fs::write(&log, "").unwrap();
+        assert_eq!(
+            String::from_utf8(follower.read().await.unwrap()).unwrap(),
+            "agentman: /workspace/logs/app.log was truncated\n"
+        );
+
+        // Rotated away: wait for the new file, then read it from the start.
+        std::fs::rename(&log, ws.join("logs/app.log.1")).unwrap();
+        assert_eq!(follower.read().await.unwrap(), b"");
+        std::fs::write(&log, "fresh\n").unwrap();
+        assert_eq!(
+            String::from_utf8(follower.read().await.unwrap()).unwrap(),
+            "agentman: /workspace/logs/app.log was replaced; following the new file\nfresh\n"
+        );
+
+        std::fs::remove_file(&log).unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", &log).unwrap();
+        assert!(follower.read().await.is_err());
+        std::fs::remove_dir_all(&ws).unwrap();
+    }
+}
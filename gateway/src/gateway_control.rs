@@ -4,20 +4,72 @@
 //! small, stable control surface for lifecycle operations like destroying a workspace.
 
 use bollard::errors::Error as BollardError;
+use bollard::models::ContainerInspectResponse;
 use bollard::query_parameters::{
-    InspectContainerOptions, StatsOptionsBuilder, StopContainerOptionsBuilder,
+    InspectContainerOptions, InspectContainerOptionsBuilder, StatsOptionsBuilder,
+    StopContainerOptionsBuilder,
 };
-use crate::docker::{ContainerManager, DestroyOptions};
-use chrono::DateTime;
+use crate::accounting::{self, ReportFormat};
+use crate::audit;
+use crate::audit_store;
+use crate::backend::ContainerBackend;
+use crate::backup;
+use crate::bench;
+use crate::build;
+use crate::checkpoint;
+use crate::config::{LoggingConfig, ShellConfig, ShellMode, SniRouterConfig, UserQuota};
+use crate::editor_cache;
+use crate::egress::{self, Ledger};
+use crate::env_file;
+use crate::file_view;
+use crate::docker::{
+    parse_memory_limit, project_network_name, read_init_log, ContainerManager, DestroyOptions,
+    QuotaUsage, RecreateOptions, IMAGE_CHOICE_LABEL,
+};
+use crate::git_identity;
+use crate::github::{validate_github_username, validate_project_name};
+use crate::github_metadata;
+use crate::guest;
+use crate::inspect_cache::InspectCache;
+use crate::i18n::{self, Lang};
+use crate::jobs;
+use crate::journal;
+use crate::log_level;
+use crate::mesh;
+use crate::migration;
+use crate::motd;
+use crate::notify;
+use crate::offboarding;
+use crate::open::{self, OpenTarget};
+use crate::ownership;
+use crate::preferences::{self, Key as PreferenceKey};
+use crate::preview;
+use crate::provision;
+use crate::proxy;
+use crate::relay;
+use crate::scan;
+use crate::search;
+use crate::security_profiles::PROFILE_LABEL;
+use crate::sni_router;
+use crate::state_snapshot;
+use crate::stack;
+use crate::stats_history;
+use crate::tmux_clients;
+use crate::user_notify;
+use crate::state::{InitState, Motd, Notifier, WorkspaceInfo, WorkspaceResources};
+use crate::workspace_size::{self, du_bytes};
+use std::sync::atomic::{AtomicBool, Ordering};
+use chrono::{DateTime, Utc};
 use futures::{StreamExt, future::join_all};
-use std::path::Path;
-use tokio::process::Command;
+use std::path::{Path, PathBuf};
 use tokio::time::{timeout, Duration};
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub(crate) enum GatewayControlCommand {
     Help,
     Destroy {
+        /// Another of the user's projects (`--project`) instead of the connected one.
+        project: Option<String>,
         yes: bool,
         keep_workspace: bool,
         dry_run: bool,
@@ -25,16 +77,407 @@ pub(crate) enum GatewayControlCommand {
     },
     ExecList,
     ExecStop,
+    /// Stop and start the sandbox's container again (kept, unlike `Recreate`).
+    Restart,
     ExecPause,
     ExecStats { current: bool, watch: bool },
+    StatsHistory { current: bool, window_secs: u64 },
+    Link { project: Option<String> },
+    Unlink { project: String },
+    Status,
+    /// Effective security and resource settings of the sandbox, from `docker inspect`.
+    Limits,
+    /// Disk usage of the sandbox, split by what `recreate` and `destroy` would free.
+    Storage,
+    /// Quick CPU, disk and network benchmark inside the sandbox.
+    Bench { json: bool },
+    /// Round trips to the client, the Docker daemon and the sandbox.
+    Ping { count: u32 },
+    /// Look for escaping symlinks, writable setuid files and large files in the workspace.
+    Scan,
+    /// Find text in the workspace, another of the user's (`project`) or all of them (`all`).
+    Search {
+        pattern: String,
+        project: Option<String>,
+        all: bool,
+        ignore_case: bool,
+    },
+    /// Connection snippets for editors and ssh.
+    Open { target: OpenTarget },
+    InitShow,
+    InitSet { command: String },
+    InitClear,
+    InitRun,
+    InitLog,
+    Wait { timeout_secs: u64, port: Option<u16> },
+    Backup { action: BackupAction },
+    Recreate { keep_running: bool, pull: bool },
+    /// Experimental CRIU checkpoint of the sandbox, or resume from it.
+    Checkpoint { action: CheckpointAction },
+    /// Per-project image selection among the gateway's allowlist.
+    Image { action: ImageAction },
+    /// Per-workspace `/dev/shm` size and ulimits.
+    Resize { action: ResizeAction },
+    /// Build an image from a Dockerfile in the workspace; `use_image` also selects it.
+    Build {
+        file: Option<String>,
+        no_cache: bool,
+        use_image: bool,
+    },
+    /// The port the SNI router sends this workspace's TLS connections to.
+    Tls { action: TlsAction },
+    /// The workspace's ownership reconciler.
+    Ownership { action: OwnershipAction },
+    /// Expiring HTTP preview links to ports in the sandbox.
+    Expose { action: ExposeAction },
+    /// The language of the gateway's messages to the user.
+    Lang { action: LangAction },
+    /// The user's own preferences.
+    Config { action: ConfigAction },
+    /// The project's login message.
+    Motd { action: MotdAction },
+    /// Which other projects this project's sandbox may run commands in.
+    Mesh { action: MeshAction },
+    CacheShow,
+    CachePrune { older_than_days: Option<u64> },
+    Tunnel,
+    Proxy,
+    /// Terminals attached to the shared tmux sessions of the user's workspaces.
+    Sessions,
+    /// The git author identity written into the user's sandboxes.
+    GitIdentity { action: GitIdentityAction },
+    /// Time-limited access to the project for someone else.
+    Guest { action: GuestAction },
+    /// Keys the user logged in with (`[trust]`).
+    Trust { action: TrustAction },
+    /// The dotenv file the gateway injects into the workspace's execs and containers.
+    EnvFile { action: EnvFileAction },
+    /// Messages to the user's own channel (webhook or email).
+    Notify { action: NotifyAction },
+    /// Run a command in the sandbox like a plain exec, with a time limit; `detach` starts it as
+    /// a background job instead.
+    Run {
+        timeout_secs: Option<u64>,
+        detach: bool,
+        command: String,
+    },
+    Jobs { action: JobsAction },
+    /// Print a workspace file.
+    Cat { path: String },
+    /// Print the end of a workspace file; `follow` keeps printing what is appended to it.
+    Tail {
+        path: String,
+        lines: usize,
+        follow: bool,
+    },
+    Stack { action: StackAction },
+    /// Create a new project rendered from a cookiecutter-style template.
+    New { project: String, from: String },
+    /// A shell in a throwaway sandbox that is removed on disconnect (handled by the SSH server).
+    Ephemeral,
+    /// Re-check the connection's uncached keys against GitHub (handled by the SSH server).
+    Verify { github_user: Option<String> },
+    /// The port forwarding policy and forwards of this connection (handled by the SSH server).
+    Route,
+    /// Give a project to another user; `owner` (`<owner>/<project>`) is for admins.
+    Transfer {
+        owner: Option<String>,
+        project: String,
+        to: String,
+    },
+    /// `agentman admin offboard [<user> [--cancel]]`.
+    AdminOffboard { user: Option<String>, cancel: bool },
+    /// `agentman admin users`: every known user and their footprint.
+    AdminUsers,
+    /// `agentman admin audit usage`: disk used by each user's audit logs.
+    AdminAuditUsage,
+    /// `agentman admin usage [--month <YYYY-MM>] [--csv|--json]`: every user's resource usage
+    /// in a month (the current one by default), for chargeback.
+    AdminUsage {
+        month: Option<String>,
+        format: ReportFormat,
+    },
+    /// `agentman admin state export`: a snapshot of the state store.
+    AdminStateExport,
+    /// `agentman admin state import <path> [--yes]`: replace the state store with a snapshot
+    /// on the gateway host; without `yes` it is only checked.
+    AdminStateImport { path: PathBuf, yes: bool },
+    /// `agentman admin migrate <user>/<project> --to <node> [--checkpoint]`: move a workspace
+    /// to another gateway.
+    AdminMigrate {
+        owner: String,
+        project: String,
+        node: String,
+        checkpoint: bool,
+    },
+    /// `agentman admin log-level [<level> [--target <module>] | reset]`.
+    AdminLogLevel { action: LogLevelAction },
+    /// `agentman admin operations [resume|rollback <user>/<project>]`: destroys, recreates
+    /// and migrations in progress or stopped half-way.
+    AdminOperations { action: OperationsAction },
+    /// `agentman admin access <user>/<project> --reason <text>`: let the admin log in to
+    /// another user's project for a while.
+    AdminAccess {
+        owner: String,
+        project: String,
+        reason: String,
+    },
+}
+
+impl GatewayControlCommand {
+    /// Whether the command only reports on containers, so it may answer from the connection's
+    /// recent inspections ([`InspectCache`]).
+    fn reports_only(&self) -> bool {
+        matches!(
+            self,
+            Self::Status | Self::ExecList | Self::ExecStats { watch: false, .. } | Self::Limits
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum LogLevelAction {
+    Show,
+    Set {
+        level: String,
+        target: Option<String>,
+    },
+    Reset,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum OperationsAction {
+    List,
+    Resume { owner: String, project: String },
+    Rollback { owner: String, project: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum BackupAction {
+    Create,
+    List,
+    Restore { spec: String, yes: bool },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum CheckpointAction {
+    Create { leave_running: bool },
+    Resume,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum JobsAction {
+    List,
+    Logs { id: String, follow: bool },
+    Kill { id: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum GitIdentityAction {
+    Show,
+    /// Missing parts come from the user's GitHub profile.
+    Set {
+        name: Option<String>,
+        email: Option<String>,
+    },
+    Clear,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum GuestAction {
+    /// `ttl_secs` defaults to `[guests] default_ttl_secs`.
+    Invite {
+        ttl_secs: Option<u64>,
+        read_only: bool,
+    },
+    List,
+    /// Revoke one invite, or (with `None`) all of the user's invites.
+    Revoke { id: Option<String> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TrustAction {
+    List,
+    /// Refuse logins with the device's key.
+    Revoke { id: u32 },
+    /// Accept a revoked device again.
+    Allow { id: u32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EnvFileAction {
+    /// Variable names only; values are never printed.
+    Show,
+    /// Replace the file with the exec's input (read by the SSH server).
+    Set,
+    Clear,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum NotifyAction {
+    Show,
+    Set(Notifier),
+    Clear,
+    Send { message: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ImageAction {
+    Show,
+    List,
+    /// Record a choice; `now` recreates the container right away instead of at the next start.
+    Set { image: String, now: bool },
+    Reset { now: bool },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ResizeAction {
+    Show,
+    /// Change the given settings; `now` recreates the container right away instead of at the
+    /// next start.
+    Set {
+        shm_size: Option<String>,
+        nofile: Option<u64>,
+        nproc: Option<u64>,
+        now: bool,
+    },
+    Reset { now: bool },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TlsAction {
+    Show,
+    Expose { port: u16 },
+    Close,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum LangAction {
+    Show,
+    List,
+    /// Checked when run, so an unknown code is reported in the user's language.
+    Set { code: String },
+    Reset,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum MotdAction {
+    Show,
+    Set { text: String },
+    Clear,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum MeshAction {
+    Show,
+    Allow { target: String },
+    Deny { target: String },
+}
+
+/// Keys and values are checked when run, so `agentman config` can list the valid ones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ConfigAction {
+    List,
+    Get { key: String },
+    Set { key: String, value: String },
+    Unset { key: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ExposeAction {
+    Preview { port: u16, ttl_secs: u64 },
+    List,
+    /// Revoke one link, or (with `None`) all of the user's links.
+    Revoke { id: Option<String> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OwnershipAction {
+    Show,
+    Watch,
+    Unwatch,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StackAction {
+    Status,
+    Up,
+    Down,
 }
 
 #[derive(Debug)]
 pub(crate) enum GatewayControlExecution {
     Immediate { exit_status: u32, output: String },
     WatchStats { current: bool, interval: Duration },
+    Wait { timeout: Duration, port: Option<u16> },
+    Backup { action: BackupAction },
+    Recreate { keep_running: bool, pull: bool },
+    Restart,
+    /// `agentman checkpoint|resume` (CRIU dumps and restores can take minutes).
+    Checkpoint { action: CheckpointAction },
+    /// `agentman admin migrate` (copies the whole workspace to another gateway).
+    Migrate {
+        owner: String,
+        project: String,
+        node: String,
+        checkpoint: bool,
+    },
+    /// `agentman admin operations resume` (runs the rest of a destroy, recreate or migration).
+    ResumeOperation { owner: String, project: String },
+    /// `agentman stack up|down` (may pull images and wait for health checks).
+    Stack { action: StackAction },
+    /// `agentman scan` (walks the whole workspace).
+    Scan,
+    /// `agentman search` (runs in several sandboxes or walks their workspaces).
+    Search {
+        pattern: String,
+        project: Option<String>,
+        all: bool,
+        ignore_case: bool,
+    },
+    /// `agentman build` (runs a Docker build, up to `[build] timeout_secs`).
+    Build {
+        file: Option<String>,
+        no_cache: bool,
+        use_image: bool,
+    },
+    /// `agentman ping`; the SSH server fills in `client` (keepalive round trips, `None` for
+    /// unanswered ones) before running it.
+    Ping {
+        count: u32,
+        client: Vec<Option<Duration>>,
+    },
+    /// Stream a detached job's output until it exits (`agentman jobs logs --follow`).
+    FollowJob { workspace: PathBuf, id: String },
+    /// Send `output` (the file's last lines), then stream what is appended to the workspace
+    /// file from `offset` on (`agentman tail -f`).
+    FollowFile {
+        workspace: PathBuf,
+        path: String,
+        output: String,
+        offset: u64,
+    },
 }
 
+/// `agentman cache prune` removes editor server versions untouched for this many days.
+const DEFAULT_CACHE_PRUNE_DAYS: u64 = 30;
+
+/// Containers `agentman list` inspects at once.
+const LIST_CONCURRENCY: usize = 8;
+
+/// Round trips `agentman ping` measures to each hop, unless `--count` says otherwise.
+const DEFAULT_PING_COUNT: u32 = 3;
+const MAX_PING_COUNT: u32 = 20;
+
+/// How long `agentman ping` waits for each answer.
+pub(crate) const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Exit status of `agentman wait` when the timeout expires (matches coreutils `timeout`).
+pub(crate) const WAIT_TIMEOUT_EXIT_STATUS: u32 = 124;
+
+/// Exit status of an exec killed for exceeding its time limit (also matches `timeout`).
+pub(crate) const EXEC_TIMEOUT_EXIT_STATUS: u32 = 124;
+
 pub(crate) fn parse_gateway_control_command(cmd: &str) -> Option<GatewayControlCommand> {
     let mut it = cmd.split_whitespace();
     let first = it.next()?;
@@ -42,160 +485,1514 @@ pub(crate) fn parse_gateway_control_command(cmd: &str) -> Option<GatewayControlC
         return None;
     }
 
-    let sub = it.next().unwrap_or("help");
+    let args: Vec<&str> = it.collect();
+    // Everything after ` -- ` is passed through verbatim (keeps the user's quoting intact).
+    let raw_tail = cmd.split_once(" -- ").map(|(_, tail)| tail.trim());
+    Some(parse_subcommand(&args, raw_tail))
+}
+
+fn parse_subcommand(args: &[&str], raw_tail: Option<&str>) -> GatewayControlCommand {
+    let sub = args.first().copied().unwrap_or("help");
+    let rest = args.get(1..).unwrap_or_default();
     match sub {
-        "help" | "--help" | "-h" => Some(GatewayControlCommand::Help),
-        "list" => {
-            if it.next().is_some() {
-                Some(GatewayControlCommand::Help)
-            } else {
-                Some(GatewayControlCommand::ExecList)
+        "help" | "--help" | "-h" => GatewayControlCommand::Help,
+        "list" => no_args(rest, GatewayControlCommand::ExecList),
+        "stop" => no_args(rest, GatewayControlCommand::ExecStop),
+        "restart" => no_args(rest, GatewayControlCommand::Restart),
+        "pause" => no_args(rest, GatewayControlCommand::ExecPause),
+        "stats" => {
+            let mut current = false;
+            let mut watch = false;
+            let mut history = None;
+            let mut it = rest.iter();
+            while let Some(arg) = it.next() {
+                match *arg {
+                    "--current" | "--curennt" => current = true,
+                    "--watch" | "-w" => watch = true,
+                    "--history" => match it.next().and_then(|w| stats_history::parse_window(w)) {
+                        Some(secs) => history = Some(secs),
+                        None => return GatewayControlCommand::Help,
+                    },
+                    "--help" | "-h" => return GatewayControlCommand::Help,
+                    _ => return GatewayControlCommand::Help,
+                }
+            }
+            match history {
+                Some(_) if watch => GatewayControlCommand::Help,
+                Some(window_secs) => GatewayControlCommand::StatsHistory {
+                    current,
+                    window_secs,
+                },
+                None => GatewayControlCommand::ExecStats { current, watch },
             }
         }
-        "stop" => {
-            if it.next().is_some() {
-                Some(GatewayControlCommand::Help)
+        // `agentman exec <cmd>` is an alias for `agentman <cmd>`.
+        "exec" => {
+            if rest.is_empty() {
+                GatewayControlCommand::Help
             } else {
-                Some(GatewayControlCommand::ExecStop)
+                parse_subcommand(rest, raw_tail)
             }
         }
-        "pause" => {
-            if it.next().is_some() {
-                Some(GatewayControlCommand::Help)
-            } else {
-                Some(GatewayControlCommand::ExecPause)
+        "link" => match rest {
+            [] => GatewayControlCommand::Link { project: None },
+            [p] if !p.starts_with('-') => GatewayControlCommand::Link {
+                project: Some(p.to_string()),
+            },
+            _ => GatewayControlCommand::Help,
+        },
+        "unlink" => match rest {
+            [p] if !p.starts_with('-') => GatewayControlCommand::Unlink {
+                project: p.to_string(),
+            },
+            _ => GatewayControlCommand::Help,
+        },
+        "status" => no_args(rest, GatewayControlCommand::Status),
+        "limits" => no_args(rest, GatewayControlCommand::Limits),
+        "storage" => no_args(rest, GatewayControlCommand::Storage),
+        "bench" => match rest {
+            [] => GatewayControlCommand::Bench { json: false },
+            ["--json"] => GatewayControlCommand::Bench { json: true },
+            _ => GatewayControlCommand::Help,
+        },
+        "ping" => match rest {
+            [] => GatewayControlCommand::Ping {
+                count: DEFAULT_PING_COUNT,
+            },
+            ["-c" | "--count", n] => match n.parse() {
+                Ok(count) if (1..=MAX_PING_COUNT).contains(&count) => {
+                    GatewayControlCommand::Ping { count }
+                }
+                _ => GatewayControlCommand::Help,
+            },
+            _ => GatewayControlCommand::Help,
+        },
+        "scan" => no_args(rest, GatewayControlCommand::Scan),
+        "search" => parse_search(rest, raw_tail).unwrap_or(GatewayControlCommand::Help),
+        "open" => match rest {
+            [] => GatewayControlCommand::Open {
+                target: OpenTarget::All,
+            },
+            [name] => match OpenTarget::parse(name) {
+                Some(target) => GatewayControlCommand::Open { target },
+                None => GatewayControlCommand::Help,
+            },
+            _ => GatewayControlCommand::Help,
+        },
+        "tunnel" => no_args(rest, GatewayControlCommand::Tunnel),
+        "proxy" => no_args(rest, GatewayControlCommand::Proxy),
+        "sessions" => no_args(rest, GatewayControlCommand::Sessions),
+        "ephemeral" => no_args(rest, GatewayControlCommand::Ephemeral),
+        "route" => no_args(rest, GatewayControlCommand::Route),
+        "verify" => match rest {
+            [] => GatewayControlCommand::Verify { github_user: None },
+            [user] if !user.starts_with('-') => GatewayControlCommand::Verify {
+                github_user: Some(user.to_string()),
+            },
+            _ => GatewayControlCommand::Help,
+        },
+        "git-identity" => {
+            let action = match rest {
+                [] | ["show"] => GitIdentityAction::Show,
+                ["clear"] => GitIdentityAction::Clear,
+                ["set", flags @ ..] => match parse_git_identity_flags(flags) {
+                    Some((name, email)) => GitIdentityAction::Set { name, email },
+                    None => return GatewayControlCommand::Help,
+                },
+                _ => return GatewayControlCommand::Help,
+            };
+            GatewayControlCommand::GitIdentity { action }
+        }
+        "env-file" => {
+            let action = match rest {
+                [] | ["show"] => EnvFileAction::Show,
+                ["set"] => EnvFileAction::Set,
+                ["clear"] => EnvFileAction::Clear,
+                _ => return GatewayControlCommand::Help,
+            };
+            GatewayControlCommand::EnvFile { action }
+        }
+        "notify" => {
+            let action = match rest {
+                [] | ["show"] => NotifyAction::Show,
+                ["set", "webhook", url] => NotifyAction::Set(Notifier::Webhook {
+                    url: url.to_string(),
+                }),
+                ["set", "email", address] => NotifyAction::Set(Notifier::Email {
+                    address: address.to_string(),
+                }),
+                ["set", ..] => return GatewayControlCommand::Help,
+                ["clear"] => NotifyAction::Clear,
+                ["--"] => return GatewayControlCommand::Help,
+                // `--` sends words that would otherwise be subcommands.
+                ["--", words @ ..] | words if !words.is_empty() => NotifyAction::Send {
+                    message: user_notify::message_from_args(words),
+                },
+                _ => return GatewayControlCommand::Help,
+            };
+            GatewayControlCommand::Notify { action }
+        }
+        "guest" => {
+            let action = match rest {
+                [] | ["list"] => GuestAction::List,
+                ["invite", flags @ ..] => {
+                    let mut ttl_secs = None;
+                    let mut read_only = false;
+                    let mut it = flags.iter();
+                    while let Some(flag) = it.next() {
+                        match *flag {
+                            "--ttl" => match it.next().and_then(|v| guest::parse_ttl(v)) {
+                                Some(secs) => ttl_secs = Some(secs),
+                                None => return GatewayControlCommand::Help,
+                            },
+                            "--read-only" | "-r" => read_only = true,
+                            _ => return GatewayControlCommand::Help,
+                        }
+                    }
+                    GuestAction::Invite {
+                        ttl_secs,
+                        read_only,
+                    }
+                }
+                ["revoke", "--all"] => GuestAction::Revoke { id: None },
+                ["revoke", id] if !id.starts_with('-') => GuestAction::Revoke {
+                    id: Some(id.to_string()),
+                },
+                _ => return GatewayControlCommand::Help,
+            };
+            GatewayControlCommand::Guest { action }
+        }
+        "trust" => {
+            let action = match rest {
+                [] | ["list"] => TrustAction::List,
+                ["revoke", id] => match id.parse() {
+                    Ok(id) => TrustAction::Revoke { id },
+                    Err(_) => return GatewayControlCommand::Help,
+                },
+                ["allow", id] => match id.parse() {
+                    Ok(id) => TrustAction::Allow { id },
+                    Err(_) => return GatewayControlCommand::Help,
+                },
+                _ => return GatewayControlCommand::Help,
+            };
+            GatewayControlCommand::Trust { action }
+        }
+        "wait" => {
+            let mut timeout_secs = 120;
+            let mut port = None;
+            let mut it = rest.iter();
+            while let Some(arg) = it.next() {
+                let parsed = match *arg {
+                    "--timeout" | "-t" => it.next().and_then(|v| v.parse().ok()).map(|v| timeout_secs = v),
+                    "--port" | "-p" => it.next().and_then(|v| v.parse().ok()).map(|v| port = Some(v)),
+                    _ => None,
+                };
+                if parsed.is_none() {
+                    return GatewayControlCommand::Help;
+                }
             }
+            GatewayControlCommand::Wait { timeout_secs, port }
         }
-        "stats" => {
-            let mut current = false;
-            let mut watch = false;
-            for arg in it {
-                match arg {
-                    "--current" | "--curennt" => current = true,
-                    "--watch" | "-w" => watch = true,
-                    "--help" | "-h" => return Some(GatewayControlCommand::Help),
-                    _ => return Some(GatewayControlCommand::Help),
+        "run" => {
+            let mut timeout_secs = None;
+            let mut detach = false;
+            let mut it = rest.iter();
+            loop {
+                match it.next().copied() {
+                    Some("--detach" | "-d") => detach = true,
+                    Some("--timeout" | "-t") => {
+                        match it.next().and_then(|v| v.parse::<u64>().ok()) {
+                            Some(secs) if secs > 0 => timeout_secs = Some(secs),
+                            _ => return GatewayControlCommand::Help,
+                        }
+                    }
+                    Some("--") => break,
+                    _ => return GatewayControlCommand::Help,
                 }
             }
-            Some(GatewayControlCommand::ExecStats { current, watch })
+            match raw_tail {
+                Some(command) if !command.is_empty() => GatewayControlCommand::Run {
+                    timeout_secs,
+                    detach,
+                    command: command.to_string(),
+                },
+                _ => GatewayControlCommand::Help,
+            }
         }
-        "exec" => {
-            let action = it.next().unwrap_or("help");
-            match action {
-                "help" | "--help" | "-h" => Some(GatewayControlCommand::Help),
-                "list" => {
-                    if it.next().is_some() {
-                        Some(GatewayControlCommand::Help)
-                    } else {
-                        Some(GatewayControlCommand::ExecList)
+        "jobs" => {
+            let action = match rest {
+                [] | ["list"] => JobsAction::List,
+                ["logs", id] => JobsAction::Logs {
+                    id: id.to_string(),
+                    follow: false,
+                },
+                ["logs", id, "--follow" | "-f"] | ["logs", "--follow" | "-f", id] => {
+                    JobsAction::Logs {
+                        id: id.to_string(),
+                        follow: true,
                     }
                 }
-                "stop" => {
-                    if it.next().is_some() {
-                        Some(GatewayControlCommand::Help)
-                    } else {
-                        Some(GatewayControlCommand::ExecStop)
+                ["kill", id] => JobsAction::Kill { id: id.to_string() },
+                _ => return GatewayControlCommand::Help,
+            };
+            GatewayControlCommand::Jobs { action }
+        }
+        "cat" => match rest {
+            [path] if !path.starts_with('-') => GatewayControlCommand::Cat {
+                path: path.to_string(),
+            },
+            ["--", _, ..] => match raw_tail {
+                Some(path) => GatewayControlCommand::Cat {
+                    path: path.to_string(),
+                },
+                None => GatewayControlCommand::Help,
+            },
+            _ => GatewayControlCommand::Help,
+        },
+        "tail" => parse_tail(rest, raw_tail).unwrap_or(GatewayControlCommand::Help),
+        "transfer" => match rest {
+            [target, "--to", to] | ["--to", to, target]
+                if !target.starts_with('-') && !to.starts_with('-') =>
+            {
+                let (owner, project) = match target.split_once('/') {
+                    Some((owner, project)) => (Some(owner.to_string()), project),
+                    None => (None, *target),
+                };
+                GatewayControlCommand::Transfer {
+                    owner,
+                    project: project.to_string(),
+                    to: to.to_string(),
+                }
+            }
+            _ => GatewayControlCommand::Help,
+        },
+        "admin" => match rest {
+            ["users"] => GatewayControlCommand::AdminUsers,
+            ["audit", "usage"] => GatewayControlCommand::AdminAuditUsage,
+            ["usage", flags @ ..] => {
+                let mut month = None;
+                let mut format = ReportFormat::Table;
+                let mut it = flags.iter();
+                while let Some(flag) = it.next() {
+                    match *flag {
+                        "--month" => match it.next().and_then(|m| accounting::parse_month(m)) {
+                            Some(m) => month = Some(m),
+                            None => return GatewayControlCommand::Help,
+                        },
+                        "--csv" => format = ReportFormat::Csv,
+                        "--json" => format = ReportFormat::Json,
+                        _ => return GatewayControlCommand::Help,
                     }
                 }
-                "pause" => {
-                    if it.next().is_some() {
-                        Some(GatewayControlCommand::Help)
-                    } else {
-                        Some(GatewayControlCommand::ExecPause)
+                GatewayControlCommand::AdminUsage { month, format }
+            }
+            ["state", "export"] => GatewayControlCommand::AdminStateExport,
+            ["state", "import", path] | ["state", "import", path, "--yes"]
+                if !path.starts_with('-') =>
+            {
+                GatewayControlCommand::AdminStateImport {
+                    path: PathBuf::from(path),
+                    yes: rest.len() == 4,
+                }
+            }
+            ["migrate", target, flags @ ..] if !target.starts_with('-') => {
+                let Some((owner, project)) = target.split_once('/') else {
+                    return GatewayControlCommand::Help;
+                };
+                let mut node = None;
+                let mut checkpoint = false;
+                let mut it = flags.iter();
+                while let Some(flag) = it.next() {
+                    match *flag {
+                        "--to" => match it.next() {
+                            Some(to) if !to.starts_with('-') => node = Some(to.to_string()),
+                            _ => return GatewayControlCommand::Help,
+                        },
+                        "--checkpoint" => checkpoint = true,
+                        _ => return GatewayControlCommand::Help,
+                    }
+                }
+                match node {
+                    Some(node) => GatewayControlCommand::AdminMigrate {
+                        owner: owner.to_string(),
+                        project: project.to_string(),
+                        node,
+                        checkpoint,
+                    },
+                    None => GatewayControlCommand::Help,
+                }
+            }
+            ["access", target, "--reason", reason @ ..]
+                if !target.starts_with('-') && !reason.is_empty() =>
+            {
+                match target.split_once('/') {
+                    Some((owner, project)) if !owner.is_empty() && !project.is_empty() => {
+                        GatewayControlCommand::AdminAccess {
+                            owner: owner.to_string(),
+                            project: project.to_string(),
+                            reason: reason.join(" "),
+                        }
                     }
+                    _ => GatewayControlCommand::Help,
+                }
+            }
+            ["log-level"] => GatewayControlCommand::AdminLogLevel {
+                action: LogLevelAction::Show,
+            },
+            ["log-level", "reset"] => GatewayControlCommand::AdminLogLevel {
+                action: LogLevelAction::Reset,
+            },
+            ["log-level", level] | ["log-level", level, "--target", _]
+                if !level.starts_with('-') =>
+            {
+                GatewayControlCommand::AdminLogLevel {
+                    action: LogLevelAction::Set {
+                        level: level.to_string(),
+                        target: rest.get(3).map(|t| t.to_string()),
+                    },
+                }
+            }
+            ["operations"] => GatewayControlCommand::AdminOperations {
+                action: OperationsAction::List,
+            },
+            [
+                "operations",
+                action @ ("resume" | "rollback"),
+                target,
+            ] => {
+                let Some((owner, project)) = target.split_once('/') else {
+                    return GatewayControlCommand::Help;
+                };
+                let (owner, project) = (owner.to_string(), project.to_string());
+                GatewayControlCommand::AdminOperations {
+                    action: if *action == "resume" {
+                        OperationsAction::Resume { owner, project }
+                    } else {
+                        OperationsAction::Rollback { owner, project }
+                    },
+                }
+            }
+            ["offboard"] => GatewayControlCommand::AdminOffboard {
+                user: None,
+                cancel: false,
+            },
+            ["offboard", user] | ["offboard", user, "--cancel"] if !user.starts_with('-') => {
+                GatewayControlCommand::AdminOffboard {
+                    user: Some(user.to_string()),
+                    cancel: rest.len() == 3,
+                }
+            }
+            _ => GatewayControlCommand::Help,
+        },
+        "new" => match rest {
+            [project, "--from", from] | ["--from", from, project]
+                if !project.starts_with('-') && !from.starts_with('-') =>
+            {
+                GatewayControlCommand::New {
+                    project: project.to_string(),
+                    from: from.to_string(),
+                }
+            }
+            _ => GatewayControlCommand::Help,
+        },
+        "recreate" => {
+            let mut keep_running = false;
+            let mut pull = false;
+            for arg in rest {
+                match *arg {
+                    "--keep-running" => keep_running = true,
+                    "--pull" => pull = true,
+                    _ => return GatewayControlCommand::Help,
+                }
+            }
+            GatewayControlCommand::Recreate { keep_running, pull }
+        }
+        "checkpoint" => match rest {
+            [] => GatewayControlCommand::Checkpoint {
+                action: CheckpointAction::Create {
+                    leave_running: false,
+                },
+            },
+            ["--leave-running"] => GatewayControlCommand::Checkpoint {
+                action: CheckpointAction::Create {
+                    leave_running: true,
+                },
+            },
+            _ => GatewayControlCommand::Help,
+        },
+        "resume" => no_args(
+            rest,
+            GatewayControlCommand::Checkpoint {
+                action: CheckpointAction::Resume,
+            },
+        ),
+        "image" => match rest {
+            [] | ["show"] => GatewayControlCommand::Image {
+                action: ImageAction::Show,
+            },
+            ["list"] => GatewayControlCommand::Image {
+                action: ImageAction::List,
+            },
+            ["set", image] | ["set", image, "--now"] if !image.starts_with('-') => {
+                GatewayControlCommand::Image {
+                    action: ImageAction::Set {
+                        image: image.to_string(),
+                        now: rest.len() == 3,
+                    },
                 }
-                "stats" => {
-                    let mut current = false;
-                    let mut watch = false;
-                    for arg in it {
-                        match arg {
-                            "--current" | "--curennt" => current = true,
-                            "--watch" | "-w" => watch = true,
-                            "--help" | "-h" => return Some(GatewayControlCommand::Help),
-                            _ => return Some(GatewayControlCommand::Help),
+            }
+            ["reset"] | ["reset", "--now"] => GatewayControlCommand::Image {
+                action: ImageAction::Reset {
+                    now: rest.len() == 2,
+                },
+            },
+            _ => GatewayControlCommand::Help,
+        },
+        "resize" => {
+            let action = match rest {
+                [] | ["show"] => ResizeAction::Show,
+                ["reset"] | ["reset", "--now"] => ResizeAction::Reset {
+                    now: rest.len() == 2,
+                },
+                _ => {
+                    let (mut shm_size, mut nofile, mut nproc, mut now) = (None, None, None, false);
+                    let mut it = rest.iter();
+                    let limit = |value: Option<&&str>| value?.parse().ok().filter(|n| *n > 0);
+                    while let Some(arg) = it.next() {
+                        match *arg {
+                            "--shm" if shm_size.is_none() => match it.next() {
+                                Some(size) => shm_size = Some(size.to_string()),
+                                None => return GatewayControlCommand::Help,
+                            },
+                            "--nofile" if nofile.is_none() => match limit(it.next()) {
+                                Some(n) => nofile = Some(n),
+                                None => return GatewayControlCommand::Help,
+                            },
+                            "--nproc" if nproc.is_none() => match limit(it.next()) {
+                                Some(n) => nproc = Some(n),
+                                None => return GatewayControlCommand::Help,
+                            },
+                            "--now" => now = true,
+                            _ => return GatewayControlCommand::Help,
                         }
                     }
-                    Some(GatewayControlCommand::ExecStats { current, watch })
+                    if shm_size.is_none() && nofile.is_none() && nproc.is_none() {
+                        return GatewayControlCommand::Help;
+                    }
+                    ResizeAction::Set {
+                        shm_size,
+                        nofile,
+                        nproc,
+                        now,
+                    }
+                }
+            };
+            GatewayControlCommand::Resize { action }
+        }
+        "build" => {
+            let mut file = None;
+            let mut no_cache = false;
+            let mut use_image = false;
+            let mut it = rest.iter();
+            while let Some(arg) = it.next() {
+                match *arg {
+                    "--file" | "-f" => match it.next() {
+                        Some(path) if file.is_none() => file = Some(path.to_string()),
+                        _ => return GatewayControlCommand::Help,
+                    },
+                    "--no-cache" => no_cache = true,
+                    "--use" => use_image = true,
+                    _ => return GatewayControlCommand::Help,
                 }
-                _ => Some(GatewayControlCommand::Help),
             }
+            GatewayControlCommand::Build {
+                file,
+                no_cache,
+                use_image,
+            }
+        }
+        "tls" => {
+            let action = match rest {
+                [] | ["show"] => TlsAction::Show,
+                ["expose", port] => match port.parse() {
+                    Ok(port) if port > 0 => TlsAction::Expose { port },
+                    _ => return GatewayControlCommand::Help,
+                },
+                ["close"] => TlsAction::Close,
+                _ => return GatewayControlCommand::Help,
+            };
+            GatewayControlCommand::Tls { action }
+        }
+        "lang" => {
+            let action = match rest {
+                [] | ["show"] => LangAction::Show,
+                ["list"] => LangAction::List,
+                ["set", code] => LangAction::Set {
+                    code: code.to_string(),
+                },
+                ["reset"] => LangAction::Reset,
+                _ => return GatewayControlCommand::Help,
+            };
+            GatewayControlCommand::Lang { action }
+        }
+        "config" => {
+            let action = match rest {
+                [] | ["list"] => ConfigAction::List,
+                ["get", key] => ConfigAction::Get {
+                    key: key.to_string(),
+                },
+                ["set", key, value] => ConfigAction::Set {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                },
+                ["unset", key] => ConfigAction::Unset {
+                    key: key.to_string(),
+                },
+                _ => return GatewayControlCommand::Help,
+            };
+            GatewayControlCommand::Config { action }
+        }
+        "motd" => {
+            let action = match rest {
+                [] | ["show"] => MotdAction::Show,
+                ["set", words @ ..] if !words.is_empty() => MotdAction::Set {
+                    text: user_notify::message_from_args(words),
+                },
+                ["clear"] => MotdAction::Clear,
+                _ => return GatewayControlCommand::Help,
+            };
+            GatewayControlCommand::Motd { action }
+        }
+        "mesh" => {
+            let action = match rest {
+                [] | ["show"] => MeshAction::Show,
+                ["allow", target] => MeshAction::Allow {
+                    target: target.to_string(),
+                },
+                ["deny", target] => MeshAction::Deny {
+                    target: target.to_string(),
+                },
+                _ => return GatewayControlCommand::Help,
+            };
+            GatewayControlCommand::Mesh { action }
+        }
+        "expose" => {
+            let action = match rest {
+                [] | ["list"] => ExposeAction::List,
+                ["revoke", "--all"] => ExposeAction::Revoke { id: None },
+                ["revoke", id] if !id.starts_with('-') => ExposeAction::Revoke {
+                    id: Some(id.to_string()),
+                },
+                [port, "--preview", ttl] | ["--preview", ttl, port] => {
+                    match (port.parse(), guest::parse_ttl(ttl)) {
+                        (Ok(port), Some(ttl_secs)) if port > 0 => {
+                            ExposeAction::Preview { port, ttl_secs }
+                        }
+                        _ => return GatewayControlCommand::Help,
+                    }
+                }
+                _ => return GatewayControlCommand::Help,
+            };
+            GatewayControlCommand::Expose { action }
         }
+        "ownership" => {
+            let action = match rest {
+                [] | ["show"] => OwnershipAction::Show,
+                ["watch"] => OwnershipAction::Watch,
+                ["unwatch"] => OwnershipAction::Unwatch,
+                _ => return GatewayControlCommand::Help,
+            };
+            GatewayControlCommand::Ownership { action }
+        }
+        "cache" => match rest {
+            [] | ["show"] => GatewayControlCommand::CacheShow,
+            ["prune"] => GatewayControlCommand::CachePrune {
+                older_than_days: Some(DEFAULT_CACHE_PRUNE_DAYS),
+            },
+            ["prune", "--all"] => GatewayControlCommand::CachePrune {
+                older_than_days: None,
+            },
+            ["prune", "--older-than", days] => match days.trim_end_matches('d').parse() {
+                Ok(days) => GatewayControlCommand::CachePrune {
+                    older_than_days: Some(days),
+                },
+                Err(_) => GatewayControlCommand::Help,
+            },
+            _ => GatewayControlCommand::Help,
+        },
+        "stack" => match rest {
+            [] | ["status"] => GatewayControlCommand::Stack {
+                action: StackAction::Status,
+            },
+            ["up"] => GatewayControlCommand::Stack {
+                action: StackAction::Up,
+            },
+            ["down"] => GatewayControlCommand::Stack {
+                action: StackAction::Down,
+            },
+            _ => GatewayControlCommand::Help,
+        },
+        "backup" => match rest {
+            [] | ["create"] => GatewayControlCommand::Backup {
+                action: BackupAction::Create,
+            },
+            ["list"] => GatewayControlCommand::Backup {
+                action: BackupAction::List,
+            },
+            ["restore", spec] if !spec.starts_with('-') => GatewayControlCommand::Backup {
+                action: BackupAction::Restore {
+                    spec: spec.to_string(),
+                    yes: false,
+                },
+            },
+            ["restore", spec, "--yes" | "-y"] | ["restore", "--yes" | "-y", spec]
+                if !spec.starts_with('-') =>
+            {
+                GatewayControlCommand::Backup {
+                    action: BackupAction::Restore {
+                        spec: spec.to_string(),
+                        yes: true,
+                    },
+                }
+            }
+            _ => GatewayControlCommand::Help,
+        },
+        "init" => match rest {
+            [] | ["show"] => GatewayControlCommand::InitShow,
+            ["set", "--", ..] => match raw_tail {
+                Some(command) if !command.is_empty() => GatewayControlCommand::InitSet {
+                    command: command.to_string(),
+                },
+                _ => GatewayControlCommand::Help,
+            },
+            ["clear"] => GatewayControlCommand::InitClear,
+            ["run"] => GatewayControlCommand::InitRun,
+            ["log"] => GatewayControlCommand::InitLog,
+            _ => GatewayControlCommand::Help,
+        },
         "destroy" => {
             let mut yes = false;
             let mut keep_workspace = false;
             let mut dry_run = false;
             let mut force = false;
+            let mut project = None;
 
-            for arg in it {
-                match arg {
+            let mut args = rest.iter();
+            while let Some(arg) = args.next() {
+                match *arg {
+                    "--project" | "-p" if project.is_none() => match args.next() {
+                        Some(name) if !name.starts_with('-') => project = Some(name.to_string()),
+                        _ => return GatewayControlCommand::Help,
+                    },
                     "--yes" | "-y" => yes = true,
                     "--keep-workspace" => keep_workspace = true,
                     "--dry-run" => dry_run = true,
                     "--force" => force = true,
-                    "--help" | "-h" => return Some(GatewayControlCommand::Help),
+                    "--help" | "-h" => return GatewayControlCommand::Help,
                     _ => {
                         // Unknown args fall back to help (keeps behavior stable).
-                        return Some(GatewayControlCommand::Help);
+                        return GatewayControlCommand::Help;
                     }
                 }
             }
 
-            Some(GatewayControlCommand::Destroy {
+            GatewayControlCommand::Destroy {
+                project,
                 yes,
                 keep_workspace,
                 dry_run,
                 force,
-            })
+            }
         }
-        _ => Some(GatewayControlCommand::Help),
+        _ => GatewayControlCommand::Help,
     }
 }
 
-pub(crate) fn gateway_control_help_text() -> String {
-    // Keep this compatible with non-interactive SSH exec flows.
-    "\
-agentman gateway control commands
-
-Usage:
-  agentman destroy [--yes] [--keep-workspace] [--dry-run] [--force]
-  agentman list
-  agentman stop
-  agentman pause
-  agentman stats [--current] [--watch]
+/// `--name <words...>` (up to the next flag, since SSH joins the command line with spaces) and
+/// `--email <address>`, each at most once.
+fn parse_git_identity_flags(flags: &[&str]) -> Option<(Option<String>, Option<String>)> {
+    let mut name = None;
+    let mut email = None;
+    let mut it = flags.iter().peekable();
+    while let Some(flag) = it.next() {
+        match *flag {
+            "--name" if name.is_none() => {
+                let mut words = Vec::new();
+                while let Some(word) = it.next_if(|w| !w.starts_with("--")) {
+                    words.push(*word);
+                }
+                if words.is_empty() {
+                    return None;
+                }
+                name = Some(words.join(" "));
+            }
+            "--email" if email.is_none() => match it.next() {
+                Some(address) if !address.starts_with("--") => email = Some(address.to_string()),
+                _ => return None,
+            },
+            _ => return None,
+        }
+    }
+    Some((name, email))
+}
 
-Notes:
-  - Without --yes, destroy refuses to delete your persistent workspace directory.
-  - --keep-workspace stops/removes container(s) but keeps your files on disk.
-  - --dry-run prints what would be deleted.
-  - stop/pause apply to the *current* sandbox (the project in your SSH user).
-  - stats without --current shows all sandboxes for your GitHub user.
-  - --watch refreshes output every second (use Ctrl-C to exit).
-  - `agentman exec <cmd>` is accepted as an alias for these commands.
-"
-    .to_string()
+/// `tail [-n <lines>] [-f] <path>`, or with the path after ` -- `.
+fn parse_tail(rest: &[&str], raw_tail: Option<&str>) -> Option<GatewayControlCommand> {
+    let mut lines = file_view::DEFAULT_TAIL_LINES;
+    let mut follow = false;
+    let mut path = None;
+    let mut it = rest.iter();
+    while let Some(arg) = it.next() {
+        match *arg {
+            "--" => {
+                path = Some(raw_tail.filter(|_| path.is_none())?);
+                break;
+            }
+            "-n" | "--lines" => {
+                lines = it.next()?.parse().ok()?;
+                if lines > file_view::MAX_TAIL_LINES {
+                    return None;
+                }
+            }
+            "-f" | "--follow" => follow = true,
+            flag if flag.starts_with('-') => return None,
+            word if path.is_none() => path = Some(word),
+            _ => return None,
+        }
+    }
+    Some(GatewayControlCommand::Tail {
+        path: path?.to_string(),
+        lines,
+        follow,
+    })
 }
 
-pub(crate) async fn execute_gateway_control_command(
-    ctrl: GatewayControlCommand,
+/// `search [--project <name>|all] [-i] <text...>`, or with the text after ` -- ` (kept as
+/// typed). Words of the text are joined with single spaces, since SSH does that to the command
+/// line anyway; a text in matching quotes loses them.
+fn parse_search(rest: &[&str], raw_tail: Option<&str>) -> Option<GatewayControlCommand> {
+    let mut project = None;
+    let mut all = false;
+    let mut ignore_case = false;
+    let mut words = Vec::new();
+    let mut it = rest.iter();
+    while let Some(arg) = it.next() {
+        match *arg {
+            "--" => {
+                if !words.is_empty() {
+                    return None;
+                }
+                words.push(raw_tail?);
+                break;
+            }
+            "--project" | "-p" if project.is_none() && !all => match it.next() {
+                Some(&"all") => all = true,
+                Some(name) if !name.starts_with('-') => project = Some(name.to_string()),
+                _ => return None,
+            },
+            "--all" if project.is_none() => all = true,
+            "--ignore-case" | "-i" => ignore_case = true,
+            flag if flag.starts_with('-') => return None,
+            word => words.push(word),
+        }
+    }
+    let pattern = words.join(" ");
+    let pattern = ['"', '\'']
+        .iter()
+        .find_map(|q| pattern.strip_prefix(*q)?.strip_suffix(*q))
+        .unwrap_or(&pattern);
+    if pattern.is_empty() {
+        return None;
+    }
+    Some(GatewayControlCommand::Search {
+        pattern: pattern.to_string(),
+        project,
+        all,
+        ignore_case,
+    })
+}
+
+/// Commands that take no arguments fall back to help when given any.
+fn no_args(rest: &[&str], cmd: GatewayControlCommand) -> GatewayControlCommand {
+    if rest.is_empty() {
+        cmd
+    } else {
+        GatewayControlCommand::Help
+    }
+}
+
+/// Move `from_user/project` to `to_user`, queue a notice for the recipient and tell admins.
+async fn run_transfer(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    from_user: &str,
+    project: &str,
+    to_user: &str,
+) -> anyhow::Result<String> {
+    validate_github_username(from_user)?;
+    validate_project_name(project)?;
+    validate_github_username(to_user)?;
+    let result = container_manager
+        .transfer_workspace(from_user, project, to_user)
+        .await?;
+
+    let state = container_manager.state();
+    state
+        .add_notice(
+            to_user,
+            format!(
+                "{github_user} transferred project {project} (from {from_user}) to you on {}; connect with `ssh {project}@<gateway>`",
+                Utc::now().format("%Y-%m-%d")
+            ),
+        )
+        .await?;
+    notify::send(
+        &container_manager.config().notify,
+        "workspace.transferred",
+        &format!("agentman: {github_user} transferred {from_user}/{project} to {to_user}"),
+    )
+    .await;
+
+    let mut out = format!("agentman: transferred {project} from {from_user} to {to_user}\n");
+    if !result.removed_containers.is_empty() {
+        out.push_str(&format!(
+            "- removed containers: {}\n",
+            result.removed_containers.join(", ")
+        ));
+    }
+    out.push_str(&format!(
+        "- workspace moved to {}\n",
+        result.workspace_path.display()
+    ));
+    for warning in &result.warnings {
+        out.push_str(&format!("- warning: {warning}\n"));
+    }
+    out.push_str(&format!(
+        "- {to_user} gets a fresh container on first connect and a notice at their next login\n- existing backups stay under {from_user}\n"
+    ));
+    Ok(out)
+}
+
+/// Capabilities Docker grants by default, before `cap_drop`/`cap_add`.
+const DOCKER_DEFAULT_CAPS: &[&str] = &[
+    "AUDIT_WRITE",
+    "CHOWN",
+    "DAC_OVERRIDE",
+    "FOWNER",
+    "FSETID",
+    "KILL",
+    "MKNOD",
+    "NET_BIND_SERVICE",
+    "NET_RAW",
+    "SETFCAP",
+    "SETGID",
+    "SETPCAP",
+    "SETUID",
+    "SYS_CHROOT",
+];
+
+/// Normalize a capability name as Docker accepts it (`cap_net_admin`, `NET_ADMIN`, ...).
+fn capability_name(cap: &str) -> String {
+    let upper = cap.to_ascii_uppercase();
+    upper.strip_prefix("CAP_").map(str::to_string).unwrap_or(upper)
+}
+
+/// The user's `[quotas]` and how much of them their running sandboxes use.
+fn format_quota(quota: &UserQuota, usage: QuotaUsage) -> String {
+    let of = |max: Option<String>| max.unwrap_or_else(|| "unlimited".to_string());
+    let max_memory = quota
+        .max_memory
+        .as_deref()
+        .and_then(|m| parse_memory_limit(m).ok())
+        .map(|m| format_bytes(m as u64));
+    format!(
+        "quota (running sandboxes):\n- sandboxes: {} of {}\n- memory: {} of {}\n\
+         - cpus: {:.2} of {}\n",
+        usage.containers,
+        of(quota.max_containers.map(|n| n.to_string())),
+        format_bytes(usage.memory as u64),
+        of(max_memory),
+        usage.nano_cpus as f64 / 1e9,
+        of(quota.max_cpus.map(|cpus| format!("{cpus:.2}"))),
+    )
+}
+
+/// Render `agentman limits` from a container inspect.
+fn format_limits(info: &ContainerInspectResponse) -> String {
+    let host = info.host_config.clone().unwrap_or_default();
+    let security_opt = host.security_opt.clone().unwrap_or_default();
+    let yes_no = |b: bool| if b { "yes" } else { "no" };
+    let mut out = String::from("security:\n");
+    let profile = info
+        .config
+        .as_ref()
+        .and_then(|c| c.labels.as_ref())
+        .and_then(|labels| labels.get(PROFILE_LABEL));
+    if let Some(profile) = profile {
+        out.push_str(&format!("- profile: {profile}\n"));
+    }
+
+    let privileged = host.privileged.unwrap_or(false);
+    out.push_str(&format!("- privileged: {}\n", yes_no(privileged)));
+
+    let dropped: Vec<String> = host.cap_drop.iter().flatten().map(|c| capability_name(c)).collect();
+    let added: Vec<String> = host.cap_add.iter().flatten().map(|c| capability_name(c)).collect();
+    let caps = if privileged || added.iter().any(|c| c == "ALL") {
+        "all".to_string()
+    } else {
+        let mut caps: Vec<String> = if dropped.iter().any(|c| c == "ALL") {
+            Vec::new()
+        } else {
+            DOCKER_DEFAULT_CAPS
+                .iter()
+                .map(|c| c.to_string())
+                .filter(|c| !dropped.contains(c))
+                .collect()
+        };
+        for cap in &added {
+            if !caps.contains(cap) {
+                caps.push(cap.clone());
+            }
+        }
+        caps.sort();
+        if caps.is_empty() { "none".to_string() } else { caps.join(", ") }
+    };
+    out.push_str(&format!("- capabilities: {caps}\n"));
+    if !dropped.is_empty() || !added.is_empty() {
+        let list = |v: &[String]| if v.is_empty() { "-".to_string() } else { v.join(", ") };
+        out.push_str(&format!("  (dropped: {}; added: {})\n", list(&dropped), list(&added)));
+    }
+
+    let seccomp = match security_opt.iter().find_map(|o| o.strip_prefix("seccomp=")) {
+        _ if privileged => "disabled (privileged)",
+        Some("unconfined") => "unconfined",
+        Some(_) => "custom profile",
+        None => "docker default profile",
+    };
+    out.push_str(&format!("- seccomp: {seccomp}\n"));
+    let apparmor = info.app_armor_profile.as_deref().filter(|p| !p.is_empty()).unwrap_or("none");
+    out.push_str(&format!("- apparmor: {apparmor}\n"));
+    if let Some(label) = info.process_label.as_deref().filter(|l| !l.is_empty()) {
+        out.push_str(&format!("- selinux: {label}\n"));
+    }
+    let no_new_privileges = security_opt.iter().any(|o| {
+        matches!(o.as_str(), "no-new-privileges" | "no-new-privileges:true" | "no-new-privileges=true")
+    });
+    out.push_str(&format!("- no-new-privileges: {}\n", yes_no(no_new_privileges)));
+
+    let mut rootfs = if host.readonly_rootfs.unwrap_or(false) {
+        "read-only".to_string()
+    } else {
+        "read-write".to_string()
+    };
+    if let Some(tmpfs) = host.tmpfs.as_ref().filter(|t| !t.is_empty()) {
+        let mut paths: Vec<&str> = tmpfs.keys().map(String::as_str).collect();
+        paths.sort();
+        rootfs.push_str(&format!(" (tmpfs: {})", paths.join(", ")));
+    }
+    out.push_str(&format!("- root filesystem: {rootfs}\n"));
+    let user = info
+        .config
+        .as_ref()
+        .and_then(|c| c.user.as_deref())
+        .filter(|u| !u.is_empty())
+        .unwrap_or("image default");
+    out.push_str(&format!("- user: {user}\n"));
+    if let Some(userns) = host.userns_mode.as_deref().filter(|m| !m.is_empty()) {
+        out.push_str(&format!("- user namespace: {userns}\n"));
+    }
+
+    out.push_str("resources:\n");
+    let bytes = |v: Option<i64>| match v {
+        Some(b) if b > 0 => format_bytes(b as u64),
+        _ => "unlimited".to_string(),
+    };
+    let mut memory = bytes(host.memory);
+    match host.memory_swap {
+        Some(-1) => memory.push_str(" (swap: unlimited)"),
+        Some(swap) if swap > 0 => memory.push_str(&format!(" (memory+swap: {})", format_bytes(swap as u64))),
+        _ => {}
+    }
+    out.push_str(&format!("- memory: {memory}\n"));
+    let cpus = match (host.nano_cpus, host.cpu_quota, host.cpu_period) {
+        (Some(n), _, _) if n > 0 => format!("{:.2}", n as f64 / 1e9),
+        (_, Some(quota), period) if quota > 0 => {
+            format!("{:.2}", quota as f64 / period.filter(|p| *p > 0).unwrap_or(100_000) as f64)
+        }
+        _ => "unlimited".to_string(),
+    };
+    out.push_str(&format!("- cpus: {cpus}\n"));
+    if let Some(set) = host.cpuset_cpus.as_deref().filter(|s| !s.is_empty()) {
+        out.push_str(&format!("- cpuset: {set}\n"));
+    }
+    let pids = match host.pids_limit {
+        Some(n) if n > 0 => n.to_string(),
+        _ => "unlimited".to_string(),
+    };
+    out.push_str(&format!("- pids: {pids}\n"));
+    if let Some(shm) = host.shm_size.filter(|s| *s > 0) {
+        out.push_str(&format!("- shm: {}\n", format_bytes(shm as u64)));
+    }
+    let ulimits: Vec<String> = host
+        .ulimits
+        .iter()
+        .flatten()
+        .filter_map(|u| {
+            Some(format!("{}={}:{}", u.name.as_deref()?, u.soft.unwrap_or(-1), u.hard.unwrap_or(-1)))
+        })
+        .collect();
+    if !ulimits.is_empty() {
+        out.push_str(&format!("- ulimits: {}\n", ulimits.join(", ")));
+    }
+    let devices: Vec<String> = host
+        .devices
+        .iter()
+        .flatten()
+        .filter_map(|d| {
+            Some(format!(
+                "{} ({})",
+                d.path_in_container.as_deref()?,
+                d.cgroup_permissions.as_deref().unwrap_or("rwm")
+            ))
+        })
+        .collect();
+    if !devices.is_empty() {
+        out.push_str(&format!("- devices: {}\n", devices.join(", ")));
+    }
+
+    out.push_str("network:\n");
+    out.push_str(&format!(
+        "- mode: {}\n",
+        host.network_mode.as_deref().filter(|m| !m.is_empty()).unwrap_or("default")
+    ));
+    let mut networks: Vec<&str> = info
+        .network_settings
+        .as_ref()
+        .and_then(|n| n.networks.as_ref())
+        .map(|n| n.keys().map(String::as_str).collect())
+        .unwrap_or_default();
+    if !networks.is_empty() {
+        networks.sort();
+        out.push_str(&format!("- networks: {}\n", networks.join(", ")));
+    }
+    out
+}
+
+/// When the workspace's container last started, or `None` if it isn't running.
+async fn container_started_at(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+) -> Option<DateTime<Utc>> {
+    let ws = container_manager.get_workspace(github_user, project).await?;
+    let info = container_manager
+        .docker()
+        .inspect_container(&ws.container_name, None::<InspectContainerOptions>)
+        .await
+        .ok()?;
+    let state = info.state?;
+    if state.running != Some(true) {
+        return None;
+    }
+    DateTime::parse_from_rfc3339(state.started_at.as_deref()?)
+        .ok()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+async fn run_jobs_action(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    action: JobsAction,
+) -> anyhow::Result<GatewayControlExecution> {
+    let workspace = container_manager.config().workspace_path(github_user, project);
+    let container_started = container_started_at(container_manager, github_user, project).await;
+    let output = match action {
+        JobsAction::List => jobs::format_list(&jobs::list(&workspace).await?, container_started),
+        JobsAction::Logs { id, follow } => {
+            let job = jobs::get(&workspace, &id).await?;
+            if follow && jobs::is_running(&job, container_started) {
+                return Ok(GatewayControlExecution::FollowJob { workspace, id });
+            }
+            // Keep output bounded for huge logs, like `agentman init log`.
+            let len = jobs::output_len(&workspace, &id).await;
+            let start = len.saturating_sub(jobs::OUTPUT_CHUNK);
+            let (bytes, _) = jobs::read_output(&workspace, &id, start).await?;
+            let mut out = String::new();
+            if start > 0 {
+                out.push_str(&format!(
+                    "agentman: (showing last {} bytes; the full log is /workspace/.agentman/jobs/{id}/output.log)\n",
+                    jobs::OUTPUT_CHUNK
+                ));
+            }
+            out.push_str(&String::from_utf8_lossy(&bytes));
+            if !out.is_empty() && !out.ends_with('\n') {
+                out.push('\n');
+            }
+            if follow && job.exit_code.is_none() {
+                out.push_str(&format!("agentman: job {id} is no longer running (the sandbox stopped)\n"));
+            }
+            // `--follow` waits for the job, so it reports the job's status.
+            let exit_status = match job.exit_code {
+                Some(code) if follow => u32::try_from(code).unwrap_or(1),
+                None if follow => 1,
+                _ => 0,
+            };
+            return Ok(GatewayControlExecution::Immediate { exit_status, output: out });
+        }
+        JobsAction::Kill { id } => {
+            let job = jobs::get(&workspace, &id).await?;
+            if !jobs::is_running(&job, container_started) {
+                anyhow::bail!("job {id} is not running");
+            }
+            let ws = container_manager
+                .get_workspace(github_user, project)
+                .await
+                .ok_or_else(|| anyhow::anyhow!("no sandbox for {github_user}/{project}"))?;
+            container_manager
+                .signal_exec_processes(&ws.container_name, &jobs::job_marker(&id), "TERM")
+                .await?;
+            format!("agentman: sent SIGTERM to job {id}\n")
+        }
+    };
+    Ok(GatewayControlExecution::Immediate {
+        exit_status: 0u32,
+        output,
+    })
+}
+
+pub(crate) fn gateway_control_help_text() -> String {
+    // Keep this compatible with non-interactive SSH exec flows.
+    "\
+agentman gateway control commands
+
+Usage:
+  agentman destroy [--project <name>] [--yes] [--keep-workspace] [--dry-run] [--force]
+  agentman list
+  agentman stop
+  agentman restart
+  agentman pause
+  agentman stats [--current] [--watch]
+  agentman stats [--current] --history <window>
+  agentman link [<project>]
+  agentman unlink <project>
+  agentman status
+  agentman limits
+  agentman storage
+  agentman bench [--json]
+  agentman tunnel
+  agentman proxy
+  agentman sessions
+  agentman git-identity [show|clear]
+  agentman git-identity set [--name <name>] [--email <email>]
+  agentman env-file [show|clear]
+  agentman env-file set < .env
+  agentman notify [show|clear]
+  agentman notify set webhook <url>
+  agentman notify set email <address>
+  agentman notify [--] <message...>
+  agentman init [show|clear|run|log]
+  agentman init set -- <command...>
+  agentman wait [--timeout <secs>] [--port <port>]
+  agentman run [--timeout <secs>] [--detach] -- <command...>
+  agentman jobs [logs <id> [--follow]|kill <id>]
+  agentman cat <path>
+  agentman tail [-n <lines>] [-f] <path>
+  agentman new <project> --from <template-git-url>
+  agentman ephemeral
+  agentman verify [<github-user>]
+  agentman route
+  agentman ping [--count <n>]
+  agentman scan
+  agentman search [--project <name>|all] [-i] <text>
+  agentman open [ssh|vscode|cursor|zed|jetbrains]
+  agentman guest [list]
+  agentman guest invite [--ttl <duration>] [--read-only]
+  agentman guest revoke <id>|--all
+  agentman trust [list]
+  agentman trust revoke|allow <id>
+  agentman recreate [--keep-running] [--pull]
+  agentman checkpoint [--leave-running]
+  agentman resume
+  agentman image [show|list]
+  agentman image set <image|name> [--now]
+  agentman image reset [--now]
+  agentman resize [show]
+  agentman resize [--shm <size>] [--nofile <n>] [--nproc <n>] [--now]
+  agentman resize reset [--now]
+  agentman build [--file <path>] [--no-cache] [--use]
+  agentman tls [show|close]
+  agentman tls expose <port>
+  agentman ownership [show|watch|unwatch]
+  agentman lang [show|list|reset]
+  agentman lang set <code>
+  agentman config [list]
+  agentman config get|unset <key>
+  agentman config set <key> <value>
+  agentman motd [show|clear]
+  agentman motd set <text>
+  agentman mesh [show]
+  agentman mesh allow|deny <project>
+  agentman expose [list]
+  agentman expose <port> --preview <duration>
+  agentman expose revoke <id>|--all
+  agentman stack [status|up|down]
+  agentman transfer <project> --to <github-user>
+  agentman admin users
+  agentman admin audit usage
+  agentman admin usage [--month <YYYY-MM>] [--csv|--json]
+  agentman admin state export
+  agentman admin state import <path> [--yes]
+  agentman admin migrate <github-user>/<project> --to <node> [--checkpoint]
+  agentman admin offboard [<github-user> [--cancel]]
+  agentman admin log-level [<level> [--target <module>]|reset]
+  agentman admin operations [resume|rollback <github-user>/<project>]
+  agentman admin access <github-user>/<project> --reason <text>
+  agentman cache [show]
+  agentman cache prune [--older-than <days>|--all]
+  agentman backup [create|list]
+  agentman backup restore <latest|snapshot-id|time> [--yes]
+
+Notes:
+  - Without --yes, destroy refuses to delete your persistent workspace directory.
+  - --keep-workspace stops/removes container(s) but keeps your files on disk.
+  - --dry-run prints what would be deleted.
+  - --project destroys another of your projects instead of the one you're connected to.
+  - stop/pause apply to the *current* sandbox (the project in your SSH user).
+  - stats without --current shows all sandboxes for your GitHub user.
+  - --watch refreshes output every second (use Ctrl-C to exit).
+  - --history shows min/avg/max CPU and memory with sparklines over a window like 30m, 1h
+    or 2d, from samples the gateway records in the background.
+  - net= in stats is the sandbox's network traffic since it was created (across restarts and
+    recreates); the last line totals this month, against your egress quota if there is one.
+  - link joins the current sandbox to another project's network (reachable by project name);
+    without an argument it lists current links. Links survive container recreation.
+  - limits shows the security and resource settings your sandbox actually runs with
+    (capabilities, seccomp/AppArmor, no-new-privileges, root filesystem, memory/CPU/pid limits,
+    network), read from the live container rather than the gateway's configuration. With
+    quotas on this gateway, it also shows how much of yours your running sandboxes use.
+  - storage shows the disk space of the sandbox's container layer (files changed outside
+    /workspace), its image, the workspace, stack volumes and your editor cache, and how much
+    recreate and destroy would free.
+  - bench runs a quick benchmark in the sandbox: SHA-256 throughput on one and on all CPUs,
+    write (with fsync) and read speed and small-file creation in /workspace, and a download
+    from the gateway's configured endpoint. --json prints the results (and the Docker host)
+    as JSON, for comparing hosts. The container must be running.
+  - tunnel shows whether this gateway is registered with its relay (for gateways behind NAT).
+  - proxy shows the gateway's egress proxy (HTTPS_PROXY in your sandbox), which destinations it
+    allows, and how much traffic this sandbox sent through it.
+  - sessions lists the terminals attached to the shared tmux session of each of your sandboxes
+    (client address, since when, terminal size) and the resulting window size.
+  - git-identity set stores the git author (user.name/user.email) for all your sandboxes and
+    writes it to the global git config of the running ones; the others get it when they start.
+    Without --name or --email, those come from your GitHub profile (the public email,
+    else your GitHub noreply address). clear forgets it; sandboxes keep their current config.
+  - env-file set stores the dotenv file piped into it (KEY=value lines) for the current
+    project on the gateway, outside /workspace, so it can't be committed. Its variables are set
+    in every command and shell you start there and in the sandbox when its container is
+    created; tmux sessions that are already running keep their environment. show lists the
+    variable names (never the values), clear deletes the file.
+  - notify sends a message to the channel you set with notify set (a Slack/Mattermost-style
+    webhook, or email when the gateway has sendmail), e.g. when an agent finishes. Scripts
+    inside the sandbox can send them too: `agentman-helper notify <message>` (or write to
+    /workspace/.agentman-notify.sock). Messages are rate-limited per project.
+  - init sets a command that runs (via `bash -lc`) each time the gateway starts the container;
+    its output goes to /workspace/.agentman/init.log and its result shows in `agentman status`.
+  - wait blocks until the sandbox is running, the init command succeeded, and the configured
+    readiness checks (or --port) pass. Exits 0 when ready, 1 on failure, 124 on timeout.
+  - run executes a command in the sandbox like `ssh host <command>`. With --timeout (or the
+    gateway's [exec] max_duration_secs), the command and everything it started are killed
+    when the time is up, and run exits with status 124.
+  - run --detach starts the command in the background and prints a job ID right away; the job
+    keeps running after you disconnect. jobs lists your jobs, logs prints a job's output
+    (--follow streams it until the job exits, then exits with the job's status), kill sends
+    SIGTERM to it. Output is kept in /workspace/.agentman/jobs/<id>/; the 20 most recent
+    finished jobs are kept.
+  - cat prints a file in /workspace (up to 1 MiB), tail its last 10 lines (or -n <lines>);
+    tail -f keeps printing what is appended until Ctrl-C, following the file when it is
+    rotated. Paths are relative to /workspace or start with it, and can't go through symlinks
+    or leave the workspace. The gateway reads the files itself, so they work with the sandbox
+    stopped and without a shell in the image. Put a path with spaces after --.
+  - new creates <project> and renders a cookiecutter-style template (cookiecutter.json plus a
+    {{cookiecutter.*}} directory) into its empty workspace, prompting for each variable
+    (Enter keeps the default; without a terminal all defaults are used). The template is
+    cloned inside the new sandbox; connect with `ssh <project>@...` afterwards.
+  - ephemeral opens a shell (use ssh -t) in a new throwaway sandbox whose /workspace lives in
+    memory; the sandbox and everything in it are removed when you disconnect. Connecting as
+    `tmp+<github-user>@...` (the gateway's ephemeral project) does the same for the whole
+    connection. Control commands other than
+    help aren't available in it, and it doesn't appear in `agentman list`.
+  - verify re-checks the SSH keys this connection offered that the gateway hasn't linked to
+    your GitHub user yet (e.g. a key added on GitHub after connecting with another one) and
+    remembers those now listed on GitHub, so they work without the username prompt next time.
+    It exits 1 while a key is still missing, for use in a retry loop.
+  - route shows which port forwards the gateway accepts for this connection (ssh -L, -R, -D
+    and ~C -L): allowed destinations, bind addresses, the workspace's reserved ports, and the
+    forwards the connection holds. ssh only reports a refused forward as a generic error, so
+    run it with the same -L/-R options to see them, e.g. `ssh -R 0:localhost:3000 ...
+    agentman route`.
+  - ping measures round trips from the gateway to your SSH client (keepalive requests), to
+    the Docker daemon (docker ping) and into your sandbox (exec of `true`), to tell network
+    slowness from a slow Docker host or sandbox. A stopped sandbox isn't started for it.
+  - scan walks /workspace and lists symlinks pointing outside it, setuid files anyone can
+    write, and very large files; it exits 1 if it found any. Gateways can also scan every
+    workspace regularly, telling you at your next login when something new turns up.
+  - search finds lines containing <text> (plain text; -i ignores case) in the current
+    project, another one (--project <name>) or all of yours (--project all), searching them
+    at once. Running sandboxes are searched with rg (or grep) inside, honoring .gitignore;
+    stopped ones aren't started, their files are read on the gateway. Each project shows its
+    first 200 matches as <project>/<path>:<line>: <text>. Exits 1 when nothing matched. Put
+    text starting with - after --, e.g. `agentman search --project all -- --force`.
+  - open prints a ~/.ssh/config entry (with the gateway's host key for known_hosts) and the
+    commands or settings that open this workspace in ssh, VS Code/Cursor, Zed and JetBrains
+    Gateway; name one to print only its part.
+  - guest invite (when the gateway enables guests) prints an SSH login that gives someone
+    without an account access to the current project until it expires (--ttl like 30m, 2h or
+    1d; the gateway sets a default and a maximum). The first SSH key used with it is the only
+    one accepted afterwards. Guests can't run control commands or forward an SSH agent, and
+    everything they do is audited under your name; --read-only guests only watch the shared
+    tmux session. revoke ends an invite and disconnects the guest.
+  - trust lists the devices (SSH keys) you logged in with when the gateway tracks them, with
+    the addresses they connected from; you are told about the first login from a new one.
+    trust revoke has the gateway refuse a device's key, even though GitHub still lists it
+    (connections already open stay); trust allow accepts it again.
+  - restart stops the sandbox container and starts it again; processes are restarted but the
+    container is kept. A pending image choice applies on the restart.
+  - recreate replaces the sandbox container with a fresh one from the gateway image (files in
+    /workspace are kept). --pull fetches the image first; --keep-running keeps the old
+    container up until the new one passes a health check, then switches over.
+  - checkpoint (experimental, when the gateway enables it) saves the memory and state of every
+    process in the sandbox with CRIU and stops it (--leave-running keeps it going); resume
+    starts the stopped sandbox from the latest checkpoint, so long-running agents continue
+    where they were, e.g. after a host reboot. Connecting normally starts the sandbox fresh
+    instead. CRIU can't save everything (open network connections, some devices); its error
+    is shown when a checkpoint or resume fails.
+  - image list shows the images this gateway lets you run; image set picks one for the current
+    project and image reset goes back to the gateway default. The switch happens the next time
+    the sandbox starts after a stop (`agentman stop`), or right away with --now, which
+    recreates the container. Files in /workspace are kept. Images with a name can also be
+    picked when connecting, as in ssh <project>.<name>@gateway (default names the gateway
+    default).
+  - resize changes the size of /dev/shm (Docker's default is 64m, too small for browsers and
+    some databases) and the open files and processes limits of the current project's sandbox,
+    up to the gateway's maximums; reset goes back to the gateway defaults. Like image set, the
+    change applies the next time the sandbox starts, or right away with --now.
+  - build builds an image from /workspace/.agentman/Dockerfile (or --file), with the
+    Dockerfile's directory as the build context, and tags it for the current project only;
+    --use also selects it like image set. Builds run with the gateway's memory, CPU and time
+    limits (when enabled on this gateway).
+  - ownership watch hands files that root creates in the sandbox (package managers, sudo) to
+    the sandbox user as they appear, so you can edit and delete them; unwatch stops it. Only
+    new files are looked at, not the whole workspace (when enabled on this gateway).
+  - tls expose makes a TLS service in the current sandbox reachable through the gateway's SNI
+    router at <project>--<github-user>.<domain> (shown by tls show). The gateway relays the
+    encrypted connection to <port> on the sandbox's localhost without terminating TLS, so the
+    service brings its own certificate. close stops routing.
+  - lang set picks the language of the gateway's own messages (sign-in, queue and forward
+    notices, ~C replies), e.g. `agentman lang set tr`; list shows the languages this gateway
+    has and reset goes back to its default. Command reference output like this help stays in
+    English.
+  - config sets your own preferences for all of your projects; list shows each one's value in
+    effect and whether you set it. Keys: shell (tmux or bash, for new interactive shells),
+    editor_cache (on or off, mounting the gateway's shared editor server caches into new
+    containers), idle_timeout (stop your sandboxes sooner than the gateway does, e.g. 30m),
+    notify_idle_stop (on or off, a message to your `agentman notify` channel when a sandbox is
+    stopped for being idle) and lang (as `agentman lang set`). unset goes back to the gateway's
+    setting.
+  - motd set gives the project a login message, shown to everyone who opens a terminal on it
+    (guests included), e.g. `agentman motd set 'staging DB is at db:5432'`. It may use
+    **bold**, *italic* and `code`, lines starting with # (heading) or - (item), and \\n
+    between lines.
+  - mesh allow lets programs in this project's sandbox run commands in your sandbox of
+    <project> (started if stopped), e.g. `agentman-helper exec <project> -- make test`; deny
+    takes it back. Commands see the calling project in AGENTMAN_MESH_FROM. Needs the gateway
+    to enable the mesh.
+  - expose --preview (when the gateway enables preview links) prints a link that serves <port>
+    on the sandbox's localhost over HTTP to anyone who has it, without logging in, until it
+    expires (a duration like 30m, 2h or 1d; the gateway sets a maximum). Use it to show an
+    agent's web output to someone without an account. The app sees the link's hostname in the
+    Host header and may have to allow it. revoke ends a link right away.
+  - stack manages the sidecar services (database, cache, ...) declared in
+    /workspace/.agentman/compose.yaml. They start with the sandbox and are reachable from it by
+    service name; the sandbox itself stays the SSH target. up applies changes to the compose
+    file (recreating changed services), down removes the sidecars but keeps their volumes.
+  - transfer gives one of your projects (any of them, not only the current one) to another
+    GitHub user: its containers are removed, the workspace moves to the recipient, and they
+    see a notice at their next login. Admins can transfer anyone's with <owner>/<project>.
+  - admin commands are limited to the gateway's admin_github_users. admin users lists every
+    known user with their GitHub profile name, cached keys, workspaces, disk usage, running
+    sandboxes and last login. admin offboard lists users
+    being offboarded; with a user it stops their sandboxes, archives their workspaces, revokes
+    their cached keys and refuses their logins, and destroys their workspaces after the
+    configured grace period. --cancel stops it (archives are kept). admin audit usage shows
+    the disk used by each user's audit logs in the gateway's directory sink, and how many of
+    their events were dropped (rate limit or size cap) since the gateway started.
+  - admin usage shows each user's CPU seconds, memory GB-hours, workspace disk GB-days and
+    network traffic in a month (UTC; the current one by default), as sampled with [accounting]
+    enabled. --csv and --json print it for billing; reports of past months are also written
+    to the accounting directory.
+  - admin state export prints a versioned, checksummed snapshot of the gateway's state (key
+    cache, workspaces, port reservations, offboarding, ...). admin state import checks a
+    snapshot at <path> on the gateway host; with --yes it replaces the state with it, keeping
+    the previous state file next to it. Sandboxes keep running either way.
+  - admin migrate moves a workspace to another gateway listed in [migration] nodes: the sandbox
+    is stopped (or checkpointed with --checkpoint, resuming its processes there), the files
+    are streamed to the node, which creates the container, and the local copy is removed.
+    The user's connections here are then pointed at the node.
+  - admin operations lists the destroys, recreates and migrations in progress, and those a
+    gateway restart or a failed step stopped half-way; such a workspace gets no new sandbox
+    until you resume the operation (its steps run again) or roll it back (the containers it
+    created are removed and the workspace is left as it is).
+  - admin log-level shows the gateway's log filter. With a level (trace, debug, info, warn,
+    error, off) it changes the default level, or with --target that of one module (docker,
+    ssh, ... or a full path such as russh::server), without a restart; reset goes back to
+    the filter the gateway started with.
+  - admin access lets you log in to another user's project as them, with your own key, for
+    the gateway's grant lifetime: ssh <project>+<github-user>@gateway. The reason is shown to
+    the owner, who is told about every such login at their next login and through their
+    notifier; the logins are recorded in the audit log as impersonation events.
+  - cache shows your shared editor server cache (VS Code, Zed, ...); prune removes server
+    versions untouched for 30 days (keeping the newest of each), --all empties it.
+  - backup snapshots are incremental. restore accepts a snapshot id or a point in time
+    (e.g. 2026-10-14T09:30, UTC) and picks the latest snapshot at or before it; it stops the
+    sandbox and saves the current files as a new snapshot first.
+  - `agentman exec <cmd>` is accepted as an alias for these commands.
+"
+    .to_string()
+}
+
+pub(crate) async fn execute_gateway_control_command(
+    ctrl: GatewayControlCommand,
     container_manager: &ContainerManager,
     github_user: &str,
     project: &str,
+    inspections: &InspectCache,
 ) -> GatewayControlExecution {
+    if !ctrl.reports_only() {
+        inspections.clear();
+    }
     match ctrl {
         GatewayControlCommand::Help => GatewayControlExecution::Immediate {
             exit_status: 0u32,
             output: gateway_control_help_text(),
         },
         GatewayControlCommand::Destroy {
+            project: target,
             yes,
             keep_workspace,
             dry_run,
             force,
         } => {
-            if !dry_run && !keep_workspace && !yes {
+            let project = target.as_deref().unwrap_or(project);
+            if let Err(e) = validate_project_name(project) {
+                GatewayControlExecution::Immediate {
+                    exit_status: 2u32,
+                    output: format!("agentman: {e}\n"),
+                }
+            } else if !dry_run && !keep_workspace && !yes {
                 GatewayControlExecution::Immediate {
                     exit_status: 2u32,
-                    output: destroy_confirmation_required_text(),
+                    output: destroy_confirmation_required_text(target.as_deref()),
                 }
             } else {
                 let opts = DestroyOptions {
@@ -230,11 +2027,22 @@ pub(crate) async fn execute_gateway_control_command(
                 };
             }
 
+            // Inspect several containers at once; users with dozens of projects wait otherwise.
+            let names: Vec<String> =
+                workspaces.iter().map(|ws| ws.container_name.clone()).collect();
+            let statuses: Vec<_> = futures::stream::iter(names)
+                .map(|name| async move {
+                    let (status, id, _) =
+                        session_container_status(container_manager, inspections, &name).await;
+                    (status, id)
+                })
+                .buffered(LIST_CONCURRENCY)
+                .collect()
+                .await;
+
             let mut out = format!("agentman: sandboxes for {github_user}\n");
-            for ws in workspaces {
+            for (ws, (status, id_short)) in workspaces.iter().zip(statuses) {
                 let is_current = ws.project == project;
-                let (status, id_short) =
-                    workspace_container_status(container_manager, &ws.container_name).await;
                 let id_suffix = id_short
                     .as_deref()
                     .map(|id| format!(" id={id}"))
@@ -384,121 +2192,1068 @@ pub(crate) async fn execute_gateway_control_command(
                 GatewayControlExecution::Immediate { exit_status, output }
             }
         },
-        GatewayControlCommand::ExecStats { current, watch } => {
-            if watch {
-                GatewayControlExecution::WatchStats {
-                    current,
-                    interval: Duration::from_secs(1),
+        GatewayControlCommand::Link { project: None } => {
+            match container_manager.get_workspace(github_user, project).await {
+                None => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: no sandbox found for {github_user}/{project}\n"),
+                },
+                Some(ws) if ws.linked_projects.is_empty() => GatewayControlExecution::Immediate {
+                    exit_status: 0u32,
+                    output: format!("agentman: sandbox {project} is not linked to any projects\n"),
+                },
+                Some(ws) => {
+                    let mut out = format!("agentman: projects linked to {project}\n");
+                    for other in &ws.linked_projects {
+                        out.push_str(&format!(
+                            "- {other}  network={}\n",
+                            project_network_name(github_user, other)
+                        ));
+                    }
+                    GatewayControlExecution::Immediate {
+                        exit_status: 0u32,
+                        output: out,
+                    }
                 }
-            } else {
-                let (exit_status, output) =
-                    render_sandbox_stats(container_manager, github_user, project, current).await;
-                GatewayControlExecution::Immediate { exit_status, output }
             }
         }
-    }
-}
-
-pub(crate) async fn render_sandbox_stats(
-    container_manager: &ContainerManager,
-    github_user: &str,
-    project: &str,
-    current: bool,
-) -> (u32, String) {
-    let mut workspaces = if current {
-        match container_manager.get_workspace(github_user, project).await {
-            Some(ws) => vec![ws],
-            None => {
-                return (
-                    1u32,
-                    format!("agentman: no current sandbox found for {github_user}/{project}\n"),
-                );
+        GatewayControlCommand::Link {
+            project: Some(other),
+        } => {
+            if let Err(e) = validate_project_name(&other) {
+                return GatewayControlExecution::Immediate {
+                    exit_status: 2u32,
+                    output: format!("agentman: {e}\n"),
+                };
+            }
+            match container_manager
+                .link_project(github_user, project, &other)
+                .await
+            {
+                Ok(network) => GatewayControlExecution::Immediate {
+                    exit_status: 0u32,
+                    output: format!(
+                        "agentman: linked {project} to {other} (network={network}); reach it as host `{other}`\n"
+                    ),
+                },
+                Err(e) => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: link failed: {e}\n"),
+                },
             }
         }
-    } else {
-        container_manager.list_workspaces(github_user).await
-    };
-    workspaces.sort_by(|a, b| a.project.cmp(&b.project));
-
-    if workspaces.is_empty() {
-        return (0u32, format!("agentman: no sandboxes for {github_user}\n"));
-    }
-
-    let mut out = format!("agentman: sandbox stats for {github_user}\n");
-    for ws in workspaces {
-        let is_current = ws.project == project;
-        let (status, id_short, running) =
-            workspace_container_status_with_running(container_manager, &ws.container_name).await;
-
-        let (cpu, mem) = if running {
-            match container_stats_line(container_manager, &ws.container_name).await {
-                Some((cpu, mem)) => (Some(cpu), mem),
-                None => (None, None),
+        GatewayControlCommand::Unlink { project: other } => {
+            match container_manager
+                .unlink_project(github_user, project, &other)
+                .await
+            {
+                Ok(true) => GatewayControlExecution::Immediate {
+                    exit_status: 0u32,
+                    output: format!("agentman: unlinked {project} from {other}\n"),
+                },
+                Ok(false) => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: {project} is not linked to {other}\n"),
+                },
+                Err(e) => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: unlink failed: {e}\n"),
+                },
             }
-        } else {
-            (None, None)
-        };
-
-        let storage = du_bytes(&ws.host_workspace_path).await;
-
-        out.push_str(&format!(
-            "- {}{}: status={}{}{}{} storage(workspace)={}\n",
-            ws.project,
-            if is_current { " (current)" } else { "" },
-            status,
-            if let Some(id) = id_short.as_deref() {
-                format!(" id={id}")
-            } else {
-                "".to_string()
-            },
-            if let Some(cpu) = cpu {
-                format!(" cpu={:.1}%", cpu)
-            } else {
-                " cpu=n/a".to_string()
-            },
-            if let Some((usage, limit)) = mem {
-                format!(" mem={}/{}", format_bytes(usage), format_bytes(limit))
-            } else {
-                " mem=n/a".to_string()
-            },
-            storage
-                .map(format_bytes)
-                .unwrap_or_else(|| "n/a".to_string())
-        ));
-    }
-    (0u32, out)
-}
+        }
+        GatewayControlCommand::Status => {
+            let Some(ws) = container_manager.get_workspace(github_user, project).await else {
+                return GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: no sandbox found for {github_user}/{project}\n"),
+                };
+            };
+            let (status, id_short, _) =
+                session_container_status(container_manager, inspections, &ws.container_name).await;
 
-/// Fast version for watch mode: skips storage (du) and parallelizes stats queries.
-pub(crate) async fn render_sandbox_stats_fast(
-    container_manager: &ContainerManager,
-    github_user: &str,
-    project: &str,
-    current: bool,
-) -> (u32, String) {
-    let mut workspaces = if current {
-        match container_manager.get_workspace(github_user, project).await {
-            Some(ws) => vec![ws],
-            None => {
-                return (
-                    1u32,
-                    format!("agentman: no current sandbox found for {github_user}/{project}\n"),
-                );
+            let mut out = format!("agentman: status for {github_user}/{project}\n");
+            out.push_str(&format!(
+                "- container: {status}  name={}{}\n",
+                ws.container_name,
+                id_short.map(|id| format!(" id={id}")).unwrap_or_default()
+            ));
+            out.push_str(&format!("- created: {}\n", ws.created_at.to_rfc3339()));
+            out.push_str(&format!(
+                "- init: {}\n",
+                format_init_status(container_manager, &ws)
+            ));
+            if let Some(ref clone) = ws.clone {
+                let max_attempts = container_manager.config().clone.max_attempts;
+                out.push_str(&format!(
+                    "- clone: {}\n",
+                    provision::format_status(clone, max_attempts)
+                ));
+            }
+            if !ws.linked_projects.is_empty() {
+                out.push_str(&format!("- links: {}\n", ws.linked_projects.join(", ")));
+            }
+            let pf = &container_manager.config().port_forwarding;
+            if let Some(range) = pf.remote_port_range {
+                let line = match container_manager
+                    .state()
+                    .reserve_port_block(github_user, project, range, pf.remote_ports_per_workspace)
+                    .await
+                {
+                    Ok(block) => format!("- remote forward ports: {block}\n"),
+                    Err(e) => format!("- remote forward ports: unavailable ({e})\n"),
+                };
+                out.push_str(&line);
+            }
+            GatewayControlExecution::Immediate {
+                exit_status: 0u32,
+                output: out,
             }
         }
-    } else {
-        container_manager.list_workspaces(github_user).await
-    };
-    workspaces.sort_by(|a, b| a.project.cmp(&b.project));
-
-    if workspaces.is_empty() {
-        return (0u32, format!("agentman: no sandboxes for {github_user}\n"));
-    }
-
-    // Parallelize: gather status + stats for all workspaces at once.
-    let futs: Vec<_> = workspaces
-        .iter()
-        .map(|ws| {
+        GatewayControlCommand::Limits => {
+            let Some(ws) = container_manager.get_workspace(github_user, project).await else {
+                return GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: no sandbox found for {github_user}/{project}\n"),
+                };
+            };
+            match inspections
+                .inspect(container_manager.docker(), &ws.container_name)
+                .await
+            {
+                Ok(info) => {
+                    let mut output = format!(
+                        "agentman: effective limits for {github_user}/{project} (container {})\n{}",
+                        ws.container_name,
+                        format_limits(&info)
+                    );
+                    if let Some(quota) = container_manager.config().quotas.for_user(github_user) {
+                        match container_manager.quota_usage(github_user).await {
+                            Ok(usage) => output.push_str(&format_quota(&quota, usage)),
+                            Err(e) => output.push_str(&format!("quota: unavailable ({e:#})\n")),
+                        }
+                    }
+                    GatewayControlExecution::Immediate {
+                        exit_status: 0u32,
+                        output,
+                    }
+                }
+                Err(e) => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: failed to inspect {}: {e}\n", ws.container_name),
+                },
+            }
+        }
+        GatewayControlCommand::Storage => {
+            match render_storage(container_manager, github_user, project).await {
+                Ok(output) => GatewayControlExecution::Immediate {
+                    exit_status: 0u32,
+                    output,
+                },
+                Err(e) => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: {e:#}\n"),
+                },
+            }
+        }
+        GatewayControlCommand::Bench { json } => {
+            let config = &container_manager.config().bench;
+            if !config.enabled {
+                return GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: "agentman: bench is disabled on this gateway\n".to_string(),
+                };
+            }
+            let Some(ws) = container_manager.get_workspace(github_user, project).await else {
+                return GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: no sandbox found for {github_user}/{project}\n"),
+                };
+            };
+            match bench::run(container_manager, config, &ws.container_name).await {
+                Ok(report) if json => GatewayControlExecution::Immediate {
+                    exit_status: 0u32,
+                    output: format!(
+                        "{}\n",
+                        serde_json::to_string_pretty(&report).unwrap_or_default()
+                    ),
+                },
+                Ok(report) => GatewayControlExecution::Immediate {
+                    exit_status: 0u32,
+                    output: format!(
+                        "agentman: benchmark of {github_user}/{project} (container {})\n{}",
+                        ws.container_name,
+                        bench::format_report(&report)
+                    ),
+                },
+                Err(e) => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: bench failed: {e:#}\n"),
+                },
+            }
+        }
+        GatewayControlCommand::InitShow => {
+            let ws = container_manager.get_workspace(github_user, project).await;
+            let command = container_manager.effective_init_command(ws.as_ref());
+            let source = match ws.as_ref().and_then(|w| w.init_command.as_ref()) {
+                Some(_) => "workspace",
+                None => "gateway default",
+            };
+            let mut out = match command {
+                Some(cmd) => format!("agentman: init command ({source}): {cmd}\n"),
+                None => "agentman: no init command configured\n".to_string(),
+            };
+            if let Some(ws) = ws {
+                out.push_str(&format!(
+                    "agentman: last run: {}\n",
+                    format_init_status(container_manager, &ws)
+                ));
+            }
+            GatewayControlExecution::Immediate {
+                exit_status: 0u32,
+                output: out,
+            }
+        }
+        GatewayControlCommand::InitSet { command } => {
+            set_workspace_init_command(container_manager, github_user, project, Some(command)).await
+        }
+        GatewayControlCommand::InitClear => {
+            set_workspace_init_command(container_manager, github_user, project, None).await
+        }
+        GatewayControlCommand::InitRun => {
+            let Some(ws) = container_manager.get_workspace(github_user, project).await else {
+                return GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: no sandbox found for {github_user}/{project}\n"),
+                };
+            };
+            if container_manager.effective_init_command(Some(&ws)).is_none() {
+                return GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: "agentman: no init command configured\n".to_string(),
+                };
+            }
+            let (status, _) = workspace_container_status(container_manager, &ws.container_name).await;
+            match ws.container_id {
+                Some(ref id) if status == "running" => {
+                    container_manager
+                        .spawn_init_command(github_user, project, id)
+                        .await;
+                    GatewayControlExecution::Immediate {
+                        exit_status: 0u32,
+                        output: "agentman: init command started; see `agentman init log`\n"
+                            .to_string(),
+                    }
+                }
+                _ => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: sandbox {project} is not running ({status})\n"),
+                },
+            }
+        }
+        GatewayControlCommand::InitLog => {
+            let path = container_manager.config().workspace_path(github_user, project);
+            match tokio::task::spawn_blocking(move || read_init_log(&path))
+                .await
+                .unwrap_or_else(|e| Err(std::io::Error::other(e)))
+            {
+                Ok(bytes) => {
+                    // Keep output bounded for huge logs.
+                    const MAX: usize = 64 * 1024;
+                    let start = bytes.len().saturating_sub(MAX);
+                    let mut out = String::new();
+                    if start > 0 {
+                        out.push_str(&format!("agentman: (showing last {MAX} bytes)\n"));
+                    }
+                    out.push_str(&String::from_utf8_lossy(&bytes[start..]));
+                    if !out.ends_with('\n') {
+                        out.push('\n');
+                    }
+                    GatewayControlExecution::Immediate {
+                        exit_status: 0u32,
+                        output: out,
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: "agentman: init command has not run yet\n".to_string(),
+                },
+                Err(e) => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: failed to read init log: {e}\n"),
+                },
+            }
+        }
+        GatewayControlCommand::Restart => GatewayControlExecution::Restart,
+        GatewayControlCommand::Recreate { keep_running, pull } => {
+            // Pulls and health checks can take minutes; run off the SSH handler.
+            GatewayControlExecution::Recreate { keep_running, pull }
+        }
+        GatewayControlCommand::Checkpoint { action } => {
+            if !container_manager.config().checkpoint.enabled {
+                return GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: "agentman: checkpoints are not enabled on this gateway\n".to_string(),
+                };
+            }
+            GatewayControlExecution::Checkpoint { action }
+        }
+        GatewayControlCommand::Image { action } => {
+            execute_image(container_manager, github_user, project, action).await
+        }
+        GatewayControlCommand::Resize { action } => {
+            let now = matches!(
+                action,
+                ResizeAction::Set { now: true, .. } | ResizeAction::Reset { now: true }
+            );
+            match run_resize_action(container_manager, github_user, project, action).await {
+                // Recreating can take a while; run off the SSH handler.
+                Ok(_) if now => GatewayControlExecution::Recreate {
+                    keep_running: false,
+                    pull: false,
+                },
+                Ok(output) => GatewayControlExecution::Immediate {
+                    exit_status: 0u32,
+                    output,
+                },
+                Err(e) => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: {e:#}\n"),
+                },
+            }
+        }
+        GatewayControlCommand::Build { .. } if !container_manager.config().build.enabled => {
+            GatewayControlExecution::Immediate {
+                exit_status: 1u32,
+                output: "agentman: image builds are not enabled on this gateway\n".to_string(),
+            }
+        }
+        GatewayControlCommand::Build {
+            file,
+            no_cache,
+            use_image,
+        } => GatewayControlExecution::Build {
+            file,
+            no_cache,
+            use_image,
+        },
+        GatewayControlCommand::Tls { action } => {
+            execute_tls(container_manager, github_user, project, action).await
+        }
+        GatewayControlCommand::Ownership { action } => {
+            match run_ownership_action(container_manager, github_user, project, action).await {
+                Ok(output) => GatewayControlExecution::Immediate {
+                    exit_status: 0u32,
+                    output,
+                },
+                Err(e) => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: {e:#}\n"),
+                },
+            }
+        }
+        GatewayControlCommand::Run {
+            timeout_secs,
+            detach: true,
+            command,
+        } => {
+            let timeout_secs = match (container_manager.config().exec.max_duration_secs, timeout_secs) {
+                (Some(max), Some(requested)) => Some(max.min(requested)),
+                (max, requested) => requested.or(max),
+            };
+            match jobs::start(container_manager, github_user, project, &command, timeout_secs).await {
+                Ok(id) => GatewayControlExecution::Immediate {
+                    exit_status: 0u32,
+                    output: format!(
+                        "{id}\nagentman: started job {id}; follow it with `agentman jobs logs {id} --follow`\n"
+                    ),
+                },
+                Err(e) => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: run --detach failed: {e:#}\n"),
+                },
+            }
+        }
+        GatewayControlCommand::Stack { .. } if !container_manager.config().stack.enabled => {
+            GatewayControlExecution::Immediate {
+                exit_status: 1u32,
+                output: "agentman: stacks are not enabled on this gateway\n".to_string(),
+            }
+        }
+        GatewayControlCommand::Stack {
+            action: StackAction::Status,
+        } => {
+            let (exit_status, output) = stack::format_status(container_manager, github_user, project).await;
+            GatewayControlExecution::Immediate { exit_status, output }
+        }
+        // Pulls and health checks can take minutes; run off the SSH handler.
+        GatewayControlCommand::Stack { action } => GatewayControlExecution::Stack { action },
+        GatewayControlCommand::Jobs { action } => {
+            match run_jobs_action(container_manager, github_user, project, action).await {
+                Ok(execution) => execution,
+                Err(e) => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: {e:#}\n"),
+                },
+            }
+        }
+        GatewayControlCommand::Cat { path } => {
+            let workspace = container_manager.config().workspace_path(github_user, project);
+            match file_view::cat(&workspace, &path).await {
+                Ok(output) => GatewayControlExecution::Immediate {
+                    exit_status: 0u32,
+                    output,
+                },
+                Err(e) => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: {e:#}\n"),
+                },
+            }
+        }
+        GatewayControlCommand::Tail {
+            path,
+            lines,
+            follow,
+        } => {
+            let workspace = container_manager.config().workspace_path(github_user, project);
+            match file_view::tail(&workspace, &path, lines).await {
+                Ok((output, offset)) if follow => GatewayControlExecution::FollowFile {
+                    workspace,
+                    path,
+                    output,
+                    offset,
+                },
+                Ok((output, _)) => GatewayControlExecution::Immediate {
+                    exit_status: 0u32,
+                    output,
+                },
+                Err(e) => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: {e:#}\n"),
+                },
+            }
+        }
+        // The SSH layer turns `run` into a regular exec; it never reaches the gateway.
+        GatewayControlCommand::Run { .. } => GatewayControlExecution::Immediate {
+            exit_status: 1u32,
+            output: "agentman: run is only available over an SSH exec request\n".to_string(),
+        },
+        GatewayControlCommand::Transfer { owner, project: target, to } => {
+            let from_user = owner.as_deref().unwrap_or(github_user);
+            if from_user != github_user && !container_manager.config().is_admin(github_user) {
+                return GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: "agentman: only gateway admins can transfer other users' projects\n"
+                        .to_string(),
+                };
+            }
+            match run_transfer(container_manager, github_user, from_user, &target, &to).await {
+                Ok(output) => GatewayControlExecution::Immediate {
+                    exit_status: 0u32,
+                    output,
+                },
+                Err(e) => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: transfer failed: {e:#}\n"),
+                },
+            }
+        }
+        GatewayControlCommand::GitIdentity { action } => {
+            execute_git_identity(container_manager, github_user, project, action).await
+        }
+        GatewayControlCommand::EnvFile { .. } if !container_manager.config().env_file.enabled => {
+            GatewayControlExecution::Immediate {
+                exit_status: 1u32,
+                output: "agentman: env files are not enabled on this gateway\n".to_string(),
+            }
+        }
+        GatewayControlCommand::EnvFile { action } => {
+            match run_env_file_action(container_manager, github_user, project, action).await {
+                Ok(output) => GatewayControlExecution::Immediate {
+                    exit_status: 0u32,
+                    output,
+                },
+                Err(e) => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: {e:#}\n"),
+                },
+            }
+        }
+        GatewayControlCommand::Notify { .. } if !container_manager.config().user_notify.enabled => {
+            GatewayControlExecution::Immediate {
+                exit_status: 1u32,
+                output: "agentman: notifications are not enabled on this gateway\n".to_string(),
+            }
+        }
+        GatewayControlCommand::Notify { action } => {
+            match run_notify_action(container_manager, github_user, project, action).await {
+                Ok(output) => GatewayControlExecution::Immediate {
+                    exit_status: 0u32,
+                    output,
+                },
+                Err(e) => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: {e:#}\n"),
+                },
+            }
+        }
+        GatewayControlCommand::Lang { action } => {
+            match run_lang_action(container_manager, github_user, action).await {
+                Ok((exit_status, output)) => GatewayControlExecution::Immediate {
+                    exit_status,
+                    output,
+                },
+                Err(e) => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: {e:#}\n"),
+                },
+            }
+        }
+        GatewayControlCommand::Config { action } => {
+            match run_config_action(container_manager, github_user, action).await {
+                Ok(output) => GatewayControlExecution::Immediate {
+                    exit_status: 0u32,
+                    output,
+                },
+                Err(e) => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: {e:#}\n"),
+                },
+            }
+        }
+        GatewayControlCommand::Motd { action } => {
+            match run_motd_action(container_manager, github_user, project, action).await {
+                Ok(output) => GatewayControlExecution::Immediate {
+                    exit_status: 0u32,
+                    output,
+                },
+                Err(e) => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: {e:#}\n"),
+                },
+            }
+        }
+        GatewayControlCommand::Mesh { action } => {
+            match run_mesh_action(container_manager, github_user, project, action).await {
+                Ok(output) => GatewayControlExecution::Immediate {
+                    exit_status: 0u32,
+                    output,
+                },
+                Err(e) => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: {e:#}\n"),
+                },
+            }
+        }
+        GatewayControlCommand::Expose { action } => {
+            match run_expose_action(container_manager, github_user, project, action).await {
+                Ok(output) => GatewayControlExecution::Immediate {
+                    exit_status: 0u32,
+                    output,
+                },
+                Err(e) => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: {e:#}\n"),
+                },
+            }
+        }
+        GatewayControlCommand::Trust { action } => {
+            match run_trust_action(container_manager, github_user, action).await {
+                Ok(output) => GatewayControlExecution::Immediate {
+                    exit_status: 0u32,
+                    output,
+                },
+                Err(e) => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: {e:#}\n"),
+                },
+            }
+        }
+        GatewayControlCommand::Guest { action } => {
+            match run_guest_action(container_manager, github_user, project, action).await {
+                Ok(output) => GatewayControlExecution::Immediate {
+                    exit_status: 0u32,
+                    output,
+                },
+                Err(e) => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: {e:#}\n"),
+                },
+            }
+        }
+        GatewayControlCommand::AdminOffboard { .. }
+        | GatewayControlCommand::AdminUsers
+        | GatewayControlCommand::AdminAuditUsage
+        | GatewayControlCommand::AdminUsage { .. }
+        | GatewayControlCommand::AdminStateExport
+        | GatewayControlCommand::AdminStateImport { .. }
+        | GatewayControlCommand::AdminMigrate { .. }
+        | GatewayControlCommand::AdminLogLevel { .. }
+        | GatewayControlCommand::AdminOperations { .. }
+        | GatewayControlCommand::AdminAccess { .. }
+            if !container_manager.config().is_admin(github_user) =>
+        {
+            GatewayControlExecution::Immediate {
+                exit_status: 1u32,
+                output: format!("agentman: {github_user} is not a gateway admin\n"),
+            }
+        }
+        GatewayControlCommand::AdminUsers => GatewayControlExecution::Immediate {
+            exit_status: 0u32,
+            output: format_users(container_manager).await,
+        },
+        GatewayControlCommand::AdminStateExport => {
+            match state_snapshot::export(container_manager.state()).await {
+                Ok(snapshot) => GatewayControlExecution::Immediate {
+                    exit_status: 0u32,
+                    output: format!("{snapshot}\n"),
+                },
+                Err(e) => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: state export failed: {e:#}\n"),
+                },
+            }
+        }
+        GatewayControlCommand::AdminStateImport { path, yes } => {
+            match import_state(container_manager, github_user, &path, yes).await {
+                Ok(output) => GatewayControlExecution::Immediate {
+                    exit_status: 0u32,
+                    output,
+                },
+                Err(e) => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: state import failed: {e:#}\n"),
+                },
+            }
+        }
+        GatewayControlCommand::AdminMigrate {
+            owner,
+            project,
+            node,
+            checkpoint,
+        } => {
+            if let Err(e) = validate_github_username(&owner).and(validate_project_name(&project)) {
+                return GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: {e}\n"),
+                };
+            }
+            // Copying a workspace can take a long time; run off the SSH handler.
+            GatewayControlExecution::Migrate {
+                owner,
+                project,
+                node,
+                checkpoint,
+            }
+        }
+        GatewayControlCommand::AdminAccess {
+            owner,
+            project: target,
+            reason,
+        } => match grant_admin_access(container_manager, github_user, &owner, &target, &reason)
+            .await
+        {
+            Ok(output) => GatewayControlExecution::Immediate {
+                exit_status: 0u32,
+                output,
+            },
+            Err(e) => GatewayControlExecution::Immediate {
+                exit_status: 1u32,
+                output: format!("agentman: {e:#}\n"),
+            },
+        },
+        GatewayControlCommand::AdminLogLevel { action } => {
+            let result = match action {
+                LogLevelAction::Show => log_level::current(),
+                LogLevelAction::Set { level, target } => {
+                    let result = log_level::set(&level, target.as_deref());
+                    if result.is_ok() {
+                        tracing::info!("{} changed the log filter", github_user);
+                    }
+                    result
+                }
+                LogLevelAction::Reset => log_level::reset(),
+            };
+            match result {
+                Ok(filter) => GatewayControlExecution::Immediate {
+                    exit_status: 0u32,
+                    output: format!("agentman: log filter: {filter}\n"),
+                },
+                Err(e) => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: {e:#}\n"),
+                },
+            }
+        }
+        GatewayControlCommand::AdminOperations { action } => match action {
+            OperationsAction::List => GatewayControlExecution::Immediate {
+                exit_status: 0u32,
+                output: journal::format_operations(&container_manager.state().operations().await),
+            },
+            // Its steps can take as long as the operation itself; run off the SSH handler.
+            OperationsAction::Resume { owner, project } => {
+                GatewayControlExecution::ResumeOperation { owner, project }
+            }
+            OperationsAction::Rollback { owner, project } => {
+                match journal::rollback(container_manager, &owner, &project).await {
+                    Ok(output) => {
+                        let target = format!("{owner}/{project}");
+                        tracing::info!("{} rolled back the operation on {}", github_user, target);
+                        GatewayControlExecution::Immediate {
+                            exit_status: 0u32,
+                            output,
+                        }
+                    }
+                    Err(e) => GatewayControlExecution::Immediate {
+                        exit_status: 1u32,
+                        output: format!("agentman: rollback failed: {e:#}\n"),
+                    },
+                }
+            }
+        },
+        GatewayControlCommand::AdminAuditUsage => match format_audit_usage(
+            &container_manager.config().logging,
+        ) {
+            Ok(output) => GatewayControlExecution::Immediate {
+                exit_status: 0u32,
+                output,
+            },
+            Err(e) => GatewayControlExecution::Immediate {
+                exit_status: 1u32,
+                output: format!("agentman: {e:#}\n"),
+            },
+        },
+        GatewayControlCommand::AdminUsage { month, format } => {
+            let month = month.unwrap_or_else(egress::current_month);
+            match format_usage(&container_manager.config().accounting, &month, format).await {
+                Ok(output) => GatewayControlExecution::Immediate {
+                    exit_status: 0u32,
+                    output,
+                },
+                Err(e) => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: {e:#}\n"),
+                },
+            }
+        }
+        GatewayControlCommand::AdminOffboard { user: None, .. } => GatewayControlExecution::Immediate {
+            exit_status: 0u32,
+            output: offboarding::format_status(container_manager).await,
+        },
+        GatewayControlCommand::AdminOffboard {
+            user: Some(user),
+            cancel,
+        } => {
+            let result = match validate_github_username(&user) {
+                Err(e) => Err(e),
+                Ok(()) if cancel => offboarding::cancel(container_manager, &user).await,
+                Ok(()) => offboarding::start_manual(container_manager, &user, github_user).await,
+            };
+            match result {
+                Ok(output) => GatewayControlExecution::Immediate {
+                    exit_status: 0u32,
+                    output,
+                },
+                Err(e) => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: offboard failed: {e:#}\n"),
+                },
+            }
+        }
+        // Like `run`, `new` needs the SSH channel (it prompts for template variables).
+        GatewayControlCommand::New { .. } => GatewayControlExecution::Immediate {
+            exit_status: 1u32,
+            output: "agentman: new is only available over an SSH exec request\n".to_string(),
+        },
+        GatewayControlCommand::Scan => GatewayControlExecution::Scan,
+        GatewayControlCommand::Search {
+            pattern,
+            project,
+            all,
+            ignore_case,
+        } => GatewayControlExecution::Search {
+            pattern,
+            project,
+            all,
+            ignore_case,
+        },
+        GatewayControlCommand::Open { target } => GatewayControlExecution::Immediate {
+            exit_status: 0u32,
+            output: open::render(
+                container_manager.config(),
+                github_user,
+                project,
+                target,
+                migration::host_public_key().as_deref(),
+            ),
+        },
+        // The client's round trips are measured by the SSH server.
+        GatewayControlCommand::Ping { count } => GatewayControlExecution::Ping {
+            count,
+            client: Vec::new(),
+        },
+        GatewayControlCommand::Verify { .. } => GatewayControlExecution::Immediate {
+            exit_status: 1u32,
+            output: "agentman: verify is only available over an SSH exec request\n".to_string(),
+        },
+        GatewayControlCommand::Route => GatewayControlExecution::Immediate {
+            exit_status: 1u32,
+            output: "agentman: route is only available over an SSH exec request\n".to_string(),
+        },
+        // The SSH server turns it into a shell when the connection can switch sandboxes.
+        GatewayControlCommand::Ephemeral => GatewayControlExecution::Immediate {
+            exit_status: 1u32,
+            output: if container_manager.config().ephemeral.enabled {
+                "agentman: ephemeral needs a connection of its own (ssh -t <gateway> agentman \
+                 ephemeral)\n"
+                    .to_string()
+            } else {
+                "agentman: ephemeral sandboxes are disabled on this gateway\n".to_string()
+            },
+        },
+        GatewayControlCommand::Proxy => GatewayControlExecution::Immediate {
+            exit_status: 0u32,
+            output: proxy::format_status(&container_manager.config().proxy, github_user, project),
+        },
+        GatewayControlCommand::Sessions => GatewayControlExecution::Immediate {
+            exit_status: 0u32,
+            output: format_sessions(&container_manager.config().shell, github_user, project),
+        },
+        GatewayControlCommand::Tunnel => GatewayControlExecution::Immediate {
+            exit_status: 0u32,
+            output: relay::format_status(relay::status().as_ref()),
+        },
+        GatewayControlCommand::CacheShow => {
+            let config = &container_manager.config().editor_cache;
+            if !config.enabled {
+                return GatewayControlExecution::Immediate {
+                    exit_status: 0u32,
+                    output: "agentman: the editor cache is not enabled on this gateway\n".to_string(),
+                };
+            }
+            let base = editor_cache::user_cache_dir(config, github_user);
+            let mut out = format!("agentman: editor cache for {github_user} ({})\n", base.display());
+            let mut total = 0;
+            for dir in &config.dirs {
+                let size = du_bytes(&base.join(dir)).await.unwrap_or(0);
+                total += size;
+                if size > 0 {
+                    out.push_str(&format!("- {dir}: {}\n", format_bytes(size)));
+                }
+            }
+            out.push_str(&format!("total: {}\n", format_bytes(total)));
+            GatewayControlExecution::Immediate {
+                exit_status: 0u32,
+                output: out,
+            }
+        }
+        GatewayControlCommand::CachePrune { older_than_days } => {
+            let config = &container_manager.config().editor_cache;
+            if !config.enabled {
+                return GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: "agentman: the editor cache is not enabled on this gateway\n".to_string(),
+                };
+            }
+            let base = editor_cache::user_cache_dir(config, github_user);
+            let before = du_bytes(&base).await.unwrap_or(0);
+            let older_than = older_than_days.map(|d| Duration::from_secs(d * 86400));
+            match editor_cache::prune(config, github_user, older_than).await {
+                Ok(report) => {
+                    let after = du_bytes(&base).await.unwrap_or(0);
+                    let mut out = String::new();
+                    for path in &report.removed {
+                        out.push_str(&format!("removed {path}\n"));
+                    }
+                    out.push_str(&format!(
+                        "agentman: pruned {} entr{}, freed {}\n",
+                        report.removed.len(),
+                        if report.removed.len() == 1 { "y" } else { "ies" },
+                        format_bytes(before.saturating_sub(after))
+                    ));
+                    GatewayControlExecution::Immediate {
+                        exit_status: 0u32,
+                        output: out,
+                    }
+                }
+                Err(e) => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: prune failed: {e:#}\n"),
+                },
+            }
+        }
+        GatewayControlCommand::Backup { action } => {
+            let config = &container_manager.config().backup;
+            if config.backend.is_none() {
+                return GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: "agentman: backups are not enabled on this gateway\n".to_string(),
+                };
+            }
+            if action != BackupAction::List {
+                // Snapshot and restore can take minutes; run them off the SSH handler.
+                return GatewayControlExecution::Backup { action };
+            }
+            match backup::list_snapshots(config, github_user, project).await {
+                Ok(snapshots) if snapshots.is_empty() => GatewayControlExecution::Immediate {
+                    exit_status: 0u32,
+                    output: format!("agentman: no snapshots for {github_user}/{project}\n"),
+                },
+                Ok(snapshots) => {
+                    let mut out = format!("agentman: snapshots for {github_user}/{project}\n");
+                    for s in snapshots.iter().rev() {
+                        out.push_str(&format!("- {}  {}\n", s.id, s.time.to_rfc3339()));
+                    }
+                    GatewayControlExecution::Immediate {
+                        exit_status: 0u32,
+                        output: out,
+                    }
+                }
+                Err(e) => GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: failed to list snapshots: {e:#}\n"),
+                },
+            }
+        }
+        GatewayControlCommand::Wait { timeout_secs, port } => GatewayControlExecution::Wait {
+            timeout: Duration::from_secs(timeout_secs),
+            port,
+        },
+        GatewayControlCommand::StatsHistory {
+            current,
+            window_secs,
+        } => {
+            let (exit_status, output) =
+                render_stats_history(container_manager, github_user, project, current, window_secs)
+                    .await;
+            GatewayControlExecution::Immediate { exit_status, output }
+        }
+        GatewayControlCommand::ExecStats { current, watch } => {
+            if watch {
+                GatewayControlExecution::WatchStats {
+                    current,
+                    interval: Duration::from_secs(1),
+                }
+            } else {
+                let (exit_status, output) = render_sandbox_stats(
+                    container_manager,
+                    inspections,
+                    github_user,
+                    project,
+                    current,
+                )
+                .await;
+                GatewayControlExecution::Immediate { exit_status, output }
+            }
+        }
+    }
+}
+
+pub(crate) async fn render_sandbox_stats(
+    container_manager: &ContainerManager,
+    inspections: &InspectCache,
+    github_user: &str,
+    project: &str,
+    current: bool,
+) -> (u32, String) {
+    let mut workspaces = if current {
+        match container_manager.get_workspace(github_user, project).await {
+            Some(ws) => vec![ws],
+            None => {
+                return (
+                    1u32,
+                    format!("agentman: no current sandbox found for {github_user}/{project}\n"),
+                );
+            }
+        }
+    } else {
+        container_manager.list_workspaces(github_user).await
+    };
+    workspaces.sort_by(|a, b| a.project.cmp(&b.project));
+
+    if workspaces.is_empty() {
+        return (0u32, format!("agentman: no sandboxes for {github_user}\n"));
+    }
+
+    let traffic = traffic_ledger(container_manager, github_user).await;
+    let mut out = format!("agentman: sandbox stats for {github_user}\n");
+    for ws in workspaces {
+        let is_current = ws.project == project;
+        let (status, id_short, running) =
+            session_container_status(container_manager, inspections, &ws.container_name).await;
+
+        let (cpu, mem) = if running {
+            match container_stats_line(container_manager, &ws.container_name).await {
+                Some((cpu, mem)) => (Some(cpu), mem),
+                None => (None, None),
+            }
+        } else {
+            (None, None)
+        };
+
+        let storage =
+            workspace_size::get(&container_manager.config().workspace_size, &ws.host_workspace_path)
+                .await;
+
+        out.push_str(&format!(
+            "- {}{}: status={}{}{}{}{} storage(workspace)={}\n",
+            ws.project,
+            if is_current { " (current)" } else { "" },
+            status,
+            if let Some(id) = id_short.as_deref() {
+                format!(" id={id}")
+            } else {
+                "".to_string()
+            },
+            if let Some(cpu) = cpu {
+                format!(" cpu={:.1}%", cpu)
+            } else {
+                " cpu=n/a".to_string()
+            },
+            if let Some((usage, limit)) = mem {
+                format!(" mem={}/{}", format_bytes(usage), format_bytes(limit))
+            } else {
+                " mem=n/a".to_string()
+            },
+            format_traffic(traffic.as_ref(), &ws.project),
+            storage
+                .map(format_bytes)
+                .unwrap_or_else(|| "n/a".to_string())
+        ));
+    }
+    push_month_traffic(&mut out, container_manager, github_user, traffic.as_ref());
+    (0u32, out)
+}
+
+/// Fast version for watch mode: skips storage (du) and parallelizes stats queries.
+pub(crate) async fn render_sandbox_stats_fast(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    current: bool,
+) -> (u32, String) {
+    let mut workspaces = if current {
+        match container_manager.get_workspace(github_user, project).await {
+            Some(ws) => vec![ws],
+            None => {
+                return (
+                    1u32,
+                    format!("agentman: no current sandbox found for {github_user}/{project}\n"),
+                );
+            }
+        }
+    } else {
+        container_manager.list_workspaces(github_user).await
+    };
+    workspaces.sort_by(|a, b| a.project.cmp(&b.project));
+
+    if workspaces.is_empty() {
+        return (0u32, format!("agentman: no sandboxes for {github_user}\n"));
+    }
+
+    // Parallelize: gather status + stats for all workspaces at once.
+    let futs: Vec<_> = workspaces
+        .iter()
+        .map(|ws| {
             let container_name = ws.container_name.clone();
             let cm = container_manager;
             async move {
@@ -507,259 +3262,3684 @@ pub(crate) async fn render_sandbox_stats_fast(
                 let (cpu, mem) = if running {
                     container_stats_line_fast(cm, &container_name).await.unwrap_or((None, None))
                 } else {
-                    (None, None)
+                    (None, None)
+                };
+                (status, id_short, cpu, mem)
+            }
+        })
+        .collect();
+
+    let results = join_all(futs).await;
+
+    let traffic = traffic_ledger(container_manager, github_user).await;
+    let mut out = format!("agentman: sandbox stats for {github_user}\n");
+    for (ws, (status, id_short, cpu, mem)) in workspaces.iter().zip(results) {
+        let is_current = ws.project == project;
+        out.push_str(&format!(
+            "- {}{}: status={}{}{}{}{}{}\n",
+            ws.project,
+            if is_current { " (current)" } else { "" },
+            status,
+            if let Some(id) = id_short.as_deref() {
+                format!(" id={id}")
+            } else {
+                "".to_string()
+            },
+            if let Some(cpu) = cpu {
+                format!(" cpu={:.1}%", cpu)
+            } else {
+                " cpu=n/a".to_string()
+            },
+            if let Some((usage, limit)) = mem {
+                format!(" mem={}/{}", format_bytes(usage), format_bytes(limit))
+            } else {
+                " mem=n/a".to_string()
+            },
+            format_traffic(traffic.as_ref(), &ws.project),
+            match proxy::egress(&ws.github_user, &ws.project) {
+                Some(egress) => format!(
+                    " proxy={} out/{} in",
+                    format_bytes(egress.sent),
+                    format_bytes(egress.received)
+                ),
+                None => "".to_string(),
+            },
+        ));
+    }
+    push_month_traffic(&mut out, container_manager, github_user, traffic.as_ref());
+    (0u32, out)
+}
+
+/// The user's network traffic ledger, when accounting is enabled.
+async fn traffic_ledger(container_manager: &ContainerManager, github_user: &str) -> Option<Ledger> {
+    let config = &container_manager.config().egress;
+    if !config.enabled {
+        return None;
+    }
+    egress::load(config, github_user)
+        .await
+        .inspect_err(|e| {
+            tracing::warn!("Failed to load the egress ledger of {}: {:#}", github_user, e)
+        })
+        .ok()
+}
+
+/// ` net=<sent> out/<received> in`: the workspace's traffic across container recreations.
+fn format_traffic(ledger: Option<&Ledger>, project: &str) -> String {
+    match ledger.and_then(|l| l.workspaces.get(project)) {
+        Some(c) => format!(" net={} out/{} in", format_bytes(c.tx_bytes), format_bytes(c.rx_bytes)),
+        None => "".to_string(),
+    }
+}
+
+fn push_month_traffic(
+    out: &mut String,
+    container_manager: &ContainerManager,
+    github_user: &str,
+    ledger: Option<&Ledger>,
+) {
+    if let Some(ledger) = ledger {
+        let quota = container_manager.config().egress.quota_bytes(github_user);
+        out.push_str(&format!(
+            "agentman: network traffic this month ({})\n",
+            egress::format_month(ledger, quota)
+        ));
+    }
+}
+
+/// `agentman stats --history`: min/avg/max and sparklines from recorded samples.
+async fn render_stats_history(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    current: bool,
+    window_secs: u64,
+) -> (u32, String) {
+    let config = &container_manager.config().stats_history;
+    if !config.enabled {
+        return (
+            1u32,
+            "agentman: stats history is not enabled on this gateway\n".to_string(),
+        );
+    }
+
+    let mut workspaces = if current {
+        match container_manager.get_workspace(github_user, project).await {
+            Some(ws) => vec![ws],
+            None => {
+                return (
+                    1u32,
+                    format!("agentman: no current sandbox found for {github_user}/{project}\n"),
+                );
+            }
+        }
+    } else {
+        container_manager.list_workspaces(github_user).await
+    };
+    workspaces.sort_by(|a, b| a.project.cmp(&b.project));
+    if workspaces.is_empty() {
+        return (0u32, format!("agentman: no sandboxes for {github_user}\n"));
+    }
+
+    let retention = config.retention_hours * 3600;
+    let window_secs = window_secs.min(retention);
+    let until = Utc::now().timestamp() + 1;
+    let since = until - window_secs as i64;
+    let width = (window_secs / config.interval_secs.max(1)).max(1) as usize;
+
+    let mut out = format!(
+        "agentman: stats history for {github_user} (last {}, one sample per {}s)\n",
+        stats_history::format_window(window_secs),
+        config.interval_secs
+    );
+    for ws in &workspaces {
+        let is_current = ws.project == project;
+        let path = stats_history::history_path(config, &ws.github_user, &ws.project);
+        let samples: Vec<_> = match stats_history::read(&path).await {
+            Ok(samples) => samples.into_iter().filter(|s| s.ts >= since).collect(),
+            Err(e) => {
+                out.push_str(&format!("- {}: failed to read history: {e:#}\n", ws.project));
+                continue;
+            }
+        };
+        let label = format!("{}{}", ws.project, if is_current { " (current)" } else { "" });
+        let (Some(cpu), Some(mem)) = (
+            stats_history::summarize(samples.iter().map(|s| s.cpu_percent)),
+            stats_history::summarize(samples.iter().map(|s| s.mem_bytes as f64)),
+        ) else {
+            out.push_str(&format!("- {label}: no samples in this window\n"));
+            continue;
+        };
+
+        let cpu_points: Vec<_> = samples.iter().map(|s| (s.ts, s.cpu_percent)).collect();
+        let mem_points: Vec<_> = samples.iter().map(|s| (s.ts, s.mem_bytes as f64)).collect();
+        let limit = samples.last().map(|s| s.mem_limit).unwrap_or(0);
+        out.push_str(&format!("- {label}: {} samples\n", samples.len()));
+        out.push_str(&format!(
+            "    cpu  min {:.1}%  avg {:.1}%  max {:.1}%  |{}|\n",
+            cpu.min,
+            cpu.avg,
+            cpu.max,
+            stats_history::sparkline(&cpu_points, since, until, width)
+        ));
+        out.push_str(&format!(
+            "    mem  min {}  avg {}  max {}{}  |{}|\n",
+            format_bytes(mem.min as u64),
+            format_bytes(mem.avg as u64),
+            format_bytes(mem.max as u64),
+            if limit > 0 {
+                format!(" / {}", format_bytes(limit))
+            } else {
+                String::new()
+            },
+            stats_history::sparkline(&mem_points, since, until, width)
+        ));
+    }
+    (0u32, out)
+}
+
+/// Block until the current sandbox is ready, or `timeout` expires.
+///
+/// Ready means: container running, init command (if any) succeeded, and the configured TCP
+/// port / health command (if any) pass. The container is started if needed.
+pub(crate) async fn wait_until_ready(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    timeout: Duration,
+    port: Option<u16>,
+    cancelled: &AtomicBool,
+) -> (u32, String) {
+    let started = tokio::time::Instant::now();
+    let deadline = started + timeout;
+
+    let container_id = match tokio::time::timeout(
+        timeout,
+        container_manager.get_or_create_container(github_user, project),
+    )
+    .await
+    {
+        Ok(Ok(id)) => id,
+        Ok(Err(e)) => return (1u32, format!("agentman: failed to start sandbox: {e}\n")),
+        Err(_) => {
+            return (
+                WAIT_TIMEOUT_EXIT_STATUS,
+                format!(
+                    "agentman: timed out after {}s waiting for the container to start\n",
+                    timeout.as_secs()
+                ),
+            );
+        }
+    };
+
+    let readiness = &container_manager.config().readiness;
+    let port = port.or(readiness.tcp_port);
+
+    loop {
+        if cancelled.load(Ordering::Relaxed) {
+            return (130u32, "agentman: wait cancelled\n".to_string());
+        }
+
+        let pending = match readiness_blocker(container_manager, github_user, project, &container_id, port)
+            .await
+        {
+            Ok(None) => {
+                return (
+                    0u32,
+                    format!(
+                        "agentman: sandbox {project} is ready ({:.1}s)\n",
+                        started.elapsed().as_secs_f64()
+                    ),
+                );
+            }
+            Ok(Some(pending)) => pending,
+            Err(fatal) => return (1u32, format!("agentman: sandbox {project} is not ready: {fatal}\n")),
+        };
+
+        if tokio::time::Instant::now() >= deadline {
+            return (
+                WAIT_TIMEOUT_EXIT_STATUS,
+                format!(
+                    "agentman: timed out after {}s waiting for: {pending}\n",
+                    timeout.as_secs()
+                ),
+            );
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+/// Recreate the current sandbox's container (optionally blue/green).
+pub(crate) async fn run_recreate(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    keep_running: bool,
+    pull: bool,
+) -> (u32, String) {
+    let started = tokio::time::Instant::now();
+    let opts = RecreateOptions { keep_running, pull };
+    match container_manager
+        .recreate_container(github_user, project, opts)
+        .await
+    {
+        Ok(res) => {
+            let mut out = format!(
+                "agentman: recreated sandbox {project} as {} in {:.1}s\n",
+                res.new_container,
+                started.elapsed().as_secs_f64()
+            );
+            if let Some(old) = res.old_container {
+                out.push_str(&format!("agentman: retired previous container {old}\n"));
+            }
+            for w in res.warnings {
+                out.push_str(&format!("agentman: warning: {w}\n"));
+            }
+            (0u32, out)
+        }
+        Err(e) => (1u32, format!("agentman: recreate failed: {e:#}\n")),
+    }
+}
+
+/// Stop the current sandbox's container and start it again, running its start-up (stack,
+/// init command) as a new connection would.
+pub(crate) async fn run_restart(
+    backend: &impl ContainerBackend,
+    github_user: &str,
+    project: &str,
+) -> (u32, String) {
+    let started = tokio::time::Instant::now();
+    let container_name = match backend.stop_container(github_user, project).await {
+        Ok(Some(name)) => name,
+        Ok(None) => {
+            return (1u32, format!("agentman: no sandbox found for {github_user}/{project}\n"));
+        }
+        Err(e) => return (1u32, format!("agentman: stop failed: {e:#}\n")),
+    };
+    match backend.get_or_create_container(github_user, project).await {
+        Ok(_) => (
+            0u32,
+            format!(
+                "agentman: restarted sandbox {project} ({container_name}) in {:.1}s\n",
+                started.elapsed().as_secs_f64()
+            ),
+        ),
+        Err(e) => (1u32, format!("agentman: restart failed: {e:#}\n")),
+    }
+}
+
+/// Checkpoint the current sandbox or resume it from its latest checkpoint.
+pub(crate) async fn run_checkpoint_action(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    action: CheckpointAction,
+) -> (u32, String) {
+    let started = tokio::time::Instant::now();
+    match action {
+        CheckpointAction::Create { leave_running } => {
+            match checkpoint::create(container_manager, github_user, project, leave_running).await
+            {
+                Ok(c) => (
+                    0u32,
+                    format!(
+                        "agentman: checkpointed sandbox {project} as {} in {:.1}s{}\n",
+                        c.name,
+                        started.elapsed().as_secs_f64(),
+                        if leave_running {
+                            ""
+                        } else {
+                            "; it is stopped, continue with `agentman resume`"
+                        }
+                    ),
+                ),
+                Err(e) => (1u32, format!("agentman: checkpoint failed: {e:#}\n")),
+            }
+        }
+        CheckpointAction::Resume => {
+            match checkpoint::resume(container_manager, github_user, project).await {
+                Ok(c) => (
+                    0u32,
+                    format!(
+                        "agentman: resumed sandbox {project} from checkpoint {} ({}) in {:.1}s\n",
+                        c.name,
+                        c.time.to_rfc3339(),
+                        started.elapsed().as_secs_f64()
+                    ),
+                ),
+                Err(e) => (1u32, format!("agentman: resume failed: {e:#}\n")),
+            }
+        }
+    }
+}
+
+/// Bring the current workspace's stack up (with its sandbox) or take it down.
+pub(crate) async fn run_stack_action(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    action: StackAction,
+) -> (u32, String) {
+    let result = match action {
+        StackAction::Status => {
+            return stack::format_status(container_manager, github_user, project).await;
+        }
+        StackAction::Up => stack_up(container_manager, github_user, project).await,
+        StackAction::Down => stack::down(container_manager, github_user, project).await,
+    };
+    match result {
+        Ok(lines) if lines.is_empty() => (0u32, "agentman: stack is up to date\n".to_string()),
+        Ok(lines) => (
+            0u32,
+            lines.iter().map(|l| format!("agentman: {l}\n")).collect(),
+        ),
+        Err(e) => {
+            let verb = if action == StackAction::Up { "up" } else { "down" };
+            (1u32, format!("agentman: stack {verb} failed: {e:#}\n"))
+        }
+    }
+}
+
+async fn stack_up(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+) -> anyhow::Result<Vec<String>> {
+    let (_, compose) = stack::load(container_manager.config(), github_user, project)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("no compose file; declare the stack in /workspace/.agentman/compose.yaml"))?;
+    let report = stack::up(container_manager, github_user, project, &compose).await?;
+    let container_id = container_manager
+        .get_or_create_container(github_user, project)
+        .await?;
+    stack::attach_sandbox(container_manager, github_user, project, &container_id).await?;
+    Ok(report)
+}
+
+/// Create or restore a backup snapshot of the current workspace.
+pub(crate) async fn run_backup_action(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    action: BackupAction,
+) -> (u32, String) {
+    let config = &container_manager.config().backup;
+    let workspace_path = container_manager.config().workspace_path(github_user, project);
+    let started = tokio::time::Instant::now();
+
+    match action {
+        BackupAction::Create | BackupAction::List => {
+            match backup::create_snapshot(config, github_user, project, &workspace_path).await {
+                Ok(s) => (
+                    0u32,
+                    format!(
+                        "agentman: created snapshot {} ({}) in {:.1}s\n",
+                        s.id,
+                        s.time.to_rfc3339(),
+                        started.elapsed().as_secs_f64()
+                    ),
+                ),
+                Err(e) => (1u32, format!("agentman: backup failed: {e:#}\n")),
+            }
+        }
+        BackupAction::Restore { spec, yes } => {
+            let snapshots = match backup::list_snapshots(config, github_user, project).await {
+                Ok(s) => s,
+                Err(e) => return (1u32, format!("agentman: failed to list snapshots: {e:#}\n")),
+            };
+            let target = match backup::select_snapshot(&snapshots, &spec) {
+                Ok(t) => t.clone(),
+                Err(e) => return (1u32, format!("agentman: {e}\n")),
+            };
+            if !yes {
+                return (
+                    1u32,
+                    format!(
+                        "agentman: would restore snapshot {} ({}) over /workspace, replacing its current \
+                         contents and stopping the sandbox.\nRe-run with --yes to proceed.\n",
+                        target.id,
+                        target.time.to_rfc3339()
+                    ),
+                );
+            }
+
+            // Save what is there now so the restore itself can be undone.
+            let safety = match backup::create_snapshot(config, github_user, project, &workspace_path)
+                .await
+            {
+                Ok(s) => Some(s),
+                Err(_) if !workspace_path.exists() => None,
+                Err(e) => {
+                    return (
+                        1u32,
+                        format!("agentman: refusing to restore, pre-restore snapshot failed: {e:#}\n"),
+                    );
+                }
+            };
+
+            if let Some(ws) = container_manager.get_workspace(github_user, project).await {
+                match container_manager
+                    .docker()
+                    .stop_container(
+                        &ws.container_name,
+                        Some(StopContainerOptionsBuilder::new().t(10).build()),
+                    )
+                    .await
+                {
+                    Ok(_)
+                    | Err(BollardError::DockerResponseServerError {
+                        status_code: 304 | 404,
+                        ..
+                    }) => {}
+                    Err(e) => return (1u32, format!("agentman: failed to stop sandbox: {e}\n")),
+                }
+            }
+
+            if let Err(e) =
+                backup::restore_snapshot(config, github_user, project, &workspace_path, &target).await
+            {
+                return (1u32, format!("agentman: restore failed: {e:#}\n"));
+            }
+
+            let mut out = format!(
+                "agentman: restored snapshot {} ({}) in {:.1}s\n",
+                target.id,
+                target.time.to_rfc3339(),
+                started.elapsed().as_secs_f64()
+            );
+            if let Some(s) = safety {
+                out.push_str(&format!(
+                    "agentman: previous contents saved as snapshot {} (undo with `agentman backup restore {} --yes`)\n",
+                    s.id, s.id
+                ));
+            }
+            out.push_str("agentman: the sandbox was stopped; reconnect to start it again\n");
+            (0u32, out)
+        }
+    }
+}
+
+/// Returns `Ok(None)` when ready, `Ok(Some(what))` while still waiting, and `Err` when the
+/// sandbox can never become ready without intervention (e.g. the init command failed).
+async fn readiness_blocker(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    container_id: &str,
+    port: Option<u16>,
+) -> Result<Option<String>, String> {
+    let (status, _) = workspace_container_status(container_manager, container_id).await;
+    if status != "running" {
+        return Ok(Some(format!("container ({status})")));
+    }
+
+    let ws = container_manager.get_workspace(github_user, project).await;
+    if container_manager.effective_init_command(ws.as_ref()).is_some() {
+        match ws.as_ref().and_then(|w| w.init_status.as_ref()) {
+            None => return Ok(Some("init command (not started)".to_string())),
+            Some(st) if st.state == InitState::Running => {
+                return Ok(Some("init command (running)".to_string()));
+            }
+            Some(st) if st.state == InitState::Failed => {
+                return Err(format!(
+                    "init command failed{}; see `agentman init log`",
+                    st.exit_code.map(|c| format!(" (exit {c})")).unwrap_or_default()
+                ));
+            }
+            Some(_) => {}
+        }
+    }
+
+    if let Some(port) = port {
+        let probe = format!("exec 3<>/dev/tcp/127.0.0.1/{port}");
+        let ok = container_manager
+            .run_exec(container_id, vec!["/bin/bash".to_string(), "-c".to_string(), probe])
+            .await
+            .is_ok_and(|o| o.exit_code == 0);
+        if !ok {
+            return Ok(Some(format!("tcp port {port}")));
+        }
+    }
+
+    if let Some(ref cmd) = container_manager.config().readiness.health_command {
+        let result = container_manager
+            .run_exec(
+                container_id,
+                vec!["/bin/bash".to_string(), "-lc".to_string(), cmd.clone()],
+            )
+            .await;
+        match result {
+            Ok(out) if out.exit_code == 0 => {}
+            Ok(out) => {
+                // Surface the last line the check printed; it usually says why it is failing.
+                let text = if out.stderr.is_empty() { out.stdout } else { out.stderr };
+                let text = String::from_utf8_lossy(&text);
+                return Ok(Some(match text.trim().lines().last() {
+                    Some(line) => format!("health command ({cmd}): {line}"),
+                    None => format!("health command ({cmd}): exit {}", out.exit_code),
+                }));
+            }
+            Err(e) => return Ok(Some(format!("health command ({cmd}): {e}"))),
+        }
+    }
+
+    Ok(None)
+}
+
+/// `agentman admin migrate`: move `owner/project` to another gateway.
+pub(crate) async fn run_migrate(
+    container_manager: &ContainerManager,
+    admin: &str,
+    owner: &str,
+    project: &str,
+    node: &str,
+    checkpoint: bool,
+) -> (u32, String) {
+    match migration::migrate(container_manager, owner, project, node, checkpoint, admin).await {
+        Ok(report) => {
+            tracing::info!("{} migrated {}/{} to {}", admin, owner, project, node);
+            (0u32, report)
+        }
+        Err(e) => (1u32, format!("agentman: migration failed: {e:#}\n")),
+    }
+}
+
+/// `agentman admin operations resume`: run the rest of the operation on `owner/project`.
+pub(crate) async fn run_resume_operation(
+    container_manager: &ContainerManager,
+    admin: &str,
+    owner: &str,
+    project: &str,
+) -> (u32, String) {
+    match journal::resume(container_manager, owner, project, admin).await {
+        Ok(report) => {
+            tracing::info!("{} resumed the operation on {}/{}", admin, owner, project);
+            (0u32, report)
+        }
+        Err(e) => (1u32, format!("agentman: {e:#}\n")),
+    }
+}
+
+/// `agentman ping`: report `client` and time `count` round trips to the Docker daemon and
+/// into the sandbox (if it is running). Exits 1 if a hop didn't answer at all.
+pub(crate) async fn run_ping(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    count: u32,
+    client: &[Option<Duration>],
+) -> (u32, String) {
+    let mut docker = Vec::new();
+    for _ in 0..count {
+        let started = tokio::time::Instant::now();
+        let ok = timeout(PING_TIMEOUT, container_manager.docker().ping())
+            .await
+            .is_ok_and(|r| r.is_ok());
+        docker.push(ok.then(|| started.elapsed()));
+    }
+
+    let mut sandbox = Vec::new();
+    let sandbox_note = match container_manager.get_workspace(github_user, project).await {
+        None => Some("no sandbox yet"),
+        Some(ws) => {
+            let (_, _, running) =
+                workspace_container_status_with_running(container_manager, &ws.container_name)
+                    .await;
+            if running {
+                for _ in 0..count {
+                    let started = tokio::time::Instant::now();
+                    let exec = container_manager
+                        .run_exec(&ws.container_name, vec!["true".to_string()]);
+                    let ok = timeout(PING_TIMEOUT, exec).await.is_ok_and(|r| r.is_ok());
+                    sandbox.push(ok.then(|| started.elapsed()));
+                }
+                None
+            } else {
+                Some("not running (connect to start it)")
+            }
+        }
+    };
+
+    let mut out = format!("agentman: round trips from the gateway ({count} each, min/avg/max)\n");
+    let mut all_answered = true;
+    let mut line = |hop: &str, how: &str, samples: &[Option<Duration>]| {
+        all_answered &= samples.iter().any(Option::is_some);
+        out.push_str(&format!("  {hop:<10} {:<34} {how}\n", format_round_trips(samples)));
+    };
+    line("client", "SSH keepalive", client);
+    line("dockerd", "docker ping", &docker);
+    match sandbox_note {
+        Some(note) => out.push_str(&format!("  {:<10} {note}\n", "sandbox")),
+        None => line("sandbox", "exec true", &sandbox),
+    }
+    (if all_answered { 0 } else { 1 }, out)
+}
+
+/// `agentman scan`: scan the workspace directory; exits 1 if anything was found.
+pub(crate) async fn run_scan(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+) -> (u32, String) {
+    let config = &container_manager.config().scan;
+    let dir = container_manager.config().workspace_path(github_user, project);
+    match scan::scan(config, &dir).await {
+        Ok(report) => {
+            let mut out = format!(
+                "agentman: scanned {} entries in /workspace: found {}\n",
+                report.entries,
+                report.summary()
+            );
+            out.push_str(&report.format(config.max_findings));
+            (if report.findings.is_empty() { 0 } else { 1 }, out)
+        }
+        Err(e) => (1u32, format!("agentman: scan failed: {e:#}\n")),
+    }
+}
+
+/// `agentman search`: search the workspaces `project` and `all` select; exits 1 if nothing
+/// matched.
+pub(crate) async fn run_search(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    pattern: &str,
+    other: Option<&str>,
+    all: bool,
+    ignore_case: bool,
+) -> (u32, String) {
+    let mut workspaces = if all {
+        container_manager.list_workspaces(github_user).await
+    } else {
+        let project = other.unwrap_or(project);
+        if let Err(e) = validate_project_name(project) {
+            return (1u32, format!("agentman: {e}\n"));
+        }
+        match container_manager.get_workspace(github_user, project).await {
+            Some(ws) => vec![ws],
+            None => {
+                return (
+                    1u32,
+                    format!("agentman: no sandbox found for {github_user}/{project}\n"),
+                );
+            }
+        }
+    };
+    workspaces.sort_by(|a, b| a.project.cmp(&b.project));
+    if workspaces.is_empty() {
+        return (1u32, format!("agentman: no sandboxes for {github_user}\n"));
+    }
+
+    let results = search::search_all(container_manager, &workspaces, pattern, ignore_case).await;
+    let mut out = String::new();
+    let mut notes = String::new();
+    let (mut total, mut matched, mut on_host) = (0, 0, 0);
+    for (ws, result) in workspaces.iter().zip(results) {
+        let found = match result {
+            Ok(found) => found,
+            Err(e) => {
+                notes.push_str(&format!("agentman: searching {} failed: {e:#}\n", ws.project));
+                continue;
+            }
+        };
+        for m in &found.matches {
+            out.push_str(&format!("{}/{}:{}: {}\n", ws.project, m.path, m.line, m.text));
+        }
+        if found.truncated {
+            notes.push_str(&format!(
+                "agentman: {} has more matches than the {} shown\n",
+                ws.project,
+                found.matches.len()
+            ));
+        }
+        total += found.matches.len();
+        matched += usize::from(!found.matches.is_empty());
+        on_host += usize::from(found.on_host);
+    }
+    out.push_str(&notes);
+    let stopped = if on_host > 0 {
+        format!(" ({on_host} stopped, searched on the gateway)")
+    } else {
+        String::new()
+    };
+    out.push_str(&format!(
+        "agentman: {total} matches for {pattern:?} in {matched} of {} projects{stopped}\n",
+        workspaces.len()
+    ));
+    (if total > 0 { 0 } else { 1 }, out)
+}
+
+pub(crate) async fn run_build(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    file: Option<&str>,
+    no_cache: bool,
+    use_image: bool,
+) -> (u32, String) {
+    let built = match build::build(container_manager, github_user, project, file, no_cache).await {
+        Ok(built) => built,
+        Err(e) => return (1u32, format!("agentman: build failed: {e:#}\n")),
+    };
+    let mut out: String = built.steps.iter().map(|step| format!("  {step}\n")).collect();
+    let size = built
+        .size_bytes
+        .map(|bytes| format!(", {:.0} MiB", bytes as f64 / (1024.0 * 1024.0)))
+        .unwrap_or_default();
+    out.push_str(&format!(
+        "agentman: built {} ({}{}) in {}s\n",
+        built.tag,
+        built.image_id.trim_start_matches("sha256:").get(..12).unwrap_or(&built.image_id),
+        size,
+        built.elapsed.as_secs()
+    ));
+    if !use_image {
+        out.push_str(&format!(
+            "Use it with `agentman image set {}` or `agentman build --use`.\n",
+            built.tag
+        ));
+        return (0u32, out);
+    }
+    let tag = built.tag;
+    match container_manager
+        .state()
+        .update_workspace(github_user, project, |ws| ws.image = Some(tag.clone()))
+        .await
+    {
+        Ok(true) => {
+            out.push_str(&format!(
+                "agentman: image for {project} set to {tag}\nIt applies the next time the \
+                 sandbox starts (after `agentman stop`), or now via `agentman recreate`.\n"
+            ));
+            (0u32, out)
+        }
+        Ok(false) => (
+            1u32,
+            format!("{out}agentman: no sandbox found for {github_user}/{project}\n"),
+        ),
+        Err(e) => (1u32, format!("{out}agentman: failed to update image: {e}\n")),
+    }
+}
+
+/// `min/avg/max` of the answered round trips, plus how many weren't answered.
+fn format_round_trips(samples: &[Option<Duration>]) -> String {
+    let answered: Vec<f64> = samples
+        .iter()
+        .flatten()
+        .map(|d| d.as_secs_f64() * 1000.0)
+        .collect();
+    let lost = samples.len() - answered.len();
+    if answered.is_empty() {
+        return "no answer".to_string();
+    }
+    let min = answered.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = answered.iter().copied().fold(0.0, f64::max);
+    let avg = answered.iter().sum::<f64>() / answered.len() as f64;
+    let mut out = format!("{min:.1}/{avg:.1}/{max:.1} ms");
+    if lost > 0 {
+        out.push_str(&format!(" ({lost} lost)"));
+    }
+    out
+}
+
+/// `agentman admin state import`: check the snapshot, and replace the state with it if `yes`.
+async fn import_state(
+    container_manager: &ContainerManager,
+    admin: &str,
+    path: &Path,
+    yes: bool,
+) -> anyhow::Result<String> {
+    let content = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| anyhow::anyhow!("failed to read {}: {e}", path.display()))?;
+    let imported = state_snapshot::parse(&content)?;
+    let summary = state_snapshot::summary(&imported);
+    if !yes {
+        return Ok(format!(
+            "agentman: {} is a valid snapshot: {summary}\n\
+             agentman: importing replaces the gateway's current state; rerun with --yes\n",
+            path.display()
+        ));
+    }
+    let backup = state_snapshot::import(container_manager.state(), imported).await?;
+    tracing::info!("State imported from {} by {}: {}", path.display(), admin, summary);
+    let mut out = format!("agentman: imported {summary}\n");
+    if let Some(backup) = backup {
+        out.push_str(&format!(
+            "agentman: the previous state file is kept as {}\n",
+            backup.display()
+        ));
+    }
+    Ok(out)
+}
+
+async fn execute_tls(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    action: TlsAction,
+) -> GatewayControlExecution {
+    let config = &container_manager.config().sni_router;
+    let fail = |output: String| GatewayControlExecution::Immediate {
+        exit_status: 1u32,
+        output,
+    };
+    if !config.enabled {
+        return fail("agentman: the SNI router is not enabled on this gateway\n".to_string());
+    }
+    let Some(hostname) = sni_router::hostname(&config.domain, github_user, project) else {
+        return fail(format!(
+            "agentman: project {project} can't be part of a hostname (use letters, digits and \
+             dashes, at most 63 characters with your username)\n"
+        ));
+    };
+    let port = match action {
+        TlsAction::Show => {
+            let Some(ws) = container_manager.get_workspace(github_user, project).await else {
+                return fail(format!("agentman: no sandbox found for {github_user}/{project}\n"));
+            };
+            let output = match ws.tls_port {
+                Some(port) => {
+                    format!("agentman: {hostname}:{} -> port {port}\n", listen_port(config))
+                }
+                None => format!(
+                    "agentman: {hostname} is not exposed; use `agentman tls expose <port>`\n"
+                ),
+            };
+            return GatewayControlExecution::Immediate {
+                exit_status: 0u32,
+                output,
+            };
+        }
+        TlsAction::Expose { port } => Some(port),
+        TlsAction::Close => None,
+    };
+    match container_manager
+        .state()
+        .update_workspace(github_user, project, |ws| ws.tls_port = port)
+        .await
+    {
+        Ok(true) => GatewayControlExecution::Immediate {
+            exit_status: 0u32,
+            output: match port {
+                Some(port) => format!(
+                    "agentman: routing TLS connections for {hostname}:{} to port {port} in the \
+                     sandbox\n",
+                    listen_port(config)
+                ),
+                None => format!("agentman: stopped routing {hostname}\n"),
+            },
+        },
+        Ok(false) => fail(format!("agentman: no sandbox found for {github_user}/{project}\n")),
+        Err(e) => fail(format!("agentman: failed to update TLS routing: {e}\n")),
+    }
+}
+
+async fn run_ownership_action(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    action: OwnershipAction,
+) -> anyhow::Result<String> {
+    if !container_manager.config().workspace_permissions.watch_ownership {
+        anyhow::bail!("the ownership reconciler is not enabled on this gateway");
+    }
+    let state = container_manager.state();
+    let watch = match action {
+        OwnershipAction::Show => {
+            let Some(ws) = state.get_workspace(github_user, project).await else {
+                anyhow::bail!("no sandbox found for {github_user}/{project}");
+            };
+            return Ok(match (ws.watch_ownership, ownership::is_watching(github_user, project)) {
+                (false, _) => format!(
+                    "agentman: {project} is not watched; use `agentman ownership watch`\n"
+                ),
+                (true, true) => format!("agentman: watching new files in {project}\n"),
+                (true, false) => format!(
+                    "agentman: {project} is opted in, but its watcher is not running (see the \
+                     gateway log); it restarts with the sandbox\n"
+                ),
+            });
+        }
+        OwnershipAction::Watch => true,
+        OwnershipAction::Unwatch => false,
+    };
+    if !state
+        .update_workspace(github_user, project, |ws| ws.watch_ownership = watch)
+        .await?
+    {
+        anyhow::bail!("no sandbox found for {github_user}/{project}");
+    }
+    if watch {
+        if let Err(e) = container_manager
+            .ensure_ownership_watcher(github_user, project)
+            .await
+        {
+            state
+                .update_workspace(github_user, project, |ws| ws.watch_ownership = false)
+                .await?;
+            return Err(e);
+        }
+        Ok(format!(
+            "agentman: files that root creates in {project} now go to the sandbox user\n"
+        ))
+    } else {
+        ownership::stop_watcher(github_user, project);
+        Ok(format!("agentman: stopped watching {project}\n"))
+    }
+}
+
+/// The public port of the SNI router, as clients connect to it.
+fn listen_port(config: &SniRouterConfig) -> u16 {
+    config
+        .listen_addr
+        .parse::<std::net::SocketAddr>()
+        .map_or(443, |addr| addr.port())
+}
+
+async fn execute_image(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    action: ImageAction,
+) -> GatewayControlExecution {
+    // The user's `users_dir` file may pick another default image.
+    let config = match container_manager.user_config(github_user).await {
+        Ok(config) => config,
+        Err(e) => {
+            return GatewayControlExecution::Immediate {
+                exit_status: 1u32,
+                output: format!("agentman: {e:#}\n"),
+            };
+        }
+    };
+    let ws = container_manager.get_workspace(github_user, project).await;
+    let (choice, now) = match action {
+        ImageAction::Show => {
+            let Some(ws) = ws else {
+                return GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!("agentman: no sandbox found for {github_user}/{project}\n"),
+                };
+            };
+            let wanted = container_manager.effective_image(github_user, Some(&ws)).await;
+            let source = match ws.image {
+                Some(ref image) if *image == wanted => "selected",
+                Some(_) => "gateway default; your selection is no longer allowed",
+                None => "gateway default",
+            };
+            let mut out = format!("agentman: image for {project}: {wanted} ({source})\n");
+            if let Ok(info) = container_manager
+                .docker()
+                .inspect_container(&ws.container_name, None::<InspectContainerOptions>)
+                .await
+                && let Some(config) = info.config
+                && config.labels.as_ref().and_then(|l| l.get(IMAGE_CHOICE_LABEL)) != ws.image.as_ref()
+            {
+                out.push_str(&format!(
+                    "agentman: the container still runs {}; it switches the next time the sandbox \
+                     starts (or now via `agentman recreate`)\n",
+                    config.image.as_deref().unwrap_or("its previous image")
+                ));
+            }
+            return GatewayControlExecution::Immediate {
+                exit_status: 0u32,
+                output: out,
+            };
+        }
+        ImageAction::List => {
+            let current = match ws {
+                Some(ws) => Some(container_manager.effective_image(github_user, Some(&ws)).await),
+                None => None,
+            };
+            let mut out = String::new();
+            let mut named: Vec<_> = config.images.named.iter().collect();
+            named.sort();
+            let mut images = vec![&config.docker_image];
+            for image in config.images.allowed.iter().chain(named.iter().map(|(_, i)| *i)) {
+                if !images.contains(&image) {
+                    images.push(image);
+                }
+            }
+            for image in images {
+                let mut notes = Vec::new();
+                if *image == config.docker_image {
+                    notes.push("default".to_string());
+                }
+                for (name, _) in named.iter().filter(|(_, i)| *i == image) {
+                    notes.push(format!("{project}.{name}"));
+                }
+                if current.as_ref() == Some(image) {
+                    notes.push("current".to_string());
+                }
+                if notes.is_empty() {
+                    out.push_str(&format!("  {image}\n"));
+                } else {
+                    out.push_str(&format!("  {image} ({})\n", notes.join(", ")));
+                }
+            }
+            let built = build::tag(&config.build, github_user, project);
+            if config.build.enabled
+                && container_manager.docker().inspect_image(&built).await.is_ok()
+            {
+                if current.as_ref() == Some(&built) {
+                    out.push_str(&format!("  {built} (built, current)\n"));
+                } else {
+                    out.push_str(&format!("  {built} (built)\n"));
+                }
+            } else if config.images.allowed.is_empty() && config.images.named.is_empty() {
+                out.push_str("agentman: image switching is not enabled on this gateway\n");
+            }
+            return GatewayControlExecution::Immediate {
+                exit_status: 0u32,
+                output: out,
+            };
+        }
+        ImageAction::Set { image, now } => {
+            let image = config.named_image(&image).map_or(image, str::to_string);
+            let own = build::is_own_image(&config.build, &image, github_user, project);
+            if !config.image_allowed(&image) && !own {
+                return GatewayControlExecution::Immediate {
+                    exit_status: 1u32,
+                    output: format!(
+                        "agentman: image {image} is not allowed; see `agentman image list`\n"
+                    ),
+                };
+            }
+            // Picking the default is the same as resetting.
+            ((image != config.docker_image).then_some(image), now)
+        }
+        ImageAction::Reset { now } => (None, now),
+    };
+
+    let output = match choice {
+        Some(ref image) => format!("agentman: image for {project} set to {image}\n"),
+        None => format!(
+            "agentman: image for {project} reset to the gateway default ({})\n",
+            config.docker_image
+        ),
+    };
+    match container_manager
+        .state()
+        .update_workspace(github_user, project, |ws| ws.image = choice)
+        .await
+    {
+        // Recreating can take minutes (pulls); run off the SSH handler.
+        Ok(true) if now => GatewayControlExecution::Recreate {
+            keep_running: false,
+            pull: false,
+        },
+        Ok(true) => GatewayControlExecution::Immediate {
+            exit_status: 0u32,
+            output: format!(
+                "{output}It applies the next time the sandbox starts (after `agentman stop`), or \
+                 now via `agentman recreate`.\n"
+            ),
+        },
+        Ok(false) => GatewayControlExecution::Immediate {
+            exit_status: 1u32,
+            output: format!("agentman: no sandbox found for {github_user}/{project}\n"),
+        },
+        Err(e) => GatewayControlExecution::Immediate {
+            exit_status: 1u32,
+            output: format!("agentman: failed to update image: {e}\n"),
+        },
+    }
+}
+
+async fn run_resize_action(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    action: ResizeAction,
+) -> anyhow::Result<String> {
+    let security = &container_manager.config().container_security;
+    let state = container_manager.state();
+    let resources = match action {
+        ResizeAction::Show => {
+            let Some(ws) = state.get_workspace(github_user, project).await else {
+                anyhow::bail!("no sandbox found for {github_user}/{project}");
+            };
+            let line = |what: &str, picked: Option<String>, gateway: Option<String>| {
+                let (value, source) = match (picked, gateway) {
+                    (Some(value), _) => (value, "picked with agentman resize"),
+                    (None, Some(value)) => (value, "gateway default"),
+                    (None, None) => ("unset".to_string(), "Docker default"),
+                };
+                format!("- {what}: {value} ({source})\n")
+            };
+            let r = &ws.resources;
+            return Ok(format!(
+                "agentman: sizes for {project}, applied when its container is created:\n{}{}{}",
+                line("/dev/shm", r.shm_size.clone(), security.shm_size.clone()),
+                line(
+                    "open files",
+                    r.nofile.map(|n| n.to_string()),
+                    security.nofile.map(|n| n.to_string())
+                ),
+                line(
+                    "processes",
+                    r.nproc.map(|n| n.to_string()),
+                    security.nproc.map(|n| n.to_string())
+                ),
+            ));
+        }
+        ResizeAction::Set {
+            shm_size,
+            nofile,
+            nproc,
+            ..
+        } => {
+            if let Some(shm) = &shm_size {
+                let bytes = crate::docker::parse_memory_limit(shm)
+                    .ok()
+                    .filter(|bytes| *bytes > 0)
+                    .ok_or_else(|| anyhow::anyhow!("--shm must be a size like 2g"))?;
+                if bytes > crate::docker::parse_memory_limit(&security.max_shm_size)? {
+                    anyhow::bail!("--shm can be at most {} on this gateway", security.max_shm_size);
+                }
+            }
+            if nofile.is_some_and(|n| n > security.max_nofile) {
+                anyhow::bail!("--nofile can be at most {} on this gateway", security.max_nofile);
+            }
+            if nproc.is_some_and(|n| n > security.max_nproc) {
+                anyhow::bail!("--nproc can be at most {} on this gateway", security.max_nproc);
+            }
+            let current = state
+                .get_workspace(github_user, project)
+                .await
+                .map(|ws| ws.resources)
+                .unwrap_or_default();
+            WorkspaceResources {
+                shm_size: shm_size.or(current.shm_size),
+                nofile: nofile.or(current.nofile),
+                nproc: nproc.or(current.nproc),
+            }
+        }
+        ResizeAction::Reset { .. } => WorkspaceResources::default(),
+    };
+    let output = if resources == WorkspaceResources::default() {
+        format!("agentman: sizes for {project} reset to the gateway defaults\n")
+    } else {
+        let mut changes = Vec::new();
+        if let Some(shm) = &resources.shm_size {
+            changes.push(format!("/dev/shm {shm}"));
+        }
+        if let Some(n) = resources.nofile {
+            changes.push(format!("{n} open files"));
+        }
+        if let Some(n) = resources.nproc {
+            changes.push(format!("{n} processes"));
+        }
+        format!("agentman: sizes for {project} set to {}\n", changes.join(", "))
+    };
+    if !state
+        .update_workspace(github_user, project, |ws| ws.resources = resources)
+        .await?
+    {
+        anyhow::bail!("no sandbox found for {github_user}/{project}");
+    }
+    Ok(format!(
+        "{output}They apply the next time the sandbox starts (after `agentman stop`), or now via \
+         `agentman recreate`.\n"
+    ))
+}
+
+async fn set_workspace_init_command(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    command: Option<String>,
+) -> GatewayControlExecution {
+    let output = match command {
+        Some(ref cmd) => format!(
+            "agentman: init command for {project} set to: {cmd}\n\
+             It runs the next time the container starts (or now via `agentman init run`).\n"
+        ),
+        None => format!("agentman: init command for {project} cleared\n"),
+    };
+    match container_manager
+        .state()
+        .update_workspace(github_user, project, |ws| ws.init_command = command)
+        .await
+    {
+        Ok(true) => GatewayControlExecution::Immediate {
+            exit_status: 0u32,
+            output,
+        },
+        Ok(false) => GatewayControlExecution::Immediate {
+            exit_status: 1u32,
+            output: format!("agentman: no sandbox found for {github_user}/{project}\n"),
+        },
+        Err(e) => GatewayControlExecution::Immediate {
+            exit_status: 1u32,
+            output: format!("agentman: failed to update init command: {e}\n"),
+        },
+    }
+}
+
+fn format_init_status(container_manager: &ContainerManager, ws: &WorkspaceInfo) -> String {
+    match ws.init_status {
+        None if container_manager.effective_init_command(Some(ws)).is_some() => {
+            "not run yet".to_string()
+        }
+        None => "none configured".to_string(),
+        Some(ref st) => {
+            let mut out = st.state.to_string();
+            if let Some(code) = st.exit_code {
+                out.push_str(&format!(" (exit {code})"));
+            }
+            if let Some(ref err) = st.error {
+                out.push_str(&format!(" ({err})"));
+            }
+            out.push_str(&format!(
+                "  {}={}",
+                if st.finished_at.is_some() { "finished" } else { "started" },
+                st.finished_at.unwrap_or(st.started_at).to_rfc3339()
+            ));
+            out.push_str(&format!("  command={:?}", st.command));
+            out
+        }
+    }
+}
+
+fn destroy_confirmation_required_text(project: Option<&str>) -> String {
+    let command = match project {
+        Some(project) => format!("agentman destroy --project {project}"),
+        None => "agentman destroy".to_string(),
+    };
+    format!(
+        "Refusing to destroy without confirmation.\n\
+This will stop/remove your container(s) and DELETE your persistent workspace.\n\n\
+Run one of:\n\
+  {command} --yes\n\
+  {command} --keep-workspace\n\
+  {command} --dry-run\n"
+    )
+}
+
+async fn execute_git_identity(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    action: GitIdentityAction,
+) -> GatewayControlExecution {
+    let state = container_manager.state();
+    let (name, email) = match action {
+        GitIdentityAction::Show => {
+            let mut out = match state.git_identity(github_user).await {
+                Some(identity) => format!(
+                    "agentman: git identity: {} <{}>\n",
+                    identity.name, identity.email
+                ),
+                None => "agentman: no git identity set; sandboxes use the image's git config\n"
+                    .to_string(),
+            };
+            let ws = container_manager.get_workspace(github_user, project).await;
+            if let Some(ws) = ws {
+                let (_, id, running) =
+                    workspace_container_status_with_running(container_manager, &ws.container_name)
+                        .await;
+                if let (Some(id), true) = (id, running) {
+                    match git_identity::current(container_manager, &id).await {
+                        Ok(Some((name, email))) if name.is_empty() && email.is_empty() => {
+                            out.push_str(&format!("agentman: {project}: not configured\n"));
+                        }
+                        Ok(Some((name, email))) => {
+                            out.push_str(&format!("agentman: {project}: {name} <{email}>\n"));
+                        }
+                        Ok(None) => {
+                            out.push_str(&format!("agentman: {project}: git is not installed\n"));
+                        }
+                        Err(e) => out.push_str(&format!("agentman: {project}: {e:#}\n")),
+                    }
+                }
+            }
+            return GatewayControlExecution::Immediate {
+                exit_status: 0u32,
+                output: out,
+            };
+        }
+        GitIdentityAction::Clear => {
+            let output = match state.set_git_identity(github_user, None).await {
+                Ok(Some(_)) => {
+                    "agentman: git identity cleared; sandboxes keep their current config\n"
+                        .to_string()
+                }
+                Ok(None) => "agentman: no git identity was set\n".to_string(),
+                Err(e) => {
+                    return GatewayControlExecution::Immediate {
+                        exit_status: 1u32,
+                        output: format!("agentman: failed to clear git identity: {e:#}\n"),
+                    };
+                }
+            };
+            return GatewayControlExecution::Immediate {
+                exit_status: 0u32,
+                output,
+            };
+        }
+        GitIdentityAction::Set { name, email } => (name, email),
+    };
+
+    let identity = match git_identity::resolve(github_user, name, email).await {
+        Ok(identity) => identity,
+        Err(e) => {
+            return GatewayControlExecution::Immediate {
+                exit_status: 1u32,
+                output: format!(
+                    "agentman: could not look up your GitHub profile ({e:#}); pass --name and --email\n"
+                ),
+            };
+        }
+    };
+    if let Err(e) = git_identity::validate(&identity) {
+        return GatewayControlExecution::Immediate {
+            exit_status: 1u32,
+            output: format!("agentman: {e:#}\n"),
+        };
+    }
+    if let Err(e) = state.set_git_identity(github_user, Some(identity.clone())).await {
+        return GatewayControlExecution::Immediate {
+            exit_status: 1u32,
+            output: format!("agentman: failed to save git identity: {e:#}\n"),
+        };
+    }
+
+    let mut out = format!(
+        "agentman: git identity set to {} <{}>\n",
+        identity.name, identity.email
+    );
+    for ws in container_manager.list_workspaces(github_user).await {
+        let (_, id, running) =
+            workspace_container_status_with_running(container_manager, &ws.container_name).await;
+        let Some(id) = id.filter(|_| running) else {
+            out.push_str(&format!("  {}: not running; applied when it starts\n", ws.project));
+            continue;
+        };
+        match git_identity::apply(container_manager, &id, &identity).await {
+            Ok(()) => out.push_str(&format!("  {}: updated\n", ws.project)),
+            Err(e) => out.push_str(&format!("  {}: failed: {e:#}\n", ws.project)),
+        }
+    }
+    GatewayControlExecution::Immediate {
+        exit_status: 0u32,
+        output: out,
+    }
+}
+
+async fn run_env_file_action(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    action: EnvFileAction,
+) -> anyhow::Result<String> {
+    let config = &container_manager.config().env_file;
+    match action {
+        EnvFileAction::Show => {
+            let Some(summary) = env_file::summary(config, github_user, project).await? else {
+                return Ok(format!("agentman: no env file for {project}\n"));
+            };
+            let mut out = format!(
+                "agentman: env file for {project}: {} variable(s){}\n",
+                summary.names.len(),
+                summary
+                    .updated_at
+                    .map(|t| format!(", updated {}", t.format("%Y-%m-%d %H:%M UTC")))
+                    .unwrap_or_default()
+            );
+            for name in summary.names {
+                out.push_str(&format!("  {name}\n"));
+            }
+            Ok(out)
+        }
+        EnvFileAction::Clear => Ok(if env_file::remove(config, github_user, project).await? {
+            format!("agentman: env file for {project} deleted; new execs no longer get it\n")
+        } else {
+            format!("agentman: no env file for {project}\n")
+        }),
+        // The SSH layer reads the file from the exec's input; `set` never reaches the gateway.
+        EnvFileAction::Set => {
+            anyhow::bail!("env-file set is only available over an SSH exec request")
+        }
+    }
+}
+
+async fn run_notify_action(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    action: NotifyAction,
+) -> anyhow::Result<String> {
+    let config = container_manager.config();
+    let state = container_manager.state();
+    match action {
+        NotifyAction::Show => Ok(match state.notifier(github_user).await {
+            Some(notifier) => format!(
+                "agentman: notifications go to {}\n",
+                user_notify::describe(&notifier)
+            ),
+            None => "agentman: no notifier set (see `agentman notify set`)\n".to_string(),
+        }),
+        NotifyAction::Set(notifier) => {
+            user_notify::validate(&config.user_notify, &notifier)?;
+            let description = user_notify::describe(&notifier);
+            state.set_notifier(github_user, Some(notifier)).await?;
+            Ok(format!("agentman: notifications now go to {description}\n"))
+        }
+        NotifyAction::Clear => Ok(match state.set_notifier(github_user, None).await? {
+            Some(_) => "agentman: notifier removed\n".to_string(),
+            None => "agentman: no notifier set\n".to_string(),
+        }),
+        NotifyAction::Send { message } => {
+            user_notify::send(config, state, github_user, project, &message).await?;
+            Ok("agentman: notification sent\n".to_string())
+        }
+    }
+}
+
+/// `agentman admin access`: record a grant for `admin` to log in to `owner`'s project.
+async fn grant_admin_access(
+    container_manager: &ContainerManager,
+    admin: &str,
+    owner: &str,
+    project: &str,
+    reason: &str,
+) -> anyhow::Result<String> {
+    let config = container_manager.config();
+    let state = container_manager.state();
+    if !config.impersonation.enabled {
+        anyhow::bail!("admin access is not enabled on this gateway");
+    }
+    validate_github_username(owner)?;
+    validate_project_name(project)?;
+    if owner == admin {
+        anyhow::bail!("{owner}/{project} is your own project");
+    }
+    if state.get_workspace(owner, project).await.is_none() {
+        anyhow::bail!("{owner} has no project {project}");
+    }
+
+    let now = Utc::now();
+    let expires_at = now + chrono::Duration::seconds(config.impersonation.ttl_secs as i64);
+    let grant = crate::state::AdminAccessGrant {
+        github_user: owner.to_string(),
+        project: project.to_string(),
+        reason: reason.to_string(),
+        granted_at: now,
+        expires_at,
+    };
+    state.set_admin_access(admin, grant).await?;
+    tracing::info!(
+        "{} was granted access to {}/{}: {}",
+        admin,
+        owner,
+        project,
+        reason
+    );
+
+    let (host, port) = open::address(config);
+    let port_flag = if port == 22 {
+        String::new()
+    } else {
+        format!(" -p {port}")
+    };
+    Ok(format!(
+        "agentman: access to {owner}/{project} until {expires} (in {ttl})\n\n\
+         Connect with one of your keys:\n\n  ssh{port_flag} {project}+{owner}@{host}\n\n\
+         {owner} is told about each login, with your reason; they are audited as impersonation.\n",
+        expires = expires_at.format("%Y-%m-%d %H:%M UTC"),
+        ttl = guest::format_ttl(config.impersonation.ttl_secs),
+    ))
+}
+
+async fn run_lang_action(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    action: LangAction,
+) -> anyhow::Result<(u32, String)> {
+    let state = container_manager.state();
+    let default = container_manager.config().i18n.default_lang;
+    let chosen = state.language(github_user).await;
+    let current = chosen.unwrap_or(default);
+    let line = |msg: i18n::Msg, lang: Lang| format!("agentman: {}\n", msg.render(lang));
+    match action {
+        LangAction::Show => {
+            let mut out = line(
+                i18n::Msg::LangCurrent {
+                    name: current.name(),
+                    code: current.code(),
+                },
+                current,
+            );
+            if chosen.is_none() {
+                out.push_str("agentman: (gateway default; change it with `agentman lang set <code>`)\n");
+            }
+            Ok((0, out))
+        }
+        LangAction::List => {
+            let rows: Vec<[String; 3]> = Lang::ALL
+                .iter()
+                .map(|lang| {
+                    [
+                        lang.code().to_string(),
+                        lang.name().to_string(),
+                        match (*lang == current, *lang == default) {
+                            (true, true) => "current, default",
+                            (true, false) => "current",
+                            (false, true) => "default",
+                            (false, false) => "",
+                        }
+                        .to_string(),
+                    ]
+                })
+                .collect();
+            Ok((0, format_table(&["CODE", "LANGUAGE", ""], &rows)))
+        }
+        LangAction::Set { code } => {
+            let Some(lang) = Lang::parse(&code) else {
+                let available = Lang::available();
+                let msg = i18n::Msg::LangUnknown {
+                    code: &code,
+                    available: &available,
                 };
-                (status, id_short, cpu, mem)
+                return Ok((2, line(msg, current)));
+            };
+            state.set_language(github_user, Some(lang)).await?;
+            Ok((0, line(i18n::Msg::LangSet { name: lang.name() }, lang)))
+        }
+        LangAction::Reset => {
+            state.set_language(github_user, None).await?;
+            Ok((0, line(i18n::Msg::LangSet { name: default.name() }, default)))
+        }
+    }
+}
+
+async fn run_config_action(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    action: ConfigAction,
+) -> anyhow::Result<String> {
+    let config = container_manager.config();
+    let state = container_manager.state();
+    let key = |name: &str| {
+        PreferenceKey::parse(name).ok_or_else(|| {
+            anyhow::anyhow!("unknown key {name:?}; keys: {}", preferences::key_names())
+        })
+    };
+    match action {
+        ConfigAction::List => {
+            let mut rows = Vec::new();
+            for key in PreferenceKey::ALL {
+                let (own, effective) = preferences::get(config, state, github_user, key).await;
+                let source = if own.is_some() { "you" } else { "gateway" };
+                rows.push([key.name().to_string(), effective, source.to_string()]);
+            }
+            Ok(format_table(&["KEY", "VALUE", "SET BY"], &rows))
+        }
+        ConfigAction::Get { key: name } => {
+            let (_, effective) = preferences::get(config, state, github_user, key(&name)?).await;
+            Ok(format!("{effective}\n"))
+        }
+        ConfigAction::Set { key: name, value } => {
+            let key = key(&name)?;
+            preferences::set(config, state, github_user, key, Some(&value)).await?;
+            let (_, effective) = preferences::get(config, state, github_user, key).await;
+            Ok(format!("agentman: {} is now {effective}\n", key.name()))
+        }
+        ConfigAction::Unset { key: name } => {
+            let key = key(&name)?;
+            preferences::set(config, state, github_user, key, None).await?;
+            let (_, effective) = preferences::get(config, state, github_user, key).await;
+            Ok(format!(
+                "agentman: {} follows the gateway again ({effective})\n",
+                key.name()
+            ))
+        }
+    }
+}
+
+async fn run_motd_action(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    action: MotdAction,
+) -> anyhow::Result<String> {
+    let state = container_manager.state();
+    match action {
+        MotdAction::Show => Ok(match state.motd(github_user, project).await {
+            Some(motd) => format!(
+                "agentman: login message of {github_user}/{project} (set {}):\n{}\n",
+                motd.set_at.format("%Y-%m-%d %H:%M UTC"),
+                motd.text
+            ),
+            None => format!(
+                "agentman: {github_user}/{project} has no login message; set one with \
+                 `agentman motd set <text>`\n"
+            ),
+        }),
+        MotdAction::Set { text } => {
+            let motd = Motd {
+                text: motd::normalize(&text)?,
+                set_at: Utc::now(),
+            };
+            state.set_motd(github_user, project, Some(motd)).await?;
+            Ok(format!(
+                "agentman: terminals opened on {github_user}/{project} now show the message\n"
+            ))
+        }
+        MotdAction::Clear => {
+            if state.motd(github_user, project).await.is_none() {
+                anyhow::bail!("{github_user}/{project} has no login message");
+            }
+            state.set_motd(github_user, project, None).await?;
+            Ok(format!("agentman: removed the login message of {github_user}/{project}\n"))
+        }
+    }
+}
+
+async fn run_mesh_action(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    action: MeshAction,
+) -> anyhow::Result<String> {
+    if !container_manager.config().mesh.enabled {
+        anyhow::bail!("the mesh is not enabled on this gateway");
+    }
+    let state = container_manager.state();
+    let output = match action {
+        MeshAction::Show => {
+            let targets = state.mesh_targets(github_user, project).await;
+            if targets.is_empty() {
+                return Ok(format!(
+                    "agentman: {project} may not run commands in other projects; allow one \
+                     with `agentman mesh allow <project>`\n"
+                ));
+            }
+            let mut out = format!("agentman: {project} may run commands in:\n");
+            for target in targets {
+                out.push_str(&format!("  {target}\n"));
+            }
+            out.push_str(&format!(
+                "Use `agentman-helper exec <project> -- <command...>` in the sandbox \
+                 (socket {}).\n",
+                mesh::CONTAINER_SOCKET
+            ));
+            return Ok(out);
+        }
+        MeshAction::Allow { target } => {
+            validate_project_name(&target)?;
+            if target == project {
+                anyhow::bail!("{project} already runs its own commands");
+            }
+            if state.get_workspace(github_user, &target).await.is_none() {
+                anyhow::bail!("no sandbox found for {github_user}/{target}");
+            }
+            state.set_mesh_target(github_user, project, &target, true).await?;
+            format!("agentman: {project} may now run commands in {target}\n")
+        }
+        MeshAction::Deny { target } => {
+            if !state.mesh_targets(github_user, project).await.contains(&target) {
+                anyhow::bail!("{project} may not run commands in {target} anyway");
+            }
+            state.set_mesh_target(github_user, project, &target, false).await?;
+            format!("agentman: {project} may no longer run commands in {target}\n")
+        }
+    };
+    mesh::refresh();
+    Ok(output)
+}
+
+async fn run_expose_action(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    action: ExposeAction,
+) -> anyhow::Result<String> {
+    let config = &container_manager.config().preview;
+    let state = container_manager.state();
+    if !config.enabled {
+        anyhow::bail!("preview links are not enabled on this gateway");
+    }
+    match action {
+        ExposeAction::List => {
+            let links = state.list_previews(github_user).await;
+            if links.is_empty() {
+                return Ok(format!("agentman: no preview links for {github_user}\n"));
+            }
+            let key = preview::key(config).await?;
+            let rows: Vec<[String; 5]> = links
+                .into_iter()
+                .map(|(id, link)| {
+                    let url = preview::url(config, &id, &preview::sign(&key, &id, &link));
+                    [
+                        id,
+                        link.project,
+                        link.port.to_string(),
+                        format!(
+                            "{} (in {})",
+                            link.expires_at.format("%Y-%m-%d %H:%M UTC"),
+                            guest::remaining(link.expires_at)
+                        ),
+                        url,
+                    ]
+                })
+                .collect();
+            Ok(format_table(&["ID", "PROJECT", "PORT", "EXPIRES", "URL"], &rows))
+        }
+        ExposeAction::Revoke { id } => {
+            let removed = state.remove_previews(github_user, id.as_deref()).await?;
+            match (id, removed.is_empty()) {
+                (Some(id), true) => anyhow::bail!("no preview link {id}"),
+                (None, true) => Ok("agentman: no preview links to revoke\n".to_string()),
+                _ => {
+                    tracing::info!("{} revoked preview links {}", github_user, removed.join(", "));
+                    Ok(format!(
+                        "agentman: revoked preview link{} {}\n",
+                        if removed.len() == 1 { "" } else { "s" },
+                        removed.join(", ")
+                    ))
+                }
+            }
+        }
+        ExposeAction::Preview { port, ttl_secs } => {
+            if ttl_secs > config.max_ttl_secs {
+                anyhow::bail!(
+                    "preview links on this gateway last at most {}",
+                    guest::format_ttl(config.max_ttl_secs)
+                );
+            }
+            if container_manager.get_workspace(github_user, project).await.is_none() {
+                anyhow::bail!("no sandbox found for {github_user}/{project}");
+            }
+            if state.list_previews(github_user).await.len() >= config.max_per_user {
+                anyhow::bail!(
+                    "you already have {} preview links; revoke one first",
+                    config.max_per_user
+                );
+            }
+
+            let key = preview::key(config).await?;
+            let id = preview::new_id()?;
+            let now = Utc::now();
+            let link = crate::state::PreviewLink {
+                github_user: github_user.to_string(),
+                project: project.to_string(),
+                port,
+                created_at: now,
+                expires_at: now + chrono::Duration::seconds(ttl_secs as i64),
+            };
+            let url = preview::url(config, &id, &preview::sign(&key, &id, &link));
+            let expires = link.expires_at.format("%Y-%m-%d %H:%M UTC");
+            state.add_preview(id.clone(), link).await?;
+            tracing::info!(
+                "{} created preview link {} to {} port {} for {}s",
+                github_user,
+                id,
+                project,
+                port,
+                ttl_secs
+            );
+            Ok(format!(
+                "agentman: preview link {id} to port {port}, expires {expires} (in {ttl}):\n\n  \
+                 {url}\n\nAnyone with the link can use it; revoke it with \
+                 `agentman expose revoke {id}`.\n",
+                ttl = guest::format_ttl(ttl_secs)
+            ))
+        }
+    }
+}
+
+async fn run_trust_action(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    action: TrustAction,
+) -> anyhow::Result<String> {
+    let state = container_manager.state();
+    if !container_manager.config().trust.enabled {
+        anyhow::bail!("this gateway doesn't track devices");
+    }
+    let (id, revoke) = match action {
+        TrustAction::List => {
+            let devices = state.devices(github_user).await;
+            if devices.is_empty() {
+                return Ok(format!("agentman: no devices recorded for {github_user}\n"));
+            }
+            let rows: Vec<[String; 5]> = devices
+                .into_iter()
+                .map(|device| {
+                    [
+                        device.id.to_string(),
+                        device.key_fingerprint,
+                        device.first_seen.format("%Y-%m-%d %H:%M UTC").to_string(),
+                        match device.revoked_at {
+                            Some(at) => format!("revoked {}", at.format("%Y-%m-%d %H:%M UTC")),
+                            None => device.last_seen.format("%Y-%m-%d %H:%M UTC").to_string(),
+                        },
+                        device.addresses.join(", "),
+                    ]
+                })
+                .collect();
+            return Ok(format_table(
+                &["ID", "KEY", "FIRST SEEN", "LAST SEEN", "ADDRESSES"],
+                &rows,
+            ));
+        }
+        TrustAction::Revoke { id } => (id, true),
+        TrustAction::Allow { id } => (id, false),
+    };
+    let Some(device) = state.set_device_revoked(github_user, id, revoke).await? else {
+        anyhow::bail!("no device {id}");
+    };
+    if revoke {
+        tracing::info!("{} revoked device {} ({})", github_user, id, device.key_fingerprint);
+        Ok(format!(
+            "agentman: revoked device {id}; logins with {} are refused\n",
+            device.key_fingerprint
+        ))
+    } else {
+        tracing::info!("{} trusts device {} again", github_user, id);
+        Ok(format!("agentman: device {id} may log in again\n"))
+    }
+}
+
+async fn run_guest_action(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    action: GuestAction,
+) -> anyhow::Result<String> {
+    let config = container_manager.config();
+    let state = container_manager.state();
+    match action {
+        GuestAction::List => {
+            let invites = state.list_guests(github_user).await;
+            if invites.is_empty() {
+                return Ok(format!("agentman: no guest invites for {github_user}\n"));
+            }
+            let rows: Vec<[String; 5]> = invites
+                .into_iter()
+                .map(|(id, invite)| {
+                    [
+                        id,
+                        invite.project,
+                        if invite.read_only { "read-only" } else { "full" }.to_string(),
+                        format!(
+                            "{} (in {})",
+                            invite.expires_at.format("%Y-%m-%d %H:%M UTC"),
+                            guest::remaining(invite.expires_at)
+                        ),
+                        invite.key_fingerprint.unwrap_or_else(|| "unused".to_string()),
+                    ]
+                })
+                .collect();
+            Ok(format_table(&["ID", "PROJECT", "ACCESS", "EXPIRES", "KEY"], &rows))
+        }
+        GuestAction::Revoke { id } => {
+            let removed = state.remove_guests(github_user, id.as_deref()).await?;
+            match (id, removed.is_empty()) {
+                (Some(id), true) => anyhow::bail!("no guest invite {id}"),
+                (None, true) => Ok("agentman: no guest invites to revoke\n".to_string()),
+                _ => {
+                    tracing::info!("{} revoked guest invites {}", github_user, removed.join(", "));
+                    Ok(format!(
+                        "agentman: revoked guest invite{} {}; connected guests are disconnected\n",
+                        if removed.len() == 1 { "" } else { "s" },
+                        removed.join(", ")
+                    ))
+                }
+            }
+        }
+        GuestAction::Invite {
+            ttl_secs,
+            read_only,
+        } => {
+            let guests = &config.guests;
+            if !guests.enabled {
+                anyhow::bail!("guest invites are not enabled on this gateway");
+            }
+            let ttl_secs = ttl_secs.unwrap_or(guests.default_ttl_secs);
+            if ttl_secs > guests.max_ttl_secs {
+                anyhow::bail!(
+                    "invites on this gateway last at most {}",
+                    guest::format_ttl(guests.max_ttl_secs)
+                );
+            }
+            let prefs = state.preferences(github_user).await;
+            if read_only && !matches!(preferences::shell_mode(config, &prefs), ShellMode::Tmux) {
+                anyhow::bail!("read-only guests need the tmux shell mode");
+            }
+            if state.list_guests(github_user).await.len() >= guests.max_per_user {
+                anyhow::bail!(
+                    "you already have {} guest invites; revoke one first",
+                    guests.max_per_user
+                );
+            }
+
+            let (id, secret) = guest::new_credentials()?;
+            let now = Utc::now();
+            let expires_at = now + chrono::Duration::seconds(ttl_secs as i64);
+            let invite = crate::state::GuestInvite {
+                github_user: github_user.to_string(),
+                project: project.to_string(),
+                read_only,
+                secret_sha256: guest::secret_sha256(&secret),
+                created_at: now,
+                expires_at,
+                key_fingerprint: None,
+            };
+            state.add_guest(id.clone(), invite).await?;
+            tracing::info!(
+                "{} invited a {} guest to {} for {}s (invite {})",
+                github_user,
+                if read_only { "read-only" } else { "full-access" },
+                project,
+                ttl_secs,
+                id
+            );
+
+            let (host, port) = open::address(config);
+            let port_flag = if port == 22 {
+                String::new()
+            } else {
+                format!(" -p {port}")
+            };
+            Ok(format!(
+                "agentman: guest invite {id} for {github_user}/{project} ({access}), \
+                 expires {expires} (in {ttl})\n\n\
+                 The guest connects with:\n\n  ssh -t{port_flag} {login}@{host}\n\n\
+                 The first SSH key used with this login is the only one accepted afterwards. It is \
+                 shown only once;\nrevoke it with `agentman guest revoke {id}`.\n",
+                access = if read_only {
+                    "read-only"
+                } else {
+                    "full access"
+                },
+                expires = expires_at.format("%Y-%m-%d %H:%M UTC"),
+                ttl = guest::format_ttl(ttl_secs),
+                login = guest::login(&id, &secret),
+            ))
+        }
+    }
+}
+
+async fn workspace_container_status(
+    container_manager: &ContainerManager,
+    container_name: &str,
+) -> (String, Option<String>) {
+    let (status, id, _running) =
+        workspace_container_status_with_running(container_manager, container_name).await;
+    (status, id)
+}
+
+pub(crate) async fn workspace_container_status_with_running(
+    container_manager: &ContainerManager,
+    container_name: &str,
+) -> (String, Option<String>, bool) {
+    let docker = container_manager.docker();
+    container_status(
+        docker
+            .inspect_container(container_name, None::<InspectContainerOptions>)
+            .await,
+    )
+}
+
+/// Like [`workspace_container_status_with_running`], from the connection's recent inspections.
+async fn session_container_status(
+    container_manager: &ContainerManager,
+    inspections: &InspectCache,
+    container_name: &str,
+) -> (String, Option<String>, bool) {
+    container_status(inspections.inspect(container_manager.docker(), container_name).await)
+}
+
+fn container_status(
+    inspected: Result<ContainerInspectResponse, BollardError>,
+) -> (String, Option<String>, bool) {
+    match inspected {
+        Ok(info) => {
+            let state = info.state.as_ref();
+            let running = state.and_then(|s| s.running).unwrap_or(false);
+            let paused = state.and_then(|s| s.paused).unwrap_or(false);
+            let status = if paused {
+                "paused".to_string()
+            } else if running {
+                "running".to_string()
+            } else {
+                state
+                    .and_then(|s| s.status.as_ref().map(|s| s.to_string()))
+                    .unwrap_or_else(|| "stopped".to_string())
+            };
+
+            let id_short = info
+                .id
+                .as_deref()
+                .map(|id| id.get(..12).unwrap_or(id).to_string());
+            (status, id_short, running)
+        }
+        Err(BollardError::DockerResponseServerError {
+            status_code: 404, ..
+        }) => ("missing".to_string(), None, false),
+        Err(_e) => ("error".to_string(), None, false),
+    }
+}
+
+pub(crate) async fn container_stats_line(
+    container_manager: &ContainerManager,
+    container_name: &str,
+) -> Option<(f64, Option<(u64, u64)>)> {
+    let docker = container_manager.docker();
+    let mut stream = docker.stats(
+        container_name,
+        // Equivalent to `docker stats --no-stream`: the daemon waits for two samples so it can
+        // populate `precpu_stats` for CPU% calculation.
+        Some(StatsOptionsBuilder::new().stream(false).build()),
+    );
+
+    // The daemon may wait for two cycles before returning the single response.
+    let next = timeout(Duration::from_secs(5), stream.next()).await.ok()??;
+    let stats = next.ok()?;
+
+    let cpu_stats = stats.cpu_stats.as_ref()?;
+    let precpu_stats = stats.precpu_stats.as_ref()?;
+    let cpu_usage = cpu_stats.cpu_usage.as_ref()?;
+    let precpu_usage = precpu_stats.cpu_usage.as_ref()?;
+
+    let cpu_total = cpu_usage.total_usage.unwrap_or(0);
+    let cpu_total_pre = precpu_usage.total_usage.unwrap_or(0);
+    let system = cpu_stats.system_cpu_usage.unwrap_or(0);
+    let system_pre = precpu_stats.system_cpu_usage.unwrap_or(0);
+
+    let cpu_delta = cpu_total.saturating_sub(cpu_total_pre);
+    let system_delta = system.saturating_sub(system_pre);
+
+    let percpu_count = cpu_usage
+        .percpu_usage
+        .as_ref()
+        .map(|v| v.len() as u64)
+        .unwrap_or(1);
+    let online_cpus = cpu_stats
+        .online_cpus
+        .map(|n| n as u64)
+        .filter(|&n| n > 0)
+        .unwrap_or(percpu_count.max(1));
+
+    // Prefer Docker's standard calculation when system CPU usage deltas are available.
+    // Some engines / cgroup modes can omit or zero out system usage, so fall back to using the
+    // daemon-provided timestamps.
+    let cpu_percent = if cpu_delta == 0 {
+        0.0
+    } else if system_delta > 0 {
+        (cpu_delta as f64 / system_delta as f64) * online_cpus as f64 * 100.0
+    } else {
+        match (stats.read.as_deref(), stats.preread.as_deref()) {
+            (Some(read), Some(preread)) => {
+                let read_ns = DateTime::parse_from_rfc3339(read)
+                    .ok()
+                    .and_then(|dt| dt.timestamp_nanos_opt());
+                let preread_ns = DateTime::parse_from_rfc3339(preread)
+                    .ok()
+                    .and_then(|dt| dt.timestamp_nanos_opt());
+                match (read_ns, preread_ns) {
+                    (Some(r), Some(p)) if r > p => {
+                        let dt_ns = (r - p) as f64;
+                        (cpu_delta as f64 / dt_ns) * 100.0
+                    }
+                    _ => 0.0,
+                }
+            }
+            _ => 0.0,
+        }
+    };
+
+    let mem = stats.memory_stats.as_ref().and_then(|m| match (m.usage, m.limit) {
+        (Some(u), Some(l)) if l > 0 => Some((u, l)),
+        _ => None,
+    });
+
+    Some((cpu_percent, mem))
+}
+
+/// Fast version for watch mode: uses one_shot for quicker response.
+/// CPU% may be less accurate but memory is reliable.
+async fn container_stats_line_fast(
+    container_manager: &ContainerManager,
+    container_name: &str,
+) -> Option<(Option<f64>, Option<(u64, u64)>)> {
+    let docker = container_manager.docker();
+    let mut stream = docker.stats(
+        container_name,
+        // one_shot gives a quick single sample (precpu_stats may be empty).
+        Some(StatsOptionsBuilder::new().stream(false).one_shot(true).build()),
+    );
+
+    let next = timeout(Duration::from_millis(1500), stream.next()).await.ok()??;
+    let stats = next.ok()?;
+
+    // Memory is always reliable.
+    let mem = stats.memory_stats.as_ref().and_then(|m| match (m.usage, m.limit) {
+        (Some(u), Some(l)) if l > 0 => Some((u, l)),
+        _ => None,
+    });
+
+    // CPU with one_shot: precpu_stats may be empty, so we try but may return None.
+    let cpu = (|| {
+        let cpu_stats = stats.cpu_stats.as_ref()?;
+        let precpu_stats = stats.precpu_stats.as_ref()?;
+        let cpu_usage = cpu_stats.cpu_usage.as_ref()?;
+        let precpu_usage = precpu_stats.cpu_usage.as_ref()?;
+
+        let cpu_total = cpu_usage.total_usage.unwrap_or(0);
+        let cpu_total_pre = precpu_usage.total_usage.unwrap_or(0);
+        if cpu_total == 0 || cpu_total_pre == 0 || cpu_total <= cpu_total_pre {
+            return None;
+        }
+
+        let system = cpu_stats.system_cpu_usage.unwrap_or(0);
+        let system_pre = precpu_stats.system_cpu_usage.unwrap_or(0);
+        let cpu_delta = cpu_total.saturating_sub(cpu_total_pre);
+        let system_delta = system.saturating_sub(system_pre);
+
+        let percpu_count = cpu_usage.percpu_usage.as_ref().map(|v| v.len() as u64).unwrap_or(1);
+        let online_cpus = cpu_stats.online_cpus.map(|n| n as u64).filter(|&n| n > 0).unwrap_or(percpu_count.max(1));
+
+        if system_delta > 0 {
+            Some((cpu_delta as f64 / system_delta as f64) * online_cpus as f64 * 100.0)
+        } else {
+            None
+        }
+    })();
+
+    Some((cpu, mem))
+}
+
+/// `agentman sessions`: who is attached to the user's tmux sessions.
+fn format_sessions(config: &ShellConfig, github_user: &str, project: &str) -> String {
+    if !matches!(config.mode, ShellMode::Tmux) {
+        return "agentman: interactive shells don't run in tmux on this gateway\n".to_string();
+    }
+    let attached = tmux_clients::list(github_user);
+    if attached.is_empty() {
+        return format!(
+            "agentman: no terminals attached to tmux session '{}'\n",
+            config.tmux_session
+        );
+    }
+    let mut out = format!(
+        "agentman: tmux session '{}' (window-size {})\n",
+        config.tmux_session,
+        config.tmux_window_size.as_str()
+    );
+    for (name, clients) in attached {
+        let window = tmux_clients::effective_size(config.tmux_window_size, &clients)
+            .map(|(cols, rows)| format!(", window {cols}x{rows}"))
+            .unwrap_or_default();
+        out.push_str(&format!(
+            "- {name}{}: {} attached{window}\n",
+            if name == project { " (current)" } else { "" },
+            clients.len()
+        ));
+        for client in clients {
+            out.push_str(&format!(
+                "  - {} since {}, {}x{}\n",
+                client.peer_addr,
+                client.attached_at.format("%Y-%m-%d %H:%M UTC"),
+                client.cols,
+                client.rows
+            ));
+        }
+    }
+    out
+}
+
+/// `agentman storage`: what the sandbox takes up on disk, and which of it `recreate` and
+/// `destroy` free.
+async fn render_storage(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+) -> anyhow::Result<String> {
+    let config = container_manager.config();
+    let Some(ws) = container_manager.get_workspace(github_user, project).await else {
+        anyhow::bail!("no sandbox found for {github_user}/{project}");
+    };
+    // Sizes of the writable layer need a walk of it in dockerd, hence only here.
+    let options = InspectContainerOptionsBuilder::new().size(true).build();
+    let inspect = container_manager
+        .docker()
+        .inspect_container(&ws.container_name, Some(options));
+    let volumes = stack::volumes_path(&config.stack, github_user, project);
+    let editor_cache = editor_cache::user_cache_dir(&config.editor_cache, github_user);
+    let (inspect, workspace, volumes, editor_cache) = tokio::join!(
+        inspect,
+        workspace_size::get(&config.workspace_size, &ws.host_workspace_path),
+        async {
+            match volumes.exists() {
+                true => du_bytes(&volumes).await,
+                false => Some(0),
             }
+        },
+        async {
+            match config.editor_cache.enabled && editor_cache.exists() {
+                true => du_bytes(&editor_cache).await,
+                false => None,
+            }
+        },
+    );
+
+    let mut notes = Vec::new();
+    let (layer, image) = match inspect {
+        Ok(info) => {
+            let layer = info.size_rw.map(|b| b.max(0) as u64);
+            let total = info.size_root_fs.map(|b| b.max(0) as u64);
+            let name = info.config.and_then(|c| c.image).unwrap_or_default();
+            (layer, total.map(|t| (t.saturating_sub(layer.unwrap_or(0)), name)))
+        }
+        Err(BollardError::DockerResponseServerError {
+            status_code: 404, ..
+        }) => {
+            notes.push("the container doesn't exist; the next login creates it".to_string());
+            (Some(0), None)
+        }
+        Err(e) => {
+            notes.push(format!("container size unknown: {e}"));
+            (None, None)
+        }
+    };
+    let own_image = image
+        .as_ref()
+        .is_some_and(|(_, name)| build::is_own_image(&config.build, name, github_user, project));
+
+    let size = |bytes: Option<u64>| bytes.map(format_bytes).unwrap_or_else(|| "?".to_string());
+    let mut rows = vec![
+        [
+            "container layer".to_string(),
+            size(layer),
+            "recreate, destroy".to_string(),
+        ],
+        [
+            "workspace".to_string(),
+            size(workspace),
+            "destroy --yes".to_string(),
+        ],
+    ];
+    if let Some((bytes, name)) = &image {
+        let freed_by = match own_image {
+            true => "destroy (the workspace's own build)".to_string(),
+            false => "nothing (shared by every sandbox on it)".to_string(),
+        };
+        rows.insert(1, [format!("image {name}"), format_bytes(*bytes), freed_by]);
+    }
+    if config.stack.enabled || volumes != Some(0) {
+        rows.push([
+            "stack volumes".to_string(),
+            size(volumes),
+            "destroy --yes".to_string(),
+        ]);
+    }
+    if config.editor_cache.enabled {
+        rows.push([
+            "editor cache".to_string(),
+            size(editor_cache),
+            "cache prune (shared by your projects)".to_string(),
+        ]);
+    }
+
+    let mut out = format!("agentman: storage of {github_user}/{project}\n");
+    out.push_str(&format_table(&["WHAT", "SIZE", "FREED BY"], &rows));
+    let image_freed = image.filter(|_| own_image).map(|(bytes, _)| bytes).unwrap_or(0);
+    let sum = |parts: &[Option<u64>]| -> String {
+        let known: u64 = parts.iter().flatten().sum();
+        match parts.iter().all(Option::is_some) {
+            true => format_bytes(known),
+            false => format!("at least {}", format_bytes(known)),
+        }
+    };
+    out.push_str(&format!(
+        "\nrecreate frees {}; destroy --keep-workspace {}; destroy --yes {}.\n",
+        sum(&[layer]),
+        sum(&[layer, Some(image_freed)]),
+        sum(&[layer, Some(image_freed), workspace, volumes]),
+    ));
+    for note in notes {
+        out.push_str(&format!("agentman: {note}\n"));
+    }
+    Ok(out)
+}
+
+/// `agentman admin users`: one row per known GitHub user.
+async fn format_users(container_manager: &ContainerManager) -> String {
+    let state = container_manager.state();
+    let users = state.known_github_users().await;
+    if users.is_empty() {
+        return "agentman: no known users\n".to_string();
+    }
+    let keys = state.key_counts().await;
+    let running = container_manager.running_containers_per_user().await;
+    let size_config = &container_manager.config().workspace_size;
+    let metadata_config = &container_manager.config().github_metadata;
+    let metadata = join_all(
+        users
+            .iter()
+            .map(|user| github_metadata::lookup(metadata_config, user)),
+    )
+    .await;
+
+    let mut rows = Vec::with_capacity(users.len());
+    for (user, metadata) in users.into_iter().zip(metadata) {
+        let workspaces = state.list_workspaces(&user).await;
+        let sizes = workspaces
+            .iter()
+            .map(|ws| workspace_size::get(size_config, &ws.host_workspace_path));
+        let disk: u64 = join_all(sizes).await.into_iter().flatten().sum();
+        let last_login = state
+            .last_login(&user)
+            .await
+            .map(|t| t.format("%Y-%m-%d %H:%M UTC").to_string())
+            .unwrap_or_else(|| "never".to_string());
+        let mut name = user.clone();
+        if container_manager.config().is_admin(&user) {
+            name.push_str(" (admin)");
+        }
+        if state.offboarding(&user).await.is_some() {
+            name.push_str(" (offboarding)");
+        }
+        rows.push([
+            name,
+            metadata
+                .and_then(|m| m.name)
+                .unwrap_or_else(|| "-".to_string()),
+            keys.get(&user).copied().unwrap_or(0).to_string(),
+            workspaces.len().to_string(),
+            format_bytes(disk),
+            match running {
+                Ok(ref running) => running.get(&user).copied().unwrap_or(0).to_string(),
+                Err(_) => "?".to_string(),
+            },
+            last_login,
+        ]);
+    }
+    let mut out = format_table(
+        &["USER", "NAME", "KEYS", "WORKSPACES", "DISK", "RUNNING", "LAST LOGIN"],
+        &rows,
+    );
+    if let Err(e) = running {
+        out.push_str(&format!("agentman: running sandboxes unknown: {e:#}\n"));
+    }
+    out
+}
+
+/// Audit log disk usage per user, from the first `directory` sink.
+fn format_audit_usage(logging: &LoggingConfig) -> anyhow::Result<String> {
+    let Some(dir) = logging.directory() else {
+        anyhow::bail!("no directory audit sink is configured");
+    };
+    let usage = audit_store::usage(dir)?;
+    let dropped = audit::dropped_events();
+    let rows: Vec<[String; 6]> = usage
+        .iter()
+        .map(|u| {
+            [
+                u.user.clone(),
+                format_bytes(u.bytes),
+                u.days.to_string(),
+                u.compressed.to_string(),
+                u.oldest
+                    .map(|d| d.to_string())
+                    .unwrap_or_else(|| "-".to_string()),
+                dropped.get(&u.user).copied().unwrap_or(0).to_string(),
+            ]
         })
         .collect();
+    let mut out = format_table(
+        &["USER", "SIZE", "DAYS", "COMPRESSED", "OLDEST", "DROPPED"],
+        &rows,
+    );
+    let total: u64 = usage.iter().map(|u| u.bytes).sum();
+    let retention = &logging.retention;
+    let limit = |value: Option<String>| value.unwrap_or_else(|| "none".to_string());
+    out.push_str(&format!(
+        "\nTotal {} in {}. Cap per user: {}; compress after: {}; delete after: {}.\n",
+        format_bytes(total),
+        dir.display(),
+        limit(retention.max_mb_per_user.map(|mb| format_bytes(mb * 1024 * 1024))),
+        limit(retention.compress_after_days.map(|d| format!("{d} days"))),
+        limit(retention.delete_after_days.map(|d| format!("{d} days"))),
+    ));
+    if let Some(max) = logging.max_events_per_minute {
+        out.push_str(&format!("Rate limit: {max} events per user per minute.\n"));
+    }
+    Ok(out)
+}
 
-    let results = join_all(futs).await;
+/// `agentman admin usage`: the month's usage per user.
+async fn format_usage(
+    config: &crate::config::AccountingConfig,
+    month: &str,
+    format: ReportFormat,
+) -> anyhow::Result<String> {
+    if !config.enabled {
+        anyhow::bail!("usage accounting is off; set [accounting] enabled = true");
+    }
+    let rows = accounting::month_usage(config, month).await?;
+    match format {
+        ReportFormat::Csv => return Ok(accounting::to_csv(month, &rows)),
+        ReportFormat::Json => return Ok(accounting::to_json(month, &rows)),
+        ReportFormat::Table => {}
+    }
+    if rows.is_empty() {
+        return Ok(format!("agentman: no usage recorded for {month}\n"));
+    }
+    let table: Vec<[String; 6]> = rows
+        .iter()
+        .map(|(github_user, u)| {
+            [
+                github_user.clone(),
+                format!("{:.0}", u.cpu_seconds),
+                format!("{:.1}", u.memory_gb_hours),
+                format!("{:.1}", u.disk_gb_days),
+                format_bytes(u.egress_bytes),
+                format_bytes(u.ingress_bytes),
+            ]
+        })
+        .collect();
+    let mut out = format_table(
+        &["USER", "CPU-SECONDS", "MEM-GB-HOURS", "DISK-GB-DAYS", "EGRESS", "INGRESS"],
+        &table,
+    );
+    out.push_str(&format!(
+        "\nUsage in {month} (UTC), sampled every {}s; GB = 10^9 bytes.\n",
+        config.interval_secs
+    ));
+    Ok(out)
+}
 
-    let mut out = format!("agentman: sandbox stats for {github_user}\n");
-    for (ws, (status, id_short, cpu, mem)) in workspaces.iter().zip(results.into_iter()) {
-        let is_current = ws.project == project;
-        out.push_str(&format!(
-            "- {}{}: status={}{}{}{}\n",
-            ws.project,
-            if is_current { " (current)" } else { "" },
-            status,
-            if let Some(id) = id_short.as_deref() {
-                format!(" id={id}")
-            } else {
-                "".to_string()
-            },
-            if let Some(cpu) = cpu {
-                format!(" cpu={:.1}%", cpu)
-            } else {
-                " cpu=n/a".to_string()
-            },
-            if let Some((usage, limit)) = mem {
-                format!(" mem={}/{}", format_bytes(usage), format_bytes(limit))
-            } else {
-                " mem=n/a".to_string()
-            },
+/// Left-aligned columns separated by two spaces.
+fn format_table<const N: usize>(header: &[&str; N], rows: &[[String; N]]) -> String {
+    let mut widths = header.map(str::len);
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let mut out = String::new();
+    let header = header.map(str::to_string);
+    for row in std::iter::once(&header).chain(rows) {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect();
+        out.push_str(cells.join("  ").trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+pub(crate) fn format_bytes(bytes: u64) -> String {
+    const KB: f64 = 1024.0;
+    const MB: f64 = 1024.0 * KB;
+    const GB: f64 = 1024.0 * MB;
+    const TB: f64 = 1024.0 * GB;
+
+    let b = bytes as f64;
+    if b < KB {
+        format!("{bytes} B")
+    } else if b < MB {
+        format!("{:.1} KiB", b / KB)
+    } else if b < GB {
+        format!("{:.1} MiB", b / MB)
+    } else if b < TB {
+        format!("{:.1} GiB", b / GB)
+    } else {
+        format!("{:.1} TiB", b / TB)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_exec_alias() {
+        assert!(matches!(
+            parse_gateway_control_command("agentman exec stats --current"),
+            Some(GatewayControlCommand::ExecStats {
+                current: true,
+                watch: false
+            })
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman exec"),
+            Some(GatewayControlCommand::Help)
+        ));
+        assert!(parse_gateway_control_command("ls -la").is_none());
+    }
+
+    #[test]
+    fn test_parse_init_set_keeps_quoting() {
+        match parse_gateway_control_command("agentman init set -- make setup ARGS='a  b'") {
+            Some(GatewayControlCommand::InitSet { command }) => {
+                assert_eq!(command, "make setup ARGS='a  b'")
+            }
+            other => panic!("unexpected: {other:?}"),
+        }
+        assert!(matches!(
+            parse_gateway_control_command("agentman init set --"),
+            Some(GatewayControlCommand::Help)
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman init set make"),
+            Some(GatewayControlCommand::Help)
+        ));
+    }
+
+    #[test]
+    fn test_parse_stats_history() {
+        assert!(matches!(
+            parse_gateway_control_command("agentman stats --history 1h"),
+            Some(GatewayControlCommand::StatsHistory {
+                current: false,
+                window_secs: 3600
+            })
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman stats --current --history 30m"),
+            Some(GatewayControlCommand::StatsHistory {
+                current: true,
+                window_secs: 1800
+            })
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman stats --history"),
+            Some(GatewayControlCommand::Help)
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman stats --history 1h --watch"),
+            Some(GatewayControlCommand::Help)
+        ));
+    }
+
+    #[test]
+    fn test_parse_run() {
+        match parse_gateway_control_command("agentman run --timeout 300 -- cargo test --all") {
+            Some(GatewayControlCommand::Run {
+                timeout_secs: Some(300),
+                detach: false,
+                command,
+            }) => assert_eq!(command, "cargo test --all"),
+            other => panic!("unexpected parse: {other:?}"),
+        }
+        assert!(matches!(
+            parse_gateway_control_command("agentman run -- sleep 5"),
+            Some(GatewayControlCommand::Run {
+                timeout_secs: None,
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman run"),
+            Some(GatewayControlCommand::Help)
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman run sleep 5"),
+            Some(GatewayControlCommand::Help)
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman run --timeout 0 -- sleep 5"),
+            Some(GatewayControlCommand::Help)
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman run --detach --timeout 60 -- make build"),
+            Some(GatewayControlCommand::Run {
+                timeout_secs: Some(60),
+                detach: true,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_parse_jobs() {
+        let parse = |cmd| match parse_gateway_control_command(cmd) {
+            Some(GatewayControlCommand::Jobs { action }) => Some(action),
+            _ => None,
+        };
+        assert_eq!(parse("agentman jobs"), Some(JobsAction::List));
+        assert_eq!(
+            parse("agentman jobs logs 0a1b2c3d"),
+            Some(JobsAction::Logs {
+                id: "0a1b2c3d".to_string(),
+                follow: false
+            })
+        );
+        assert_eq!(
+            parse("agentman jobs logs -f 0a1b2c3d"),
+            Some(JobsAction::Logs {
+                id: "0a1b2c3d".to_string(),
+                follow: true
+            })
+        );
+        assert_eq!(
+            parse("agentman jobs kill 0a1b2c3d"),
+            Some(JobsAction::Kill {
+                id: "0a1b2c3d".to_string()
+            })
+        );
+        assert_eq!(parse("agentman jobs logs"), None);
+        assert_eq!(parse("agentman jobs logs 0a1b2c3d --tail"), None);
+    }
+
+    #[test]
+    fn test_parse_new() {
+        for cmd in [
+            "agentman new api --from https://github.com/org/template.git",
+            "agentman new --from https://github.com/org/template.git api",
+        ] {
+            match parse_gateway_control_command(cmd) {
+                Some(GatewayControlCommand::New { project, from }) => {
+                    assert_eq!(project, "api");
+                    assert_eq!(from, "https://github.com/org/template.git");
+                }
+                other => panic!("unexpected parse of {cmd:?}: {other:?}"),
+            }
+        }
+        assert!(matches!(
+            parse_gateway_control_command("agentman new api"),
+            Some(GatewayControlCommand::Help)
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman new --from --yes api"),
+            Some(GatewayControlCommand::Help)
+        ));
+    }
+
+    #[test]
+    fn test_parse_transfer() {
+        match parse_gateway_control_command("agentman transfer api --to hubot") {
+            Some(GatewayControlCommand::Transfer {
+                owner: None,
+                project,
+                to,
+            }) => assert_eq!((project.as_str(), to.as_str()), ("api", "hubot")),
+            other => panic!("unexpected parse: {other:?}"),
+        }
+        match parse_gateway_control_command("agentman transfer --to hubot octocat/api") {
+            Some(GatewayControlCommand::Transfer {
+                owner: Some(owner),
+                project,
+                ..
+            }) => assert_eq!((owner.as_str(), project.as_str()), ("octocat", "api")),
+            other => panic!("unexpected parse: {other:?}"),
+        }
+        assert!(matches!(
+            parse_gateway_control_command("agentman transfer api"),
+            Some(GatewayControlCommand::Help)
+        ));
+    }
+
+    #[test]
+    fn test_parse_admin_offboard() {
+        assert!(matches!(
+            parse_gateway_control_command("agentman admin offboard"),
+            Some(GatewayControlCommand::AdminOffboard { user: None, cancel: false })
+        ));
+        match parse_gateway_control_command("agentman admin offboard octocat --cancel") {
+            Some(GatewayControlCommand::AdminOffboard {
+                user: Some(user),
+                cancel: true,
+            }) => assert_eq!(user, "octocat"),
+            other => panic!("unexpected parse: {other:?}"),
+        }
+        assert!(matches!(
+            parse_gateway_control_command("agentman admin offboard --cancel"),
+            Some(GatewayControlCommand::Help)
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman admin"),
+            Some(GatewayControlCommand::Help)
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman admin users"),
+            Some(GatewayControlCommand::AdminUsers)
+        ));
+        let cmd = "agentman admin access octocat/api --reason ticket 42";
+        match parse_gateway_control_command(cmd) {
+            Some(GatewayControlCommand::AdminAccess {
+                owner,
+                project,
+                reason,
+            }) => {
+                assert_eq!(owner, "octocat");
+                assert_eq!(project, "api");
+                assert_eq!(reason, "ticket 42");
+            }
+            other => panic!("unexpected parse: {other:?}"),
+        }
+        for cmd in [
+            "agentman admin access octocat/api",
+            "agentman admin access octocat/api --reason",
+            "agentman admin access octocat --reason ticket",
+        ] {
+            assert!(
+                matches!(parse_gateway_control_command(cmd), Some(GatewayControlCommand::Help)),
+                "{cmd}"
+            );
+        }
+
+        assert!(matches!(
+            parse_gateway_control_command("agentman admin audit usage"),
+            Some(GatewayControlCommand::AdminAuditUsage)
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman admin audit"),
+            Some(GatewayControlCommand::Help)
         ));
+        let usage = |cmd: &str| match parse_gateway_control_command(cmd) {
+            Some(GatewayControlCommand::AdminUsage { month, format }) => Some((month, format)),
+            _ => None,
+        };
+        assert_eq!(usage("agentman admin usage"), Some((None, ReportFormat::Table)));
+        assert_eq!(
+            usage("agentman admin usage --month 2025-01 --csv"),
+            Some((Some("2025-01".to_string()), ReportFormat::Csv))
+        );
+        assert_eq!(
+            usage("agentman admin usage --json --month 2025-1"),
+            Some((Some("2025-01".to_string()), ReportFormat::Json))
+        );
+        for cmd in [
+            "agentman admin usage --month",
+            "agentman admin usage --month 2025-13",
+            "agentman admin usage --xml",
+        ] {
+            assert!(
+                matches!(parse_gateway_control_command(cmd), Some(GatewayControlCommand::Help)),
+                "{cmd}"
+            );
+        }
+        assert!(matches!(
+            parse_gateway_control_command("agentman admin state export"),
+            Some(GatewayControlCommand::AdminStateExport)
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman admin state import /tmp/state.json --yes"),
+            Some(GatewayControlCommand::AdminStateImport { ref path, yes: true })
+                if path == Path::new("/tmp/state.json")
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman admin state import --yes"),
+            Some(GatewayControlCommand::Help)
+        ));
+        let migrate = parse_gateway_control_command(
+            "agentman admin migrate octocat/web --to node-b --checkpoint",
+        );
+        assert!(matches!(
+            migrate,
+            Some(GatewayControlCommand::AdminMigrate {
+                ref owner, ref project, ref node, checkpoint: true
+            }) if owner == "octocat" && project == "web" && node == "node-b"
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman admin log-level"),
+            Some(GatewayControlCommand::AdminLogLevel { action: LogLevelAction::Show })
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman admin log-level reset"),
+            Some(GatewayControlCommand::AdminLogLevel { action: LogLevelAction::Reset })
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman admin log-level debug --target docker"),
+            Some(GatewayControlCommand::AdminLogLevel {
+                action: LogLevelAction::Set { ref level, target: Some(ref target) },
+            }) if level == "debug" && target == "docker"
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman admin log-level debug --target"),
+            Some(GatewayControlCommand::Help)
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman admin operations"),
+            Some(GatewayControlCommand::AdminOperations { action: OperationsAction::List })
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman admin operations rollback octocat/web"),
+            Some(GatewayControlCommand::AdminOperations {
+                action: OperationsAction::Rollback { ref owner, ref project },
+            }) if owner == "octocat" && project == "web"
+        ));
+        for cmd in [
+            "agentman admin migrate octocat/web",
+            "agentman admin migrate web --to node-b",
+            "agentman admin migrate octocat/web --to --checkpoint",
+            "agentman admin operations resume web",
+            "agentman admin operations undo octocat/web",
+        ] {
+            assert!(
+                matches!(parse_gateway_control_command(cmd), Some(GatewayControlCommand::Help)),
+                "{cmd}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_env_file() {
+        let parse = |cmd| match parse_gateway_control_command(cmd) {
+            Some(GatewayControlCommand::EnvFile { action }) => Some(action),
+            _ => None,
+        };
+        assert_eq!(parse("agentman env-file"), Some(EnvFileAction::Show));
+        assert_eq!(parse("agentman env-file show"), Some(EnvFileAction::Show));
+        assert_eq!(parse("agentman env-file set"), Some(EnvFileAction::Set));
+        assert_eq!(parse("agentman env-file clear"), Some(EnvFileAction::Clear));
+        assert_eq!(parse("agentman env-file set .env"), None);
+        assert_eq!(parse("agentman env-file unset KEY"), None);
+    }
+
+    #[test]
+    fn test_parse_notify() {
+        let parse = |cmd| match parse_gateway_control_command(cmd) {
+            Some(GatewayControlCommand::Notify { action }) => Some(action),
+            _ => None,
+        };
+        let send = |message: &str| {
+            Some(NotifyAction::Send {
+                message: message.to_string(),
+            })
+        };
+        assert_eq!(parse("agentman notify"), Some(NotifyAction::Show));
+        assert_eq!(parse("agentman notify clear"), Some(NotifyAction::Clear));
+        assert_eq!(
+            parse("agentman notify set webhook https://hooks.example.com/x"),
+            Some(NotifyAction::Set(Notifier::Webhook {
+                url: "https://hooks.example.com/x".to_string()
+            }))
+        );
+        assert_eq!(
+            parse("agentman notify set email mona@example.com"),
+            Some(NotifyAction::Set(Notifier::Email {
+                address: "mona@example.com".to_string()
+            }))
+        );
+        assert_eq!(parse("agentman notify \"training finished\""), send("training finished"));
+        assert_eq!(parse("agentman notify tests passed"), send("tests passed"));
+        assert_eq!(parse("agentman notify -- clear"), send("clear"));
+        assert_eq!(parse("agentman notify set sms 555"), None);
+        assert_eq!(parse("agentman notify --"), None);
+    }
+
+    #[test]
+    fn test_parse_destroy_project() {
+        let parse = |cmd| match parse_gateway_control_command(cmd) {
+            Some(GatewayControlCommand::Destroy { project, yes, .. }) => Some((project, yes)),
+            _ => None,
+        };
+        assert_eq!(parse("agentman destroy --yes"), Some((None, true)));
+        assert_eq!(
+            parse("agentman destroy --project old-experiment --yes"),
+            Some((Some("old-experiment".to_string()), true))
+        );
+        assert_eq!(
+            parse("agentman destroy -y -p old-experiment"),
+            Some((Some("old-experiment".to_string()), true))
+        );
+        assert_eq!(parse("agentman destroy --project"), None);
+        assert_eq!(parse("agentman destroy --project --yes"), None);
+        assert_eq!(parse("agentman destroy --project a --project b"), None);
+    }
+
+    #[test]
+    fn test_parse_git_identity() {
+        let parse = |cmd| match parse_gateway_control_command(cmd) {
+            Some(GatewayControlCommand::GitIdentity { action }) => Some(action),
+            _ => None,
+        };
+        assert_eq!(parse("agentman git-identity"), Some(GitIdentityAction::Show));
+        assert_eq!(parse("agentman git-identity clear"), Some(GitIdentityAction::Clear));
+        assert_eq!(
+            parse("agentman git-identity set"),
+            Some(GitIdentityAction::Set {
+                name: None,
+                email: None
+            })
+        );
+        assert_eq!(
+            parse("agentman git-identity set --name Mona Lisa Octocat --email mona@example.com"),
+            Some(GitIdentityAction::Set {
+                name: Some("Mona Lisa Octocat".to_string()),
+                email: Some("mona@example.com".to_string()),
+            })
+        );
+        assert_eq!(
+            parse("agentman git-identity set --email mona@example.com"),
+            Some(GitIdentityAction::Set {
+                name: None,
+                email: Some("mona@example.com".to_string()),
+            })
+        );
+        assert_eq!(parse("agentman git-identity set --name"), None);
+        assert_eq!(parse("agentman git-identity set --email a@b --email c@d"), None);
+        assert_eq!(parse("agentman git-identity set Mona"), None);
+    }
+
+    #[test]
+    fn test_parse_trust() {
+        let parse = |cmd| match parse_gateway_control_command(cmd) {
+            Some(GatewayControlCommand::Trust { action }) => Some(action),
+            _ => None,
+        };
+        assert_eq!(parse("agentman trust"), Some(TrustAction::List));
+        assert_eq!(parse("agentman trust list"), Some(TrustAction::List));
+        assert_eq!(parse("agentman trust revoke 3"), Some(TrustAction::Revoke { id: 3 }));
+        assert_eq!(parse("agentman trust allow 3"), Some(TrustAction::Allow { id: 3 }));
+        assert_eq!(parse("agentman trust revoke"), None);
+        assert_eq!(parse("agentman trust revoke SHA256:abc"), None);
+    }
+
+    #[test]
+    fn test_parse_guest() {
+        let parse = |cmd| match parse_gateway_control_command(cmd) {
+            Some(GatewayControlCommand::Guest { action }) => Some(action),
+            _ => None,
+        };
+        assert_eq!(parse("agentman guest"), Some(GuestAction::List));
+        assert_eq!(
+            parse("agentman guest invite"),
+            Some(GuestAction::Invite {
+                ttl_secs: None,
+                read_only: false
+            })
+        );
+        assert_eq!(
+            parse("agentman guest invite --ttl 2h --read-only"),
+            Some(GuestAction::Invite {
+                ttl_secs: Some(7200),
+                read_only: true
+            })
+        );
+        assert_eq!(
+            parse("agentman guest revoke 1a2b3c4d"),
+            Some(GuestAction::Revoke {
+                id: Some("1a2b3c4d".to_string())
+            })
+        );
+        assert_eq!(parse("agentman guest revoke --all"), Some(GuestAction::Revoke { id: None }));
+        assert_eq!(parse("agentman guest invite --ttl"), None);
+        assert_eq!(parse("agentman guest invite --ttl 2 weeks"), None);
+        assert_eq!(parse("agentman guest revoke"), None);
+    }
+
+    #[test]
+    fn test_parse_lang() {
+        let parse = |cmd| match parse_gateway_control_command(cmd) {
+            Some(GatewayControlCommand::Lang { action }) => Some(action),
+            _ => None,
+        };
+        assert_eq!(parse("agentman lang"), Some(LangAction::Show));
+        assert_eq!(parse("agentman lang list"), Some(LangAction::List));
+        assert_eq!(
+            parse("agentman lang set tr"),
+            Some(LangAction::Set {
+                code: "tr".to_string()
+            })
+        );
+        assert_eq!(parse("agentman lang reset"), Some(LangAction::Reset));
+        assert_eq!(parse("agentman lang set"), None);
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let parse = |cmd| match parse_gateway_control_command(cmd) {
+            Some(GatewayControlCommand::Config { action }) => Some(action),
+            _ => None,
+        };
+        assert_eq!(parse("agentman config"), Some(ConfigAction::List));
+        assert_eq!(
+            parse("agentman config get shell"),
+            Some(ConfigAction::Get {
+                key: "shell".to_string()
+            })
+        );
+        assert_eq!(
+            parse("agentman config set idle_timeout 30m"),
+            Some(ConfigAction::Set {
+                key: "idle_timeout".to_string(),
+                value: "30m".to_string()
+            })
+        );
+        assert_eq!(
+            parse("agentman config unset shell"),
+            Some(ConfigAction::Unset {
+                key: "shell".to_string()
+            })
+        );
+        assert_eq!(parse("agentman config set shell"), None);
+    }
+
+    #[test]
+    fn test_parse_motd() {
+        let parse = |cmd| match parse_gateway_control_command(cmd) {
+            Some(GatewayControlCommand::Motd { action }) => Some(action),
+            _ => None,
+        };
+        assert_eq!(parse("agentman motd"), Some(MotdAction::Show));
+        assert_eq!(
+            parse("agentman motd set \"staging DB is at db:5432\""),
+            Some(MotdAction::Set {
+                text: "staging DB is at db:5432".to_string()
+            })
+        );
+        assert_eq!(parse("agentman motd clear"), Some(MotdAction::Clear));
+        assert_eq!(parse("agentman motd set"), None);
+    }
+
+    #[test]
+    fn test_parse_mesh() {
+        let parse = |cmd| match parse_gateway_control_command(cmd) {
+            Some(GatewayControlCommand::Mesh { action }) => Some(action),
+            _ => None,
+        };
+        assert_eq!(parse("agentman mesh"), Some(MeshAction::Show));
+        assert_eq!(
+            parse("agentman mesh allow api"),
+            Some(MeshAction::Allow {
+                target: "api".to_string()
+            })
+        );
+        assert_eq!(
+            parse("agentman mesh deny api"),
+            Some(MeshAction::Deny {
+                target: "api".to_string()
+            })
+        );
+        assert_eq!(parse("agentman mesh allow"), None);
+        assert_eq!(parse("agentman mesh allow api web"), None);
+    }
+
+    #[test]
+    fn test_parse_expose() {
+        let parse = |cmd| match parse_gateway_control_command(cmd) {
+            Some(GatewayControlCommand::Expose { action }) => Some(action),
+            _ => None,
+        };
+        assert_eq!(parse("agentman expose"), Some(ExposeAction::List));
+        assert_eq!(
+            parse("agentman expose 3000 --preview 1h"),
+            Some(ExposeAction::Preview {
+                port: 3000,
+                ttl_secs: 3600
+            })
+        );
+        assert_eq!(
+            parse("agentman expose --preview 30m 8080"),
+            Some(ExposeAction::Preview {
+                port: 8080,
+                ttl_secs: 1800
+            })
+        );
+        assert_eq!(
+            parse("agentman expose revoke 0a1b2c3d"),
+            Some(ExposeAction::Revoke {
+                id: Some("0a1b2c3d".to_string())
+            })
+        );
+        assert_eq!(parse("agentman expose revoke --all"), Some(ExposeAction::Revoke { id: None }));
+        assert_eq!(parse("agentman expose 3000"), None);
+        assert_eq!(parse("agentman expose 0 --preview 1h"), None);
+        assert_eq!(parse("agentman expose 3000 --preview forever"), None);
     }
-    (0u32, out)
-}
 
-fn destroy_confirmation_required_text() -> String {
-    "Refusing to destroy without confirmation.\n\
-This will stop/remove your container(s) and DELETE your persistent workspace.\n\n\
-Run one of:\n\
-  agentman destroy --yes\n\
-  agentman destroy --keep-workspace\n\
-  agentman destroy --dry-run\n"
-        .to_string()
-}
+    #[test]
+    fn test_format_table() {
+        let rows = [
+            ["octocat".to_string(), "2".to_string(), String::new()],
+            ["a".to_string(), "10".to_string(), "x".to_string()],
+        ];
+        assert_eq!(
+            format_table(&["USER", "KEYS", "LAST"], &rows),
+            "USER     KEYS  LAST\noctocat  2\na        10    x\n"
+        );
+    }
 
-async fn workspace_container_status(
-    container_manager: &ContainerManager,
-    container_name: &str,
-) -> (String, Option<String>) {
-    let (status, id, _running) =
-        workspace_container_status_with_running(container_manager, container_name).await;
-    (status, id)
-}
+    #[test]
+    fn test_format_round_trips() {
+        let ms = |n| Some(Duration::from_millis(n));
+        assert_eq!(
+            format_round_trips(&[ms(10), ms(20), ms(30)]),
+            "10.0/20.0/30.0 ms"
+        );
+        assert_eq!(format_round_trips(&[ms(4), None]), "4.0/4.0/4.0 ms (1 lost)");
+        assert_eq!(format_round_trips(&[None, None]), "no answer");
+    }
 
-async fn workspace_container_status_with_running(
-    container_manager: &ContainerManager,
-    container_name: &str,
-) -> (String, Option<String>, bool) {
-    let docker = container_manager.docker();
-    match docker
-        .inspect_container(container_name, None::<InspectContainerOptions>)
-        .await
-    {
-        Ok(info) => {
-            let state = info.state.as_ref();
-            let running = state.and_then(|s| s.running).unwrap_or(false);
-            let paused = state.and_then(|s| s.paused).unwrap_or(false);
-            let status = if paused {
-                "paused".to_string()
-            } else if running {
-                "running".to_string()
-            } else {
-                state
-                    .and_then(|s| s.status.as_ref().map(|s| s.to_string()))
-                    .unwrap_or_else(|| "stopped".to_string())
-            };
+    #[test]
+    fn test_format_limits() {
+        use bollard::models::{ContainerConfig, HostConfig, ResourcesUlimits};
+        use std::collections::HashMap;
 
-            let id_short = info
-                .id
-                .as_deref()
-                .map(|id| id.get(..12).unwrap_or(id).to_string());
-            (status, id_short, running)
-        }
-        Err(BollardError::DockerResponseServerError {
-            status_code: 404, ..
-        }) => ("missing".to_string(), None, false),
-        Err(_e) => ("error".to_string(), None, false),
+        let info = ContainerInspectResponse {
+            host_config: Some(HostConfig {
+                cap_drop: Some(vec!["ALL".to_string()]),
+                cap_add: Some(vec!["CHOWN".to_string(), "cap_setuid".to_string()]),
+                security_opt: Some(vec!["no-new-privileges:true".to_string()]),
+                readonly_rootfs: Some(true),
+                tmpfs: Some(HashMap::from([("/tmp".to_string(), "rw".to_string())])),
+                memory: Some(4 * 1024 * 1024 * 1024),
+                nano_cpus: Some(1_500_000_000),
+                ulimits: Some(vec![ResourcesUlimits {
+                    name: Some("nofile".to_string()),
+                    soft: Some(1024),
+                    hard: Some(4096),
+                }]),
+                network_mode: Some("bridge".to_string()),
+                ..Default::default()
+            }),
+            app_armor_profile: Some("docker-default".to_string()),
+            config: Some(ContainerConfig::default()),
+            ..Default::default()
+        };
+        let out = format_limits(&info);
+        assert!(out.contains("- capabilities: CHOWN, SETUID\n  (dropped: ALL; added: CHOWN, SETUID)\n"));
+        assert!(out.contains("- seccomp: docker default profile\n"));
+        assert!(out.contains("- apparmor: docker-default\n"));
+        assert!(out.contains("- no-new-privileges: yes\n"));
+        assert!(out.contains("- root filesystem: read-only (tmpfs: /tmp)\n"));
+        assert!(out.contains("- user: image default\n"));
+        assert!(out.contains("- memory: 4.0 GiB\n"));
+        assert!(out.contains("- cpus: 1.50\n"));
+        assert!(out.contains("- pids: unlimited\n"));
+        assert!(out.contains("- ulimits: nofile=1024:4096\n"));
+        assert!(out.contains("- mode: bridge\n"));
+
+        // Docker defaults without drops; unconfined seccomp is reported as such.
+        let info = ContainerInspectResponse {
+            host_config: Some(HostConfig {
+                cap_drop: Some(vec!["NET_RAW".to_string()]),
+                security_opt: Some(vec!["seccomp=unconfined".to_string()]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let out = format_limits(&info);
+        assert!(out.contains("KILL, MKNOD, NET_BIND_SERVICE, SETFCAP"));
+        assert!(!out.contains("NET_RAW,"));
+        assert!(out.contains("- seccomp: unconfined\n"));
+        assert!(out.contains("- no-new-privileges: no\n"));
     }
-}
 
-async fn container_stats_line(
-    container_manager: &ContainerManager,
-    container_name: &str,
-) -> Option<(f64, Option<(u64, u64)>)> {
-    let docker = container_manager.docker();
-    let mut stream = docker.stats(
-        container_name,
-        // Equivalent to `docker stats --no-stream`: the daemon waits for two samples so it can
-        // populate `precpu_stats` for CPU% calculation.
-        Some(StatsOptionsBuilder::new().stream(false).build()),
-    );
+    #[test]
+    fn test_parse_tunnel() {
+        assert!(matches!(
+            parse_gateway_control_command("agentman tunnel"),
+            Some(GatewayControlCommand::Tunnel)
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman proxy"),
+            Some(GatewayControlCommand::Proxy)
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman sessions"),
+            Some(GatewayControlCommand::Sessions)
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman storage"),
+            Some(GatewayControlCommand::Storage)
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman limits"),
+            Some(GatewayControlCommand::Limits)
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman open"),
+            Some(GatewayControlCommand::Open { target: OpenTarget::All })
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman open zed"),
+            Some(GatewayControlCommand::Open { target: OpenTarget::Zed })
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman open emacs"),
+            Some(GatewayControlCommand::Help)
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman scan"),
+            Some(GatewayControlCommand::Scan)
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman ping"),
+            Some(GatewayControlCommand::Ping { count: DEFAULT_PING_COUNT })
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman ping --count 10"),
+            Some(GatewayControlCommand::Ping { count: 10 })
+        ));
+        for cmd in ["agentman ping -c 0", "agentman ping -c 21", "agentman ping 3"] {
+            assert!(matches!(
+                parse_gateway_control_command(cmd),
+                Some(GatewayControlCommand::Help)
+            ));
+        }
+        assert!(matches!(
+            parse_gateway_control_command("agentman verify"),
+            Some(GatewayControlCommand::Verify { github_user: None })
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman verify octocat"),
+            Some(GatewayControlCommand::Verify { github_user: Some(ref u) }) if u == "octocat"
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman ephemeral"),
+            Some(GatewayControlCommand::Ephemeral)
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman bench --json"),
+            Some(GatewayControlCommand::Bench { json: true })
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman bench --quick"),
+            Some(GatewayControlCommand::Help)
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman tunnel up"),
+            Some(GatewayControlCommand::Help)
+        ));
+    }
 
-    // The daemon may wait for two cycles before returning the single response.
-    let next = timeout(Duration::from_secs(5), stream.next()).await.ok()??;
-    let stats = next.ok()?;
+    #[test]
+    fn test_parse_route() {
+        assert!(matches!(
+            parse_gateway_control_command("agentman route"),
+            Some(GatewayControlCommand::Route)
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman route -v"),
+            Some(GatewayControlCommand::Help)
+        ));
+    }
 
-    let cpu_stats = stats.cpu_stats.as_ref()?;
-    let precpu_stats = stats.precpu_stats.as_ref()?;
-    let cpu_usage = cpu_stats.cpu_usage.as_ref()?;
-    let precpu_usage = precpu_stats.cpu_usage.as_ref()?;
+    #[test]
+    fn test_parse_resize() {
+        let action = |cmd: &str| match parse_gateway_control_command(cmd) {
+            Some(GatewayControlCommand::Resize { action }) => Some(action),
+            _ => None,
+        };
+        assert_eq!(action("agentman resize"), Some(ResizeAction::Show));
+        assert_eq!(
+            action("agentman resize --shm 2g --nofile 65536"),
+            Some(ResizeAction::Set {
+                shm_size: Some("2g".to_string()),
+                nofile: Some(65536),
+                nproc: None,
+                now: false
+            })
+        );
+        assert_eq!(
+            action("agentman resize --now --nproc 4096"),
+            Some(ResizeAction::Set {
+                shm_size: None,
+                nofile: None,
+                nproc: Some(4096),
+                now: true
+            })
+        );
+        assert_eq!(action("agentman resize reset --now"), Some(ResizeAction::Reset { now: true }));
+        assert_eq!(action("agentman resize --now"), None);
+        assert_eq!(action("agentman resize --nofile 0"), None);
+        assert_eq!(action("agentman resize --shm"), None);
+        assert_eq!(action("agentman resize --shm 1g --shm 2g"), None);
+    }
 
-    let cpu_total = cpu_usage.total_usage.unwrap_or(0);
-    let cpu_total_pre = precpu_usage.total_usage.unwrap_or(0);
-    let system = cpu_stats.system_cpu_usage.unwrap_or(0);
-    let system_pre = precpu_stats.system_cpu_usage.unwrap_or(0);
+    #[test]
+    fn test_parse_cat_and_tail() {
+        let cat = |cmd: &str| match parse_gateway_control_command(cmd) {
+            Some(GatewayControlCommand::Cat { path }) => Some(path),
+            _ => None,
+        };
+        let tail = |cmd: &str| match parse_gateway_control_command(cmd) {
+            Some(GatewayControlCommand::Tail {
+                path,
+                lines,
+                follow,
+            }) => Some((path, lines, follow)),
+            _ => None,
+        };
+        assert_eq!(
+            cat("agentman cat /workspace/.env.example"),
+            Some("/workspace/.env.example".to_string())
+        );
+        assert_eq!(
+            cat("agentman cat -- notes/meeting notes.md"),
+            Some("notes/meeting notes.md".to_string())
+        );
+        assert_eq!(cat("agentman cat"), None);
+        assert_eq!(cat("agentman cat a b"), None);
+        assert_eq!(
+            tail("agentman tail logs/app.log"),
+            Some(("logs/app.log".to_string(), file_view::DEFAULT_TAIL_LINES, false))
+        );
+        assert_eq!(
+            tail("agentman tail -f -n 100 logs/app.log"),
+            Some(("logs/app.log".to_string(), 100, true))
+        );
+        assert_eq!(tail("agentman tail -f"), None);
+        assert_eq!(tail("agentman tail -n x a.log"), None);
+        assert_eq!(tail("agentman tail -n 100000 a.log"), None);
+        assert_eq!(tail("agentman tail -F a.log"), None);
+    }
 
-    let cpu_delta = cpu_total.saturating_sub(cpu_total_pre);
-    let system_delta = system.saturating_sub(system_pre);
+    #[test]
+    fn test_parse_search() {
+        let search = |cmd: &str| match parse_gateway_control_command(cmd) {
+            Some(GatewayControlCommand::Search {
+                pattern,
+                project,
+                all,
+                ignore_case,
+            }) => Some((pattern, project, all, ignore_case)),
+            _ => None,
+        };
+        assert_eq!(
+            search("agentman search TODO --project all"),
+            Some(("TODO".to_string(), None, true, false))
+        );
+        assert_eq!(
+            search("agentman search -i \"fix me\" -p api"),
+            Some(("fix me".to_string(), Some("api".to_string()), false, true))
+        );
+        assert_eq!(
+            search("agentman search --all --  --force  push"),
+            Some(("--force  push".to_string(), None, true, false))
+        );
+        assert_eq!(search("agentman search"), None);
+        assert_eq!(search("agentman search --force"), None);
+        assert_eq!(search("agentman search TODO --project"), None);
+        assert_eq!(search("agentman search TODO -p api --all"), None);
+    }
 
-    let percpu_count = cpu_usage
-        .percpu_usage
-        .as_ref()
-        .map(|v| v.len() as u64)
-        .unwrap_or(1);
-    let online_cpus = cpu_stats
-        .online_cpus
-        .map(|n| n as u64)
-        .filter(|&n| n > 0)
-        .unwrap_or(percpu_count.max(1));
+    #[test]
+    fn test_parse_image() {
+        let action = |cmd: &str| match parse_gateway_control_command(cmd) {
+            Some(GatewayControlCommand::Image { action }) => Some(action),
+            _ => None,
+        };
+        assert_eq!(action("agentman image"), Some(ImageAction::Show));
+        assert_eq!(action("agentman image list"), Some(ImageAction::List));
+        assert_eq!(
+            action("agentman image set python:agent-3.12"),
+            Some(ImageAction::Set {
+                image: "python:agent-3.12".to_string(),
+                now: false
+            })
+        );
+        assert_eq!(
+            action("agentman image set python:agent-3.12 --now"),
+            Some(ImageAction::Set {
+                image: "python:agent-3.12".to_string(),
+                now: true
+            })
+        );
+        assert_eq!(
+            action("agentman image reset --now"),
+            Some(ImageAction::Reset { now: true })
+        );
+        assert_eq!(action("agentman image set"), None);
+        assert_eq!(action("agentman image set --now"), None);
+    }
 
-    // Prefer Docker's standard calculation when system CPU usage deltas are available.
-    // Some engines / cgroup modes can omit or zero out system usage, so fall back to using the
-    // daemon-provided timestamps.
-    let cpu_percent = if cpu_delta == 0 {
-        0.0
-    } else if system_delta > 0 {
-        (cpu_delta as f64 / system_delta as f64) * online_cpus as f64 * 100.0
-    } else {
-        match (stats.read.as_deref(), stats.preread.as_deref()) {
-            (Some(read), Some(preread)) => {
-                let read_ns = DateTime::parse_from_rfc3339(read)
-                    .ok()
-                    .and_then(|dt| dt.timestamp_nanos_opt());
-                let preread_ns = DateTime::parse_from_rfc3339(preread)
-                    .ok()
-                    .and_then(|dt| dt.timestamp_nanos_opt());
-                match (read_ns, preread_ns) {
-                    (Some(r), Some(p)) if r > p => {
-                        let dt_ns = (r - p) as f64;
-                        (cpu_delta as f64 / dt_ns) * 100.0
-                    }
-                    _ => 0.0,
-                }
-            }
-            _ => 0.0,
-        }
-    };
+    #[test]
+    fn test_parse_build() {
+        let parse = |cmd: &str| match parse_gateway_control_command(cmd) {
+            Some(GatewayControlCommand::Build {
+                file,
+                no_cache,
+                use_image,
+            }) => Some((file, no_cache, use_image)),
+            _ => None,
+        };
+        assert_eq!(parse("agentman build"), Some((None, false, false)));
+        assert_eq!(
+            parse("agentman build --use --file docker/Dockerfile.dev --no-cache"),
+            Some((Some("docker/Dockerfile.dev".to_string()), true, true))
+        );
+        assert_eq!(
+            parse("agentman build -f Dockerfile"),
+            Some((Some("Dockerfile".to_string()), false, false))
+        );
+        assert_eq!(parse("agentman build --file"), None);
+        assert_eq!(parse("agentman build -f a -f b"), None);
+        assert_eq!(parse("agentman build .agentman"), None);
+    }
 
-    let mem = stats.memory_stats.as_ref().and_then(|m| match (m.usage, m.limit) {
-        (Some(u), Some(l)) if l > 0 => Some((u, l)),
-        _ => None,
-    });
+    #[test]
+    fn test_parse_tls() {
+        let action = |cmd: &str| match parse_gateway_control_command(cmd) {
+            Some(GatewayControlCommand::Tls { action }) => Some(action),
+            _ => None,
+        };
+        assert_eq!(action("agentman tls"), Some(TlsAction::Show));
+        assert_eq!(
+            action("agentman tls expose 8443"),
+            Some(TlsAction::Expose { port: 8443 })
+        );
+        assert_eq!(action("agentman tls close"), Some(TlsAction::Close));
+        assert_eq!(action("agentman tls expose 0"), None);
+        assert_eq!(action("agentman tls expose https"), None);
+    }
 
-    Some((cpu_percent, mem))
-}
+    #[test]
+    fn test_parse_ownership() {
+        let action = |cmd: &str| match parse_gateway_control_command(cmd) {
+            Some(GatewayControlCommand::Ownership { action }) => Some(action),
+            _ => None,
+        };
+        assert_eq!(action("agentman ownership"), Some(OwnershipAction::Show));
+        assert_eq!(action("agentman ownership watch"), Some(OwnershipAction::Watch));
+        assert_eq!(action("agentman ownership unwatch"), Some(OwnershipAction::Unwatch));
+        assert_eq!(action("agentman ownership fix"), None);
+    }
 
-/// Fast version for watch mode: uses one_shot for quicker response.
-/// CPU% may be less accurate but memory is reliable.
-async fn container_stats_line_fast(
-    container_manager: &ContainerManager,
-    container_name: &str,
-) -> Option<(Option<f64>, Option<(u64, u64)>)> {
-    let docker = container_manager.docker();
-    let mut stream = docker.stats(
-        container_name,
-        // one_shot gives a quick single sample (precpu_stats may be empty).
-        Some(StatsOptionsBuilder::new().stream(false).one_shot(true).build()),
-    );
+    #[test]
+    fn test_parse_cache() {
+        assert!(matches!(
+            parse_gateway_control_command("agentman cache"),
+            Some(GatewayControlCommand::CacheShow)
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman cache prune"),
+            Some(GatewayControlCommand::CachePrune {
+                older_than_days: Some(30)
+            })
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman cache prune --older-than 7d"),
+            Some(GatewayControlCommand::CachePrune {
+                older_than_days: Some(7)
+            })
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman cache prune --all"),
+            Some(GatewayControlCommand::CachePrune {
+                older_than_days: None
+            })
+        ));
+    }
 
-    let next = timeout(Duration::from_millis(1500), stream.next()).await.ok()??;
-    let stats = next.ok()?;
+    #[test]
+    fn test_parse_restart() {
+        assert!(matches!(
+            parse_gateway_control_command("agentman restart"),
+            Some(GatewayControlCommand::Restart)
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman restart now"),
+            Some(GatewayControlCommand::Help)
+        ));
+    }
 
-    // Memory is always reliable.
-    let mem = stats.memory_stats.as_ref().and_then(|m| match (m.usage, m.limit) {
-        (Some(u), Some(l)) if l > 0 => Some((u, l)),
-        _ => None,
-    });
+    #[tokio::test]
+    async fn test_run_restart() {
+        use crate::backend::mock::MockBackend;
+        use crate::state::StateManager;
+        use std::sync::Arc;
 
-    // CPU with one_shot: precpu_stats may be empty, so we try but may return None.
-    let cpu = (|| {
-        let cpu_stats = stats.cpu_stats.as_ref()?;
-        let precpu_stats = stats.precpu_stats.as_ref()?;
-        let cpu_usage = cpu_stats.cpu_usage.as_ref()?;
-        let precpu_usage = precpu_stats.cpu_usage.as_ref()?;
+        let path =
+            std::env::temp_dir().join(format!("agentman-restart-{}.json", std::process::id()));
+        let state = Arc::new(StateManager::load(path.clone()).await.unwrap());
+        let backend = MockBackend::new(state.clone());
+        let (status, output) = run_restart(&backend, "octocat", "web").await;
+        assert_eq!((status, output.as_str()), (1, "agentman: no sandbox found for octocat/web\n"));
 
-        let cpu_total = cpu_usage.total_usage.unwrap_or(0);
-        let cpu_total_pre = precpu_usage.total_usage.unwrap_or(0);
-        if cpu_total == 0 || cpu_total_pre == 0 || cpu_total <= cpu_total_pre {
-            return None;
-        }
+        state
+            .set_workspace(WorkspaceInfo {
+                github_user: "octocat".to_string(),
+                project: "web".to_string(),
+                container_name: "agentman-octocat-web".to_string(),
+                container_id: None,
+                created_at: Utc::now(),
+                host_workspace_path: PathBuf::from("/srv/workspaces/octocat/web"),
+                linked_projects: Vec::new(),
+                init_command: None,
+                init_status: None,
+                image: None,
+                tls_port: None,
+                watch_ownership: false,
+                clone: None,
+                resources: Default::default(),
+            })
+            .await
+            .unwrap();
+        let id = backend.get_or_create_container("octocat", "web").await.unwrap();
+        let (status, output) = run_restart(&backend, "octocat", "web").await;
+        assert_eq!(status, 0, "{output}");
+        assert!(output.starts_with("agentman: restarted sandbox web (agentman-octocat-web) in "));
+        assert_eq!(backend.lifecycle(), [format!("stop {id}"), format!("start {id}")]);
+        assert_eq!(backend.container("octocat", "web"), Some(id));
+        // Restarting clears the connection's cached inspections, so `status` shows the restart.
+        assert!(!GatewayControlCommand::Restart.reports_only());
 
-        let system = cpu_stats.system_cpu_usage.unwrap_or(0);
-        let system_pre = precpu_stats.system_cpu_usage.unwrap_or(0);
-        let cpu_delta = cpu_total.saturating_sub(cpu_total_pre);
-        let system_delta = system.saturating_sub(system_pre);
+        backend.fail_create.store(true, std::sync::atomic::Ordering::Relaxed);
+        let (status, output) = run_restart(&backend, "octocat", "web").await;
+        assert_eq!(status, 1);
+        assert!(output.starts_with("agentman: restart failed: "), "{output}");
 
-        let percpu_count = cpu_usage.percpu_usage.as_ref().map(|v| v.len() as u64).unwrap_or(1);
-        let online_cpus = cpu_stats.online_cpus.map(|n| n as u64).filter(|&n| n > 0).unwrap_or(percpu_count.max(1));
+        let _ = std::fs::remove_file(&path);
+    }
 
-        if system_delta > 0 {
-            Some((cpu_delta as f64 / system_delta as f64) * online_cpus as f64 * 100.0)
-        } else {
-            None
-        }
-    })();
+    #[test]
+    fn test_parse_recreate() {
+        assert!(matches!(
+            parse_gateway_control_command("agentman recreate"),
+            Some(GatewayControlCommand::Recreate {
+                keep_running: false,
+                pull: false
+            })
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman recreate --pull --keep-running"),
+            Some(GatewayControlCommand::Recreate {
+                keep_running: true,
+                pull: true
+            })
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman recreate --now"),
+            Some(GatewayControlCommand::Help)
+        ));
+    }
 
-    Some((cpu, mem))
-}
+    #[test]
+    fn test_parse_checkpoint() {
+        let action = |cmd| match parse_gateway_control_command(cmd) {
+            Some(GatewayControlCommand::Checkpoint { action }) => Some(action),
+            _ => None,
+        };
+        assert_eq!(
+            action("agentman checkpoint"),
+            Some(CheckpointAction::Create {
+                leave_running: false
+            })
+        );
+        assert_eq!(
+            action("agentman checkpoint --leave-running"),
+            Some(CheckpointAction::Create {
+                leave_running: true
+            })
+        );
+        assert_eq!(action("agentman resume"), Some(CheckpointAction::Resume));
+        assert_eq!(action("agentman checkpoint now"), None);
+        assert_eq!(action("agentman resume latest"), None);
+    }
 
-async fn du_bytes(path: &Path) -> Option<u64> {
-    let out = Command::new("du")
-        .arg("-s")
-        .arg("--block-size=1")
-        .arg(path)
-        .output()
-        .await
-        .ok()?;
-    if !out.status.success() {
-        return None;
+    #[test]
+    fn test_parse_backup() {
+        let action = |cmd| match parse_gateway_control_command(cmd) {
+            Some(GatewayControlCommand::Backup { action }) => Some(action),
+            _ => None,
+        };
+        assert_eq!(action("agentman backup"), Some(BackupAction::Create));
+        assert_eq!(action("agentman backup list"), Some(BackupAction::List));
+        assert_eq!(
+            action("agentman backup restore 2026-10-14T09:30 --yes"),
+            Some(BackupAction::Restore {
+                spec: "2026-10-14T09:30".to_string(),
+                yes: true
+            })
+        );
+        assert_eq!(
+            action("agentman backup restore latest"),
+            Some(BackupAction::Restore {
+                spec: "latest".to_string(),
+                yes: false
+            })
+        );
+        assert_eq!(action("agentman backup restore"), None);
+        assert_eq!(action("agentman backup restore --yes"), None);
     }
-    let stdout = String::from_utf8_lossy(&out.stdout);
-    let first = stdout.split_whitespace().next()?;
-    first.parse::<u64>().ok()
-}
 
-fn format_bytes(bytes: u64) -> String {
-    const KB: f64 = 1024.0;
-    const MB: f64 = 1024.0 * KB;
-    const GB: f64 = 1024.0 * MB;
-    const TB: f64 = 1024.0 * GB;
+    #[test]
+    fn test_parse_wait() {
+        assert!(matches!(
+            parse_gateway_control_command("agentman wait"),
+            Some(GatewayControlCommand::Wait {
+                timeout_secs: 120,
+                port: None
+            })
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman wait --timeout 30 --port 5432"),
+            Some(GatewayControlCommand::Wait {
+                timeout_secs: 30,
+                port: Some(5432)
+            })
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman wait --timeout"),
+            Some(GatewayControlCommand::Help)
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman wait --port 99999"),
+            Some(GatewayControlCommand::Help)
+        ));
+    }
 
-    let b = bytes as f64;
-    if b < KB {
-        format!("{bytes} B")
-    } else if b < MB {
-        format!("{:.1} KiB", b / KB)
-    } else if b < GB {
-        format!("{:.1} MiB", b / MB)
-    } else if b < TB {
-        format!("{:.1} GiB", b / GB)
-    } else {
-        format!("{:.1} TiB", b / TB)
+    #[test]
+    fn test_parse_link() {
+        assert!(matches!(
+            parse_gateway_control_command("agentman link"),
+            Some(GatewayControlCommand::Link { project: None })
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman link api"),
+            Some(GatewayControlCommand::Link { project: Some(p) }) if p == "api"
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman unlink"),
+            Some(GatewayControlCommand::Help)
+        ));
+        assert!(matches!(
+            parse_gateway_control_command("agentman unlink api extra"),
+            Some(GatewayControlCommand::Help)
+        ));
     }
 }
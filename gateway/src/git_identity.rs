@@ -0,0 +1,144 @@
+//! Git author identity in sandboxes (`agentman git-identity`).
+//!
+//! A user's identity is kept in gateway state and written to the global git config of each new
+//! container, and of their running ones when it changes. Parts not given on the command line
+//! come from the user's GitHub profile: the display name (or login) and the public email, or the
+//! `noreply` address GitHub attributes to the account when no email is public.
+
+use anyhow::{Result, bail};
+
+use crate::docker::ContainerManager;
+use crate::github;
+use crate::state::GitIdentity;
+
+/// Writes `user.name` and `user.email` (arguments 1 and 2) to the global git config.
+const APPLY_SCRIPT: &str = r#"
+command -v git >/dev/null 2>&1 || exit 0
+git config --global user.name "$1" && git config --global user.email "$2"
+"#;
+
+/// Prints the global `user.name` and `user.email` as `name=` and `email=` lines.
+const SHOW_SCRIPT: &str = r#"
+command -v git >/dev/null 2>&1 || exit 3
+printf 'name=%s\n' "$(git config --global --get user.name)"
+printf 'email=%s\n' "$(git config --global --get user.email)"
+"#;
+
+/// Complete `name` and `email` from `github_user`'s GitHub profile where missing.
+pub async fn resolve(
+    github_user: &str,
+    name: Option<String>,
+    email: Option<String>,
+) -> Result<GitIdentity> {
+    if let (Some(name), Some(email)) = (&name, &email) {
+        return Ok(GitIdentity {
+            name: name.clone(),
+            email: email.clone(),
+        });
+    }
+    let profile = github::api().fetch_profile(github_user).await?;
+    let name = name.unwrap_or_else(|| {
+        profile
+            .name
+            .clone()
+            .filter(|n| !n.trim().is_empty())
+            .unwrap_or_else(|| profile.login.clone())
+    });
+    let email = email.unwrap_or_else(|| {
+        profile
+            .email
+            .clone()
+            .filter(|e| !e.trim().is_empty())
+            .unwrap_or_else(|| profile.noreply_email())
+    });
+    Ok(GitIdentity { name, email })
+}
+
+/// Reject identities git would choke on (or that can't be an address at all).
+pub fn validate(identity: &GitIdentity) -> Result<()> {
+    if identity.name.trim().is_empty() || identity.name.contains(['\n', '<', '>']) {
+        bail!("invalid name {:?}", identity.name);
+    }
+    let email = &identity.email;
+    match email.split_once('@') {
+        Some((local, domain))
+            if !local.is_empty()
+                && !domain.is_empty()
+                && !email.contains(|c: char| c.is_whitespace() || c == '<' || c == '>') => {}
+        _ => bail!("invalid email {:?}", email),
+    }
+    Ok(())
+}
+
+/// Write `identity` to the global git config of the container's default user (no-op without
+/// git in the image).
+pub async fn apply(
+    container_manager: &ContainerManager,
+    container_id: &str,
+    identity: &GitIdentity,
+) -> Result<()> {
+    let cmd = vec![
+        "sh".to_string(),
+        "-c".to_string(),
+        APPLY_SCRIPT.to_string(),
+        "sh".to_string(),
+        identity.name.clone(),
+        identity.email.clone(),
+    ];
+    let output = container_manager.run_exec(container_id, cmd).await?;
+    if output.exit_code != 0 {
+        bail!(
+            "git config exited with {}: {}",
+            output.exit_code,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// The global `user.name` and `user.email` in the container (empty when unset; `None` without
+/// git).
+pub async fn current(
+    container_manager: &ContainerManager,
+    container_id: &str,
+) -> Result<Option<(String, String)>> {
+    let cmd = vec!["sh".to_string(), "-c".to_string(), SHOW_SCRIPT.to_string()];
+    let output = container_manager.run_exec(container_id, cmd).await?;
+    if output.exit_code == 3 {
+        return Ok(None);
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let field = |key: &str| {
+        stdout
+            .lines()
+            .find_map(|l| l.strip_prefix(key))
+            .unwrap_or_default()
+            .to_string()
+    };
+    let (name, email) = (field("name="), field("email="));
+    Ok(Some((name, email)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity(name: &str, email: &str) -> GitIdentity {
+        GitIdentity {
+            name: name.to_string(),
+            email: email.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(validate(&identity("Mona Lisa Octocat", "mona@example.com")).is_ok());
+        assert!(validate(&identity("octocat", "1+octocat@users.noreply.github.com")).is_ok());
+
+        assert!(validate(&identity(" ", "mona@example.com")).is_err());
+        assert!(validate(&identity("Mona <x>", "mona@example.com")).is_err());
+        assert!(validate(&identity("Mona", "mona")).is_err());
+        assert!(validate(&identity("Mona", "@example.com")).is_err());
+        assert!(validate(&identity("Mona", "mona@example.com>")).is_err());
+    }
+}
@@ -0,0 +1,207 @@
+//! Git commit signing with the user's GitHub SSH signing keys (`[git_signing]`).
+//!
+//! When a container is created, the user's signing keys are fetched from GitHub (and cached for
+//! `cache_secs`) and written to an allowed-signers file in the container, and git is set up to
+//! sign with the first of them through the forwarded agent (`user.signingkey = "key::..."`).
+//! Signing the user set up themselves (any other `user.signingkey`) is left alone.
+
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use tracing::{debug, warn};
+
+use crate::config::GitSigningConfig;
+use crate::docker::ContainerManager;
+use crate::github::{self, parse_ssh_key};
+
+/// Signing keys by GitHub user, with when they were fetched.
+type Cache = HashMap<String, (Instant, Vec<String>)>;
+
+static CACHE: LazyLock<Mutex<Cache>> = LazyLock::new(Default::default);
+
+/// Sets up signing for the container's default user. Arguments: `sign_commits` (`true` or
+/// `false`), then the keys, the first of which signs.
+const SETUP_SCRIPT: &str = r#"
+command -v git >/dev/null 2>&1 || exit 0
+sign_commits=$1
+shift
+dir="${XDG_CONFIG_HOME:-$HOME/.config}/git"
+mkdir -p "$dir" || exit 1
+for key in "$@"; do
+    printf '* %s\n' "$key"
+done > "$dir/allowed_signers.tmp" || exit 1
+mv "$dir/allowed_signers.tmp" "$dir/allowed_signers" || exit 1
+git config --global gpg.ssh.allowedSignersFile "$dir/allowed_signers" || exit 1
+
+current=$(git config --global --get user.signingkey)
+case "$current" in
+    "" | key::*) ;;
+    *) exit 0 ;;
+esac
+keep=
+for key in "$@"; do
+    if [ "$current" = "key::$key" ]; then
+        keep=1
+    fi
+done
+if [ -z "$keep" ]; then
+    git config --global user.signingkey "key::$1" || exit 1
+fi
+git config --global gpg.format ssh || exit 1
+if [ "$sign_commits" = true ] && ! git config --global --get commit.gpgsign >/dev/null; then
+    git config --global commit.gpgsign true || exit 1
+fi
+"#;
+
+/// The user's signing keys (normalized to `type base64`), fetched from GitHub unless fetched
+/// within `cache_secs`. When GitHub can't be reached, the last keys fetched are used.
+async fn signing_keys(config: &GitSigningConfig, github_user: &str) -> Result<Vec<String>> {
+    let cached = CACHE.lock().unwrap().get(github_user).cloned();
+    if let Some((at, ref keys)) = cached
+        && at.elapsed() < Duration::from_secs(config.cache_secs)
+    {
+        return Ok(keys.clone());
+    }
+    let keys = match github::api().fetch_signing_keys(github_user).await {
+        Ok(keys) => normalize(&keys),
+        Err(e) => match cached {
+            Some((_, keys)) => {
+                warn!("Using cached signing keys for {}: {:#}", github_user, e);
+                return Ok(keys);
+            }
+            None => return Err(e),
+        },
+    };
+    CACHE
+        .lock()
+        .unwrap()
+        .insert(github_user.to_string(), (Instant::now(), keys.clone()));
+    Ok(keys)
+}
+
+/// Drop comments and anything that doesn't parse as a public key.
+fn normalize(keys: &[String]) -> Vec<String> {
+    keys.iter()
+        .filter_map(|key| parse_ssh_key(key).ok())
+        .map(|(key_type, key_data)| format!("{} {}", key_type, key_data))
+        .collect()
+}
+
+/// Set up commit signing in a new container of `github_user` (no-op without signing keys).
+pub async fn configure(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    container_id: &str,
+) -> Result<()> {
+    let config = &container_manager.config().git_signing;
+    let keys = signing_keys(config, github_user).await?;
+    if keys.is_empty() {
+        debug!("{} has no SSH signing keys on GitHub", github_user);
+        return Ok(());
+    }
+    let output = container_manager
+        .run_exec(container_id, setup_command(&keys, config.sign_commits))
+        .await?;
+    if output.exit_code != 0 {
+        anyhow::bail!(
+            "git signing setup exited with {}: {}",
+            output.exit_code,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+fn setup_command(keys: &[String], sign_commits: bool) -> Vec<String> {
+    let mut cmd = vec![
+        "sh".to_string(),
+        "-c".to_string(),
+        SETUP_SCRIPT.to_string(),
+        "sh".to_string(),
+        sign_commits.to_string(),
+    ];
+    cmd.extend(keys.iter().cloned());
+    cmd
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_A: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIFcM2rt5d0eoq9sAHzKFmUcfTHmUfsDdSnHhhBCgEXAq";
+    const KEY_B: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIB2x8ZOmBdfGyHg1rMs3TAbWjWXwU4Uo6cK1vvUQMoUG";
+
+    #[test]
+    fn test_normalize() {
+        let keys = vec![
+            format!("{} laptop", KEY_A),
+            "not a key".to_string(),
+            KEY_B.to_string(),
+        ];
+        assert_eq!(normalize(&keys), vec![KEY_A.to_string(), KEY_B.to_string()]);
+    }
+
+    /// `program` with `home` as the home directory and no other global git config.
+    fn in_home(program: &str, home: &std::path::Path) -> std::process::Command {
+        let mut cmd = std::process::Command::new(program);
+        cmd.env("HOME", home)
+            .env_remove("XDG_CONFIG_HOME")
+            .env_remove("GIT_CONFIG_GLOBAL");
+        cmd
+    }
+
+    #[test]
+    fn test_setup_script() {
+        if std::process::Command::new("git")
+            .arg("--version")
+            .output()
+            .is_err()
+        {
+            return;
+        }
+        let home =
+            std::env::temp_dir().join(format!("agentman-signing-test-{}", std::process::id()));
+        std::fs::create_dir_all(&home).unwrap();
+        let run = |keys: &[&str]| {
+            let keys: Vec<String> = keys.iter().map(|k| k.to_string()).collect();
+            let cmd = setup_command(&keys, true);
+            let status = in_home(&cmd[0], &home).args(&cmd[1..]).status().unwrap();
+            assert!(status.success());
+        };
+        let git_config = |key: &str| {
+            let out = in_home("git", &home)
+                .args(["config", "--global", "--get", key])
+                .output()
+                .unwrap();
+            String::from_utf8_lossy(&out.stdout).trim().to_string()
+        };
+
+        run(&[KEY_A, KEY_B]);
+        assert_eq!(git_config("user.signingkey"), format!("key::{}", KEY_A));
+        assert_eq!(git_config("gpg.format"), "ssh");
+        assert_eq!(git_config("commit.gpgsign"), "true");
+        let signers = std::fs::read_to_string(home.join(".config/git/allowed_signers")).unwrap();
+        assert_eq!(signers, format!("* {}\n* {}\n", KEY_A, KEY_B));
+
+        // A key the user picked among theirs is kept; a key removed from GitHub is replaced.
+        run(&[KEY_B, KEY_A]);
+        assert_eq!(git_config("user.signingkey"), format!("key::{}", KEY_A));
+        run(&[KEY_B]);
+        assert_eq!(git_config("user.signingkey"), format!("key::{}", KEY_B));
+
+        // Signing set up by the user is left alone.
+        let gpg_key = "0123456789ABCDEF";
+        in_home("git", &home)
+            .args(["config", "--global", "user.signingkey", gpg_key])
+            .status()
+            .unwrap();
+        run(&[KEY_A]);
+        assert_eq!(git_config("user.signingkey"), gpg_key);
+
+        std::fs::remove_dir_all(&home).unwrap();
+    }
+}
@@ -3,34 +3,92 @@
 //! This module handles:
 //! - Fetching a user's SSH public keys from `github.com/<user>.keys`
 //! - Verifying a presented SSH key against a user's known keys
+//! - Fetching a user's SSH signing keys and profile from the GitHub API (for git in sandboxes
+//!   and display names)
+//! - Checking team memberships (for security profiles)
 //! - Computing key fingerprints for caching
+//!
+//! Requests go to the URLs in `[github]`, github.com unless configured otherwise.
 
 use anyhow::{anyhow, Context, Result};
 use base64::Engine;
 use sha2::{Digest, Sha256};
-use tracing::{debug, info};
+use std::sync::OnceLock;
+use tracing::{debug, info, warn};
+
+use crate::config::GitHubConfig;
 
 /// HTTP client for fetching GitHub keys.
 pub struct GitHubKeyFetcher {
     client: reqwest::Client,
+    /// `[github] keys_url` and `api_url`, without a trailing slash.
+    keys_url: String,
+    api_url: String,
+}
+
+/// Public profile of a GitHub user, as far as git identities and display names need it.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct GitHubProfile {
+    pub login: String,
+    pub id: u64,
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Only set when the user made an email public.
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub avatar_url: Option<String>,
+}
+
+/// Result of a profile lookup that may run into GitHub's rate limit.
+#[derive(Debug)]
+pub enum ProfileLookup {
+    Found(GitHubProfile),
+    /// The API refused the request for now; retry after this long (when GitHub said).
+    RateLimited(Option<std::time::Duration>),
+}
+
+impl GitHubProfile {
+    /// The user's GitHub-provided `noreply` address, which GitHub attributes to them.
+    pub fn noreply_email(&self) -> String {
+        format!("{}+{}@users.noreply.github.com", self.id, self.login)
+    }
+}
+
+static API: OnceLock<GitHubKeyFetcher> = OnceLock::new();
+
+/// Point [`api`] at `config`'s URLs; called once at startup, before any lookup.
+pub fn configure(config: &GitHubConfig) {
+    if API.set(GitHubKeyFetcher::new(config)).is_err() {
+        warn!("GitHub API client already in use; [github] applies to logins only");
+    }
+}
+
+/// Shared client for GitHub API lookups outside the SSH auth path.
+pub fn api() -> &'static GitHubKeyFetcher {
+    API.get_or_init(|| GitHubKeyFetcher::new(&GitHubConfig::default()))
 }
 
 impl GitHubKeyFetcher {
     /// Create a new GitHub key fetcher.
-    pub fn new() -> Self {
+    pub fn new(config: &GitHubConfig) -> Self {
         let client = reqwest::Client::builder()
             .user_agent("agentman-gateway/0.1")
             .timeout(std::time::Duration::from_secs(10))
             .build()
             .expect("Failed to create HTTP client");
-        Self { client }
+        Self {
+            client,
+            keys_url: config.keys_url.trim_end_matches('/').to_string(),
+            api_url: config.api_url.trim_end_matches('/').to_string(),
+        }
     }
 
     /// Fetch SSH public keys for a GitHub user.
     ///
     /// Returns a list of key strings in OpenSSH format.
     pub async fn fetch_keys(&self, github_user: &str) -> Result<Vec<String>> {
-        let url = format!("https://github.com/{}.keys", github_user);
+        let url = format!("{}/{}.keys", self.keys_url, github_user);
         debug!("Fetching keys from {}", url);
 
         let response = self
@@ -68,6 +126,190 @@ impl GitHubKeyFetcher {
         Ok(keys)
     }
 
+    /// Fetch a user's public SSH signing keys (separate from their authentication keys).
+    ///
+    /// Returns a list of key strings in OpenSSH format.
+    pub async fn fetch_signing_keys(&self, github_user: &str) -> Result<Vec<String>> {
+        #[derive(serde::Deserialize)]
+        struct SigningKey {
+            key: String,
+        }
+
+        let url = format!("{}/users/{}/ssh_signing_keys", self.api_url, github_user);
+        debug!("Fetching signing keys from {}", url);
+
+        let response = self
+            .client
+            .get(&url)
+            .header("Accept", "application/vnd.github+json")
+            .send()
+            .await
+            .with_context(|| format!("Failed to fetch signing keys for {}", github_user))?;
+
+        if !response.status().is_success() {
+            return Err(anyhow!(
+                "GitHub returned {} for signing keys of {}",
+                response.status(),
+                github_user
+            ));
+        }
+
+        let body = response
+            .text()
+            .await
+            .with_context(|| format!("Failed to read signing keys for {}", github_user))?;
+        let keys: Vec<SigningKey> = serde_json::from_str(&body)
+            .with_context(|| format!("Failed to parse signing keys for {}", github_user))?;
+
+        info!(
+            "Fetched {} signing key(s) for GitHub user {}",
+            keys.len(),
+            github_user
+        );
+
+        Ok(keys.into_iter().map(|k| k.key.trim().to_string()).collect())
+    }
+
+    /// Fetch a user's public profile.
+    pub async fn fetch_profile(&self, github_user: &str) -> Result<GitHubProfile> {
+        match self.lookup_profile(github_user).await? {
+            ProfileLookup::Found(profile) => Ok(profile),
+            ProfileLookup::RateLimited(_) => Err(anyhow!(
+                "GitHub's API rate limit is exhausted; try again later"
+            )),
+        }
+    }
+
+    /// Fetch a user's public profile, telling a rate-limited API apart from other failures.
+    pub async fn lookup_profile(&self, github_user: &str) -> Result<ProfileLookup> {
+        let url = format!("{}/users/{}", self.api_url, github_user);
+        debug!("Fetching profile from {}", url);
+
+        let response = self
+            .client
+            .get(&url)
+            .header("Accept", "application/vnd.github+json")
+            .send()
+            .await
+            .with_context(|| format!("Failed to fetch profile of {}", github_user))?;
+
+        if let Some(retry_after) = rate_limited(&response) {
+            return Ok(ProfileLookup::RateLimited(retry_after));
+        }
+        if !response.status().is_success() {
+            return Err(anyhow!(
+                "GitHub returned {} for profile of {}",
+                response.status(),
+                github_user
+            ));
+        }
+
+        let body = response
+            .text()
+            .await
+            .with_context(|| format!("Failed to read profile of {}", github_user))?;
+        serde_json::from_str(&body)
+            .map(ProfileLookup::Found)
+            .with_context(|| format!("Failed to parse profile of {}", github_user))
+    }
+
+    /// Whether `github_user` is an active member of `org`'s team `team_slug`. Needs a token
+    /// that can read the org's teams.
+    pub async fn fetch_team_membership(
+        &self,
+        org: &str,
+        team_slug: &str,
+        github_user: &str,
+        token: &str,
+    ) -> Result<bool> {
+        #[derive(serde::Deserialize)]
+        struct Membership {
+            state: String,
+        }
+
+        let team = format!("{}/{}", org, team_slug);
+        let url = format!(
+            "{}/orgs/{}/teams/{}/memberships/{}",
+            self.api_url, org, team_slug, github_user
+        );
+        debug!("Fetching team membership from {}", url);
+
+        let response = self
+            .client
+            .get(&url)
+            .header("Accept", "application/vnd.github+json")
+            .bearer_auth(token)
+            .send()
+            .await
+            .with_context(|| format!("Failed to fetch {} membership of {}", team, github_user))?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        if !response.status().is_success() {
+            return Err(anyhow!(
+                "GitHub returned {} for {} membership of {}",
+                response.status(),
+                team,
+                github_user
+            ));
+        }
+
+        let body = response
+            .text()
+            .await
+            .with_context(|| format!("Failed to read {} membership of {}", team, github_user))?;
+        let membership: Membership = serde_json::from_str(&body)
+            .with_context(|| format!("Failed to parse {} membership of {}", team, github_user))?;
+        Ok(membership.state == "active")
+    }
+
+    /// Whether `github_user` is an active member of the organization `org`. Needs a token
+    /// that can read the org's members.
+    pub async fn fetch_org_membership(
+        &self,
+        org: &str,
+        github_user: &str,
+        token: &str,
+    ) -> Result<bool> {
+        #[derive(serde::Deserialize)]
+        struct Membership {
+            state: String,
+        }
+
+        let url = format!("{}/orgs/{}/memberships/{}", self.api_url, org, github_user);
+        debug!("Fetching org membership from {}", url);
+
+        let response = self
+            .client
+            .get(&url)
+            .header("Accept", "application/vnd.github+json")
+            .bearer_auth(token)
+            .send()
+            .await
+            .with_context(|| format!("Failed to fetch {} membership of {}", org, github_user))?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        if !response.status().is_success() {
+            return Err(anyhow!(
+                "GitHub returned {} for {} membership of {}",
+                response.status(),
+                org,
+                github_user
+            ));
+        }
+
+        let body = response
+            .text()
+            .await
+            .with_context(|| format!("Failed to read {} membership of {}", org, github_user))?;
+        let membership: Membership = serde_json::from_str(&body)
+            .with_context(|| format!("Failed to parse {} membership of {}", org, github_user))?;
+        Ok(membership.state == "active")
+    }
+
     /// Verify that a public key belongs to a GitHub user.
     ///
     /// Returns the key type (e.g., "ssh-ed25519") if the key is found.
@@ -99,6 +341,34 @@ impl GitHubKeyFetcher {
     }
 }
 
+/// `Some` (with the wait, if GitHub gave one) when `response` is GitHub refusing a request
+/// over its rate limit: a 429, or a 403 with no requests remaining.
+fn rate_limited(response: &reqwest::Response) -> Option<Option<std::time::Duration>> {
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<u64>().ok())
+    };
+    let exhausted = match response.status() {
+        reqwest::StatusCode::TOO_MANY_REQUESTS => true,
+        reqwest::StatusCode::FORBIDDEN => {
+            header("x-ratelimit-remaining") == Some(0) || header("retry-after").is_some()
+        }
+        _ => false,
+    };
+    if !exhausted {
+        return None;
+    }
+    let retry_after = header("retry-after").or_else(|| {
+        let reset = header("x-ratelimit-reset")?;
+        let now = chrono::Utc::now().timestamp().max(0) as u64;
+        Some(reset.saturating_sub(now))
+    });
+    Some(retry_after.map(std::time::Duration::from_secs))
+}
+
 /// Parse an SSH public key string into (type, base64_data).
 ///
 /// Handles formats like:
@@ -124,10 +394,6 @@ pub fn parse_ssh_key(key: &str) -> Result<(String, String)> {
 /// Compute the SHA256 fingerprint of an SSH public key.
 ///
 /// Returns the fingerprint in "SHA256:..." format used by `ssh-keygen -l`.
-///
-/// Note: the gateway currently uses `compute_fingerprint_from_pubkey` for the auth flow, but this
-/// helper is useful when working with OpenSSH-formatted public key strings.
-#[allow(dead_code)]
 pub fn compute_fingerprint(public_key: &str) -> Result<String> {
     let (_, key_data) = parse_ssh_key(public_key)?;
     let decoded = base64::engine::general_purpose::STANDARD
@@ -201,6 +467,17 @@ pub fn parse_ssh_username(username: &str) -> (String, Option<String>) {
     }
 }
 
+/// Split an image variant off the project part of an SSH username (`[images] named`).
+///
+/// - "project" -> (project, None)
+/// - "project.python" -> (project, Some(python))
+pub fn parse_image_variant(project: &str) -> (String, Option<String>) {
+    match project.split_once('.') {
+        Some((project, variant)) => (project.to_string(), Some(variant.to_string())),
+        None => (project.to_string(), None),
+    }
+}
+
 /// Validate a project name (no path traversal, safe for container names).
 pub fn validate_project_name(name: &str) -> Result<()> {
     if name.is_empty() {
@@ -280,6 +557,20 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_image_variant() {
+        assert_eq!(parse_image_variant("web"), ("web".to_string(), None));
+        assert_eq!(
+            parse_image_variant("web.python"),
+            ("web".to_string(), Some("python".to_string()))
+        );
+        // The rest is the variant; unknown names are refused when the sandbox is needed.
+        assert_eq!(
+            parse_image_variant("web.py.3"),
+            ("web".to_string(), Some("py.3".to_string()))
+        );
+    }
+
     #[test]
     fn test_validate_project_name() {
         assert!(validate_project_name("myproject").is_ok());
@@ -0,0 +1,230 @@
+//! Display metadata of GitHub users (`[github_metadata]`): the profile name and avatar URL
+//! shown in `agentman admin users`, login greetings and `agentman notify` webhooks.
+//!
+//! Profiles come from the unauthenticated GitHub API through an in-memory cache. A profile is
+//! refetched after `cache_secs`, and the old one is used while a refetch fails. Once GitHub
+//! reports its rate limit exhausted, nothing is requested until it resets; users without a
+//! cached profile are shown by login meanwhile. Nothing here fails: no metadata just means
+//! the login is all there is to show.
+
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tracing::{debug, warn};
+
+use crate::config::GitHubMetadataConfig;
+use crate::github::{self, ProfileLookup};
+use crate::metrics;
+
+/// Wait before retrying a user whose lookup failed.
+const RETRY_FAILED: Duration = Duration::from_secs(300);
+
+/// Wait after a rate-limited response that didn't say when the limit resets.
+const RATE_LIMIT_BACKOFF: Duration = Duration::from_secs(900);
+
+static CACHE: LazyLock<Mutex<Cache>> = LazyLock::new(Default::default);
+
+/// What is shown of a GitHub user besides their login.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct UserMetadata {
+    pub name: Option<String>,
+    pub avatar_url: Option<String>,
+}
+
+#[derive(Default)]
+struct Cache {
+    /// By lowercase login.
+    entries: HashMap<String, Entry>,
+    /// No requests before this (GitHub's rate limit).
+    backoff_until: Option<Instant>,
+}
+
+struct Entry {
+    /// The last profile fetched, if any.
+    metadata: Option<UserMetadata>,
+    checked: Instant,
+    /// Whether the last lookup succeeded.
+    ok: bool,
+}
+
+enum Fetched {
+    Found(UserMetadata),
+    Failed,
+    RateLimited(Option<Duration>),
+}
+
+/// `github_user`'s metadata, fetched if it isn't cached or is due for a refresh.
+pub async fn lookup(config: &GitHubMetadataConfig, github_user: &str) -> Option<UserMetadata> {
+    if !config.enabled {
+        return None;
+    }
+    let key = github_user.to_ascii_lowercase();
+    let (cached, due) = CACHE.lock().unwrap().get(config, &key, Instant::now());
+    if !due {
+        return cached;
+    }
+    let fetched = fetch(github_user).await;
+    let mut cache = CACHE.lock().unwrap();
+    cache.store(&key, fetched, Instant::now());
+    cache.get(config, &key, Instant::now()).0
+}
+
+/// `github_user`'s cached metadata, without a request.
+pub fn cached(config: &GitHubMetadataConfig, github_user: &str) -> Option<UserMetadata> {
+    if !config.enabled {
+        return None;
+    }
+    let key = github_user.to_ascii_lowercase();
+    CACHE.lock().unwrap().get(config, &key, Instant::now()).0
+}
+
+/// Refresh `github_user`'s metadata in the background if it's due, so it is cached by the
+/// time a session shows it.
+pub fn prefetch(config: &GitHubMetadataConfig, github_user: &str) {
+    if !config.enabled {
+        return;
+    }
+    let key = github_user.to_ascii_lowercase();
+    if !CACHE.lock().unwrap().get(config, &key, Instant::now()).1 {
+        return;
+    }
+    let config = config.clone();
+    let github_user = github_user.to_string();
+    tokio::spawn(async move {
+        lookup(&config, &github_user).await;
+    });
+}
+
+/// `Name (@login)`, or the login alone without a profile name.
+pub fn display_name(metadata: Option<&UserMetadata>, github_user: &str) -> String {
+    match metadata.and_then(|m| m.name.as_deref()) {
+        Some(name) => format!("{name} (@{github_user})"),
+        None => github_user.to_string(),
+    }
+}
+
+async fn fetch(github_user: &str) -> Fetched {
+    let result = github::api().lookup_profile(github_user).await;
+    let outcome = match result {
+        Ok(ProfileLookup::Found(_)) => "found",
+        Ok(ProfileLookup::RateLimited(_)) => "rate_limited",
+        Err(_) => "failed",
+    };
+    metrics::inc(
+        "agentman_github_metadata_fetches_total",
+        "GitHub profile lookups for display metadata, by outcome.",
+        &[("outcome", outcome)],
+    );
+    match result {
+        Ok(ProfileLookup::Found(profile)) => Fetched::Found(UserMetadata {
+            name: profile
+                .name
+                .map(|n| n.chars().filter(|c| !c.is_control()).collect::<String>())
+                .map(|n| n.trim().to_string())
+                .filter(|n| !n.is_empty()),
+            avatar_url: profile.avatar_url.filter(|u| u.starts_with("https://")),
+        }),
+        Ok(ProfileLookup::RateLimited(retry_after)) => {
+            warn!("GitHub rate limit reached; showing logins without profile names for now");
+            Fetched::RateLimited(retry_after)
+        }
+        Err(e) => {
+            debug!("No display metadata for {}: {:#}", github_user, e);
+            Fetched::Failed
+        }
+    }
+}
+
+impl Cache {
+    /// The cached metadata for `key`, and whether it is due for a (re)fetch.
+    fn get(
+        &self,
+        config: &GitHubMetadataConfig,
+        key: &str,
+        now: Instant,
+    ) -> (Option<UserMetadata>, bool) {
+        let backing_off = self.backoff_until.is_some_and(|until| now < until);
+        match self.entries.get(key) {
+            Some(entry) => {
+                let ttl = if entry.ok {
+                    Duration::from_secs(config.cache_secs)
+                } else {
+                    RETRY_FAILED
+                };
+                let due = !backing_off && now.duration_since(entry.checked) >= ttl;
+                (entry.metadata.clone(), due)
+            }
+            None => (None, !backing_off),
+        }
+    }
+
+    fn store(&mut self, key: &str, fetched: Fetched, now: Instant) {
+        let (metadata, ok) = match fetched {
+            Fetched::Found(metadata) => (Some(metadata), true),
+            Fetched::Failed => (None, false),
+            Fetched::RateLimited(retry_after) => {
+                self.backoff_until = Some(now + retry_after.unwrap_or(RATE_LIMIT_BACKOFF));
+                return;
+            }
+        };
+        let previous = self.entries.remove(key).and_then(|e| e.metadata);
+        self.entries.insert(
+            key.to_string(),
+            Entry {
+                metadata: metadata.or(previous),
+                checked: now,
+                ok,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn octocat() -> UserMetadata {
+        UserMetadata {
+            name: Some("The Octocat".to_string()),
+            avatar_url: Some("https://avatars.githubusercontent.com/u/583231".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_cache_refresh_and_degradation() {
+        let config = GitHubMetadataConfig::default();
+        let mut cache = Cache::default();
+        let now = Instant::now();
+        assert_eq!(cache.get(&config, "octocat", now), (None, true));
+
+        cache.store("octocat", Fetched::Found(octocat()), now);
+        assert_eq!(cache.get(&config, "octocat", now), (Some(octocat()), false));
+        let expired = now + Duration::from_secs(config.cache_secs);
+        assert_eq!(cache.get(&config, "octocat", expired), (Some(octocat()), true));
+
+        // A failed refresh keeps the old profile, and is retried sooner.
+        cache.store("octocat", Fetched::Failed, expired);
+        assert_eq!(cache.get(&config, "octocat", expired), (Some(octocat()), false));
+        let retry = expired + RETRY_FAILED;
+        assert_eq!(cache.get(&config, "octocat", retry), (Some(octocat()), true));
+
+        // Nothing is due while rate-limited.
+        cache.store("octocat", Fetched::RateLimited(None), retry);
+        assert_eq!(cache.get(&config, "octocat", retry), (Some(octocat()), false));
+        assert_eq!(cache.get(&config, "hubot", retry), (None, false));
+        let reset = retry + RATE_LIMIT_BACKOFF;
+        assert_eq!(cache.get(&config, "hubot", reset), (None, true));
+    }
+
+    #[test]
+    fn test_display_name() {
+        assert_eq!(
+            display_name(Some(&octocat()), "octocat"),
+            "The Octocat (@octocat)"
+        );
+        assert_eq!(display_name(Some(&UserMetadata::default()), "octocat"), "octocat");
+        assert_eq!(display_name(None, "octocat"), "octocat");
+    }
+}
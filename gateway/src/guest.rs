@@ -0,0 +1,203 @@
+//! Time-limited guest access to a workspace (`agentman guest`).
+//!
+//! `agentman guest invite` records an invite for the current project and prints an SSH login,
+//! `guest-<id>-<secret>`; the state only keeps a hash of the secret. The first key that logs in
+//! with it is bound to the invite, so the login is useless to anyone else once the guest has
+//! used it. Until the invite expires or is revoked, the guest acts as the inviter in that
+//! project, and everything they do is audited under the inviter with a `guest <id>` note.
+//! Read-only guests only get a read-only view of the shared tmux session; other guests get
+//! shells, commands and forwards, but no control commands and no agent forwarding (the
+//! forwarded agent's socket is shared with the inviter's sessions).
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use russh::Disconnect;
+use russh::keys::ssh_key::rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use crate::secret::{constant_time_eq, hex, is_hex};
+use crate::state::{GuestInvite, StateManager};
+
+/// SSH user names starting with this are guest logins.
+pub const LOGIN_PREFIX: &str = "guest-";
+
+const ID_BYTES: usize = 4;
+const SECRET_BYTES: usize = 16;
+
+/// How often a guest's connection checks whether their invite was revoked.
+const REVOKE_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+/// A guest logged in on a connection.
+pub struct Guest {
+    pub id: String,
+    pub read_only: bool,
+    /// Disconnects the guest at expiry or revocation; started with the first channel.
+    pub watchdog: Option<tokio::task::JoinHandle<()>>,
+}
+
+impl Drop for Guest {
+    fn drop(&mut self) {
+        if let Some(watchdog) = self.watchdog.take() {
+            watchdog.abort();
+        }
+    }
+}
+
+/// A new invite ID and secret.
+pub fn new_credentials() -> Result<(String, String)> {
+    let mut bytes = [0u8; ID_BYTES + SECRET_BYTES];
+    OsRng
+        .try_fill_bytes(&mut bytes)
+        .context("Failed to generate a guest secret")?;
+    let (id, secret) = bytes.split_at(ID_BYTES);
+    Ok((hex(id), hex(secret)))
+}
+
+/// The SSH user name of an invite.
+pub fn login(id: &str, secret: &str) -> String {
+    format!("{LOGIN_PREFIX}{id}-{secret}")
+}
+
+/// Invite ID and secret of a guest login.
+pub fn parse_login(user: &str) -> Option<(&str, &str)> {
+    let (id, secret) = user.strip_prefix(LOGIN_PREFIX)?.split_once('-')?;
+    (is_hex(id, ID_BYTES) && is_hex(secret, SECRET_BYTES)).then_some((id, secret))
+}
+
+pub fn secret_sha256(secret: &str) -> String {
+    hex(&Sha256::digest(secret.as_bytes()))
+}
+
+/// Whether `secret` is the one the invite was created with.
+pub fn verify(invite: &GuestInvite, secret: &str) -> bool {
+    let expected = invite.secret_sha256.as_bytes();
+    let actual = secret_sha256(secret);
+    constant_time_eq(expected, actual.as_bytes())
+}
+
+/// `--ttl` values: seconds, or a number with an `s`, `m`, `h` or `d` suffix.
+pub fn parse_ttl(value: &str) -> Option<u64> {
+    let (number, unit) = match value.char_indices().last()? {
+        (i, c) if c.is_ascii_alphabetic() => (&value[..i], c),
+        _ => (value, 's'),
+    };
+    let unit = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 3600,
+        'd' => 86400,
+        _ => return None,
+    };
+    let secs = number.parse::<u64>().ok()?.checked_mul(unit)?;
+    (secs > 0).then_some(secs)
+}
+
+/// A lifetime such as `2h`, `1h30m` or `45s`.
+pub fn format_ttl(secs: u64) -> String {
+    let (days, hours, mins) = (secs / 86400, secs % 86400 / 3600, secs % 3600 / 60);
+    let mut out = String::new();
+    for (n, unit) in [(days, 'd'), (hours, 'h'), (mins, 'm')] {
+        if n > 0 {
+            out.push_str(&format!("{n}{unit}"));
+        }
+    }
+    if out.is_empty() {
+        out = format!("{secs}s");
+    }
+    out
+}
+
+/// Time left until `expires_at`, for listings.
+pub fn remaining(expires_at: DateTime<Utc>) -> String {
+    format_ttl((expires_at - Utc::now()).num_seconds().max(0) as u64)
+}
+
+/// Disconnect a guest's connection once invite `id` expires or is revoked.
+pub fn watch(
+    handle: russh::server::Handle,
+    state: Arc<StateManager>,
+    id: String,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        while let Some(invite) = state.guest(&id).await {
+            let left = (invite.expires_at - Utc::now())
+                .to_std()
+                .unwrap_or_default();
+            tokio::time::sleep(left.min(REVOKE_CHECK_INTERVAL)).await;
+        }
+        info!(
+            "Guest invite {} expired or was revoked; disconnecting the guest",
+            id
+        );
+        let _ = handle
+            .disconnect(
+                Disconnect::ByApplication,
+                "guest access ended".to_string(),
+                String::new(),
+            )
+            .await;
+    })
+}
+
+/// Shell command for a read-only guest: watch the shared tmux session without resizing it.
+pub fn read_only_shell(tmux_session: &str) -> Vec<String> {
+    let script = format!(
+        "if ! command -v tmux >/dev/null 2>&1; then \
+         echo 'agentman: tmux is not installed' >&2; exit 1; fi; \
+         tmux attach-session -t '{tmux_session}' -f read-only,ignore-size 2>/dev/null || \
+         {{ echo 'agentman: nobody has started the shared terminal session yet' >&2; exit 1; }}"
+    );
+    vec!["/bin/bash".to_string(), "-lc".to_string(), script]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_login_round_trip() {
+        let (id, secret) = new_credentials().unwrap();
+        assert_eq!(id.len(), 8);
+        assert_eq!(secret.len(), 32);
+        let user = login(&id, &secret);
+        assert_eq!(parse_login(&user), Some((id.as_str(), secret.as_str())));
+
+        assert_eq!(parse_login("web+octocat"), None);
+        assert_eq!(parse_login("guest-web"), None);
+        assert_eq!(parse_login(&format!("guest-{id}-{}", &secret[1..])), None);
+        assert_eq!(parse_login(&format!("guest-ABCDEF01-{secret}")), None);
+
+        let invite = GuestInvite {
+            github_user: "octocat".to_string(),
+            project: "web".to_string(),
+            read_only: true,
+            secret_sha256: secret_sha256(&secret),
+            created_at: Utc::now(),
+            expires_at: Utc::now(),
+            key_fingerprint: None,
+        };
+        assert!(verify(&invite, &secret));
+        assert!(!verify(&invite, &id));
+    }
+
+    #[test]
+    fn test_ttl() {
+        assert_eq!(parse_ttl("90"), Some(90));
+        assert_eq!(parse_ttl("30m"), Some(1800));
+        assert_eq!(parse_ttl("2h"), Some(7200));
+        assert_eq!(parse_ttl("1d"), Some(86400));
+        assert_eq!(parse_ttl("0h"), None);
+        assert_eq!(parse_ttl("2w"), None);
+        assert_eq!(parse_ttl("h"), None);
+        assert_eq!(parse_ttl(""), None);
+
+        assert_eq!(format_ttl(7200), "2h");
+        assert_eq!(format_ttl(5400), "1h30m");
+        assert_eq!(format_ttl(90000), "1d1h");
+        assert_eq!(format_ttl(45), "45s");
+    }
+}
@@ -0,0 +1,253 @@
+//! Active/passive failover between two gateways (see [`HaConfig`]).
+//!
+//! Both instances point at the same Docker host and state file, and coordinate through a lease
+//! file on shared storage. Only the lease holder is active. A passive instance waits in
+//! [`acquire`] before loading state or listening, and takes over once the active instance stops
+//! renewing its lease. Every takeover bumps the lease epoch, so an instance that loses its lease
+//! can tell. It then exits (its supervisor restarts it as passive), and [`ensure_active`] keeps it
+//! from creating containers in the meantime.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::config::HaConfig;
+use crate::metrics;
+
+/// Whether failover is enabled (otherwise [`ensure_active`] always passes).
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Expiry (unix ms) of the lease as of our last successful write.
+static VALID_UNTIL_MS: AtomicI64 = AtomicI64::new(0);
+
+/// Wait after writing the lease before trusting it, so a competing write from the other
+/// instance (which read the same expired lease) lands first and is seen.
+const SETTLE: Duration = Duration::from_secs(2);
+
+/// Contents of the lease file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Lease {
+    holder: String,
+    /// Incremented on every change of holder.
+    epoch: u64,
+    expires_at: DateTime<Utc>,
+}
+
+/// This instance's ID in the lease (`node_id`, or the hostname).
+fn node_id(config: &HaConfig) -> String {
+    config.node_id.clone().unwrap_or_else(|| {
+        std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|s| s.trim().to_string())
+            .ok()
+            .filter(|s| !s.is_empty())
+            .unwrap_or_else(|| format!("pid-{}", std::process::id()))
+    })
+}
+
+async fn read_lease(path: &Path) -> Result<Option<Lease>> {
+    match tokio::fs::read(path).await {
+        Ok(bytes) => Ok(serde_json::from_slice(&bytes).ok()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read lease {}", path.display())),
+    }
+}
+
+/// Replace the lease file atomically (write a per-node temp file, then rename).
+async fn write_lease(path: &Path, node: &str, lease: &Lease) -> Result<()> {
+    let tmp = PathBuf::from(format!("{}.{node}.tmp", path.display()));
+    let body = serde_json::to_vec(lease).context("Failed to serialize lease")?;
+    tokio::fs::write(&tmp, body)
+        .await
+        .with_context(|| format!("Failed to write {}", tmp.display()))?;
+    tokio::fs::rename(&tmp, path)
+        .await
+        .with_context(|| format!("Failed to replace lease {}", path.display()))
+}
+
+/// Claim the lease for `node` if it is free, expired or already ours. Returns the lease written,
+/// or the other holder's live lease.
+async fn try_claim(
+    path: &Path,
+    node: &str,
+    lease_duration: chrono::Duration,
+    now: DateTime<Utc>,
+) -> Result<std::result::Result<Lease, Lease>> {
+    let current = read_lease(path).await?;
+    let epoch = match current {
+        Some(ref lease) if lease.holder == node => lease.epoch,
+        Some(ref lease) if lease.expires_at > now => return Ok(Err(lease.clone())),
+        Some(ref lease) => lease.epoch + 1,
+        None => 1,
+    };
+    let lease = Lease {
+        holder: node.to_string(),
+        epoch,
+        expires_at: now + lease_duration,
+    };
+    write_lease(path, node, &lease).await?;
+    Ok(Ok(lease))
+}
+
+fn set_valid_until(lease: &Lease) {
+    VALID_UNTIL_MS.store(lease.expires_at.timestamp_millis(), Ordering::SeqCst);
+}
+
+/// Block until this instance holds the lease, then keep renewing it in the background.
+///
+/// Returns immediately when failover is disabled.
+pub async fn acquire(config: &HaConfig) -> Result<()> {
+    if !config.enabled {
+        return Ok(());
+    }
+    ENABLED.store(true, Ordering::SeqCst);
+    metrics::set(
+        "agentman_ha_active",
+        "1 while this gateway holds the failover lease",
+        &[],
+        0.0,
+    );
+
+    let node = node_id(config);
+    let lease_duration = chrono::Duration::seconds(config.lease_secs as i64);
+    let retry = Duration::from_secs(config.renew_secs);
+    let mut waiting_on: Option<String> = None;
+
+    let lease = loop {
+        match try_claim(&config.lock_file, &node, lease_duration, Utc::now()).await {
+            Ok(Ok(claimed)) => {
+                tokio::time::sleep(SETTLE).await;
+                match read_lease(&config.lock_file).await {
+                    Ok(Some(lease)) if lease == claimed => break lease,
+                    Ok(Some(lease)) => info!("Lost the race for the lease to {}", lease.holder),
+                    Ok(None) => warn!("Lease {} disappeared", config.lock_file.display()),
+                    Err(e) => warn!("{:#}", e),
+                }
+            }
+            Ok(Err(other)) => {
+                if waiting_on.as_deref() != Some(other.holder.as_str()) {
+                    info!(
+                        "Passive: {} is active (lease epoch {}); waiting to take over",
+                        other.holder, other.epoch
+                    );
+                    waiting_on = Some(other.holder);
+                }
+            }
+            Err(e) => warn!("Failover lease check failed: {:#}", e),
+        }
+        tokio::time::sleep(retry).await;
+    };
+
+    set_valid_until(&lease);
+    metrics::set(
+        "agentman_ha_active",
+        "1 while this gateway holds the failover lease",
+        &[],
+        1.0,
+    );
+    info!(
+        "Active: holding lease {} as {} (epoch {})",
+        config.lock_file.display(),
+        node,
+        lease.epoch
+    );
+
+    let config = config.clone();
+    tokio::spawn(async move { renew(&config, &node, lease.epoch, lease_duration).await });
+    Ok(())
+}
+
+/// Renew the lease until it is lost, then exit the process.
+async fn renew(config: &HaConfig, node: &str, epoch: u64, lease_duration: chrono::Duration) {
+    let path = &config.lock_file;
+    loop {
+        tokio::time::sleep(Duration::from_secs(config.renew_secs)).await;
+
+        let outcome = match read_lease(path).await {
+            Ok(Some(lease)) if lease.holder != node || lease.epoch != epoch => step_down(&format!(
+                "{} took over the lease (epoch {})",
+                lease.holder, lease.epoch
+            )),
+            Ok(_) => {
+                let lease = Lease {
+                    holder: node.to_string(),
+                    epoch,
+                    expires_at: Utc::now() + lease_duration,
+                };
+                write_lease(path, node, &lease)
+                    .await
+                    .map(|()| set_valid_until(&lease))
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = outcome {
+            if ensure_active().is_err() {
+                step_down(&format!(
+                    "could not renew the lease before it expired: {e:#}"
+                ));
+            }
+            warn!("Failover lease renewal failed (will retry): {:#}", e);
+        }
+    }
+}
+
+/// Stop acting as the active instance: refuse new containers and exit so the supervisor
+/// restarts this gateway as passive.
+fn step_down(reason: &str) -> ! {
+    VALID_UNTIL_MS.store(0, Ordering::SeqCst);
+    error!("Stepping down as active gateway: {}", reason);
+    std::process::exit(75);
+}
+
+/// Fail unless this instance holds an unexpired lease (always passes without failover).
+///
+/// Checked before creating containers, so an instance that lost its lease never creates them
+/// next to the new active one.
+pub fn ensure_active() -> Result<()> {
+    if !ENABLED.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+    if Utc::now().timestamp_millis() >= VALID_UNTIL_MS.load(Ordering::SeqCst) {
+        bail!("this gateway is not the active instance (failover lease not held)");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_try_claim() {
+        let dir = std::env::temp_dir().join(format!("agentman-ha-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("lease.json");
+        let lease = chrono::Duration::seconds(15);
+        let t0 = Utc::now();
+
+        // Free lease: claimed with epoch 1.
+        let a = try_claim(&path, "a", lease, t0).await.unwrap().unwrap();
+        assert_eq!((a.holder.as_str(), a.epoch), ("a", 1));
+
+        // Live lease held by a: b stays passive, a renews in the same epoch.
+        let held = try_claim(&path, "b", lease, t0).await.unwrap().unwrap_err();
+        assert_eq!(held.holder, "a");
+        let renewed = try_claim(&path, "a", lease, t0 + chrono::Duration::seconds(5))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(renewed.epoch, 1);
+
+        // Expired: b takes over with a new epoch.
+        let later = t0 + chrono::Duration::seconds(60);
+        let b = try_claim(&path, "b", lease, later).await.unwrap().unwrap();
+        assert_eq!((b.holder.as_str(), b.epoch), ("b", 2));
+        assert_eq!(read_lease(&path).await.unwrap(), Some(b));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
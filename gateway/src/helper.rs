@@ -0,0 +1,55 @@
+//! Deployment of `agentman-helper` (`src/bin/agentman-helper.rs`) into containers.
+//!
+//! The helper is a statically linked binary with primitives the gateway would otherwise need the
+//! image to provide (a TCP bridge for forwards, a PTY wrapper, file watching, process
+//! supervision). It is copied into every container through the Docker archive API right after
+//! the container is created, so it works with any image, including ones without a shell.
+
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use bollard::Docker;
+use bollard::query_parameters::UploadToContainerOptionsBuilder;
+
+use crate::config::HelperConfig;
+use crate::tar;
+
+/// Where the helper lands in containers (on the default PATH).
+pub const CONTAINER_PATH: &str = "/usr/local/bin/agentman-helper";
+
+/// The helper binary on the gateway host, if there is one to install.
+fn host_path(config: &HelperConfig) -> Option<PathBuf> {
+    if !config.enabled {
+        return None;
+    }
+    match config.path {
+        Some(ref path) => Some(path.clone()),
+        None => std::env::current_exe()
+            .ok()?
+            .parent()
+            .map(|dir| dir.join("agentman-helper"))
+            .filter(|path| path.is_file()),
+    }
+}
+
+/// Copy the helper into a (created, not necessarily started) container.
+///
+/// Does nothing when the helper is disabled or not installed next to the gateway.
+pub async fn install(docker: &Docker, config: &HelperConfig, container_id: &str) -> Result<()> {
+    let Some(path) = host_path(config) else {
+        return Ok(());
+    };
+    let binary = tokio::fs::read(&path)
+        .await
+        .with_context(|| format!("Failed to read helper {}", path.display()))?;
+    let archive = tar::single_file(CONTAINER_PATH.trim_start_matches('/'), 0o755, &binary);
+    let options = UploadToContainerOptionsBuilder::new().path("/").build();
+    docker
+        .upload_to_container(
+            container_id,
+            Some(options),
+            bollard::body_full(archive.into()),
+        )
+        .await
+        .context("Failed to copy agentman-helper into the container")
+}
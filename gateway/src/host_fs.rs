@@ -0,0 +1,307 @@
+//! Touching sandbox-writable paths on the host without following symlinks.
+//!
+//! The gateway runs as root, and a running sandbox can replace any part of a path in its
+//! workspace with a symlink at any moment, so checking a path and then using it is a race.
+//! A [`Handle`] is an `O_PATH` descriptor opened one component at a time with `O_NOFOLLOW`:
+//! a symlink yields a handle to the link itself, which callers refuse after looking at its
+//! metadata. Whatever is done next (reading, creating children, chmod) goes through the
+//! descriptor, so it applies to the object that was checked, wherever the path points by then.
+
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::{File, Metadata, OpenOptions};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::raw::c_int;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use tracing::warn;
+
+mod sys {
+    use std::os::raw::{c_char, c_int, c_uint};
+
+    pub const O_WRONLY: c_int = 0o1;
+    pub const O_CREAT: c_int = 0o100;
+    pub const O_EXCL: c_int = 0o200;
+    pub const O_CLOEXEC: c_int = 0o2000000;
+    pub const O_PATH: c_int = 0o10000000;
+    #[cfg(any(target_arch = "aarch64", target_arch = "arm"))]
+    pub const O_DIRECTORY: c_int = 0o40000;
+    #[cfg(not(any(target_arch = "aarch64", target_arch = "arm")))]
+    pub const O_DIRECTORY: c_int = 0o200000;
+    #[cfg(any(target_arch = "aarch64", target_arch = "arm"))]
+    pub const O_NOFOLLOW: c_int = 0o100000;
+    #[cfg(not(any(target_arch = "aarch64", target_arch = "arm")))]
+    pub const O_NOFOLLOW: c_int = 0o400000;
+    pub const AT_SYMLINK_NOFOLLOW: c_int = 0x100;
+    pub const AT_REMOVEDIR: c_int = 0x200;
+
+    unsafe extern "C" {
+        pub fn openat(dirfd: c_int, pathname: *const c_char, flags: c_int, ...) -> c_int;
+        pub fn mkdirat(dirfd: c_int, pathname: *const c_char, mode: c_uint) -> c_int;
+        pub fn unlinkat(dirfd: c_int, pathname: *const c_char, flags: c_int) -> c_int;
+        pub fn fchownat(
+            dirfd: c_int,
+            pathname: *const c_char,
+            owner: c_uint,
+            group: c_uint,
+            flags: c_int,
+        ) -> c_int;
+    }
+}
+
+/// An `O_PATH` descriptor of a file, directory or symlink.
+#[derive(Debug)]
+pub struct Handle(File);
+
+impl Handle {
+    /// The directory at `path`, which the gateway controls (symlinks along it are followed).
+    pub fn open_dir(path: &Path) -> io::Result<Self> {
+        OpenOptions::new()
+            .read(true)
+            .custom_flags(sys::O_PATH | sys::O_DIRECTORY)
+            .open(path)
+            .map(Self)
+    }
+
+    /// The entry `name` of this directory; a symlink is not followed.
+    pub fn child(&self, name: &OsStr) -> io::Result<Self> {
+        self.openat(name, sys::O_PATH | sys::O_NOFOLLOW, 0).map(Self)
+    }
+
+    /// Create the directory `name` in this directory.
+    pub fn create_dir(&self, name: &OsStr, mode: u32) -> io::Result<()> {
+        let name = entry_name(name)?;
+        // SAFETY: `name` is a NUL-terminated string that outlives the call.
+        if unsafe { sys::mkdirat(self.0.as_raw_fd(), name.as_ptr(), mode) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// The directory `name` in this directory, created with `mode` if missing. Anything else by
+    /// that name (a symlink included) is refused.
+    pub fn subdir(&self, name: &OsStr, mode: u32) -> io::Result<Self> {
+        match self.create_dir(name, mode) {
+            Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+            _ => {}
+        }
+        let dir = self.child(name)?;
+        if !dir.metadata()?.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotADirectory,
+                format!("{} is not a directory", name.display()),
+            ));
+        }
+        Ok(dir)
+    }
+
+    /// Create the file `name` in this directory for writing, removing whatever file or symlink
+    /// had that name first (a symlink is removed, not followed).
+    pub fn replace_file(&self, name: &OsStr, mode: u32) -> io::Result<File> {
+        match self.unlinkat(name, 0) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        self.create_file(name, mode)
+    }
+
+    /// Remove the entry `name` of this directory, with everything below it if it is a
+    /// directory. Symlinks are removed, not followed.
+    pub fn remove_all(&self, name: &OsStr) -> io::Result<()> {
+        let entry = self.child(name)?;
+        let flags = if entry.metadata()?.is_dir() {
+            for child in entry.entries()? {
+                entry.remove_all(&child)?;
+            }
+            sys::AT_REMOVEDIR
+        } else {
+            0
+        };
+        self.unlinkat(name, flags)
+    }
+
+    fn unlinkat(&self, name: &OsStr, flags: c_int) -> io::Result<()> {
+        let name = entry_name(name)?;
+        // SAFETY: `name` is a NUL-terminated string that outlives the call.
+        if unsafe { sys::unlinkat(self.0.as_raw_fd(), name.as_ptr(), flags) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Create the file `name` in this directory for writing; fails if anything (a symlink
+    /// included) already has that name. `mode` is subject to the umask.
+    pub fn create_file(&self, name: &OsStr, mode: u32) -> io::Result<File> {
+        let flags = sys::O_WRONLY | sys::O_CREAT | sys::O_EXCL | sys::O_NOFOLLOW;
+        self.openat(name, flags, mode)
+    }
+
+    fn openat(&self, name: &OsStr, flags: c_int, mode: u32) -> io::Result<File> {
+        let name = entry_name(name)?;
+        // SAFETY: `name` is a NUL-terminated string that outlives the call; the returned
+        // descriptor is owned by the `File`.
+        let fd = unsafe {
+            sys::openat(self.0.as_raw_fd(), name.as_ptr(), flags | sys::O_CLOEXEC, mode)
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` was just opened and nothing else owns it.
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    /// Metadata of the object itself (`lstat`, through the descriptor).
+    pub fn metadata(&self) -> io::Result<Metadata> {
+        self.0.metadata()
+    }
+
+    /// A path that refers to this object for as long as the handle is open, for calls that
+    /// only take paths. Opening it reopens the object rather than resolving the original path.
+    pub fn proc_path(&self) -> PathBuf {
+        PathBuf::from(format!("/proc/self/fd/{}", self.0.as_raw_fd()))
+    }
+
+    /// Open the object for reading. Check [`Self::metadata`] first: opening a FIFO blocks and
+    /// a device is the host's.
+    pub fn reopen(&self) -> io::Result<File> {
+        File::open(self.proc_path())
+    }
+
+    /// Names in this directory.
+    pub fn entries(&self) -> io::Result<Vec<OsString>> {
+        std::fs::read_dir(self.proc_path())?
+            .map(|entry| entry.map(|e| e.file_name()))
+            .collect()
+    }
+
+    /// Change the owner of the object. Check [`Self::metadata`] first: on a symlink this would
+    /// apply to its target.
+    pub fn chown(&self, uid: u32, gid: u32) -> io::Result<()> {
+        std::os::unix::fs::chown(self.proc_path(), Some(uid), Some(gid))
+    }
+
+    /// Change the owner of the entry `name` of this directory itself, a symlink included.
+    pub fn chown_child(&self, name: &OsStr, uid: u32, gid: u32) -> io::Result<()> {
+        let name = entry_name(name)?;
+        // SAFETY: `name` is a NUL-terminated string that outlives the call.
+        let flags = sys::AT_SYMLINK_NOFOLLOW;
+        if unsafe { sys::fchownat(self.0.as_raw_fd(), name.as_ptr(), uid, gid, flags) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Change the mode of the object; like [`Self::chown`], not for symlinks.
+    pub fn chmod(&self, mode: u32) -> io::Result<()> {
+        std::fs::set_permissions(self.proc_path(), std::fs::Permissions::from_mode(mode))
+    }
+}
+
+/// The entry `rel` below the directory `root`; no symlink along `rel` is followed, and the
+/// last component may be a symlink (check [`Handle::metadata`]).
+pub fn walk(root: &Path, rel: &Path) -> io::Result<Handle> {
+    let mut handle = Handle::open_dir(root)?;
+    for part in rel.iter() {
+        handle = handle.child(part)?;
+    }
+    Ok(handle)
+}
+
+/// Let the owner of the directory `dir` (the container user, for a workspace) use the socket
+/// `name` the gateway just bound in it: owned by them with mode 0600, or mode 0666 where it
+/// can't be chowned (the gateway isn't root, or `dir` is root's). Anything but a socket is
+/// refused, so a symlink the sandbox swapped in after the bind isn't followed.
+pub fn share_socket(dir: &Path, name: &OsStr) -> io::Result<()> {
+    let dir = Handle::open_dir(dir)?;
+    let socket = dir.child(name)?;
+    if !socket.metadata()?.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not a socket", name.display()),
+        ));
+    }
+    let owner = dir.metadata()?;
+    let chowned = owner.uid() != 0
+        && socket
+            .chown(owner.uid(), owner.gid())
+            .inspect_err(|e| warn!("Failed to chown {}: {}", name.display(), e))
+            .is_ok();
+    socket.chmod(if chowned { 0o600 } else { 0o666 })
+}
+
+/// `name` as a C string, if it is a single path component.
+fn entry_name(name: &OsStr) -> io::Result<CString> {
+    let bytes = name.as_bytes();
+    if bytes.is_empty() || bytes == b"." || bytes == b".." || bytes.contains(&b'/') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a file name", name.display()),
+        ));
+    }
+    CString::new(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_handle_does_not_follow() {
+        let dir = std::env::temp_dir().join(format!("agentman-host-fs-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("a")).unwrap();
+        std::fs::write(dir.join("a/file"), "inside").unwrap();
+        std::fs::write(dir.join("outside"), "outside").unwrap();
+        std::os::unix::fs::symlink("../outside", dir.join("a/link")).unwrap();
+
+        let root = Handle::open_dir(&dir).unwrap();
+        let a = root.child(OsStr::new("a")).unwrap();
+        let link = a.child(OsStr::new("link")).unwrap();
+        assert!(link.metadata().unwrap().file_type().is_symlink());
+        for name in ["..", ".", "a/file", ""] {
+            assert!(root.child(OsStr::new(name)).is_err(), "{name}");
+        }
+
+        // Replacing a directory with a symlink after it was opened doesn't redirect the handle.
+        let file = a.child(OsStr::new("file")).unwrap();
+        std::fs::rename(dir.join("a"), dir.join("moved")).unwrap();
+        std::os::unix::fs::symlink(".", dir.join("a")).unwrap();
+        let mut content = String::new();
+        file.reopen().unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "inside");
+        let again = a.child(OsStr::new("file")).unwrap();
+        let moved = std::fs::metadata(dir.join("moved/file")).unwrap();
+        assert_eq!(again.metadata().unwrap().ino(), moved.ino());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_share_socket() {
+        let dir = std::env::temp_dir().join(format!("agentman-share-sock-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let target = dir.join("host-file");
+        std::fs::write(&target, "").unwrap();
+        std::fs::set_permissions(&target, std::fs::Permissions::from_mode(0o600)).unwrap();
+
+        let _listener = std::os::unix::net::UnixListener::bind(dir.join("app.sock")).unwrap();
+        share_socket(&dir, OsStr::new("app.sock")).unwrap();
+        let mode = std::fs::metadata(dir.join("app.sock")).unwrap().permissions().mode();
+        // Chowned to the directory's owner, unless that is root.
+        let expected = if std::fs::metadata(&dir).unwrap().uid() == 0 { 0o666 } else { 0o600 };
+        assert_eq!(mode & 0o777, expected);
+
+        // A symlink swapped in for the socket is refused, and its target left alone.
+        std::fs::remove_file(dir.join("app.sock")).unwrap();
+        std::os::unix::fs::symlink(&target, dir.join("app.sock")).unwrap();
+        assert!(share_socket(&dir, OsStr::new("app.sock")).is_err());
+        let mode = std::fs::metadata(&target).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
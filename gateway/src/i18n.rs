@@ -0,0 +1,241 @@
+//! Translations of the gateway's own messages (`agentman lang`).
+//!
+//! Each language is a catalog: a function rendering every [`Msg`] in that language. The
+//! matches are exhaustive, so a message added without a translation doesn't compile. Users
+//! pick a language with `agentman lang set <code>`; before login (the keyboard-interactive
+//! prompt) and for guests, `[i18n] default_lang` applies. Only what the gateway says while
+//! connecting and around sessions is translated; command reference output (`agentman help`,
+//! tables) stays English so it matches the documentation.
+
+use serde::{Deserialize, Serialize};
+
+/// A supported language.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Lang {
+    #[default]
+    En,
+    Tr,
+    De,
+}
+
+impl Lang {
+    pub const ALL: [Lang; 3] = [Lang::En, Lang::Tr, Lang::De];
+
+    /// The language with ISO 639-1 code `code` (case-insensitive; region suffixes like
+    /// `tr_TR` or `de-AT` are ignored).
+    pub fn parse(code: &str) -> Option<Lang> {
+        let code = code.split(['_', '-', '.']).next()?.to_ascii_lowercase();
+        Self::ALL.into_iter().find(|lang| lang.code() == code)
+    }
+
+    pub fn code(self) -> &'static str {
+        match self {
+            Lang::En => "en",
+            Lang::Tr => "tr",
+            Lang::De => "de",
+        }
+    }
+
+    /// The language's name in itself.
+    pub fn name(self) -> &'static str {
+        match self {
+            Lang::En => "English",
+            Lang::Tr => "Türkçe",
+            Lang::De => "Deutsch",
+        }
+    }
+
+    /// `code (name)` for every language, for listings and errors.
+    pub fn available() -> String {
+        Self::ALL
+            .iter()
+            .map(|lang| format!("{} ({})", lang.code(), lang.name()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// A message the gateway shows users. Lines carry no `agentman: ` prefix or line ending.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Msg<'a> {
+    /// Title, instructions and prompt of the keyboard-interactive GitHub username question.
+    UsernameTitle,
+    UsernameInstructions,
+    UsernamePrompt,
+    SignedInAs { name: &'a str },
+    ReadOnlyGuestNeedsTerminal,
+    ControlUnavailableEphemeral,
+    MovedToGateway {
+        project: &'a str,
+        node: &'a str,
+        address: &'a str,
+    },
+    QueueNextInLine,
+    QueueAhead { ahead: usize },
+    NoGatewayForwards,
+    CancelledForward { addr: &'a str },
+    NoForward { addr: &'a str },
+    ForwardFailed { error: &'a str },
+    Forwarding { addr: &'a str, target: &'a str },
+    LangCurrent { name: &'a str, code: &'a str },
+    LangSet { name: &'a str },
+    LangUnknown { code: &'a str, available: &'a str },
+}
+
+impl Msg<'_> {
+    pub fn render(&self, lang: Lang) -> String {
+        match lang {
+            Lang::En => en(self),
+            Lang::Tr => tr(self),
+            Lang::De => de(self),
+        }
+    }
+}
+
+fn en(msg: &Msg) -> String {
+    match msg {
+        Msg::UsernameTitle => "GitHub Username".to_string(),
+        Msg::UsernameInstructions => "Enter your GitHub username to verify your SSH key:".to_string(),
+        Msg::UsernamePrompt => "GitHub username: ".to_string(),
+        Msg::SignedInAs { name } => format!("signed in as {name}"),
+        Msg::ReadOnlyGuestNeedsTerminal => "read-only guests need a terminal (ssh -t)".to_string(),
+        Msg::ControlUnavailableEphemeral => {
+            "control commands are not available in an ephemeral sandbox".to_string()
+        }
+        Msg::MovedToGateway {
+            project,
+            node,
+            address,
+        } => format!("{project} was moved to the gateway {node} on {address}; connect there instead"),
+        Msg::QueueNextInLine => "waiting to create the sandbox (next in line)".to_string(),
+        Msg::QueueAhead { ahead } => format!("waiting to create the sandbox ({ahead} ahead)"),
+        Msg::NoGatewayForwards => "no gateway forwards".to_string(),
+        Msg::CancelledForward { addr } => format!("cancelled forward {addr}"),
+        Msg::NoForward { addr } => format!("no forward on {addr}"),
+        Msg::ForwardFailed { error } => format!("forward failed: {error}"),
+        Msg::Forwarding { addr, target } => {
+            format!("forwarding {addr} on the gateway to {target} in the sandbox")
+        }
+        Msg::LangCurrent { name, code } => format!("language: {name} ({code})"),
+        Msg::LangSet { name } => format!("messages are now in {name}"),
+        Msg::LangUnknown { code, available } => {
+            format!("unknown language {code:?}; available: {available}")
+        }
+    }
+}
+
+fn tr(msg: &Msg) -> String {
+    match msg {
+        Msg::UsernameTitle => "GitHub Kullanıcı Adı".to_string(),
+        Msg::UsernameInstructions => {
+            "SSH anahtarınızı doğrulamak için GitHub kullanıcı adınızı girin:".to_string()
+        }
+        Msg::UsernamePrompt => "GitHub kullanıcı adı: ".to_string(),
+        Msg::SignedInAs { name } => format!("{name} olarak oturum açıldı"),
+        Msg::ReadOnlyGuestNeedsTerminal => {
+            "salt okunur misafirlerin bir terminale ihtiyacı var (ssh -t)".to_string()
+        }
+        Msg::ControlUnavailableEphemeral => {
+            "kontrol komutları geçici bir sanal ortamda kullanılamaz".to_string()
+        }
+        Msg::MovedToGateway {
+            project,
+            node,
+            address,
+        } => format!("{project}, {address} adresindeki {node} geçidine taşındı; oraya bağlanın"),
+        Msg::QueueNextInLine => "sanal ortam oluşturmak için bekleniyor (sıradaki)".to_string(),
+        Msg::QueueAhead { ahead } => {
+            format!("sanal ortam oluşturmak için bekleniyor (önde {ahead} tane var)")
+        }
+        Msg::NoGatewayForwards => "geçit yönlendirmesi yok".to_string(),
+        Msg::CancelledForward { addr } => format!("{addr} yönlendirmesi iptal edildi"),
+        Msg::NoForward { addr } => format!("{addr} üzerinde yönlendirme yok"),
+        Msg::ForwardFailed { error } => format!("yönlendirme başarısız: {error}"),
+        Msg::Forwarding { addr, target } => {
+            format!("geçitteki {addr}, sanal ortamdaki {target} adresine yönlendiriliyor")
+        }
+        Msg::LangCurrent { name, code } => format!("dil: {name} ({code})"),
+        Msg::LangSet { name } => format!("mesajlar artık {name}"),
+        Msg::LangUnknown { code, available } => {
+            format!("bilinmeyen dil {code:?}; kullanılabilir diller: {available}")
+        }
+    }
+}
+
+fn de(msg: &Msg) -> String {
+    match msg {
+        Msg::UsernameTitle => "GitHub-Benutzername".to_string(),
+        Msg::UsernameInstructions => {
+            "Gib deinen GitHub-Benutzernamen ein, um deinen SSH-Schlüssel zu prüfen:".to_string()
+        }
+        Msg::UsernamePrompt => "GitHub-Benutzername: ".to_string(),
+        Msg::SignedInAs { name } => format!("angemeldet als {name}"),
+        Msg::ReadOnlyGuestNeedsTerminal => {
+            "Gäste mit Lesezugriff brauchen ein Terminal (ssh -t)".to_string()
+        }
+        Msg::ControlUnavailableEphemeral => {
+            "Steuerbefehle sind in einer temporären Sandbox nicht verfügbar".to_string()
+        }
+        Msg::MovedToGateway {
+            project,
+            node,
+            address,
+        } => format!(
+            "{project} wurde auf das Gateway {node} unter {address} verschoben; verbinde dich dorthin"
+        ),
+        Msg::QueueNextInLine => "warte auf das Erstellen der Sandbox (als Nächstes dran)".to_string(),
+        Msg::QueueAhead { ahead } => {
+            format!("warte auf das Erstellen der Sandbox ({ahead} davor)")
+        }
+        Msg::NoGatewayForwards => "keine Gateway-Weiterleitungen".to_string(),
+        Msg::CancelledForward { addr } => format!("Weiterleitung {addr} beendet"),
+        Msg::NoForward { addr } => format!("keine Weiterleitung auf {addr}"),
+        Msg::ForwardFailed { error } => format!("Weiterleitung fehlgeschlagen: {error}"),
+        Msg::Forwarding { addr, target } => {
+            format!("leite {addr} auf dem Gateway an {target} in der Sandbox weiter")
+        }
+        Msg::LangCurrent { name, code } => format!("Sprache: {name} ({code})"),
+        Msg::LangSet { name } => format!("Meldungen sind jetzt auf {name}"),
+        Msg::LangUnknown { code, available } => {
+            format!("unbekannte Sprache {code:?}; verfügbar: {available}")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Lang::parse("tr"), Some(Lang::Tr));
+        assert_eq!(Lang::parse("TR"), Some(Lang::Tr));
+        assert_eq!(Lang::parse("de_AT.UTF-8"), Some(Lang::De));
+        assert_eq!(Lang::parse("en-US"), Some(Lang::En));
+        assert_eq!(Lang::parse("xx"), None);
+        assert_eq!(Lang::parse(""), None);
+        for lang in Lang::ALL {
+            assert_eq!(Lang::parse(lang.code()), Some(lang));
+        }
+    }
+
+    #[test]
+    fn test_catalogs_keep_arguments() {
+        let msg = Msg::MovedToGateway {
+            project: "web",
+            node: "eu-1",
+            address: "eu.example.com:22",
+        };
+        for lang in Lang::ALL {
+            let text = msg.render(lang);
+            for arg in ["web", "eu-1", "eu.example.com:22"] {
+                assert!(text.contains(arg), "{lang:?}: {text}");
+            }
+        }
+        assert_eq!(
+            Msg::QueueAhead { ahead: 3 }.render(Lang::En),
+            "waiting to create the sandbox (3 ahead)"
+        );
+    }
+}
@@ -0,0 +1,73 @@
+//! Audited admin access to other users' projects (`agentman admin access`).
+//!
+//! An admin runs `agentman admin access <user>/<project> --reason <text>`, which records a
+//! time-limited grant. Until it expires, logging in as `<project>+<user>` with one of the
+//! admin's cached keys reaches that user's sandbox as the user. Each such login queues a notice
+//! for the owner's next login, goes to their notifier (`agentman notify`) when they have one,
+//! and is recorded as an `impersonation` audit event; everything else the admin does on the
+//! connection is audited under the owner with an `impersonated_by` field.
+
+use chrono::{DateTime, Utc};
+use tracing::{info, warn};
+
+use crate::config::GatewayConfig;
+use crate::state::{AdminAccessGrant, StateManager};
+use crate::user_notify;
+
+/// An admin logged in to another user's project on a connection.
+#[derive(Debug, Clone)]
+pub struct Impersonation {
+    pub admin: String,
+    pub reason: String,
+}
+
+/// The grant letting `admin` log in to `github_user`'s `project`, if any.
+pub async fn grant_for(
+    config: &GatewayConfig,
+    state: &StateManager,
+    admin: &str,
+    github_user: &str,
+    project: &str,
+) -> Option<AdminAccessGrant> {
+    if !config.impersonation.enabled || !config.is_admin(admin) || admin == github_user {
+        return None;
+    }
+    state
+        .admin_access(admin)
+        .await
+        .filter(|grant| grant.github_user == github_user && grant.project == project)
+}
+
+/// What the owner is told about a login.
+pub fn message(admin: &str, project: &str, reason: &str, at: DateTime<Utc>) -> String {
+    format!(
+        "admin {admin} accessed your project {project} on {}: {reason}",
+        at.format("%Y-%m-%d %H:%M UTC")
+    )
+}
+
+/// Tell the owner about a login: a notice for their next login, and their notifier.
+pub async fn notify_owner(
+    config: &GatewayConfig,
+    state: &StateManager,
+    github_user: &str,
+    project: &str,
+    impersonation: &Impersonation,
+) {
+    let text = message(
+        &impersonation.admin,
+        project,
+        &impersonation.reason,
+        Utc::now(),
+    );
+    info!("{}", text);
+    if let Err(e) = state.add_notice(github_user, text.clone()).await {
+        warn!("Failed to queue a notice for {}: {:#}", github_user, e);
+    }
+    if config.user_notify.enabled
+        && state.notifier(github_user).await.is_some()
+        && let Err(e) = user_notify::send(config, state, github_user, project, &text).await
+    {
+        warn!("Failed to notify {} of admin access: {:#}", github_user, e);
+    }
+}
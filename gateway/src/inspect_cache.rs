@@ -0,0 +1,96 @@
+//! Container metadata reused within an SSH connection.
+//!
+//! Interactive control commands (`agentman status`, `list`, `stats`, `limits`) each inspected
+//! the containers they report on, so a connection running several of them in a row (an SSH
+//! `ControlMaster` session, an editor polling `agentman status`) paid a Docker API round trip
+//! for every one. Each connection keeps the inspections its commands made for [`TTL`] and
+//! answers later commands from them. Any other control command the connection runs (`stop`,
+//! `recreate`, ...) clears the cache, so the next report shows what it changed; changes made
+//! elsewhere show up once an entry expires.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use bollard::Docker;
+use bollard::errors::Error as BollardError;
+use bollard::models::ContainerInspectResponse;
+use bollard::query_parameters::InspectContainerOptions;
+
+use crate::metrics;
+
+/// How long an inspection is reused.
+pub const TTL: Duration = Duration::from_secs(5);
+
+/// Inspected containers by name, with when they were inspected.
+#[derive(Default)]
+pub struct InspectCache {
+    entries: Mutex<HashMap<String, (Instant, ContainerInspectResponse)>>,
+}
+
+impl InspectCache {
+    /// `docker inspect` of `container_name`, reused if this connection made it recently.
+    pub async fn inspect(
+        &self,
+        docker: &Docker,
+        container_name: &str,
+    ) -> Result<ContainerInspectResponse, BollardError> {
+        if let Some(info) = self.get(container_name) {
+            metrics::inc(
+                "agentman_control_inspect_cache_total",
+                "Container inspections of control commands, by whether the connection had one.",
+                &[("result", "hit")],
+            );
+            return Ok(info);
+        }
+        metrics::inc(
+            "agentman_control_inspect_cache_total",
+            "Container inspections of control commands, by whether the connection had one.",
+            &[("result", "miss")],
+        );
+        let info = docker
+            .inspect_container(container_name, None::<InspectContainerOptions>)
+            .await?;
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (at, _)| at.elapsed() < TTL);
+        entries.insert(container_name.to_string(), (Instant::now(), info.clone()));
+        Ok(info)
+    }
+
+    fn get(&self, container_name: &str) -> Option<ContainerInspectResponse> {
+        let entries = self.entries.lock().unwrap();
+        let (at, info) = entries.get(container_name)?;
+        (at.elapsed() < TTL).then(|| info.clone())
+    }
+
+    /// Forget every inspection, after a command that may have changed containers.
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entries_expire_and_clear() {
+        let cache = InspectCache::default();
+        let info = ContainerInspectResponse {
+            name: Some("/agentman-octocat-web".to_string()),
+            ..Default::default()
+        };
+        cache.entries.lock().unwrap().insert(
+            "agentman-octocat-web".to_string(),
+            (Instant::now(), info.clone()),
+        );
+        cache.entries.lock().unwrap().insert(
+            "agentman-octocat-api".to_string(),
+            (Instant::now() - TTL, info.clone()),
+        );
+        assert_eq!(cache.get("agentman-octocat-web"), Some(info));
+        assert_eq!(cache.get("agentman-octocat-api"), None);
+        cache.clear();
+        assert_eq!(cache.get("agentman-octocat-web"), None);
+    }
+}
@@ -0,0 +1,453 @@
+//! Detached jobs (`agentman run --detach`).
+//!
+//! A job is a command started in its own session inside the sandbox, so it keeps running after
+//! the SSH connection that started it goes away. Everything about it lives in the workspace under
+//! `/workspace/.agentman/jobs/<id>/`: `command`, `started_at`, `output.log` (stdout and stderr
+//! interleaved) and, once the command finishes, `exit_code`. The gateway reads those files from
+//! the host side, so `agentman jobs` works from any later session.
+
+use std::ffi::OsStr;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::{DateTime, SecondsFormat, Utc};
+use sha2::{Digest, Sha256};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::docker::{ContainerManager, EXEC_MARKER_ENV};
+use crate::host_fs::{self, Handle};
+
+/// Finished jobs kept per workspace; older ones are removed when a new job starts.
+const KEEP_FINISHED_JOBS: usize = 20;
+
+/// Largest chunk of output returned by one [`read_output`] call.
+pub(crate) const OUTPUT_CHUNK: u64 = 64 * 1024;
+
+static NEXT_JOB: AtomicU64 = AtomicU64::new(0);
+
+/// Starts the job in the background and returns as soon as it is launched.
+///
+/// `$1` = job dir, `$2` = command, `$3` = time limit in seconds (empty = none),
+/// `$4` = marker (`AGENTMAN_EXEC_ID=job-<id>`, set only for the command itself so
+/// `agentman jobs kill` leaves the wrapper alive to record the exit code), `$5` = start time.
+const START_JOB_SCRIPT: &str = r#"
+set -e
+mkdir -p "$(dirname "$1")"
+mkdir "$1"
+printf '%s' "$2" > "$1/command"
+printf '%s\n' "$5" > "$1/started_at"
+setsid bash -c '
+dir=$1; cmd=$2; secs=$3; marker=$4
+cd /workspace
+if [ -n "$secs" ]; then
+  timeout -k 10 "$secs" env "$marker" bash -c "$cmd"
+else
+  env "$marker" bash -c "$cmd"
+fi >"$dir/output.log" 2>&1 </dev/null
+echo $? >"$dir/exit_code.tmp"
+mv "$dir/exit_code.tmp" "$dir/exit_code"
+' agentman-job "$1" "$2" "$3" "$4" >/dev/null 2>&1 </dev/null &
+"#;
+
+/// A detached job as recorded in the workspace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Job {
+    pub id: String,
+    pub command: String,
+    pub started_at: Option<DateTime<Utc>>,
+    /// `None` while the command runs (or if the container stopped under it).
+    pub exit_code: Option<i32>,
+}
+
+/// Directory holding a workspace's jobs, relative to the workspace.
+const JOBS_DIR: &str = ".agentman/jobs";
+
+/// Job IDs are 8 lowercase hex digits.
+pub(crate) fn validate_job_id(id: &str) -> Result<()> {
+    if id.len() == 8 && id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "invalid job id {id:?} (expected 8 hex digits, see `agentman jobs`)"
+        ))
+    }
+}
+
+/// The marker [`EXEC_MARKER_ENV`] value carried by a job's processes.
+pub(crate) fn job_marker(id: &str) -> String {
+    format!("job-{id}")
+}
+
+fn new_job_id() -> String {
+    let seed = format!(
+        "{}-{}-{}",
+        std::process::id(),
+        Utc::now().timestamp_nanos_opt().unwrap_or_default(),
+        NEXT_JOB.fetch_add(1, Ordering::Relaxed)
+    );
+    Sha256::digest(seed.as_bytes())[..4]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Start `command` as a detached job in the sandbox (creating it if needed); returns the job ID.
+pub(crate) async fn start(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    command: &str,
+    timeout_secs: Option<u64>,
+) -> Result<String> {
+    let container_id = container_manager
+        .get_or_create_container(github_user, project)
+        .await?;
+    let workspace = container_manager
+        .config()
+        .workspace_path(github_user, project);
+    if let Err(e) = prune(&workspace, KEEP_FINISHED_JOBS).await {
+        tracing::warn!(
+            "Failed to prune jobs for {}/{}: {:#}",
+            github_user,
+            project,
+            e
+        );
+    }
+
+    let id = new_job_id();
+    let dir = format!("/workspace/.agentman/jobs/{id}");
+    let output = container_manager
+        .run_exec(
+            &container_id,
+            vec![
+                "bash".to_string(),
+                "-c".to_string(),
+                START_JOB_SCRIPT.to_string(),
+                "agentman-job".to_string(),
+                dir,
+                command.to_string(),
+                timeout_secs.map(|s| s.to_string()).unwrap_or_default(),
+                format!("{EXEC_MARKER_ENV}={}", job_marker(&id)),
+                Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            ],
+        )
+        .await?;
+    if output.exit_code != 0 {
+        bail!(
+            "failed to start job: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(id)
+}
+
+/// The workspace's jobs directory. The sandbox owns everything below the workspace, so the
+/// path is walked without following symlinks ([`host_fs`]).
+fn open_jobs_dir(workspace_path: &Path) -> io::Result<Handle> {
+    let dir = host_fs::walk(workspace_path, Path::new(JOBS_DIR))?;
+    if !dir.metadata()?.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotADirectory,
+            format!("{JOBS_DIR} is not a directory"),
+        ));
+    }
+    Ok(dir)
+}
+
+/// The regular file `name` of job `id`, opened for reading (see [`open_jobs_dir`]).
+async fn open_job_file(workspace_path: &Path, id: &str, name: &str) -> io::Result<File> {
+    let workspace_path = workspace_path.to_path_buf();
+    let (id, name) = (id.to_string(), name.to_string());
+    let file = tokio::task::spawn_blocking(move || {
+        let dir = open_jobs_dir(&workspace_path)?.child(OsStr::new(&id))?;
+        let file = dir.child(OsStr::new(&name))?;
+        if !file.metadata()?.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{JOBS_DIR}/{id}/{name} is not a regular file"),
+            ));
+        }
+        file.reopen()
+    })
+    .await
+    .map_err(io::Error::other)??;
+    Ok(File::from_std(file))
+}
+
+async fn read_job_file(workspace_path: &Path, id: &str, name: &str) -> io::Result<String> {
+    let mut content = String::new();
+    open_job_file(workspace_path, id, name)
+        .await?
+        .read_to_string(&mut content)
+        .await?;
+    Ok(content)
+}
+
+async fn read_job(workspace_path: &Path, id: &str) -> Result<Job> {
+    let command = read_job_file(workspace_path, id, "command")
+        .await
+        .with_context(|| format!("no job {id}"))?;
+    let started_at = read_job_file(workspace_path, id, "started_at")
+        .await
+        .ok()
+        .and_then(|s| DateTime::parse_from_rfc3339(s.trim()).ok())
+        .map(|t| t.with_timezone(&Utc));
+    Ok(Job {
+        id: id.to_string(),
+        command,
+        started_at,
+        exit_code: read_exit_code(workspace_path, id).await,
+    })
+}
+
+async fn read_exit_code(workspace_path: &Path, id: &str) -> Option<i32> {
+    read_job_file(workspace_path, id, "exit_code")
+        .await
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+}
+
+/// Look up one job.
+pub(crate) async fn get(workspace_path: &Path, id: &str) -> Result<Job> {
+    validate_job_id(id)?;
+    read_job(workspace_path, id).await
+}
+
+/// All jobs of a workspace, oldest first.
+pub(crate) async fn list(workspace_path: &Path) -> Result<Vec<Job>> {
+    let workspace = workspace_path.to_path_buf();
+    let names = tokio::task::spawn_blocking(move || open_jobs_dir(&workspace)?.entries())
+        .await
+        .context("list task failed")?;
+    let names = match names {
+        Ok(names) => names,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).context("Failed to read jobs directory"),
+    };
+    let mut jobs = Vec::new();
+    for name in names {
+        let name = name.to_string_lossy();
+        if validate_job_id(&name).is_err() {
+            continue;
+        }
+        // A job directory without `command` is still being set up (or was left half-created).
+        if let Ok(job) = read_job(workspace_path, &name).await {
+            jobs.push(job);
+        }
+    }
+    jobs.sort_by(|a, b| a.started_at.cmp(&b.started_at).then(a.id.cmp(&b.id)));
+    Ok(jobs)
+}
+
+/// Remove the oldest finished jobs so at most `keep` finished ones remain.
+async fn prune(workspace_path: &Path, keep: usize) -> Result<()> {
+    let finished: Vec<Job> = list(workspace_path)
+        .await?
+        .into_iter()
+        .filter(|j| j.exit_code.is_some())
+        .collect();
+    let excess = finished.len().saturating_sub(keep);
+    let ids: Vec<String> = finished[..excess].iter().map(|j| j.id.clone()).collect();
+    let workspace = workspace_path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let dir = open_jobs_dir(&workspace)?;
+        ids.iter().try_for_each(|id| dir.remove_all(OsStr::new(id)))
+    })
+    .await
+    .context("prune task failed")?
+    .context("Failed to remove job")
+}
+
+/// Read up to [`OUTPUT_CHUNK`] bytes of a job's output starting at `offset`.
+///
+/// Also returns the job's exit code once it has finished; the exit code is read first, so when
+/// it is `Some` and the chunk comes back empty, all output has been read.
+pub(crate) async fn read_output(
+    workspace_path: &Path,
+    id: &str,
+    offset: u64,
+) -> Result<(Vec<u8>, Option<i32>)> {
+    let exit_code = read_exit_code(workspace_path, id).await;
+    let mut file = match open_job_file(workspace_path, id, "output.log").await {
+        Ok(file) => file,
+        // The job hasn't opened its log yet.
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((Vec::new(), exit_code)),
+        Err(e) => return Err(e).context("Failed to open job output"),
+    };
+    file.seek(io::SeekFrom::Start(offset)).await?;
+    let mut buf = Vec::new();
+    file.take(OUTPUT_CHUNK).read_to_end(&mut buf).await?;
+    Ok((buf, exit_code))
+}
+
+/// Size of a job's output so far.
+pub(crate) async fn output_len(workspace_path: &Path, id: &str) -> u64 {
+    match open_job_file(workspace_path, id, "output.log").await {
+        Ok(file) => file.metadata().await.map(|m| m.len()).unwrap_or(0),
+        Err(_) => 0,
+    }
+}
+
+/// Whether a job is still running, given when the sandbox container last started (`None` if it
+/// isn't running). Jobs started before that were killed with the previous container.
+pub(crate) fn is_running(job: &Job, container_started: Option<DateTime<Utc>>) -> bool {
+    match (job.exit_code, container_started, job.started_at) {
+        (Some(_), _, _) | (None, None, _) => false,
+        // `started_at` has second precision.
+        (None, Some(since), Some(started)) => started.timestamp() >= since.timestamp(),
+        (None, Some(_), None) => true,
+    }
+}
+
+fn status_text(job: &Job, container_started: Option<DateTime<Utc>>) -> String {
+    match job.exit_code {
+        Some(code) => format!("exited {code}"),
+        None if is_running(job, container_started) => "running".to_string(),
+        None => "lost".to_string(),
+    }
+}
+
+/// `agentman jobs` output.
+pub(crate) fn format_list(jobs: &[Job], container_started: Option<DateTime<Utc>>) -> String {
+    if jobs.is_empty() {
+        return "agentman: no jobs (start one with `agentman run --detach -- <command>`)\n"
+            .to_string();
+    }
+    let mut out = format!("{:<10}{:<11}{:<22}COMMAND\n", "ID", "STATUS", "STARTED");
+    for job in jobs {
+        let started = job
+            .started_at
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_else(|| "-".to_string());
+        let command = job.command.replace('\n', " ");
+        out.push_str(&format!(
+            "{:<10}{:<11}{:<22}{}\n",
+            job.id,
+            status_text(job, container_started),
+            started,
+            command
+        ));
+    }
+    out.push_str(
+        "lost = the sandbox stopped while the job ran; `agentman jobs logs <id>` shows its output\n",
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn write_job(workspace: &Path, id: &str, started: &str, exit: Option<&str>) {
+        let dir = workspace.join(JOBS_DIR).join(id);
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        tokio::fs::write(dir.join("command"), format!("echo {id}"))
+            .await
+            .unwrap();
+        tokio::fs::write(dir.join("started_at"), format!("{started}\n"))
+            .await
+            .unwrap();
+        tokio::fs::write(dir.join("output.log"), format!("hello from {id}\n"))
+            .await
+            .unwrap();
+        if let Some(exit) = exit {
+            tokio::fs::write(dir.join("exit_code"), exit).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn test_validate_job_id() {
+        assert!(validate_job_id("0a1b2c3d").is_ok());
+        assert!(validate_job_id(&new_job_id()).is_ok());
+        assert!(validate_job_id("0A1B2C3D").is_err());
+        assert!(validate_job_id("../../etc").is_err());
+        assert!(validate_job_id("0a1b2c3").is_err());
+        assert_ne!(new_job_id(), new_job_id());
+    }
+
+    #[tokio::test]
+    async fn test_list_prune_and_read_output() {
+        let tmp = std::env::temp_dir().join(format!("agentman-jobs-{}", new_job_id()));
+        write_job(&tmp, "00000002", "2026-10-14T10:00:00Z", Some("0\n")).await;
+        write_job(&tmp, "00000001", "2026-10-14T09:00:00Z", Some("3\n")).await;
+        write_job(&tmp, "00000003", "2026-10-14T11:00:00Z", None).await;
+        tokio::fs::create_dir_all(tmp.join(JOBS_DIR).join("not-a-job"))
+            .await
+            .unwrap();
+
+        let jobs = list(&tmp).await.unwrap();
+        let ids: Vec<&str> = jobs.iter().map(|j| j.id.as_str()).collect();
+        assert_eq!(ids, ["00000001", "00000002", "00000003"]);
+        assert_eq!(jobs[0].exit_code, Some(3));
+        assert_eq!(jobs[2].exit_code, None);
+
+        let since = DateTime::parse_from_rfc3339("2026-10-14T10:30:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert!(is_running(&jobs[2], Some(since)));
+        assert!(!is_running(&jobs[2], None));
+        let later = since + chrono::Duration::hours(1);
+        let listing = format_list(&jobs, Some(later));
+        assert!(listing.contains("00000001  exited 3"), "{listing}");
+        assert!(listing.contains("00000003  lost"), "{listing}");
+
+        let (chunk, exit) = read_output(&tmp, "00000002", 6).await.unwrap();
+        assert_eq!(chunk, b"from 00000002\n");
+        assert_eq!(exit, Some(0));
+        assert_eq!(output_len(&tmp, "00000002").await, 20);
+
+        // Only the newest finished job is kept; running jobs are never pruned.
+        prune(&tmp, 1).await.unwrap();
+        let ids: Vec<String> = list(&tmp)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|j| j.id)
+            .collect();
+        assert_eq!(ids, ["00000002", "00000003"]);
+        assert!(get(&tmp, "00000001").await.is_err());
+        assert!(get(&tmp, "../jobs").await.is_err());
+
+        tokio::fs::remove_dir_all(&tmp).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_jobs_do_not_follow_symlinks() {
+        let tmp = std::env::temp_dir().join(format!("agentman-jobs-{}", new_job_id()));
+        let ws = tmp.join("ws");
+        write_job(&ws, "00000001", "2026-10-14T09:00:00Z", Some("0\n")).await;
+        let secret = tmp.join("secret");
+        tokio::fs::write(&secret, "host secret\n").await.unwrap();
+
+        // A job's files are read only if they are regular files in the job's directory.
+        let log = ws.join(JOBS_DIR).join("00000001/output.log");
+        tokio::fs::remove_file(&log).await.unwrap();
+        std::os::unix::fs::symlink(&secret, &log).unwrap();
+        assert!(read_output(&ws, "00000001", 0).await.is_err());
+        assert_eq!(output_len(&ws, "00000001").await, 0);
+
+        // Nor is a `.agentman` swapped for a symlink followed, by reads or by pruning.
+        let outside = tmp.join("outside");
+        write_job(&outside, "00000002", "2026-10-14T10:00:00Z", Some("0\n")).await;
+        tokio::fs::remove_dir_all(ws.join(".agentman")).await.unwrap();
+        std::os::unix::fs::symlink(outside.join(".agentman"), ws.join(".agentman")).unwrap();
+        assert!(list(&ws).await.is_err());
+        assert!(get(&ws, "00000002").await.is_err());
+        assert!(prune(&ws, 0).await.is_err());
+        assert!(outside.join(JOBS_DIR).join("00000002/command").exists());
+
+        // Pruning removes a symlink inside a job's directory, not its target.
+        write_job(&tmp, "00000003", "2026-10-14T11:00:00Z", Some("0\n")).await;
+        let link = tmp.join(JOBS_DIR).join("00000003/link");
+        std::os::unix::fs::symlink(&outside, &link).unwrap();
+        prune(&tmp, 0).await.unwrap();
+        assert!(list(&tmp).await.unwrap().is_empty());
+        assert!(outside.join(JOBS_DIR).join("00000002/command").exists());
+        assert_eq!(tokio::fs::read_to_string(&secret).await.unwrap(), "host secret\n");
+
+        tokio::fs::remove_dir_all(&tmp).await.unwrap();
+    }
+}
@@ -0,0 +1,318 @@
+//! Journal of destructive operations in progress (`agentman admin operations`).
+//!
+//! Destroying, recreating and migrating a workspace take several steps (containers, files,
+//! state), and a gateway that crashes between two of them used to leave half-deleted
+//! workspaces behind without a trace. Each of these operations is recorded in the state store
+//! before its first step, its progress is updated as it goes, and the entry is removed once it
+//! has finished. An entry found at startup belongs to an operation the crash interrupted; so
+//! does one whose step failed after something had changed. Such a workspace refuses new
+//! sandboxes, and admins are notified, until an admin resumes the operation (runs its steps
+//! again, each of which copes with the work already done) or rolls it back (removes the
+//! containers it created and leaves the workspace as it is).
+
+use anyhow::{Context, Result, bail};
+use chrono::Utc;
+use tracing::warn;
+
+use crate::docker::{ContainerManager, DestroyOptions, RecreateOptions};
+use crate::guest;
+use crate::metrics;
+use crate::migration;
+use crate::notify;
+use crate::state::{Operation, OperationKind, StateManager};
+
+/// Step of an operation that has not changed anything yet.
+pub const STEP_STARTING: &str = "starting";
+
+/// Step of a migration whose workspace already lives on the other gateway.
+pub const STEP_REMOVING_LOCAL_COPY: &str = "removing local copy";
+
+/// Move the operation on a workspace on to `step`.
+pub async fn step(state: &StateManager, github_user: &str, project: &str, step: &str) {
+    if let Err(e) = state
+        .update_operation(github_user, project, |op| op.step = step.to_string())
+        .await
+    {
+        warn!("Failed to journal {}/{} reaching {}: {:#}", github_user, project, step, e);
+    }
+}
+
+/// Record a container the operation on a workspace created.
+pub async fn created(state: &StateManager, github_user: &str, project: &str, container: &str) {
+    if let Err(e) = state
+        .update_operation(github_user, project, |op| {
+            op.created_containers.push(container.to_string())
+        })
+        .await
+    {
+        warn!("Failed to journal container {} of {}/{}: {:#}", container, github_user, project, e);
+    }
+}
+
+/// Close the operation on a workspace after it returned `result`: a success, or a failure
+/// before anything changed, removes it; other failures keep it for an admin.
+pub async fn end<T>(state: &StateManager, github_user: &str, project: &str, result: &Result<T>) {
+    let outcome = match result {
+        Ok(_) => state.finish_operation(github_user, project).await,
+        Err(e) => match state.operation(github_user, project).await {
+            Some(op) if op.step != STEP_STARTING => {
+                warn!(
+                    "{} of {}/{} failed at step {:?}; it waits for an admin: {:#}",
+                    op.kind, github_user, project, op.step, e
+                );
+                let error = format!("{e:#}");
+                state
+                    .update_operation(github_user, project, |op| op.error = Some(error))
+                    .await
+            }
+            _ => state.finish_operation(github_user, project).await,
+        },
+    };
+    if let Err(e) = outcome {
+        warn!("Failed to journal the end of an operation on {}/{}: {:#}", github_user, project, e);
+    }
+}
+
+/// The refusal for new sandboxes of a workspace whose last operation stopped half-way.
+pub async fn check_usable(state: &StateManager, github_user: &str, project: &str) -> Result<()> {
+    match state.operation(github_user, project).await {
+        Some(op) if op.stalled() => bail!(
+            "the {} of {github_user}/{project} stopped at step {:?}; a gateway admin has to \
+             resume or roll it back (agentman admin operations)",
+            op.kind,
+            op.step
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Mark the operations an earlier gateway process left behind as interrupted, and tell admins.
+pub async fn recover(container_manager: &ContainerManager) {
+    let state = container_manager.state();
+    let interrupted = match state.interrupt_operations().await {
+        Ok(interrupted) => interrupted,
+        Err(e) => {
+            warn!("Failed to check for interrupted operations: {:#}", e);
+            return;
+        }
+    };
+    let mut stalled = Vec::new();
+    for (key, op) in interrupted {
+        // Interrupted before it changed anything: there is nothing to resume or roll back.
+        if op.step == STEP_STARTING && op.created_containers.is_empty() {
+            if let Some((github_user, project)) = key.split_once('/')
+                && let Err(e) = state.finish_operation(github_user, project).await
+            {
+                warn!("Failed to drop the interrupted {} of {}: {:#}", op.kind, key, e);
+            }
+            continue;
+        }
+        warn!(
+            "The {} of {} was interrupted at step {:?}; resume or roll it back with \
+             `agentman admin operations`",
+            op.kind, key, op.step
+        );
+        stalled.push((key, op));
+    }
+    let interrupted = stalled;
+    metrics::add(
+        "agentman_operations_interrupted_total",
+        "Destroys, recreates and migrations found unfinished when the gateway started.",
+        &[],
+        interrupted.len() as f64,
+    );
+    if !interrupted.is_empty() {
+        let text = format!(
+            "agentman: {} operation(s) were interrupted by a gateway restart and need an \
+             admin:\n{}",
+            interrupted.len(),
+            format_operations(&interrupted)
+        );
+        notify::send(&container_manager.config().notify, "operations.interrupted", &text).await;
+    }
+}
+
+/// `agentman admin operations`: one line per operation.
+pub fn format_operations(operations: &[(String, Operation)]) -> String {
+    if operations.is_empty() {
+        return "agentman: no operations in progress\n".to_string();
+    }
+    let now = Utc::now();
+    operations
+        .iter()
+        .map(|(key, op)| {
+            let age = (now - op.started_at).num_seconds().max(0) as u64;
+            let status = match (&op.error, op.interrupted) {
+                (Some(error), _) => format!("failed: {error}"),
+                (None, true) => "interrupted by a restart".to_string(),
+                (None, false) => "running".to_string(),
+            };
+            format!(
+                "{key}: {} at step {:?}, started {} ago, {status}\n",
+                op.kind,
+                op.step,
+                guest::format_ttl(age)
+            )
+        })
+        .collect()
+}
+
+/// The stalled operation on `github_user/project`.
+async fn stalled(state: &StateManager, github_user: &str, project: &str) -> Result<Operation> {
+    match state.operation(github_user, project).await {
+        Some(op) if op.stalled() => Ok(op),
+        Some(op) => bail!("the {} of {github_user}/{project} is still running", op.kind),
+        None => bail!("no unfinished operation on {github_user}/{project}"),
+    }
+}
+
+/// Run a stalled operation's steps again; returns a report.
+pub async fn resume(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    requested_by: &str,
+) -> Result<String> {
+    let state = container_manager.state();
+    let op = stalled(state, github_user, project).await?;
+    // Running again; a new failure is recorded like the first.
+    state
+        .update_operation(github_user, project, |op| {
+            op.interrupted = false;
+            op.error = None;
+        })
+        .await?;
+    let result = match op.kind {
+        OperationKind::Destroy { keep_workspace } => {
+            let opts = DestroyOptions {
+                keep_workspace,
+                force: true,
+                dry_run: false,
+            };
+            container_manager
+                .destroy_steps(github_user, project, opts)
+                .await
+                .map(|res| {
+                    let mut out = format!("agentman: destroyed {github_user}/{project}\n");
+                    for w in res.warnings {
+                        out.push_str(&format!("agentman: warning: {w}\n"));
+                    }
+                    out
+                })
+        }
+        OperationKind::Recreate => {
+            // A half-started replacement goes; the workspace gets a fresh container.
+            let opts = RecreateOptions {
+                keep_running: false,
+                pull: false,
+            };
+            match remove_created_containers(container_manager, github_user, project, &op).await {
+                Ok(removed) => container_manager
+                    .recreate_steps(github_user, project, opts)
+                    .await
+                    .map(|res| {
+                        format!(
+                            "{removed}agentman: recreated {github_user}/{project} as {}\n",
+                            res.new_container
+                        )
+                    }),
+                Err(e) => Err(e),
+            }
+        }
+        OperationKind::Migrate { ref node } if op.step == STEP_REMOVING_LOCAL_COPY => {
+            let (user, by) = (github_user, requested_by);
+            migration::remove_local_copy(container_manager, user, project, node, by)
+                .await
+        }
+        OperationKind::Migrate { ref node } => {
+            // Nothing here changed but the stopped sandbox; start over (the other gateway
+            // refuses a workspace it already has, in which case roll back and check there).
+            state.finish_operation(github_user, project).await?;
+            let (user, by) = (github_user, requested_by);
+            return migration::migrate(container_manager, user, project, node, false, by)
+                .await
+                .context("Migration failed again; the workspace stays here");
+        }
+    };
+    end(state, github_user, project, &result).await;
+    result.with_context(|| format!("Resuming the {} failed", op.kind))
+}
+
+/// Give up on a stalled operation: remove the containers it created and forget it; returns a
+/// report.
+pub async fn rollback(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+) -> Result<String> {
+    let state = container_manager.state();
+    let op = stalled(state, github_user, project).await?;
+    if let OperationKind::Migrate { ref node } = op.kind
+        && op.step == STEP_REMOVING_LOCAL_COPY
+    {
+        bail!("{github_user}/{project} already lives on {node}; resume to remove the local copy");
+    }
+    let mut out = remove_created_containers(container_manager, github_user, project, &op).await?;
+    state.finish_operation(github_user, project).await?;
+    let workspace_dir = container_manager.config().workspace_path(github_user, project);
+    out.push_str(&format!(
+        "agentman: rolled back the {} of {github_user}/{project} (stopped at step {:?}); {}\n",
+        op.kind,
+        op.step,
+        if workspace_dir.exists() {
+            "its files are in place, and the next connection starts a sandbox"
+        } else {
+            "its workspace directory is gone"
+        }
+    ));
+    Ok(out)
+}
+
+/// Remove the containers `op` created, except the one the workspace uses now; returns a
+/// report.
+async fn remove_created_containers(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    op: &Operation,
+) -> Result<String> {
+    let current = container_manager
+        .state()
+        .get_workspace(github_user, project)
+        .await
+        .and_then(|ws| ws.container_id);
+    let mut out = String::new();
+    for container in &op.created_containers {
+        if current.as_ref() == Some(container) {
+            continue;
+        }
+        container_manager.retire_container(container).await?;
+        out.push_str(&format!("agentman: removed container {container}\n"));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_operations() {
+        assert_eq!(format_operations(&[]), "agentman: no operations in progress\n");
+        let op = Operation {
+            kind: OperationKind::Migrate {
+                node: "node-b".to_string(),
+            },
+            started_at: Utc::now() - chrono::Duration::minutes(5),
+            step: STEP_REMOVING_LOCAL_COPY.to_string(),
+            created_containers: Vec::new(),
+            error: None,
+            interrupted: true,
+        };
+        assert_eq!(
+            format_operations(&[("octocat/web".to_string(), op)]),
+            "octocat/web: migrate to node-b at step \"removing local copy\", started 5m ago, \
+             interrupted by a restart\n"
+        );
+    }
+}
@@ -0,0 +1,142 @@
+//! Changing the log filter of the running gateway (`agentman admin log-level`).
+//!
+//! The filter built at startup (`RUST_LOG`, `--verbose`) sits behind a reload layer, so an
+//! admin can turn on debug logs for one module while reproducing a problem and turn them off
+//! again without restarting the gateway and dropping its sessions.
+
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use anyhow::{Context, Result, anyhow, bail};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Registry, fmt, reload};
+
+/// Prefix of the gateway's own tracing targets.
+const CRATE_TARGET: &str = env!("CARGO_CRATE_NAME");
+
+struct Reloadable {
+    handle: reload::Handle<EnvFilter, Registry>,
+    /// The filter the gateway started with, for `reset`.
+    startup: String,
+}
+
+static FILTER: OnceLock<Reloadable> = OnceLock::new();
+
+/// Install the global subscriber with `filter`, keeping a handle to change it later.
+pub fn init(filter: EnvFilter) {
+    let startup = filter.to_string();
+    let (filter, handle) = reload::Layer::new(filter);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer().with_target(false))
+        .init();
+    let _ = FILTER.set(Reloadable { handle, startup });
+}
+
+fn reloadable() -> Result<&'static Reloadable> {
+    FILTER
+        .get()
+        .ok_or_else(|| anyhow!("the log filter can't be changed in this process"))
+}
+
+/// The filter in effect, as `RUST_LOG`-style directives.
+pub fn current() -> Result<String> {
+    reloadable()?
+        .handle
+        .with_current(|filter| filter.to_string())
+        .context("Failed to read the log filter")
+}
+
+/// Log `target` (a gateway module such as `docker`, or a full target path such as
+/// `russh::server`) at `level`, or change the default level if `target` is `None`. Other
+/// directives are kept. Returns the new filter.
+pub fn set(level: &str, target: Option<&str>) -> Result<String> {
+    let directives = merge(&current()?, level, target)?;
+    apply(&directives)
+}
+
+/// Go back to the filter the gateway started with.
+pub fn reset() -> Result<String> {
+    apply(&reloadable()?.startup)
+}
+
+fn apply(directives: &str) -> Result<String> {
+    let filter = EnvFilter::try_new(directives)
+        .with_context(|| format!("Invalid log filter {directives:?}"))?;
+    reloadable()?
+        .handle
+        .reload(filter)
+        .context("Failed to change the log filter")?;
+    let now = current()?;
+    tracing::warn!("Log filter changed to {}", now);
+    Ok(now)
+}
+
+/// `current` with the directive for `target` (or the default level) replaced by `level`.
+fn merge(current: &str, level: &str, target: Option<&str>) -> Result<String> {
+    let level = LevelFilter::from_str(level)
+        .map_err(|_| anyhow!("unknown level {level:?} (trace, debug, info, warn, error, off)"))?;
+    let level = level.to_string().to_lowercase();
+    let is_default = |d: &str| LevelFilter::from_str(d).is_ok();
+
+    let mut directives: Vec<String> = current
+        .split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .map(str::to_string)
+        .collect();
+    match target {
+        None => {
+            directives.retain(|d| !is_default(d));
+            directives.insert(0, level);
+        }
+        Some(target) => {
+            if target.is_empty()
+                || !target
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+            {
+                bail!("invalid target {target:?}");
+            }
+            let target = if target.contains("::") || target == CRATE_TARGET {
+                target.to_string()
+            } else {
+                format!("{CRATE_TARGET}::{target}")
+            };
+            directives.retain(|d| d.split_once('=').map(|(t, _)| t) != Some(target.as_str()));
+            directives.push(format!("{target}={level}"));
+        }
+    }
+    Ok(directives.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        assert_eq!(merge("info", "DEBUG", None).unwrap(), "debug");
+        assert_eq!(
+            merge("info", "debug", Some("docker")).unwrap(),
+            "info,agentman_gateway::docker=debug"
+        );
+        assert_eq!(
+            merge("agentman_gateway::docker=debug,warn", "trace", Some("docker")).unwrap(),
+            "warn,agentman_gateway::docker=trace"
+        );
+        assert_eq!(
+            merge("agentman_gateway::docker=debug,warn", "error", None).unwrap(),
+            "error,agentman_gateway::docker=debug"
+        );
+        assert_eq!(
+            merge("info", "debug", Some("russh::server")).unwrap(),
+            "info,russh::server=debug"
+        );
+        assert!(merge("info", "loud", None).is_err());
+        assert!(merge("info", "debug", Some("docker=trace")).is_err());
+        assert!(EnvFilter::try_new(merge("info", "off", Some("ssh")).unwrap()).is_ok());
+    }
+}
@@ -3,21 +3,94 @@
 //! A Rust SSH server that authenticates users via GitHub SSH keys,
 //! manages Docker containers per project, and supports port forwarding.
 
+mod accounting;
+mod admin_api;
+mod admission;
+mod audit;
+mod audit_store;
+mod auth_guard;
+mod backend;
+mod backup;
+mod bench;
+mod build;
+mod checkpoint;
+mod compression;
 mod config;
+mod config_check;
 mod docker;
+mod docker_transport;
+mod editor_cache;
+mod egress;
+mod env_file;
+mod ephemeral;
+mod escape;
+#[cfg(any(test, feature = "fake-github"))]
+mod fake_github;
+mod file_view;
 mod gateway_control;
+mod guest;
+mod git_identity;
+mod git_signing;
 mod github;
+mod github_metadata;
+mod ha;
+mod helper;
+mod host_fs;
+mod i18n;
+mod impersonation;
+mod inspect_cache;
+mod jobs;
+mod journal;
+mod log_level;
+mod memory_guard;
+mod mesh;
+mod metrics;
+mod migration;
+mod motd;
+mod notify;
+mod offboarding;
+mod open;
+mod org_access;
+mod ownership;
+mod preferences;
+mod preview;
+mod provision;
+mod proxy;
+mod proxy_ca;
+mod relay;
+mod scan;
+mod search;
+mod secret;
+mod security_events;
+mod security_profiles;
+mod slow_clients;
+mod sni_router;
 mod ssh;
+mod stack;
 mod state;
+mod state_snapshot;
+mod stats_history;
+mod streamlocal;
+mod tar;
+mod tcp_bridge;
+mod template;
+mod tmux_clients;
+mod trust;
+mod user_config;
+mod user_notify;
+mod workspace_guard;
+mod workspace_size;
+mod yaml;
 
 use anyhow::{Context, Result};
 use clap::Parser;
 use std::path::PathBuf;
 use std::sync::Arc;
-use tracing::{info, Level};
+use tracing::{info, warn, Level};
 use tracing_subscriber::EnvFilter;
 
-use crate::config::GatewayConfig;
+use crate::audit::AuditLogger;
+use crate::config::{GatewayConfig, LoggingConfig};
 use crate::docker::ContainerManager;
 use crate::github::GitHubKeyFetcher;
 use crate::state::StateManager;
@@ -34,10 +107,39 @@ struct Cli {
     #[arg(long)]
     generate_config: bool,
 
+    /// Validate the configuration file, print problems with their locations, and exit non-zero
+    /// on errors
+    #[arg(long)]
+    check_config: bool,
+
+    /// With --check-config, treat unknown keys as errors
+    #[arg(long, requires = "check_config")]
+    strict: bool,
+
     /// Override listen address
     #[arg(short, long)]
     listen: Option<String>,
 
+    /// Generate a new proxy CA (see [proxy_ca]) and exit; the running gateway updates
+    /// containers within an hour
+    #[arg(long)]
+    rotate_proxy_ca: bool,
+
+    /// Write a snapshot of the state store to PATH ("-" for stdout) and exit
+    #[arg(long, value_name = "PATH")]
+    export_state: Option<PathBuf>,
+
+    /// Replace the state store with a snapshot (from --export-state or `agentman admin state
+    /// export`) and exit; the previous state file is kept. Stop the gateway first
+    #[arg(long, value_name = "PATH", conflicts_with = "export_state")]
+    import_state: Option<PathBuf>,
+
+    /// Verify logins against a fake GitHub serving `<user>.keys` files from DIR, for
+    /// integration tests and local development; github.com is never contacted
+    #[cfg(feature = "fake-github")]
+    #[arg(long, value_name = "DIR")]
+    fake_github: Option<PathBuf>,
+
     /// Enable verbose logging
     #[arg(short, long)]
     verbose: bool,
@@ -55,10 +157,7 @@ async fn main() -> Result<()> {
             .unwrap_or_else(|_| EnvFilter::new(Level::INFO.to_string()))
     };
 
-    tracing_subscriber::fmt()
-        .with_env_filter(filter)
-        .with_target(false)
-        .init();
+    log_level::init(filter);
 
     // Handle --generate-config
     if cli.generate_config {
@@ -68,18 +167,89 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
+    // Handle --check-config
+    if cli.check_config {
+        let ok = config_check::run(&cli.config, cli.strict)?;
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+
     // Load configuration
     let mut config = GatewayConfig::load_or_default(&cli.config)
         .with_context(|| format!("Failed to load config from {}", cli.config.display()))?;
 
+    for warning in config.warnings() {
+        warn!("Config: {}", warning);
+    }
+
     // Apply CLI overrides
     if let Some(listen) = cli.listen {
         config.listen_addr = listen;
     }
+    // Kept alive until the gateway exits.
+    #[cfg(feature = "fake-github")]
+    let _fake_github = match cli.fake_github {
+        Some(dir) => {
+            let server = fake_github::FakeGitHub::start("127.0.0.1:0", dir).await?;
+            config.github = server.config();
+            warn!("Logins are verified against a fake GitHub at {}", config.github.keys_url);
+            Some(server)
+        }
+        None => None,
+    };
 
     // Ensure required directories exist
     config.ensure_dirs()?;
 
+    // Generate or rotate the CA containers trust (no-op unless [proxy_ca] enabled = true)
+    if cli.rotate_proxy_ca {
+        anyhow::ensure!(config.proxy_ca.enabled, "[proxy_ca] is not enabled");
+        proxy_ca::ensure(&config.proxy_ca, true).await?;
+        println!("Rotated proxy CA in {}", config.proxy_ca.dir.display());
+        return Ok(());
+    }
+
+    // Snapshots of the state store, for migrations and recovery drills
+    if let Some(ref path) = cli.export_state {
+        let state = StateManager::load(config.state_file.clone())
+            .await
+            .context("Failed to load state")?;
+        let snapshot = state_snapshot::export(&state).await?;
+        if path.as_os_str() == "-" {
+            println!("{}", snapshot);
+        } else {
+            tokio::fs::write(path, snapshot + "\n")
+                .await
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+            eprintln!("Exported {} to {}", config.state_file.display(), path.display());
+        }
+        return Ok(());
+    }
+    if let Some(ref path) = cli.import_state {
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let imported = state_snapshot::parse(&content)
+            .with_context(|| format!("Refusing to import {}", path.display()))?;
+        let summary = state_snapshot::summary(&imported);
+        // The current file may be what's being recovered from, so it isn't parsed.
+        let state = StateManager::empty(config.state_file.clone());
+        let backup = state_snapshot::import(&state, imported).await?;
+        println!("Imported {} into {}", summary, config.state_file.display());
+        if let Some(backup) = backup {
+            println!("Previous state file kept as {}", backup.display());
+        }
+        return Ok(());
+    }
+
+    // With [ha] enabled, wait here as the passive instance until we hold the failover lease
+    ha::acquire(&config.ha)
+        .await
+        .context("Failed to acquire the failover lease")?;
+
+    proxy_ca::ensure(&config.proxy_ca, false)
+        .await
+        .context("Failed to prepare proxy CA")?;
+
     info!("Starting agentman-gateway");
     info!("  Listen address: {}", config.listen_addr);
     info!("  Docker image: {}", config.docker_image);
@@ -96,8 +266,9 @@ async fn main() -> Result<()> {
 
     info!("State loaded from {}", config.state_file.display());
 
-    // Initialize GitHub key fetcher
-    let github_fetcher = Arc::new(GitHubKeyFetcher::new());
+    // Initialize GitHub key fetcher (and the shared client for profiles and teams)
+    github::configure(&config.github);
+    let github_fetcher = Arc::new(GitHubKeyFetcher::new(&config.github));
 
     // Initialize Docker container manager
     let container_manager = Arc::new(
@@ -106,8 +277,99 @@ async fn main() -> Result<()> {
             .context("Failed to initialize Docker container manager")?,
     );
 
+    // Move containers from older naming schemes to their stable names
+    container_manager.reconcile_container_names().await;
+    // Ephemeral sandboxes don't outlive their connections
+    container_manager.remove_ephemeral_containers().await;
+    // Destroys, recreates and migrations a crash interrupted wait for an admin
+    journal::recover(&container_manager).await;
+
+    // Start audit log sinks
+    let audit = Arc::new(
+        AuditLogger::start(&config.logging)
+            .await
+            .context("Failed to initialize audit logging")?,
+    );
+    if !config.logging.sinks.is_empty() {
+        info!("  Audit sinks: {}", config.logging.sinks.len());
+    }
+
+    // Forward kernel security events from containers, to dedicated sinks if configured
+    if config.security_events.enabled {
+        let sinks = &config.security_events.sinks;
+        let security_audit = if sinks.is_empty() {
+            audit.clone()
+        } else {
+            // They have their own rate limit.
+            let logging = LoggingConfig {
+                sinks: sinks.clone(),
+                max_events_per_minute: None,
+                retention: config.logging.retention.clone(),
+            };
+            Arc::new(
+                AuditLogger::start(&logging)
+                    .await
+                    .context("Failed to initialize security event sinks")?,
+            )
+        };
+        security_events::spawn(container_manager.clone(), security_audit);
+    }
+
+    // Start scheduled backups (no-op unless [backup] interval_hours is set)
+    backup::spawn_scheduler(container_manager.clone());
+
+    // Record resource samples for `agentman stats --history`
+    stats_history::spawn_sampler(container_manager.clone());
+
+    // Account network traffic per workspace and enforce monthly egress quotas
+    egress::spawn_sampler(container_manager.clone());
+
+    // Add up per-user resource usage for chargeback (no-op unless [accounting] enabled)
+    accounting::spawn_sampler(container_manager.clone());
+
+    // Relay `agentman notify` messages from scripts inside existing sandboxes
+    user_notify::serve_existing(&config, &state).await;
+
+    // Run commands from sandboxes in the user's other projects (no-op unless [mesh] enabled)
+    mesh::spawn(container_manager.clone(), audit.clone());
+
+    // Keep workspace sizes cached so `agentman stats` doesn't wait for du
+    workspace_size::spawn_indexer(container_manager.clone());
+
+    // Rotate the proxy CA when due and re-trust it in running containers
+    proxy_ca::spawn_rotation(container_manager.clone());
+
+    // Flag, archive and remove inactive users (manual `agentman admin offboard` always works)
+    offboarding::spawn(container_manager.clone(), audit.clone());
+
+    // Stop sandboxes nobody has been connected to for [idle] timeout_secs
+    docker::spawn_idle_reaper(container_manager.clone());
+
+    // Look for escaping symlinks, writable setuid files and large files (opt-in)
+    scan::spawn(container_manager.clone());
+
+    // Refuse new channels, and optionally restart, when the gateway itself uses too much
+    memory_guard::spawn(container_manager.clone());
+
+    // Start the egress proxy (no-op unless [proxy] enabled = true)
+    proxy::serve(container_manager.clone()).await?;
+
+    // Start the TLS router (no-op unless [sni_router] enabled = true)
+    sni_router::serve(container_manager.clone()).await?;
+
+    // Serve HTTP preview links (no-op unless [preview] enabled = true)
+    preview::serve(container_manager.clone()).await?;
+
+    // Start the admin API (no-op unless [admin_api] enabled = true)
+    admin_api::serve(container_manager.clone(), audit.clone()).await?;
+
+    // Start metrics endpoint
+    if let Some(ref addr) = config.metrics.listen_addr {
+        metrics::serve(addr).await?;
+    }
+
     // Run SSH server
-    ssh::run_server(config, state, container_manager, github_fetcher).await?;
+    ssh::run_server(config, state, container_manager, github_fetcher, audit).await?;
 
     Ok(())
 }
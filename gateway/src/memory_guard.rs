@@ -0,0 +1,250 @@
+//! Self-monitoring of the gateway process (`[memory_guard]`).
+//!
+//! The gateway runs on the host it manages, so a leak in it, or a flood of connections, takes
+//! memory from the sandboxes and the Docker daemon, and the kernel's OOM killer may pick one of
+//! those first. The guard samples the process RSS and the number of live tokio tasks. Over a
+//! limit it logs diagnostics, tells admins and sheds load: new SSH channels and forwards are
+//! refused while open ones keep working, until usage is back under `resume_percent` of the
+//! limits. With `restart_after_secs`, a gateway that stays over a limit exits so its supervisor
+//! restarts it; sandboxes (and their tmux sessions) don't depend on the gateway process.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use tracing::{error, info, warn};
+
+use crate::config::MemoryGuardConfig;
+use crate::docker::ContainerManager;
+use crate::gateway_control::format_bytes;
+use crate::{metrics, notify};
+
+/// Exit status of a restart (EX_TEMPFAIL, as when a failover lease is lost).
+const RESTART_EXIT_STATUS: i32 = 75;
+
+static SHEDDING: AtomicBool = AtomicBool::new(false);
+
+/// Open SSH connections, for the diagnostics.
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether new channels should be refused.
+pub fn shedding() -> bool {
+    SHEDDING.load(Ordering::Relaxed)
+}
+
+/// Count a channel refused while shedding.
+pub fn record_refused(kind: &str) {
+    metrics::inc(
+        "agentman_gateway_shed_channels_total",
+        "SSH channels and forwards refused while the gateway was over a memory guard limit.",
+        &[("kind", kind)],
+    );
+}
+
+pub fn connection_opened() {
+    CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn connection_closed() {
+    CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    rss_bytes: Option<u64>,
+    tasks: usize,
+}
+
+impl Sample {
+    fn take() -> Self {
+        let rss_bytes = std::fs::read_to_string("/proc/self/status")
+            .ok()
+            .and_then(|status| parse_vm_rss(&status));
+        let tasks = tokio::runtime::Handle::current()
+            .metrics()
+            .num_alive_tasks();
+        Sample { rss_bytes, tasks }
+    }
+
+    /// The limits this sample exceeds, with the limits scaled by `percent`.
+    fn breaches(&self, config: &MemoryGuardConfig, percent: u64) -> Vec<String> {
+        let mut breaches = Vec::new();
+        let max_rss = config.max_rss_mb * 1024 * 1024 * percent / 100;
+        if let Some(rss) = self.rss_bytes
+            && config.max_rss_mb > 0
+            && rss >= max_rss
+        {
+            breaches.push(format!(
+                "RSS {} over {}",
+                format_bytes(rss),
+                format_bytes(max_rss)
+            ));
+        }
+        let max_tasks = config.max_tasks as u64 * percent / 100;
+        if config.max_tasks > 0 && self.tasks as u64 >= max_tasks {
+            breaches.push(format!("{} tasks over {}", self.tasks, max_tasks));
+        }
+        breaches
+    }
+}
+
+/// `VmRSS` of `/proc/<pid>/status`, in bytes.
+fn parse_vm_rss(status: &str) -> Option<u64> {
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let mut fields = line["VmRSS:".len()..].split_whitespace();
+    let value: u64 = fields.next()?.parse().ok()?;
+    match fields.next() {
+        Some("kB") => Some(value * 1024),
+        _ => None,
+    }
+}
+
+/// What an admin needs to tell a leak from a load spike.
+fn diagnostics(sample: &Sample) -> String {
+    let runtime = tokio::runtime::Handle::current().metrics();
+    format!(
+        "rss={} tasks={} workers={} global_queue={} ssh_connections={}",
+        sample
+            .rss_bytes
+            .map(format_bytes)
+            .unwrap_or_else(|| "unknown".to_string()),
+        sample.tasks,
+        runtime.num_workers(),
+        runtime.global_queue_depth(),
+        CONNECTIONS.load(Ordering::Relaxed)
+    )
+}
+
+pub fn spawn(container_manager: Arc<ContainerManager>) {
+    let config = container_manager.config().memory_guard.clone();
+    if !config.enabled {
+        return;
+    }
+    info!(
+        "Memory guard: max RSS {} MiB, max {} tasks (0 = no limit)",
+        config.max_rss_mb, config.max_tasks
+    );
+
+    tokio::spawn(async move {
+        let notify_config = &container_manager.config().notify;
+        let mut tick = tokio::time::interval(Duration::from_secs(config.interval_secs));
+        tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        let mut over_since: Option<Instant> = None;
+        loop {
+            tick.tick().await;
+            let sample = Sample::take();
+            if let Some(rss) = sample.rss_bytes {
+                metrics::set(
+                    "agentman_gateway_rss_bytes",
+                    "Resident memory of the gateway process.",
+                    &[],
+                    rss as f64,
+                );
+            }
+            metrics::set(
+                "agentman_gateway_tasks",
+                "Live tokio tasks in the gateway process.",
+                &[],
+                sample.tasks as f64,
+            );
+
+            let breaches = sample.breaches(&config, 100);
+            over_since = match over_since {
+                _ if breaches.is_empty() => None,
+                Some(since) => Some(since),
+                None => Some(Instant::now()),
+            };
+            if !shedding() && !breaches.is_empty() {
+                SHEDDING.store(true, Ordering::Relaxed);
+                let text = format!(
+                    "gateway over its memory guard limits ({}); refusing new SSH channels [{}]",
+                    breaches.join(", "),
+                    diagnostics(&sample)
+                );
+                error!("{}", text);
+                notify::send(notify_config, "memory_guard.shedding", &text).await;
+            } else if shedding()
+                && sample
+                    .breaches(&config, u64::from(config.resume_percent))
+                    .is_empty()
+            {
+                SHEDDING.store(false, Ordering::Relaxed);
+                let text = format!(
+                    "gateway back under its memory guard limits; accepting new SSH channels [{}]",
+                    diagnostics(&sample)
+                );
+                info!("{}", text);
+                notify::send(notify_config, "memory_guard.recovered", &text).await;
+            } else if shedding() {
+                warn!("Still shedding load: {}", diagnostics(&sample));
+            }
+            metrics::set(
+                "agentman_gateway_shedding",
+                "1 while the gateway refuses new SSH channels (memory guard).",
+                &[],
+                if shedding() { 1.0 } else { 0.0 },
+            );
+
+            if let Some(since) = over_since
+                && config.restart_after_secs > 0
+                && since.elapsed() >= Duration::from_secs(config.restart_after_secs)
+            {
+                let text = format!(
+                    "gateway over its memory guard limits for {}s ({}); restarting [{}]",
+                    since.elapsed().as_secs(),
+                    breaches.join(", "),
+                    diagnostics(&sample)
+                );
+                error!("{}", text);
+                notify::send(notify_config, "memory_guard.restart", &text).await;
+                if let Err(e) = container_manager.state().save().await {
+                    warn!("Failed to save state before restarting: {:#}", e);
+                }
+                std::process::exit(RESTART_EXIT_STATUS);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_vm_rss() {
+        let status = "Name:\tagentman-gateway\nVmPeak:\t  912340 kB\nVmRSS:\t   52180 kB\n";
+        assert_eq!(parse_vm_rss(status), Some(52180 * 1024));
+        assert_eq!(parse_vm_rss("Name:\tx\n"), None);
+        assert_eq!(parse_vm_rss("VmRSS:\t12 MB\n"), None);
+    }
+
+    #[test]
+    fn test_breaches() {
+        let config = MemoryGuardConfig {
+            max_rss_mb: 100,
+            max_tasks: 1000,
+            ..MemoryGuardConfig::default()
+        };
+        let sample = |rss_mb: u64, tasks| Sample {
+            rss_bytes: Some(rss_mb * 1024 * 1024),
+            tasks,
+        };
+        assert!(sample(50, 10).breaches(&config, 100).is_empty());
+        assert_eq!(sample(120, 10).breaches(&config, 100).len(), 1);
+        assert_eq!(sample(120, 1000).breaches(&config, 100).len(), 2);
+        // Between the resume threshold and the limit: not over, but not recovered either.
+        assert!(sample(95, 10).breaches(&config, 100).is_empty());
+        assert_eq!(sample(95, 10).breaches(&config, 90).len(), 1);
+
+        let unlimited = MemoryGuardConfig {
+            max_rss_mb: 0,
+            max_tasks: 0,
+            ..config
+        };
+        assert!(
+            sample(1 << 20, 1 << 20)
+                .breaches(&unlimited, 100)
+                .is_empty()
+        );
+    }
+}
@@ -0,0 +1,529 @@
+//! Commands from one sandbox in another of the same user's projects (`[mesh]`).
+//!
+//! Agents orchestrating several sandboxes need to run commands in them, and handing them the
+//! Docker socket would hand them the host. Instead, each workspace its owner allowed to reach
+//! other projects (`agentman mesh allow <project>`) gets a Unix socket in the workspace
+//! (`/workspace/.agentman-mesh.sock`, served like the notify socket), and the gateway runs the
+//! requested command in the target's container itself. The socket a request arrives on is what
+//! identifies the calling workspace. Each request is audited under the target project, with
+//! the caller in `mesh_from`; refused ones as denied.
+//!
+//! A client (`agentman-helper exec <project> -- <command...>`) writes the target project and
+//! the command's arguments, each terminated by a NUL byte, then an empty field (a second NUL);
+//! everything after that is the command's stdin, ended by shutting down its side. The gateway
+//! replies with frames of a kind byte, a big-endian `u32` length and the payload: stdout,
+//! stderr, then the exit status (a big-endian `i32`), or an error message instead of it.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+
+use anyhow::{Result, anyhow, bail};
+use bollard::container::LogOutput;
+use bollard::exec::StartExecResults;
+use chrono::Utc;
+use futures::StreamExt;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+use crate::audit::{AuditEvent, AuditEventKind, AuditLogger, AuditOutcome};
+use crate::config::MeshConfig;
+use crate::docker::{ContainerManager, EXEC_MARKER_ENV};
+use crate::gateway_control::EXEC_TIMEOUT_EXIT_STATUS;
+use crate::github::validate_project_name;
+use crate::metrics;
+use crate::user_notify;
+
+/// The mesh socket in the workspace directory.
+pub const SOCKET_NAME: &str = ".agentman-mesh.sock";
+
+/// Where the socket is inside containers.
+pub const CONTAINER_SOCKET: &str = "/workspace/.agentman-mesh.sock";
+
+/// Reply frame kinds.
+pub const FRAME_STDOUT: u8 = 1;
+pub const FRAME_STDERR: u8 = 2;
+pub const FRAME_EXIT: u8 = 3;
+pub const FRAME_ERROR: u8 = 4;
+
+/// Longest request (target and arguments).
+const MAX_REQUEST_BYTES: usize = 64 * 1024;
+
+/// How long a client may take to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often the served sockets are checked against the grants (deleted socket files come
+/// back then).
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A served socket and the task accepting on it.
+type Listener = (PathBuf, JoinHandle<()>);
+
+/// Mesh sockets being served, by workspace (`github_user/project`).
+static LISTENERS: LazyLock<Mutex<HashMap<String, Listener>>> = LazyLock::new(Default::default);
+
+/// Commands running on behalf of each workspace, for `max_concurrent`.
+static RUNNING: LazyLock<Mutex<HashMap<String, usize>>> = LazyLock::new(Default::default);
+
+/// Woken when grants change.
+static CHANGED: LazyLock<Notify> = LazyLock::new(Notify::new);
+
+static NEXT_MARKER: AtomicU64 = AtomicU64::new(1);
+
+/// `AGENTMAN_MESH_SOCK` for containers, when the mesh is enabled.
+pub fn container_env(config: &MeshConfig) -> Option<String> {
+    config
+        .enabled
+        .then(|| format!("AGENTMAN_MESH_SOCK={CONTAINER_SOCKET}"))
+}
+
+/// A command to run in another project.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub project: String,
+    pub command: Vec<String>,
+}
+
+/// Read a request, leaving what follows it (the command's stdin) in `reader`.
+pub async fn read_request<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Request> {
+    let mut fields = Vec::new();
+    let mut remaining = MAX_REQUEST_BYTES;
+    loop {
+        let mut field = Vec::new();
+        let read = (&mut *reader)
+            .take(remaining as u64 + 1)
+            .read_until(0, &mut field)
+            .await?;
+        if read > remaining {
+            bail!("the request is longer than {MAX_REQUEST_BYTES} bytes");
+        }
+        remaining -= read;
+        if field.pop() != Some(0) {
+            bail!("incomplete request");
+        }
+        if field.is_empty() {
+            break;
+        }
+        fields.push(String::from_utf8(field).map_err(|_| anyhow!("the request is not UTF-8"))?);
+    }
+    let mut fields = fields.into_iter();
+    let project = fields.next().ok_or_else(|| anyhow!("no project given"))?;
+    validate_project_name(&project)?;
+    let command: Vec<String> = fields.collect();
+    if command.is_empty() {
+        bail!("no command given");
+    }
+    Ok(Request { project, command })
+}
+
+/// One reply frame.
+pub fn frame(kind: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(5 + payload.len());
+    frame.push(kind);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Serve the mesh sockets of the workspaces allowed to reach other projects, and keep them in
+/// line with the grants. No-op unless `[mesh] enabled`.
+pub fn spawn(manager: Arc<ContainerManager>, audit: Arc<AuditLogger>) {
+    if !manager.config().mesh.enabled {
+        return;
+    }
+    info!("Serving mesh sockets for workspaces with `agentman mesh allow` grants");
+    tokio::spawn(async move {
+        loop {
+            reconcile(&manager, &audit).await;
+            tokio::select! {
+                _ = CHANGED.notified() => {}
+                _ = tokio::time::sleep(RECONCILE_INTERVAL) => {}
+            }
+        }
+    });
+}
+
+/// Grants changed (or a workspace went away): serve or stop sockets now.
+pub fn refresh() {
+    CHANGED.notify_one();
+}
+
+async fn reconcile(manager: &Arc<ContainerManager>, audit: &Arc<AuditLogger>) {
+    let state = manager.state();
+    let mut wanted = HashMap::new();
+    for (github_user, project) in state.mesh_sources().await {
+        if let Some(ws) = state.get_workspace(&github_user, &project).await
+            && ws.host_workspace_path.is_dir()
+        {
+            wanted.insert(
+                format!("{github_user}/{project}"),
+                (github_user, project, ws.host_workspace_path),
+            );
+        }
+    }
+
+    let mut listeners = LISTENERS.lock().unwrap();
+    listeners.retain(|key, (path, task)| {
+        let keep = wanted.contains_key(key);
+        if !keep {
+            debug!("No longer serving {}", path.display());
+            task.abort();
+            let _ = std::fs::remove_file(&*path);
+        }
+        keep
+    });
+    for (key, (github_user, project, dir)) in wanted {
+        let path = dir.join(SOCKET_NAME);
+        if let Some((served, task)) = listeners.get(&key)
+            && *served == path
+            && !task.is_finished()
+            && std::fs::symlink_metadata(&path).is_ok()
+        {
+            continue;
+        }
+        if let Some((_, task)) = listeners.remove(&key) {
+            task.abort();
+        }
+        match serve(manager, audit, &github_user, &project, &path, &dir) {
+            Ok(task) => {
+                listeners.insert(key, (path, task));
+            }
+            Err(e) => warn!("Failed to serve the mesh socket of {}: {:#}", key, e),
+        }
+    }
+}
+
+fn serve(
+    manager: &Arc<ContainerManager>,
+    audit: &Arc<AuditLogger>,
+    github_user: &str,
+    project: &str,
+    path: &Path,
+    workspace_dir: &Path,
+) -> Result<JoinHandle<()>> {
+    let listener = user_notify::bind(path, workspace_dir)?;
+    debug!("Serving {}", path.display());
+    let (manager, audit) = (manager.clone(), audit.clone());
+    let (user, project) = (github_user.to_string(), project.to_string());
+    Ok(tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Mesh socket of {}/{} failed: {}", user, project, e);
+                    return;
+                }
+            };
+            let (manager, audit) = (manager.clone(), audit.clone());
+            let (user, project) = (user.clone(), project.clone());
+            tokio::spawn(async move {
+                handle_client(stream, &manager, &audit, &user, &project).await;
+            });
+        }
+    }))
+}
+
+/// A command slot of a workspace, released on drop.
+struct Slot(String);
+
+impl Slot {
+    fn take(workspace: &str, max: usize) -> Result<Slot> {
+        let mut running = RUNNING.lock().unwrap();
+        let count = running.entry(workspace.to_string()).or_default();
+        if *count >= max {
+            bail!("{max} commands are already running from this sandbox; try again later");
+        }
+        *count += 1;
+        Ok(Slot(workspace.to_string()))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut running = RUNNING.lock().unwrap();
+        if let Some(count) = running.get_mut(&self.0) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                running.remove(&self.0);
+            }
+        }
+    }
+}
+
+async fn handle_client(
+    stream: UnixStream,
+    manager: &ContainerManager,
+    audit: &AuditLogger,
+    github_user: &str,
+    project: &str,
+) {
+    let (read, mut write) = stream.into_split();
+    let mut reader = BufReader::new(read);
+    let request = match tokio::time::timeout(READ_TIMEOUT, read_request(&mut reader)).await {
+        Ok(request) => request,
+        Err(_) => Err(anyhow!("no request within {}s", READ_TIMEOUT.as_secs())),
+    };
+    let (outcome, result) = match request {
+        Ok(request) => {
+            let event = audit_event(github_user, project, &request);
+            let started = Instant::now();
+            match authorize(manager, github_user, project, &request.project).await {
+                Ok(slot) => {
+                    let result =
+                        run(manager, github_user, project, request, reader, &mut write).await;
+                    drop(slot);
+                    audit.record(command_ended(event, &result, started.elapsed()));
+                    let outcome = match result {
+                        Ok(Some(_)) => "completed",
+                        Ok(None) => "killed",
+                        Err(_) => "failed",
+                    };
+                    (outcome, result.map(|_| ()))
+                }
+                Err(e) => {
+                    let mut event = event.field("error", format!("{e:#}"));
+                    event.outcome = AuditOutcome::Denied;
+                    audit.record(event);
+                    ("denied", Err(e))
+                }
+            }
+        }
+        Err(e) => ("failed", Err(e)),
+    };
+    if let Err(e) = result {
+        debug!("Mesh request from {}/{} failed: {:#}", github_user, project, e);
+        let message = format!("{e:#}");
+        let _ = write.write_all(&frame(FRAME_ERROR, message.as_bytes())).await;
+    }
+    metrics::inc(
+        "agentman_mesh_commands_total",
+        "Commands sandboxes ran in other projects of their user, by outcome.",
+        &[("outcome", outcome)],
+    );
+    let _ = write.shutdown().await;
+}
+
+/// The audit event of a command `from` asked to run in `request.project`, recorded under the
+/// target.
+fn audit_event(github_user: &str, from: &str, request: &Request) -> AuditEvent {
+    AuditEvent::new(AuditEventKind::Exec, AuditOutcome::Success)
+        .github_user(Some(github_user))
+        .project(Some(&request.project))
+        .detail(request.command.join(" "))
+        .field("mesh_from", from)
+}
+
+/// `started` as recorded when the command ends (`None`: killed).
+fn command_ended(
+    started: AuditEvent,
+    result: &Result<Option<i64>>,
+    duration: Duration,
+) -> AuditEvent {
+    let mut event = started.field("duration_secs", format!("{:.3}", duration.as_secs_f64()));
+    match result {
+        Ok(Some(code)) => event = event.field("exit_status", code.to_string()),
+        Ok(None) => {
+            event = event
+                .field("exit_status", EXEC_TIMEOUT_EXIT_STATUS.to_string())
+                .field("killed", "true");
+        }
+        Err(e) => {
+            event = event.field("error", format!("{e:#}"));
+            event.outcome = AuditOutcome::Failure;
+        }
+    }
+    event.timestamp = Utc::now();
+    event
+}
+
+/// Check that `project` may run a command in `target` now, and take one of its slots.
+async fn authorize(
+    manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    target: &str,
+) -> Result<Slot> {
+    let config = &manager.config().mesh;
+    let state = manager.state();
+    if !config.enabled {
+        bail!("the mesh is not enabled on this gateway");
+    }
+    if !state.mesh_targets(github_user, project).await.iter().any(|t| t == target) {
+        bail!(
+            "{project} may not run commands in {target}; allow it with \
+             `agentman mesh allow {target}` on {project}"
+        );
+    }
+    if state.get_workspace(github_user, target).await.is_none() {
+        bail!("no sandbox found for {github_user}/{target}");
+    }
+    Slot::take(&format!("{github_user}/{project}"), config.max_concurrent)
+}
+
+/// Run an authorized request and send its output and exit status; `None` if the command was
+/// killed (it ran too long, or the client went away).
+async fn run(
+    manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    request: Request,
+    reader: BufReader<OwnedReadHalf>,
+    write: &mut OwnedWriteHalf,
+) -> Result<Option<i64>> {
+    let config = &manager.config().mesh;
+    let state = manager.state();
+    let target = request.project.as_str();
+    let container_id = manager.get_or_create_container(github_user, target).await?;
+
+    // The target counts as in use while the command runs, so it isn't stopped as idle.
+    state.connection_opened(github_user, target);
+    let result =
+        exec(manager, config, project, &container_id, request.command, reader, write).await;
+    state.connection_closed(github_user, target);
+    let exit_code = result?;
+    info!(
+        "{}/{} ran a command in {} ({})",
+        github_user,
+        project,
+        target,
+        exit_code.map_or("killed".to_string(), |code| format!("exit {code}"))
+    );
+    let status = exit_code.unwrap_or(EXEC_TIMEOUT_EXIT_STATUS as i64) as i32;
+    write.write_all(&frame(FRAME_EXIT, &status.to_be_bytes())).await?;
+    Ok(exit_code)
+}
+
+async fn exec(
+    manager: &ContainerManager,
+    config: &MeshConfig,
+    from: &str,
+    container_id: &str,
+    command: Vec<String>,
+    reader: BufReader<OwnedReadHalf>,
+    write: &mut OwnedWriteHalf,
+) -> Result<Option<i64>> {
+    let marker = format!(
+        "mesh-{}-{}",
+        std::process::id(),
+        NEXT_MARKER.fetch_add(1, Ordering::Relaxed)
+    );
+    let env = vec![
+        format!("{EXEC_MARKER_ENV}={marker}"),
+        format!("AGENTMAN_MESH_FROM={from}"),
+    ];
+    let exec_id = manager.create_exec(container_id, command, false, Some(env)).await?;
+    let StartExecResults::Attached { mut output, mut input } =
+        manager.start_exec(&exec_id, false).await?
+    else {
+        bail!("the command did not attach");
+    };
+    let stdin = tokio::spawn(async move {
+        let mut reader = reader;
+        let _ = tokio::io::copy(&mut reader, &mut input).await;
+        let _ = input.shutdown().await;
+    });
+
+    let deadline = Instant::now() + Duration::from_secs(config.max_duration_secs);
+    let mut finished = false;
+    loop {
+        let chunk = match tokio::time::timeout_at(deadline, output.next()).await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => {
+                finished = true;
+                break;
+            }
+            Err(_) => break,
+        };
+        let (kind, bytes) = match chunk {
+            Ok(LogOutput::StdErr { message }) => (FRAME_STDERR, message),
+            Ok(other) => (FRAME_STDOUT, other.into_bytes()),
+            Err(e) => {
+                warn!("Mesh command output in {} failed: {}", container_id, e);
+                break;
+            }
+        };
+        // A client that went away takes its command with it.
+        if write.write_all(&frame(kind, &bytes)).await.is_err() {
+            break;
+        }
+    }
+    stdin.abort();
+    if finished {
+        return Ok(Some(manager.wait_exec_exit_code(&exec_id).await?));
+    }
+    if let Err(e) = manager
+        .signal_exec_processes(container_id, &marker, "KILL")
+        .await
+    {
+        warn!("Failed to kill a mesh command in {}: {:#}", container_id, e);
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_request() {
+        let mut input: &[u8] = b"api\0make\0test\0\0piped input";
+        let request = read_request(&mut input).await.unwrap();
+        assert_eq!(
+            request,
+            Request {
+                project: "api".to_string(),
+                command: vec!["make".to_string(), "test".to_string()],
+            }
+        );
+        assert_eq!(input, b"piped input");
+
+        for bad in [&b"api\0\0"[..], b"api\0make", b"../x\0ls\0\0", b"\0"] {
+            let mut input = bad;
+            assert!(read_request(&mut input).await.is_err(), "{bad:?}");
+        }
+        let long = [vec![b'a'; MAX_REQUEST_BYTES + 1], b"\0\0".to_vec()].concat();
+        let err = read_request(&mut long.as_slice()).await.unwrap_err();
+        assert!(err.to_string().contains("longer than"), "{err}");
+    }
+
+    #[test]
+    fn test_frame_and_slots() {
+        assert_eq!(frame(FRAME_EXIT, &7i32.to_be_bytes()), [3, 0, 0, 0, 4, 0, 0, 0, 7]);
+
+        let workspace = format!("slot-test-{}/web", std::process::id());
+        let first = Slot::take(&workspace, 1).unwrap();
+        assert!(Slot::take(&workspace, 1).is_err());
+        drop(first);
+        assert!(Slot::take(&workspace, 1).is_ok());
+    }
+
+    #[test]
+    fn test_audit_event() {
+        let request = Request {
+            project: "api".to_string(),
+            command: vec!["make".to_string(), "test".to_string()],
+        };
+        let event = audit_event("octocat", "web", &request);
+        assert_eq!(event.project.as_deref(), Some("api"));
+        assert_eq!(event.detail.as_deref(), Some("make test"));
+        assert_eq!(event.fields["mesh_from"], "web");
+
+        let done = command_ended(event.clone(), &Ok(Some(2)), Duration::from_millis(1500));
+        assert_eq!(done.outcome, AuditOutcome::Success);
+        assert_eq!(done.fields["exit_status"], "2");
+        assert_eq!(done.fields["duration_secs"], "1.500");
+        let killed = command_ended(event.clone(), &Ok(None), Duration::ZERO);
+        assert_eq!(killed.fields["exit_status"], "124");
+        assert_eq!(killed.fields["killed"], "true");
+        let failed = command_ended(event, &Err(anyhow!("no such image")), Duration::ZERO);
+        assert_eq!(failed.outcome, AuditOutcome::Failure);
+        assert_eq!(failed.fields["error"], "no such image");
+    }
+}
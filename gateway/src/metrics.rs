@@ -0,0 +1,253 @@
+//! Process-wide metrics in Prometheus text format.
+//!
+//! Counters, gauges and histograms live in a global registry so any module can record without threading a
+//! handle through every call site. When `[metrics] listen_addr` is set, [`serve`] exposes them
+//! on `GET /metrics`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::{LazyLock, Mutex};
+
+use anyhow::{Context, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tracing::{debug, info};
+
+static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::default);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Counter,
+    Gauge,
+}
+
+struct Family {
+    help: &'static str,
+    kind: Kind,
+    /// Rendered label set (e.g. `outcome="success"`) -> value.
+    series: BTreeMap<String, f64>,
+}
+
+/// Cumulative-bucket histogram of one label set.
+struct Histogram {
+    /// Count per upper bound in the family's `bounds` (not cumulative), plus `+Inf`.
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+struct HistogramFamily {
+    help: &'static str,
+    bounds: &'static [f64],
+    series: BTreeMap<String, Histogram>,
+}
+
+#[derive(Default)]
+struct Registry {
+    families: Mutex<BTreeMap<&'static str, Family>>,
+    histograms: Mutex<BTreeMap<&'static str, HistogramFamily>>,
+}
+
+impl Registry {
+    fn update(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        kind: Kind,
+        labels: &[(&str, &str)],
+        f: impl FnOnce(&mut f64),
+    ) {
+        let mut families = self.families.lock().unwrap_or_else(|e| e.into_inner());
+        let family = families.entry(name).or_insert_with(|| Family {
+            help,
+            kind,
+            series: BTreeMap::new(),
+        });
+        f(family.series.entry(render_labels(labels)).or_insert(0.0));
+    }
+
+    fn observe(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        bounds: &'static [f64],
+        labels: &[(&str, &str)],
+        value: f64,
+    ) {
+        let mut histograms = self.histograms.lock().unwrap_or_else(|e| e.into_inner());
+        let family = histograms.entry(name).or_insert_with(|| HistogramFamily {
+            help,
+            bounds,
+            series: BTreeMap::new(),
+        });
+        let histogram = family
+            .series
+            .entry(render_labels(labels))
+            .or_insert_with(|| Histogram {
+                counts: vec![0; bounds.len() + 1],
+                sum: 0.0,
+                count: 0,
+            });
+        let bucket = family.bounds.iter().position(|b| value <= *b).unwrap_or(family.bounds.len());
+        histogram.counts[bucket] += 1;
+        histogram.sum += value;
+        histogram.count += 1;
+    }
+
+    fn render(&self) -> String {
+        let mut out = self.render_scalars();
+        let histograms = self.histograms.lock().unwrap_or_else(|e| e.into_inner());
+        for (name, family) in histograms.iter() {
+            let _ = writeln!(out, "# HELP {name} {}", family.help);
+            let _ = writeln!(out, "# TYPE {name} histogram");
+            for (labels, histogram) in &family.series {
+                let sep = if labels.is_empty() { "" } else { "," };
+                let mut cumulative = 0;
+                let bounds = family.bounds.iter().map(|b| b.to_string()).chain(["+Inf".to_string()]);
+                for (le, n) in bounds.zip(&histogram.counts) {
+                    cumulative += n;
+                    let _ = writeln!(out, "{name}_bucket{{{labels}{sep}le=\"{le}\"}} {cumulative}");
+                }
+                let braces = if labels.is_empty() { String::new() } else { format!("{{{labels}}}") };
+                let _ = writeln!(out, "{name}_sum{braces} {}", histogram.sum);
+                let _ = writeln!(out, "{name}_count{braces} {}", histogram.count);
+            }
+        }
+        out
+    }
+
+    fn render_scalars(&self) -> String {
+        let families = self.families.lock().unwrap_or_else(|e| e.into_inner());
+        let mut out = String::new();
+        for (name, family) in families.iter() {
+            let kind = match family.kind {
+                Kind::Counter => "counter",
+                Kind::Gauge => "gauge",
+            };
+            let _ = writeln!(out, "# HELP {name} {}", family.help);
+            let _ = writeln!(out, "# TYPE {name} {kind}");
+            for (labels, value) in &family.series {
+                if labels.is_empty() {
+                    let _ = writeln!(out, "{name} {value}");
+                } else {
+                    let _ = writeln!(out, "{name}{{{labels}}} {value}");
+                }
+            }
+        }
+        out
+    }
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    labels
+        .iter()
+        .map(|(k, v)| {
+            let v = v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+            format!("{k}=\"{v}\"")
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Add `value` to a counter.
+pub fn add(name: &'static str, help: &'static str, labels: &[(&str, &str)], value: f64) {
+    REGISTRY.update(name, help, Kind::Counter, labels, |v| *v += value);
+}
+
+/// Increment a counter by one.
+pub fn inc(name: &'static str, help: &'static str, labels: &[(&str, &str)]) {
+    add(name, help, labels, 1.0);
+}
+
+/// Set a gauge.
+pub fn set(name: &'static str, help: &'static str, labels: &[(&str, &str)], value: f64) {
+    REGISTRY.update(name, help, Kind::Gauge, labels, |v| *v = value);
+}
+
+/// Record `value` in a histogram with upper bounds `bounds` (ascending).
+pub fn observe(
+    name: &'static str,
+    help: &'static str,
+    bounds: &'static [f64],
+    labels: &[(&str, &str)],
+    value: f64,
+) {
+    REGISTRY.observe(name, help, bounds, labels, value);
+}
+
+/// Render all metrics in the Prometheus text exposition format.
+pub fn render() -> String {
+    REGISTRY.render()
+}
+
+/// Serve `GET /metrics` on `addr` until the process exits.
+pub async fn serve(addr: &str) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to bind metrics endpoint on {addr}"))?;
+    info!("Metrics endpoint listening on http://{}/metrics", addr);
+
+    tokio::spawn(async move {
+        loop {
+            let Ok((mut stream, peer)) = listener.accept().await else {
+                continue;
+            };
+            tokio::spawn(async move {
+                // Only the request line matters; scrapers send small requests.
+                let mut buf = [0u8; 1024];
+                let n = stream.read(&mut buf).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&buf[..n]);
+                let path = request.split_whitespace().nth(1).unwrap_or("");
+
+                let (status, body) = if request.starts_with("GET ") && path == "/metrics" {
+                    ("200 OK", render())
+                } else {
+                    ("404 Not Found", "not found\n".to_string())
+                };
+                let response = format!(
+                    "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                if let Err(e) = stream.write_all(response.as_bytes()).await {
+                    debug!("Metrics response to {} failed: {}", peer, e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_prometheus_text() {
+        let registry = Registry::default();
+        registry.update("agentman_test_total", "Test counter.", Kind::Counter, &[("outcome", "ok")], |v| *v += 2.0);
+        registry.update("agentman_test_total", "Test counter.", Kind::Counter, &[("outcome", "bad\"")], |v| *v += 1.0);
+        registry.update("agentman_test_gauge", "Test gauge.", Kind::Gauge, &[], |v| *v = 5.0);
+
+        let out = registry.render();
+        assert!(out.contains("# TYPE agentman_test_gauge gauge\nagentman_test_gauge 5\n"));
+        assert!(out.contains("# TYPE agentman_test_total counter\n"));
+        assert!(out.contains("agentman_test_total{outcome=\"ok\"} 2\n"));
+        assert!(out.contains("agentman_test_total{outcome=\"bad\\\"\"} 1\n"));
+    }
+
+    #[test]
+    fn test_render_histogram() {
+        let registry = Registry::default();
+        for value in [0.05, 0.5, 3.0] {
+            registry.observe("agentman_test_seconds", "Test histogram.", &[0.1, 1.0], &[("op", "x")], value);
+        }
+
+        let out = registry.render();
+        assert!(out.contains("# TYPE agentman_test_seconds histogram\n"));
+        assert!(out.contains("agentman_test_seconds_bucket{op=\"x\",le=\"0.1\"} 1\n"));
+        assert!(out.contains("agentman_test_seconds_bucket{op=\"x\",le=\"1\"} 2\n"));
+        assert!(out.contains("agentman_test_seconds_bucket{op=\"x\",le=\"+Inf\"} 3\n"));
+        assert!(out.contains("agentman_test_seconds_sum{op=\"x\"} 3.55\n"));
+        assert!(out.contains("agentman_test_seconds_count{op=\"x\"} 3\n"));
+    }
+}
@@ -0,0 +1,693 @@
+//! Moving workspaces between gateways (`agentman admin migrate`).
+//!
+//! The source gateway stops the sandbox (or checkpoints it, see [`crate::checkpoint`]), then
+//! connects to the target gateway's SSH listener as [`PEER_USER`], authenticating with its own
+//! host key, which the target must list in `[migration] nodes`. It runs
+//! `agentman-migrate receive <manifest>` there and streams the workspace directory into the
+//! channel as a gzipped tar archive. The target unpacks it next to its workspace root, moves it
+//! into place, records the workspace (init command, image, TLS port carry over) and creates the
+//! container, starting it from the checkpoint if there is one. Only once the target reports
+//! success does the source remove its container and files and remember where the workspace
+//! went, so that its users are pointed at the new gateway.
+//!
+//! Checkpoints are not copied: `[checkpoint] dir` must be storage both gateways see.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow, bail};
+use base64::Engine;
+use bollard::errors::Error as BollardError;
+use bollard::query_parameters::StopContainerOptionsBuilder;
+use chrono::Utc;
+use russh::ChannelMsg;
+use russh::client;
+use russh::keys::{PrivateKey, PrivateKeyWithHashAlg, PublicKey};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::checkpoint;
+use crate::config::{MigrationConfig, MigrationNode};
+use crate::docker::{ContainerManager, DestroyOptions};
+use crate::github::{
+    compute_fingerprint_from_pubkey, validate_github_username, validate_project_name,
+};
+use crate::ha;
+use crate::journal;
+use crate::metrics;
+use crate::state::{MigrationRecord, OperationKind, WorkspaceInfo, WorkspaceResources};
+
+/// SSH user gateways log in to each other as.
+pub const PEER_USER: &str = "agentman-migrate";
+
+/// Exec command prefix of an incoming migration.
+const RECEIVE_COMMAND: &str = "agentman-migrate receive";
+
+/// The gateway's host key, used to log in to other gateways.
+static HOST_KEY: OnceLock<PrivateKey> = OnceLock::new();
+
+/// Remember the host key for outgoing migrations (called once the SSH server has it).
+pub fn init(host_key: &PrivateKey) {
+    let _ = HOST_KEY.set(host_key.clone());
+}
+
+/// The gateway's public host key in OpenSSH format, once the SSH server has loaded it.
+pub fn host_public_key() -> Option<String> {
+    HOST_KEY.get()?.public_key().to_openssh().ok()
+}
+
+/// What the target needs to know besides the files.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub github_user: String,
+    pub project: String,
+    #[serde(default)]
+    pub init_command: Option<String>,
+    #[serde(default)]
+    pub image: Option<String>,
+    #[serde(default)]
+    pub tls_port: Option<u16>,
+    #[serde(default)]
+    pub resources: WorkspaceResources,
+    /// Checkpoint in the shared `[checkpoint] dir` to start the sandbox from.
+    #[serde(default)]
+    pub checkpoint: Option<String>,
+}
+
+impl Manifest {
+    /// The exec command that hands this manifest to the target.
+    fn to_command(&self) -> Result<String> {
+        let json = serde_json::to_vec(self).context("Failed to serialize manifest")?;
+        let encoded = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(json);
+        Ok(format!("{RECEIVE_COMMAND} {encoded}"))
+    }
+
+    /// The manifest of an incoming migration command; `None` for any other command.
+    pub fn from_command(command: &str) -> Option<Result<Self>> {
+        let encoded = command.trim().strip_prefix(RECEIVE_COMMAND)?.trim();
+        Some(
+            base64::engine::general_purpose::URL_SAFE_NO_PAD
+                .decode(encoded)
+                .context("Invalid migration manifest")
+                .and_then(|json| {
+                    serde_json::from_slice(&json).context("Invalid migration manifest")
+                }),
+        )
+    }
+}
+
+/// The node an incoming [`PEER_USER`] login with `key` comes from.
+pub fn peer_node<'a>(config: &'a MigrationConfig, key: &PublicKey) -> Option<&'a MigrationNode> {
+    let fingerprint = compute_fingerprint_from_pubkey(key);
+    config
+        .nodes
+        .iter()
+        .find(|node| node.host_key_fingerprint == fingerprint)
+}
+
+/// Move `github_user/project` to the gateway `node`; returns a report.
+pub async fn migrate(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    node: &str,
+    use_checkpoint: bool,
+    requested_by: &str,
+) -> Result<String> {
+    let result = try_migrate(
+        container_manager,
+        github_user,
+        project,
+        node,
+        use_checkpoint,
+        requested_by,
+    )
+    .await;
+    record("out", &result);
+    result
+}
+
+/// Forget the local copy of a workspace that lives on `node` now, and send its users there;
+/// returns a report.
+pub async fn remove_local_copy(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    node: &str,
+    requested_by: &str,
+) -> Result<String> {
+    let node = container_manager
+        .config()
+        .migration
+        .nodes
+        .iter()
+        .find(|n| n.name == node)
+        .ok_or_else(|| anyhow!("unknown node {node:?} (see [migration] nodes)"))?;
+    // Users are sent on even if removing the copy fails.
+    container_manager
+        .state()
+        .set_migration(
+            github_user,
+            project,
+            Some(MigrationRecord {
+                node: node.name.clone(),
+                address: node.public_address().to_string(),
+                migrated_at: Utc::now(),
+                requested_by: requested_by.to_string(),
+            }),
+        )
+        .await?;
+    let opts = DestroyOptions {
+        keep_workspace: false,
+        force: true,
+        dry_run: false,
+    };
+    let res = container_manager
+        .destroy_steps(github_user, project, opts)
+        .await
+        .with_context(|| {
+            format!(
+                "{github_user}/{project} lives on {} now, but removing the local copy failed \
+                 (resume it with `agentman admin operations`)",
+                node.name
+            )
+        })?;
+    Ok(res
+        .warnings
+        .iter()
+        .map(|w| format!("agentman: warning: {w}\n"))
+        .collect())
+}
+
+async fn try_migrate(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    node: &str,
+    use_checkpoint: bool,
+    requested_by: &str,
+) -> Result<String> {
+    let config = container_manager.config();
+    let node = config
+        .migration
+        .nodes
+        .iter()
+        .find(|n| n.name == node)
+        .ok_or_else(|| anyhow!("unknown node {node:?} (see [migration] nodes)"))?;
+    let key = HOST_KEY
+        .get()
+        .ok_or_else(|| anyhow!("the SSH server has not loaded its host key yet"))?;
+    if compute_fingerprint_from_pubkey(key.public_key()) == node.host_key_fingerprint {
+        bail!("{} is this gateway", node.name);
+    }
+    ha::ensure_active()?;
+    let ws = container_manager
+        .get_workspace(github_user, project)
+        .await
+        .ok_or_else(|| anyhow!("no workspace {github_user}/{project} on this gateway"))?;
+    let workspace_path = config.workspace_path(github_user, project);
+    if !workspace_path.is_dir() {
+        bail!(
+            "workspace directory {} does not exist",
+            workspace_path.display()
+        );
+    }
+
+    // Journaled until it has finished (see `journal`).
+    let state = container_manager.state();
+    let kind = OperationKind::Migrate {
+        node: node.name.clone(),
+    };
+    state
+        .begin_operation(github_user, project, kind, journal::STEP_STARTING)
+        .await?;
+    let result = migrate_steps(
+        container_manager,
+        github_user,
+        project,
+        node,
+        &ws,
+        use_checkpoint,
+        requested_by,
+    )
+    .await;
+    journal::end(state, github_user, project, &result).await;
+    result
+}
+
+/// The steps of a migration, under an operation already journaled.
+async fn migrate_steps(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    node: &MigrationNode,
+    ws: &WorkspaceInfo,
+    use_checkpoint: bool,
+    requested_by: &str,
+) -> Result<String> {
+    let config = container_manager.config();
+    let key = HOST_KEY
+        .get()
+        .ok_or_else(|| anyhow!("the SSH server has not loaded its host key yet"))?;
+    let workspace_path = config.workspace_path(github_user, project);
+
+    // The files must not change while they are copied.
+    let mut report = String::new();
+    let checkpoint = if use_checkpoint {
+        let c = checkpoint::create(container_manager, github_user, project, false)
+            .await
+            .context("Failed to checkpoint the sandbox")?;
+        report.push_str(&format!(
+            "agentman: checkpointed sandbox {project} as {}\n",
+            c.name
+        ));
+        Some(c.name)
+    } else {
+        stop(container_manager, &ws.container_name).await?;
+        None
+    };
+
+    let manifest = Manifest {
+        github_user: github_user.to_string(),
+        project: project.to_string(),
+        init_command: ws.init_command.clone(),
+        image: ws.image.clone(),
+        tls_port: ws.tls_port,
+        resources: ws.resources.clone(),
+        checkpoint,
+    };
+    let started = tokio::time::Instant::now();
+    let step = format!("sending to {}", node.name);
+    journal::step(container_manager.state(), github_user, project, &step).await;
+    let timeout = Duration::from_secs(config.migration.timeout_secs);
+    let sent = tokio::time::timeout(timeout, send(node, key, &manifest, &workspace_path))
+        .await
+        .map_err(|_| {
+            anyhow!(
+                "transfer to {} timed out after {}s",
+                node.name,
+                timeout.as_secs()
+            )
+        })
+        .and_then(|sent| sent)
+        .with_context(|| {
+            format!(
+                "Failed to migrate to {}; the workspace stays here, its sandbox stopped, until \
+                 the migration is resumed or rolled back (agentman admin operations)",
+                node.name
+            )
+        })?;
+    report.push_str(&sent);
+    info!(
+        "Migrated {}/{} to {} in {:.1}s",
+        github_user,
+        project,
+        node.name,
+        started.elapsed().as_secs_f64()
+    );
+
+    // The workspace lives on the node now.
+    let step = journal::STEP_REMOVING_LOCAL_COPY;
+    journal::step(container_manager.state(), github_user, project, step).await;
+    let removed =
+        remove_local_copy(container_manager, github_user, project, &node.name, requested_by)
+            .await?;
+    report.push_str(&removed);
+    report.push_str(&format!(
+        "agentman: migrated {github_user}/{project} to {} in {:.1}s; users connect to {} now\n",
+        node.name,
+        started.elapsed().as_secs_f64(),
+        node.public_address()
+    ));
+    Ok(report)
+}
+
+async fn stop(container_manager: &ContainerManager, container: &str) -> Result<()> {
+    match container_manager
+        .docker()
+        .stop_container(
+            container,
+            Some(StopContainerOptionsBuilder::new().t(10).build()),
+        )
+        .await
+    {
+        Ok(_)
+        | Err(BollardError::DockerResponseServerError {
+            status_code: 304 | 404,
+            ..
+        }) => Ok(()),
+        Err(e) => Err(e).context("Failed to stop the sandbox"),
+    }
+}
+
+/// Stream the workspace to `node`; returns the node's report.
+async fn send(
+    node: &MigrationNode,
+    key: &PrivateKey,
+    manifest: &Manifest,
+    workspace_path: &Path,
+) -> Result<String> {
+    let client_config = Arc::new(client::Config {
+        keepalive_interval: Some(Duration::from_secs(15)),
+        keepalive_max: 3,
+        ..Default::default()
+    });
+    let handler = PeerClient {
+        expected_fingerprint: node.host_key_fingerprint.clone(),
+    };
+    let mut handle = client::connect(client_config, node.address.as_str(), handler)
+        .await
+        .with_context(|| format!("Failed to connect to {} ({})", node.name, node.address))?;
+    let hash_alg = handle.best_supported_rsa_hash().await?.flatten();
+    let auth = handle
+        .authenticate_publickey(
+            PEER_USER,
+            PrivateKeyWithHashAlg::new(Arc::new(key.clone()), hash_alg),
+        )
+        .await?;
+    if !auth.success() {
+        bail!(
+            "{} did not accept this gateway's host key (is this gateway in its [migration] nodes?)",
+            node.name
+        );
+    }
+
+    let channel = handle.channel_open_session().await?;
+    channel.exec(true, manifest.to_command()?).await?;
+    let mut tar = Command::new("tar")
+        .args(["-czf", "-", "--numeric-owner", "-C"])
+        .arg(workspace_path)
+        .arg(".")
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .context("Failed to run tar (is it installed?)")?;
+    let archive = tar.stdout.take().context("tar has no stdout")?;
+
+    let (mut read, write) = channel.split();
+    let upload = async {
+        write.data(archive).await?;
+        write.eof().await?;
+        anyhow::Ok(())
+    };
+    let replies = async {
+        let mut output = String::new();
+        let mut exit_status = None;
+        while let Some(msg) = read.wait().await {
+            match msg {
+                ChannelMsg::Data { data } | ChannelMsg::ExtendedData { data, .. } => {
+                    output.push_str(&String::from_utf8_lossy(&data));
+                }
+                ChannelMsg::ExitStatus {
+                    exit_status: status,
+                } => exit_status = Some(status),
+                ChannelMsg::Close => break,
+                _ => {}
+            }
+        }
+        (exit_status, output)
+    };
+    let (uploaded, (exit_status, output)) = tokio::join!(upload, replies);
+    let tar = tar.wait_with_output().await?;
+    if !tar.status.success() {
+        bail!(
+            "tar exited with {}: {}",
+            tar.status,
+            String::from_utf8_lossy(&tar.stderr).trim()
+        );
+    }
+    match exit_status {
+        Some(0) => Ok(output),
+        // Its report says why; a failed upload is a consequence.
+        Some(_) => bail!(
+            "{} refused the workspace:\n{}",
+            node.name,
+            output.trim_end()
+        ),
+        None => {
+            uploaded?;
+            bail!("{} closed the connection without a result", node.name)
+        }
+    }
+}
+
+struct PeerClient {
+    expected_fingerprint: String,
+}
+
+impl client::Handler for PeerClient {
+    type Error = anyhow::Error;
+
+    async fn check_server_key(
+        &mut self,
+        server_public_key: &PublicKey,
+    ) -> Result<bool, Self::Error> {
+        let fingerprint = compute_fingerprint_from_pubkey(server_public_key);
+        if fingerprint != self.expected_fingerprint {
+            warn!(
+                "Migration target host key mismatch: expected {}, got {}",
+                self.expected_fingerprint, fingerprint
+            );
+            return Ok(false);
+        }
+        Ok(true)
+    }
+}
+
+/// Take in a workspace sent by the gateway `from`, reading the archive from `upload` until it
+/// is closed; returns a report.
+pub async fn receive(
+    container_manager: &ContainerManager,
+    from: &str,
+    manifest: Manifest,
+    upload: mpsc::Receiver<Vec<u8>>,
+) -> Result<String> {
+    let result = try_receive(container_manager, from, manifest, upload).await;
+    record("in", &result);
+    result
+}
+
+async fn try_receive(
+    container_manager: &ContainerManager,
+    from: &str,
+    manifest: Manifest,
+    upload: mpsc::Receiver<Vec<u8>>,
+) -> Result<String> {
+    let config = container_manager.config();
+    let Manifest {
+        ref github_user,
+        ref project,
+        ..
+    } = manifest;
+    validate_github_username(github_user)?;
+    validate_project_name(project)?;
+    ha::ensure_active()?;
+    if container_manager
+        .get_workspace(github_user, project)
+        .await
+        .is_some()
+    {
+        bail!("{github_user}/{project} already exists on this gateway");
+    }
+    let workspace_path = config.workspace_path(github_user, project);
+    if tokio::fs::try_exists(&workspace_path).await.unwrap_or(true) {
+        bail!(
+            "{} already exists on this gateway",
+            workspace_path.display()
+        );
+    }
+    if let Some(ref name) = manifest.checkpoint {
+        let dir = checkpoint::workspace_dir(&config.checkpoint, github_user, project);
+        if !config.checkpoint.enabled || !dir.join(name).is_dir() {
+            bail!(
+                "checkpoint {name} is not in {} (checkpoints need to be enabled, with a dir \
+                 shared between the gateways)",
+                dir.display()
+            );
+        }
+    }
+
+    info!("Receiving {}/{} from {}", github_user, project, from);
+    let staging = staging_path(&workspace_path);
+    if tokio::fs::try_exists(&staging).await.unwrap_or(false) {
+        tokio::fs::remove_dir_all(&staging)
+            .await
+            .with_context(|| format!("Failed to remove {}", staging.display()))?;
+    }
+    tokio::fs::create_dir_all(&staging)
+        .await
+        .with_context(|| format!("Failed to create {}", staging.display()))?;
+    let bytes = match unpack(upload, &staging).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            let _ = tokio::fs::remove_dir_all(&staging).await;
+            return Err(e);
+        }
+    };
+    tokio::fs::rename(&staging, &workspace_path)
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to move the workspace to {}",
+                workspace_path.display()
+            )
+        })?;
+    metrics::add(
+        "agentman_migration_received_bytes_total",
+        "Compressed workspace bytes received from other gateways.",
+        &[],
+        bytes as f64,
+    );
+
+    container_manager
+        .state()
+        .set_workspace(WorkspaceInfo {
+            github_user: github_user.clone(),
+            project: project.clone(),
+            container_name: config.container_name(github_user, project),
+            container_id: None,
+            created_at: Utc::now(),
+            host_workspace_path: workspace_path,
+            linked_projects: Vec::new(),
+            init_command: manifest.init_command.clone(),
+            init_status: None,
+            image: manifest.image.clone(),
+            tls_port: manifest.tls_port,
+            watch_ownership: false,
+            clone: None,
+            resources: manifest.resources.clone(),
+        })
+        .await?;
+    // It may be coming back.
+    container_manager
+        .state()
+        .set_migration(github_user, project, None)
+        .await?;
+
+    let mut report = format!(
+        "agentman: {github_user}/{project} received ({:.1} MiB compressed)\n",
+        bytes as f64 / (1024.0 * 1024.0)
+    );
+    if manifest.checkpoint.is_some() {
+        let resumed = match container_manager
+            .create_stopped_container(github_user, project)
+            .await
+        {
+            Ok(_) => checkpoint::resume(container_manager, github_user, project).await,
+            Err(e) => Err(e),
+        };
+        match resumed {
+            Ok(c) => {
+                report.push_str(&format!(
+                    "agentman: resumed sandbox from checkpoint {}\n",
+                    c.name
+                ));
+                return Ok(report);
+            }
+            // The files made it; only the processes are lost.
+            Err(e) => report.push_str(&format!(
+                "agentman: warning: resuming from the checkpoint failed, starting the sandbox \
+                 fresh: {e:#}\n"
+            )),
+        }
+    }
+    container_manager
+        .get_or_create_container(github_user, project)
+        .await
+        .context("Failed to create the sandbox")?;
+    report.push_str("agentman: sandbox created\n");
+    Ok(report)
+}
+
+/// Where an incoming workspace is unpacked before it is moved into place.
+fn staging_path(workspace_path: &Path) -> PathBuf {
+    let name = workspace_path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    workspace_path.with_file_name(format!(".{name}.migrating"))
+}
+
+/// Extract the archive arriving on `upload` into `dir`; returns its size.
+async fn unpack(mut upload: mpsc::Receiver<Vec<u8>>, dir: &Path) -> Result<u64> {
+    let mut tar = Command::new("tar")
+        .args(["-xzf", "-", "--numeric-owner", "-C"])
+        .arg(dir)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .context("Failed to run tar (is it installed?)")?;
+    let mut stdin = tar.stdin.take().context("tar has no stdin")?;
+    let mut bytes = 0u64;
+    while let Some(chunk) = upload.recv().await {
+        bytes += chunk.len() as u64;
+        // tar exiting early is reported below.
+        if stdin.write_all(&chunk).await.is_err() {
+            break;
+        }
+    }
+    drop(stdin);
+    let output = tar.wait_with_output().await?;
+    if !output.status.success() {
+        bail!(
+            "tar exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(bytes)
+}
+
+fn record<T>(direction: &str, result: &Result<T>) {
+    metrics::inc(
+        "agentman_migrations_total",
+        "Workspaces migrated to (out) or from (in) other gateways, by outcome.",
+        &[
+            ("direction", direction),
+            ("outcome", if result.is_ok() { "ok" } else { "failed" }),
+        ],
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_command_roundtrip() {
+        let manifest = Manifest {
+            github_user: "octocat".to_string(),
+            project: "web".to_string(),
+            init_command: Some("npm install && echo 'done'".to_string()),
+            image: None,
+            tls_port: Some(8443),
+            resources: WorkspaceResources {
+                shm_size: Some("2g".to_string()),
+                ..Default::default()
+            },
+            checkpoint: Some("20261015T120000Z".to_string()),
+        };
+        let command = manifest.to_command().unwrap();
+        assert!(command.starts_with("agentman-migrate receive "));
+        assert!(!command["agentman-migrate receive ".len()..].contains(' '));
+        assert_eq!(Manifest::from_command(&command).unwrap().unwrap(), manifest);
+
+        assert!(Manifest::from_command("agentman list").is_none());
+        assert!(
+            Manifest::from_command("agentman-migrate receive !!")
+                .unwrap()
+                .is_err()
+        );
+        assert_eq!(
+            staging_path(Path::new("/srv/workspaces/octocat/web")),
+            PathBuf::from("/srv/workspaces/octocat/.web.migrating")
+        );
+    }
+}
@@ -0,0 +1,132 @@
+//! Per-project login messages (`agentman motd`).
+//!
+//! A project's owner sets a message that everyone opening a terminal on the project sees
+//! before their shell starts, guests and admins with access included. The message is kept in
+//! the gateway state as typed and rendered for the terminal with a little markdown:
+//! `**bold**`, `*italic*` or `_italic_`, `` `code` ``, `# headings` and `- ` bullets, with a
+//! literal `\n` starting a new line (the message arrives as one command line). Control
+//! characters are refused, so a message can't drive the collaborators' terminals.
+
+use anyhow::{Result, bail};
+
+/// Longest message, in characters.
+pub const MAX_CHARS: usize = 1000;
+
+/// Most lines a message may render to.
+pub const MAX_LINES: usize = 20;
+
+const BOLD: &str = "\x1b[1m";
+const ITALIC: &str = "\x1b[3m";
+const CODE: &str = "\x1b[36m";
+const HEADING: &str = "\x1b[1;4m";
+const RESET: &str = "\x1b[0m";
+
+/// `text` as stored: trimmed, and checked for length and control characters.
+pub fn normalize(text: &str) -> Result<String> {
+    let text = text.trim();
+    if text.is_empty() {
+        bail!("the message is empty; use `agentman motd clear` to remove it");
+    }
+    if text.chars().count() > MAX_CHARS {
+        bail!("the message is longer than {MAX_CHARS} characters");
+    }
+    if text.chars().any(char::is_control) {
+        bail!("the message can't contain control characters");
+    }
+    if lines(text).count() > MAX_LINES {
+        bail!("the message has more than {MAX_LINES} lines");
+    }
+    Ok(text.to_string())
+}
+
+fn lines(text: &str) -> impl Iterator<Item = &str> {
+    text.split("\\n").map(str::trim)
+}
+
+/// `text` for a terminal, one string per line (without line endings).
+pub fn render(text: &str) -> Vec<String> {
+    lines(text)
+        .map(|line| {
+            let heading = line.trim_start_matches('#');
+            let bullet = line.strip_prefix("- ").or_else(|| line.strip_prefix("* "));
+            if heading.len() < line.len() && heading.starts_with(' ') {
+                format!("{HEADING}{}{RESET}", strip_markup(heading.trim()))
+            } else if let Some(item) = bullet {
+                format!("  • {}", inline(item))
+            } else {
+                inline(line)
+            }
+        })
+        .collect()
+}
+
+/// Inline markup: `**bold**`, `*italic*`, `_italic_` and `` `code` ``. A span's text can't
+/// start or end with a space, and `*`/`_` don't open one inside a word (`snake_case`), so
+/// stray markers are kept as typed.
+fn inline(line: &str) -> String {
+    let mut out = String::new();
+    let mut rest = line;
+    let mut prev: Option<char> = None;
+    while let Some(c) = rest.chars().next() {
+        let in_word = prev.is_some_and(char::is_alphanumeric);
+        let styled = [("`", CODE), ("**", BOLD), ("*", ITALIC), ("_", ITALIC)]
+            .into_iter()
+            .filter(|(marker, _)| *marker == "`" || !in_word)
+            .find_map(|(marker, style)| {
+                let after = rest.strip_prefix(marker)?;
+                let inner = &after[..after.find(marker)?];
+                let padded = inner.starts_with(' ') || inner.ends_with(' ');
+                (!inner.is_empty() && !padded).then_some((marker, style, inner))
+            });
+        match styled {
+            Some((marker, style, inner)) => {
+                // Code is shown as is; other spans may hold code or further markup.
+                let text = if marker == "`" { inner.to_string() } else { inline(inner) };
+                out.push_str(&format!("{style}{text}{RESET}"));
+                rest = &rest[2 * marker.len() + inner.len()..];
+                prev = marker.chars().last();
+            }
+            None => {
+                out.push(c);
+                rest = &rest[c.len_utf8()..];
+                prev = Some(c);
+            }
+        }
+    }
+    out
+}
+
+/// `text` without inline markers, for headings (which have a style of their own).
+fn strip_markup(text: &str) -> String {
+    text.replace("**", "").replace('`', "")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("  staging DB is at db:5432 ").unwrap(), "staging DB is at db:5432");
+        assert!(normalize("   ").is_err());
+        assert!(normalize("evil \x1b]0;title\x07").is_err());
+        assert!(normalize(&"x".repeat(MAX_CHARS + 1)).is_err());
+        assert!(normalize(&"line\\n".repeat(MAX_LINES)).is_err());
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!(
+            render("# Staging\\n- DB at `db:5432`\\n**do not** run _migrations_"),
+            vec![
+                format!("{HEADING}Staging{RESET}"),
+                format!("  • DB at {CODE}db:5432{RESET}"),
+                format!("{BOLD}do not{RESET} run {ITALIC}migrations{RESET}"),
+            ]
+        );
+        // Unclosed markers and snake_case words stay as typed.
+        assert_eq!(render("2 * 3 * 4, see my_file_name"), vec!["2 * 3 * 4, see my_file_name"]);
+        assert_eq!(render("`a*b*c`"), vec![format!("{CODE}a*b*c{RESET}")]);
+        assert_eq!(render("#hashtag"), vec!["#hashtag"]);
+    }
+}
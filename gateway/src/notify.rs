@@ -0,0 +1,216 @@
+//! Admin notifications.
+//!
+//! Events admins should hear about (users flagged for offboarding, workspaces archived, ...)
+//! are always logged, and POSTed as JSON to `[notify] webhook_url` when one is configured.
+//! Delivery is best effort: a failed POST is logged and counted, never retried.
+//!
+//! Background tasks report each run with [`task_failed`] / [`task_succeeded`]. Admins are
+//! alerted once a task has failed `failure_threshold` times in a row, then at most once per
+//! `failure_cooldown_secs` while it keeps failing, and once more when it recovers.
+
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, anyhow};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::config::NotifyConfig;
+use crate::metrics;
+
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    /// Human-readable message (the field Slack/Mattermost webhooks display).
+    text: &'a str,
+    /// Machine-readable event name, e.g. `offboarding.flagged`.
+    event: &'a str,
+}
+
+/// Notify admins about `event`.
+pub async fn send(config: &NotifyConfig, event: &str, text: &str) {
+    info!("Admin notification ({}): {}", event, text);
+    let Some(ref url) = config.webhook_url else {
+        return;
+    };
+    let outcome = match post(url, &Payload { text, event }).await {
+        Ok(()) => "sent",
+        Err(e) => {
+            warn!("Failed to deliver admin notification ({}): {:#}", event, e);
+            "failed"
+        }
+    };
+    metrics::inc(
+        "agentman_notifications_total",
+        "Admin notifications posted to the webhook, by outcome.",
+        &[("outcome", outcome)],
+    );
+}
+
+/// Failures of a background task since its last success.
+#[derive(Debug)]
+struct FailureRun {
+    count: u32,
+    since: DateTime<Utc>,
+    /// Last alert about this run (unset: not alerted yet).
+    notified_at: Option<Instant>,
+    /// Failures since that alert.
+    unreported: u32,
+}
+
+impl FailureRun {
+    fn new(since: DateTime<Utc>) -> Self {
+        Self {
+            count: 0,
+            since,
+            notified_at: None,
+            unreported: 0,
+        }
+    }
+
+    /// Record a failure; returns the alert text when admins should hear about it.
+    fn fail(
+        &mut self,
+        config: &NotifyConfig,
+        task: &str,
+        error: &str,
+        now: Instant,
+    ) -> Option<String> {
+        self.count += 1;
+        if self.count < config.failure_threshold {
+            return None;
+        }
+        let cooldown = Duration::from_secs(config.failure_cooldown_secs);
+        if self
+            .notified_at
+            .is_some_and(|at| now.duration_since(at) < cooldown)
+        {
+            self.unreported += 1;
+            return None;
+        }
+        let mut text = format!(
+            "Background task {task} has failed {} time(s) in a row since {}: {error}",
+            self.count,
+            self.since.format("%Y-%m-%d %H:%M UTC")
+        );
+        if self.unreported > 0 {
+            text.push_str(&format!(
+                " ({} failure(s) since the last alert were not reported)",
+                self.unreported
+            ));
+        }
+        self.notified_at = Some(now);
+        self.unreported = 0;
+        Some(text)
+    }
+}
+
+static FAILURES: LazyLock<Mutex<HashMap<String, FailureRun>>> = LazyLock::new(Default::default);
+
+/// Report a failed run of a background task (the caller still logs it).
+pub async fn task_failed(config: &NotifyConfig, task: &str, error: &str) {
+    metrics::inc(
+        "agentman_background_failures_total",
+        "Failed runs of background tasks, by task.",
+        &[("task", task)],
+    );
+    let text = FAILURES
+        .lock()
+        .unwrap()
+        .entry(task.to_string())
+        .or_insert_with(|| FailureRun::new(Utc::now()))
+        .fail(config, task, error, Instant::now());
+    if let Some(text) = text {
+        send(config, "task.failed", &text).await;
+    }
+}
+
+/// Report a successful run of a background task, ending any failure streak.
+pub async fn task_succeeded(config: &NotifyConfig, task: &str) {
+    let Some(run) = FAILURES.lock().unwrap().remove(task) else {
+        return;
+    };
+    if run.notified_at.is_some() {
+        let text = format!(
+            "Background task {task} recovered after {} failure(s) since {}",
+            run.count,
+            run.since.format("%Y-%m-%d %H:%M UTC")
+        );
+        send(config, "task.recovered", &text).await;
+    }
+}
+
+async fn post(url: &str, payload: &Payload<'_>) -> Result<()> {
+    let client = reqwest::Client::builder()
+        .user_agent("agentman-gateway/0.1")
+        .timeout(Duration::from_secs(10))
+        .build()
+        .context("Failed to create HTTP client")?;
+    let response = client
+        .post(url)
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_vec(payload)?)
+        .send()
+        .await
+        .context("Failed to POST to webhook")?;
+    if !response.status().is_success() {
+        return Err(anyhow!("webhook returned {}", response.status()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failure_policy() {
+        let config = NotifyConfig {
+            failure_threshold: 2,
+            failure_cooldown_secs: 60,
+            ..NotifyConfig::default()
+        };
+        let start = Instant::now();
+        let mut run = FailureRun::new(Utc::now());
+
+        assert!(run.fail(&config, "backup", "disk full", start).is_none());
+        let alert = run.fail(&config, "backup", "disk full", start).unwrap();
+        assert!(alert.contains("failed 2 time(s)"), "{alert}");
+
+        // Within the cooldown: counted, not sent.
+        assert!(
+            run.fail(
+                &config,
+                "backup",
+                "disk full",
+                start + Duration::from_secs(30)
+            )
+            .is_none()
+        );
+        assert!(
+            run.fail(
+                &config,
+                "backup",
+                "disk full",
+                start + Duration::from_secs(59)
+            )
+            .is_none()
+        );
+
+        let alert = run
+            .fail(
+                &config,
+                "backup",
+                "disk full",
+                start + Duration::from_secs(60),
+            )
+            .unwrap();
+        assert!(alert.contains("failed 5 time(s)"), "{alert}");
+        assert!(
+            alert.contains("2 failure(s) since the last alert"),
+            "{alert}"
+        );
+        assert_eq!(run.unreported, 0);
+    }
+}
@@ -0,0 +1,548 @@
+//! Offboarding of inactive GitHub users (see [`OffboardingConfig`]).
+//!
+//! A background task runs the policy every `check_interval_secs`:
+//! 1. flag users with no login for `inactive_days`
+//! 2. `archive_after_days` after the flag: stop their containers, write a `.tar.gz` of each
+//!    workspace to `archive_dir`, and revoke their cached keys
+//! 3. `destroy_after_days` after the flag: destroy their containers and workspaces
+//!
+//! Every stage is audited (`offboard` events) and announced to admins via [`notify`]. Progress is
+//! kept in the state file, so a restart picks up where it left off, and a login before the
+//! destroy stage lifts the flag. `agentman admin offboard <user>` starts the workflow by hand:
+//! archiving happens right away, the destroy stage keeps its grace period, and the user's
+//! logins are refused until an admin cancels it.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use bollard::errors::Error as BollardError;
+use bollard::query_parameters::StopContainerOptionsBuilder;
+use chrono::{DateTime, Utc};
+use tokio::process::Command;
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+use crate::audit::{AuditEvent, AuditEventKind, AuditLogger, AuditOutcome};
+use crate::config::OffboardingConfig;
+use crate::docker::{ContainerManager, DestroyOptions};
+use crate::notify;
+use crate::stack;
+use crate::state::OffboardingRecord;
+
+/// Archive file names (sortable, filesystem-safe).
+const ARCHIVE_TIME_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// Wakes the policy task early (after a manual trigger).
+static WAKE: Notify = Notify::const_new();
+
+/// The next stage due for a flagged user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    Archive,
+    Destroy,
+}
+
+fn days(n: u64) -> chrono::Duration {
+    chrono::Duration::days(i64::try_from(n).unwrap_or(i64::MAX / 86_400_000))
+}
+
+fn next_stage(
+    config: &OffboardingConfig,
+    record: &OffboardingRecord,
+    now: DateTime<Utc>,
+) -> Option<Stage> {
+    if record.destroyed_at.is_some() {
+        return None;
+    }
+    if record.archived_at.is_none() {
+        let due = record.requested_by.is_some()
+            || now >= record.flagged_at + days(config.archive_after_days);
+        return due.then_some(Stage::Archive);
+    }
+    let destroy_after = config.destroy_after_days?;
+    (now >= record.flagged_at + days(destroy_after)).then_some(Stage::Destroy)
+}
+
+/// Run the offboarding policy in the background.
+///
+/// The task always runs so `agentman admin offboard` works; `enabled` only controls whether
+/// inactive users are flagged automatically.
+pub fn spawn(container_manager: Arc<ContainerManager>, audit: Arc<AuditLogger>) {
+    let config = &container_manager.config().offboarding;
+    let interval = Duration::from_secs(config.check_interval_secs.max(60));
+    if config.enabled {
+        info!(
+            "Offboarding users inactive for {} days (checked every {}s)",
+            config.inactive_days,
+            interval.as_secs()
+        );
+    }
+
+    tokio::spawn(async move {
+        loop {
+            let notify_config = &container_manager.config().notify;
+            match run_once(&container_manager, &audit, Utc::now()).await {
+                Ok(()) => notify::task_succeeded(notify_config, "offboarding").await,
+                Err(e) => {
+                    warn!("Offboarding check failed: {:#}", e);
+                    notify::task_failed(notify_config, "offboarding", &format!("{e:#}")).await;
+                }
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = WAKE.notified() => {}
+            }
+        }
+    });
+}
+
+async fn run_once(
+    container_manager: &ContainerManager,
+    audit: &AuditLogger,
+    now: DateTime<Utc>,
+) -> Result<()> {
+    let gateway_config = container_manager.config();
+    let config = &gateway_config.offboarding;
+    let state = container_manager.state();
+
+    if config.enabled {
+        let users = state.known_github_users().await;
+        state.seed_last_login(&users, now).await?;
+        for user in users {
+            if gateway_config.is_admin(&user) || state.offboarding(&user).await.is_some() {
+                continue;
+            }
+            let Some(last_login) = state.last_login(&user).await else {
+                continue;
+            };
+            if now < last_login + days(config.inactive_days) {
+                continue;
+            }
+            let record = OffboardingRecord {
+                flagged_at: now,
+                requested_by: None,
+                archived_at: None,
+                keys_revoked_at: None,
+                destroyed_at: None,
+            };
+            state.set_offboarding(&user, record.clone()).await?;
+            let detail = format!("flagged: no login since {}", last_login.format("%Y-%m-%d"));
+            record_event(audit, &user, AuditOutcome::Success, &detail);
+            notify::send(
+                &gateway_config.notify,
+                "offboarding.flagged",
+                &format!("agentman: {user} {detail}; {}", schedule(config, &record)),
+            )
+            .await;
+        }
+    }
+
+    for (user, record) in state.list_offboarding().await {
+        let Some(stage) = next_stage(config, &record, now) else {
+            continue;
+        };
+        let (event, result) = match stage {
+            Stage::Archive => (
+                "offboarding.archived",
+                archive_stage(container_manager, &user, record, now).await,
+            ),
+            Stage::Destroy => (
+                "offboarding.destroyed",
+                destroy_stage(container_manager, &user, record, now).await,
+            ),
+        };
+        let (outcome, detail) = match result {
+            Ok(detail) => (AuditOutcome::Success, detail),
+            Err(e) => (
+                AuditOutcome::Failure,
+                format!("{event} failed (will retry): {e:#}"),
+            ),
+        };
+        record_event(audit, &user, outcome, &detail);
+        notify::send(
+            &gateway_config.notify,
+            event,
+            &format!("agentman: {user} {detail}"),
+        )
+        .await;
+    }
+    Ok(())
+}
+
+/// Stop containers, archive workspaces and revoke keys. Returns an audit detail.
+async fn archive_stage(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    mut record: OffboardingRecord,
+    now: DateTime<Utc>,
+) -> Result<String> {
+    let config = &container_manager.config().offboarding;
+    let state = container_manager.state();
+    let workspaces = state.list_workspaces(github_user).await;
+
+    let mut archives = Vec::new();
+    for ws in &workspaces {
+        stop_container(container_manager, &ws.container_name).await?;
+        // Sidecars come back with the sandbox; their volumes stay.
+        if let Err(e) = stack::down(container_manager, github_user, &ws.project).await {
+            warn!("Failed to take down stack of {}/{}: {:#}", github_user, ws.project, e);
+        }
+        if config.archive {
+            let path = container_manager
+                .config()
+                .workspace_path(github_user, &ws.project);
+            if let Some(archive) =
+                archive_workspace(&config.archive_dir, github_user, &ws.project, &path, now).await?
+            {
+                archives.push(archive);
+            }
+        }
+    }
+
+    let mut detail = format!("archived: stopped {} sandbox(es)", workspaces.len());
+    if config.archive {
+        detail.push_str(&format!(
+            ", wrote {} archive(s) to {}",
+            archives.len(),
+            config.archive_dir.join(github_user).display()
+        ));
+    }
+    if config.revoke_keys {
+        let revoked = state.revoke_keys(github_user).await?;
+        record.keys_revoked_at = Some(now);
+        detail.push_str(&format!(", revoked {revoked} cached key(s)"));
+    }
+    record.archived_at = Some(now);
+    // A login while archiving lifts an inactivity flag; don't bring it back.
+    if state.offboarding(github_user).await.is_some() {
+        state.set_offboarding(github_user, record).await?;
+    }
+    Ok(detail)
+}
+
+/// Destroy every container and workspace of the user. Returns an audit detail.
+async fn destroy_stage(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    mut record: OffboardingRecord,
+    now: DateTime<Utc>,
+) -> Result<String> {
+    let state = container_manager.state();
+    let workspaces = state.list_workspaces(github_user).await;
+    let mut warnings = Vec::new();
+    for ws in &workspaces {
+        let result = container_manager
+            .destroy_workspace(
+                github_user,
+                &ws.project,
+                DestroyOptions {
+                    keep_workspace: false,
+                    force: false,
+                    dry_run: false,
+                },
+            )
+            .await?;
+        warnings.extend(result.warnings);
+    }
+    for warning in &warnings {
+        warn!("Offboarding {}: {}", github_user, warning);
+    }
+
+    if record.requested_by.is_some() {
+        // Keep the record so logins stay refused until an admin cancels it.
+        record.destroyed_at = Some(now);
+        state.set_offboarding(github_user, record).await?;
+    } else {
+        state.remove_offboarding(github_user, true).await?;
+    }
+    Ok(format!(
+        "destroyed: removed {} workspace(s){}",
+        workspaces.len(),
+        if warnings.is_empty() {
+            String::new()
+        } else {
+            format!(" ({} warning(s), see gateway log)", warnings.len())
+        }
+    ))
+}
+
+async fn stop_container(container_manager: &ContainerManager, container: &str) -> Result<()> {
+    match container_manager
+        .docker()
+        .stop_container(
+            container,
+            Some(StopContainerOptionsBuilder::new().t(10).build()),
+        )
+        .await
+    {
+        // 304: already stopped; 404: no container (never started, or removed by hand).
+        Ok(_)
+        | Err(BollardError::DockerResponseServerError {
+            status_code: 304 | 404,
+            ..
+        }) => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("Failed to stop {container}")),
+    }
+}
+
+/// Write `<dir>/<user>/<project>-<time>.tar.gz`. Returns `None` when the workspace has no
+/// directory on disk.
+async fn archive_workspace(
+    dir: &Path,
+    github_user: &str,
+    project: &str,
+    workspace_path: &Path,
+    now: DateTime<Utc>,
+) -> Result<Option<PathBuf>> {
+    let (Some(parent), Some(name)) = (workspace_path.parent(), workspace_path.file_name()) else {
+        bail!("invalid workspace path {}", workspace_path.display());
+    };
+    if !workspace_path.is_dir() {
+        return Ok(None);
+    }
+
+    let user_dir = dir.join(github_user);
+    tokio::fs::create_dir_all(&user_dir)
+        .await
+        .with_context(|| format!("Failed to create {}", user_dir.display()))?;
+    let archive = user_dir.join(format!(
+        "{project}-{}.tar.gz",
+        now.format(ARCHIVE_TIME_FORMAT)
+    ));
+    // Written under a temporary name so an interrupted run never leaves a truncated archive
+    // that looks complete.
+    let partial = archive.with_extension("gz.partial");
+
+    let output = Command::new("tar")
+        .arg("-czf")
+        .arg(&partial)
+        .arg("-C")
+        .arg(parent)
+        .arg("--")
+        .arg(name)
+        .output()
+        .await
+        .context("Failed to run tar")?;
+    if !output.status.success() {
+        let _ = tokio::fs::remove_file(&partial).await;
+        bail!(
+            "tar of {} failed: {}",
+            workspace_path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    // Workspaces hold credentials as often as not.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        tokio::fs::set_permissions(&partial, std::fs::Permissions::from_mode(0o600)).await?;
+    }
+    tokio::fs::rename(&partial, &archive).await?;
+    info!(
+        "Archived {}/{} to {}",
+        github_user,
+        project,
+        archive.display()
+    );
+    Ok(Some(archive))
+}
+
+fn record_event(audit: &AuditLogger, github_user: &str, outcome: AuditOutcome, detail: &str) {
+    audit.record(
+        AuditEvent::new(AuditEventKind::Offboard, outcome)
+            .github_user(Some(github_user))
+            .detail(detail),
+    );
+}
+
+/// When the remaining stages are due, e.g. "archive on 2026-10-22, destroy on 2026-11-14".
+fn schedule(config: &OffboardingConfig, record: &OffboardingRecord) -> String {
+    let archive = match record.archived_at {
+        Some(at) => format!("archived {}", at.format("%Y-%m-%d")),
+        None if record.requested_by.is_some() => "archive now".to_string(),
+        None => format!(
+            "archive on {}",
+            (record.flagged_at + days(config.archive_after_days)).format("%Y-%m-%d")
+        ),
+    };
+    let destroy = match (record.destroyed_at, config.destroy_after_days) {
+        (Some(at), _) => format!("destroyed {}", at.format("%Y-%m-%d")),
+        (None, Some(n)) => format!(
+            "destroy on {}",
+            (record.flagged_at + days(n)).format("%Y-%m-%d")
+        ),
+        (None, None) => "never destroyed".to_string(),
+    };
+    format!("{archive}, {destroy}")
+}
+
+/// `agentman admin offboard <user>`: start (or take over) offboarding of `github_user`.
+pub async fn start_manual(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    admin: &str,
+) -> Result<String> {
+    let gateway_config = container_manager.config();
+    let state = container_manager.state();
+    let record = match state.offboarding(github_user).await {
+        Some(existing) if existing.requested_by.is_some() => {
+            return Ok(format!(
+                "agentman: {github_user} is already being offboarded ({})\n",
+                schedule(&gateway_config.offboarding, &existing)
+            ));
+        }
+        // Taking over an inactivity flag keeps the stages already done.
+        Some(existing) => OffboardingRecord {
+            requested_by: Some(admin.to_string()),
+            ..existing
+        },
+        None => OffboardingRecord {
+            flagged_at: Utc::now(),
+            requested_by: Some(admin.to_string()),
+            archived_at: None,
+            keys_revoked_at: None,
+            destroyed_at: None,
+        },
+    };
+    state.set_offboarding(github_user, record.clone()).await?;
+    WAKE.notify_one();
+
+    let plan = schedule(&gateway_config.offboarding, &record);
+    notify::send(
+        &gateway_config.notify,
+        "offboarding.flagged",
+        &format!("agentman: {github_user} offboarded by {admin}; {plan}"),
+    )
+    .await;
+    Ok(format!(
+        "agentman: offboarding {github_user} ({plan}); their logins are refused until `agentman admin offboard {github_user} --cancel`\n"
+    ))
+}
+
+/// `agentman admin offboard <user> --cancel`.
+pub async fn cancel(container_manager: &ContainerManager, github_user: &str) -> Result<String> {
+    Ok(
+        match container_manager
+            .state()
+            .remove_offboarding(github_user, false)
+            .await?
+        {
+            Some(record) if record.destroyed_at.is_some() => format!(
+                "agentman: cancelled offboarding of {github_user}; their workspaces were already destroyed\n"
+            ),
+            Some(_) => format!("agentman: cancelled offboarding of {github_user}\n"),
+            None => format!("agentman: {github_user} is not being offboarded\n"),
+        },
+    )
+}
+
+/// `agentman admin offboard`: every user being offboarded and what's next for them.
+pub async fn format_status(container_manager: &ContainerManager) -> String {
+    let config = &container_manager.config().offboarding;
+    let records = container_manager.state().list_offboarding().await;
+    let mut out = format!(
+        "Automatic offboarding: {}\n",
+        if config.enabled {
+            format!("after {} days without a login", config.inactive_days)
+        } else {
+            "disabled".to_string()
+        }
+    );
+    if records.is_empty() {
+        out.push_str("No users are being offboarded.\n");
+    }
+    for (user, record) in records {
+        let reason = match record.requested_by {
+            Some(ref admin) => format!("by {admin}"),
+            None => "inactive".to_string(),
+        };
+        out.push_str(&format!(
+            "{user}: flagged {} ({reason}); {}\n",
+            record.flagged_at.format("%Y-%m-%d %H:%M UTC"),
+            schedule(config, &record)
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(flagged_days_ago: i64, manual: bool) -> (OffboardingRecord, DateTime<Utc>) {
+        let now = Utc::now();
+        (
+            OffboardingRecord {
+                flagged_at: now - chrono::Duration::days(flagged_days_ago),
+                requested_by: manual.then(|| "admin".to_string()),
+                archived_at: None,
+                keys_revoked_at: None,
+                destroyed_at: None,
+            },
+            now,
+        )
+    }
+
+    #[test]
+    fn test_next_stage() {
+        let config = OffboardingConfig::default();
+
+        let (r, now) = record(1, false);
+        assert_eq!(next_stage(&config, &r, now), None);
+        let (r, now) = record(7, false);
+        assert_eq!(next_stage(&config, &r, now), Some(Stage::Archive));
+
+        // Manual offboarding archives right away but keeps the destroy grace period.
+        let (mut r, now) = record(0, true);
+        assert_eq!(next_stage(&config, &r, now), Some(Stage::Archive));
+        r.archived_at = Some(now);
+        assert_eq!(next_stage(&config, &r, now), None);
+
+        let (mut r, now) = record(30, false);
+        r.archived_at = Some(now);
+        assert_eq!(next_stage(&config, &r, now), Some(Stage::Destroy));
+        r.destroyed_at = Some(now);
+        assert_eq!(next_stage(&config, &r, now), None);
+
+        let config = OffboardingConfig {
+            destroy_after_days: None,
+            ..Default::default()
+        };
+        let (mut r, now) = record(365, false);
+        r.archived_at = Some(now);
+        assert_eq!(next_stage(&config, &r, now), None);
+    }
+
+    #[tokio::test]
+    async fn test_archive_workspace() {
+        let root =
+            std::env::temp_dir().join(format!("agentman-offboard-test-{}", std::process::id()));
+        let workspace = root.join("workspaces/octocat/api");
+        std::fs::create_dir_all(&workspace).unwrap();
+        std::fs::write(workspace.join("main.rs"), "fn main() {}\n").unwrap();
+
+        let now = Utc::now();
+        let archives = root.join("archives");
+        let archive = archive_workspace(&archives, "octocat", "api", &workspace, now)
+            .await
+            .unwrap()
+            .expect("archive written");
+        assert!(archive.starts_with(archives.join("octocat")));
+        assert!(archive.to_string_lossy().ends_with(".tar.gz"));
+
+        let listing = std::process::Command::new("tar")
+            .arg("-tzf")
+            .arg(&archive)
+            .output()
+            .unwrap();
+        assert!(String::from_utf8_lossy(&listing.stdout).contains("api/main.rs"));
+
+        let missing = archive_workspace(&archives, "octocat", "gone", &root.join("nope"), now)
+            .await
+            .unwrap();
+        assert!(missing.is_none());
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,174 @@
+//! Connection snippets for editors and ssh (`agentman open`).
+//!
+//! The snippets use the `<project>+<github-user>` login, so the gateway never has to ask which
+//! GitHub user a key belongs to, and a `HostKeyAlias` shared by all of a gateway's projects,
+//! so its host key is trusted once rather than per `Host` entry. The address is
+//! `public_address`, or a placeholder with the listen port when the gateway doesn't know it.
+
+use crate::config::{GatewayConfig, split_host_port};
+
+/// Stand-in for the gateway's address when `public_address` isn't set.
+const PLACEHOLDER_HOST: &str = "<gateway-host>";
+
+/// Which snippets to print.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenTarget {
+    All,
+    Ssh,
+    VsCode,
+    Zed,
+    JetBrains,
+}
+
+impl OpenTarget {
+    pub fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "ssh" => OpenTarget::Ssh,
+            "vscode" | "code" | "cursor" => OpenTarget::VsCode,
+            "zed" => OpenTarget::Zed,
+            "jetbrains" | "gateway" => OpenTarget::JetBrains,
+            _ => return None,
+        })
+    }
+}
+
+/// The snippets for `github_user/project`; `host_key` is the gateway's public host key in
+/// OpenSSH format, for a known_hosts line.
+pub fn render(
+    config: &GatewayConfig,
+    github_user: &str,
+    project: &str,
+    target: OpenTarget,
+    host_key: Option<&str>,
+) -> String {
+    let (host, port) = address(config);
+    let user = format!("{project}+{github_user}");
+    let alias = format!("agentman-{project}");
+    let key_alias = format!("agentman-{host}");
+    let port_flag = if port == 22 {
+        String::new()
+    } else {
+        format!(" -p {port}")
+    };
+    let url_port = if port == 22 {
+        String::new()
+    } else {
+        format!(":{port}")
+    };
+    let show = |t: OpenTarget| target == OpenTarget::All || target == t;
+
+    let mut out = String::new();
+    if host == PLACEHOLDER_HOST {
+        out.push_str(&format!(
+            "# Replace {PLACEHOLDER_HOST} with the gateway's address (admins: set public_address).\n\n"
+        ));
+    }
+    // Every editor below reads ~/.ssh/config, so the Host entry comes first.
+    if target != OpenTarget::Zed {
+        out.push_str(&format!(
+            "# ~/.ssh/config\n\
+             Host {alias}\n  \
+             HostName {host}\n  \
+             Port {port}\n  \
+             User {user}\n  \
+             HostKeyAlias {key_alias}\n  \
+             ServerAliveInterval 30\n\n"
+        ));
+        if let Some(key) = host_key {
+            out.push_str(&format!(
+                "# ~/.ssh/known_hosts (the gateway's host key)\n{key_alias} {key}\n\n"
+            ));
+        }
+    }
+    if show(OpenTarget::Ssh) {
+        out.push_str(&format!(
+            "# ssh\nssh {alias}\nssh{port_flag} {user}@{host}\n\n"
+        ));
+    }
+    if show(OpenTarget::VsCode) {
+        out.push_str(&format!(
+            "# VS Code / Cursor (Remote-SSH: Connect to Host... -> {alias})\n\
+             code --remote ssh-remote+{alias} /workspace\n\n"
+        ));
+    }
+    if show(OpenTarget::Zed) {
+        out.push_str(&format!(
+            "# Zed\nzed ssh://{user}@{host}{url_port}/workspace\n\n"
+        ));
+    }
+    if show(OpenTarget::JetBrains) {
+        out.push_str(&format!(
+            "# JetBrains Gateway (SSH connection; or use the {alias} entry of ~/.ssh/config)\n\
+             Host: {host}  Port: {port}  User: {user}  Project directory: /workspace\n\n"
+        ));
+    }
+    out.truncate(out.trim_end().len());
+    out.push('\n');
+    out
+}
+
+/// Host and port users connect to.
+pub fn address(config: &GatewayConfig) -> (String, u16) {
+    if let Some((host, port)) = config.public_address.as_deref().and_then(split_host_port) {
+        return (host.to_string(), port);
+    }
+    let port = config
+        .listen_addr
+        .rsplit_once(':')
+        .and_then(|(_, port)| port.parse().ok())
+        .unwrap_or(22);
+    (PLACEHOLDER_HOST.to_string(), port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let mut config = GatewayConfig {
+            public_address: Some("gw.example.com:2222".to_string()),
+            ..GatewayConfig::default()
+        };
+        let all = render(
+            &config,
+            "octocat",
+            "web",
+            OpenTarget::All,
+            Some("ssh-ed25519 AAAAC3Nz"),
+        );
+        assert!(all.starts_with(
+            "# ~/.ssh/config\nHost agentman-web\n  HostName gw.example.com\n  Port 2222\n  \
+             User web+octocat\n  HostKeyAlias agentman-gw.example.com\n"
+        ));
+        assert!(all.contains("\nagentman-gw.example.com ssh-ed25519 AAAAC3Nz\n"));
+        assert!(all.contains("\nssh -p 2222 web+octocat@gw.example.com\n"));
+        assert!(all.contains("\ncode --remote ssh-remote+agentman-web /workspace\n"));
+        assert!(all.contains("\nzed ssh://web+octocat@gw.example.com:2222/workspace\n"));
+        assert!(all.contains("Host: gw.example.com  Port: 2222  User: web+octocat"));
+        assert!(all.ends_with("/workspace\n"));
+
+        let zed = render(&config, "octocat", "web", OpenTarget::Zed, None);
+        assert_eq!(
+            zed,
+            "# Zed\nzed ssh://web+octocat@gw.example.com:2222/workspace\n"
+        );
+
+        config.public_address = Some("gw.example.com".to_string());
+        let ssh = render(&config, "octocat", "web", OpenTarget::Ssh, None);
+        assert!(ssh.contains("  Port 22\n"));
+        assert!(ssh.contains("\nssh web+octocat@gw.example.com\n"));
+
+        config.public_address = None;
+        let ssh = render(&config, "octocat", "web", OpenTarget::Ssh, None);
+        assert!(ssh.starts_with("# Replace <gateway-host>"));
+        assert!(ssh.contains("\nssh -p 2222 web+octocat@<gateway-host>\n"));
+    }
+
+    #[test]
+    fn test_parse_target() {
+        assert_eq!(OpenTarget::parse("cursor"), Some(OpenTarget::VsCode));
+        assert_eq!(OpenTarget::parse("jetbrains"), Some(OpenTarget::JetBrains));
+        assert_eq!(OpenTarget::parse("emacs"), None);
+    }
+}
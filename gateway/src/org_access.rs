@@ -0,0 +1,158 @@
+//! Organization membership gate (`allowed_github_orgs`).
+//!
+//! A verified key only proves who a user is on GitHub. With `allowed_github_orgs` set, users
+//! also have to be active members of one of the organizations (gateway admins excepted).
+//! Memberships are looked up with `github_org_token_file` when a user logs in and reused for
+//! [`CACHE_TTL`]; when GitHub can't be reached the last answer is used, and without one the
+//! user is refused. SSH can't tell a client why its key was rejected, so a refused user's key
+//! is accepted, their sessions print the refusal and exit 1, and everything else the
+//! connection asks for (forwards, agent forwarding, SFTP) is refused.
+
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use tracing::warn;
+
+use crate::config::GatewayConfig;
+use crate::github::GitHubKeyFetcher;
+use crate::metrics;
+
+/// How long a membership is reused before asking GitHub again.
+pub const CACHE_TTL: Duration = Duration::from_secs(600);
+
+/// Organization memberships by (organization, GitHub user), with when they were fetched.
+type Cache = HashMap<(String, String), (Instant, bool)>;
+
+static MEMBERSHIPS: LazyLock<Mutex<Cache>> = LazyLock::new(Default::default);
+
+/// Why `github_user` may not use the gateway, if they may not.
+pub async fn check(
+    config: &GatewayConfig,
+    github: &GitHubKeyFetcher,
+    github_user: &str,
+) -> Option<String> {
+    if config.allowed_github_orgs.is_empty() || config.is_admin(github_user) {
+        return None;
+    }
+    let mut unchecked = false;
+    for org in &config.allowed_github_orgs {
+        match is_member(config, github, org, github_user).await {
+            Ok(true) => {
+                record("allowed");
+                return None;
+            }
+            Ok(false) => {}
+            Err(e) => {
+                warn!("Failed to check the {} membership of {}: {:#}", org, github_user, e);
+                unchecked = true;
+            }
+        }
+    }
+    let orgs = config.allowed_github_orgs.join(", ");
+    Some(if unchecked {
+        record("error");
+        format!(
+            "access denied: the gateway couldn't check whether {github_user} is a member of \
+             {orgs} on GitHub; try again later"
+        )
+    } else {
+        record("denied");
+        format!(
+            "access denied: {github_user} is not a member of {orgs} on GitHub; ask an owner of \
+             the organization to invite you, then reconnect"
+        )
+    })
+}
+
+fn record(result: &str) {
+    metrics::inc(
+        "agentman_org_access_total",
+        "Logins checked against allowed_github_orgs, by result (allowed, denied or error).",
+        &[("result", result)],
+    );
+}
+
+/// Whether `github_user` is an active member of `org`.
+async fn is_member(
+    config: &GatewayConfig,
+    github: &GitHubKeyFetcher,
+    org: &str,
+    github_user: &str,
+) -> Result<bool> {
+    let key = (org.to_ascii_lowercase(), github_user.to_ascii_lowercase());
+    let cached = MEMBERSHIPS.lock().unwrap().get(&key).copied();
+    if let Some((at, member)) = cached
+        && at.elapsed() < CACHE_TTL
+    {
+        return Ok(member);
+    }
+    let member = match fetch_membership(config, github, org, github_user).await {
+        Ok(member) => member,
+        Err(e) => match cached {
+            Some((_, member)) => {
+                warn!("Using cached {} membership of {}: {:#}", org, github_user, e);
+                return Ok(member);
+            }
+            None => return Err(e),
+        },
+    };
+    MEMBERSHIPS.lock().unwrap().insert(key, (Instant::now(), member));
+    Ok(member)
+}
+
+async fn fetch_membership(
+    config: &GatewayConfig,
+    github: &GitHubKeyFetcher,
+    org: &str,
+    github_user: &str,
+) -> Result<bool> {
+    let path = config
+        .github_org_token_file
+        .as_ref()
+        .context("github_org_token_file is not set")?;
+    let token = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    github.fetch_org_membership(org, github_user, token.trim()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::github;
+
+    #[tokio::test]
+    async fn test_check() {
+        let config = GatewayConfig {
+            allowed_github_orgs: vec!["acme".to_string(), "globex".to_string()],
+            github_org_token_file: Some("/nonexistent/token".into()),
+            admin_github_users: vec!["root-admin".to_string()],
+            ..Default::default()
+        };
+        // Cached memberships are used without asking GitHub.
+        let cache = |org: &str, user: &str, member: bool| {
+            MEMBERSHIPS.lock().unwrap().insert(
+                (org.to_string(), user.to_string()),
+                (Instant::now(), member),
+            );
+        };
+        cache("acme", "org-member", false);
+        cache("globex", "org-member", true);
+        cache("acme", "org-outsider", false);
+        cache("globex", "org-outsider", false);
+        cache("acme", "org-half-known", false);
+
+        assert_eq!(check(&config, github::api(), "Org-Member").await, None);
+        assert_eq!(check(&config, github::api(), "root-admin").await, None);
+        let refusal = check(&config, github::api(), "org-outsider").await.unwrap();
+        assert!(refusal.contains("org-outsider is not a member of acme, globex"), "{refusal}");
+        // A membership that can't be checked refuses rather than letting the user in.
+        let refusal = check(&config, github::api(), "org-half-known").await.unwrap();
+        assert!(refusal.contains("couldn't check"), "{refusal}");
+
+        let open = GatewayConfig::default();
+        assert_eq!(check(&open, github::api(), "org-outsider").await, None);
+    }
+}
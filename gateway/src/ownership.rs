@@ -0,0 +1,438 @@
+//! Background ownership reconciler for workspaces (`agentman ownership watch`).
+//!
+//! `[workspace_permissions] chown_mode` only fixes the workspace root when a container starts.
+//! Files that root creates in the container later (package managers, `sudo make install`, tools
+//! run as root) stay root-owned on the host, and the container user can't edit or remove them.
+//! Workspaces that opt in get a watcher instead of recursive chowns: inotify reports each entry
+//! created, moved into the tree or re-owned, and the gateway hands the ones owned by the
+//! container's root to the container user. Only those entries are looked at, so a huge tree
+//! costs nothing until files appear in it. A new directory is walked once, since whatever was
+//! created in it before it was watched isn't reported. Files owned by other container users (a
+//! database's data directory, say) are left alone.
+//!
+//! The sandbox can rename and replace anything in the tree while the gateway works on it, so
+//! events are read from inotify itself (a name is whatever bytes the sandbox chose), and every
+//! entry is reached through [`host_fs`] handles and re-owned with `fchownat` relative to its
+//! directory: nothing outside the workspace can be reached. The watcher only runs when the
+//! container's root is not the host's (`userns-remap`); otherwise the gateway can't tell files
+//! the sandbox made from the host's.
+
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::{Arc, LazyLock, Mutex};
+
+use anyhow::{Context, Result, bail};
+use tokio::io::unix::AsyncFd;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use crate::docker::WorkspaceOwner;
+use crate::host_fs::{self, Handle};
+
+mod sys {
+    use std::os::raw::{c_char, c_int};
+
+    pub const IN_NONBLOCK: c_int = 0o4000;
+    pub const IN_CLOEXEC: c_int = 0o2000000;
+    pub const IN_ATTRIB: u32 = 0x4;
+    pub const IN_MOVED_TO: u32 = 0x80;
+    pub const IN_CREATE: u32 = 0x100;
+    pub const IN_Q_OVERFLOW: u32 = 0x4000;
+    pub const IN_IGNORED: u32 = 0x8000;
+    pub const IN_ONLYDIR: u32 = 0x0100_0000;
+    pub const IN_ISDIR: u32 = 0x4000_0000;
+
+    unsafe extern "C" {
+        pub fn inotify_init1(flags: c_int) -> c_int;
+        pub fn inotify_add_watch(fd: c_int, pathname: *const c_char, mask: u32) -> c_int;
+    }
+}
+
+/// Size of an event's fixed part (`struct inotify_event` without its name).
+const EVENT_HEADER: usize = 16;
+
+/// A running watcher and what it was started with.
+struct Watcher {
+    dir: PathBuf,
+    owner: WorkspaceOwner,
+    task: JoinHandle<()>,
+}
+
+/// Watchers, by workspace (`github_user/project`).
+static WATCHERS: LazyLock<Mutex<HashMap<String, Watcher>>> = LazyLock::new(Default::default);
+
+/// Watch `workspace_dir` unless it already is: files that appear owned by host UID `root_uid`
+/// (the container's root) are given to `owner`. Refused when `root_uid` is the host's root.
+pub(crate) fn ensure_watcher(
+    github_user: &str,
+    project: &str,
+    workspace_dir: &Path,
+    root_uid: u32,
+    owner: WorkspaceOwner,
+) -> Result<()> {
+    if root_uid == 0 {
+        bail!("the sandbox's root is the host's root; the ownership watcher needs userns-remap");
+    }
+    let key = format!("{github_user}/{project}");
+    let mut watchers = WATCHERS.lock().unwrap();
+    if let Some(watcher) = watchers.get(&key)
+        && watcher.dir == workspace_dir
+        && watcher.owner == owner
+        && !watcher.task.is_finished()
+    {
+        return Ok(());
+    }
+    if let Some(watcher) = watchers.remove(&key) {
+        watcher.task.abort();
+    }
+    let dir = workspace_dir.to_path_buf();
+    let task = tokio::spawn({
+        let dir = dir.clone();
+        let key = key.clone();
+        async move {
+            if let Err(e) = watch(&dir, root_uid, owner).await {
+                warn!("Ownership watcher of {} stopped: {:#}", key, e);
+            }
+        }
+    });
+    info!("Watching ownership in {}", dir.display());
+    watchers.insert(key, Watcher { dir, owner, task });
+    Ok(())
+}
+
+/// Stop watching the workspace (opt-out, destroy).
+pub(crate) fn stop_watcher(github_user: &str, project: &str) {
+    let key = format!("{github_user}/{project}");
+    if let Some(watcher) = WATCHERS.lock().unwrap().remove(&key) {
+        watcher.task.abort();
+    }
+}
+
+/// Whether the workspace's watcher is running.
+pub(crate) fn is_watching(github_user: &str, project: &str) -> bool {
+    let key = format!("{github_user}/{project}");
+    WATCHERS
+        .lock()
+        .unwrap()
+        .get(&key)
+        .is_some_and(|watcher| !watcher.task.is_finished())
+}
+
+async fn watch(dir: &Path, root_uid: u32, owner: WorkspaceOwner) -> Result<()> {
+    // SAFETY: takes only flags.
+    let fd = unsafe { sys::inotify_init1(sys::IN_NONBLOCK | sys::IN_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error()).context("Failed to initialize inotify");
+    }
+    // SAFETY: `fd` was just created and nothing else owns it.
+    let inotify = Arc::new(unsafe { File::from_raw_fd(fd) });
+    let readable = AsyncFd::new(inotify.clone())?;
+
+    let root = dir.to_path_buf();
+    let mut dirs: HashMap<i32, PathBuf> = tokio::task::spawn_blocking({
+        let inotify = inotify.clone();
+        move || watch_tree(&inotify, &root, Path::new(""))
+    })
+    .await?
+    .into_iter()
+    .collect();
+    if dirs.is_empty() {
+        bail!("failed to watch {}", dir.display());
+    }
+
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let mut guard = readable.readable().await?;
+        let read = guard.try_io(|fd| {
+            let mut file: &File = fd.get_ref();
+            file.read(&mut buf)
+        });
+        let Ok(read) = read else {
+            continue;
+        };
+        let mut changed = Vec::new();
+        for event in parse_events(&buf[..read?]) {
+            if event.mask & sys::IN_Q_OVERFLOW != 0 {
+                debug!("Missed file events in {}", dir.display());
+                continue;
+            }
+            if event.mask & sys::IN_IGNORED != 0 {
+                dirs.remove(&event.wd);
+                continue;
+            }
+            let Some(parent) = dirs.get(&event.wd) else {
+                continue;
+            };
+            // The watched directory itself.
+            if event.name.is_empty() {
+                continue;
+            }
+            let new_dir = event.mask & sys::IN_ISDIR != 0
+                && event.mask & (sys::IN_CREATE | sys::IN_MOVED_TO) != 0;
+            changed.push((parent.join(&event.name), new_dir));
+        }
+        if changed.is_empty() {
+            continue;
+        }
+        let root = dir.to_path_buf();
+        let inotify = inotify.clone();
+        let (fixed, watched) = tokio::task::spawn_blocking(move || {
+            let mut fixed = 0;
+            let mut watched = Vec::new();
+            for (rel, new_dir) in changed {
+                // Watch first, so nothing created meanwhile goes unnoticed by both.
+                if new_dir {
+                    watched.extend(watch_tree(&inotify, &root, &rel));
+                }
+                fixed += reconcile(&root, &rel, root_uid, owner);
+            }
+            (fixed, watched)
+        })
+        .await?;
+        // A directory moved within the tree keeps its watch, now under its new path.
+        dirs.extend(watched);
+        if fixed > 0 {
+            debug!("Handed {} path(s) in {} to the container user", fixed, dir.display());
+        }
+    }
+}
+
+/// One inotify event: the watch it came from, what happened, and the entry's name (empty for
+/// the watched directory itself).
+#[derive(Debug, PartialEq, Eq)]
+struct Event {
+    wd: i32,
+    mask: u32,
+    name: OsString,
+}
+
+/// The events in `buf`, as read from an inotify descriptor. Names are NUL-padded and can
+/// contain anything else, newlines included.
+fn parse_events(mut buf: &[u8]) -> Vec<Event> {
+    let mut events = Vec::new();
+    while buf.len() >= EVENT_HEADER {
+        let field = |at: usize| u32::from_ne_bytes(buf[at..at + 4].try_into().unwrap());
+        let (wd, mask, len) = (field(0) as i32, field(4), field(12) as usize);
+        let Some(name) = buf.get(EVENT_HEADER..EVENT_HEADER + len) else {
+            break;
+        };
+        let name = name.split(|b| *b == 0).next().unwrap_or_default();
+        events.push(Event {
+            wd,
+            mask,
+            name: OsStr::from_bytes(name).to_os_string(),
+        });
+        buf = &buf[EVENT_HEADER + len..];
+    }
+    events
+}
+
+/// Watch the directory `rel` of `dir` and every directory below it. Returns the watches
+/// added (or renewed, for directories already watched) with the paths they now stand for.
+fn watch_tree(inotify: &File, dir: &Path, rel: &Path) -> Vec<(i32, PathBuf)> {
+    let mut watched = Vec::new();
+    let Ok(handle) = host_fs::walk(dir, rel) else {
+        return watched;
+    };
+    let mask = sys::IN_CREATE | sys::IN_MOVED_TO | sys::IN_ATTRIB | sys::IN_ONLYDIR;
+    let mut pending = vec![(handle, rel.to_path_buf())];
+    while let Some((handle, rel)) = pending.pop() {
+        if !handle.metadata().is_ok_and(|md| md.is_dir()) {
+            continue;
+        }
+        // The descriptor's path reaches the directory that was checked, wherever it is now.
+        let Ok(path) = CString::new(handle.proc_path().into_os_string().into_vec()) else {
+            continue;
+        };
+        // SAFETY: `path` is a NUL-terminated string that outlives the call.
+        let wd = unsafe { sys::inotify_add_watch(inotify.as_raw_fd(), path.as_ptr(), mask) };
+        if wd < 0 {
+            let e = io::Error::last_os_error();
+            warn!("Failed to watch {} in {}: {}", rel.display(), dir.display(), e);
+            break;
+        }
+        watched.push((wd, rel.clone()));
+        for name in handle.entries().unwrap_or_default() {
+            if let Ok(child) = handle.child(&name)
+                && child.metadata().is_ok_and(|md| md.is_dir())
+            {
+                pending.push((child, rel.join(&name)));
+            }
+        }
+    }
+    watched
+}
+
+/// Give the entry `rel` of `dir` to `owner` if `root_uid` owns it, and everything below it if
+/// it is a directory. No symlink is followed, along `rel` or below it. Returns how many
+/// entries were changed.
+fn reconcile(dir: &Path, rel: &Path, root_uid: u32, owner: WorkspaceOwner) -> usize {
+    use std::os::unix::fs::MetadataExt;
+
+    let (Some(parent), Some(name)) = (rel.parent(), rel.file_name()) else {
+        return 0;
+    };
+    let Ok(parent) = host_fs::walk(dir, parent) else {
+        return 0;
+    };
+    let mut fixed = 0;
+    let mut pending: Vec<(Rc<Handle>, OsString)> = vec![(Rc::new(parent), name.to_owned())];
+    while let Some((parent, name)) = pending.pop() {
+        let Ok(entry) = parent.child(&name) else {
+            continue;
+        };
+        let Ok(md) = entry.metadata() else {
+            continue;
+        };
+        if md.uid() == root_uid && parent.chown_child(&name, owner.uid, owner.gid).is_ok() {
+            fixed += 1;
+        }
+        if md.is_dir()
+            && let Ok(names) = entry.entries()
+        {
+            let entry = Rc::new(entry);
+            pending.extend(names.into_iter().map(|name| (entry.clone(), name)));
+        }
+    }
+    fixed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{MetadataExt, lchown};
+
+    #[test]
+    fn test_reconcile_hands_root_files_to_owner() {
+        let dir = std::env::temp_dir().join(format!("agentman-ownership-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("build/out")).unwrap();
+        std::fs::write(dir.join("build/out/a.o"), "").unwrap();
+        std::fs::write(dir.join("db"), "").unwrap();
+
+        let uid = std::fs::metadata(&dir).unwrap().uid();
+        if uid != 0 {
+            // Only root can hand files to other users.
+            std::fs::remove_dir_all(&dir).unwrap();
+            return;
+        }
+        // A database's files belong to another container user.
+        lchown(dir.join("db"), Some(999), Some(999)).unwrap();
+
+        let owner = WorkspaceOwner {
+            uid: 1000,
+            gid: 1000,
+        };
+        assert_eq!(reconcile(&dir, Path::new("build"), 0, owner), 3);
+        assert_eq!(std::fs::metadata(dir.join("build/out/a.o")).unwrap().uid(), 1000);
+        assert_eq!(reconcile(&dir, Path::new("build"), 0, owner), 0);
+        assert_eq!(reconcile(&dir, Path::new("db"), 0, owner), 0);
+        assert_eq!(std::fs::metadata(dir.join("db")).unwrap().uid(), 999);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reconcile_stays_in_the_workspace() {
+        let base = std::env::temp_dir().join(format!("agentman-owner-ws-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        let (dir, host) = (base.join("ws"), base.join("host"));
+        std::fs::create_dir_all(dir.join("x\n/etc")).unwrap();
+        std::fs::create_dir_all(&host).unwrap();
+        std::fs::write(host.join("shadow"), "").unwrap();
+        std::fs::write(dir.join("x\n/etc/shadow"), "").unwrap();
+        if std::fs::metadata(&dir).unwrap().uid() != 0 {
+            std::fs::remove_dir_all(&base).unwrap();
+            return;
+        }
+        let owner = WorkspaceOwner {
+            uid: 1000,
+            gid: 1000,
+        };
+
+        // A name with a newline is one event, and stays a name in the workspace.
+        let mut buf = Vec::new();
+        for (wd, mask, name) in [(1, sys::IN_CREATE | sys::IN_ISDIR, &b"x\n"[..]), (2, 4, b"")] {
+            let len = if name.is_empty() { 0 } else { 16 };
+            for field in [wd as u32, mask, 0, len] {
+                buf.extend_from_slice(&field.to_ne_bytes());
+            }
+            buf.extend_from_slice(name);
+            buf.resize(buf.len() + len as usize - name.len(), 0);
+        }
+        let events = parse_events(&buf);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].name, OsStr::new("x\n"));
+        assert_eq!(events[1], Event { wd: 2, mask: 4, name: OsString::new() });
+        assert_eq!(reconcile(&dir, Path::new("x\n"), 0, owner), 3);
+        assert_eq!(std::fs::metadata(dir.join("x\n/etc/shadow")).unwrap().uid(), 1000);
+        assert_eq!(std::fs::metadata(host.join("shadow")).unwrap().uid(), 0);
+
+        // A directory swapped for a symlink is neither followed along a path nor walked; only
+        // the link itself is re-owned.
+        std::fs::rename(dir.join("x\n"), dir.join("moved")).unwrap();
+        std::os::unix::fs::symlink(&host, dir.join("x\n")).unwrap();
+        lchown(dir.join("x\n"), Some(0), Some(0)).unwrap();
+        assert_eq!(reconcile(&dir, Path::new("x\n/shadow"), 0, owner), 0);
+        assert_eq!(reconcile(&dir, Path::new("x\n"), 0, owner), 1);
+        assert_eq!(std::fs::symlink_metadata(dir.join("x\n")).unwrap().uid(), 1000);
+        assert_eq!(std::fs::metadata(host.join("shadow")).unwrap().uid(), 0);
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_watch() {
+        let dir = std::env::temp_dir().join(format!("agentman-owner-watch-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        if std::fs::metadata(&dir).unwrap().uid() != 0 {
+            std::fs::remove_dir_all(&dir).unwrap();
+            return;
+        }
+        let owner = WorkspaceOwner {
+            uid: 1000,
+            gid: 1000,
+        };
+        let task = tokio::spawn({
+            let dir = dir.clone();
+            async move { watch(&dir, 0, owner).await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+        // In a watched directory, and in a new one.
+        std::fs::write(dir.join("src/main.rs"), "").unwrap();
+        std::fs::create_dir_all(dir.join("target/debug")).unwrap();
+        std::fs::write(dir.join("target/debug/app"), "").unwrap();
+        let owned = |path: &str| std::fs::symlink_metadata(dir.join(path)).unwrap().uid() == 1000;
+        let paths = ["src/main.rs", "target", "target/debug", "target/debug/app"];
+        for _ in 0..50 {
+            if paths.iter().all(|path| owned(path)) {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert!(paths.iter().all(|path| owned(path)));
+        assert!(!task.is_finished());
+        task.abort();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ensure_watcher_refuses_host_root() {
+        let owner = WorkspaceOwner {
+            uid: 1000,
+            gid: 1000,
+        };
+        let dir = std::env::temp_dir();
+        assert!(ensure_watcher("octocat", "web", &dir, 0, owner).is_err());
+        assert!(!is_watching("octocat", "web"));
+    }
+}
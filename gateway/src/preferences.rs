@@ -0,0 +1,264 @@
+//! Per-user preferences (`agentman config`).
+//!
+//! Users tune a few gateway behaviors for themselves: how interactive shells start, whether
+//! the shared editor caches are mounted, how soon idle sandboxes stop and whether they hear
+//! about it. Preferences are kept in the gateway state; an unset one follows the gateway's
+//! config, and none can go past what the config allows (an idle timeout only shortens
+//! `[idle] timeout_secs`, the editor cache needs `[editor_cache] enabled`). `lang` is the
+//! language from `agentman lang`, listed here so all of a user's settings are in one place.
+
+use anyhow::{Result, bail};
+
+use crate::config::{GatewayConfig, ShellMode};
+use crate::guest;
+use crate::i18n::Lang;
+use crate::state::{StateManager, UserPreferences};
+
+/// Shortest idle timeout a user may pick, so a dropped connection can be re-established.
+const MIN_IDLE_TIMEOUT_SECS: u64 = 60;
+
+/// A preference name, as typed after `agentman config get/set`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Shell,
+    EditorCache,
+    IdleTimeout,
+    NotifyIdleStop,
+    Lang,
+}
+
+impl Key {
+    pub const ALL: [Key; 5] = [
+        Key::Shell,
+        Key::EditorCache,
+        Key::IdleTimeout,
+        Key::NotifyIdleStop,
+        Key::Lang,
+    ];
+
+    /// The key named `name`; `-` and `_` are interchangeable.
+    pub fn parse(name: &str) -> Option<Key> {
+        let name = name.replace('-', "_");
+        Self::ALL.into_iter().find(|key| key.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Key::Shell => "shell",
+            Key::EditorCache => "editor_cache",
+            Key::IdleTimeout => "idle_timeout",
+            Key::NotifyIdleStop => "notify_idle_stop",
+            Key::Lang => "lang",
+        }
+    }
+
+    /// Accepted values, for errors and `agentman help`.
+    pub fn values(self) -> &'static str {
+        match self {
+            Key::Shell => "tmux or bash",
+            Key::EditorCache | Key::NotifyIdleStop => "on or off",
+            Key::IdleTimeout => "a duration such as 30m or 2h (at least 1m)",
+            Key::Lang => "a language code (see `agentman lang list`)",
+        }
+    }
+}
+
+/// Every key's names, for errors.
+pub fn key_names() -> String {
+    Key::ALL.map(Key::name).join(", ")
+}
+
+/// The shell mode in effect for the user.
+pub fn shell_mode(config: &GatewayConfig, prefs: &UserPreferences) -> ShellMode {
+    prefs.shell.unwrap_or(config.shell.mode)
+}
+
+/// Whether the shared editor caches are mounted into the user's new containers.
+pub fn editor_cache(config: &GatewayConfig, prefs: &UserPreferences) -> bool {
+    config.editor_cache.enabled && prefs.editor_cache != Some(false)
+}
+
+/// How long the user's sandboxes may go without connections before they are stopped.
+pub fn idle_timeout_secs(config: &GatewayConfig, prefs: &UserPreferences) -> Option<u64> {
+    match (config.idle.timeout_secs, prefs.idle_timeout_secs) {
+        (Some(gateway), Some(user)) => Some(gateway.min(user)),
+        (gateway, user) => gateway.or(user),
+    }
+}
+
+/// The user's own value for `key` (`None` if unset) and the value in effect.
+pub async fn get(
+    config: &GatewayConfig,
+    state: &StateManager,
+    github_user: &str,
+    key: Key,
+) -> (Option<String>, String) {
+    let prefs = state.preferences(github_user).await;
+    match key {
+        Key::Shell => (
+            prefs.shell.map(|mode| shell_name(mode).to_string()),
+            shell_name(shell_mode(config, &prefs)).to_string(),
+        ),
+        Key::EditorCache => (
+            prefs.editor_cache.map(|on| on_off(on).to_string()),
+            on_off(editor_cache(config, &prefs)).to_string(),
+        ),
+        Key::IdleTimeout => (
+            prefs.idle_timeout_secs.map(guest::format_ttl),
+            idle_timeout_secs(config, &prefs).map_or("never".to_string(), guest::format_ttl),
+        ),
+        Key::NotifyIdleStop => (
+            prefs.notify_idle_stop.map(|on| on_off(on).to_string()),
+            on_off(prefs.notify_idle_stop == Some(true) && config.user_notify.enabled).to_string(),
+        ),
+        Key::Lang => {
+            let chosen = state.language(github_user).await;
+            let lang = chosen.unwrap_or(config.i18n.default_lang);
+            (chosen.map(|lang| lang.code().to_string()), lang.code().to_string())
+        }
+    }
+}
+
+/// Set the user's `key` to `value`, or (with `None`) go back to the gateway's config.
+pub async fn set(
+    config: &GatewayConfig,
+    state: &StateManager,
+    github_user: &str,
+    key: Key,
+    value: Option<&str>,
+) -> Result<()> {
+    let invalid = |value: &str| {
+        anyhow::anyhow!("{value:?} is not valid for {}; use {}", key.name(), key.values())
+    };
+    if key == Key::Lang {
+        let lang = value
+            .map(|code| Lang::parse(code).ok_or_else(|| invalid(code)))
+            .transpose()?;
+        return state.set_language(github_user, lang).await;
+    }
+
+    let mut prefs = state.preferences(github_user).await;
+    match (key, value) {
+        (Key::Shell, value) => {
+            prefs.shell = value
+                .map(|v| match v {
+                    "tmux" => Ok(ShellMode::Tmux),
+                    "bash" => Ok(ShellMode::Bash),
+                    _ => Err(invalid(v)),
+                })
+                .transpose()?;
+        }
+        (Key::EditorCache, value) => {
+            let on = value.map(|v| parse_on_off(v).ok_or_else(|| invalid(v))).transpose()?;
+            if on == Some(true) && !config.editor_cache.enabled {
+                bail!("this gateway doesn't share editor caches");
+            }
+            prefs.editor_cache = on;
+        }
+        (Key::IdleTimeout, value) => {
+            let secs = value
+                .map(|v| {
+                    guest::parse_ttl(v)
+                        .filter(|secs| *secs >= MIN_IDLE_TIMEOUT_SECS)
+                        .ok_or_else(|| invalid(v))
+                })
+                .transpose()?;
+            if let (Some(secs), Some(gateway)) = (secs, config.idle.timeout_secs)
+                && secs > gateway
+            {
+                bail!(
+                    "this gateway stops sandboxes idle for {}; the idle timeout can only be shorter",
+                    guest::format_ttl(gateway)
+                );
+            }
+            prefs.idle_timeout_secs = secs;
+        }
+        (Key::NotifyIdleStop, value) => {
+            let on = value.map(|v| parse_on_off(v).ok_or_else(|| invalid(v))).transpose()?;
+            if on == Some(true) && !config.user_notify.enabled {
+                bail!("user notifications are not enabled on this gateway");
+            }
+            prefs.notify_idle_stop = on;
+        }
+        (Key::Lang, _) => unreachable!("handled above"),
+    }
+    state.set_preferences(github_user, prefs).await
+}
+
+fn shell_name(mode: ShellMode) -> &'static str {
+    match mode {
+        ShellMode::Tmux => "tmux",
+        ShellMode::Bash => "bash",
+    }
+}
+
+fn on_off(on: bool) -> &'static str {
+    if on { "on" } else { "off" }
+}
+
+fn parse_on_off(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "on" | "true" | "yes" => Some(true),
+        "off" | "false" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_key() {
+        assert_eq!(Key::parse("idle-timeout"), Some(Key::IdleTimeout));
+        assert_eq!(Key::parse("editor_cache"), Some(Key::EditorCache));
+        assert_eq!(Key::parse("nope"), None);
+        for key in Key::ALL {
+            assert_eq!(Key::parse(key.name()), Some(key));
+        }
+    }
+
+    #[test]
+    fn test_idle_timeout_only_shortens() {
+        let mut config = GatewayConfig::default();
+        let mut prefs = UserPreferences::default();
+        assert_eq!(idle_timeout_secs(&config, &prefs), None);
+        prefs.idle_timeout_secs = Some(600);
+        assert_eq!(idle_timeout_secs(&config, &prefs), Some(600));
+        config.idle.timeout_secs = Some(300);
+        assert_eq!(idle_timeout_secs(&config, &prefs), Some(300));
+        prefs.idle_timeout_secs = None;
+        assert_eq!(idle_timeout_secs(&config, &prefs), Some(300));
+    }
+
+    #[tokio::test]
+    async fn test_set_and_get() {
+        let dir = std::env::temp_dir().join(format!("agentman-prefs-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let state = StateManager::empty(dir.join("state.json"));
+        let mut config = GatewayConfig::default();
+        config.idle.timeout_secs = Some(3600);
+
+        set(&config, &state, "alice", Key::Shell, Some("bash")).await.unwrap();
+        assert_eq!(
+            get(&config, &state, "alice", Key::Shell).await,
+            (Some("bash".to_string()), "bash".to_string())
+        );
+        assert!(set(&config, &state, "alice", Key::Shell, Some("zsh")).await.is_err());
+        assert!(set(&config, &state, "alice", Key::IdleTimeout, Some("2h")).await.is_err());
+        assert!(set(&config, &state, "alice", Key::IdleTimeout, Some("30s")).await.is_err());
+        set(&config, &state, "alice", Key::IdleTimeout, Some("30m")).await.unwrap();
+        assert_eq!(get(&config, &state, "alice", Key::IdleTimeout).await.1, "30m");
+
+        set(&config, &state, "alice", Key::Shell, None).await.unwrap();
+        set(&config, &state, "alice", Key::IdleTimeout, None).await.unwrap();
+        assert_eq!(state.preferences("alice").await, UserPreferences::default());
+        assert_eq!(
+            get(&config, &state, "alice", Key::IdleTimeout).await,
+            (None, "1h".to_string())
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,372 @@
+//! Expiring HTTP preview links (`[preview]`, `agentman expose <port> --preview <duration>`).
+//!
+//! A link is `<scheme>://<id>-<signature>.<domain>/`: the ID names a record in the state (user,
+//! project, port, expiry) and the signature is an HMAC-SHA256 of that record under the key at
+//! `key_path`, so a link can't be made up or pointed elsewhere by editing the hostname, and
+//! removing the key revokes every link. Requests need no login; the gateway reads the request
+//! headers for the `Host`, checks the link, and relays the connection unchanged to the port on
+//! the sandbox's loopback through a bridge exec, like the SNI router does for TLS. Connections
+//! are cut when the link expires. Nothing is rewritten, so apps see the preview hostname in
+//! `Host` and may need to allow it (e.g. Vite's `server.allowedHosts`).
+
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use bollard::query_parameters::InspectContainerOptions;
+use chrono::Utc;
+use russh::keys::ssh_key::rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info};
+
+use crate::config::PreviewConfig;
+use crate::docker::ContainerManager;
+use crate::metrics;
+use crate::secret::{constant_time_eq, hex, is_hex};
+use crate::ssh::bridge_into_container;
+use crate::state::PreviewLink;
+
+const ID_BYTES: usize = 4;
+/// Truncated HMAC; the label stays well under the 63 bytes a DNS label may have.
+const SIGNATURE_BYTES: usize = 16;
+const KEY_BYTES: usize = 32;
+
+/// Upper bound for request headers.
+const MAX_REQUEST_HEAD: usize = 16 * 1024;
+
+/// A new link ID.
+pub fn new_id() -> Result<String> {
+    let mut bytes = [0u8; ID_BYTES];
+    OsRng
+        .try_fill_bytes(&mut bytes)
+        .context("Failed to generate a preview link ID")?;
+    Ok(hex(&bytes))
+}
+
+/// The signing key, generated on first use.
+pub async fn key(config: &PreviewConfig) -> Result<Vec<u8>> {
+    match tokio::fs::read(&config.key_path).await {
+        Ok(key) if key.len() >= KEY_BYTES => return Ok(key),
+        Ok(_) => anyhow::bail!("{} is too short for a preview key", config.key_path.display()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read {}", config.key_path.display()));
+        }
+    }
+    let mut key = vec![0u8; KEY_BYTES];
+    OsRng
+        .try_fill_bytes(&mut key)
+        .context("Failed to generate a preview key")?;
+    write_key(&config.key_path, &key).await?;
+    info!("Generated preview signing key {}", config.key_path.display());
+    Ok(key)
+}
+
+async fn write_key(path: &Path, key: &[u8]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .await
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    file.write_all(key).await?;
+    file.sync_all().await?;
+    Ok(())
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    const BLOCK: usize = 64;
+    let mut block = [0u8; BLOCK];
+    if key.len() > BLOCK {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| block.map(|b| b ^ byte);
+    let inner = Sha256::new()
+        .chain_update(pad(0x36))
+        .chain_update(message)
+        .finalize();
+    Sha256::new()
+        .chain_update(pad(0x5c))
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+/// The signature of link `id`.
+pub fn sign(key: &[u8], id: &str, link: &PreviewLink) -> String {
+    let message = format!(
+        "{id}\n{}\n{}\n{}\n{}",
+        link.github_user,
+        link.project,
+        link.port,
+        link.expires_at.timestamp()
+    );
+    hex(&hmac_sha256(key, message.as_bytes())[..SIGNATURE_BYTES])
+}
+
+/// Whether `signature` is the one link `id` was created with.
+fn verify(key: &[u8], id: &str, link: &PreviewLink, signature: &str) -> bool {
+    let expected = sign(key, id, link);
+    constant_time_eq(expected.as_bytes(), signature.as_bytes())
+}
+
+/// The URL of link `id`.
+pub fn url(config: &PreviewConfig, id: &str, signature: &str) -> String {
+    format!("{}://{id}-{signature}.{}/", config.scheme, config.domain)
+}
+
+/// Link ID and signature of a `Host` header value under `domain`.
+fn route<'a>(domain: &str, host: &'a str) -> Option<(&'a str, &'a str)> {
+    // Drop the port; preview hostnames are never IPv6 literals.
+    let host = host.rsplit_once(':').map_or(host, |(host, _)| host);
+    let host = host.strip_suffix('.').unwrap_or(host);
+    let label = host
+        .strip_suffix(domain)?
+        .strip_suffix('.')
+        .filter(|label| !label.contains('.'))?;
+    let (id, signature) = label.split_once('-')?;
+    (is_hex(id, ID_BYTES) && is_hex(signature, SIGNATURE_BYTES)).then_some((id, signature))
+}
+
+/// The lowercased `Host` header of a complete request head.
+fn host_header(head: &[u8]) -> Option<String> {
+    let head = std::str::from_utf8(head).ok()?;
+    head.split("\r\n").skip(1).find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim()
+            .eq_ignore_ascii_case("host")
+            .then(|| value.trim().to_ascii_lowercase())
+    })
+}
+
+/// Start serving preview links if `[preview] enabled = true`.
+pub async fn serve(container_manager: Arc<ContainerManager>) -> Result<()> {
+    let config = &container_manager.config().preview;
+    if !config.enabled {
+        return Ok(());
+    }
+    // Fail at startup rather than at the first `agentman expose`.
+    key(config).await?;
+    let listener = TcpListener::bind(&config.listen_addr)
+        .await
+        .with_context(|| format!("Failed to bind preview listener on {}", config.listen_addr))?;
+    info!(
+        "Serving preview links on {} for *.{}",
+        config.listen_addr, config.domain
+    );
+
+    tokio::spawn(async move {
+        loop {
+            let Ok((stream, peer)) = listener.accept().await else {
+                continue;
+            };
+            let container_manager = container_manager.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_client(stream, &container_manager).await {
+                    debug!("Preview connection from {} ended: {:#}", peer, e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+fn count_connection(outcome: &str) {
+    metrics::inc(
+        "agentman_preview_connections_total",
+        "Connections to preview links by outcome.",
+        &[("outcome", outcome)],
+    );
+}
+
+/// Answer with a short plain-text error and close the connection.
+async fn respond(client: &mut TcpStream, status: &str, body: &str) -> Result<()> {
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; charset=utf-8\r\n\
+         Content-Length: {}\r\nConnection: close\r\nCache-Control: no-store\r\n\r\n{body}",
+        body.len()
+    );
+    client.write_all(response.as_bytes()).await?;
+    client.shutdown().await?;
+    Ok(())
+}
+
+async fn handle_client(mut client: TcpStream, container_manager: &ContainerManager) -> Result<()> {
+    let config = &container_manager.config().preview;
+    let timeout = Duration::from_secs(config.request_timeout_secs);
+    let head = match tokio::time::timeout(timeout, read_request_head(&mut client)).await {
+        Ok(Ok(head)) => head,
+        Ok(Err(e)) => {
+            count_connection("bad_request");
+            return Err(e);
+        }
+        Err(_) => {
+            count_connection("bad_request");
+            respond(&mut client, "408 Request Timeout", "Request timed out.\n").await?;
+            anyhow::bail!("Timed out waiting for request headers");
+        }
+    };
+    let end = head
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .context("incomplete request head")?;
+    let host = host_header(&head[..end + 2]).unwrap_or_default();
+
+    let not_found = "This preview link doesn't exist or has expired.\n";
+    let Some((id, signature)) = route(&config.domain, &host) else {
+        count_connection("unknown_host");
+        respond(&mut client, "404 Not Found", not_found).await?;
+        anyhow::bail!("not a preview link: {host:?}");
+    };
+    let state = container_manager.state();
+    let Some(link) = state.preview(id).await else {
+        count_connection("unknown_link");
+        respond(&mut client, "404 Not Found", not_found).await?;
+        anyhow::bail!("no preview link {id}");
+    };
+    if !verify(&key(config).await?, id, &link, signature) {
+        count_connection("bad_signature");
+        respond(&mut client, "404 Not Found", not_found).await?;
+        anyhow::bail!("bad signature for preview link {id}");
+    }
+    let Some(ws) = container_manager
+        .get_workspace(&link.github_user, &link.project)
+        .await
+    else {
+        count_connection("unknown_link");
+        respond(&mut client, "404 Not Found", not_found).await?;
+        anyhow::bail!("preview link {id} names a missing workspace");
+    };
+    let running = container_manager
+        .docker()
+        .inspect_container(&ws.container_name, None::<InspectContainerOptions>)
+        .await
+        .ok()
+        .and_then(|info| info.state?.running)
+        .unwrap_or(false);
+    if !running {
+        count_connection("not_running");
+        respond(
+            &mut client,
+            "503 Service Unavailable",
+            "The sandbox behind this preview link is not running.\n",
+        )
+        .await?;
+        anyhow::bail!("sandbox {}/{} is not running", ws.github_user, ws.project);
+    }
+
+    count_connection("routed");
+    debug!(
+        "Preview {}: {}/{} port {}",
+        id, ws.github_user, ws.project, link.port
+    );
+    let left = (link.expires_at - Utc::now()).to_std().unwrap_or_default();
+    // Visitors keep the sandbox from being stopped as idle, like SSH connections.
+    state.connection_opened(&ws.github_user, &ws.project);
+    let relayed = tokio::time::timeout(
+        left,
+        bridge_into_container(
+            container_manager,
+            &ws.container_name,
+            "127.0.0.1",
+            link.port,
+            client,
+            &head,
+        ),
+    )
+    .await;
+    state.connection_closed(&ws.github_user, &ws.project);
+    match relayed {
+        Ok(result) => result,
+        Err(_) => anyhow::bail!("preview link {id} expired"),
+    }
+}
+
+/// Read until the request headers are complete; returns everything read so far.
+async fn read_request_head(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(2048);
+    let mut chunk = [0u8; 2048];
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            anyhow::bail!("Client closed before sending a request");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if buf.windows(4).any(|w| w == b"\r\n\r\n") {
+            return Ok(buf);
+        }
+        if buf.len() > MAX_REQUEST_HEAD {
+            respond(stream, "431 Request Header Fields Too Large", "Request headers too large.\n")
+                .await?;
+            anyhow::bail!("Request headers too large");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hmac_sha256() {
+        // RFC 4231, test case 2.
+        assert_eq!(
+            hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let link = PreviewLink {
+            github_user: "octocat".to_string(),
+            project: "web".to_string(),
+            port: 3000,
+            created_at: Utc::now(),
+            expires_at: Utc::now() + chrono::Duration::hours(1),
+        };
+        let key = [7u8; KEY_BYTES];
+        let signature = sign(&key, "0a1b2c3d", &link);
+        assert_eq!(signature.len(), SIGNATURE_BYTES * 2);
+        assert!(verify(&key, "0a1b2c3d", &link, &signature));
+        assert!(!verify(&[8u8; KEY_BYTES], "0a1b2c3d", &link, &signature));
+        assert!(!verify(&key, "0a1b2c3e", &link, &signature));
+        let moved = PreviewLink {
+            port: 22,
+            ..link.clone()
+        };
+        assert!(!verify(&key, "0a1b2c3d", &moved, &signature));
+    }
+
+    #[test]
+    fn test_route() {
+        let domain = "preview.example.com";
+        let sig = "0123456789abcdef0123456789abcdef";
+        let host = format!("0a1b2c3d-{sig}.preview.example.com");
+        assert_eq!(route(domain, &host), Some(("0a1b2c3d", sig)));
+        assert_eq!(route(domain, &format!("{host}:8080")), Some(("0a1b2c3d", sig)));
+        assert_eq!(route(domain, &format!("{host}.")), Some(("0a1b2c3d", sig)));
+        assert_eq!(route(domain, &format!("x.{host}")), None);
+        assert_eq!(route(domain, "0a1b2c3d-abc.preview.example.com"), None);
+        assert_eq!(route(domain, &format!("0a1b2c3d-{sig}.example.com")), None);
+        assert_eq!(route(domain, "preview.example.com"), None);
+    }
+
+    #[test]
+    fn test_host_header() {
+        let head = b"GET / HTTP/1.1\r\nUser-Agent: curl\r\nHOST:  A.Example.com:8080 \r\n\r\n";
+        assert_eq!(host_header(head).as_deref(), Some("a.example.com:8080"));
+        assert_eq!(host_header(b"GET / HTTP/1.0\r\n\r\n"), None);
+    }
+}
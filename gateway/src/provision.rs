@@ -0,0 +1,278 @@
+//! Repositories cloned into new workspaces (`[clone]`).
+//!
+//! When a workspace is created, its repository is picked from the user's
+//! `AGENTMAN_CLONE_URL` SSH environment variable or the gateway's `url_template`, and recorded
+//! as a pending clone in the workspace's state. The clone runs inside the sandbox (so it uses
+//! the sandbox's network and git setup) before the init command, each time the sandbox starts
+//! until it has succeeded or used up `max_attempts`; the attempt is recorded before it starts,
+//! so one a restart cut short is tried again too. Workspaces that already have files are never
+//! cloned into.
+//!
+//! The repository is cloned without a checkout next to the workspace, its `.git` moved into
+//! `/workspace` and the files checked out there, which leaves `.agentman/` in place. A retry
+//! finds the `.git` of an attempt that got that far and only checks out again.
+
+use std::time::Duration;
+
+use anyhow::{Result, bail};
+use chrono::Utc;
+use tracing::{info, warn};
+
+use crate::docker::ContainerManager;
+use crate::metrics;
+use crate::state::{CloneState, CloneStatus, WorkspaceInfo, WorkspaceResources};
+use crate::template;
+
+/// SSH environment variable naming the repository for a new workspace.
+pub const URL_ENV: &str = "AGENTMAN_CLONE_URL";
+
+/// Where the repository is cloned before its `.git` moves into the workspace.
+const CLONE_DIR: &str = ".agentman/clone";
+
+/// Record the repository to clone into `github_user/project` if the workspace is new:
+/// `requested` (from [`URL_ENV`]) or else the gateway's `url_template`.
+pub async fn plan(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    requested: Option<&str>,
+) -> Result<()> {
+    let config = &container_manager.config().clone;
+    if let Some(url) = requested {
+        if !config.allow_env {
+            bail!("this gateway doesn't clone repositories from {URL_ENV}");
+        }
+        template::validate_url(url)?;
+    }
+    let Some(url) = requested
+        .map(str::to_string)
+        .or_else(|| config.template_url(github_user, project))
+    else {
+        return Ok(());
+    };
+    let state = container_manager.state();
+    let existing = state.get_workspace(github_user, project).await;
+    // Only workspaces that never had a container, or a clone, are new.
+    if existing
+        .as_ref()
+        .is_some_and(|ws| ws.container_id.is_some() || ws.clone.is_some())
+    {
+        return Ok(());
+    }
+    let workspace_path = container_manager
+        .config()
+        .workspace_path(github_user, project);
+    if template::has_user_files(&workspace_path).await? {
+        info!(
+            "Not cloning {} into {}/{}: the workspace already has files",
+            url, github_user, project
+        );
+        return Ok(());
+    }
+    let status = CloneStatus {
+        url,
+        state: CloneState::Pending,
+        attempts: 0,
+        error: None,
+        updated_at: Utc::now(),
+    };
+    match existing {
+        Some(_) => {
+            state
+                .update_workspace(github_user, project, |ws| ws.clone = Some(status))
+                .await?;
+            Ok(())
+        }
+        None => {
+            let config = container_manager.config();
+            state
+                .set_workspace(WorkspaceInfo {
+                    github_user: github_user.to_string(),
+                    project: project.to_string(),
+                    container_name: config.container_name(github_user, project),
+                    container_id: None,
+                    created_at: Utc::now(),
+                    host_workspace_path: workspace_path,
+                    linked_projects: Vec::new(),
+                    init_command: None,
+                    init_status: None,
+                    image: None,
+                    tls_port: None,
+                    watch_ownership: false,
+                    clone: Some(status),
+                    resources: WorkspaceResources::default(),
+                })
+                .await
+        }
+    }
+}
+
+/// Clone the workspace's pending repository into its running container `container_id`.
+/// Failures are recorded in the workspace's state, never returned: the sandbox is usable
+/// either way.
+pub async fn run(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    container_id: &str,
+) {
+    let config = &container_manager.config().clone;
+    let state = container_manager.state();
+    let Some(status) = state
+        .get_workspace(github_user, project)
+        .await
+        .and_then(|ws| ws.clone)
+    else {
+        return;
+    };
+    if status.state == CloneState::Succeeded || status.attempts >= config.max_attempts {
+        return;
+    }
+    let attempt = status.attempts + 1;
+    info!(
+        "Cloning {} into {}/{} (attempt {} of {})",
+        status.url, github_user, project, attempt, config.max_attempts
+    );
+    record(container_manager, github_user, project, |st| {
+        st.state = CloneState::Pending;
+        st.attempts = attempt;
+    })
+    .await;
+
+    let timeout = Duration::from_secs(config.timeout_secs);
+    let result = match tokio::time::timeout(
+        timeout,
+        container_manager.run_exec(container_id, command(&status.url)),
+    )
+    .await
+    {
+        Ok(Ok(output)) if output.exit_code == 0 => Ok(()),
+        Ok(Ok(output)) => Err(format!(
+            "git exited with {}: {}",
+            output.exit_code,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+        Ok(Err(e)) => Err(format!("{e:#}")),
+        Err(_) => Err(format!("timed out after {}s", timeout.as_secs())),
+    };
+    let outcome = match &result {
+        Ok(()) => "succeeded",
+        Err(_) => "failed",
+    };
+    metrics::inc(
+        "agentman_workspace_clones_total",
+        "Repositories cloned into new workspaces ([clone]), by outcome.",
+        &[("outcome", outcome)],
+    );
+    match result {
+        Ok(()) => {
+            info!("Cloned {} into {}/{}", status.url, github_user, project);
+            record(container_manager, github_user, project, |st| {
+                st.state = CloneState::Succeeded;
+                st.error = None;
+            })
+            .await;
+        }
+        Err(error) => {
+            warn!(
+                "Failed to clone {} into {}/{}: {}",
+                status.url, github_user, project, error
+            );
+            record(container_manager, github_user, project, |st| {
+                st.state = CloneState::Failed;
+                st.error = Some(error);
+            })
+            .await;
+        }
+    }
+}
+
+/// `agentman status`: the clone's progress.
+pub fn format_status(status: &CloneStatus, max_attempts: u32) -> String {
+    let mut out = format!("{}  url={}", status.state, status.url);
+    if status.state != CloneState::Succeeded {
+        out.push_str(&format!("  attempts={}/{max_attempts}", status.attempts));
+    }
+    if let Some(ref error) = status.error {
+        out.push_str(&format!("  ({error})"));
+    }
+    out
+}
+
+async fn record(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    update: impl FnOnce(&mut CloneStatus),
+) {
+    if let Err(e) = container_manager
+        .state()
+        .update_workspace(github_user, project, |ws| {
+            if let Some(st) = ws.clone.as_mut() {
+                update(st);
+                st.updated_at = Utc::now();
+            }
+        })
+        .await
+    {
+        warn!("Failed to record the clone of {}/{}: {:#}", github_user, project, e);
+    }
+}
+
+/// The command cloning `url` into `/workspace`, or finishing an earlier attempt's checkout.
+fn command(url: &str) -> Vec<String> {
+    let script = format!(
+        "set -e
+if [ -d .git ]; then
+  if [ \"$(git config --get remote.origin.url)\" != \"$1\" ]; then
+    echo \"/workspace has another git repository\" >&2
+    exit 1
+  fi
+else
+  rm -rf {CLONE_DIR}
+  git clone --quiet --no-checkout -- \"$1\" {CLONE_DIR}
+  mv {CLONE_DIR}/.git .git
+  rm -rf {CLONE_DIR}
+fi
+if git rev-parse --quiet --verify HEAD >/dev/null; then
+  git reset --quiet --hard
+fi"
+    );
+    [
+        "env",
+        // Never block on credential or host key prompts nobody can answer.
+        "GIT_TERMINAL_PROMPT=0",
+        "GIT_SSH_COMMAND=ssh -o BatchMode=yes -o StrictHostKeyChecking=accept-new",
+        "sh",
+        "-c",
+        &script,
+        "agentman-clone",
+        url,
+    ]
+    .map(str::to_string)
+    .to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_status() {
+        let mut status = CloneStatus {
+            url: "https://github.com/octocat/web.git".to_string(),
+            state: CloneState::Failed,
+            attempts: 1,
+            error: Some("git exited with 128: repository not found".to_string()),
+            updated_at: Utc::now(),
+        };
+        assert_eq!(
+            format_status(&status, 3),
+            "failed  url=https://github.com/octocat/web.git  attempts=1/3  (git exited with 128: \
+             repository not found)"
+        );
+        status.state = CloneState::Succeeded;
+        status.error = None;
+        assert_eq!(format_status(&status, 3), "succeeded  url=https://github.com/octocat/web.git");
+    }
+}
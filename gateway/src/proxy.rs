@@ -0,0 +1,534 @@
+//! Egress proxy for sandboxes.
+//!
+//! A softer alternative to cutting containers off the network: with `[proxy] enabled = true`
+//! the gateway runs an HTTP CONNECT proxy on an address only the containers can reach (the
+//! docker bridge), and containers get `HTTPS_PROXY` pointing at it. Each connection is
+//! attributed to a workspace by its source IP; connections from anything that isn't an
+//! agentman container are refused. Destinations must match the `allow` list, and bytes are
+//! counted per workspace for `agentman stats` / `agentman proxy` and the
+//! `agentman_proxy_*` metrics.
+//!
+//! Only `CONNECT` is supported, so plain-HTTP requests are not proxied.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+use crate::config::ProxyConfig;
+use crate::docker::ContainerManager;
+use crate::gateway_control::format_bytes;
+use crate::metrics;
+use crate::user_notify::is_public;
+
+/// Upper bound for a CONNECT request head.
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+/// Time a client gets to send its request head.
+const REQUEST_HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Unknown source IPs refresh the container address map at most this often.
+const RESOLVE_MIN_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Per-workspace egress since the gateway started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EgressCounters {
+    /// Bytes sent from the sandbox to destinations.
+    pub sent: u64,
+    /// Bytes received from destinations.
+    pub received: u64,
+    /// Tunnels opened.
+    pub connections: u64,
+    /// Requests refused by the allowlist.
+    pub denied: u64,
+}
+
+static EGRESS: LazyLock<Mutex<HashMap<(String, String), EgressCounters>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Egress counters for a workspace, or `None` if it hasn't used the proxy.
+pub fn egress(github_user: &str, project: &str) -> Option<EgressCounters> {
+    EGRESS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(&(github_user.to_string(), project.to_string()))
+        .copied()
+}
+
+fn record(workspace: &Workspace, f: impl FnOnce(&mut EgressCounters)) {
+    let mut egress = EGRESS.lock().unwrap_or_else(|e| e.into_inner());
+    f(egress
+        .entry((workspace.github_user.clone(), workspace.project.clone()))
+        .or_default());
+}
+
+/// Environment variables pointing a container's tools at the proxy.
+pub fn container_env(config: &ProxyConfig) -> Vec<String> {
+    if !config.enabled {
+        return Vec::new();
+    }
+    let url = format!("http://{}", config.listen_addr);
+    vec![
+        format!("HTTPS_PROXY={url}"),
+        format!("https_proxy={url}"),
+        "NO_PROXY=localhost,127.0.0.1,::1".to_string(),
+        "no_proxy=localhost,127.0.0.1,::1".to_string(),
+    ]
+}
+
+/// Whether `host:port` matches the allowlist.
+pub fn is_allowed(config: &ProxyConfig, host: &str, port: u16) -> bool {
+    matching_patterns(config, host, port).next().is_some()
+}
+
+/// Whether a pattern without wildcards matches `host:port`: only a destination named that
+/// way may be on a loopback, private or link-local address.
+fn is_named(config: &ProxyConfig, host: &str, port: u16) -> bool {
+    matching_patterns(config, host, port).any(|pattern| !pattern.contains('*'))
+}
+
+fn matching_patterns<'a>(
+    config: &'a ProxyConfig,
+    host: &str,
+    port: u16,
+) -> impl Iterator<Item = &'a str> {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    config.allow.iter().map(String::as_str).filter(move |pattern| {
+        let (pattern_host, pattern_port) = match pattern.rsplit_once(':') {
+            Some((h, p)) => (h, p.parse::<u16>().ok()),
+            None => (*pattern, None),
+        };
+        let port_ok = match pattern_port {
+            Some(p) => p == port,
+            None => config.allowed_ports.contains(&port),
+        };
+        port_ok && host_matches(&pattern_host.to_ascii_lowercase(), &host)
+    })
+}
+
+/// The addresses of `host:port` the proxy may connect to: all of them if an allow pattern
+/// names the host, else only public ones (DNS, or a name under a wildcard, could otherwise
+/// lead into the gateway's own network).
+fn checked_addrs(
+    config: &ProxyConfig,
+    host: &str,
+    port: u16,
+    addrs: impl IntoIterator<Item = SocketAddr>,
+) -> Vec<SocketAddr> {
+    let named = is_named(config, host, port);
+    addrs
+        .into_iter()
+        .filter(|addr| named || is_public(addr.ip()))
+        .collect()
+}
+
+fn host_matches(pattern: &str, host: &str) -> bool {
+    if pattern == "*" {
+        return true;
+    }
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => host
+            .strip_suffix(suffix)
+            .is_some_and(|prefix| prefix.ends_with('.') && prefix.len() > 1),
+        None => pattern == host,
+    }
+}
+
+/// Parse the target of a `CONNECT host:port HTTP/1.x` request head.
+fn parse_connect(head: &str) -> Result<(String, u16), &'static str> {
+    let request_line = head.lines().next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target), Some(version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err("400 Bad Request");
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err("400 Bad Request");
+    }
+    if !method.eq_ignore_ascii_case("CONNECT") {
+        return Err("405 Method Not Allowed");
+    }
+    let (host, port) = target.rsplit_once(':').ok_or("400 Bad Request")?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = port.parse::<u16>().map_err(|_| "400 Bad Request")?;
+    if host.is_empty() || port == 0 {
+        return Err("400 Bad Request");
+    }
+    Ok((host.to_string(), port))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Workspace {
+    github_user: String,
+    project: String,
+}
+
+/// Maps container IPs to workspaces, refreshed from Docker when an unknown IP shows up.
+struct ClientResolver {
+    container_manager: Arc<ContainerManager>,
+    cache: tokio::sync::Mutex<(HashMap<IpAddr, Workspace>, Option<Instant>)>,
+}
+
+impl ClientResolver {
+    async fn resolve(&self, ip: IpAddr) -> Option<Workspace> {
+        let mut cache = self.cache.lock().await;
+        if let Some(ws) = cache.0.get(&ip) {
+            return Some(ws.clone());
+        }
+        if cache.1.is_some_and(|t| t.elapsed() < RESOLVE_MIN_INTERVAL) {
+            return None;
+        }
+        cache.1 = Some(Instant::now());
+        match self.container_manager.workspace_addresses().await {
+            Ok(addresses) => {
+                cache.0 = addresses
+                    .into_iter()
+                    .map(|(ip, (github_user, project))| {
+                        (
+                            ip,
+                            Workspace {
+                                github_user,
+                                project,
+                            },
+                        )
+                    })
+                    .collect();
+            }
+            Err(e) => warn!("Proxy: failed to list container addresses: {:#}", e),
+        }
+        cache.0.get(&ip).cloned()
+    }
+}
+
+/// Start the proxy if `[proxy] enabled = true`.
+pub async fn serve(container_manager: Arc<ContainerManager>) -> Result<()> {
+    let config = container_manager.config().proxy.clone();
+    if !config.enabled {
+        return Ok(());
+    }
+    let listener = TcpListener::bind(&config.listen_addr)
+        .await
+        .with_context(|| format!("Failed to bind egress proxy on {}", config.listen_addr))?;
+    info!(
+        "Egress proxy listening on {} ({} allowed destinations)",
+        config.listen_addr,
+        config.allow.len()
+    );
+
+    let config = Arc::new(config);
+    let resolver = Arc::new(ClientResolver {
+        container_manager,
+        cache: tokio::sync::Mutex::new((HashMap::new(), None)),
+    });
+    tokio::spawn(async move {
+        loop {
+            let Ok((stream, peer)) = listener.accept().await else {
+                continue;
+            };
+            let config = config.clone();
+            let resolver = resolver.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_client(stream, peer, &config, &resolver).await {
+                    debug!("Proxy connection from {} ended: {:#}", peer, e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+fn count_request(outcome: &str) {
+    metrics::inc(
+        "agentman_proxy_requests_total",
+        "Egress proxy requests by outcome.",
+        &[("outcome", outcome)],
+    );
+}
+
+async fn respond(stream: &mut TcpStream, status: &str) -> Result<()> {
+    stream
+        .write_all(
+            format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                .as_bytes(),
+        )
+        .await?;
+    Ok(())
+}
+
+async fn handle_client(
+    mut client: TcpStream,
+    peer: SocketAddr,
+    config: &ProxyConfig,
+    resolver: &ClientResolver,
+) -> Result<()> {
+    let Some(workspace) = resolver.resolve(peer.ip()).await else {
+        count_request("unknown_client");
+        warn!(
+            "Proxy: refusing connection from {} (not an agentman container)",
+            peer
+        );
+        return respond(&mut client, "403 Forbidden").await;
+    };
+
+    let (head, leftover) = tokio::time::timeout(REQUEST_HEAD_TIMEOUT, read_head(&mut client))
+        .await
+        .context("Timed out waiting for request")??;
+    let (host, port) = match parse_connect(&head) {
+        Ok(target) => target,
+        Err(status) => {
+            count_request("bad_request");
+            return respond(&mut client, status).await;
+        }
+    };
+
+    if !is_allowed(config, &host, port) {
+        count_request("denied");
+        record(&workspace, |c| c.denied += 1);
+        info!(
+            "Proxy: denied {}/{} -> {}:{} (not in allowlist)",
+            workspace.github_user, workspace.project, host, port
+        );
+        return respond(&mut client, "403 Forbidden").await;
+    }
+
+    let connect_timeout = Duration::from_secs(config.connect_timeout_secs);
+    let lookup = tokio::net::lookup_host((host.as_str(), port));
+    let resolved: Vec<SocketAddr> = match tokio::time::timeout(connect_timeout, lookup).await {
+        Ok(Ok(addrs)) => addrs.collect(),
+        Ok(Err(e)) => {
+            count_request("upstream_error");
+            debug!("Proxy: resolving {} failed: {}", host, e);
+            return respond(&mut client, "502 Bad Gateway").await;
+        }
+        Err(_) => {
+            count_request("upstream_error");
+            return respond(&mut client, "504 Gateway Timeout").await;
+        }
+    };
+    // Only the addresses checked here are connected to; the name isn't resolved again.
+    let addrs = checked_addrs(config, &host, port, resolved.iter().copied());
+    if addrs.is_empty() && !resolved.is_empty() {
+        count_request("denied");
+        record(&workspace, |c| c.denied += 1);
+        info!(
+            "Proxy: denied {}/{} -> {}:{} (internal address)",
+            workspace.github_user, workspace.project, host, port
+        );
+        return respond(&mut client, "403 Forbidden").await;
+    }
+
+    let connect = TcpStream::connect(&addrs[..]);
+    let mut upstream = match tokio::time::timeout(connect_timeout, connect).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => {
+            count_request("upstream_error");
+            debug!("Proxy: connect to {}:{} failed: {}", host, port, e);
+            return respond(&mut client, "502 Bad Gateway").await;
+        }
+        Err(_) => {
+            count_request("upstream_error");
+            return respond(&mut client, "504 Gateway Timeout").await;
+        }
+    };
+
+    count_request("allowed");
+    record(&workspace, |c| c.connections += 1);
+    debug!(
+        "Proxy: {}/{} -> {}:{}",
+        workspace.github_user, workspace.project, host, port
+    );
+    client
+        .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+        .await?;
+    if !leftover.is_empty() {
+        upstream.write_all(&leftover).await?;
+        record_bytes(&workspace, Direction::Sent, leftover.len());
+    }
+
+    let (client_r, client_w) = client.into_split();
+    let (upstream_r, upstream_w) = upstream.into_split();
+    let (sent, received) = tokio::join!(
+        pump(client_r, upstream_w, &workspace, Direction::Sent),
+        pump(upstream_r, client_w, &workspace, Direction::Received),
+    );
+    sent.and(received)
+}
+
+/// Read up to the end of the request head; returns the head and any bytes read past it.
+async fn read_head(stream: &mut TcpStream) -> Result<(String, Vec<u8>)> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            anyhow::bail!("Client closed before sending a request");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let leftover = buf.split_off(end + 4);
+            return Ok((String::from_utf8_lossy(&buf).into_owned(), leftover));
+        }
+        if buf.len() > MAX_REQUEST_HEAD {
+            anyhow::bail!("Request head too large");
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Direction {
+    Sent,
+    Received,
+}
+
+fn record_bytes(workspace: &Workspace, direction: Direction, n: usize) {
+    let n = n as u64;
+    let label = match direction {
+        Direction::Sent => {
+            record(workspace, |c| c.sent += n);
+            "sent"
+        }
+        Direction::Received => {
+            record(workspace, |c| c.received += n);
+            "received"
+        }
+    };
+    metrics::add(
+        "agentman_proxy_bytes_total",
+        "Bytes tunnelled by the egress proxy, per workspace and direction.",
+        &[
+            ("github_user", &workspace.github_user),
+            ("project", &workspace.project),
+            ("direction", label),
+        ],
+        n as f64,
+    );
+}
+
+/// Copy one direction of a tunnel, counting bytes as they pass (tunnels can live for hours).
+async fn pump(
+    mut from: impl AsyncRead + Unpin,
+    mut to: impl AsyncWrite + Unpin,
+    workspace: &Workspace,
+    direction: Direction,
+) -> Result<()> {
+    let mut buf = vec![0u8; 16 * 1024];
+    loop {
+        let n = from.read(&mut buf).await?;
+        if n == 0 {
+            let _ = to.shutdown().await;
+            return Ok(());
+        }
+        to.write_all(&buf[..n]).await?;
+        record_bytes(workspace, direction, n);
+    }
+}
+
+/// Human-readable proxy status for `agentman proxy`.
+pub fn format_status(config: &ProxyConfig, github_user: &str, project: &str) -> String {
+    if !config.enabled {
+        return "Proxy: not enabled on this gateway ([proxy] enabled = false)\n".to_string();
+    }
+    let ports = config
+        .allowed_ports
+        .iter()
+        .map(u16::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+    let mut out = format!(
+        "Proxy: http://{} (HTTPS_PROXY in your sandbox; CONNECT only)\n",
+        config.listen_addr
+    );
+    out.push_str(&format!(
+        "Allowed destinations: {} (default ports: {})\n",
+        config.allow.join(", "),
+        ports
+    ));
+    let counters = egress(github_user, project).unwrap_or_default();
+    out.push_str(&format!(
+        "This sandbox since gateway start: {} tunnels, {} denied, {} sent, {} received\n",
+        counters.connections,
+        counters.denied,
+        format_bytes(counters.sent),
+        format_bytes(counters.received)
+    ));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(allow: &[&str]) -> ProxyConfig {
+        ProxyConfig {
+            enabled: true,
+            allow: allow.iter().map(|s| s.to_string()).collect(),
+            allowed_ports: vec![443],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_is_allowed() {
+        let cfg = config(&["github.com", "*.npmjs.org", "registry.local:5000"]);
+        assert!(is_allowed(&cfg, "github.com", 443));
+        assert!(is_allowed(&cfg, "GitHub.com.", 443));
+        assert!(!is_allowed(&cfg, "github.com", 22));
+        assert!(!is_allowed(&cfg, "api.github.com", 443));
+        assert!(is_allowed(&cfg, "registry.npmjs.org", 443));
+        assert!(!is_allowed(&cfg, "npmjs.org", 443));
+        assert!(!is_allowed(&cfg, "evilnpmjs.org", 443));
+        assert!(is_allowed(&cfg, "registry.local", 5000));
+        assert!(!is_allowed(&cfg, "registry.local", 443));
+
+        let any = config(&["*"]);
+        assert!(is_allowed(&any, "example.com", 443));
+        assert!(!is_allowed(&any, "example.com", 25));
+    }
+
+    #[test]
+    fn test_checked_addrs() {
+        let cfg = config(&["*.example.com", "registry.local:5000", "10.0.0.7"]);
+        let public: SocketAddr = "93.184.216.34:443".parse().unwrap();
+        let internal = ["127.0.0.1:443", "10.1.2.3:443", "169.254.169.254:443", "[::1]:443"];
+        let internal: Vec<SocketAddr> = internal.iter().map(|a| a.parse().unwrap()).collect();
+
+        // A wildcard match only reaches public addresses.
+        let mut addrs = internal.clone();
+        addrs.push(public);
+        assert_eq!(checked_addrs(&cfg, "a.example.com", 443, addrs), vec![public]);
+        assert!(checked_addrs(&cfg, "a.example.com", 443, internal.clone()).is_empty());
+        assert!(checked_addrs(&config(&["*"]), "localhost", 443, internal.clone()).is_empty());
+
+        // A destination the allowlist names may be internal.
+        let registry: SocketAddr = "10.0.0.5:5000".parse().unwrap();
+        assert_eq!(checked_addrs(&cfg, "registry.local", 5000, [registry]), vec![registry]);
+        let literal: SocketAddr = "10.0.0.7:443".parse().unwrap();
+        assert_eq!(checked_addrs(&cfg, "10.0.0.7", 443, [literal]), vec![literal]);
+    }
+
+    #[test]
+    fn test_parse_connect() {
+        assert_eq!(
+            parse_connect("CONNECT github.com:443 HTTP/1.1\r\nHost: github.com:443\r\n\r\n"),
+            Ok(("github.com".to_string(), 443))
+        );
+        assert_eq!(
+            parse_connect("CONNECT [2001:db8::1]:443 HTTP/1.1\r\n\r\n"),
+            Ok(("2001:db8::1".to_string(), 443))
+        );
+        assert_eq!(
+            parse_connect("GET http://example.com/ HTTP/1.1\r\n\r\n"),
+            Err("405 Method Not Allowed")
+        );
+        assert_eq!(
+            parse_connect("CONNECT github.com HTTP/1.1\r\n\r\n"),
+            Err("400 Bad Request")
+        );
+        assert_eq!(parse_connect("garbage\r\n\r\n"), Err("400 Bad Request"));
+    }
+}
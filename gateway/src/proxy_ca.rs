@@ -0,0 +1,379 @@
+//! Per-deployment CA for TLS-inspecting egress proxies.
+//!
+//! An intercepting proxy (mitmproxy, Squid `ssl-bump`, ...) re-signs upstream certificates
+//! with its own CA, which sandboxes must trust. With `[proxy_ca] enabled = true` the gateway
+//! owns that CA:
+//!
+//! - `<dir>/private/` holds the CA keys; `current.crt` / `current.key` always point at the
+//!   newest CA, for the proxy to sign with.
+//! - `<dir>/trust/` holds the public certificates of every CA that is still valid, plus a
+//!   `bundle.pem`. It is bind-mounted read-only into containers at [`CONTAINER_TRUST_DIR`] and
+//!   installed into the system trust store (`update-ca-certificates`) when a container is
+//!   created.
+//!
+//! Rotation overlaps: a new CA is generated `rotate_before_days` before the current one
+//! expires, and both stay trusted until the old one expires, so certificates the proxy already
+//! handed out keep working. Running containers pick up trust changes within an hour.
+//! `agentman-gateway --rotate-proxy-ca` forces a rotation.
+//!
+//! Keys and certificates are generated with the `openssl` CLI.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use tokio::process::Command;
+use tracing::{info, warn};
+
+use crate::config::ProxyCaConfig;
+use crate::docker::ContainerManager;
+use crate::gateway_control::workspace_container_status_with_running;
+use crate::notify;
+
+/// Where the trust directory is mounted inside containers (picked up by
+/// `update-ca-certificates`).
+pub const CONTAINER_TRUST_DIR: &str = "/usr/local/share/ca-certificates/agentman";
+
+/// How often running containers are checked for trust changes.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(3600);
+
+const CERT_PREFIX: &str = "agentman-proxy-ca-";
+
+fn private_dir(config: &ProxyCaConfig) -> PathBuf {
+    config.dir.join("private")
+}
+
+/// Host directory mounted into containers.
+pub fn trust_dir(config: &ProxyCaConfig) -> PathBuf {
+    config.dir.join("trust")
+}
+
+/// `host:container:ro` bind for the trust directory, if enabled.
+pub fn bind(config: &ProxyCaConfig) -> Option<String> {
+    config
+        .enabled
+        .then(|| format!("{}:{}:ro", trust_dir(config).display(), CONTAINER_TRUST_DIR))
+}
+
+/// Environment variables for runtimes that don't read the system trust store.
+pub fn container_env(config: &ProxyCaConfig) -> Vec<String> {
+    if !config.enabled {
+        return Vec::new();
+    }
+    vec![
+        format!("NODE_EXTRA_CA_CERTS={CONTAINER_TRUST_DIR}/bundle.pem"),
+        "REQUESTS_CA_BUNDLE=/etc/ssl/certs/ca-certificates.crt".to_string(),
+    ]
+}
+
+/// A CA certificate in the trust directory.
+#[derive(Debug, Clone)]
+struct CaCert {
+    /// Generation stamp (`%Y%m%d%H%M%S`), also the file name suffix.
+    stamp: String,
+    cert: PathBuf,
+    not_after: DateTime<Utc>,
+}
+
+/// Make sure a valid CA exists, rotating or pruning as needed.
+///
+/// Returns `true` if the set of trusted certificates changed.
+pub async fn ensure(config: &ProxyCaConfig, force_rotate: bool) -> Result<bool> {
+    if !config.enabled {
+        return Ok(false);
+    }
+    for dir in [private_dir(config), trust_dir(config)] {
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    set_mode(&private_dir(config), 0o700).await?;
+
+    let now = Utc::now();
+    let mut changed = false;
+    let mut certs = Vec::new();
+    for ca in list(config).await? {
+        if ca.not_after <= now {
+            info!("Removing expired proxy CA {}", ca.stamp);
+            remove(config, &ca).await;
+            changed = true;
+        } else {
+            certs.push(ca);
+        }
+    }
+
+    let rotate_at = chrono::Duration::days(config.rotate_before_days as i64);
+    let needs_new = force_rotate
+        || certs
+            .last()
+            .is_none_or(|newest| newest.not_after - now <= rotate_at);
+    if needs_new {
+        let stamp = now.format("%Y%m%d%H%M%S").to_string();
+        generate(config, &stamp).await?;
+        info!(
+            "Generated proxy CA {} (valid for {} days)",
+            stamp, config.validity_days
+        );
+        changed = true;
+        certs = list(config).await?;
+    }
+
+    if changed || !trust_dir(config).join("bundle.pem").exists() {
+        write_bundle(config, &certs).await?;
+        if let Some(newest) = certs.last() {
+            link_current(config, &newest.stamp).await?;
+        }
+    }
+    Ok(changed)
+}
+
+async fn list(config: &ProxyCaConfig) -> Result<Vec<CaCert>> {
+    let dir = trust_dir(config);
+    let mut entries = tokio::fs::read_dir(&dir)
+        .await
+        .with_context(|| format!("Failed to read {}", dir.display()))?;
+    let mut certs = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Some(stamp) = name
+            .strip_prefix(CERT_PREFIX)
+            .and_then(|s| s.strip_suffix(".crt"))
+        else {
+            continue;
+        };
+        let cert = entry.path();
+        match not_after(&cert).await {
+            Ok(not_after) => certs.push(CaCert {
+                stamp: stamp.to_string(),
+                cert,
+                not_after,
+            }),
+            Err(e) => warn!("Ignoring unreadable proxy CA {}: {:#}", cert.display(), e),
+        }
+    }
+    // Stamps sort chronologically.
+    certs.sort_by(|a, b| a.stamp.cmp(&b.stamp));
+    Ok(certs)
+}
+
+async fn openssl(args: &[&str]) -> Result<String> {
+    let output = Command::new("openssl")
+        .args(args)
+        .output()
+        .await
+        .context("Failed to run openssl (is it installed?)")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "openssl {} failed: {}",
+            args.first().unwrap_or(&""),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+async fn not_after(cert: &Path) -> Result<DateTime<Utc>> {
+    let cert = cert.to_string_lossy();
+    let out = openssl(&["x509", "-noout", "-enddate", "-in", &cert]).await?;
+    parse_not_after(&out)
+}
+
+/// Parse `notAfter=Oct 15 12:00:00 2027 GMT`.
+fn parse_not_after(output: &str) -> Result<DateTime<Utc>> {
+    let value = output
+        .trim()
+        .strip_prefix("notAfter=")
+        .context("Unexpected openssl -enddate output")?;
+    let value = value.trim_end_matches(" GMT");
+    let parsed = NaiveDateTime::parse_from_str(
+        &value.split_whitespace().collect::<Vec<_>>().join(" "),
+        "%b %d %H:%M:%S %Y",
+    )
+    .with_context(|| format!("Failed to parse certificate expiry {value:?}"))?;
+    Ok(parsed.and_utc())
+}
+
+async fn generate(config: &ProxyCaConfig, stamp: &str) -> Result<()> {
+    let key = private_dir(config).join(format!("{CERT_PREFIX}{stamp}.key"));
+    let cert = trust_dir(config).join(format!("{CERT_PREFIX}{stamp}.crt"));
+    let key_s = key.to_string_lossy();
+    let cert_s = cert.to_string_lossy();
+    let subject = format!("/O=agentman/CN=agentman proxy CA {stamp}");
+    let days = config.validity_days.to_string();
+    openssl(&[
+        "req",
+        "-x509",
+        "-newkey",
+        "ec",
+        "-pkeyopt",
+        "ec_paramgen_curve:prime256v1",
+        "-nodes",
+        "-keyout",
+        &key_s,
+        "-out",
+        &cert_s,
+        "-days",
+        &days,
+        "-subj",
+        &subject,
+        "-addext",
+        "basicConstraints=critical,CA:TRUE,pathlen:0",
+        "-addext",
+        "keyUsage=critical,keyCertSign,cRLSign",
+    ])
+    .await?;
+    set_mode(&key, 0o600).await?;
+    set_mode(&cert, 0o644).await
+}
+
+async fn remove(config: &ProxyCaConfig, ca: &CaCert) {
+    let key = private_dir(config).join(format!("{CERT_PREFIX}{}.key", ca.stamp));
+    for path in [&ca.cert, &key] {
+        if let Err(e) = tokio::fs::remove_file(path).await
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            warn!("Failed to remove {}: {}", path.display(), e);
+        }
+    }
+}
+
+async fn write_bundle(config: &ProxyCaConfig, certs: &[CaCert]) -> Result<()> {
+    let mut bundle = String::new();
+    for ca in certs {
+        bundle.push_str(&tokio::fs::read_to_string(&ca.cert).await?);
+    }
+    let path = trust_dir(config).join("bundle.pem");
+    let tmp = path.with_extension("pem.tmp");
+    tokio::fs::write(&tmp, bundle).await?;
+    tokio::fs::rename(&tmp, &path)
+        .await
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Point `private/current.{crt,key}` at the newest CA.
+async fn link_current(config: &ProxyCaConfig, stamp: &str) -> Result<()> {
+    let private = private_dir(config);
+    for (link, target) in [
+        (
+            "current.key",
+            private.join(format!("{CERT_PREFIX}{stamp}.key")),
+        ),
+        (
+            "current.crt",
+            trust_dir(config).join(format!("{CERT_PREFIX}{stamp}.crt")),
+        ),
+    ] {
+        let link = private.join(link);
+        let tmp = link.with_extension("tmp");
+        let _ = tokio::fs::remove_file(&tmp).await;
+        tokio::fs::symlink(&target, &tmp).await?;
+        tokio::fs::rename(&tmp, &link)
+            .await
+            .with_context(|| format!("Failed to update {}", link.display()))?;
+    }
+    Ok(())
+}
+
+async fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+        .await
+        .with_context(|| format!("Failed to set permissions on {}", path.display()))
+}
+
+/// Install the mounted CAs into a container's system trust store.
+pub async fn install(container_manager: &ContainerManager, container_id: &str) -> Result<()> {
+    let output = container_manager
+        .run_exec_as(
+            container_id,
+            vec!["update-ca-certificates".to_string(), "--fresh".to_string()],
+            Some("root"),
+        )
+        .await?;
+    if output.exit_code != 0 {
+        anyhow::bail!(
+            "update-ca-certificates exited with {}: {}",
+            output.exit_code,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Rotate the CA when due and re-install trust in running containers when it changes.
+pub fn spawn_rotation(container_manager: Arc<ContainerManager>) {
+    let config = container_manager.config().proxy_ca.clone();
+    if !config.enabled {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut applied = current_fingerprint(&config).await;
+        let mut tick = tokio::time::interval(REFRESH_INTERVAL);
+        tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        tick.tick().await;
+        loop {
+            tick.tick().await;
+            let notify_config = &container_manager.config().notify;
+            if let Err(e) = ensure(&config, false).await {
+                warn!("Proxy CA rotation failed: {:#}", e);
+                notify::task_failed(notify_config, "proxy_ca.rotation", &format!("{e:#}")).await;
+                continue;
+            }
+            notify::task_succeeded(notify_config, "proxy_ca.rotation").await;
+            // Also catches rotations done with `--rotate-proxy-ca` while we were running.
+            let fingerprint = current_fingerprint(&config).await;
+            if fingerprint == applied {
+                continue;
+            }
+            info!("Proxy CA trust changed; updating running containers");
+            refresh_running(&container_manager).await;
+            applied = fingerprint;
+        }
+    });
+}
+
+/// Contents of the trust bundle; changes whenever a CA is added or removed.
+async fn current_fingerprint(config: &ProxyCaConfig) -> String {
+    tokio::fs::read_to_string(trust_dir(config).join("bundle.pem"))
+        .await
+        .unwrap_or_default()
+}
+
+async fn refresh_running(container_manager: &ContainerManager) {
+    let state = container_manager.state();
+    for github_user in state.list_github_users().await {
+        for ws in state.list_workspaces(&github_user).await {
+            let Some(id) = ws.container_id else {
+                continue;
+            };
+            let (_, _, running) =
+                workspace_container_status_with_running(container_manager, &id).await;
+            if !running {
+                continue;
+            }
+            if let Err(e) = install(container_manager, &id).await {
+                warn!(
+                    "Failed to update proxy CA trust in {}/{}: {:#}",
+                    ws.github_user, ws.project, e
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_not_after() {
+        let parsed = parse_not_after("notAfter=Oct 15 12:00:00 2027 GMT\n").unwrap();
+        assert_eq!(parsed.to_rfc3339(), "2027-10-15T12:00:00+00:00");
+        let parsed = parse_not_after("notAfter=Mar  5 08:09:10 2030 GMT").unwrap();
+        assert_eq!(parsed.to_rfc3339(), "2030-03-05T08:09:10+00:00");
+        assert!(parse_not_after("garbage").is_err());
+    }
+}
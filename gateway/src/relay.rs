@@ -0,0 +1,327 @@
+//! Reverse tunnel registration with an external relay.
+//!
+//! Gateways behind NAT can't accept inbound SSH. With `[relay] address` set, the gateway keeps an
+//! outbound SSH connection to a public bastion and asks it for a remote forward
+//! (`remote_bind:remote_port`). Every connection the bastion accepts on that port arrives back
+//! here as a `forwarded-tcpip` channel and is spliced onto the local SSH listener, so clients
+//! see the gateway as if it were listening on the bastion.
+//!
+//! The tunnel is re-established with exponential backoff whenever it drops; keepalives detect
+//! half-dead connections. Health is exposed through `agentman tunnel` and the
+//! `agentman_relay_*` metrics.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use russh::client;
+use russh::keys::{PrivateKey, PrivateKeyWithHashAlg};
+use tokio::net::TcpStream;
+use tracing::{debug, info, warn};
+
+use crate::config::RelayConfig;
+use crate::github::compute_fingerprint_from_pubkey;
+use crate::metrics;
+
+/// A tunnel that stayed up at least this long resets the reconnect backoff.
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+/// Missed keepalives before the bastion is considered gone.
+const KEEPALIVE_MAX: usize = 3;
+
+static STATUS: LazyLock<Mutex<Option<RelayStatus>>> = LazyLock::new(|| Mutex::new(None));
+
+/// Current relay health, as shown by `agentman tunnel`.
+#[derive(Debug, Clone, Default)]
+pub struct RelayStatus {
+    /// Bastion endpoint clients should use, e.g. `relay.example.com:2222`.
+    pub endpoint: String,
+
+    /// When the current tunnel was established; `None` while disconnected.
+    pub connected_since: Option<DateTime<Utc>>,
+
+    /// Most recent connection error.
+    pub last_error: Option<String>,
+
+    /// Number of times the tunnel had to be re-established.
+    pub reconnects: u64,
+
+    /// Client connections relayed since startup.
+    pub relayed_connections: u64,
+}
+
+/// Snapshot of the relay status, or `None` if no relay is configured.
+pub fn status() -> Option<RelayStatus> {
+    STATUS.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+fn update_status(f: impl FnOnce(&mut RelayStatus)) {
+    if let Some(status) = STATUS.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        f(status);
+    }
+}
+
+/// Start the relay task if `[relay] address` is set.
+///
+/// `local_addr` is the gateway's own SSH listener; `host_key` is used to authenticate to the
+/// bastion unless `key_path` points at a dedicated key.
+pub fn spawn(config: &RelayConfig, local_addr: SocketAddr, host_key: &PrivateKey) -> Result<()> {
+    let Some(ref address) = config.address else {
+        return Ok(());
+    };
+
+    let key = match config.key_path {
+        Some(ref path) => russh::keys::load_secret_key(path, None)
+            .with_context(|| format!("Failed to load relay key from {}", path.display()))?,
+        None => host_key.clone(),
+    };
+
+    let host = address.rsplit_once(':').map_or(address.as_str(), |(h, _)| h);
+    *STATUS.lock().unwrap_or_else(|e| e.into_inner()) = Some(RelayStatus {
+        endpoint: format!("{}:{}", host, config.remote_port),
+        ..Default::default()
+    });
+
+    info!(
+        "Relay enabled: registering with {} as {} (remote port {})",
+        address, config.user, config.remote_port
+    );
+    tokio::spawn(run(config.clone(), loopback_target(local_addr), Arc::new(key)));
+    Ok(())
+}
+
+/// Relayed connections are spliced onto the local listener; a wildcard bind is reached via
+/// loopback.
+fn loopback_target(mut addr: SocketAddr) -> SocketAddr {
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr {
+            SocketAddr::V4(_) => std::net::Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => std::net::Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    addr
+}
+
+async fn run(config: RelayConfig, local_addr: SocketAddr, key: Arc<PrivateKey>) {
+    let max_backoff = Duration::from_secs(config.max_backoff_secs);
+    let mut backoff = Duration::from_secs(1);
+    set_connected_metric(false);
+
+    loop {
+        let started = Instant::now();
+        match session(&config, local_addr, key.clone()).await {
+            Ok(()) => warn!("Relay tunnel to {} closed", config.address.as_deref().unwrap_or("")),
+            Err(e) => {
+                warn!("Relay tunnel error: {:#}", e);
+                update_status(|s| s.last_error = Some(format!("{:#}", e)));
+            }
+        }
+        update_status(|s| {
+            s.connected_since = None;
+            s.reconnects += 1;
+        });
+        set_connected_metric(false);
+        metrics::inc(
+            "agentman_relay_reconnects_total",
+            "Times the relay tunnel had to be re-established.",
+            &[],
+        );
+
+        if started.elapsed() >= STABLE_AFTER {
+            backoff = Duration::from_secs(1);
+        }
+        debug!("Reconnecting to relay in {:?}", backoff);
+        tokio::time::sleep(backoff).await;
+        backoff = next_backoff(backoff, max_backoff);
+    }
+}
+
+fn next_backoff(current: Duration, max: Duration) -> Duration {
+    (current * 2).min(max)
+}
+
+/// One tunnel lifetime: connect, authenticate, register the forward, then wait for the
+/// connection to drop.
+async fn session(config: &RelayConfig, local_addr: SocketAddr, key: Arc<PrivateKey>) -> Result<()> {
+    let address = config.address.as_deref().unwrap_or_default();
+    let client_config = Arc::new(client::Config {
+        keepalive_interval: Some(Duration::from_secs(config.keepalive_secs)),
+        keepalive_max: KEEPALIVE_MAX,
+        ..Default::default()
+    });
+    let handler = RelayClient {
+        expected_fingerprint: config.server_key_fingerprint.clone(),
+        local_addr,
+    };
+
+    let mut handle = client::connect(client_config, address, handler)
+        .await
+        .with_context(|| format!("Failed to connect to relay {}", address))?;
+
+    let hash_alg = handle.best_supported_rsa_hash().await?.flatten();
+    let auth = handle
+        .authenticate_publickey(&config.user, PrivateKeyWithHashAlg::new(key, hash_alg))
+        .await?;
+    if !auth.success() {
+        anyhow::bail!("Relay rejected public key for user {}", config.user);
+    }
+
+    handle
+        .tcpip_forward(&config.remote_bind, u32::from(config.remote_port))
+        .await
+        .with_context(|| {
+            format!(
+                "Relay refused remote forward on {}:{}",
+                config.remote_bind, config.remote_port
+            )
+        })?;
+
+    info!(
+        "Relay tunnel up: {}:{} on {} -> {}",
+        config.remote_bind, config.remote_port, address, local_addr
+    );
+    update_status(|s| {
+        s.connected_since = Some(Utc::now());
+        s.last_error = None;
+    });
+    set_connected_metric(true);
+
+    // The handle resolves when the connection ends (EOF, keepalive timeout, or error).
+    handle.await
+}
+
+fn set_connected_metric(connected: bool) {
+    metrics::set(
+        "agentman_relay_connected",
+        "Whether the relay tunnel is currently established.",
+        &[],
+        if connected { 1.0 } else { 0.0 },
+    );
+}
+
+struct RelayClient {
+    expected_fingerprint: String,
+    local_addr: SocketAddr,
+}
+
+impl client::Handler for RelayClient {
+    type Error = anyhow::Error;
+
+    async fn check_server_key(
+        &mut self,
+        server_public_key: &russh::keys::PublicKey,
+    ) -> Result<bool, Self::Error> {
+        let fingerprint = compute_fingerprint_from_pubkey(server_public_key);
+        if fingerprint != self.expected_fingerprint {
+            warn!(
+                "Relay host key mismatch: expected {}, got {}",
+                self.expected_fingerprint, fingerprint
+            );
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    async fn server_channel_open_forwarded_tcpip(
+        &mut self,
+        channel: russh::Channel<client::Msg>,
+        _connected_address: &str,
+        _connected_port: u32,
+        originator_address: &str,
+        originator_port: u32,
+        _session: &mut client::Session,
+    ) -> Result<(), Self::Error> {
+        let local_addr = self.local_addr;
+        let originator = format!("{}:{}", originator_address, originator_port);
+        update_status(|s| s.relayed_connections += 1);
+        metrics::inc(
+            "agentman_relay_connections_total",
+            "Client connections received through the relay tunnel.",
+            &[],
+        );
+
+        tokio::spawn(async move {
+            debug!("Relaying connection from {} to {}", originator, local_addr);
+            let mut local = match TcpStream::connect(local_addr).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Relay: failed to connect to local listener {}: {}", local_addr, e);
+                    let _ = channel.close().await;
+                    return;
+                }
+            };
+            let mut remote = channel.into_stream();
+            if let Err(e) = tokio::io::copy_bidirectional(&mut remote, &mut local).await {
+                debug!("Relayed connection from {} ended: {}", originator, e);
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Human-readable status for `agentman tunnel`.
+pub fn format_status(status: Option<&RelayStatus>) -> String {
+    let Some(status) = status else {
+        return "Relay: not configured ([relay] address is unset)\n".to_string();
+    };
+
+    let mut out = format!("Relay endpoint: {}\n", status.endpoint);
+    match status.connected_since {
+        Some(since) => out.push_str(&format!(
+            "State: connected since {}\n",
+            since.format("%Y-%m-%d %H:%M:%S UTC")
+        )),
+        None => out.push_str("State: disconnected (reconnecting)\n"),
+    }
+    out.push_str(&format!("Reconnects: {}\n", status.reconnects));
+    out.push_str(&format!("Relayed connections: {}\n", status.relayed_connections));
+    if let Some(ref err) = status.last_error {
+        out.push_str(&format!("Last error: {}\n", err));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_backoff_caps() {
+        let max = Duration::from_secs(60);
+        let mut backoff = Duration::from_secs(1);
+        let mut seen = Vec::new();
+        for _ in 0..8 {
+            seen.push(backoff.as_secs());
+            backoff = next_backoff(backoff, max);
+        }
+        assert_eq!(seen, vec![1, 2, 4, 8, 16, 32, 60, 60]);
+    }
+
+    #[test]
+    fn test_loopback_target() {
+        let wildcard: SocketAddr = "0.0.0.0:2222".parse().unwrap();
+        assert_eq!(loopback_target(wildcard), "127.0.0.1:2222".parse().unwrap());
+        let v6: SocketAddr = "[::]:2222".parse().unwrap();
+        assert_eq!(loopback_target(v6), "[::1]:2222".parse().unwrap());
+        let fixed: SocketAddr = "10.0.0.5:2222".parse().unwrap();
+        assert_eq!(loopback_target(fixed), fixed);
+    }
+
+    #[test]
+    fn test_format_status() {
+        assert!(format_status(None).contains("not configured"));
+
+        let status = RelayStatus {
+            endpoint: "relay.example.com:2222".to_string(),
+            last_error: Some("connection refused".to_string()),
+            reconnects: 3,
+            ..Default::default()
+        };
+        let out = format_status(Some(&status));
+        assert!(out.contains("relay.example.com:2222"));
+        assert!(out.contains("disconnected"));
+        assert!(out.contains("Last error: connection refused"));
+    }
+}
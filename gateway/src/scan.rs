@@ -0,0 +1,451 @@
+//! Workspace content checks (`agentman scan`, and in the background with `[scan] enabled`).
+//!
+//! A workspace is a bind mount of a host directory, so whatever the sandbox puts there is also
+//! seen by the host: backups, templates, migrations and admins' tools read it. The scan walks
+//! the directory without following symlinks or crossing into other filesystems and reports
+//! symlinks that resolve outside `/workspace` (absolute targets are taken as the sandbox sees
+//! them), setuid/setgid files anyone can write, and files of `large_file_mb` or more. The
+//! background scan tells admins (`[notify]`) and the owner (a notice at their next login) when
+//! a workspace's findings change, so a known finding is reported once.
+
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+use tracing::{info, warn};
+
+use crate::config::ScanConfig;
+use crate::docker::ContainerManager;
+use crate::gateway_control::format_bytes;
+use crate::{metrics, notify};
+
+/// Where the workspace is mounted in the sandbox.
+const MOUNT: &str = "/workspace";
+
+/// Fingerprint of the findings last reported per workspace directory.
+static REPORTED: LazyLock<Mutex<HashMap<PathBuf, u64>>> = LazyLock::new(Default::default);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Finding {
+    /// A symlink whose target lies outside the mount.
+    EscapingSymlink { path: PathBuf, target: PathBuf },
+    /// A setuid or setgid file writable by anyone.
+    WritableSetuid { path: PathBuf, mode: u32 },
+    LargeFile { path: PathBuf, bytes: u64 },
+}
+
+impl Finding {
+    fn kind(&self) -> &'static str {
+        match self {
+            Finding::EscapingSymlink { .. } => "symlink",
+            Finding::WritableSetuid { .. } => "setuid",
+            Finding::LargeFile { .. } => "large",
+        }
+    }
+
+    fn path(&self) -> &Path {
+        match self {
+            Finding::EscapingSymlink { path, .. }
+            | Finding::WritableSetuid { path, .. }
+            | Finding::LargeFile { path, .. } => path,
+        }
+    }
+}
+
+/// What a scan of one workspace found.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub findings: Vec<Finding>,
+    /// Entries looked at.
+    pub entries: u64,
+    /// The walk stopped at `max_entries`.
+    pub truncated: bool,
+    /// Directories that couldn't be read.
+    pub unreadable: u64,
+}
+
+impl Report {
+    fn count(&self, kind: &str) -> usize {
+        self.findings.iter().filter(|f| f.kind() == kind).count()
+    }
+
+    /// One line, e.g. "2 symlinks pointing outside /workspace, 1 large file".
+    pub fn summary(&self) -> String {
+        let mut parts = Vec::new();
+        for (kind, one, many) in [
+            (
+                "symlink",
+                "symlink pointing outside /workspace",
+                "symlinks pointing outside /workspace",
+            ),
+            (
+                "setuid",
+                "setuid file writable by anyone",
+                "setuid files writable by anyone",
+            ),
+            ("large", "large file", "large files"),
+        ] {
+            match self.count(kind) {
+                0 => {}
+                1 => parts.push(format!("1 {one}")),
+                n => parts.push(format!("{n} {many}")),
+            }
+        }
+        if parts.is_empty() {
+            "nothing".to_string()
+        } else {
+            parts.join(", ")
+        }
+    }
+
+    /// The findings (at most `max`), one per line, with paths as the sandbox sees them.
+    pub fn format(&self, max: usize) -> String {
+        let mut out = String::new();
+        for finding in self.findings.iter().take(max) {
+            let line = match finding {
+                Finding::EscapingSymlink { path, target } => format!(
+                    "  symlink  {} -> {}",
+                    in_mount(path).display(),
+                    target.display()
+                ),
+                Finding::WritableSetuid { path, mode } => format!(
+                    "  setuid   {} (mode {:o})",
+                    in_mount(path).display(),
+                    mode & 0o7777
+                ),
+                Finding::LargeFile { path, bytes } => format!(
+                    "  large    {} ({})",
+                    in_mount(path).display(),
+                    format_bytes(*bytes)
+                ),
+            };
+            out.push_str(&line);
+            out.push('\n');
+        }
+        if self.findings.len() > max {
+            out.push_str(&format!("  ... and {} more\n", self.findings.len() - max));
+        }
+        if self.truncated {
+            out.push_str(&format!(
+                "  (stopped after {} entries; the rest wasn't scanned)\n",
+                self.entries
+            ));
+        }
+        if self.unreadable > 0 {
+            out.push_str(&format!(
+                "  ({} directories couldn't be read)\n",
+                self.unreadable
+            ));
+        }
+        out
+    }
+
+    fn fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.findings.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+fn in_mount(path: &Path) -> PathBuf {
+    Path::new(MOUNT).join(path)
+}
+
+/// Scan the workspace directory at `dir`.
+pub async fn scan(config: &ScanConfig, dir: &Path) -> Result<Report> {
+    let config = config.clone();
+    let dir = dir.to_path_buf();
+    tokio::task::spawn_blocking(move || walk(&config, &dir))
+        .await
+        .map_err(|e| anyhow!("scan task failed: {e}"))?
+}
+
+fn walk(config: &ScanConfig, root: &Path) -> Result<Report> {
+    let root_meta = std::fs::symlink_metadata(root)
+        .with_context(|| format!("Failed to stat {}", root.display()))?;
+    if !root_meta.is_dir() {
+        return Err(anyhow!("{} is not a directory", root.display()));
+    }
+    let large_bytes = config.large_file_mb.saturating_mul(1024 * 1024);
+
+    let mut report = Report::default();
+    let mut pending = vec![PathBuf::new()];
+    'walk: while let Some(rel_dir) = pending.pop() {
+        let Ok(entries) = std::fs::read_dir(root.join(&rel_dir)) else {
+            report.unreadable += 1;
+            continue;
+        };
+        for entry in entries.flatten() {
+            if report.entries >= config.max_entries {
+                report.truncated = true;
+                break 'walk;
+            }
+            report.entries += 1;
+            // Doesn't follow symlinks.
+            let Ok(meta) = entry.metadata() else {
+                continue;
+            };
+            let rel = rel_dir.join(entry.file_name());
+            let file_type = meta.file_type();
+            if file_type.is_symlink() {
+                if let Ok(target) = std::fs::read_link(entry.path())
+                    && escapes(&rel, &target)
+                {
+                    report
+                        .findings
+                        .push(Finding::EscapingSymlink { path: rel, target });
+                }
+            } else if file_type.is_dir() {
+                // Something mounted over a subdirectory isn't part of the workspace.
+                if meta.dev() == root_meta.dev() {
+                    pending.push(rel);
+                }
+            } else if file_type.is_file() {
+                let mode = meta.mode();
+                if mode & 0o6000 != 0 && mode & 0o002 != 0 {
+                    report.findings.push(Finding::WritableSetuid {
+                        path: rel.clone(),
+                        mode,
+                    });
+                }
+                if large_bytes > 0 && meta.len() >= large_bytes {
+                    report.findings.push(Finding::LargeFile {
+                        path: rel,
+                        bytes: meta.len(),
+                    });
+                }
+            }
+        }
+    }
+    report
+        .findings
+        .sort_by(|a, b| (a.kind(), a.path()).cmp(&(b.kind(), b.path())));
+    Ok(report)
+}
+
+/// Whether the symlink at `link` (relative to the workspace) points outside it. Absolute
+/// targets are resolved as the sandbox sees them, where the workspace is `/workspace`.
+fn escapes(link: &Path, target: &Path) -> bool {
+    let (mut depth, rest) = if target.is_absolute() {
+        match target.strip_prefix(MOUNT) {
+            Ok(rest) => (0usize, rest),
+            Err(_) => return true,
+        }
+    } else {
+        let parent = link.parent().unwrap_or(Path::new(""));
+        (parent.components().count(), target)
+    };
+    for component in rest.components() {
+        match component {
+            Component::ParentDir => match depth.checked_sub(1) {
+                Some(d) => depth = d,
+                None => return true,
+            },
+            Component::Normal(_) => depth += 1,
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+        }
+    }
+    false
+}
+
+/// Scan every workspace every `interval_secs` (no-op unless `[scan] enabled`).
+pub fn spawn(container_manager: Arc<ContainerManager>) {
+    let config = container_manager.config().scan.clone();
+    if !config.enabled {
+        return;
+    }
+    info!("Scanning workspaces every {}s", config.interval_secs);
+
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(Duration::from_secs(config.interval_secs));
+        tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            tick.tick().await;
+            let notify_config = &container_manager.config().notify;
+            match scan_all(&container_manager, &config).await {
+                Ok(()) => notify::task_succeeded(notify_config, "scan").await,
+                Err(e) => {
+                    warn!("Workspace scan failed: {:#}", e);
+                    notify::task_failed(notify_config, "scan", &format!("{e:#}")).await;
+                }
+            }
+        }
+    });
+}
+
+async fn scan_all(container_manager: &ContainerManager, config: &ScanConfig) -> Result<()> {
+    let state = container_manager.state();
+    let mut totals: HashMap<&'static str, usize> = HashMap::new();
+    let mut failed = Vec::new();
+    let mut seen = Vec::new();
+    for github_user in state.list_github_users().await {
+        for ws in state.list_workspaces(&github_user).await {
+            let dir = ws.host_workspace_path.clone();
+            if !dir.is_dir() {
+                continue;
+            }
+            let report = match scan(config, &dir).await {
+                Ok(report) => report,
+                Err(e) => {
+                    failed.push(format!("{github_user}/{}: {e:#}", ws.project));
+                    continue;
+                }
+            };
+            for finding in &report.findings {
+                *totals.entry(finding.kind()).or_default() += 1;
+            }
+            seen.push(dir.clone());
+            if changed(&dir, &report) {
+                report_findings(container_manager, config, &github_user, &ws.project, &report)
+                    .await;
+            }
+        }
+    }
+    // Forget destroyed and transferred workspaces.
+    REPORTED.lock().unwrap().retain(|dir, _| seen.contains(dir));
+
+    for kind in ["symlink", "setuid", "large"] {
+        metrics::set(
+            "agentman_scan_findings",
+            "Findings of the last background workspace scan, by kind.",
+            &[("kind", kind)],
+            totals.get(kind).copied().unwrap_or(0) as f64,
+        );
+    }
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!("{}", failed.join("; ")))
+    }
+}
+
+/// Remember `report` for `dir`; true if it has findings that weren't reported yet.
+fn changed(dir: &Path, report: &Report) -> bool {
+    let mut reported = REPORTED.lock().unwrap();
+    if report.findings.is_empty() {
+        reported.remove(dir);
+        return false;
+    }
+    let fingerprint = report.fingerprint();
+    reported.insert(dir.to_path_buf(), fingerprint) != Some(fingerprint)
+}
+
+async fn report_findings(
+    container_manager: &ContainerManager,
+    config: &ScanConfig,
+    github_user: &str,
+    project: &str,
+    report: &Report,
+) {
+    let summary = report.summary();
+    notify::send(
+        &container_manager.config().notify,
+        "scan.findings",
+        &format!(
+            "Workspace scan of {github_user}/{project} found {summary}:\n{}",
+            report.format(config.max_findings)
+        ),
+    )
+    .await;
+    let notice = format!(
+        "the workspace scan found {summary} in {project}; run `agentman scan` there for details"
+    );
+    if let Err(e) = container_manager
+        .state()
+        .add_notice(github_user, notice)
+        .await
+    {
+        warn!("Failed to save scan notice for {}: {:#}", github_user, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{PermissionsExt, symlink};
+
+    #[test]
+    fn test_escapes() {
+        let link = Path::new("a/b/link");
+        assert!(!escapes(link, Path::new("c")));
+        assert!(!escapes(link, Path::new("../../x")));
+        assert!(escapes(link, Path::new("../../../x")));
+        assert!(!escapes(link, Path::new("./../c/../../d")));
+        assert!(escapes(Path::new("link"), Path::new("..")));
+        assert!(!escapes(link, Path::new("/workspace/a")));
+        assert!(!escapes(link, Path::new("/workspace")));
+        assert!(escapes(link, Path::new("/workspace/../etc")));
+        assert!(escapes(link, Path::new("/workspaces/a")));
+        assert!(escapes(link, Path::new("/etc/passwd")));
+    }
+
+    #[tokio::test]
+    async fn test_scan() {
+        let dir = std::env::temp_dir().join(format!("agentman-scan-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        symlink("../README", dir.join("src/readme")).unwrap();
+        symlink("../../etc/passwd", dir.join("src/passwd")).unwrap();
+        symlink("/workspace/src", dir.join("code")).unwrap();
+        let tool = dir.join("tool");
+        std::fs::write(&tool, b"#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&tool, std::fs::Permissions::from_mode(0o4777)).unwrap();
+        std::fs::write(dir.join("big"), vec![0u8; 1024 * 1024]).unwrap();
+
+        let config = ScanConfig {
+            large_file_mb: 1,
+            ..ScanConfig::default()
+        };
+        let report = scan(&config, &dir).await.unwrap();
+        assert_eq!(report.entries, 6);
+        assert!(!report.truncated);
+        assert_eq!(
+            report.findings,
+            vec![
+                Finding::LargeFile {
+                    path: PathBuf::from("big"),
+                    bytes: 1024 * 1024
+                },
+                Finding::WritableSetuid {
+                    path: PathBuf::from("tool"),
+                    mode: std::fs::metadata(&tool).unwrap().mode()
+                },
+                Finding::EscapingSymlink {
+                    path: PathBuf::from("src/passwd"),
+                    target: PathBuf::from("../../etc/passwd")
+                },
+            ]
+        );
+        assert_eq!(
+            report.summary(),
+            "1 symlink pointing outside /workspace, 1 setuid file writable by anyone, \
+             1 large file"
+        );
+        let formatted = report.format(1);
+        assert!(formatted.contains("  large    /workspace/big (1.0 MiB)\n"), "{formatted}");
+        assert!(formatted.contains("... and 2 more"), "{formatted}");
+
+        // Reported once until the findings change.
+        assert!(changed(&dir, &report));
+        assert!(!changed(&dir, &report));
+        assert!(!changed(&dir, &Report::default()));
+        assert!(changed(&dir, &report));
+
+        let truncated = scan(
+            &ScanConfig {
+                max_entries: 2,
+                ..config
+            },
+            &dir,
+        )
+        .await
+        .unwrap();
+        assert!(truncated.truncated);
+        assert_eq!(truncated.entries, 2);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,322 @@
+//! Text search across a user's workspaces (`agentman search`).
+//!
+//! Work spread over several sandboxes is hard to find again from inside any one of them. The
+//! search runs in each workspace at once: in a running sandbox it is an exec of `rg` (or `grep`
+//! in images without it), so it sees the files as the sandbox's user does and honors
+//! `.gitignore`; a stopped sandbox isn't started for it, and its workspace directory is read on
+//! the host instead, without following symlinks or crossing into other filesystems. Patterns
+//! are plain text (optionally case-insensitive) so both ways find the same lines.
+
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+use bollard::query_parameters::InspectContainerOptions;
+use futures::StreamExt;
+
+use crate::docker::ContainerManager;
+use crate::host_fs::Handle;
+use crate::state::WorkspaceInfo;
+
+/// Matches reported per workspace.
+pub const MAX_MATCHES: usize = 200;
+
+/// Longest line shown, in characters.
+const MAX_LINE_CHARS: usize = 300;
+
+/// Files larger than this aren't searched.
+const MAX_FILE_BYTES: u64 = 1024 * 1024;
+
+/// Entries a host-side search looks at before giving up on the rest.
+const MAX_ENTRIES: u64 = 200_000;
+
+/// Workspaces searched at once.
+const CONCURRENCY: usize = 4;
+
+/// How long the search of one workspace may take.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Directories never searched (rg skips `.git` through `--glob` below).
+const SKIPPED_DIRS: [&str; 2] = [".git", "node_modules"];
+
+/// Picks `rg`, else `grep`. Arguments: `$1` the pattern, `$2` `-i` or nothing. Output is cut
+/// after one line more than [`MAX_MATCHES`], so a truncated search is recognizable.
+const SEARCH_SCRIPT: &str = r#"if command -v rg >/dev/null 2>&1; then
+  rg --no-heading --line-number --color never --fixed-strings --hidden --glob '!.git' \
+    --max-filesize 1M $2 -e "$1" 2>/dev/null
+else
+  grep -rnIF --exclude-dir=.git --exclude-dir=node_modules $2 -e "$1" . 2>/dev/null
+fi | head -n 201"#;
+
+/// One matching line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Match {
+    /// Relative to the workspace.
+    pub path: String,
+    pub line: u64,
+    pub text: String,
+}
+
+/// What the search of one workspace found.
+#[derive(Debug, Default)]
+pub struct Found {
+    pub matches: Vec<Match>,
+    /// More than [`MAX_MATCHES`] lines matched (or the host-side walk stopped early).
+    pub truncated: bool,
+    /// Searched on the host because the sandbox wasn't running.
+    pub on_host: bool,
+}
+
+/// Search `workspaces` for `pattern`; results come in the order of `workspaces`.
+pub async fn search_all(
+    container_manager: &ContainerManager,
+    workspaces: &[WorkspaceInfo],
+    pattern: &str,
+    ignore_case: bool,
+) -> Vec<Result<Found>> {
+    futures::stream::iter(workspaces.to_vec())
+        .map(|ws| async move {
+            tokio::time::timeout(TIMEOUT, search(container_manager, &ws, pattern, ignore_case))
+                .await
+                .unwrap_or_else(|_| Err(anyhow!("timed out after {}s", TIMEOUT.as_secs())))
+        })
+        .buffered(CONCURRENCY)
+        .collect()
+        .await
+}
+
+/// Search one workspace: in its sandbox if it is running, else on the host.
+async fn search(
+    container_manager: &ContainerManager,
+    ws: &WorkspaceInfo,
+    pattern: &str,
+    ignore_case: bool,
+) -> Result<Found> {
+    let running = container_manager
+        .docker()
+        .inspect_container(&ws.container_name, None::<InspectContainerOptions>)
+        .await
+        .ok()
+        .and_then(|info| info.state)
+        .is_some_and(|state| state.running == Some(true) && state.paused != Some(true));
+    if running {
+        let output = container_manager
+            .run_exec(&ws.container_name, command(pattern, ignore_case))
+            .await?;
+        let mut matches = parse_output(&output.stdout);
+        let truncated = matches.len() > MAX_MATCHES;
+        matches.truncate(MAX_MATCHES);
+        return Ok(Found {
+            matches,
+            truncated,
+            on_host: false,
+        });
+    }
+    let dir = ws.host_workspace_path.clone();
+    let pattern = pattern.to_string();
+    let mut found = tokio::task::spawn_blocking(move || search_dir(&dir, &pattern, ignore_case))
+        .await
+        .map_err(|e| anyhow!("search task failed: {e}"))??;
+    found.on_host = true;
+    Ok(found)
+}
+
+/// Exec command searching `/workspace` for `pattern`.
+pub fn command(pattern: &str, ignore_case: bool) -> Vec<String> {
+    vec![
+        "/bin/sh".to_string(),
+        "-c".to_string(),
+        SEARCH_SCRIPT.to_string(),
+        "agentman-search".to_string(),
+        pattern.to_string(),
+        if ignore_case { "-i" } else { "" }.to_string(),
+    ]
+}
+
+/// `path:line:text` lines of rg or grep.
+fn parse_output(stdout: &[u8]) -> Vec<Match> {
+    String::from_utf8_lossy(stdout)
+        .lines()
+        .filter_map(|line| {
+            let (path, rest) = line.split_once(':')?;
+            let (number, text) = rest.split_once(':')?;
+            Some(Match {
+                path: path.trim_start_matches("./").to_string(),
+                line: number.parse().ok()?,
+                text: shorten(text),
+            })
+        })
+        .collect()
+}
+
+/// Search the workspace directory `root` on the host. The sandbox may be changing it, so the
+/// walk goes through [`Handle`]s and never resolves a path below `root` again.
+fn search_dir(root: &Path, pattern: &str, ignore_case: bool) -> Result<Found> {
+    let root_dir =
+        Handle::open_dir(root).with_context(|| format!("Failed to open {}", root.display()))?;
+    let root_dev = root_dir.metadata()?.dev();
+    let needle = if ignore_case { pattern.to_lowercase() } else { pattern.to_string() };
+
+    let mut found = Found::default();
+    let mut entries_seen = 0u64;
+    // Directories to search, each with a handle of its parent; the root is its own parent.
+    let mut pending = vec![(Rc::new(root_dir), PathBuf::new())];
+    'walk: while let Some((parent, rel_dir)) = pending.pop() {
+        let dir = match rel_dir.file_name() {
+            None => parent,
+            Some(name) => match parent.child(name) {
+                Ok(dir) => Rc::new(dir),
+                Err(_) => continue,
+            },
+        };
+        // Checked again on the handle: the entry may have been swapped since it was listed.
+        // Something mounted over a subdirectory isn't part of the workspace.
+        match dir.metadata() {
+            Ok(meta) if meta.is_dir() && meta.dev() == root_dev => {}
+            _ => continue,
+        }
+        let Ok(mut names) = dir.entries() else {
+            continue;
+        };
+        names.sort();
+        // Popped from the end, so the first directory is searched first.
+        let mut subdirs = Vec::new();
+        for name in names {
+            entries_seen += 1;
+            if entries_seen > MAX_ENTRIES {
+                found.truncated = true;
+                break 'walk;
+            }
+            // Doesn't follow symlinks.
+            let Ok(entry) = dir.child(&name) else {
+                continue;
+            };
+            let Ok(meta) = entry.metadata() else {
+                continue;
+            };
+            let rel = rel_dir.join(&name);
+            if meta.is_dir() {
+                if !SKIPPED_DIRS.iter().any(|skipped| name == *skipped) {
+                    subdirs.push((dir.clone(), rel));
+                }
+            } else if meta.is_file() && meta.len() <= MAX_FILE_BYTES {
+                search_file(&entry, &rel, &needle, ignore_case, &mut found);
+                if found.matches.len() > MAX_MATCHES {
+                    found.matches.truncate(MAX_MATCHES);
+                    found.truncated = true;
+                    break 'walk;
+                }
+            }
+        }
+        pending.extend(subdirs.into_iter().rev());
+    }
+    Ok(found)
+}
+
+/// Search the regular file `file`, shown as `rel`.
+fn search_file(file: &Handle, rel: &Path, needle: &str, ignore_case: bool, found: &mut Found) {
+    let mut content = Vec::new();
+    // The file may have grown since its size was checked.
+    let read = file.reopen().and_then(|f| f.take(MAX_FILE_BYTES).read_to_end(&mut content));
+    if read.is_err() {
+        return;
+    }
+    // Binary files are skipped, like grep -I.
+    if content[..content.len().min(8192)].contains(&0) {
+        return;
+    }
+    let content = String::from_utf8_lossy(&content);
+    for (i, line) in content.lines().enumerate() {
+        let hit = if ignore_case {
+            line.to_lowercase().contains(needle)
+        } else {
+            line.contains(needle)
+        };
+        if hit {
+            found.matches.push(Match {
+                path: rel.display().to_string(),
+                line: i as u64 + 1,
+                text: shorten(line),
+            });
+            if found.matches.len() > MAX_MATCHES {
+                return;
+            }
+        }
+    }
+}
+
+fn shorten(text: &str) -> String {
+    match text.char_indices().nth(MAX_LINE_CHARS) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_output() {
+        let out = b"src/main.rs:12:    // TODO: retry\n./README.md:3:TODO: a:b\nnot a match\n";
+        assert_eq!(
+            parse_output(out),
+            vec![
+                Match {
+                    path: "src/main.rs".to_string(),
+                    line: 12,
+                    text: "    // TODO: retry".to_string(),
+                },
+                Match {
+                    path: "README.md".to_string(),
+                    line: 3,
+                    text: "TODO: a:b".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_search_dir() {
+        let dir = std::env::temp_dir().join(format!("agentman-search-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::create_dir_all(dir.join(".git")).unwrap();
+        std::fs::create_dir_all(dir.join("node_modules/dep")).unwrap();
+        std::fs::write(dir.join("src/main.rs"), "fn main() {}\n// todo: retry\n").unwrap();
+        std::fs::write(dir.join("README.md"), "TODO: docs\n").unwrap();
+        std::fs::write(dir.join(".git/HEAD"), "TODO\n").unwrap();
+        std::fs::write(dir.join("node_modules/dep/index.js"), "// TODO\n").unwrap();
+        std::fs::write(dir.join("blob.bin"), b"TODO\0\x01").unwrap();
+        std::os::unix::fs::symlink("/etc", dir.join("etc")).unwrap();
+
+        let found = search_dir(&dir, "TODO", false).unwrap();
+        let hits: Vec<_> = found.matches.iter().map(|m| (m.path.as_str(), m.line)).collect();
+        assert_eq!(hits, vec![("README.md", 1)]);
+        assert!(!found.truncated);
+
+        let found = search_dir(&dir, "TODO", true).unwrap();
+        let hits: Vec<_> = found.matches.iter().map(|m| (m.path.as_str(), m.line)).collect();
+        assert_eq!(hits, vec![("README.md", 1), ("src/main.rs", 2)]);
+
+        std::fs::write(dir.join("many.txt"), "TODO\n".repeat(MAX_MATCHES + 5)).unwrap();
+        let found = search_dir(&dir, "TODO", false).unwrap();
+        assert_eq!(found.matches.len(), MAX_MATCHES);
+        assert!(found.truncated);
+
+        // A file is read through the handle that was checked, wherever its path leads now.
+        let readme = Handle::open_dir(&dir).unwrap().child("README.md".as_ref()).unwrap();
+        std::fs::rename(dir.join("README.md"), dir.join("moved.md")).unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", dir.join("README.md")).unwrap();
+        let mut found = Found::default();
+        search_file(&readme, Path::new("README.md"), "root", false, &mut found);
+        assert!(found.matches.is_empty());
+        search_file(&readme, Path::new("README.md"), "TODO", false, &mut found);
+        assert_eq!(found.matches.len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,34 @@
+//! Printing and comparing tokens, signatures and secret hashes.
+
+/// Lowercase hex of `bytes`.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Whether `s` is the lowercase hex of `bytes` bytes.
+pub fn is_hex(s: &str, bytes: usize) -> bool {
+    s.len() == bytes * 2 && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Whether `a` and `b` are equal, in a time that doesn't depend on where they first differ
+/// (only on their lengths), for checking secrets.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex_and_constant_time_eq() {
+        assert_eq!(hex(&[0x00, 0xab, 0x7f]), "00ab7f");
+        assert!(is_hex("00ab7f", 3));
+        assert!(!is_hex("00AB7F", 3));
+        assert!(!is_hex("00ab7", 3));
+        assert!(constant_time_eq(b"token", b"token"));
+        assert!(!constant_time_eq(b"token", b"tokem"));
+        assert!(!constant_time_eq(b"token", b"token2"));
+        assert!(!constant_time_eq(b"", b"x"));
+    }
+}
@@ -0,0 +1,702 @@
+//! Container security events from the kernel audit subsystem (see [`SecurityEventsConfig`]).
+//!
+//! A background task follows the auditd log and picks out records about:
+//! - seccomp: `SECCOMP` records, written for filter actions the kernel logs (kill, trap,
+//!   `SCMP_ACT_LOG`, ...). Docker's default profile returns `EPERM` silently, so only profiles
+//!   that log their denials show up here.
+//! - capabilities: `AVC` denials from AppArmor (`operation="capable"`) or SELinux
+//!   (`tclass=capability`).
+//! - ptrace: `AVC` denials for ptrace, and `SYSCALL` records matching the audit rule tagged
+//!   with `ptrace_key`.
+//!
+//! The offending process is mapped to its container through `/proc/<pid>/cgroup` (falling back
+//! to the parent for exited processes), and only processes in agentman containers are kept.
+//! Each one becomes a `security` [`AuditEvent`] with the user and project plus structured fields
+//! (category, container, pid, comm, syscall, capability, ...), which the configured sinks ship
+//! to syslog/SIEM. The gateway must run in the host PID namespace for the lookup to work.
+
+use std::collections::HashMap;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use bollard::query_parameters::InspectContainerOptions;
+use tokio::io::{AsyncBufReadExt, AsyncSeekExt, BufReader};
+use tracing::{debug, info, warn};
+
+use crate::audit::{AuditEvent, AuditEventKind, AuditLogger, AuditOutcome};
+use crate::config::SecurityEventsConfig;
+use crate::docker::ContainerManager;
+use crate::metrics;
+use crate::notify;
+
+/// How often the log is checked for new records (and for rotation) at EOF.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Delay before retrying after the log could not be opened or read.
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Rate limiting window for `max_events_per_minute`.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Containers remembered by [`Resolver`] before its cache is reset.
+const MAX_CACHED_CONTAINERS: usize = 1024;
+
+/// Fields auditd hex-encodes when they contain spaces or control characters.
+const ENCODED_FIELDS: &[&str] = &["comm", "exe", "name", "proctitle", "cwd"];
+
+/// Start following the audit log, sending events to `audit`.
+pub fn spawn(container_manager: Arc<ContainerManager>, audit: Arc<AuditLogger>) {
+    let config = container_manager.config().security_events.clone();
+    info!(
+        "Forwarding container security events from {}",
+        config.audit_log.display()
+    );
+
+    tokio::spawn(async move {
+        let mut resolver = Resolver::default();
+        let mut limiter = RateLimiter::new(config.max_events_per_minute);
+        loop {
+            if let Err(e) = follow(
+                &container_manager,
+                &audit,
+                &config,
+                &mut resolver,
+                &mut limiter,
+            )
+            .await
+            {
+                warn!("Security event forwarding paused: {:#}", e);
+                let notify_config = &container_manager.config().notify;
+                notify::task_failed(notify_config, "security_events", &format!("{e:#}")).await;
+            }
+            tokio::time::sleep(RETRY_INTERVAL).await;
+        }
+    });
+}
+
+/// Tail the audit log until an I/O error, reopening it when it is rotated or truncated.
+async fn follow(
+    container_manager: &ContainerManager,
+    audit: &AuditLogger,
+    config: &SecurityEventsConfig,
+    resolver: &mut Resolver,
+    limiter: &mut RateLimiter,
+) -> Result<()> {
+    let path = &config.audit_log;
+    let (mut reader, mut inode) = open_log(path, true).await?;
+    notify::task_succeeded(&container_manager.config().notify, "security_events").await;
+    let mut pos = reader.stream_position().await?;
+    let mut line = Vec::new();
+
+    loop {
+        let n = reader
+            .read_until(b'\n', &mut line)
+            .await
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        pos += n as u64;
+
+        if line.ends_with(b"\n") {
+            let text = String::from_utf8_lossy(&line);
+            if let Some(record) = parse_record(text.trim_end())
+                && let Some(event) = classify(&record, &config.ptrace_key)
+            {
+                forward(container_manager, audit, resolver, limiter, event).await;
+            }
+            line.clear();
+            continue;
+        }
+
+        // At EOF (possibly mid-line): summarise suppressed events, then wait for more.
+        for (owner, count) in limiter.expired(Instant::now()) {
+            audit.record(suppressed_event(&owner, count));
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+
+        let Ok(meta) = tokio::fs::metadata(path).await else {
+            continue; // between rotation and auditd reopening
+        };
+        if meta.ino() != inode {
+            debug!("{} was rotated, reopening", path.display());
+            (reader, inode) = open_log(path, false).await?;
+            pos = 0;
+            line.clear();
+        } else if meta.len() < pos {
+            debug!("{} was truncated, reading from the start", path.display());
+            reader.seek(std::io::SeekFrom::Start(0)).await?;
+            pos = 0;
+            line.clear();
+        }
+    }
+}
+
+async fn open_log(path: &Path, at_end: bool) -> Result<(BufReader<tokio::fs::File>, u64)> {
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("Failed to open audit log {}", path.display()))?;
+    let inode = file.metadata().await?.ino();
+    if at_end {
+        file.seek(std::io::SeekFrom::End(0)).await?;
+    }
+    Ok((BufReader::new(file), inode))
+}
+
+async fn forward(
+    container_manager: &ContainerManager,
+    audit: &AuditLogger,
+    resolver: &mut Resolver,
+    limiter: &mut RateLimiter,
+    event: SecurityEvent,
+) {
+    let mut owner = resolver.resolve(container_manager, event.pid).await;
+    if owner.is_none()
+        && let Some(ppid) = event.ppid
+    {
+        owner = resolver.resolve(container_manager, ppid).await;
+    }
+    let Some(owner) = owner else {
+        return; // not an agentman container (or the process is gone)
+    };
+
+    metrics::inc(
+        "agentman_security_events_total",
+        "Kernel security events from agent containers",
+        &[("category", event.category)],
+    );
+    if !limiter.allow(&owner, Instant::now()) {
+        return;
+    }
+    audit.record(to_audit_event(&event, &owner));
+}
+
+/// A security-relevant audit record, before it is attributed to a container.
+#[derive(Debug, Clone, PartialEq)]
+struct SecurityEvent {
+    /// `seccomp`, `capability` or `ptrace`.
+    category: &'static str,
+    outcome: AuditOutcome,
+    pid: u32,
+    ppid: Option<u32>,
+    detail: String,
+    fields: Vec<(&'static str, String)>,
+}
+
+/// The agentman container a process belongs to.
+#[derive(Debug, Clone, PartialEq)]
+struct Owner {
+    github_user: String,
+    project: String,
+    container: String,
+    /// Compose service, for sidecars.
+    service: Option<String>,
+    workspace: Option<String>,
+}
+
+fn to_audit_event(event: &SecurityEvent, owner: &Owner) -> AuditEvent {
+    let mut ev = AuditEvent::new(AuditEventKind::Security, event.outcome)
+        .github_user(Some(&owner.github_user))
+        .project(Some(&owner.project))
+        .detail(event.detail.clone())
+        .field("category", event.category)
+        .field("container", owner.container.clone())
+        .field("pid", event.pid.to_string());
+    if let Some(ref service) = owner.service {
+        ev = ev.field("service", service.clone());
+    }
+    if let Some(ref workspace) = owner.workspace {
+        ev = ev.field("workspace", workspace.clone());
+    }
+    for (key, value) in &event.fields {
+        ev = ev.field(key, value.clone());
+    }
+    ev
+}
+
+fn suppressed_event(owner: &Owner, count: u64) -> AuditEvent {
+    AuditEvent::new(AuditEventKind::Security, AuditOutcome::Denied)
+        .github_user(Some(&owner.github_user))
+        .project(Some(&owner.project))
+        .detail(format!(
+            "{count} more security event(s) suppressed by rate limit"
+        ))
+        .field("category", "suppressed")
+        .field("container", owner.container.clone())
+        .field("count", count.to_string())
+}
+
+/// One parsed auditd line: `type=<kind> msg=audit(<time>:<serial>): key=value ...`.
+#[derive(Debug, Default)]
+struct Record {
+    kind: String,
+    serial: String,
+    fields: HashMap<String, String>,
+    /// SELinux permission list (`{ sys_admin }`).
+    permissions: Vec<String>,
+    /// A bare `denied` word (SELinux `avc:  denied  { ... }`).
+    denied: bool,
+}
+
+impl Record {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.fields.get(key).map(String::as_str)
+    }
+
+    fn pid(&self, key: &str) -> Option<u32> {
+        self.get(key)?.parse().ok()
+    }
+}
+
+fn parse_record(line: &str) -> Option<Record> {
+    // ENRICHED log format appends interpreted fields after a group separator.
+    let line = line.split('\x1d').next().unwrap_or(line);
+    let rest = line.strip_prefix("type=")?;
+    let (kind, rest) = rest.split_once(' ')?;
+    let rest = rest.trim_start().strip_prefix("msg=audit(")?;
+    let (stamp, body) = rest.split_once("):")?;
+    let serial = stamp.split_once(':').map(|(_, s)| s).unwrap_or(stamp);
+
+    let mut record = Record {
+        kind: kind.to_string(),
+        serial: serial.to_string(),
+        ..Default::default()
+    };
+
+    let mut chars = body.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        if c == '{' {
+            let end = body[start..].find('}').map_or(body.len(), |i| start + i);
+            record
+                .permissions
+                .extend(body[start + 1..end].split_whitespace().map(str::to_string));
+            while chars.next_if(|&(i, _)| i <= end).is_some() {}
+            continue;
+        }
+
+        // Key (or bare word) up to '=' or whitespace.
+        let mut key_end = body.len();
+        while let Some(&(i, c)) = chars.peek() {
+            if c == '=' || c.is_whitespace() {
+                key_end = i;
+                break;
+            }
+            chars.next();
+        }
+        let key = &body[start..key_end];
+        if chars.next_if(|&(_, c)| c == '=').is_none() {
+            if key == "denied" {
+                record.denied = true;
+            }
+            continue;
+        }
+
+        let quote = chars
+            .next_if(|&(_, c)| c == '"' || c == '\'')
+            .map(|(_, c)| c);
+        let value_start = chars.peek().map_or(body.len(), |&(i, _)| i);
+        let mut value_end = body.len();
+        for (i, c) in chars.by_ref() {
+            let done = match quote {
+                Some(q) => c == q,
+                None => c.is_whitespace(),
+            };
+            if done {
+                value_end = i;
+                break;
+            }
+        }
+        let raw = &body[value_start..value_end.max(value_start)];
+        let value = match quote {
+            None if ENCODED_FIELDS.contains(&key) => {
+                decode_hex(raw).unwrap_or_else(|| raw.to_string())
+            }
+            _ => raw.to_string(),
+        };
+        record.fields.insert(key.to_string(), value);
+    }
+    Some(record)
+}
+
+/// Decode an auditd hex-encoded string (`2F62696E2F7368` -> `/bin/sh`).
+fn decode_hex(s: &str) -> Option<String> {
+    if s.is_empty() || !s.len().is_multiple_of(2) || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let bytes: Option<Vec<u8>> = (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect();
+    // NUL-separated argv in proctitle.
+    Some(String::from_utf8_lossy(&bytes?).replace('\0', " "))
+}
+
+/// Name of a seccomp filter action from the `code=` field.
+fn seccomp_action(code: &str) -> &'static str {
+    let code = u32::from_str_radix(code.trim_start_matches("0x"), 16).unwrap_or(u32::MAX);
+    match code & 0xffff_0000 {
+        0x8000_0000 => "kill_process",
+        0x0000_0000 => "kill_thread",
+        0x0003_0000 => "trap",
+        0x0005_0000 => "errno",
+        0x7fc0_0000 => "user_notif",
+        0x7ff0_0000 => "trace",
+        0x7ffc_0000 => "log",
+        0x7fff_0000 => "allow",
+        _ => "unknown",
+    }
+}
+
+fn classify(record: &Record, ptrace_key: &str) -> Option<SecurityEvent> {
+    let pid = record.pid("pid")?;
+    let comm = record.get("comm").unwrap_or("?").to_string();
+    let mut fields = vec![("audit_id", record.serial.clone()), ("comm", comm.clone())];
+    if let Some(exe) = record.get("exe") {
+        fields.push(("exe", exe.to_string()));
+    }
+
+    let (category, outcome, detail) = match record.kind.as_str() {
+        "SECCOMP" => {
+            let syscall = record.get("syscall").unwrap_or("?");
+            let action = seccomp_action(record.get("code").unwrap_or(""));
+            fields.push(("syscall", syscall.to_string()));
+            fields.push(("action", action.to_string()));
+            if let Some(arch) = record.get("arch") {
+                fields.push(("arch", arch.to_string()));
+            }
+            let outcome = match action {
+                "log" | "allow" => AuditOutcome::Success,
+                _ => AuditOutcome::Denied,
+            };
+            (
+                "seccomp",
+                outcome,
+                format!("seccomp {action} of syscall {syscall} by {comm}"),
+            )
+        }
+        "AVC" if record.get("apparmor") == Some("DENIED") => {
+            let profile = record.get("profile").unwrap_or("?");
+            fields.push(("profile", profile.to_string()));
+            match record.get("operation")? {
+                "capable" => {
+                    let cap = record.get("capname").unwrap_or("?");
+                    fields.push(("capability", cap.to_string()));
+                    let detail = format!("apparmor denied capability {cap} to {comm}");
+                    ("capability", AuditOutcome::Denied, detail)
+                }
+                "ptrace" => {
+                    let peer = record.get("peer").unwrap_or("?");
+                    fields.push(("target", peer.to_string()));
+                    let detail = format!("apparmor denied ptrace of {peer} by {comm}");
+                    ("ptrace", AuditOutcome::Denied, detail)
+                }
+                _ => return None,
+            }
+        }
+        "AVC" if record.denied => {
+            let tclass = record.get("tclass")?;
+            let perms = record.permissions.join(",");
+            let outcome = if record.get("permissive") == Some("1") {
+                AuditOutcome::Success
+            } else {
+                AuditOutcome::Denied
+            };
+            if let Some(context) = record.get("scontext") {
+                fields.push(("scontext", context.to_string()));
+            }
+            if matches!(
+                tclass,
+                "capability" | "capability2" | "cap_userns" | "cap2_userns"
+            ) {
+                fields.push(("capability", perms.clone()));
+                (
+                    "capability",
+                    outcome,
+                    format!("selinux denied capability {perms} to {comm}"),
+                )
+            } else if tclass == "process" && record.permissions.iter().any(|p| p == "ptrace") {
+                if let Some(target) = record.get("tcontext") {
+                    fields.push(("target", target.to_string()));
+                }
+                (
+                    "ptrace",
+                    outcome,
+                    format!("selinux denied ptrace by {comm}"),
+                )
+            } else {
+                return None;
+            }
+        }
+        "SYSCALL" if record.get("key") == Some(ptrace_key) => {
+            let success = record.get("success") == Some("yes");
+            fields.push(("syscall", record.get("syscall").unwrap_or("?").to_string()));
+            if let Some(request) = record.get("a0") {
+                fields.push(("request", request.to_string()));
+            }
+            if let Some(exit) = record.get("exit") {
+                fields.push(("exit", exit.to_string()));
+            }
+            let (outcome, verb) = if success {
+                (AuditOutcome::Success, "succeeded")
+            } else {
+                (AuditOutcome::Failure, "failed")
+            };
+            ("ptrace", outcome, format!("ptrace by {comm} {verb}"))
+        }
+        _ => return None,
+    };
+
+    Some(SecurityEvent {
+        category,
+        outcome,
+        pid,
+        ppid: record.pid("ppid"),
+        detail,
+        fields,
+    })
+}
+
+/// Find the Docker container ID in `/proc/<pid>/cgroup` (cgroup v1 `/docker/<id>`, v2
+/// `docker-<id>.scope`, ...).
+fn container_id_from_cgroup(content: &str) -> Option<&str> {
+    content
+        .lines()
+        .flat_map(|line| line.split(['/', '-', '.', ':']))
+        .find(|part| part.len() == 64 && part.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+/// Maps processes to agentman containers, caching container lookups.
+#[derive(Default)]
+struct Resolver {
+    containers: HashMap<String, Option<Owner>>,
+}
+
+impl Resolver {
+    async fn resolve(&mut self, container_manager: &ContainerManager, pid: u32) -> Option<Owner> {
+        let cgroup = tokio::fs::read_to_string(format!("/proc/{pid}/cgroup"))
+            .await
+            .ok()?;
+        let id = container_id_from_cgroup(&cgroup)?.to_string();
+        if let Some(owner) = self.containers.get(&id) {
+            return owner.clone();
+        }
+
+        let owner = match container_manager
+            .docker()
+            .inspect_container(&id, None::<InspectContainerOptions>)
+            .await
+        {
+            Ok(info) => {
+                let labels = info.config.and_then(|c| c.labels).unwrap_or_default();
+                match (
+                    labels.get("agentman.managed").map(String::as_str),
+                    labels.get("agentman.github_user"),
+                    labels.get("agentman.project"),
+                ) {
+                    (Some("true"), Some(user), Some(project)) => Some(Owner {
+                        github_user: user.clone(),
+                        project: project.clone(),
+                        container: info
+                            .name
+                            .map(|n| n.trim_start_matches('/').to_string())
+                            .unwrap_or_else(|| id[..12].to_string()),
+                        service: labels.get("agentman.stack_service").cloned(),
+                        workspace: labels.get("agentman.workspace_path").cloned(),
+                    }),
+                    _ => None,
+                }
+            }
+            Err(e) => {
+                debug!("Failed to inspect container {}: {}", id, e);
+                return None; // not cached: may be a transient error
+            }
+        };
+
+        if self.containers.len() >= MAX_CACHED_CONTAINERS {
+            self.containers.clear();
+        }
+        self.containers.insert(id, owner.clone());
+        owner
+    }
+}
+
+/// Per-container limit of forwarded events per [`RATE_WINDOW`].
+struct RateLimiter {
+    max: u32,
+    windows: HashMap<String, Window>,
+}
+
+struct Window {
+    start: Instant,
+    forwarded: u32,
+    suppressed: u64,
+    owner: Owner,
+}
+
+impl RateLimiter {
+    fn new(max: u32) -> Self {
+        Self {
+            max,
+            windows: HashMap::new(),
+        }
+    }
+
+    /// Count an event for `owner`'s container; false once the window's budget is spent.
+    fn allow(&mut self, owner: &Owner, now: Instant) -> bool {
+        let window = self
+            .windows
+            .entry(owner.container.clone())
+            .or_insert_with(|| Window {
+                start: now,
+                forwarded: 0,
+                suppressed: 0,
+                owner: owner.clone(),
+            });
+        if window.forwarded < self.max {
+            window.forwarded += 1;
+            true
+        } else {
+            window.suppressed += 1;
+            false
+        }
+    }
+
+    /// Drop finished windows, returning those that suppressed events.
+    fn expired(&mut self, now: Instant) -> Vec<(Owner, u64)> {
+        let mut out = Vec::new();
+        self.windows.retain(|_, window| {
+            if now.duration_since(window.start) < RATE_WINDOW {
+                return true;
+            }
+            if window.suppressed > 0 {
+                out.push((window.owner.clone(), window.suppressed));
+            }
+            false
+        });
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(line: &str) -> Option<SecurityEvent> {
+        classify(&parse_record(line).unwrap(), "agentman-ptrace")
+    }
+
+    fn field<'a>(ev: &'a SecurityEvent, key: &str) -> Option<&'a str> {
+        ev.fields
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    #[test]
+    fn test_seccomp_record() {
+        let ev = event(
+            "type=SECCOMP msg=audit(1700000000.123:456): auid=4294967295 uid=0 gid=0 \
+             ses=4294967295 pid=4242 comm=\"unshare\" exe=\"/usr/bin/unshare\" sig=0 \
+             arch=c000003e syscall=272 compat=0 ip=0x7f0 code=0x50000",
+        )
+        .unwrap();
+        assert_eq!(ev.category, "seccomp");
+        assert_eq!(ev.outcome, AuditOutcome::Denied);
+        assert_eq!(ev.pid, 4242);
+        assert_eq!(field(&ev, "audit_id"), Some("456"));
+        assert_eq!(field(&ev, "action"), Some("errno"));
+        assert_eq!(field(&ev, "syscall"), Some("272"));
+        assert_eq!(field(&ev, "exe"), Some("/usr/bin/unshare"));
+    }
+
+    #[test]
+    fn test_avc_records() {
+        let ev = event(
+            "type=AVC msg=audit(1700000000.5:7): apparmor=\"DENIED\" operation=\"capable\" \
+             class=\"cap\" profile=\"docker-default\" pid=99 comm=\"mount\" capability=21 \
+             capname=\"sys_admin\"",
+        )
+        .unwrap();
+        assert_eq!(ev.category, "capability");
+        assert_eq!(field(&ev, "capability"), Some("sys_admin"));
+        assert_eq!(field(&ev, "profile"), Some("docker-default"));
+
+        let ev = event(
+            "type=AVC msg=audit(1700000000.5:8): avc:  denied  { ptrace } for  pid=12 \
+             comm=\"strace\" scontext=system_u:system_r:container_t:s0:c1,c2 \
+             tcontext=system_u:system_r:container_t:s0:c3,c4 tclass=process permissive=0",
+        )
+        .unwrap();
+        assert_eq!(ev.category, "ptrace");
+        assert_eq!(ev.outcome, AuditOutcome::Denied);
+        assert_eq!(field(&ev, "comm"), Some("strace"));
+
+        // Other AppArmor denials (file access, ...) are not forwarded.
+        assert!(event(
+            "type=AVC msg=audit(1.0:9): apparmor=\"DENIED\" operation=\"open\" pid=1 comm=\"cat\""
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_ptrace_rule_record() {
+        // Hex-encoded comm (contains a space), ENRICHED trailer.
+        let ev = event(
+            "type=SYSCALL msg=audit(1700000000.1:77): arch=c000003e syscall=101 success=no \
+             exit=-1 a0=10 a1=1 ppid=300 pid=301 comm=6D7920676462 exe=\"/usr/bin/gdb\" \
+             key=\"agentman-ptrace\"\x1dARCH=x86_64 SYSCALL=ptrace",
+        )
+        .unwrap();
+        assert_eq!(ev.category, "ptrace");
+        assert_eq!(ev.outcome, AuditOutcome::Failure);
+        assert_eq!(ev.ppid, Some(300));
+        assert_eq!(field(&ev, "comm"), Some("my gdb"));
+        assert_eq!(field(&ev, "request"), Some("10"));
+
+        assert!(event(
+            "type=SYSCALL msg=audit(1.0:78): syscall=101 success=yes pid=1 comm=\"x\" key=\"other\""
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_container_id_from_cgroup() {
+        let id = "a".repeat(64);
+        let v2 = format!("0::/system.slice/docker-{id}.scope\n");
+        assert_eq!(container_id_from_cgroup(&v2), Some(id.as_str()));
+        let v1 = format!("12:pids:/docker/{id}\n1:name=systemd:/docker/{id}\n");
+        assert_eq!(container_id_from_cgroup(&v1), Some(id.as_str()));
+        assert_eq!(
+            container_id_from_cgroup("0::/user.slice/session-3.scope\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let owner = Owner {
+            github_user: "octocat".into(),
+            project: "demo".into(),
+            container: "agentman-octocat-demo".into(),
+            service: None,
+            workspace: None,
+        };
+        let mut limiter = RateLimiter::new(2);
+        let start = Instant::now();
+        assert!(limiter.allow(&owner, start));
+        assert!(limiter.allow(&owner, start));
+        assert!(!limiter.allow(&owner, start));
+        assert!(!limiter.allow(&owner, start));
+        assert!(limiter.expired(start).is_empty());
+
+        let later = start + RATE_WINDOW;
+        let expired = limiter.expired(later);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].1, 2);
+        assert!(limiter.allow(&owner, later));
+    }
+}
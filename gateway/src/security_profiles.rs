@@ -0,0 +1,163 @@
+//! Per-user container security profiles (`[security_profiles]`).
+//!
+//! The profile of a user is resolved when one of their containers is created and recorded on
+//! the container as [`PROFILE_LABEL`], so that later decisions (project links, `agentman
+//! limits`) follow what the container actually runs with rather than the current config.
+//! Team memberships are looked up with the configured token and cached for `cache_secs`; when
+//! GitHub can't be reached, the last answer is used, and without one container creation fails
+//! rather than falling through to a more permissive profile.
+
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use tracing::warn;
+
+use crate::config::{SecurityProfile, SecurityProfilesConfig};
+use crate::github;
+
+/// Label with the name of the profile a container was created with.
+pub const PROFILE_LABEL: &str = "agentman.security_profile";
+
+/// Internal Docker network (no route out) for containers of profiles without egress.
+pub const NO_EGRESS_NETWORK: &str = "agentman-no-egress";
+
+/// Team memberships by (team, GitHub user), with when they were fetched.
+type Cache = HashMap<(String, String), (Instant, bool)>;
+
+static MEMBERSHIPS: LazyLock<Mutex<Cache>> = LazyLock::new(Default::default);
+
+/// The first profile that lists `github_user` or one of their teams.
+pub async fn resolve<'a>(
+    config: &'a SecurityProfilesConfig,
+    github_user: &str,
+) -> Result<Option<&'a SecurityProfile>> {
+    for profile in &config.profiles {
+        if profile.lists_user(github_user) {
+            return Ok(Some(profile));
+        }
+        for team in &profile.teams {
+            if is_member(config, team, github_user)
+                .await
+                .with_context(|| format!("resolving security profile {}", profile.name))?
+            {
+                return Ok(Some(profile));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Whether `github_user` is an active member of `team` (`<org>/<team-slug>`).
+async fn is_member(config: &SecurityProfilesConfig, team: &str, github_user: &str) -> Result<bool> {
+    let key = (team.to_string(), github_user.to_string());
+    let cached = MEMBERSHIPS.lock().unwrap().get(&key).copied();
+    if let Some((at, member)) = cached
+        && at.elapsed() < Duration::from_secs(config.cache_secs)
+    {
+        return Ok(member);
+    }
+    let member = match fetch_membership(config, team, github_user).await {
+        Ok(member) => member,
+        Err(e) => match cached {
+            Some((_, member)) => {
+                warn!(
+                    "Using cached {} membership of {}: {:#}",
+                    team, github_user, e
+                );
+                return Ok(member);
+            }
+            None => return Err(e),
+        },
+    };
+    MEMBERSHIPS
+        .lock()
+        .unwrap()
+        .insert(key, (Instant::now(), member));
+    Ok(member)
+}
+
+async fn fetch_membership(
+    config: &SecurityProfilesConfig,
+    team: &str,
+    github_user: &str,
+) -> Result<bool> {
+    let (org, slug) = team
+        .split_once('/')
+        .with_context(|| format!("invalid team {team:?}"))?;
+    let path = config
+        .github_token_file
+        .as_ref()
+        .context("security_profiles: github_token_file is not set")?;
+    let token = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    github::api()
+        .fetch_team_membership(org, slug, github_user, token.trim())
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(name: &str, users: &[&str], teams: &[&str]) -> SecurityProfile {
+        SecurityProfile {
+            name: name.to_string(),
+            users: users.iter().map(|u| u.to_string()).collect(),
+            teams: teams.iter().map(|t| t.to_string()).collect(),
+            cap_add: None,
+            no_new_privileges: None,
+            readonly_rootfs: None,
+            memory_limit: None,
+            cpu_limit: None,
+            pids_limit: None,
+            egress: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let config = SecurityProfilesConfig {
+            github_token_file: Some("/nonexistent/token".into()),
+            profiles: vec![
+                profile("trusted", &["octocat"], &[]),
+                profile("platform", &[], &["acme/platform"]),
+                profile("untrusted", &["*"], &[]),
+            ],
+            ..Default::default()
+        };
+        let name = |p: Option<&SecurityProfile>| p.map(|p| p.name.clone());
+
+        assert_eq!(
+            name(resolve(&config, "octocat").await.unwrap()),
+            Some("trusted".to_string())
+        );
+        // Cached memberships are used without asking GitHub.
+        let cache = |user: &str, member: bool| {
+            MEMBERSHIPS.lock().unwrap().insert(
+                ("acme/platform".to_string(), user.to_string()),
+                (Instant::now(), member),
+            );
+        };
+        cache("hubot", true);
+        cache("monalisa", false);
+        assert_eq!(
+            name(resolve(&config, "hubot").await.unwrap()),
+            Some("platform".to_string())
+        );
+        assert_eq!(
+            name(resolve(&config, "monalisa").await.unwrap()),
+            Some("untrusted".to_string())
+        );
+        // A membership that can't be checked fails instead of falling through.
+        assert!(resolve(&config, "unknown-user").await.is_err());
+
+        let config = SecurityProfilesConfig {
+            profiles: vec![profile("trusted", &["octocat"], &[])],
+            ..Default::default()
+        };
+        assert!(resolve(&config, "hubot").await.unwrap().is_none());
+    }
+}
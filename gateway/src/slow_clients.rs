@@ -0,0 +1,199 @@
+//! Back-pressure on command output and slow-client detection (`[slow_clients]`).
+//!
+//! russh queues channel data without limit while the client's window is closed, so a client
+//! that stops reading (a suspended laptop, a wedged editor) would have the gateway buffer a
+//! command's whole output. Each exec channel gets a [`Flow`] counting the output handed to the
+//! connection since the connection last had nothing queued for the channel, which it reports
+//! when the client opens its window again. Beyond the client's window plus
+//! `max_buffered_bytes`, the exec's output isn't read until the client catches up; a channel
+//! that stays stalled is logged and, with `close_after_secs`, closed.
+//!
+//! The client's window is only known from its adjustments, so until the first one it is
+//! assumed to be OpenSSH's default.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::compression::Traffic;
+use crate::config::SlowClientsConfig;
+use crate::metrics;
+
+/// Window assumed for a client before it adjusts it (OpenSSH's channel default).
+const DEFAULT_CLIENT_WINDOW: u64 = 2 * 1024 * 1024;
+
+/// Bounds of the stall histogram, in seconds.
+const STALL_BOUNDS: &[f64] = &[1.0, 5.0, 15.0, 30.0, 60.0, 300.0, 900.0, 3600.0];
+
+/// Bounds of the throughput histogram, in bytes per second.
+const THROUGHPUT_BOUNDS: &[f64] = &[1e3, 1e4, 1e5, 1e6, 1e7, 1e8];
+
+/// Output of one channel on its way to the client.
+#[derive(Debug)]
+pub struct Flow {
+    /// Bytes handed to the connection since it last had nothing queued for the channel.
+    outstanding: AtomicU64,
+    /// The client's window at its last adjustment.
+    window: AtomicU64,
+    drained: Notify,
+}
+
+impl Default for Flow {
+    fn default() -> Self {
+        Self {
+            outstanding: AtomicU64::new(0),
+            window: AtomicU64::new(DEFAULT_CLIENT_WINDOW),
+            drained: Notify::new(),
+        }
+    }
+}
+
+/// The client read nothing for `close_after_secs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stalled {
+    pub secs: u64,
+}
+
+impl Flow {
+    /// Count `bytes` handed to the connection.
+    pub fn queued(&self, bytes: usize) {
+        self.outstanding.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// The client adjusted its window to `window` and the connection sent everything queued
+    /// for the channel.
+    pub fn drained(&self, window: u32) {
+        self.window.store(window as u64, Ordering::Relaxed);
+        self.outstanding.store(0, Ordering::Relaxed);
+        self.drained.notify_waiters();
+    }
+
+    fn over_limit(&self, config: &SlowClientsConfig) -> bool {
+        let window = self.window.load(Ordering::Relaxed);
+        self.outstanding.load(Ordering::Relaxed) >= window.saturating_add(config.max_buffered_bytes)
+    }
+
+    /// Wait until the channel may take more output; `what` names it in logs. Fails once the
+    /// client has read nothing for `close_after_secs`.
+    pub async fn ready(&self, config: &SlowClientsConfig, what: &str) -> Result<(), Stalled> {
+        if !self.over_limit(config) {
+            return Ok(());
+        }
+        let started = Instant::now();
+        let warn_at = started + Duration::from_secs(config.warn_after_secs);
+        let close_at = config.close_after_secs.map(|secs| started + Duration::from_secs(secs));
+        let mut warned = false;
+        let result = loop {
+            let notified = self.drained.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if !self.over_limit(config) {
+                break Ok(());
+            }
+            let deadline = if warned { close_at } else { Some(warn_at) };
+            let Some(deadline) = deadline else {
+                notified.await;
+                continue;
+            };
+            if tokio::time::timeout_at(deadline, notified).await.is_ok() {
+                continue;
+            }
+            if warned {
+                break Err(Stalled {
+                    secs: config.close_after_secs.unwrap_or_default(),
+                });
+            }
+            warned = true;
+            warn!(
+                "The client of {} has read no output for {}s; holding the command's output",
+                what, config.warn_after_secs
+            );
+            metrics::inc(
+                "agentman_ssh_stalled_channels_total",
+                "Channels whose client read no output for [slow_clients] warn_after_secs.",
+                &[],
+            );
+        };
+        let stalled_for = started.elapsed();
+        metrics::observe(
+            "agentman_ssh_channel_stall_seconds",
+            "How long command output waited for clients that had stopped reading.",
+            STALL_BOUNDS,
+            &[],
+            stalled_for.as_secs_f64(),
+        );
+        if warned && result.is_ok() {
+            info!("The client of {} caught up after {}s", what, stalled_for.as_secs());
+        }
+        result
+    }
+}
+
+/// Record an ended connection's channel data and throughput.
+pub fn record_connection(traffic: &Traffic, duration: Duration) {
+    let (received, sent) = traffic.payload();
+    for (direction, bytes) in [("in", received), ("out", sent)] {
+        metrics::add(
+            "agentman_ssh_channel_bytes_total",
+            "Channel data of SSH connections.",
+            &[("direction", direction)],
+            bytes as f64,
+        );
+        // Short connections and idle directions say nothing about throughput.
+        if duration >= Duration::from_secs(1) && bytes > 0 {
+            metrics::observe(
+                "agentman_ssh_connection_throughput_bytes_per_second",
+                "Average channel data rate of SSH connections lasting at least a second.",
+                THROUGHPUT_BOUNDS,
+                &[("direction", direction)],
+                bytes as f64 / duration.as_secs_f64(),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(close_after_secs: Option<u64>) -> SlowClientsConfig {
+        SlowClientsConfig {
+            max_buffered_bytes: 1000,
+            warn_after_secs: 1,
+            close_after_secs,
+            notify: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ready_waits_for_the_client() {
+        let flow = std::sync::Arc::new(Flow::default());
+        flow.queued(DEFAULT_CLIENT_WINDOW as usize + 999);
+        assert_eq!(flow.ready(&config(Some(5)), "test").await, Ok(()));
+
+        flow.queued(1);
+        let waiter = tokio::spawn({
+            let flow = flow.clone();
+            async move { flow.ready(&config(Some(5)), "test").await }
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!waiter.is_finished());
+        flow.drained(4096);
+        assert_eq!(waiter.await.unwrap(), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn test_ready_gives_up_on_stalled_clients() {
+        let flow = Flow::default();
+        flow.queued(DEFAULT_CLIENT_WINDOW as usize + 1000);
+        assert_eq!(flow.ready(&config(Some(1)), "test").await, Err(Stalled { secs: 1 }));
+
+        // Without close_after_secs it keeps waiting.
+        let waited =
+            tokio::time::timeout(Duration::from_millis(1500), flow.ready(&config(None), "test")).await;
+        assert!(waited.is_err());
+    }
+}
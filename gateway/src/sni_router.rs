@@ -0,0 +1,331 @@
+//! TLS passthrough router (`[sni_router]`).
+//!
+//! Sandboxes running raw TLS services (gRPC, databases, anything that isn't worth an HTTP
+//! reverse proxy) share one public port: the router reads the server name from each
+//! connection's ClientHello, which is sent in the clear, and relays the connection unchanged
+//! to the port the workspace exposed with `agentman tls`. TLS is never terminated here, so the
+//! service presents its own certificate (for `*.<domain>` or its own hostname).
+//!
+//! Hostnames are `<project>--<github-user>.<domain>`. GitHub usernames can't contain `--`, so
+//! the last `--` separates the two even when the project name has one. Only workspaces that
+//! opted in are reachable, and connections go through a bridge exec like `~C -L` forwards, so
+//! the service only needs to listen on the container's loopback.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info};
+
+use crate::docker::ContainerManager;
+use crate::metrics;
+use crate::ssh::bridge_into_container;
+use crate::state::WorkspaceInfo;
+
+/// Upper bound for the TLS records carrying a ClientHello (post-quantum key shares make them
+/// a few KiB).
+const MAX_CLIENT_HELLO: usize = 32 * 1024;
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const EXTENSION_SERVER_NAME: u16 = 0;
+const SERVER_NAME_HOST: u8 = 0;
+
+/// The hostname routed to a workspace, or `None` if its project name can't be part of one
+/// (underscores, non-ASCII, or longer than a DNS label).
+pub fn hostname(domain: &str, github_user: &str, project: &str) -> Option<String> {
+    let label = format!("{project}--{github_user}").to_ascii_lowercase();
+    let valid = label.len() <= 63
+        && label
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-');
+    valid.then(|| format!("{label}.{domain}"))
+}
+
+/// The `(github_user, project)` a server name addresses, lowercased; `None` if it isn't a
+/// sandbox hostname under `domain`.
+fn route<'a>(domain: &str, server_name: &'a str) -> Option<(&'a str, &'a str)> {
+    let server_name = server_name.strip_suffix('.').unwrap_or(server_name);
+    let label = server_name
+        .strip_suffix(domain)?
+        .strip_suffix('.')
+        .filter(|label| !label.contains('.'))?;
+    let (project, github_user) = label.rsplit_once("--")?;
+    (!project.is_empty() && !github_user.is_empty()).then_some((github_user, project))
+}
+
+/// The server name of the ClientHello at the start of what the client sent. `None` while
+/// incomplete; `Some(None)` if it isn't a ClientHello or names no host.
+fn server_name(data: &[u8]) -> Option<Option<String>> {
+    // The handshake message can be split over several records.
+    let mut handshake = Vec::new();
+    let mut rest = data;
+    loop {
+        let header = rest.get(..5)?;
+        if header[0] != CONTENT_TYPE_HANDSHAKE {
+            return Some(None);
+        }
+        let length = u16::from_be_bytes([header[3], header[4]]) as usize;
+        handshake.extend_from_slice(rest.get(5..5 + length)?);
+        rest = &rest[5 + length..];
+        if handshake.len() < 4 {
+            continue;
+        }
+        if handshake[0] != HANDSHAKE_CLIENT_HELLO {
+            return Some(None);
+        }
+        let length = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+        if let Some(body) = handshake.get(4..4 + length) {
+            return Some(client_hello_server_name(body));
+        }
+    }
+}
+
+/// Split `n` bytes off the front of `data`.
+fn take<'a>(data: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+    let taken = data.get(..n)?;
+    *data = &data[n..];
+    Some(taken)
+}
+
+/// A vector with a one- or two-byte length prefix.
+fn take_prefixed<'a>(data: &mut &'a [u8], prefix: usize) -> Option<&'a [u8]> {
+    let length = take(data, prefix)?
+        .iter()
+        .fold(0, |n, &b| n << 8 | b as usize);
+    take(data, length)
+}
+
+fn client_hello_server_name(mut body: &[u8]) -> Option<String> {
+    // Version and random, then session ID, cipher suites and compression methods.
+    take(&mut body, 2 + 32)?;
+    take_prefixed(&mut body, 1)?;
+    take_prefixed(&mut body, 2)?;
+    take_prefixed(&mut body, 1)?;
+    let mut extensions = take_prefixed(&mut body, 2)?;
+    while !extensions.is_empty() {
+        let kind = u16::from_be_bytes(take(&mut extensions, 2)?.try_into().ok()?);
+        let mut data = take_prefixed(&mut extensions, 2)?;
+        if kind != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let mut names = take_prefixed(&mut data, 2)?;
+        while !names.is_empty() {
+            let name_type = take(&mut names, 1)?[0];
+            let name = take_prefixed(&mut names, 2)?;
+            if name_type == SERVER_NAME_HOST {
+                return std::str::from_utf8(name)
+                    .ok()
+                    .map(|name| name.to_ascii_lowercase());
+            }
+        }
+    }
+    None
+}
+
+/// Start the router if `[sni_router] enabled = true`.
+pub async fn serve(container_manager: Arc<ContainerManager>) -> Result<()> {
+    let config = &container_manager.config().sni_router;
+    if !config.enabled {
+        return Ok(());
+    }
+    let listener = TcpListener::bind(&config.listen_addr)
+        .await
+        .with_context(|| format!("Failed to bind SNI router on {}", config.listen_addr))?;
+    info!(
+        "SNI router listening on {} for *.{}",
+        config.listen_addr, config.domain
+    );
+
+    tokio::spawn(async move {
+        loop {
+            let Ok((stream, peer)) = listener.accept().await else {
+                continue;
+            };
+            let container_manager = container_manager.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_client(stream, &container_manager).await {
+                    debug!("SNI router connection from {} ended: {:#}", peer, e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+fn count_connection(outcome: &str) {
+    metrics::inc(
+        "agentman_sni_router_connections_total",
+        "Connections to the SNI router by outcome.",
+        &[("outcome", outcome)],
+    );
+}
+
+async fn handle_client(mut client: TcpStream, container_manager: &ContainerManager) -> Result<()> {
+    let config = &container_manager.config().sni_router;
+    let timeout = Duration::from_secs(config.client_hello_timeout_secs);
+    let (hello, name) = match tokio::time::timeout(timeout, read_client_hello(&mut client)).await {
+        Ok(Ok((hello, Some(name)))) => (hello, name),
+        Ok(Ok((_, None))) => {
+            count_connection("no_server_name");
+            anyhow::bail!("Not a TLS ClientHello with a server name");
+        }
+        Ok(Err(e)) => {
+            count_connection("bad_client_hello");
+            return Err(e);
+        }
+        Err(_) => {
+            count_connection("bad_client_hello");
+            anyhow::bail!("Timed out waiting for a ClientHello");
+        }
+    };
+    let Some((ws, port)) = find_workspace(container_manager, &config.domain, &name).await else {
+        count_connection("unknown_host");
+        anyhow::bail!("no exposed sandbox for {name}");
+    };
+
+    count_connection("routed");
+    debug!(
+        "SNI router: {} -> {}/{} port {}",
+        name, ws.github_user, ws.project, port
+    );
+    bridge_into_container(
+        container_manager,
+        &ws.container_name,
+        "127.0.0.1",
+        port,
+        client,
+        &hello,
+    )
+    .await
+}
+
+/// Read until the ClientHello is complete; returns what was read and its server name.
+async fn read_client_hello(stream: &mut TcpStream) -> Result<(Vec<u8>, Option<String>)> {
+    let mut buf = Vec::with_capacity(2048);
+    let mut chunk = [0u8; 2048];
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            anyhow::bail!("Client closed before sending a ClientHello");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(name) = server_name(&buf) {
+            return Ok((buf, name));
+        }
+        if buf.len() > MAX_CLIENT_HELLO {
+            anyhow::bail!("ClientHello too large");
+        }
+    }
+}
+
+/// The workspace whose hostname is `name`, and the port it exposed.
+async fn find_workspace(
+    container_manager: &ContainerManager,
+    domain: &str,
+    name: &str,
+) -> Option<(WorkspaceInfo, u16)> {
+    let (github_user, _) = route(domain, name)?;
+    let state = container_manager.state();
+    for user in state.known_github_users().await {
+        if !user.eq_ignore_ascii_case(github_user) {
+            continue;
+        }
+        for ws in state.list_workspaces(&user).await {
+            if let Some(port) = ws.tls_port
+                && hostname(domain, &ws.github_user, &ws.project).as_deref() == Some(name)
+            {
+                return Some((ws, port));
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A ClientHello record with the given server name (or none), like a browser's.
+    fn client_hello(name: Option<&str>) -> Vec<u8> {
+        let mut extensions = Vec::new();
+        // supported_versions
+        extensions.extend_from_slice(&[0, 43, 0, 3, 2, 3, 4]);
+        if let Some(name) = name {
+            let mut list = vec![SERVER_NAME_HOST];
+            list.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            list.extend_from_slice(name.as_bytes());
+            extensions.extend_from_slice(&EXTENSION_SERVER_NAME.to_be_bytes());
+            extensions.extend_from_slice(&(list.len() as u16 + 2).to_be_bytes());
+            extensions.extend_from_slice(&(list.len() as u16).to_be_bytes());
+            extensions.extend_from_slice(&list);
+        }
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[7; 32]);
+        body.extend_from_slice(&[32]);
+        body.extend_from_slice(&[9; 32]);
+        body.extend_from_slice(&[0, 4, 0x13, 0x01, 0x13, 0x02]);
+        body.extend_from_slice(&[1, 0]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut handshake = vec![HANDSHAKE_CLIENT_HELLO];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&body);
+        let mut record = vec![CONTENT_TYPE_HANDSHAKE, 3, 1];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[test]
+    fn test_server_name() {
+        let hello = client_hello(Some("API--OctoCat.tls.example.com"));
+        assert_eq!(
+            server_name(&hello),
+            Some(Some("api--octocat.tls.example.com".to_string()))
+        );
+        assert_eq!(server_name(&hello[..hello.len() - 1]), None);
+        assert_eq!(server_name(&client_hello(None)), Some(None));
+        assert_eq!(server_name(b"GET / HTTP/1.1\r\n"), Some(None));
+
+        // The same handshake split over two records.
+        let handshake = &hello[5..];
+        let mut split = Vec::new();
+        for part in [&handshake[..10], &handshake[10..]] {
+            split.extend_from_slice(&[CONTENT_TYPE_HANDSHAKE, 3, 1]);
+            split.extend_from_slice(&(part.len() as u16).to_be_bytes());
+            split.extend_from_slice(part);
+        }
+        assert_eq!(
+            server_name(&split),
+            Some(Some("api--octocat.tls.example.com".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_route() {
+        let domain = "tls.example.com";
+        assert_eq!(
+            route(domain, "api--octocat.tls.example.com"),
+            Some(("octocat", "api"))
+        );
+        assert_eq!(
+            route(domain, "my--api--octo-cat.tls.example.com."),
+            Some(("octo-cat", "my--api"))
+        );
+        assert_eq!(route(domain, "api.tls.example.com"), None);
+        assert_eq!(route(domain, "a.api--octocat.tls.example.com"), None);
+        assert_eq!(route(domain, "api--octocat.example.com"), None);
+        assert_eq!(route(domain, "api--octocat-tls.example.com"), None);
+
+        assert_eq!(
+            hostname(domain, "OctoCat", "My-API").as_deref(),
+            Some("my-api--octocat.tls.example.com")
+        );
+        assert_eq!(hostname(domain, "octocat", "my_api"), None);
+        assert_eq!(hostname(domain, "octocat", &"a".repeat(60)), None);
+    }
+}
@@ -4,52 +4,89 @@
 //! - Public key authentication with GitHub verification
 //! - Session channels (shell, exec)
 //! - Port forwarding (direct-tcpip, tcpip-forward)
+//! - Unix socket forwarding (direct-streamlocal, streamlocal-forward)
 
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::time::Duration;
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use bollard::exec::StartExecResults;
 use bollard::container::LogOutput;
 use chrono::Utc;
 use futures::StreamExt;
 use russh::server::{Auth, Handler, Msg, Session};
-use russh::{Channel, ChannelId, CryptoVec, MethodKind, MethodSet};
+use russh::{Channel, ChannelId, ChannelMsg, CryptoVec, MethodKind, MethodSet};
 use russh::keys::PublicKey;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, UnixListener, UnixStream};
-use tokio::process::Command;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, info, warn};
 
-use crate::config::{GatewayConfig, ShellMode};
-use crate::docker::ContainerManager;
+use crate::admission;
+use crate::audit::{AuditEvent, AuditEventKind, AuditLogger, AuditOutcome};
+use crate::auth_guard::{
+    record_delay_metric, record_outcome_metric, record_skipped_lookup_metric, AuthGuard,
+};
+use crate::compression;
+use crate::config::{GatewayConfig, HostKeyBackend, ShellMode};
+use crate::backend::ContainerBackend;
+use crate::docker::{ContainerManager, QuotaExceeded, EXEC_MARKER_ENV};
+use crate::env_file;
+use crate::file_view;
 use crate::gateway_control::{
-    execute_gateway_control_command, parse_gateway_control_command, render_sandbox_stats_fast,
-    GatewayControlExecution,
+    parse_gateway_control_command, EnvFileAction, GatewayControlCommand, GatewayControlExecution,
+    EXEC_TIMEOUT_EXIT_STATUS, PING_TIMEOUT,
 };
+use crate::jobs;
+use crate::github_metadata;
 use crate::github::{
-    compute_fingerprint_from_pubkey, parse_ssh_username, public_key_to_openssh,
-    validate_github_username, validate_project_name, GitHubKeyFetcher,
+    compute_fingerprint, compute_fingerprint_from_pubkey, parse_image_variant, parse_ssh_key,
+    parse_ssh_username,
+    public_key_to_openssh, validate_github_username, validate_project_name, GitHubKeyFetcher,
 };
-use crate::state::{KeyCacheEntry, StateManager};
+use crate::memory_guard;
+use crate::metrics;
+use crate::migration::{self, Manifest};
+use crate::motd;
+use crate::org_access;
+use crate::relay;
+use crate::ephemeral;
+use crate::guest::{self, Guest};
+use crate::host_fs;
+use crate::i18n::{self, Lang};
+use crate::impersonation::{self, Impersonation};
+use crate::inspect_cache::InspectCache;
+use crate::escape::{self, EscapeParser, Input as EscapeInput, PromptCommand};
+use crate::preferences;
+use crate::provision;
+use crate::slow_clients;
+use crate::streamlocal;
+use crate::tcp_bridge;
+use crate::user_config;
+use crate::tmux_clients;
+use crate::trust;
+use crate::user_notify;
+use crate::state::{KeyCacheEntry, PortReservation, StateManager};
+use crate::template::{self, Answers};
 
 /// Shared state for the SSH server.
-pub struct ServerState {
+pub struct ServerState<B: ContainerBackend = ContainerManager> {
     pub config: Arc<GatewayConfig>,
     pub state: Arc<StateManager>,
-    pub container_manager: Arc<ContainerManager>,
+    pub container_manager: Arc<B>,
     pub github_fetcher: Arc<GitHubKeyFetcher>,
+    pub audit: Arc<AuditLogger>,
+    pub auth_guard: AuthGuard,
 }
 
 /// Per-connection handler state.
-pub struct ConnectionHandler {
+pub struct ConnectionHandler<B: ContainerBackend = ContainerManager> {
     /// Shared server state.
-    server: Arc<ServerState>,
+    server: Arc<ServerState<B>>,
 
     /// Client's socket address.
     peer_addr: SocketAddr,
@@ -68,13 +105,31 @@ pub struct ConnectionHandler {
 
     /// Active gateway-control watch sessions (channel_id -> cancelled flag).
     watch_sessions: HashMap<ChannelId, Arc<AtomicBool>>,
+    /// `agentman jobs logs --follow` and `agentman tail -f` channels. Like watch sessions, but
+    /// stdin EOF doesn't stop them (`ssh -n host agentman jobs logs <id> -f` should wait for the
+    /// job).
+    job_follows: HashMap<ChannelId, Arc<AtomicBool>>,
+
+    /// Channels whose input the gateway reads itself: `agentman new` template prompts and
+    /// `agentman env-file set`.
+    input_sessions: HashMap<ChannelId, InputSession>,
 
     /// Pending GitHub username for keyboard-interactive auth.
     pending_github_user: Option<String>,
 
+    /// Set once this connection has waited for a key to show up on GitHub.
+    waited_for_published_key: bool,
+
     /// Active remote port forwards (bind_addr -> listener task handle).
     remote_forwards: HashMap<(String, u32), tokio::task::JoinHandle<()>>,
 
+    /// Remote Unix socket forwards by path in the sandbox: the accept task and the socket's
+    /// host path.
+    remote_socket_forwards: HashMap<String, (tokio::task::JoinHandle<()>, PathBuf)>,
+
+    /// Forwards from gateway host ports into the sandbox, added at the `~C` prompt.
+    gateway_forwards: HashMap<(String, u16), GatewayForward>,
+
     /// All public key fingerprints offered during this auth session.
     /// We cache all of them once GitHub verification succeeds.
     offered_key_fingerprints: Vec<String>,
@@ -82,8 +137,59 @@ pub struct ConnectionHandler {
     /// PTY info per SSH channel (set by pty_request).
     ptys: HashMap<ChannelId, PtyInfo>,
 
+    /// Shells attached to the workspace's shared tmux session (for `agentman sessions`).
+    tmux_attachments: HashMap<ChannelId, tmux_clients::Attachment>,
+
     /// OpenSSH agent forwarding state for this SSH connection (if enabled by the client).
     agent_forwarding: Option<AgentForwardingState>,
+
+    /// Set by `agentman ephemeral`: the connection uses a throwaway sandbox.
+    ephemeral_requested: bool,
+
+    /// Image name from the SSH username (`project.<name>`), applied with the first sandbox.
+    image_variant: Option<String>,
+
+    /// Image picked when connecting that the running container doesn't use yet.
+    image_pending: Option<String>,
+
+    /// Repository from `AGENTMAN_CLONE_URL`, cloned if the first sandbox creates the workspace.
+    clone_url: Option<String>,
+
+    /// Slot of the connection's throwaway sandbox, held until it is removed.
+    ephemeral_lease: Option<ephemeral::Lease>,
+
+    /// Containers this connection's control commands inspected recently.
+    inspections: InspectCache,
+
+    /// Byte counts for the compression metrics.
+    traffic: Arc<compression::Traffic>,
+
+    /// Set when another gateway (`[migration] nodes`) logged in to migrate a workspace here.
+    migration_peer: Option<String>,
+
+    /// Channels receiving a migrated workspace's archive.
+    migration_uploads: HashMap<ChannelId, mpsc::Sender<Vec<u8>>>,
+
+    /// Set when a guest logged in with an invite (`agentman guest invite`); `github_user` and
+    /// `project` are then the inviter's.
+    guest: Option<Guest>,
+
+    /// Set when an admin logged in to another user's project (`agentman admin access`);
+    /// `github_user` is then the owner.
+    impersonation: Option<Impersonation>,
+
+    /// The workspace whose sandbox this connection uses, counted as activity for `[idle]`.
+    active_workspace: Option<(String, String)>,
+
+    /// Set once authentication succeeded, for `[ssh] handshake_timeout_secs`.
+    authenticated: Arc<AtomicBool>,
+
+    /// Why the user may not use the gateway (`allowed_github_orgs`, `allowed_projects`);
+    /// sessions only print it.
+    refusal: Option<String>,
+
+    /// The config with the user's `users_dir` overrides, once they logged in.
+    config: Arc<GatewayConfig>,
 }
 
 struct ExecSession {
@@ -91,8 +197,75 @@ struct ExecSession {
     tty: bool,
     /// Channel for sending data to the container.
     stdin_tx: Option<mpsc::Sender<Vec<u8>>>,
+    /// Set when the exec's processes can be signalled as a group.
+    tracked: Option<TrackedExec>,
+    /// Set once the exec's output has ended (the command exited or was killed).
+    finished: Arc<AtomicBool>,
+    /// Gateway escape sequences, for PTY sessions when `[shell] escape_char` is set.
+    escape: Option<EscapeParser>,
+    /// The exec's output on its way to the client (`[slow_clients]`).
+    flow: Arc<slow_clients::Flow>,
+}
+
+/// A `~C -L` forward: a listener on the gateway host bridged into the sandbox.
+struct GatewayForward {
+    /// `host:port` in the sandbox.
+    target: String,
+    task: tokio::task::JoinHandle<()>,
+}
+
+/// Client input for `agentman new` prompts or `agentman env-file set`.
+struct InputSession {
+    /// Dropped on EOF.
+    input: Option<mpsc::Sender<Vec<u8>>>,
+    /// Set when the channel closes: stop without rendering.
+    cancelled: Arc<AtomicBool>,
+}
+
+/// An exec whose processes carry `EXEC_MARKER_ENV=marker`, so they can be signalled as a group.
+#[derive(Debug, Clone)]
+struct TrackedExec {
+    container_id: String,
+    marker: String,
+    /// Kill the exec's processes and end the channel after this long.
+    timeout: Option<Duration>,
+    /// Hang up the exec's processes if its channel closes while it is still running.
+    hangup_on_close: bool,
+}
+
+static NEXT_EXEC_MARKER: AtomicU64 = AtomicU64::new(1);
+
+/// How long `direct_connect` waits for the container before using the bridge instead.
+const DIRECT_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Client data a forwarded socket may leave unread before [`relay_channel`] gives up on it.
+const RELAY_BACKLOG_BYTES: usize = 16 * 1024 * 1024;
+
+/// A marker unique across execs of this gateway process and its restarts.
+fn new_exec_marker() -> String {
+    format!(
+        "{}-{}-{}",
+        std::process::id(),
+        Utc::now().timestamp_millis(),
+        NEXT_EXEC_MARKER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// The image name in the SSH username (`project.<name>`) isn't one of `[images] named`.
+#[derive(Debug)]
+struct UnknownImage {
+    name: String,
+    available: String,
 }
 
+impl std::fmt::Display for UnknownImage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unknown image {:?}; available: {}", self.name, self.available)
+    }
+}
+
+impl std::error::Error for UnknownImage {}
+
 #[derive(Debug, Clone)]
 struct PtyInfo {
     term: String,
@@ -104,7 +277,8 @@ struct PtyInfo {
 enum ChannelStreamKind {
     /// Normal SSH session channels (shell/exec): return exit-status and keep stderr separate.
     Session,
-    /// TCP forwarding channels (direct-tcpip / forwarded-tcpip): treat as raw byte streams.
+    /// TCP and Unix socket forwarding channels (direct-tcpip / forwarded-tcpip,
+    /// direct-streamlocal): treat as raw byte streams.
     TcpForward,
 }
 
@@ -137,7 +311,7 @@ impl Drop for AgentForwardingState {
         // a dangling symlink when the last agent-forwarding connection exits, while still being
         // safe with concurrent connections (last one wins).
         match std::fs::read_link(&self.symlink_host_path) {
-            Ok(target) if target == PathBuf::from(&self.socket_filename) => {
+            Ok(target) if target == self.socket_filename => {
                 let _ = std::fs::remove_file(&self.symlink_host_path);
             }
             _ => {}
@@ -186,6 +360,85 @@ async fn bridge_agent_forwarding(sock: UnixStream, channel: Channel<Msg>) -> Res
     Ok(())
 }
 
+/// Relay a TCP or Unix socket connection over a forwarding channel (a remote forward's
+/// forwarded-tcpip or forwarded-streamlocal channel, or a direct-tcpip one with
+/// `direct_connect`), in both directions. Each direction runs on its own, so a socket that is
+/// slow to read never stops the other direction. Socket data waits for the client's SSH window.
+/// Client data is taken off the channel right away and queued for the socket: russh stops the
+/// whole connection while a channel's messages aren't read. A socket that falls more than
+/// [`RELAY_BACKLOG_BYTES`] behind ends the relay. EOF on either side half-closes the other; the
+/// channel is closed once both directions are done, or right away when the client closes it.
+async fn relay_channel(stream: impl AsyncRead + AsyncWrite, channel: Channel<Msg>) {
+    let (mut chan_rx, chan_tx) = channel.split();
+    let (mut sock_r, mut sock_w) = tokio::io::split(stream);
+    let (queue_tx, mut queue_rx) = mpsc::unbounded_channel::<CryptoVec>();
+    let backlog = AtomicUsize::new(0);
+    {
+        // Socket -> client.
+        let upload = async {
+            let mut chan_writer = chan_tx.make_writer();
+            let _ = tokio::io::copy(&mut sock_r, &mut chan_writer).await;
+            let _ = chan_tx.eof().await;
+        };
+        // Client -> queue, until the client's EOF or close; true if the relay should end now.
+        let receive = async {
+            let queue_tx = queue_tx;
+            loop {
+                match chan_rx.wait().await {
+                    Some(ChannelMsg::Data { data }) => {
+                        let queued = backlog.fetch_add(data.len(), Ordering::Relaxed);
+                        if queued + data.len() > RELAY_BACKLOG_BYTES {
+                            warn!("Forwarded socket stopped reading; closing the channel");
+                            break true;
+                        }
+                        // The socket failed.
+                        if queue_tx.send(data).is_err() {
+                            break false;
+                        }
+                    }
+                    Some(ChannelMsg::Eof) => break false,
+                    Some(ChannelMsg::Close) | None => break true,
+                    Some(_) => {}
+                }
+            }
+        };
+        // Queue -> socket.
+        let download = async {
+            while let Some(data) = queue_rx.recv().await {
+                if sock_w.write_all(&data).await.is_err() {
+                    break;
+                }
+                backlog.fetch_sub(data.len(), Ordering::Relaxed);
+            }
+            let _ = sock_w.shutdown().await;
+        };
+        tokio::pin!(upload, receive, download);
+        let (mut uploaded, mut downloaded) = (false, false);
+        let closed = loop {
+            tokio::select! {
+                _ = &mut upload, if !uploaded => uploaded = true,
+                _ = &mut download, if !downloaded => downloaded = true,
+                closed = &mut receive => break closed,
+            }
+        };
+        if !closed {
+            tokio::join!(
+                async {
+                    if !uploaded {
+                        upload.await;
+                    }
+                },
+                async {
+                    if !downloaded {
+                        download.await;
+                    }
+                },
+            );
+        }
+    }
+    let _ = chan_tx.close().await;
+}
+
 fn exec_env(tty: bool, term: &str, ssh_auth_sock: Option<&str>) -> Vec<String> {
     // Keep this small and non-invasive:
     // - Zed (and other editors) probe `$SHELL` over non-PTY exec sessions.
@@ -202,8 +455,10 @@ fn exec_env(tty: bool, term: &str, ssh_auth_sock: Option<&str>) -> Vec<String> {
     env
 }
 
-impl ConnectionHandler {
-    fn new(server: Arc<ServerState>, peer_addr: SocketAddr) -> Self {
+impl<B: ContainerBackend> ConnectionHandler<B> {
+    fn new(server: Arc<ServerState<B>>, peer_addr: SocketAddr) -> Self {
+        let config = server.config.clone();
+        memory_guard::connection_opened();
         Self {
             server,
             peer_addr,
@@ -212,25 +467,84 @@ impl ConnectionHandler {
             container_id: None,
             exec_sessions: HashMap::new(),
             watch_sessions: HashMap::new(),
+            job_follows: HashMap::new(),
+            input_sessions: HashMap::new(),
             pending_github_user: None,
+            waited_for_published_key: false,
             remote_forwards: HashMap::new(),
+            remote_socket_forwards: HashMap::new(),
+            gateway_forwards: HashMap::new(),
             offered_key_fingerprints: Vec::new(),
             ptys: HashMap::new(),
+            tmux_attachments: HashMap::new(),
             agent_forwarding: None,
+            ephemeral_requested: false,
+            image_variant: None,
+            image_pending: None,
+            clone_url: None,
+            ephemeral_lease: None,
+            inspections: InspectCache::default(),
+            traffic: Arc::new(compression::Traffic::default()),
+            migration_peer: None,
+            migration_uploads: HashMap::new(),
+            guest: None,
+            impersonation: None,
+            active_workspace: None,
+            authenticated: Arc::new(AtomicBool::new(false)),
+            refusal: None,
+            config,
         }
     }
 }
 
-impl Handler for ConnectionHandler {
+impl<B: ContainerBackend> Handler for ConnectionHandler<B> {
     type Error = anyhow::Error;
 
+    async fn authentication_banner(&mut self) -> Result<Option<String>, Self::Error> {
+        Ok(self.server.config.ssh.banner.as_ref().map(|banner| {
+            // Clients print the banner as is.
+            if banner.ends_with('\n') {
+                banner.clone()
+            } else {
+                format!("{banner}\r\n")
+            }
+        }))
+    }
+
+    /// The `none` request clients open with: answer with the methods that can still succeed,
+    /// so clients that can't prompt (`ssh -o BatchMode=yes`) give up right away instead of
+    /// trying methods the gateway never accepts.
+    async fn auth_none(&mut self, user: &str) -> Result<Auth, Self::Error> {
+        let methods = if guest::parse_login(user).is_some() || user == migration::PEER_USER {
+            MethodSet::from(&[MethodKind::PublicKey][..])
+        } else {
+            let policy = self.server.auth_guard.policy(self.peer_addr.ip(), None);
+            auth_methods(policy.restrict_methods)
+        };
+        Ok(Auth::Reject {
+            proceed_with_methods: Some(methods),
+            partial_success: false,
+        })
+    }
+
+    async fn auth_succeeded(&mut self, _session: &mut Session) -> Result<(), Self::Error> {
+        self.authenticated.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
     /// Called when a new client connects.
     async fn channel_open_session(
         &mut self,
         channel: Channel<Msg>,
-        _session: &mut Session,
+        session: &mut Session,
     ) -> Result<bool, Self::Error> {
+        if memory_guard::shedding() {
+            warn!("Refusing a session channel from {}: shedding load", self.peer_addr);
+            memory_guard::record_refused("session");
+            return Ok(false);
+        }
         debug!("Session channel opened: {:?}", channel.id());
+        self.watch_guest(session);
         Ok(true)
     }
 
@@ -240,21 +554,29 @@ impl Handler for ConnectionHandler {
         user: &str,
         public_key: &PublicKey,
     ) -> Result<Auth, Self::Error> {
+        // Guest logins carry a secret; keep them out of the logs.
+        if let Some((id, secret)) = guest::parse_login(user) {
+            return Ok(self.auth_guest(id, secret, public_key, false).await);
+        }
         debug!("Public key offered by user '{}' from {}", user, self.peer_addr);
+        if user == migration::PEER_USER {
+            return Ok(self.auth_migration_peer(public_key).await);
+        }
 
         // Parse username to extract project and optional github user hint
         let (project, github_hint) = parse_ssh_username(user);
+        let (project, image_variant) = parse_image_variant(&project);
 
         // Validate project name
         if let Err(e) = validate_project_name(&project) {
             warn!("Invalid project name '{}': {}", project, e);
-            return Ok(Auth::Reject {
-                proceed_with_methods: None,
-                partial_success: false,
-            });
+            return Ok(self
+                .reject_auth(AuditOutcome::Denied, format!("invalid project name: {e}"), None, false)
+                .await);
         }
 
         self.project = Some(project.clone());
+        self.image_variant = image_variant;
 
         // Get key fingerprint
         let fingerprint = compute_fingerprint_from_pubkey(public_key);
@@ -271,6 +593,26 @@ impl Handler for ConnectionHandler {
                 "Found cached GitHub user '{}' for key {}",
                 cached.github_username, fingerprint
             );
+            // An admin logging in to another user's project with `agentman admin access`.
+            if let Some(ref owner) = github_hint
+                && *owner != cached.github_username
+                && let Some(grant) = impersonation::grant_for(
+                    &self.server.config,
+                    &self.server.state,
+                    &cached.github_username,
+                    owner,
+                    &project,
+                )
+                .await
+            {
+                self.impersonation = Some(Impersonation {
+                    admin: cached.github_username,
+                    reason: grant.reason,
+                });
+                self.github_user = Some(grant.github_user);
+                return Ok(Auth::Accept);
+            }
+            self.impersonation = None;
             self.github_user = Some(cached.github_username);
             return Ok(Auth::Accept);
         }
@@ -280,15 +622,22 @@ impl Handler for ConnectionHandler {
         // (This happens when user already entered their GitHub username)
         if let Some(ref github_user) = self.pending_github_user {
             debug!("Verifying key against pending GitHub user '{}'", github_user);
-            
+
+            let github_user = github_user.clone();
+            if !self.allow_github_lookups(Some(&github_user), 1) {
+                return Ok(self
+                    .reject_auth(
+                        AuditOutcome::Denied,
+                        format!("GitHub lookup for {github_user} refused: too many recent failures"),
+                        Some(&github_user),
+                        true,
+                    )
+                    .await);
+            }
+
             let openssh_key = public_key_to_openssh(public_key);
 
-            match self
-                .server
-                .github_fetcher
-                .verify_key(github_user, &openssh_key)
-                .await
-            {
+            match self.verify_pending_key(&github_user, &openssh_key).await {
                 Ok(verified_type) => {
                     info!(
                         "Verified key for GitHub user '{}' (type: {})",
@@ -296,9 +645,9 @@ impl Handler for ConnectionHandler {
                     );
 
                     // Cache ALL offered keys for this GitHub user, not just the verified one
-                    self.cache_all_offered_keys(github_user, &verified_type).await;
+                    self.cache_all_offered_keys(&github_user, &verified_type).await;
 
-                    self.github_user = Some(github_user.clone());
+                    self.github_user = Some(github_user);
                     self.pending_github_user = None;
                     return Ok(Auth::Accept);
                 }
@@ -308,12 +657,14 @@ impl Handler for ConnectionHandler {
                         github_user, e
                     );
                     // Keep publickey enabled so the client can try another key without re-prompting.
-                    let methods =
-                        MethodSet::from(&[MethodKind::PublicKey, MethodKind::KeyboardInteractive][..]);
-                    return Ok(Auth::Reject {
-                        proceed_with_methods: Some(methods),
-                        partial_success: false,
-                    });
+                    return Ok(self
+                        .reject_auth(
+                            AuditOutcome::Failure,
+                            format!("key not verified for {github_user}: {e}"),
+                            Some(&github_user),
+                            true,
+                        )
+                        .await);
                 }
             }
         }
@@ -322,10 +673,20 @@ impl Handler for ConnectionHandler {
         if let Some(github_user) = github_hint {
             if let Err(e) = validate_github_username(&github_user) {
                 warn!("Invalid GitHub username '{}': {}", github_user, e);
-                return Ok(Auth::Reject {
-                    proceed_with_methods: None,
-                    partial_success: false,
-                });
+                return Ok(self
+                    .reject_auth(AuditOutcome::Denied, format!("invalid GitHub username: {e}"), None, false)
+                    .await);
+            }
+
+            if !self.allow_github_lookups(Some(&github_user), 1) {
+                return Ok(self
+                    .reject_auth(
+                        AuditOutcome::Denied,
+                        format!("GitHub lookup for {github_user} refused: too many recent failures"),
+                        Some(&github_user),
+                        false,
+                    )
+                    .await);
             }
 
             let openssh_key = public_key_to_openssh(public_key);
@@ -350,17 +711,29 @@ impl Handler for ConnectionHandler {
                 }
                 Err(e) => {
                     warn!("Failed to verify key for '{}': {}", github_user, e);
-                    return Ok(Auth::Reject {
-                        proceed_with_methods: None,
-                        partial_success: false,
-                    });
+                    return Ok(self
+                        .reject_auth(
+                            AuditOutcome::Failure,
+                            format!("key not verified for {github_user}: {e}"),
+                            Some(&github_user),
+                            false,
+                        )
+                        .await);
                 }
             }
         }
 
-        // Check bootstrap users
+        // Check bootstrap users (one GitHub lookup each, so subject to the guard's budget)
         let openssh_key = public_key_to_openssh(public_key);
-        for bootstrap_user in &self.server.config.bootstrap_github_users {
+        let bootstrap_users = &self.server.config.bootstrap_github_users;
+        let bootstrap_users = if !bootstrap_users.is_empty()
+            && self.allow_github_lookups(None, bootstrap_users.len())
+        {
+            bootstrap_users.as_slice()
+        } else {
+            &[]
+        };
+        for bootstrap_user in bootstrap_users {
             if let Ok(verified_type) = self
                 .server
                 .github_fetcher
@@ -386,9 +759,9 @@ impl Handler for ConnectionHandler {
             "Key {} not cached for {}, allowing client to try other keys",
             fingerprint, self.peer_addr
         );
-        let methods = MethodSet::from(&[MethodKind::PublicKey, MethodKind::KeyboardInteractive][..]);
+        let policy = self.server.auth_guard.policy(self.peer_addr.ip(), None);
         Ok(Auth::Reject {
-            proceed_with_methods: Some(methods),
+            proceed_with_methods: Some(auth_methods(policy.restrict_methods)),
             partial_success: false,
         })
     }
@@ -400,15 +773,40 @@ impl Handler for ConnectionHandler {
         _submethods: &str,
         response: Option<russh::server::Response<'_>>,
     ) -> Result<Auth, Self::Error> {
+        if guest::parse_login(user).is_some() {
+            // Guests have no GitHub user to name; their invite binds a key.
+            return Ok(Auth::Reject {
+                proceed_with_methods: Some(MethodSet::from(&[MethodKind::PublicKey][..])),
+                partial_success: false,
+            });
+        }
         debug!("Keyboard-interactive auth for user '{}'", user);
+        if user == migration::PEER_USER {
+            return Ok(Auth::Reject {
+                proceed_with_methods: None,
+                partial_success: false,
+            });
+        }
 
         match response {
             None => {
+                if self.server.auth_guard.policy(self.peer_addr.ip(), None).restrict_methods {
+                    return Ok(self
+                        .reject_auth(
+                            AuditOutcome::Denied,
+                            "keyboard-interactive refused: too many recent failures",
+                            None,
+                            true,
+                        )
+                        .await);
+                }
+
                 // Initial request - ask for GitHub username
+                let lang = self.server.config.i18n.default_lang;
                 Ok(Auth::Partial {
-                    name: "GitHub Username".into(),
-                    instructions: "Enter your GitHub username to verify your SSH key:".into(),
-                    prompts: vec![("GitHub username: ".into(), true)].into(),
+                    name: i18n::Msg::UsernameTitle.render(lang).into(),
+                    instructions: i18n::Msg::UsernameInstructions.render(lang).into(),
+                    prompts: vec![(i18n::Msg::UsernamePrompt.render(lang).into(), true)].into(),
                 })
             }
             Some(response) => {
@@ -427,10 +825,9 @@ impl Handler for ConnectionHandler {
                 let github_user = responses[0].clone();
                 if let Err(e) = validate_github_username(&github_user) {
                     warn!("Invalid GitHub username '{}': {}", github_user, e);
-                    return Ok(Auth::Reject {
-                        proceed_with_methods: None,
-                        partial_success: false,
-                    });
+                    return Ok(self
+                        .reject_auth(AuditOutcome::Denied, format!("invalid GitHub username: {e}"), None, false)
+                        .await);
                 }
 
                 self.pending_github_user = Some(github_user);
@@ -451,7 +848,13 @@ impl Handler for ConnectionHandler {
         user: &str,
         public_key: &PublicKey,
     ) -> Result<Auth, Self::Error> {
+        if let Some((id, secret)) = guest::parse_login(user) {
+            return Ok(self.auth_guest(id, secret, public_key, true).await);
+        }
         debug!("Public key auth (with signature) for user '{}'", user);
+        if user == migration::PEER_USER {
+            return Ok(self.auth_migration_peer(public_key).await);
+        }
 
         // Track this key too
         let fingerprint = compute_fingerprint_from_pubkey(public_key);
@@ -461,32 +864,41 @@ impl Handler for ConnectionHandler {
 
         // If we already have a github_user from offered phase, accept
         if self.github_user.is_some() {
-            return Ok(Auth::Accept);
+            return Ok(self.accept_auth(fingerprint).await);
         }
 
         // If we have a pending github user from keyboard-interactive, verify
         if let Some(github_user) = self.pending_github_user.take() {
+            if !self.allow_github_lookups(Some(&github_user), 1) {
+                return Ok(self
+                    .reject_auth(
+                        AuditOutcome::Denied,
+                        format!("GitHub lookup for {github_user} refused: too many recent failures"),
+                        Some(&github_user),
+                        false,
+                    )
+                    .await);
+            }
             let openssh_key = public_key_to_openssh(public_key);
 
-            match self
-                .server
-                .github_fetcher
-                .verify_key(&github_user, &openssh_key)
-                .await
-            {
+            match self.verify_pending_key(&github_user, &openssh_key).await {
                 Ok(verified_type) => {
                     // Cache ALL offered keys for this GitHub user
                     self.cache_all_offered_keys(&github_user, &verified_type).await;
 
                     self.github_user = Some(github_user);
-                    return Ok(Auth::Accept);
+                    return Ok(self.accept_auth(fingerprint).await);
                 }
                 Err(e) => {
                     warn!("Failed to verify key: {}", e);
-                    return Ok(Auth::Reject {
-                        proceed_with_methods: None,
-                        partial_success: false,
-                    });
+                    return Ok(self
+                        .reject_auth(
+                            AuditOutcome::Failure,
+                            format!("key not verified for {github_user}: {e}"),
+                            Some(&github_user),
+                            false,
+                        )
+                        .await);
                 }
             }
         }
@@ -497,6 +909,28 @@ impl Handler for ConnectionHandler {
         })
     }
 
+    /// Handle environment variables: only `AGENTMAN_CLONE_URL` is taken, from users connecting
+    /// to their own projects.
+    async fn env_request(
+        &mut self,
+        channel_id: ChannelId,
+        variable_name: &str,
+        variable_value: &str,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        if variable_name != provision::URL_ENV {
+            return Ok(());
+        }
+        if self.guest.is_some() || self.impersonation.is_some() {
+            session.channel_failure(channel_id)?;
+            return Ok(());
+        }
+        debug!("{} for channel {:?}: {}", variable_name, channel_id, variable_value);
+        self.clone_url = Some(variable_value.to_string());
+        session.channel_success(channel_id)?;
+        Ok(())
+    }
+
     /// Handle PTY request.
     async fn pty_request(
         &mut self,
@@ -536,35 +970,101 @@ impl Handler for ConnectionHandler {
     ) -> Result<(), Self::Error> {
         info!("Shell request on channel {:?}", channel_id);
 
-        let github_user = self
+        let github_user = &self
             .github_user
-            .as_ref()
+            .clone()
             .ok_or_else(|| anyhow!("Not authenticated"))?;
-        let project = self
+        let project = &self
             .project
-            .as_ref()
+            .clone()
             .ok_or_else(|| anyhow!("No project specified"))?;
 
-        // Get or create container
-        let container_id = self
-            .server
-            .container_manager
-            .get_or_create_container(github_user, project)
-            .await?;
-
-        self.container_id = Some(container_id.clone());
+        if self.refused(channel_id, session)? {
+            return Ok(());
+        }
+        if self.moved_elsewhere(channel_id, session).await? {
+            return Ok(());
+        }
+        let Some(container_id) = self.sandbox_or_refuse(channel_id, session).await? else {
+            return Ok(());
+        };
 
         let (tty, term) = match self.ptys.get(&channel_id) {
             Some(pty) => (true, pty.term.as_str()),
             None => (false, "xterm-256color"),
         };
 
-        let ssh_auth_sock = self
-            .agent_forwarding
-            .as_ref()
-            .map(|a| a.ssh_auth_sock_in_container());
+        let ssh_auth_sock = self.ssh_auth_sock();
+
+        // Notices wait for a terminal session, so editor bootstraps don't swallow them. They
+        // are the inviter's (or owner's), so guests and admins with access don't get them.
+        if tty && self.guest.is_none() && self.impersonation.is_none() {
+            let metadata = &self.server.config.github_metadata;
+            if metadata.greeting {
+                let name = github_metadata::cached(metadata, github_user);
+                let name = github_metadata::display_name(name.as_ref(), github_user);
+                let text = format!(
+                    "agentman: {}\r\n",
+                    i18n::Msg::SignedInAs { name: &name }.render(self.lang().await)
+                );
+                session.data(channel_id, CryptoVec::from_slice(text.as_bytes()))?;
+            }
+            match self.server.state.take_notices(github_user).await {
+                Ok(notices) if !notices.is_empty() => {
+                    let mut text = String::new();
+                    for notice in notices {
+                        text.push_str(&format!("agentman: {notice}\r\n"));
+                    }
+                    session.data(channel_id, CryptoVec::from_slice(text.as_bytes()))?;
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Failed to load notices for {}: {:#}", github_user, e),
+            }
+            if let Some(image) = self.image_pending.take() {
+                let text = format!(
+                    "agentman: the sandbox keeps its current image until it stops; {image} is used \
+                     after `agentman stop` (or now via `agentman recreate`)\r\n"
+                );
+                session.data(channel_id, CryptoVec::from_slice(text.as_bytes()))?;
+            }
+        }
+        // The project's login message is for everyone on it.
+        if tty
+            && !self.is_ephemeral()
+            && let Some(motd) = self.server.state.motd(github_user, project).await
+        {
+            let mut text = String::new();
+            for line in motd::render(&motd.text) {
+                text.push_str(&format!("{line}\r\n"));
+            }
+            session.data(channel_id, CryptoVec::from_slice(text.as_bytes()))?;
+        }
 
-        let cmd = match self.server.config.shell.mode {
+        let prefs = self.server.state.preferences(github_user).await;
+        let shell_mode = preferences::shell_mode(&self.server.config, &prefs);
+        let uses_tmux = tty && matches!(shell_mode, ShellMode::Tmux);
+        let read_only = self.is_read_only_guest();
+        if read_only && !uses_tmux {
+            self.audit(
+                AuditEventKind::Shell,
+                AuditOutcome::Denied,
+                "read-only guest without a pty",
+            );
+            session.channel_success(channel_id)?;
+            let handle = session.handle();
+            let output = format!(
+                "agentman: {}\n",
+                i18n::Msg::ReadOnlyGuestNeedsTerminal.render(self.lang().await)
+            );
+            tokio::spawn(async move {
+                finish_control_channel(&handle, channel_id, false, 1, output).await;
+            });
+            return Ok(());
+        }
+        let cmd = match shell_mode {
+            _ if read_only => guest::read_only_shell(&sanitize_tmux_session_name(
+                &self.server.config.shell.tmux_session,
+            )),
             ShellMode::Bash => vec!["/bin/bash".to_string(), "-l".to_string()],
             ShellMode::Tmux => {
                 // Only start tmux when the client requested a PTY (true interactive session).
@@ -572,9 +1072,11 @@ impl Handler for ConnectionHandler {
                 if tty {
                     let session_name =
                         sanitize_tmux_session_name(&self.server.config.shell.tmux_session);
+                    // Attaching re-applies window-size, so config changes reach running sessions.
                     let script = format!(
-                        "if command -v tmux >/dev/null 2>&1; then exec tmux new-session -A -s '{session}' -c /workspace /bin/bash -l; else exec /bin/bash -l; fi",
-                        session = session_name
+                        "if command -v tmux >/dev/null 2>&1; then exec tmux new-session -A -s '{session}' -c /workspace /bin/bash -l \\; set-option -g window-size {window_size}; else exec /bin/bash -l; fi",
+                        session = session_name,
+                        window_size = self.server.config.shell.tmux_window_size.as_str()
                     );
                     vec!["/bin/bash".to_string(), "-lc".to_string(), script]
                 } else {
@@ -583,41 +1085,68 @@ impl Handler for ConnectionHandler {
             }
         };
 
+        // The tmux server outlives the connection by design; only plain shells are tracked
+        // (and hung up on disconnect).
+        let mut env = exec_env(tty, term, ssh_auth_sock.as_deref());
+        let tracked = (!uses_tmux && self.server.config.exec.kill_on_disconnect).then(|| {
+            let tracked = TrackedExec {
+                container_id: container_id.clone(),
+                marker: new_exec_marker(),
+                timeout: None,
+                hangup_on_close: true,
+            };
+            env.push(format!("{}={}", EXEC_MARKER_ENV, tracked.marker));
+            tracked
+        });
+        self.inject_env_file(&mut env).await;
+        let attachment = self
+            .ptys
+            .get(&channel_id)
+            .filter(|_| uses_tmux && !self.is_ephemeral())
+            .map(|pty| {
+                tmux_clients::attach(github_user, project, self.peer_addr, pty.cols, pty.rows)
+            });
+
         // Create exec in container
         let exec_id = self
             .server
             .container_manager
-            .create_exec(
-                &container_id,
-                cmd,
-                tty,
-                Some(exec_env(tty, term, ssh_auth_sock.as_deref())),
-            )
+            .create_exec(&container_id, cmd, tty, Some(env))
             .await?;
 
         // Start exec and connect to channel
+        let event = self.audit_event(
+            AuditEventKind::Shell,
+            AuditOutcome::Success,
+            if tty { "pty" } else { "no-pty" },
+        );
         self.start_exec_session(
             channel_id,
             exec_id.clone(),
             tty,
             ChannelStreamKind::Session,
+            tracked,
+            Some(event.clone()),
             session,
         )
             .await?;
 
         // Confirm the shell request was accepted (client may be waiting on this).
         session.channel_success(channel_id)?;
+        self.server.audit.record(event);
+        if let Some(attachment) = attachment {
+            self.tmux_attachments.insert(channel_id, attachment);
+        }
 
         // Resize to stored PTY dimensions
-        if let Some(pty) = self.ptys.get(&channel_id) {
-            if let Err(e) = self
+        if let Some(pty) = self.ptys.get(&channel_id)
+            && let Err(e) = self
                 .server
                 .container_manager
                 .resize_exec(&exec_id, pty.cols as u16, pty.rows as u16)
                 .await
-            {
-                warn!("Failed to set initial exec size: {}", e);
-            }
+        {
+            warn!("Failed to set initial exec size: {}", e);
         }
 
         Ok(())
@@ -633,6 +1162,10 @@ impl Handler for ConnectionHandler {
         let command = String::from_utf8_lossy(data).to_string();
         info!("Exec request on channel {:?}: {}", channel_id, command);
 
+        if let Some(from) = self.migration_peer.clone() {
+            return self.receive_migration(channel_id, from, &command, session);
+        }
+
         let github_user = self
             .github_user
             .as_ref()
@@ -641,17 +1174,123 @@ impl Handler for ConnectionHandler {
             .project
             .as_ref()
             .ok_or_else(|| anyhow!("No project specified"))?;
+        if self.refused(channel_id, session)? {
+            return Ok(());
+        }
+
+        // Guests get the sandbox but not the gateway's control commands; read-only guests get
+        // nothing but the shared terminal.
+        if let Some(ref guest) = self.guest {
+            let refusal = match parse_gateway_control_command(command.trim()) {
+                _ if guest.read_only => Some("read-only guests can only open a terminal (ssh -t)"),
+                Some(GatewayControlCommand::Run { detach: false, .. }) | None => None,
+                Some(_) => Some("control commands are not available to guests"),
+            };
+            if let Some(refusal) = refusal {
+                self.audit(AuditEventKind::Control, AuditOutcome::Denied, command.trim());
+                session.channel_success(channel_id)?;
+                let handle = session.handle();
+                let has_pty = self.ptys.contains_key(&channel_id);
+                let output = format!("agentman: {refusal}\n");
+                tokio::spawn(async move {
+                    finish_control_channel(&handle, channel_id, has_pty, 1, output).await;
+                });
+                return Ok(());
+            }
+        }
+
+        // `agentman run` is a regular exec with a (shorter) time limit; `run --detach` starts a
+        // background job, which the gateway handles.
+        let mut exec_command = command.clone();
+        let mut timeout_secs = self.server.config.exec.max_duration_secs;
+        let ctrl = match parse_gateway_control_command(command.trim()) {
+            Some(GatewayControlCommand::Run {
+                timeout_secs: requested,
+                detach: false,
+                command: inner,
+            }) => {
+                timeout_secs = match (timeout_secs, requested) {
+                    (Some(max), Some(requested)) => Some(max.min(requested)),
+                    (max, requested) => requested.or(max),
+                };
+                exec_command = inner;
+                None
+            }
+            // A shell in a new throwaway sandbox, unless the connection already uses the
+            // workspace's container.
+            Some(GatewayControlCommand::Ephemeral)
+                if self.server.config.ephemeral.enabled
+                    && (self.container_id.is_none() || self.is_ephemeral()) =>
+            {
+                self.audit(AuditEventKind::Control, AuditOutcome::Success, command.trim());
+                self.ephemeral_requested = true;
+                return self.shell_request(channel_id, session).await;
+            }
+            Some(GatewayControlCommand::EnvFile {
+                action: EnvFileAction::Set,
+            }) if self.server.config.env_file.enabled && !self.is_ephemeral() => {
+                self.audit(AuditEventKind::Control, AuditOutcome::Success, command.trim());
+                session.channel_success(channel_id)?;
+                let (github_user, project) = (github_user.clone(), project.clone());
+                self.receive_env_file(channel_id, github_user, project, session);
+                return Ok(());
+            }
+            Some(GatewayControlCommand::New {
+                project: new_project,
+                from,
+            }) if !self.is_ephemeral() => {
+                self.audit(AuditEventKind::Control, AuditOutcome::Success, command.trim());
+                session.channel_success(channel_id)?;
+                self.start_template_prompts(channel_id, new_project, from, session);
+                return Ok(());
+            }
+            ctrl => ctrl,
+        };
 
         // Gateway control commands (handled by the gateway itself, not inside the container).
         // This is intentionally a very small "control surface" to keep behavior predictable.
-        if let Some(ctrl) = parse_gateway_control_command(command.trim()) {
-            let res = execute_gateway_control_command(
-                ctrl,
-                self.server.container_manager.as_ref(),
-                github_user,
-                project,
-            )
-            .await;
+        if let Some(ctrl) = ctrl {
+            // They act on the workspace named in the SSH user, which an ephemeral sandbox isn't.
+            let res = if let GatewayControlCommand::Verify { ref github_user } = ctrl {
+                // Needs the keys this connection offered.
+                self.verify_offered_keys(github_user.as_deref()).await
+            } else if matches!(ctrl, GatewayControlCommand::Route) {
+                // Needs this connection's forwards.
+                self.route_report().await
+            } else if self.is_ephemeral() && !matches!(ctrl, GatewayControlCommand::Help) {
+                GatewayControlExecution::Immediate {
+                    exit_status: 1,
+                    output: format!(
+                        "agentman: {}\n",
+                        i18n::Msg::ControlUnavailableEphemeral.render(self.lang().await)
+                    ),
+                }
+            } else {
+                self.server
+                    .container_manager
+                    .execute_control(ctrl, github_user, project, &self.inspections)
+                    .await
+            };
+            let outcome = match res {
+                GatewayControlExecution::Immediate { exit_status: 0, .. }
+                | GatewayControlExecution::WatchStats { .. }
+                | GatewayControlExecution::Wait { .. }
+                | GatewayControlExecution::Backup { .. }
+                | GatewayControlExecution::Recreate { .. }
+                | GatewayControlExecution::Restart
+                | GatewayControlExecution::Checkpoint { .. }
+                | GatewayControlExecution::Migrate { .. }
+                | GatewayControlExecution::ResumeOperation { .. }
+                | GatewayControlExecution::Stack { .. }
+                | GatewayControlExecution::Ping { .. }
+                | GatewayControlExecution::Scan
+                | GatewayControlExecution::Search { .. }
+                | GatewayControlExecution::Build { .. }
+                | GatewayControlExecution::FollowJob { .. }
+                | GatewayControlExecution::FollowFile { .. } => AuditOutcome::Success,
+                GatewayControlExecution::Immediate { .. } => AuditOutcome::Failure,
+            };
+            self.audit(AuditEventKind::Control, outcome, command.trim());
 
             // Confirm the exec request was accepted (OpenSSH sets want-reply=true).
             session.channel_success(channel_id)?;
@@ -701,9 +1340,7 @@ impl Handler for ConnectionHandler {
                                 break;
                             }
 
-                            let (status, out) =
-                                render_sandbox_stats_fast(cm.as_ref(), &github_user, &project, current)
-                                    .await;
+                            let (status, out) = cm.render_stats(&github_user, &project, current).await;
                             final_status = status;
 
                             // Home cursor + clear to end-of-screen, then redraw in-place.
@@ -746,27 +1383,130 @@ impl Handler for ConnectionHandler {
 
                     return Ok(());
                 }
-            }
-        }
-
-        // Get or create container
-        let container_id = self
-            .server
-            .container_manager
-            .get_or_create_container(github_user, project)
-            .await?;
-
-        self.container_id = Some(container_id.clone());
-
-        let (tty, term) = match self.ptys.get(&channel_id) {
-            Some(pty) => (true, pty.term.as_str()),
-            None => (false, "xterm-256color"),
-        };
-
-        let ssh_auth_sock = self
-            .agent_forwarding
-            .as_ref()
-            .map(|a| a.ssh_auth_sock_in_container());
+                GatewayControlExecution::FollowJob { workspace, id } => {
+                    let has_pty = self.ptys.contains_key(&channel_id);
+                    let cancelled = Arc::new(AtomicBool::new(false));
+                    self.job_follows.insert(channel_id, cancelled.clone());
+                    tokio::spawn(async move {
+                        let (exit_status, output) =
+                            follow_job(&handle, channel_id, has_pty, &workspace, &id, &cancelled)
+                                .await;
+                        finish_control_channel(&handle, channel_id, has_pty, exit_status, output)
+                            .await;
+                    });
+                    return Ok(());
+                }
+                GatewayControlExecution::FollowFile {
+                    workspace,
+                    path,
+                    output,
+                    offset,
+                } => {
+                    let has_pty = self.ptys.contains_key(&channel_id);
+                    let cancelled = Arc::new(AtomicBool::new(false));
+                    self.job_follows.insert(channel_id, cancelled.clone());
+                    let follower = file_view::Follower::new(workspace, path, offset);
+                    tokio::spawn(async move {
+                        let (exit_status, output) =
+                            follow_file(&handle, channel_id, has_pty, output, follower, &cancelled)
+                                .await;
+                        finish_control_channel(&handle, channel_id, has_pty, exit_status, output)
+                            .await;
+                    });
+                    return Ok(());
+                }
+                GatewayControlExecution::Ping { count, .. } => {
+                    // Keepalive requests go out back to back; the client answers them in order.
+                    let mut replies = Vec::new();
+                    for _ in 0..count {
+                        let (tx, rx) = oneshot::channel();
+                        session.send_ping(tx)?;
+                        replies.push(rx);
+                    }
+                    let sent = tokio::time::Instant::now();
+                    let cm = self.server.container_manager.clone();
+                    let github_user = github_user.to_string();
+                    let project = project.to_string();
+                    let has_pty = self.ptys.contains_key(&channel_id);
+
+                    tokio::spawn(async move {
+                        let deadline = sent + PING_TIMEOUT;
+                        let mut client = Vec::new();
+                        for reply in replies {
+                            let answered = tokio::time::timeout_at(deadline, reply).await;
+                            client.push(matches!(answered, Ok(Ok(()))).then(|| sent.elapsed()));
+                        }
+                        let ping = GatewayControlExecution::Ping { count, client };
+                        let cancelled = AtomicBool::new(false);
+                        let (exit_status, output) = cm
+                            .run_deferred_control(ping, &github_user, &project, &cancelled)
+                            .await;
+                        finish_control_channel(&handle, channel_id, has_pty, exit_status, output)
+                            .await;
+                    });
+
+                    return Ok(());
+                }
+                deferred @ (GatewayControlExecution::Wait { .. }
+                | GatewayControlExecution::Recreate { .. }
+                | GatewayControlExecution::Restart
+                | GatewayControlExecution::Checkpoint { .. }
+                | GatewayControlExecution::Migrate { .. }
+                | GatewayControlExecution::ResumeOperation { .. }
+                | GatewayControlExecution::Stack { .. }
+                | GatewayControlExecution::Scan
+                | GatewayControlExecution::Search { .. }
+                | GatewayControlExecution::Build { .. }
+                | GatewayControlExecution::Backup { .. }) => {
+                    let cm = self.server.container_manager.clone();
+                    let github_user = github_user.to_string();
+                    let project = project.to_string();
+                    let has_pty = self.ptys.contains_key(&channel_id);
+
+                    // Reuse the watch-session plumbing so Ctrl-C / channel close cancel a wait.
+                    let cancelled = Arc::new(AtomicBool::new(false));
+                    if matches!(deferred, GatewayControlExecution::Wait { .. }) {
+                        self.watch_sessions.insert(channel_id, cancelled.clone());
+                    }
+
+                    // Waits, pulls, health checks, backups and checkpoints can take minutes;
+                    // run off the SSH handler.
+                    tokio::spawn(async move {
+                        let (exit_status, output) = cm
+                            .run_deferred_control(deferred, &github_user, &project, &cancelled)
+                            .await;
+                        finish_control_channel(&handle, channel_id, has_pty, exit_status, output)
+                            .await;
+                    });
+
+                    return Ok(());
+                }
+            }
+        }
+
+        if self.moved_elsewhere(channel_id, session).await? {
+            return Ok(());
+        }
+        let Some(container_id) = self.sandbox_or_refuse(channel_id, session).await? else {
+            return Ok(());
+        };
+
+        let (tty, term) = match self.ptys.get(&channel_id) {
+            Some(pty) => (true, pty.term.as_str()),
+            None => (false, "xterm-256color"),
+        };
+
+        let ssh_auth_sock = self.ssh_auth_sock();
+
+        let tracked = TrackedExec {
+            container_id: container_id.clone(),
+            marker: new_exec_marker(),
+            timeout: timeout_secs.map(Duration::from_secs),
+            hangup_on_close: self.server.config.exec.kill_on_disconnect,
+        };
+        let mut env = exec_env(tty, term, ssh_auth_sock.as_deref());
+        env.push(format!("{}={}", EXEC_MARKER_ENV, tracked.marker));
+        self.inject_env_file(&mut env).await;
 
         // Create exec in container
         let exec_id = self
@@ -776,37 +1516,106 @@ impl Handler for ConnectionHandler {
                 &container_id,
                 // Exec requests should behave like standard sshd: don't force a login shell.
                 // This avoids user rc files (e.g. tmux auto-attach) breaking editor bootstrap flows.
-                vec!["/bin/bash".to_string(), "-c".to_string(), command],
+                vec!["/bin/bash".to_string(), "-c".to_string(), exec_command],
                 tty,
-                Some(exec_env(tty, term, ssh_auth_sock.as_deref())),
+                Some(env),
             )
             .await?;
 
         // Start exec and connect to channel
+        let event = self.audit_event(AuditEventKind::Exec, AuditOutcome::Success, command.as_str());
         self.start_exec_session(
             channel_id,
             exec_id.clone(),
             tty,
             ChannelStreamKind::Session,
+            Some(tracked),
+            Some(event.clone()),
             session,
         )
             .await?;
 
         // Confirm the exec request was accepted (OpenSSH sets want-reply=true).
         session.channel_success(channel_id)?;
+        self.server.audit.record(event);
 
         // Resize to stored PTY dimensions
-        if let Some(pty) = self.ptys.get(&channel_id) {
-            if let Err(e) = self
+        if let Some(pty) = self.ptys.get(&channel_id)
+            && let Err(e) = self
                 .server
                 .container_manager
                 .resize_exec(&exec_id, pty.cols as u16, pty.rows as u16)
                 .await
-            {
-                warn!("Failed to set initial exec size: {}", e);
-            }
+        {
+            warn!("Failed to set initial exec size: {}", e);
+        }
+
+        Ok(())
+    }
+
+    /// Handle subsystem request: `sftp` runs `sftp-server` in the sandbox over the channel.
+    async fn subsystem_request(
+        &mut self,
+        channel_id: ChannelId,
+        name: &str,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        info!("Subsystem request on channel {:?}: {}", channel_id, name);
+
+        if name != "sftp"
+            || !self.server.config.ssh.sftp
+            || self.migration_peer.is_some()
+            || self.refusal.is_some()
+        {
+            session.channel_failure(channel_id)?;
+            return Ok(());
         }
+        if self.guest.as_ref().is_some_and(|guest| guest.read_only) {
+            self.audit(AuditEventKind::Exec, AuditOutcome::Denied, "sftp");
+            session.channel_failure(channel_id)?;
+            return Ok(());
+        }
+
+        if self.moved_elsewhere(channel_id, session).await? {
+            return Ok(());
+        }
+        let Some(container_id) = self.sandbox_or_refuse(channel_id, session).await? else {
+            return Ok(());
+        };
 
+        let tracked = TrackedExec {
+            container_id: container_id.clone(),
+            marker: new_exec_marker(),
+            timeout: None,
+            hangup_on_close: true,
+        };
+        let mut env = exec_env(false, "", None);
+        env.push(format!("{}={}", EXEC_MARKER_ENV, tracked.marker));
+
+        let exec_id = self
+            .server
+            .container_manager
+            .create_exec(
+                &container_id,
+                sftp_server_command(self.server.config.ssh.sftp_server.as_deref()),
+                false,
+                Some(env),
+            )
+            .await?;
+        let event = self.audit_event(AuditEventKind::Exec, AuditOutcome::Success, "sftp");
+        self.start_exec_session(
+            channel_id,
+            exec_id,
+            false,
+            ChannelStreamKind::Session,
+            Some(tracked),
+            Some(event.clone()),
+            session,
+        )
+        .await?;
+
+        session.channel_success(channel_id)?;
+        self.server.audit.record(event);
         Ok(())
     }
 
@@ -829,6 +1638,9 @@ impl Handler for ConnectionHandler {
             pty.cols = col_width;
             pty.rows = row_height;
         }
+        if let Some(attachment) = self.tmux_attachments.get(&channel_id) {
+            attachment.resize(col_width, row_height);
+        }
 
         if let Some(exec_session) = self.exec_sessions.get(&channel_id) {
             if !exec_session.tty {
@@ -853,8 +1665,33 @@ impl Handler for ConnectionHandler {
         channel_id: ChannelId,
         session: &mut Session,
     ) -> Result<bool, Self::Error> {
+        if self.refusal.is_some() {
+            session.channel_failure(channel_id)?;
+            return Ok(false);
+        }
         if !self.server.config.agent_forwarding.allow {
             warn!("Agent forwarding denied by policy");
+            self.audit(AuditEventKind::AgentForward, AuditOutcome::Denied, "disabled by policy");
+            session.channel_failure(channel_id)?;
+            return Ok(false);
+        }
+
+        // The agent socket is shared through the workspace directory, which an ephemeral
+        // sandbox doesn't have.
+        if self.is_ephemeral() {
+            self.audit(AuditEventKind::AgentForward, AuditOutcome::Denied, "ephemeral sandbox");
+            session.channel_failure(channel_id)?;
+            return Ok(false);
+        }
+        // The inviter's sessions would pick up the guest's agent through the same symlink.
+        if self.guest.is_some() {
+            self.audit(AuditEventKind::AgentForward, AuditOutcome::Denied, "guest session");
+            session.channel_failure(channel_id)?;
+            return Ok(false);
+        }
+        // Likewise the owner's, for an admin's agent.
+        if self.impersonation.is_some() {
+            self.audit(AuditEventKind::AgentForward, AuditOutcome::Denied, "admin access");
             session.channel_failure(channel_id)?;
             return Ok(false);
         }
@@ -898,44 +1735,16 @@ impl Handler for ConnectionHandler {
             )
         })?;
 
-        // Make the socket usable from the container user (UID/GID 1000 in the base image).
-        #[cfg(unix)]
+        // Make the socket usable from the container user, who owns the workspace directory (see
+        // `[workspace_permissions]`), without following a symlink the sandbox swapped in.
+        if let Some(name) = socket_host_path.file_name()
+            && let Err(e) = host_fs::share_socket(&workspace_host_path, name)
         {
-            use std::os::unix::fs::PermissionsExt;
-
-            let mut chowned = false;
-            match Command::new("chown")
-                .arg("1000:1000")
-                .arg(&socket_host_path)
-                .status()
-                .await
-            {
-                Ok(status) if status.success() => chowned = true,
-                Ok(status) => warn!(
-                    "chown 1000:1000 {} exited with status {}",
-                    socket_host_path.display(),
-                    status
-                ),
-                Err(e) => warn!(
-                    "Failed to run chown 1000:1000 {}: {}",
-                    socket_host_path.display(),
-                    e
-                ),
-            }
-
-            // If we couldn't chown (common when not running as root), fall back to a permissive mode
-            // so ForwardAgent works. The socket lives under the user's workspace directory.
-            let mode = if chowned { 0o600 } else { 0o666 };
-            if let Err(e) = std::fs::set_permissions(
-                &socket_host_path,
-                std::fs::Permissions::from_mode(mode),
-            ) {
-                warn!(
-                    "Failed to chmod agent socket {}: {}",
-                    socket_host_path.display(),
-                    e
-                );
-            }
+            warn!(
+                "Failed to share agent socket {}: {}",
+                socket_host_path.display(),
+                e
+            );
         }
 
         // Prefer a stable SSH_AUTH_SOCK path via symlink, so long-lived sessions (e.g. tmux) can
@@ -1002,6 +1811,14 @@ impl Handler for ConnectionHandler {
                 .unwrap_or_else(|| "<unknown>".to_string())
         );
 
+        self.audit(
+            AuditEventKind::AgentForward,
+            AuditOutcome::Success,
+            self.agent_forwarding
+                .as_ref()
+                .map(|a| a.ssh_auth_sock_in_container())
+                .unwrap_or_default(),
+        );
         session.channel_success(channel_id)?;
         Ok(true)
     }
@@ -1011,24 +1828,66 @@ impl Handler for ConnectionHandler {
         &mut self,
         channel_id: ChannelId,
         data: &[u8],
-        _session: &mut Session,
+        session: &mut Session,
     ) -> Result<(), Self::Error> {
+        self.traffic.received(data.len());
+        if let Some(upload) = self.migration_uploads.get(&channel_id) {
+            // Waiting here slows the sender down to the speed of unpacking.
+            if upload.send(data.to_vec()).await.is_err() {
+                self.migration_uploads.remove(&channel_id);
+            }
+            return Ok(());
+        }
+        if let Some(prompt) = self.input_sessions.get(&channel_id) {
+            if let Some(ref tx) = prompt.input {
+                let _ = tx.send(data.to_vec()).await;
+            }
+            return Ok(());
+        }
+
         // Allow Ctrl-C to stop `agentman stats --watch` when a PTY is allocated.
-        if let Some(cancelled) = self.watch_sessions.get(&channel_id) {
-            if data.iter().any(|&b| b == 0x03) {
+        if let Some(cancelled) = self
+            .watch_sessions
+            .get(&channel_id)
+            .or_else(|| self.job_follows.get(&channel_id))
+        {
+            if data.contains(&0x03) {
                 cancelled.store(true, Ordering::Relaxed);
             }
             return Ok(());
         }
 
-        if let Some(exec_session) = self.exec_sessions.get(&channel_id) {
-            if let Some(ref tx) = exec_session.stdin_tx {
-                let _ = tx.send(data.to_vec()).await;
+        let Some(exec_session) = self.exec_sessions.get_mut(&channel_id) else {
+            return Ok(());
+        };
+        let inputs = match exec_session.escape {
+            Some(ref mut parser) => parser.feed(data),
+            None => vec![EscapeInput::Data(data.to_vec())],
+        };
+        for input in inputs {
+            if !self.handle_input(channel_id, input, session).await? {
+                break;
             }
         }
         Ok(())
     }
 
+    /// The client took output and opened its window again; once everything queued for the
+    /// channel is sent, its command may produce more (`[slow_clients]`).
+    async fn window_adjusted(
+        &mut self,
+        channel: ChannelId,
+        new_size: u32,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        if !session.has_pending_data(channel)
+            && let Some(exec_session) = self.exec_sessions.get(&channel)
+        {
+            exec_session.flow.drained(new_size);
+        }
+        Ok(())
+    }
+
     /// Handle channel close.
     async fn channel_close(
         &mut self,
@@ -1036,11 +1895,21 @@ impl Handler for ConnectionHandler {
         _session: &mut Session,
     ) -> Result<(), Self::Error> {
         debug!("Channel closed: {:?}", channel_id);
-        self.exec_sessions.remove(&channel_id);
+        self.migration_uploads.remove(&channel_id);
+        if let Some(exec_session) = self.exec_sessions.remove(&channel_id) {
+            self.hang_up(exec_session);
+        }
         if let Some(cancelled) = self.watch_sessions.remove(&channel_id) {
             cancelled.store(true, Ordering::Relaxed);
         }
+        if let Some(cancelled) = self.job_follows.remove(&channel_id) {
+            cancelled.store(true, Ordering::Relaxed);
+        }
+        if let Some(prompt) = self.input_sessions.remove(&channel_id) {
+            prompt.cancelled.store(true, Ordering::Relaxed);
+        }
         self.ptys.remove(&channel_id);
+        self.tmux_attachments.remove(&channel_id);
         Ok(())
     }
 
@@ -1051,9 +1920,15 @@ impl Handler for ConnectionHandler {
         _session: &mut Session,
     ) -> Result<(), Self::Error> {
         debug!("Channel EOF: {:?}", channel_id);
+        // The end of a migrated workspace's archive.
+        self.migration_uploads.remove(&channel_id);
         if let Some(cancelled) = self.watch_sessions.remove(&channel_id) {
             cancelled.store(true, Ordering::Relaxed);
         }
+        // Remaining template prompts take their defaults; an env file is complete.
+        if let Some(prompt) = self.input_sessions.get_mut(&channel_id) {
+            prompt.input = None;
+        }
         // Drop the stdin sender to signal EOF to container
         if let Some(exec_session) = self.exec_sessions.get_mut(&channel_id) {
             exec_session.stdin_tx = None;
@@ -1071,8 +1946,34 @@ impl Handler for ConnectionHandler {
         originator_port: u32,
         session: &mut Session,
     ) -> Result<bool, Self::Error> {
-        if !self.server.config.port_forwarding.allow_local {
+        if self.refusal.is_some() {
+            return Ok(false);
+        }
+        if self.is_read_only_guest() {
+            self.audit(
+                AuditEventKind::LocalForward,
+                AuditOutcome::Denied,
+                format!("{host_to_connect}:{port_to_connect} (read-only guest)"),
+            );
+            return Ok(false);
+        }
+        if memory_guard::shedding() {
+            self.audit(
+                AuditEventKind::LocalForward,
+                AuditOutcome::Denied,
+                format!("{host_to_connect}:{port_to_connect} (gateway overloaded)"),
+            );
+            memory_guard::record_refused("local_forward");
+            return Ok(false);
+        }
+        self.watch_guest(session);
+        if !self.config.port_forwarding.allow_local {
             warn!("Local port forwarding disabled");
+            self.audit(
+                AuditEventKind::LocalForward,
+                AuditOutcome::Denied,
+                format!("{host_to_connect}:{port_to_connect} (local forwarding disabled)"),
+            );
             return Ok(false);
         }
 
@@ -1083,47 +1984,60 @@ impl Handler for ConnectionHandler {
 
         // Ensure we have a container for this connection. VS Code Remote-SSH relies heavily on
         // connecting to loopback ports (127.0.0.1) *inside* the remote environment.
-        let github_user = self
-            .github_user
-            .as_ref()
-            .ok_or_else(|| anyhow!("Not authenticated"))?;
-        let project = self
-            .project
-            .as_ref()
-            .ok_or_else(|| anyhow!("No project specified"))?;
-
         let container_id = match self.container_id.clone() {
             Some(id) => id,
-            None => {
-                let id = self
-                    .server
-                    .container_manager
-                    .get_or_create_container(github_user, project)
-                    .await?;
-                self.container_id = Some(id.clone());
-                id
-            }
+            None => self.sandbox().await?,
         };
 
         // Determine destination inside the container.
         // - For localhost requests: always connect to 127.0.0.1 inside the container (supports services bound to loopback).
         // - For non-local destinations: only allow if explicitly enabled by policy.
+        // - With `allow_dynamic`, any host: `ssh -D` turns SOCKS requests into such channels.
+        let pf = &self.config.port_forwarding;
         let dest_host = if is_localhost(host_to_connect) {
             "127.0.0.1".to_string()
-        } else if self.server.config.port_forwarding.allow_nonlocal_destinations {
-            host_to_connect.to_string()
-        } else {
+        } else if !pf.allow_nonlocal_destinations && !pf.allow_dynamic {
             warn!("Non-local destination {} denied by policy", host_to_connect);
+            self.audit(
+                AuditEventKind::LocalForward,
+                AuditOutcome::Denied,
+                format!("{host_to_connect}:{port_to_connect} (non-local destination)"),
+            );
+            return Ok(false);
+        } else if !is_forward_destination(host_to_connect) || !(1..=65535).contains(&port_to_connect) {
+            // The bridge hands the host to socat and friends, which would read options in it.
+            self.audit(
+                AuditEventKind::LocalForward,
+                AuditOutcome::Denied,
+                format!("{host_to_connect:?}:{port_to_connect} (invalid destination)"),
+            );
             return Ok(false);
+        } else {
+            host_to_connect.to_string()
         };
 
-        // Use socat inside the container to connect and bridge bytes. This avoids needing access to
-        // the container's loopback from the gateway host (bridge networking).
-        let cmd = vec![
-            "socat".to_string(),
-            "-".to_string(),
-            format!("TCP:{}:{}", dest_host, port_to_connect),
-        ];
+        if pf.direct_connect
+            && dest_host == "127.0.0.1"
+            && let Some(stream) = self.connect_direct(&container_id, port_to_connect).await
+        {
+            let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+            tokio::spawn(relay_channel(stream, channel));
+            metrics::inc(
+                "agentman_local_forwards_total",
+                "Local forward channels, by how they reach the sandbox (direct or bridge).",
+                &[("path", "direct")],
+            );
+            self.audit(
+                AuditEventKind::LocalForward,
+                AuditOutcome::Success,
+                format!("{dest_host}:{port_to_connect} (direct to {peer})"),
+            );
+            return Ok(true);
+        }
+
+        // Bridge bytes from inside the container (socat, or whatever the image has instead). This
+        // avoids needing access to the container's loopback from the gateway host (bridge networking).
+        let cmd = tcp_bridge::command(&dest_host, port_to_connect);
 
         let exec_id = self
             .server
@@ -1132,9 +2046,27 @@ impl Handler for ConnectionHandler {
             .await?;
 
         // Treat direct-tcpip as a raw byte stream: no exit-status and no SSH stderr extended-data.
-        self.start_exec_session(channel.id(), exec_id, false, ChannelStreamKind::TcpForward, session)
-            .await?;
+        self.start_exec_session(
+            channel.id(),
+            exec_id,
+            false,
+            ChannelStreamKind::TcpForward,
+            None,
+            None,
+            session,
+        )
+        .await?;
+        metrics::inc(
+            "agentman_local_forwards_total",
+            "Local forward channels, by how they reach the sandbox (direct or bridge).",
+            &[("path", "bridge")],
+        );
 
+        self.audit(
+            AuditEventKind::LocalForward,
+            AuditOutcome::Success,
+            format!("{dest_host}:{port_to_connect}"),
+        );
         Ok(true)
     }
 
@@ -1145,37 +2077,77 @@ impl Handler for ConnectionHandler {
         port: &mut u32,
         session: &mut Session,
     ) -> Result<bool, Self::Error> {
-        if !self.server.config.port_forwarding.allow_remote {
+        if self.refusal.is_some() {
+            return Ok(false);
+        }
+        if self.is_read_only_guest() {
+            self.audit(
+                AuditEventKind::RemoteForward,
+                AuditOutcome::Denied,
+                format!("{address}:{port} (read-only guest)"),
+            );
+            return Ok(false);
+        }
+        if memory_guard::shedding() {
+            self.audit(
+                AuditEventKind::RemoteForward,
+                AuditOutcome::Denied,
+                format!("{address}:{port} (gateway overloaded)"),
+            );
+            memory_guard::record_refused("remote_forward");
+            return Ok(false);
+        }
+        self.watch_guest(session);
+        if !self.config.port_forwarding.allow_remote {
             warn!("Remote port forwarding disabled");
+            self.audit(
+                AuditEventKind::RemoteForward,
+                AuditOutcome::Denied,
+                format!("{address}:{port} (remote forwarding disabled)"),
+            );
             return Ok(false);
         }
 
-        // Determine bind address
-        let bind_addr = if address.is_empty() || address == "0.0.0.0" || address == "*" {
-            if self.server.config.port_forwarding.allow_gateway_ports {
-                "0.0.0.0"
-            } else {
-                "127.0.0.1"
+        let bind_addr = self.forward_bind_addr(address);
+
+        let reservation = match self.remote_port_reservation().await {
+            Ok(reservation) => reservation,
+            Err(e) => {
+                warn!("Remote forward on {}:{} refused: {:#}", bind_addr, port, e);
+                self.audit(
+                    AuditEventKind::RemoteForward,
+                    AuditOutcome::Failure,
+                    format!("{bind_addr}:{port}: {e:#}"),
+                );
+                return Ok(false);
             }
-        } else if is_localhost(address) {
-            "127.0.0.1"
-        } else if self.server.config.port_forwarding.allow_gateway_ports {
-            address
-        } else {
-            warn!("GatewayPorts disabled, binding to localhost");
-            "127.0.0.1"
         };
+        if let Some(block) = reservation
+            && *port != 0
+            && !u16::try_from(*port).is_ok_and(|p| block.contains(p))
+        {
+            warn!(
+                "Remote forward on {}:{} refused: outside this workspace's reserved ports {}",
+                bind_addr, port, block
+            );
+            self.audit(
+                AuditEventKind::RemoteForward,
+                AuditOutcome::Denied,
+                format!("{bind_addr}:{port} (outside reserved ports {block})"),
+            );
+            return Ok(false);
+        }
 
         let listen_addr = format!("{}:{}", bind_addr, port);
         info!("Starting remote forward on {}", listen_addr);
 
-        match TcpListener::bind(&listen_addr).await {
+        match bind_remote_forward(bind_addr, *port, reservation).await {
             Ok(listener) => {
                 // If port was 0, get the actual port
-                if *port == 0 {
-                    if let Ok(addr) = listener.local_addr() {
-                        *port = addr.port() as u32;
-                    }
+                if *port == 0
+                    && let Ok(addr) = listener.local_addr()
+                {
+                    *port = addr.port() as u32;
                 }
 
                 let handle = session.handle();
@@ -1200,29 +2172,7 @@ impl Handler for ConnectionHandler {
                                         )
                                         .await
                                     {
-                                        Ok(channel) => {
-                                            // Relay data
-                                            let (mut read_half, _write_half) = stream.into_split();
-                                            let channel = channel;
-
-                                            let read_task = async {
-                                                let mut buf = vec![0u8; 32768];
-                                                loop {
-                                                    match read_half.read(&mut buf).await {
-                                                        Ok(0) => break,
-                                                        Ok(n) => {
-                                                            if channel.data(&buf[..n]).await.is_err() {
-                                                                break;
-                                                            }
-                                                        }
-                                                        Err(_) => break,
-                                                    }
-                                                }
-                                                let _ = channel.eof().await;
-                                            };
-
-                                            read_task.await;
-                                        }
+                                        Ok(channel) => relay_channel(stream, channel).await,
                                         Err(e) => {
                                             warn!("Failed to open forwarded-tcpip channel: {}", e);
                                         }
@@ -1240,10 +2190,20 @@ impl Handler for ConnectionHandler {
                 self.remote_forwards
                     .insert((address_for_insert, *port), task);
 
+                self.audit(
+                    AuditEventKind::RemoteForward,
+                    AuditOutcome::Success,
+                    format!("{}:{}", bind_addr, port),
+                );
                 Ok(true)
             }
             Err(e) => {
-                warn!("Failed to bind {}: {}", listen_addr, e);
+                warn!("Failed to bind {}: {:#}", listen_addr, e);
+                self.audit(
+                    AuditEventKind::RemoteForward,
+                    AuditOutcome::Failure,
+                    format!("{listen_addr}: {e:#}"),
+                );
                 Ok(false)
             }
         }
@@ -1264,23 +2224,1105 @@ impl Handler for ConnectionHandler {
             Ok(false)
         }
     }
-}
 
-impl ConnectionHandler {
-    /// Cache all offered keys for a GitHub user.
-    ///
-    /// This ensures that all keys the client offered during auth are cached,
-    /// not just the one that was verified against GitHub. This prevents
-    /// repeated keyboard-interactive prompts when the client offers keys
-    /// in a different order on reconnect.
-    async fn cache_all_offered_keys(&self, github_user: &str, key_type: &str) {
-        for fingerprint in &self.offered_key_fingerprints {
-            // Skip if already cached
-            if self.server.state.get_github_user(fingerprint).await.is_some() {
-                continue;
-            }
+    /// Handle a direct-streamlocal channel (local forward to a Unix socket in the sandbox).
+    async fn channel_open_direct_streamlocal(
+        &mut self,
+        channel: Channel<Msg>,
+        socket_path: &str,
+        session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        if self.refusal.is_some() {
+            return Ok(false);
+        }
+        let pf = &self.config.port_forwarding;
+        let refusal = if self.is_read_only_guest() {
+            Some("read-only guest")
+        } else if memory_guard::shedding() {
+            memory_guard::record_refused("local_forward");
+            Some("gateway overloaded")
+        } else if !pf.allow_local {
+            Some("local forwarding disabled")
+        } else if !streamlocal::allowed(pf, socket_path) {
+            Some("not in streamlocal_paths")
+        } else {
+            None
+        };
+        if let Some(reason) = refusal {
+            self.audit(
+                AuditEventKind::LocalForward,
+                AuditOutcome::Denied,
+                format!("unix:{socket_path:?} ({reason})"),
+            );
+            return Ok(false);
+        }
+        self.watch_guest(session);
+        info!("Direct-streamlocal request: {}", socket_path);
 
-            let entry = KeyCacheEntry {
+        let container_id = match self.container_id.clone() {
+            Some(id) => id,
+            None => self.sandbox().await?,
+        };
+        let exec_id = self
+            .server
+            .container_manager
+            .create_exec(&container_id, tcp_bridge::unix_command(socket_path), false, None)
+            .await?;
+        self.start_exec_session(
+            channel.id(),
+            exec_id,
+            false,
+            ChannelStreamKind::TcpForward,
+            None,
+            None,
+            session,
+        )
+        .await?;
+        self.audit(
+            AuditEventKind::LocalForward,
+            AuditOutcome::Success,
+            format!("unix:{socket_path}"),
+        );
+        Ok(true)
+    }
+
+    /// Handle a streamlocal-forward request (remote forward from a Unix socket in the sandbox).
+    async fn streamlocal_forward(
+        &mut self,
+        socket_path: &str,
+        session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        if self.refusal.is_some() {
+            return Ok(false);
+        }
+        let pf = &self.config.port_forwarding;
+        let host_path = match (&self.github_user, &self.project) {
+            (Some(github_user), Some(project)) if !self.is_ephemeral() => {
+                streamlocal::host_path(&self.server.config, github_user, project, socket_path)
+            }
+            _ => None,
+        };
+        let refusal = if self.is_read_only_guest() {
+            Some("read-only guest")
+        } else if memory_guard::shedding() {
+            memory_guard::record_refused("remote_forward");
+            Some("gateway overloaded")
+        } else if !pf.allow_remote {
+            Some("remote forwarding disabled")
+        } else if !streamlocal::allowed(pf, socket_path) {
+            Some("not in streamlocal_paths")
+        } else if host_path.is_none() {
+            Some("not directly in /workspace")
+        } else if self.remote_socket_forwards.contains_key(socket_path) {
+            Some("already forwarded")
+        } else {
+            None
+        };
+        let Some(host_path) = host_path.filter(|_| refusal.is_none()) else {
+            self.audit(
+                AuditEventKind::RemoteForward,
+                AuditOutcome::Denied,
+                format!("unix:{socket_path:?} ({})", refusal.unwrap_or_default()),
+            );
+            return Ok(false);
+        };
+        self.watch_guest(session);
+
+        let listener = match streamlocal::bind(&host_path).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                warn!("Failed to create forwarded socket {}: {:#}", socket_path, e);
+                self.audit(
+                    AuditEventKind::RemoteForward,
+                    AuditOutcome::Failure,
+                    format!("unix:{socket_path}: {e:#}"),
+                );
+                return Ok(false);
+            }
+        };
+        info!("Starting remote socket forward on {}", socket_path);
+        let handle = session.handle();
+        let path = socket_path.to_string();
+        let task = tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        warn!("Accept error: {}", e);
+                        break;
+                    }
+                };
+                let (handle, path) = (handle.clone(), path.clone());
+                tokio::spawn(async move {
+                    match handle.channel_open_forwarded_streamlocal(path).await {
+                        Ok(channel) => relay_channel(stream, channel).await,
+                        Err(e) => warn!("Failed to open forwarded-streamlocal channel: {}", e),
+                    }
+                });
+            }
+        });
+        self.remote_socket_forwards
+            .insert(socket_path.to_string(), (task, host_path));
+        self.audit(
+            AuditEventKind::RemoteForward,
+            AuditOutcome::Success,
+            format!("unix:{socket_path}"),
+        );
+        Ok(true)
+    }
+
+    /// Handle cancel-streamlocal-forward request.
+    async fn cancel_streamlocal_forward(
+        &mut self,
+        socket_path: &str,
+        _session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        let Some((task, host_path)) = self.remote_socket_forwards.remove(socket_path) else {
+            return Ok(false);
+        };
+        task.abort();
+        streamlocal::remove(&host_path);
+        info!("Cancelled remote socket forward on {}", socket_path);
+        Ok(true)
+    }
+}
+
+impl<B: ContainerBackend> ConnectionHandler<B> {
+    /// Record a successful login, unless an admin is offboarding the user.
+    async fn accept_auth(&mut self, fingerprint: String) -> Auth {
+        if let (Some(github_user), Some(impersonation)) = (&self.github_user, &self.impersonation) {
+            let project = self.project.as_deref().unwrap_or_default();
+            info!(
+                "Admin {} logged in to {}/{} from {}",
+                impersonation.admin, github_user, project, self.peer_addr
+            );
+            self.audit(
+                AuditEventKind::Impersonation,
+                AuditOutcome::Success,
+                impersonation.reason.clone(),
+            );
+            impersonation::notify_owner(
+                &self.server.config,
+                &self.server.state,
+                github_user,
+                project,
+                impersonation,
+            )
+            .await;
+        } else if let Some(ref github_user) = self.github_user {
+            if let Some(record) = self.server.state.offboarding(github_user).await
+                && let Some(ref admin) = record.requested_by
+            {
+                return self
+                    .reject_auth(
+                        AuditOutcome::Denied,
+                        format!("{github_user} was offboarded by {admin}"),
+                        Some(github_user),
+                        false,
+                    )
+                    .await;
+            }
+            let project = self.project.as_deref().unwrap_or_default();
+            let ip = self.peer_addr.ip();
+            let state = &self.server.state;
+            let config = &self.server.config;
+            if let Err(refusal) =
+                trust::on_login(config, state, github_user, project, &fingerprint, ip).await
+            {
+                // The user may hold other keys.
+                return self
+                    .reject_auth(AuditOutcome::Denied, refusal, Some(github_user), true)
+                    .await;
+            }
+            let github = &self.server.github_fetcher;
+            let refusal = match org_access::check(config, github, github_user).await {
+                Some(refusal) => Some(refusal),
+                None => match self.user_config(github_user).await {
+                    Ok(user_config) => {
+                        self.config = user_config;
+                        let project = Some(project).filter(|_| !self.is_ephemeral());
+                        project.and_then(|project| {
+                            user_config::project_refusal(&self.config, github_user, project)
+                        })
+                    }
+                    Err(refusal) => Some(refusal),
+                },
+            };
+            if let Some(refusal) = refusal {
+                // The client can't be told why a key is rejected; its sessions can.
+                self.audit(AuditEventKind::Auth, AuditOutcome::Denied, refusal.clone());
+                record_outcome_metric(AuditOutcome::Denied.as_str());
+                self.refusal = Some(refusal);
+                return Auth::Accept;
+            }
+            match self.server.state.record_login(github_user).await {
+                Ok(Some(_)) => {
+                    info!("{} logged in again; lifted offboarding flag", github_user);
+                    self.audit(
+                        AuditEventKind::Offboard,
+                        AuditOutcome::Success,
+                        "cancelled: logged in",
+                    );
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Failed to record login for {}: {:#}", github_user, e),
+            }
+            github_metadata::prefetch(&self.server.config.github_metadata, github_user);
+        }
+        self.audit(AuditEventKind::Auth, AuditOutcome::Success, fingerprint);
+        record_outcome_metric(AuditOutcome::Success.as_str());
+        self.server.auth_guard.record_success(self.peer_addr.ip());
+        Auth::Accept
+    }
+
+    /// Auth with a guest login. `signed` is set once the client proved it holds the key; the
+    /// first such key is bound to the invite, and other keys are refused from then on.
+    async fn auth_guest(
+        &mut self,
+        id: &str,
+        secret: &str,
+        public_key: &PublicKey,
+        signed: bool,
+    ) -> Auth {
+        let invite = match self.server.state.guest(id).await {
+            Some(invite) if self.server.config.guests.enabled && guest::verify(&invite, secret) => {
+                invite
+            }
+            _ => {
+                return self
+                    .reject_auth(
+                        AuditOutcome::Denied,
+                        format!("unknown, expired or revoked guest invite {id}"),
+                        None,
+                        false,
+                    )
+                    .await;
+            }
+        };
+        let fingerprint = compute_fingerprint_from_pubkey(public_key);
+        if invite
+            .key_fingerprint
+            .as_ref()
+            .is_some_and(|bound| *bound != fingerprint)
+        {
+            // The guest may hold the bound key among others.
+            return self
+                .reject_auth(
+                    AuditOutcome::Denied,
+                    format!("guest invite {id} is bound to another key"),
+                    None,
+                    true,
+                )
+                .await;
+        }
+        if !signed {
+            return Auth::Accept;
+        }
+
+        let inviter = invite.github_user.clone();
+        if let Some(record) = self.server.state.offboarding(&inviter).await
+            && let Some(ref admin) = record.requested_by
+        {
+            return self
+                .reject_auth(
+                    AuditOutcome::Denied,
+                    format!("guest invite {id}: {inviter} was offboarded by {admin}"),
+                    Some(&inviter),
+                    false,
+                )
+                .await;
+        }
+        match self.server.state.bind_guest_key(id, &fingerprint).await {
+            Ok(true) => {}
+            Ok(false) => {
+                return self
+                    .reject_auth(
+                        AuditOutcome::Denied,
+                        format!("guest invite {id} expired or is bound to another key"),
+                        None,
+                        false,
+                    )
+                    .await;
+            }
+            Err(e) => {
+                warn!("Failed to bind a key to guest invite {}: {:#}", id, e);
+                return self
+                    .reject_auth(
+                        AuditOutcome::Failure,
+                        format!("guest invite {id}: {e:#}"),
+                        None,
+                        false,
+                    )
+                    .await;
+            }
+        }
+        if invite.key_fingerprint.is_none() {
+            let notice = format!(
+                "guest invite {id} was first used from {} with key {fingerprint}",
+                self.peer_addr.ip()
+            );
+            if let Err(e) = self.server.state.add_notice(&inviter, notice).await {
+                warn!("Failed to queue a notice for {}: {:#}", inviter, e);
+            }
+        }
+
+        info!(
+            "Guest of {} (invite {}) logged in to {} from {}",
+            inviter, id, invite.project, self.peer_addr
+        );
+        self.github_user = Some(inviter);
+        self.project = Some(invite.project);
+        self.guest = Some(Guest {
+            id: id.to_string(),
+            read_only: invite.read_only,
+            watchdog: None,
+        });
+        self.audit(AuditEventKind::Auth, AuditOutcome::Success, fingerprint);
+        record_outcome_metric(AuditOutcome::Success.as_str());
+        self.server.auth_guard.record_success(self.peer_addr.ip());
+        Auth::Accept
+    }
+
+    /// Start disconnecting a guest at the end of their invite, once per connection.
+    fn watch_guest(&mut self, session: &Session) {
+        if let Some(ref mut guest) = self.guest
+            && guest.watchdog.is_none()
+        {
+            guest.watchdog = Some(guest::watch(
+                session.handle(),
+                self.server.state.clone(),
+                guest.id.clone(),
+            ));
+        }
+    }
+
+    /// Language of messages to this connection: the logged-in user's choice, or the gateway
+    /// default before login and for guests. Admins with access get their own.
+    async fn lang(&self) -> Lang {
+        let user = match self.impersonation {
+            Some(ref impersonation) => Some(&impersonation.admin),
+            None if self.guest.is_some() => None,
+            None => self.github_user.as_ref(),
+        };
+        match user {
+            Some(user) => self.server.state.language(user).await,
+            None => None,
+        }
+        .unwrap_or(self.server.config.i18n.default_lang)
+    }
+
+    /// Connect from the gateway to `port` on the container's IP (`direct_connect`); `None` if
+    /// the container has no IP or nothing there accepts, so the caller uses the bridge exec.
+    async fn connect_direct(&self, container_id: &str, port: u32) -> Option<tokio::net::TcpStream> {
+        let port = u16::try_from(port).ok()?;
+        let ip = match self.server.container_manager.container_ip(container_id).await {
+            Ok(ip) => ip,
+            Err(e) => {
+                debug!("No direct connection to {}: {:#}", container_id, e);
+                return None;
+            }
+        };
+        let connect = tokio::net::TcpStream::connect((ip, port));
+        match tokio::time::timeout(DIRECT_CONNECT_TIMEOUT, connect).await {
+            Ok(Ok(stream)) => Some(stream),
+            Ok(Err(e)) => {
+                debug!("Direct connection to {}:{} failed, bridging: {}", ip, port, e);
+                None
+            }
+            Err(_) => {
+                debug!("Direct connection to {}:{} timed out, bridging", ip, port);
+                None
+            }
+        }
+    }
+
+    /// Whether this connection is a guest limited to watching the shared tmux session.
+    fn is_read_only_guest(&self) -> bool {
+        self.guest.as_ref().is_some_and(|guest| guest.read_only)
+    }
+
+    /// Auth of another gateway logging in to migrate a workspace here; its host key must
+    /// belong to one of the `[migration] nodes`.
+    async fn auth_migration_peer(&mut self, public_key: &PublicKey) -> Auth {
+        match migration::peer_node(&self.server.config.migration, public_key) {
+            Some(node) => {
+                info!("Gateway {} logged in from {} to migrate", node.name, self.peer_addr);
+                self.migration_peer = Some(node.name.clone());
+                self.audit(
+                    AuditEventKind::Auth,
+                    AuditOutcome::Success,
+                    format!("migration peer {}", node.name),
+                );
+                Auth::Accept
+            }
+            None => {
+                self.reject_auth(
+                    AuditOutcome::Denied,
+                    "migration login with a host key not in [migration] nodes",
+                    None,
+                    false,
+                )
+                .await
+            }
+        }
+    }
+
+    /// `agentman-migrate receive` from another gateway: the workspace archive arrives as the
+    /// channel's data, up to EOF.
+    fn receive_migration(
+        &mut self,
+        channel_id: ChannelId,
+        from: String,
+        command: &str,
+        session: &mut Session,
+    ) -> Result<()> {
+        session.channel_success(channel_id)?;
+        let handle = session.handle();
+        let manifest = match Manifest::from_command(command) {
+            Some(Ok(manifest)) => manifest,
+            Some(Err(e)) => {
+                tokio::spawn(async move {
+                    let output = format!("agentman: {e:#}\n");
+                    finish_control_channel(&handle, channel_id, false, 1, output).await;
+                });
+                return Ok(());
+            }
+            None => {
+                tokio::spawn(async move {
+                    let output = "agentman: only migrations are accepted from other gateways\n";
+                    finish_control_channel(&handle, channel_id, false, 1, output.to_string())
+                        .await;
+                });
+                return Ok(());
+            }
+        };
+        self.audit(
+            AuditEventKind::Control,
+            AuditOutcome::Success,
+            format!(
+                "migrate {}/{} in from {}",
+                manifest.github_user, manifest.project, from
+            ),
+        );
+        let (tx, rx) = mpsc::channel(16);
+        self.migration_uploads.insert(channel_id, tx);
+        let backend = self.server.container_manager.clone();
+        tokio::spawn(async move {
+            let (exit_status, output) = match backend.receive_migration(&from, manifest, rx).await
+            {
+                Ok(report) => (0, report),
+                Err(e) => {
+                    warn!("Migration from {} failed: {:#}", from, e);
+                    (1, format!("agentman: {e:#}\n"))
+                }
+            };
+            finish_control_channel(&handle, channel_id, false, exit_status, output).await;
+        });
+        Ok(())
+    }
+
+    /// Tell the user where their workspace went if it was migrated to another gateway;
+    /// returns whether it was (and the channel is being closed).
+    async fn moved_elsewhere(&self, channel_id: ChannelId, session: &mut Session) -> Result<bool> {
+        if self.is_ephemeral() {
+            return Ok(false);
+        }
+        let (Some(github_user), Some(project)) = (&self.github_user, &self.project) else {
+            return Ok(false);
+        };
+        let Some(moved) = self.server.state.migration(github_user, project).await else {
+            return Ok(false);
+        };
+        session.channel_success(channel_id)?;
+        let handle = session.handle();
+        let has_pty = self.ptys.contains_key(&channel_id);
+        let msg = i18n::Msg::MovedToGateway {
+            project,
+            node: &moved.node,
+            address: &moved.address,
+        };
+        let output = format!("agentman: {}\n", msg.render(self.lang().await));
+        tokio::spawn(async move {
+            finish_control_channel(&handle, channel_id, has_pty, 1, output).await;
+        });
+        Ok(true)
+    }
+
+    /// Reject an auth attempt: audit it, count it against the client, and hold the reply for
+    /// the guard's delay. `keep_trying` leaves publickey (and keyboard-interactive, unless
+    /// restricted) available so the client can offer another key.
+    async fn reject_auth(
+        &self,
+        outcome: AuditOutcome,
+        detail: impl Into<String>,
+        github_user: Option<&str>,
+        keep_trying: bool,
+    ) -> Auth {
+        self.audit(AuditEventKind::Auth, outcome, detail);
+        record_outcome_metric(outcome.as_str());
+
+        let ip = self.peer_addr.ip();
+        self.server.auth_guard.record_failure(ip, github_user);
+        let policy = self.server.auth_guard.policy(ip, github_user);
+        if !policy.reject_delay.is_zero() {
+            debug!("Delaying auth rejection for {} by {:?}", self.peer_addr, policy.reject_delay);
+            record_delay_metric(policy.reject_delay);
+            tokio::time::sleep(policy.reject_delay).await;
+        }
+
+        Auth::Reject {
+            proceed_with_methods: keep_trying.then(|| auth_methods(policy.restrict_methods)),
+            partial_success: false,
+        }
+    }
+
+    /// Ask the guard before verifying an uncached key against GitHub; counts the lookups.
+    fn allow_github_lookups(&self, github_user: Option<&str>, count: usize) -> bool {
+        let ip = self.peer_addr.ip();
+        if !self.server.auth_guard.policy(ip, github_user).allow_github_lookup {
+            warn!(
+                "Skipping GitHub key lookup for {}: too many recent auth failures",
+                self.peer_addr
+            );
+            record_skipped_lookup_metric();
+            return false;
+        }
+        self.server.auth_guard.record_github_lookups(ip, count);
+        true
+    }
+
+    /// Gateway host address to listen on for a forward requested on `address`.
+    fn forward_bind_addr<'a>(&self, address: &'a str) -> &'a str {
+        if address.is_empty() || address == "0.0.0.0" || address == "*" {
+            if self.config.port_forwarding.allow_gateway_ports {
+                "0.0.0.0"
+            } else {
+                "127.0.0.1"
+            }
+        } else if is_localhost(address) {
+            "127.0.0.1"
+        } else if self.config.port_forwarding.allow_gateway_ports {
+            address
+        } else {
+            warn!("GatewayPorts disabled, binding to localhost");
+            "127.0.0.1"
+        }
+    }
+
+    /// Act on PTY input after escape processing; returns false once the channel is closed.
+    async fn handle_input(
+        &mut self,
+        channel_id: ChannelId,
+        input: EscapeInput,
+        session: &mut Session,
+    ) -> Result<bool> {
+        let reply = match input {
+            EscapeInput::Data(data) => {
+                if let Some(exec_session) = self.exec_sessions.get(&channel_id)
+                    && let Some(ref tx) = exec_session.stdin_tx
+                {
+                    let _ = tx.send(data).await;
+                }
+                return Ok(true);
+            }
+            EscapeInput::Echo(bytes) => {
+                session.data(channel_id, CryptoVec::from(bytes))?;
+                return Ok(true);
+            }
+            EscapeInput::Help => {
+                let escape = self.server.config.shell.escape_byte().unwrap_or(b'~');
+                format!("\r\n{}", escape::help_text(escape))
+            }
+            EscapeInput::Prompt => "\r\nagentman> ".to_string(),
+            EscapeInput::Cancel => "\r\n".to_string(),
+            EscapeInput::Submit(line) => format!("\r\n{}", self.run_prompt_command(&line).await),
+            EscapeInput::Close => {
+                let text = b"\r\nagentman: session closed\r\n";
+                session.data(channel_id, CryptoVec::from_slice(text))?;
+                self.audit(AuditEventKind::Shell, AuditOutcome::Success, "closed by escape");
+                self.close_from_escape(channel_id, session, true)?;
+                return Ok(false);
+            }
+            EscapeInput::Suspend => {
+                let (Some(github_user), Some(project)) =
+                    (self.github_user.clone(), self.project.clone())
+                else {
+                    return Ok(true);
+                };
+                let execution = self
+                    .server
+                    .container_manager
+                    .execute_control(
+                        GatewayControlCommand::ExecPause,
+                        &github_user,
+                        &project,
+                        &self.inspections,
+                    )
+                    .await;
+                let GatewayControlExecution::Immediate { exit_status, output } = execution else {
+                    return Ok(true);
+                };
+                let text = format!("\r\n{}", output.replace('\n', "\r\n"));
+                session.data(channel_id, CryptoVec::from_slice(text.as_bytes()))?;
+                if exit_status != 0 {
+                    return Ok(true);
+                }
+                self.audit(AuditEventKind::Shell, AuditOutcome::Success, "suspended by escape");
+                // Processes are frozen, not hung up; the next connection unpauses them.
+                self.close_from_escape(channel_id, session, false)?;
+                return Ok(false);
+            }
+        };
+        session.data(channel_id, CryptoVec::from_slice(reply.as_bytes()))?;
+        Ok(true)
+    }
+
+    /// End a PTY session from an escape sequence, as if the client had closed it.
+    fn close_from_escape(
+        &mut self,
+        channel_id: ChannelId,
+        session: &mut Session,
+        hang_up: bool,
+    ) -> Result<()> {
+        if let Some(exec_session) = self.exec_sessions.remove(&channel_id)
+            && hang_up
+        {
+            self.hang_up(exec_session);
+        }
+        self.tmux_attachments.remove(&channel_id);
+        self.ptys.remove(&channel_id);
+        session.exit_status_request(channel_id, 255)?;
+        session.eof(channel_id)?;
+        session.close(channel_id)?;
+        Ok(())
+    }
+
+    /// Run a line entered at the `~C` prompt and return its output.
+    async fn run_prompt_command(&mut self, line: &str) -> String {
+        let command = match escape::parse_prompt(line) {
+            Ok(Some(command)) => command,
+            Ok(None) => return String::new(),
+            Err(e) => return format!("agentman: {e:#}\r\n"),
+        };
+        let lang = self.lang().await;
+        match command {
+            PromptCommand::Help => escape::PROMPT_HELP.to_string(),
+            PromptCommand::List => {
+                if self.gateway_forwards.is_empty() {
+                    return format!("agentman: {}\r\n", i18n::Msg::NoGatewayForwards.render(lang));
+                }
+                let mut forwards: Vec<_> = self.gateway_forwards.iter().collect();
+                forwards.sort_by(|a, b| a.0.cmp(b.0));
+                forwards
+                    .into_iter()
+                    .map(|((bind, port), f)| format!("{bind}:{port} -> {}\r\n", f.target))
+                    .collect()
+            }
+            PromptCommand::CancelForward { bind, port } => {
+                let bind = bind
+                    .as_deref()
+                    .map_or("127.0.0.1", |b| self.forward_bind_addr(b))
+                    .to_string();
+                let addr = format!("{bind}:{port}");
+                let msg = match self.gateway_forwards.remove(&(bind, port)) {
+                    Some(forward) => {
+                        forward.task.abort();
+                        i18n::Msg::CancelledForward { addr: &addr }
+                    }
+                    None => i18n::Msg::NoForward { addr: &addr },
+                };
+                format!("agentman: {}\r\n", msg.render(lang))
+            }
+            PromptCommand::Forward {
+                bind,
+                port,
+                host,
+                host_port,
+            } => match self
+                .add_gateway_forward(bind.as_deref(), port, &host, host_port)
+                .await
+            {
+                Ok(reply) => reply,
+                Err(e) => {
+                    self.audit(
+                        AuditEventKind::LocalForward,
+                        AuditOutcome::Failure,
+                        format!("gateway port {port} -> {host}:{host_port}: {e:#}"),
+                    );
+                    let error = format!("{e:#}");
+                    format!("agentman: {}\r\n", i18n::Msg::ForwardFailed { error: &error }.render(lang))
+                }
+            },
+        }
+    }
+
+    /// Listen on a gateway host port (`~C -L`) and bridge each connection into the sandbox,
+    /// under the same policy as `-L` and `-R` forwards.
+    async fn add_gateway_forward(
+        &mut self,
+        bind: Option<&str>,
+        port: u16,
+        host: &str,
+        host_port: u16,
+    ) -> Result<String> {
+        let pf = &self.config.port_forwarding;
+        if !pf.allow_local {
+            bail!("local port forwarding is disabled on this gateway");
+        }
+        if self.is_read_only_guest() {
+            bail!("read-only guests can't forward ports");
+        }
+        if memory_guard::shedding() {
+            bail!("the gateway is overloaded; try again later");
+        }
+        let dest = if is_localhost(host) {
+            "127.0.0.1".to_string()
+        } else if pf.allow_nonlocal_destinations && is_forward_destination(host) {
+            host.to_string()
+        } else if pf.allow_nonlocal_destinations {
+            bail!("invalid destination {host:?}");
+        } else {
+            bail!("non-local destination {host} is not allowed");
+        };
+        let bind_addr = bind
+            .map_or("127.0.0.1", |b| self.forward_bind_addr(b))
+            .to_string();
+        let reservation = self.remote_port_reservation().await?;
+        if let Some(block) = reservation
+            && port != 0
+            && !block.contains(port)
+        {
+            bail!("port {port} is outside this workspace's reserved ports {block}");
+        }
+        let listener = bind_remote_forward(&bind_addr, port as u32, reservation).await?;
+        let port = listener.local_addr()?.port();
+        let container_id = self
+            .container_id
+            .clone()
+            .ok_or_else(|| anyhow!("no sandbox for this connection"))?;
+
+        let target = format!("{dest}:{host_port}");
+        let backend = self.server.container_manager.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        warn!("Accept error: {}", e);
+                        break;
+                    }
+                };
+                let backend = backend.clone();
+                let container_id = container_id.clone();
+                let dest = dest.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = bridge_into_container(
+                        &*backend,
+                        &container_id,
+                        &dest,
+                        host_port,
+                        stream,
+                        &[],
+                    )
+                    .await
+                    {
+                        debug!("Gateway forward to {}:{} failed: {:#}", dest, host_port, e);
+                    }
+                });
+            }
+        });
+
+        self.audit(
+            AuditEventKind::LocalForward,
+            AuditOutcome::Success,
+            format!("gateway {bind_addr}:{port} -> {target}"),
+        );
+        let msg = i18n::Msg::Forwarding {
+            addr: &format!("{bind_addr}:{port}"),
+            target: &target,
+        };
+        let reply = format!("agentman: {}\r\n", msg.render(self.lang().await));
+        self.gateway_forwards
+            .insert((bind_addr, port), GatewayForward { target, task });
+        Ok(reply)
+    }
+
+    /// The host port block this workspace's remote forwards must use, when a range is configured.
+    async fn remote_port_reservation(&self) -> Result<Option<PortReservation>> {
+        let pf = &self.config.port_forwarding;
+        let Some(range) = pf.remote_port_range else {
+            return Ok(None);
+        };
+        let (Some(github_user), Some(project)) = (&self.github_user, &self.project) else {
+            return Err(anyhow!("no workspace for this connection"));
+        };
+        let block = self
+            .server
+            .state
+            .reserve_port_block(github_user, project, range, pf.remote_ports_per_workspace)
+            .await?;
+        Ok(Some(block))
+    }
+
+    /// Record an audit event for this connection.
+    fn audit(&self, kind: AuditEventKind, outcome: AuditOutcome, detail: impl Into<String>) {
+        self.server.audit.record(self.audit_event(kind, outcome, detail));
+    }
+
+    /// The audit event [`Self::audit`] records.
+    fn audit_event(
+        &self,
+        kind: AuditEventKind,
+        outcome: AuditOutcome,
+        detail: impl Into<String>,
+    ) -> AuditEvent {
+        // A guest's activity is recorded under the inviter.
+        let detail = match self.guest {
+            Some(ref guest) => format!("guest {}: {}", guest.id, detail.into()),
+            None => detail.into(),
+        };
+        let mut event = AuditEvent::new(kind, outcome)
+            .github_user(self.github_user.as_deref())
+            .project(self.project.as_deref())
+            .peer(self.peer_addr)
+            .detail(detail);
+        // An admin's activity is recorded under the owner.
+        if let Some(ref impersonation) = self.impersonation {
+            event = event.field("impersonated_by", impersonation.admin.clone());
+        }
+        event
+    }
+
+    /// Verify a key against the GitHub user named at the keyboard-interactive prompt. A key
+    /// that isn't listed yet is re-checked with backoff for up to `key_propagation_wait_secs`,
+    /// since the user may have added it on GitHub a moment ago. Only the first such key of a
+    /// connection waits; the client's other keys are then checked against the list as it is.
+    async fn verify_pending_key(&mut self, github_user: &str, openssh_key: &str) -> Result<String> {
+        let fetcher = self.server.github_fetcher.clone();
+        let mut error = match fetcher.verify_key(github_user, openssh_key).await {
+            Ok(verified_type) => return Ok(verified_type),
+            Err(e) => e,
+        };
+        let wait = Duration::from_secs(self.server.config.key_propagation_wait_secs);
+        if self.waited_for_published_key || wait.is_zero() {
+            return Err(error);
+        }
+        self.waited_for_published_key = true;
+        info!(
+            "Key not found for GitHub user '{}' yet; re-checking for up to {}s",
+            github_user,
+            wait.as_secs()
+        );
+        let deadline = tokio::time::Instant::now() + wait;
+        let mut delay = Duration::from_secs(2);
+        let verified = loop {
+            let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+            if remaining.is_zero() || !self.allow_github_lookups(Some(github_user), 1) {
+                break Err(error);
+            }
+            tokio::time::sleep(delay.min(remaining)).await;
+            match fetcher.verify_key(github_user, openssh_key).await {
+                Ok(verified_type) => break Ok(verified_type),
+                Err(e) => error = e,
+            }
+            delay *= 2;
+        };
+        metrics::inc(
+            "agentman_auth_key_propagation_waits_total",
+            "Keyboard-interactive logins that waited for their key to appear on GitHub.",
+            &[("outcome", if verified.is_ok() { "found" } else { "missing" })],
+        );
+        verified
+    }
+
+    /// `agentman route`: the forwarding policy that applies to this connection and the
+    /// forwards it holds. Refused forwards reach the client as bare channel or request
+    /// failures, so this is where users find out why.
+    async fn route_report(&self) -> GatewayControlExecution {
+        let pf = &self.config.port_forwarding;
+        let allowed = |on: bool| if on { "allowed" } else { "not allowed" };
+        let mut out = String::new();
+        if self.is_read_only_guest() {
+            out.push_str("agentman: read-only guests can't forward ports\n");
+        }
+        if memory_guard::shedding() {
+            out.push_str("agentman: the gateway is overloaded; new forwards are refused for now\n");
+        }
+
+        out.push_str(&format!("Local forwards (ssh -L):   {}\n", allowed(pf.allow_local)));
+        out.push_str(&format!(
+            "  destinations: localhost in the sandbox{}\n",
+            if pf.allow_nonlocal_destinations {
+                ", and other hosts from the sandbox's network"
+            } else {
+                " only"
+            }
+        ));
+        out.push_str(&format!(
+            "  path: {}\n",
+            if pf.direct_connect {
+                "direct to the container's address (loopback-only services through a bridge)"
+            } else {
+                "a bridge command in the sandbox"
+            }
+        ));
+        out.push_str(&format!(
+            "Dynamic forwards (ssh -D): {}{}\n",
+            allowed(pf.allow_dynamic),
+            if pf.allow_dynamic { " (any host and port, from the sandbox's network)" } else { "" }
+        ));
+
+        out.push_str(&format!("Remote forwards (ssh -R):  {}\n", allowed(pf.allow_remote)));
+        out.push_str(&format!(
+            "  bind addresses: {}\n",
+            if pf.allow_gateway_ports {
+                "any address of the gateway host"
+            } else {
+                "127.0.0.1 on the gateway host (other addresses are bound there too)"
+            }
+        ));
+        let ports = match self.remote_port_reservation().await {
+            Ok(Some(block)) => format!("{block} (reserved for this workspace; 0 picks one)"),
+            Ok(None) => "any free port".to_string(),
+            Err(e) => format!("none available: {e:#}"),
+        };
+        out.push_str(&format!("  ports: {ports}\n"));
+        let escape = self.server.config.shell.escape_byte().is_some();
+        out.push_str(&format!(
+            "Gateway forwards (~C -L): {}\n",
+            match (pf.allow_local, escape) {
+                (false, _) => "not allowed",
+                (true, false) => "not available ([shell] escape_char = \"none\")",
+                (true, true) => "allowed, from a terminal, on the same ports as ssh -R",
+            }
+        ));
+        let sockets = if pf.streamlocal_paths.is_empty() || !(pf.allow_local || pf.allow_remote) {
+            "not allowed".to_string()
+        } else {
+            pf.streamlocal_paths.join(", ")
+        };
+        out.push_str(&format!("Unix socket forwards:      {sockets}\n"));
+
+        let mut active: Vec<String> = self
+            .remote_forwards
+            .keys()
+            .map(|(address, port)| format!("  -R {}:{port}\n", self.forward_bind_addr(address)))
+            .collect();
+        active.extend(self.remote_socket_forwards.keys().map(|path| format!("  -R {path}\n")));
+        active.extend(
+            self.gateway_forwards
+                .iter()
+                .map(|((bind, port), f)| format!("  ~C -L {bind}:{port} -> {}\n", f.target)),
+        );
+        active.sort();
+        if active.is_empty() {
+            out.push_str("Active forwards: none\n");
+        } else {
+            out.push_str("Active forwards:\n");
+            out.extend(active);
+        }
+        GatewayControlExecution::Immediate {
+            exit_status: 0,
+            output: out,
+        }
+    }
+
+    /// `agentman verify`: check the keys this connection offered that aren't cached against
+    /// the user's GitHub keys now, and cache the ones listed there. Exits 1 while any of them
+    /// is still missing, so it can be retried in a loop.
+    async fn verify_offered_keys(&self, requested: Option<&str>) -> GatewayControlExecution {
+        let fail = |output: String| GatewayControlExecution::Immediate {
+            exit_status: 1,
+            output,
+        };
+        let Some(github_user) = self.github_user.as_deref() else {
+            return fail("agentman: not authenticated\n".to_string());
+        };
+        if let Some(requested) = requested
+            && !requested.eq_ignore_ascii_case(github_user)
+        {
+            return fail(format!(
+                "agentman: this connection is authenticated as {github_user}; only its own keys \
+                 can be verified\n"
+            ));
+        }
+        if !self.allow_github_lookups(Some(github_user), 1) {
+            return fail(
+                "agentman: too many recent authentication failures; try again later\n".to_string(),
+            );
+        }
+        let keys = match self.server.github_fetcher.fetch_keys(github_user).await {
+            Ok(keys) => keys,
+            Err(e) => return fail(format!("agentman: verify failed: {e:#}\n")),
+        };
+        let published: HashMap<String, String> = keys
+            .iter()
+            .filter_map(|key| {
+                let fingerprint = compute_fingerprint(key).ok()?;
+                let (key_type, _) = parse_ssh_key(key).ok()?;
+                Some((fingerprint, key_type))
+            })
+            .collect();
+
+        let mut out = format!(
+            "agentman: {} key(s) listed on github.com/{github_user}.keys\n",
+            published.len()
+        );
+        let mut missing = 0;
+        for fingerprint in &self.offered_key_fingerprints {
+            let status = match self.server.state.get_github_user(fingerprint).await {
+                Some(cached) if cached.github_username == github_user => "cached",
+                Some(_) => "cached for another GitHub user",
+                None => match published.get(fingerprint) {
+                    Some(key_type) => {
+                        let entry = KeyCacheEntry {
+                            github_username: github_user.to_string(),
+                            verified_at: Utc::now(),
+                            key_type: key_type.clone(),
+                        };
+                        match self.server.state.cache_key(fingerprint.clone(), entry).await {
+                            Ok(()) => {
+                                info!(
+                                    "Cached key {} for GitHub user '{}'",
+                                    fingerprint, github_user
+                                );
+                                "verified and cached"
+                            }
+                            Err(e) => {
+                                warn!("Failed to cache key {}: {}", fingerprint, e);
+                                missing += 1;
+                                "on GitHub, but caching failed"
+                            }
+                        }
+                    }
+                    None => {
+                        missing += 1;
+                        "not on GitHub yet"
+                    }
+                },
+            };
+            out.push_str(&format!("  {fingerprint}: {status}\n"));
+        }
+        GatewayControlExecution::Immediate {
+            exit_status: u32::from(missing > 0),
+            output: out,
+        }
+    }
+
+    /// Cache all offered keys for a GitHub user.
+    ///
+    /// This ensures that all keys the client offered during auth are cached,
+    /// not just the one that was verified against GitHub. This prevents
+    /// repeated keyboard-interactive prompts when the client offers keys
+    /// in a different order on reconnect.
+    async fn cache_all_offered_keys(&self, github_user: &str, key_type: &str) {
+        for fingerprint in &self.offered_key_fingerprints {
+            // Skip if already cached
+            if self.server.state.get_github_user(fingerprint).await.is_some() {
+                continue;
+            }
+
+            let entry = KeyCacheEntry {
                 github_username: github_user.to_string(),
                 verified_at: Utc::now(),
                 key_type: key_type.to_string(),
@@ -1294,16 +3336,346 @@ impl ConnectionHandler {
         }
     }
 
-    /// Start an exec session and connect it to an SSH channel.
+    /// Run `agentman new <project> --from <url>`: clone the template, ask for its variables on
+    /// this channel, then render it into the new project's workspace.
+    fn start_template_prompts(
+        &mut self,
+        channel_id: ChannelId,
+        project: String,
+        url: String,
+        session: &mut Session,
+    ) {
+        let Some(github_user) = self.github_user.clone() else {
+            return;
+        };
+        let (tx, rx) = mpsc::channel(16);
+        let cancelled = Arc::new(AtomicBool::new(false));
+        self.input_sessions.insert(
+            channel_id,
+            InputSession {
+                input: Some(tx),
+                cancelled: cancelled.clone(),
+            },
+        );
+
+        let cm = self.server.container_manager.clone();
+        let has_pty = self.ptys.contains_key(&channel_id);
+        let handle = session.handle();
+        // Cloning can take a while; run off the SSH handler.
+        tokio::spawn(async move {
+            let mut out = PromptOutput {
+                handle: &handle,
+                channel_id,
+                has_pty,
+            };
+            let mut reader = LineReader {
+                input: rx,
+                pending: Vec::new().into(),
+                echo: has_pty,
+                eof: false,
+                after_cr: false,
+                cancelled,
+            };
+            let (exit_status, output) =
+                run_template_prompts(&*cm, &github_user, &project, &url, &mut out, &mut reader)
+                    .await;
+            finish_control_channel(&handle, channel_id, has_pty, exit_status, output).await;
+        });
+    }
+
+    /// Run `agentman env-file set`: read the channel's input to EOF and store it as the
+    /// workspace's env file. Nothing is stored if the channel closes first.
+    fn receive_env_file(
+        &mut self,
+        channel_id: ChannelId,
+        github_user: String,
+        project: String,
+        session: &mut Session,
+    ) {
+        let handle = session.handle();
+        let has_pty = self.ptys.contains_key(&channel_id);
+        if has_pty {
+            // Typed input would be echoed and Ctrl-D isn't an EOF in raw mode.
+            tokio::spawn(async move {
+                let output = "agentman: pipe the file in without -t: \
+                              ssh <host> agentman env-file set < .env\n"
+                    .to_string();
+                finish_control_channel(&handle, channel_id, has_pty, 1, output).await;
+            });
+            return;
+        }
+        let (tx, mut rx) = mpsc::channel(16);
+        let cancelled = Arc::new(AtomicBool::new(false));
+        self.input_sessions.insert(
+            channel_id,
+            InputSession {
+                input: Some(tx),
+                cancelled: cancelled.clone(),
+            },
+        );
+
+        let config = self.server.config.env_file.clone();
+        tokio::spawn(async move {
+            // One byte over the limit is enough to refuse the file.
+            let mut content = Vec::new();
+            while let Some(chunk) = rx.recv().await {
+                content.extend_from_slice(&chunk);
+                if content.len() > config.max_bytes {
+                    break;
+                }
+            }
+            if cancelled.load(Ordering::Relaxed) {
+                return;
+            }
+            let (exit_status, output) =
+                match env_file::store(&config, &github_user, &project, &content).await {
+                    Ok(count) => (
+                        0,
+                        format!(
+                            "agentman: env file for {project} saved ({count} variable(s)); new \
+                             execs get them, tmux sessions already running don't\n"
+                        ),
+                    ),
+                    Err(e) => (1, format!("agentman: {e:#}\n")),
+                };
+            finish_control_channel(&handle, channel_id, has_pty, exit_status, output).await;
+        });
+    }
+
+    /// Add the workspace's env file (`agentman env-file`) to an exec's environment.
+    async fn inject_env_file(&self, env: &mut Vec<String>) {
+        if self.is_ephemeral() {
+            return;
+        }
+        if let (Some(github_user), Some(project)) = (&self.github_user, &self.project) {
+            env_file::inject(&self.server.config.env_file, github_user, project, env).await;
+        }
+    }
+
+    /// Hang up an exec whose channel went away while the command was still running.
+    /// Whether the connection uses a throwaway sandbox instead of the workspace's container.
+    fn is_ephemeral(&self) -> bool {
+        let config = &self.server.config.ephemeral;
+        config.enabled
+            && (self.ephemeral_requested || self.project.as_deref() == Some(config.project.as_str()))
+    }
+
+    /// The connection's sandbox: the workspace's container (created or started if needed), or
+    /// the throwaway one, created on first use.
+    async fn sandbox(&mut self) -> Result<String> {
+        let github_user = self
+            .github_user
+            .clone()
+            .ok_or_else(|| anyhow!("Not authenticated"))?;
+        let container_id = if self.is_ephemeral() {
+            if let Some(ref id) = self.container_id {
+                return Ok(id.clone());
+            }
+            let lease = ephemeral::lease(&github_user, self.server.config.ephemeral.max_per_user)?;
+            let id = self
+                .server
+                .container_manager
+                .create_ephemeral(&github_user)
+                .await?;
+            self.ephemeral_lease = Some(lease);
+            id
+        } else {
+            let project = self
+                .project
+                .as_ref()
+                .ok_or_else(|| anyhow!("No project specified"))?;
+            if let Some(moved) = self.server.state.migration(&github_user, project).await {
+                bail!("{github_user}/{project} was moved to the gateway {}", moved.node);
+            }
+            if let Some(name) = self.image_variant.clone() {
+                let config = &self.server.config;
+                let Some(image) = config.named_image(&name) else {
+                    let mut names: Vec<_> =
+                        config.images.named.keys().map(String::as_str).collect();
+                    names.sort_unstable();
+                    names.insert(0, "default");
+                    return Err(UnknownImage {
+                        name,
+                        available: names.join(", "),
+                    }
+                    .into());
+                };
+                let choice = (image != config.docker_image).then(|| image.to_string());
+                let image = image.to_string();
+                self.image_variant = None;
+                if self
+                    .server
+                    .container_manager
+                    .select_image(&github_user, project, choice)
+                    .await?
+                {
+                    self.image_pending = Some(image);
+                }
+            }
+            if let Some(url) = self.clone_url.take() {
+                self.server
+                    .container_manager
+                    .request_clone(&github_user, project, &url)
+                    .await?;
+            }
+            let container_id = self
+                .server
+                .container_manager
+                .get_or_create_container(&github_user, project)
+                .await?;
+            if self.active_workspace.is_none() {
+                self.server.state.connection_opened(&github_user, project);
+                self.active_workspace = Some((github_user, project.clone()));
+            }
+            container_id
+        };
+        self.container_id = Some(container_id.clone());
+        Ok(container_id)
+    }
+
+    /// [`Self::sandbox`] for a session channel, telling the client its place in the queue
+    /// while the sandbox waits to be created (`[admission]`).
+    async fn sandbox_reporting(
+        &mut self,
+        channel_id: ChannelId,
+        handle: russh::server::Handle,
+    ) -> Result<String> {
+        let project = if self.is_ephemeral() {
+            Some(self.server.config.ephemeral.project.clone())
+        } else {
+            self.project.clone()
+        };
+        let (Some(github_user), Some(project)) = (self.github_user.clone(), project) else {
+            return self.sandbox().await;
+        };
+        let reporter = tokio::spawn(report_queue_position(
+            handle,
+            channel_id,
+            self.ptys.contains_key(&channel_id),
+            format!("{github_user}/{project}"),
+            self.lang().await,
+        ));
+        let result = self.sandbox().await;
+        reporter.abort();
+        result
+    }
+
+    /// [`Self::sandbox_reporting`], except that a user over their quota (`[quotas]`) or asking
+    /// for an unknown image is told why and the channel ends with exit status 1; `None` then.
+    async fn sandbox_or_refuse(
+        &mut self,
+        channel_id: ChannelId,
+        session: &mut Session,
+    ) -> Result<Option<String>> {
+        let err = match self.sandbox_reporting(channel_id, session.handle()).await {
+            Ok(container_id) => return Ok(Some(container_id)),
+            Err(e) => e,
+        };
+        let reason = match err.downcast_ref::<QuotaExceeded>() {
+            Some(exceeded) => exceeded.to_string(),
+            None => match err.downcast_ref::<UnknownImage>() {
+                Some(unknown) => unknown.to_string(),
+                None => return Err(err),
+            },
+        };
+        session.channel_success(channel_id)?;
+        let handle = session.handle();
+        let has_pty = self.ptys.contains_key(&channel_id);
+        let output = format!("agentman: {reason}\n");
+        tokio::spawn(async move {
+            finish_control_channel(&handle, channel_id, has_pty, 1, output).await;
+        });
+        Ok(None)
+    }
+
+    /// The config with `github_user`'s `users_dir` overrides, or why they are refused if their
+    /// file is broken.
+    async fn user_config(&self, github_user: &str) -> Result<Arc<GatewayConfig>, String> {
+        user_config::resolve(&self.server.config, github_user).await.map_err(|e| {
+            warn!("Refusing {}: {:#}", github_user, e);
+            format!(
+                "access denied: the gateway's settings for {github_user} are invalid; ask an \
+                 admin to fix them"
+            )
+        })
+    }
+
+    /// Answer a session of a user `allowed_github_orgs` or `allowed_projects` refused with the
+    /// refusal; returns whether it did.
+    fn refused(&self, channel_id: ChannelId, session: &mut Session) -> Result<bool> {
+        let Some(ref refusal) = self.refusal else {
+            return Ok(false);
+        };
+        session.channel_success(channel_id)?;
+        let handle = session.handle();
+        let has_pty = self.ptys.contains_key(&channel_id);
+        let output = format!("agentman: {refusal}\n");
+        tokio::spawn(async move {
+            finish_control_channel(&handle, channel_id, has_pty, 1, output).await;
+        });
+        Ok(true)
+    }
+
+    /// `SSH_AUTH_SOCK` for sessions, when the client forwards its agent into the workspace.
+    fn ssh_auth_sock(&self) -> Option<String> {
+        self.agent_forwarding
+            .as_ref()
+            .filter(|_| !self.is_ephemeral())
+            .map(|a| a.ssh_auth_sock_in_container())
+    }
+
+    fn hang_up(&self, exec_session: ExecSession) {
+        let Some(tracked) = exec_session.tracked else {
+            return;
+        };
+        if !tracked.hangup_on_close || exec_session.finished.load(Ordering::Relaxed) {
+            return;
+        }
+        // Connections can be torn down while the runtime shuts down; nothing to clean up then.
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+
+        let backend = self.server.container_manager.clone();
+        let grace = Duration::from_secs(self.server.config.exec.kill_grace_secs);
+        runtime.spawn(async move {
+            debug!(
+                "Hanging up exec {} in {} after its channel closed",
+                tracked.marker, tracked.container_id
+            );
+            metrics::inc(
+                "agentman_exec_hangups_total",
+                "Execs signalled because their SSH channel closed while they were running.",
+                &[],
+            );
+            for (signal, delay) in [("HUP", Duration::ZERO), ("KILL", grace)] {
+                tokio::time::sleep(delay).await;
+                if let Err(e) = backend
+                    .signal_exec_processes(&tracked.container_id, &tracked.marker, signal)
+                    .await
+                {
+                    warn!("Failed to send SIG{} to exec {}: {:#}", signal, tracked.marker, e);
+                    return;
+                }
+            }
+        });
+    }
+
+    /// Start an exec session and connect it to an SSH channel. `audit` is recorded again,
+    /// with the exit status and duration, when the command ends.
+    #[allow(clippy::too_many_arguments)]
     async fn start_exec_session(
         &mut self,
         channel_id: ChannelId,
         exec_id: String,
         tty: bool,
         kind: ChannelStreamKind,
+        tracked: Option<TrackedExec>,
+        audit: Option<AuditEvent>,
         session: &mut Session,
     ) -> Result<()> {
-        let docker = self.server.container_manager.docker().clone();
+        let backend = self.server.container_manager.clone();
+        let started = tokio::time::Instant::now();
 
         // Start the exec
         let results = self
@@ -1315,17 +3687,31 @@ impl ConnectionHandler {
         // Create channel for stdin
         let (stdin_tx, mut stdin_rx) = mpsc::channel::<Vec<u8>>(32);
 
+        let finished = Arc::new(AtomicBool::new(false));
+        let flow = Arc::new(slow_clients::Flow::default());
         self.exec_sessions.insert(
             channel_id,
             ExecSession {
                 exec_id: exec_id.clone(),
                 tty,
                 stdin_tx: Some(stdin_tx),
+                tracked: tracked.clone(),
+                finished: finished.clone(),
+                escape: (tty && kind == ChannelStreamKind::Session)
+                    .then(|| self.server.config.shell.escape_byte())
+                    .flatten()
+                    .map(EscapeParser::new),
+                flow: flow.clone(),
             },
         );
 
         // Get session handle for async operations
         let handle = session.handle();
+        let traffic = self.traffic.clone();
+        let server = self.server.clone();
+        let github_user = self.github_user.clone().unwrap_or_default();
+        let project = self.project.clone().unwrap_or_default();
+        let what = format!("channel {channel_id} of {github_user}/{project}");
 
         // Spawn task to handle the exec I/O
         tokio::spawn(async move {
@@ -1342,83 +3728,123 @@ impl ConnectionHandler {
 
                     // Task to forward container output to SSH channel
                     let stdout_task = async move {
-                        while let Some(output_result) = output.next().await {
-                            match output_result {
-                                Ok(output) => {
-                                    match output {
-                                        LogOutput::StdErr { message } => {
-                                            match kind {
-                                                ChannelStreamKind::Session => {
-                                                    // Keep stderr separate so tools like Zed can use stdout as a clean transport.
-                                                    if handle
-                                                        .extended_data(
-                                                            channel_id,
-                                                            1, // SSH_EXTENDED_DATA_STDERR
-                                                            CryptoVec::from_slice(message.as_ref()),
-                                                        )
-                                                        .await
-                                                        .is_err()
-                                                    {
-                                                        break;
+                        let slow_clients = &server.config.slow_clients;
+                        let mut stalled = None;
+                        let forward = async {
+                            while let Some(output_result) = output.next().await {
+                                match output_result {
+                                    Ok(output) => {
+                                        match output {
+                                            LogOutput::StdErr { message } => {
+                                                match kind {
+                                                    ChannelStreamKind::Session => {
+                                                        if let Err(e) = flow.ready(slow_clients, &what).await {
+                                                            stalled = Some(e);
+                                                            break;
+                                                        }
+                                                        flow.queued(message.len());
+                                                        traffic.sent(message.len());
+                                                        // Keep stderr separate so tools like Zed can use stdout as a clean transport.
+                                                        if handle
+                                                            .extended_data(
+                                                                channel_id,
+                                                                1, // SSH_EXTENDED_DATA_STDERR
+                                                                CryptoVec::from_slice(message.as_ref()),
+                                                            )
+                                                            .await
+                                                            .is_err()
+                                                        {
+                                                            break;
+                                                        }
+                                                    }
+                                                    ChannelStreamKind::TcpForward => {
+                                                        // For TCP forwarding channels, do not send stderr as it would corrupt the byte stream.
+                                                        // Log it server-side instead.
+                                                        warn!(
+                                                            "tcp-forward stderr (ignored): {}",
+                                                            String::from_utf8_lossy(message.as_ref())
+                                                        );
                                                     }
-                                                }
-                                                ChannelStreamKind::TcpForward => {
-                                                    // For TCP forwarding channels, do not send stderr as it would corrupt the byte stream.
-                                                    // Log it server-side instead.
-                                                    warn!(
-                                                        "tcp-forward stderr (ignored): {}",
-                                                        String::from_utf8_lossy(message.as_ref())
-                                                    );
                                                 }
                                             }
-                                        }
-                                        LogOutput::StdOut { message }
-                                        | LogOutput::StdIn { message }
-                                        | LogOutput::Console { message } => {
-                                            if handle
-                                                .data(
-                                                    channel_id,
-                                                    CryptoVec::from_slice(message.as_ref()),
-                                                )
-                                                .await
-                                                .is_err()
-                                            {
-                                                break;
+                                            LogOutput::StdOut { message }
+                                            | LogOutput::StdIn { message }
+                                            | LogOutput::Console { message } => {
+                                                if let Err(e) = flow.ready(slow_clients, &what).await {
+                                                    stalled = Some(e);
+                                                    break;
+                                                }
+                                                flow.queued(message.len());
+                                                traffic.sent(message.len());
+                                                if handle
+                                                    .data(
+                                                        channel_id,
+                                                        CryptoVec::from_slice(message.as_ref()),
+                                                    )
+                                                    .await
+                                                    .is_err()
+                                                {
+                                                    break;
+                                                }
                                             }
                                         }
                                     }
-                                }
-                                Err(e) => {
-                                    warn!("Exec output error: {}", e);
-                                    break;
+                                    Err(e) => {
+                                        warn!("Exec output error: {}", e);
+                                        break;
+                                    }
                                 }
                             }
+                        };
+                        let timed_out = match tracked.as_ref().and_then(|t| t.timeout) {
+                            Some(limit) => tokio::time::timeout(limit, forward).await.is_err(),
+                            None => {
+                                forward.await;
+                                false
+                            }
+                        };
+                        finished.store(true, Ordering::Relaxed);
+                        if let Some(stalled) = stalled {
+                            close_stalled_channel(
+                                &server,
+                                &handle,
+                                channel_id,
+                                tracked.as_ref(),
+                                (&github_user, &project),
+                                stalled,
+                            )
+                            .await;
+                            return;
                         }
 
                         if kind == ChannelStreamKind::Session {
                             // Capture exit status for clients (editors) that rely on it.
-                            // `inspect_exec` may briefly report Running=true even after the output stream ends,
-                            // so we poll for a short time.
-                            let mut exit_status: u32 = 255;
-                            for _ in 0..80 {
-                                match docker.inspect_exec(&exec_id).await {
-                                    Ok(info) => {
-                                        if info.running.unwrap_or(false) {
-                                            tokio::time::sleep(Duration::from_millis(25)).await;
-                                            continue;
-                                        }
-                                        let code = info.exit_code.unwrap_or(0);
-                                        exit_status = if code < 0 { 255 } else { code as u32 };
-                                        break;
-                                    }
+                            // Docker may briefly report the exec as running after its output ends;
+                            // the backend polls for a short time.
+                            let exit_status = match tracked {
+                                Some(ref tracked) if timed_out => {
+                                    kill_timed_out_exec(&*backend, &handle, channel_id, tty, tracked)
+                                        .await
+                                }
+                                _ => match backend.exec_exit_code(&exec_id).await {
+                                    Ok(code) if code >= 0 => code as u32,
+                                    Ok(_) => 255,
                                     Err(e) => {
                                         warn!("Failed to inspect exec {}: {}", exec_id, e);
-                                        break;
+                                        255
                                     }
-                                }
-                            }
+                                },
+                            };
 
                             let _ = handle.exit_status_request(channel_id, exit_status).await;
+                            if let Some(event) = audit {
+                                server.audit.record(command_ended(
+                                    event,
+                                    exit_status,
+                                    started.elapsed(),
+                                    timed_out,
+                                ));
+                            }
                         }
 
                         // Send EOF and close
@@ -1441,7 +3867,182 @@ impl ConnectionHandler {
     }
 }
 
+impl<B: ContainerBackend> Drop for ConnectionHandler<B> {
+    fn drop(&mut self) {
+        memory_guard::connection_closed();
+        if let Some((github_user, project)) = self.active_workspace.take() {
+            self.server.state.connection_closed(&github_user, &project);
+        }
+        for forward in self.gateway_forwards.values() {
+            forward.task.abort();
+        }
+        for (task, host_path) in self.remote_socket_forwards.values() {
+            task.abort();
+            streamlocal::remove(host_path);
+        }
+        // Removing a throwaway sandbox ends everything in it.
+        if let Some(lease) = self.ephemeral_lease.take() {
+            if let (Some(container_id), Ok(runtime)) =
+                (self.container_id.clone(), tokio::runtime::Handle::try_current())
+            {
+                let backend = self.server.container_manager.clone();
+                runtime.spawn(async move {
+                    if let Err(e) = backend.remove_ephemeral(&container_id).await {
+                        warn!("{:#}", e);
+                    }
+                    drop(lease);
+                });
+            }
+            return;
+        }
+        // A dropped connection doesn't always close its channels first.
+        for (_, exec_session) in std::mem::take(&mut self.exec_sessions) {
+            self.hang_up(exec_session);
+        }
+    }
+}
+
+/// Kill the processes of an exec that ran past its time limit and tell the client why.
+async fn kill_timed_out_exec<B: ContainerBackend>(
+    backend: &B,
+    handle: &russh::server::Handle,
+    channel_id: ChannelId,
+    tty: bool,
+    tracked: &TrackedExec,
+) -> u32 {
+    let limit = tracked.timeout.unwrap_or_default().as_secs();
+    warn!(
+        "Exec {} in {} exceeded its {}s time limit; killing its processes",
+        tracked.marker, tracked.container_id, limit
+    );
+    if let Err(e) = backend
+        .signal_exec_processes(&tracked.container_id, &tracked.marker, "KILL")
+        .await
+    {
+        warn!("Failed to kill exec {}: {:#}", tracked.marker, e);
+    }
+    metrics::inc(
+        "agentman_exec_timeouts_total",
+        "Exec requests killed for exceeding their time limit.",
+        &[],
+    );
+
+    let message = format!("agentman: command timed out after {limit}s and was killed\n");
+    if tty {
+        let _ = handle
+            .data(channel_id, CryptoVec::from_slice(message.replace('\n', "\r\n").as_bytes()))
+            .await;
+    } else {
+        let _ = handle
+            .extended_data(channel_id, 1, CryptoVec::from_slice(message.as_bytes()))
+            .await;
+    }
+    EXEC_TIMEOUT_EXIT_STATUS
+}
+
+/// Tell the client how many sandboxes are ahead of `workspace`'s in the creation queue,
+/// whenever that changes, until aborted.
+async fn report_queue_position(
+    handle: russh::server::Handle,
+    channel_id: ChannelId,
+    tty: bool,
+    workspace: String,
+    lang: Lang,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    let mut last = None;
+    loop {
+        interval.tick().await;
+        let position = admission::position(&workspace);
+        if let Some(ahead) = position
+            && position != last
+        {
+            let msg = match ahead {
+                0 => i18n::Msg::QueueNextInLine,
+                ahead => i18n::Msg::QueueAhead { ahead },
+            };
+            let message = format!("agentman: {}\n", msg.render(lang));
+            let sent = if tty {
+                let message = message.replace('\n', "\r\n");
+                handle
+                    .data(channel_id, CryptoVec::from_slice(message.as_bytes()))
+                    .await
+            } else {
+                handle
+                    .extended_data(channel_id, 1, CryptoVec::from_slice(message.as_bytes()))
+                    .await
+            };
+            if sent.is_err() {
+                return;
+            }
+        }
+        last = position;
+    }
+}
+
+/// `started` (a shell's or exec's audit event) as recorded when the command ends.
+fn command_ended(
+    started: AuditEvent,
+    exit_status: u32,
+    duration: Duration,
+    timed_out: bool,
+) -> AuditEvent {
+    let mut event = started
+        .field("exit_status", exit_status.to_string())
+        .field("duration_secs", format!("{:.3}", duration.as_secs_f64()));
+    if timed_out {
+        event = event.field("timed_out", "true");
+    }
+    event.timestamp = Utc::now();
+    event
+}
+
 /// Check if a hostname refers to localhost.
+/// Where distributions install OpenSSH's `sftp-server`.
+const SFTP_SERVER_PATHS: &[&str] = &[
+    "/usr/lib/openssh/sftp-server",
+    "/usr/libexec/openssh/sftp-server",
+    "/usr/lib/ssh/sftp-server",
+    "/usr/libexec/sftp-server",
+    "/usr/lib/sftp-server",
+];
+
+/// The exec behind the `sftp` subsystem: the first `sftp-server` found, starting in
+/// `/workspace`. stdout carries the SFTP protocol, so only stderr may explain a failure.
+fn sftp_server_command(configured: Option<&str>) -> Vec<String> {
+    let mut cmd = vec![
+        "/bin/bash".to_string(),
+        "-c".to_string(),
+        "for s in \"$@\"; do [ -x \"$s\" ] && exec \"$s\" -d /workspace; done; \
+         echo 'agentman: sftp-server is not installed in this sandbox' >&2; exit 127"
+            .to_string(),
+        "sftp".to_string(),
+    ];
+    match configured {
+        Some(path) => cmd.push(path.to_string()),
+        None => cmd.extend(SFTP_SERVER_PATHS.iter().map(|path| path.to_string())),
+    }
+    cmd
+}
+
+/// Whether `host` is a DNS name or IP address a forward may connect to.
+fn is_forward_destination(host: &str) -> bool {
+    if host.parse::<std::net::IpAddr>().is_ok() {
+        return true;
+    }
+    let host = host.strip_suffix('.').unwrap_or(host);
+    !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        })
+}
+
 fn is_localhost(host: &str) -> bool {
     host == "localhost"
         || host == "127.0.0.1"
@@ -1450,6 +4051,432 @@ fn is_localhost(host: &str) -> bool {
         || host == "0.0.0.0"
 }
 
+/// Bind the listener for a remote forward.
+///
+/// With a reservation, `port == 0` takes the first free port of the workspace's block, and a
+/// bind failure inside the block is reported as a conflict with another host service.
+async fn bind_remote_forward(
+    bind_addr: &str,
+    port: u32,
+    reservation: Option<PortReservation>,
+) -> Result<TcpListener> {
+    let Some(block) = reservation else {
+        return Ok(TcpListener::bind(format!("{bind_addr}:{port}")).await?);
+    };
+
+    if port != 0 {
+        return TcpListener::bind(format!("{bind_addr}:{port}"))
+            .await
+            .with_context(|| {
+                format!("port {port} of reserved block {block} is held by another host process")
+            });
+    }
+
+    for candidate in block.ports() {
+        if let Ok(listener) = TcpListener::bind(format!("{bind_addr}:{candidate}")).await {
+            return Ok(listener);
+        }
+    }
+    Err(anyhow!("every port in reserved block {block} is already in use"))
+}
+
+/// Relay a TCP connection to `host:port` inside the container through a bridge exec.
+/// `prefix` is sent first: bytes already read from the connection (e.g. a sniffed TLS
+/// ClientHello).
+pub(crate) async fn bridge_into_container<B: ContainerBackend>(
+    backend: &B,
+    container_id: &str,
+    host: &str,
+    port: u16,
+    stream: tokio::net::TcpStream,
+    prefix: &[u8],
+) -> Result<()> {
+    let cmd = tcp_bridge::command(host, port as u32);
+    let exec_id = backend.create_exec(container_id, cmd, false, None).await?;
+    let StartExecResults::Attached {
+        mut output,
+        mut input,
+    } = backend.start_exec(&exec_id, false).await?
+    else {
+        bail!("bridge exec {exec_id} started detached");
+    };
+    let (mut read_half, mut write_half) = stream.into_split();
+    let upload = async {
+        if input.write_all(prefix).await.is_ok() {
+            let _ = tokio::io::copy(&mut read_half, &mut input).await;
+        }
+        let _ = input.shutdown().await;
+    };
+    let download = async {
+        while let Some(Ok(chunk)) = output.next().await {
+            match chunk {
+                LogOutput::StdOut { message } | LogOutput::Console { message } => {
+                    if write_half.write_all(&message).await.is_err() {
+                        break;
+                    }
+                }
+                LogOutput::StdErr { message } => {
+                    debug!("Bridge stderr: {}", String::from_utf8_lossy(&message));
+                }
+                LogOutput::StdIn { .. } => {}
+            }
+        }
+        let _ = write_half.shutdown().await;
+    };
+    tokio::join!(upload, download);
+    Ok(())
+}
+
+/// Close a channel whose client stopped reading (`[slow_clients] close_after_secs`), hang up
+/// its command and, with `notify`, tell the user.
+async fn close_stalled_channel<B: ContainerBackend>(
+    server: &ServerState<B>,
+    handle: &russh::server::Handle,
+    channel_id: ChannelId,
+    tracked: Option<&TrackedExec>,
+    (github_user, project): (&str, &str),
+    stalled: slow_clients::Stalled,
+) {
+    warn!(
+        "Closing channel {} of {}/{}: the client read no output for {}s",
+        channel_id, github_user, project, stalled.secs
+    );
+    metrics::inc(
+        "agentman_ssh_stalled_channels_closed_total",
+        "Channels closed after their client read no output for [slow_clients] close_after_secs.",
+        &[],
+    );
+    if let Some(tracked) = tracked
+        && let Err(e) = server
+            .container_manager
+            .signal_exec_processes(&tracked.container_id, &tracked.marker, "HUP")
+            .await
+    {
+        warn!("Failed to hang up exec {}: {:#}", tracked.marker, e);
+    }
+    let _ = handle.close(channel_id).await;
+
+    let config = &server.config;
+    if config.slow_clients.notify
+        && config.user_notify.enabled
+        && server.state.notifier(github_user).await.is_some()
+    {
+        let text = format!(
+            "closed a session of {project}: your client read none of its output for {}s",
+            stalled.secs
+        );
+        if let Err(e) = user_notify::send(config, &server.state, github_user, project, &text).await {
+            warn!("Failed to notify {} of a stalled channel: {:#}", github_user, e);
+        }
+    }
+}
+
+/// Send a deferred control command's output and exit status, then close its channel.
+async fn finish_control_channel(
+    handle: &russh::server::Handle,
+    channel_id: ChannelId,
+    has_pty: bool,
+    exit_status: u32,
+    output: String,
+) {
+    // Use CRLF when PTY is allocated (ssh -t) for proper line display.
+    let data = if has_pty {
+        output.replace('\n', "\r\n")
+    } else {
+        output
+    };
+    let _ = handle
+        .data(channel_id, CryptoVec::from_slice(data.as_bytes()))
+        .await;
+    let _ = handle.exit_status_request(channel_id, exit_status).await;
+    let _ = handle.eof(channel_id).await;
+    let _ = handle.close(channel_id).await;
+}
+
+/// Stream a detached job's output to the channel until the job exits (or the client cancels);
+/// returns the exit status and any final message for the channel.
+async fn follow_job(
+    handle: &russh::server::Handle,
+    channel_id: ChannelId,
+    has_pty: bool,
+    workspace: &Path,
+    id: &str,
+    cancelled: &AtomicBool,
+) -> (u32, String) {
+    let mut offset = 0;
+    loop {
+        let (chunk, exit_code) = match jobs::read_output(workspace, id, offset).await {
+            Ok(read) => read,
+            Err(e) => return (1, format!("agentman: failed to read job {id}: {e:#}\n")),
+        };
+        if !chunk.is_empty() {
+            offset += chunk.len() as u64;
+            // Use CRLF when PTY is allocated (ssh -t) for proper line display.
+            let data = if has_pty {
+                String::from_utf8_lossy(&chunk)
+                    .replace('\n', "\r\n")
+                    .into_bytes()
+            } else {
+                chunk
+            };
+            if handle
+                .data(channel_id, CryptoVec::from_slice(&data))
+                .await
+                .is_err()
+            {
+                return (1, String::new());
+            }
+            continue;
+        }
+        if let Some(code) = exit_code {
+            return (u32::try_from(code).unwrap_or(1), String::new());
+        }
+        if cancelled.load(Ordering::Relaxed) {
+            // Ctrl-C stops following, not the job.
+            return (130, String::new());
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+}
+
+/// Send `output`, then what `follower` reads, until the client cancels or the file can't be
+/// followed anymore; returns the exit status and any final message for the channel.
+async fn follow_file(
+    handle: &russh::server::Handle,
+    channel_id: ChannelId,
+    has_pty: bool,
+    output: String,
+    mut follower: file_view::Follower,
+    cancelled: &AtomicBool,
+) -> (u32, String) {
+    let mut chunk = output.into_bytes();
+    loop {
+        if !chunk.is_empty() {
+            // Use CRLF when PTY is allocated (ssh -t) for proper line display.
+            let data = if has_pty {
+                String::from_utf8_lossy(&chunk)
+                    .replace('\n', "\r\n")
+                    .into_bytes()
+            } else {
+                chunk
+            };
+            if handle
+                .data(channel_id, CryptoVec::from_slice(&data))
+                .await
+                .is_err()
+            {
+                return (1, String::new());
+            }
+        }
+        if cancelled.load(Ordering::Relaxed) {
+            return (130, String::new());
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        chunk = match follower.read().await {
+            Ok(chunk) => chunk,
+            Err(e) => return (1, format!("agentman: {e:#}\n")),
+        };
+    }
+}
+
+/// Writes prompt text to a control channel.
+struct PromptOutput<'a> {
+    handle: &'a russh::server::Handle,
+    channel_id: ChannelId,
+    has_pty: bool,
+}
+
+impl PromptOutput<'_> {
+    async fn send(&mut self, text: &str) {
+        // Use CRLF when PTY is allocated (ssh -t) for proper line display.
+        let data = if self.has_pty {
+            text.replace('\n', "\r\n")
+        } else {
+            text.to_string()
+        };
+        let _ = self
+            .handle
+            .data(self.channel_id, CryptoVec::from_slice(data.as_bytes()))
+            .await;
+    }
+}
+
+enum PromptInput {
+    Line(String),
+    /// The client sent EOF (or Ctrl-D on an empty line); use defaults from here on.
+    Eof,
+    /// Ctrl-C, or the channel closed.
+    Interrupted,
+}
+
+/// Minimal line editing for prompts. With a PTY the client's terminal is in raw mode, so we
+/// echo input and handle backspace ourselves; without one, lines arrive already cooked.
+struct LineReader {
+    input: mpsc::Receiver<Vec<u8>>,
+    pending: std::collections::VecDeque<u8>,
+    echo: bool,
+    eof: bool,
+    /// The previous byte ended a line with `\r` (so a following `\n` is part of it).
+    after_cr: bool,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl LineReader {
+    async fn read_line(&mut self, out: &mut PromptOutput<'_>) -> PromptInput {
+        let mut line: Vec<u8> = Vec::new();
+        loop {
+            let Some(b) = self.pending.pop_front() else {
+                if self.eof {
+                    return if line.is_empty() {
+                        PromptInput::Eof
+                    } else {
+                        PromptInput::Line(String::from_utf8_lossy(&line).into_owned())
+                    };
+                }
+                match self.input.recv().await {
+                    Some(chunk) => self.pending.extend(chunk),
+                    None if self.cancelled.load(Ordering::Relaxed) => {
+                        return PromptInput::Interrupted;
+                    }
+                    None => self.eof = true,
+                }
+                continue;
+            };
+            let after_cr = std::mem::replace(&mut self.after_cr, b == b'\r');
+            match b {
+                b'\n' if after_cr => {}
+                b'\r' | b'\n' => {
+                    if self.echo {
+                        out.send("\n").await;
+                    }
+                    return PromptInput::Line(String::from_utf8_lossy(&line).into_owned());
+                }
+                0x03 => return PromptInput::Interrupted,
+                0x04 if line.is_empty() => {
+                    self.eof = true;
+                    return PromptInput::Eof;
+                }
+                0x7f | 0x08 => {
+                    // Drop a whole UTF-8 character.
+                    while line.pop().is_some_and(|b| b & 0xc0 == 0x80) {}
+                    if self.echo {
+                        out.send("\x08 \x08").await;
+                    }
+                }
+                _ => {
+                    line.push(b);
+                    if self.echo {
+                        let _ = out
+                            .handle
+                            .data(out.channel_id, CryptoVec::from_slice(&[b]))
+                            .await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Clone, prompt and render for `agentman new`. Returns the exit status and final output.
+async fn run_template_prompts<B: ContainerBackend>(
+    backend: &B,
+    github_user: &str,
+    project: &str,
+    url: &str,
+    out: &mut PromptOutput<'_>,
+    reader: &mut LineReader,
+) -> (u32, String) {
+    out.send(&format!("agentman: fetching template {url} ...\n"))
+        .await;
+    let template = match backend.prepare_template(github_user, project, url).await {
+        Ok(template) => template,
+        Err(e) => return (1, format!("agentman new: {e:#}\n")),
+    };
+
+    let mut answers = Answers::new();
+    for variable in &template.spec.variables {
+        let default = template::default_for(variable, &answers);
+        let prompt = match variable {
+            template::Variable::Text { name, .. } => format!("{name} [{default}]: "),
+            template::Variable::Choice { name, options } => {
+                let mut prompt = format!("Select {name}:\n");
+                for (i, option) in options.iter().enumerate() {
+                    prompt.push_str(&format!("{} - {option}\n", i + 1));
+                }
+                let numbers: Vec<String> = (1..=options.len()).map(|i| i.to_string()).collect();
+                prompt.push_str(&format!("Choose from {} [1]: ", numbers.join(", ")));
+                prompt
+            }
+        };
+        let answer = loop {
+            out.send(&prompt).await;
+            let input = match reader.read_line(out).await {
+                PromptInput::Line(line) => line,
+                PromptInput::Eof => {
+                    out.send("\n").await;
+                    break default.clone();
+                }
+                PromptInput::Interrupted => {
+                    backend.discard_template(&template).await;
+                    return (
+                        130,
+                        format!(
+                            "\nagentman new: cancelled; {project} was created but nothing was rendered into it\n"
+                        ),
+                    );
+                }
+            };
+            match template::resolve_answer(variable, &input, &answers) {
+                Ok(answer) => break answer,
+                Err(msg) => out.send(&format!("{msg}\n")).await,
+            }
+        };
+        answers.push((variable.name().to_string(), answer));
+    }
+
+    match backend
+        .render_template(github_user, project, template, answers)
+        .await
+    {
+        Ok(summary) => (0, summary),
+        Err(e) => (1, format!("agentman new: {e:#}\n")),
+    }
+}
+
+/// End a connection that didn't authenticate within `[ssh] handshake_timeout_secs`.
+async fn close_unauthenticated(
+    handle: russh::server::Handle,
+    peer_addr: SocketAddr,
+    timeout: Duration,
+) {
+    info!(
+        "Closing connection from {}: not authenticated within {}s",
+        peer_addr,
+        timeout.as_secs()
+    );
+    metrics::inc(
+        "agentman_ssh_handshake_timeouts_total",
+        "Connections closed for not authenticating within [ssh] handshake_timeout_secs.",
+        &[],
+    );
+    let _ = handle
+        .disconnect(
+            russh::Disconnect::ByApplication,
+            "authentication timed out".to_string(),
+            String::new(),
+        )
+        .await;
+}
+
+/// Methods to offer after a rejection; keyboard-interactive is withdrawn under attack.
+fn auth_methods(restrict: bool) -> MethodSet {
+    if restrict {
+        MethodSet::from(&[MethodKind::PublicKey][..])
+    } else {
+        MethodSet::from(&[MethodKind::PublicKey, MethodKind::KeyboardInteractive][..])
+    }
+}
+
 fn sanitize_tmux_session_name(name: &str) -> String {
     let mut out = String::with_capacity(name.len());
     for c in name.chars() {
@@ -1472,22 +4499,20 @@ pub async fn run_server(
     state: Arc<StateManager>,
     container_manager: Arc<ContainerManager>,
     github_fetcher: Arc<GitHubKeyFetcher>,
+    audit: Arc<AuditLogger>,
 ) -> Result<()> {
     // Load or generate host key
-    let key = load_or_generate_host_key(&config.host_key_path).await?;
+    let key = load_host_key(&config).await?;
 
-    let russh_config = Arc::new(russh::server::Config {
-        auth_rejection_time: Duration::from_secs(1),
-        auth_rejection_time_initial: Some(Duration::from_secs(0)),
-        keys: vec![key],
-        ..Default::default()
-    });
+    let russh_config = Arc::new(server_config(&config, key.clone()));
 
     let server_state = Arc::new(ServerState {
         config: config.clone(),
         state,
         container_manager,
         github_fetcher,
+        audit,
+        auth_guard: AuthGuard::new(config.auth_guard.clone()),
     });
 
     let addr: SocketAddr = config
@@ -1500,27 +4525,124 @@ pub async fn run_server(
     let listener = tokio::net::TcpListener::bind(addr).await?;
     info!("SSH server listening on {}", listener.local_addr()?);
 
+    // Register with the relay (no-op unless [relay] address is set)
+    relay::spawn(&config.relay, listener.local_addr()?, &key)?;
+    // Outgoing migrations log in to other gateways with the host key.
+    migration::init(&key);
+
+    serve(listener, russh_config, server_state).await
+}
+
+/// russh settings from `[ssh]` and `[compression]`.
+fn server_config(config: &GatewayConfig, key: russh::keys::PrivateKey) -> russh::server::Config {
+    let ssh = &config.ssh;
+    let rekey_limit = ssh.rekey_limit_mb * 1024 * 1024;
+    russh::server::Config {
+        auth_rejection_time: Duration::from_secs(1),
+        auth_rejection_time_initial: Some(Duration::from_secs(0)),
+        keys: vec![key],
+        // Only what the handler implements; passwords are never accepted.
+        methods: auth_methods(false),
+        preferred: compression::preferred(&config.compression),
+        max_auth_attempts: ssh.max_auth_attempts,
+        limits: russh::Limits::new(
+            rekey_limit,
+            rekey_limit,
+            Duration::from_secs(ssh.rekey_interval_secs),
+        ),
+        window_size: ssh.window_size,
+        maximum_packet_size: ssh.maximum_packet_size,
+        ..Default::default()
+    }
+}
+
+/// Accept SSH connections on `listener` until it fails.
+async fn serve<B: ContainerBackend>(
+    listener: TcpListener,
+    russh_config: Arc<russh::server::Config>,
+    server_state: Arc<ServerState<B>>,
+) -> Result<()> {
     loop {
         let (stream, peer_addr) = listener.accept().await?;
         let server_state_clone = server_state.clone();
         let russh_config_clone = russh_config.clone();
 
         tokio::spawn(async move {
+            let compression_config = server_state_clone.config.compression.clone();
+            let handshake_timeout =
+                Duration::from_secs(server_state_clone.config.ssh.handshake_timeout_secs);
+            let connected_at = std::time::Instant::now();
             let handler = ConnectionHandler::new(server_state_clone, peer_addr);
+            let traffic = handler.traffic.clone();
+            let authenticated = handler.authenticated.clone();
+            let stream =
+                compression::CountingStream::new(stream, traffic.clone(), &compression_config);
             match russh::server::run_stream(russh_config_clone, stream, handler).await {
                 Ok(session) => {
+                    let timeout = (!handshake_timeout.is_zero()).then(|| {
+                        let handle = session.handle();
+                        tokio::spawn(async move {
+                            tokio::time::sleep(handshake_timeout).await;
+                            if !authenticated.load(Ordering::Relaxed) {
+                                close_unauthenticated(handle, peer_addr, handshake_timeout).await;
+                            }
+                        })
+                    });
                     if let Err(e) = session.await {
                         warn!("SSH session error: {}", e);
                     }
+                    if let Some(timeout) = timeout {
+                        timeout.abort();
+                    }
                 }
                 Err(e) => {
                     warn!("SSH connection error: {}", e);
                 }
             }
+            traffic.record(&compression_config);
+            slow_clients::record_connection(&traffic, connected_at.elapsed());
         });
     }
 }
 
+/// Time allowed for `host_key_command` (a KMS or secret manager round trip).
+const HOST_KEY_COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Load the host key from the configured backend.
+async fn load_host_key(config: &GatewayConfig) -> Result<russh::keys::PrivateKey> {
+    match config.host_key_backend {
+        HostKeyBackend::File => load_or_generate_host_key(&config.host_key_path).await,
+        HostKeyBackend::Command => load_host_key_from_command(&config.host_key_command).await,
+    }
+}
+
+/// Run `host_key_command` and parse the OpenSSH private key it prints. The key is never
+/// written to disk.
+async fn load_host_key_from_command(argv: &[String]) -> Result<russh::keys::PrivateKey> {
+    let (program, args) = argv
+        .split_first()
+        .ok_or_else(|| anyhow!("host_key_command is empty"))?;
+    info!("Loading host key from command {}", program);
+
+    let output = tokio::process::Command::new(program)
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(HOST_KEY_COMMAND_TIMEOUT, output)
+        .await
+        .map_err(|_| anyhow!("host_key_command timed out after {}s", HOST_KEY_COMMAND_TIMEOUT.as_secs()))?
+        .with_context(|| format!("Failed to run host_key_command {program}"))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("host_key_command failed ({}): {}", output.status, stderr.trim());
+    }
+
+    let secret = String::from_utf8(output.stdout).context("host_key_command printed non-UTF-8 output")?;
+    russh::keys::decode_secret_key(secret.trim(), None)
+        .context("host_key_command did not print an unencrypted OpenSSH private key")
+}
+
 /// Load host key from file or generate a new one.
 async fn load_or_generate_host_key(path: &std::path::Path) -> Result<russh::keys::PrivateKey> {
     use russh::keys::ssh_key::{Algorithm, LineEnding};
@@ -1558,3 +4680,1419 @@ async fn load_or_generate_host_key(path: &std::path::Path) -> Result<russh::keys
         Ok(key)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::mock::MockBackend;
+    use crate::config::LoggingConfig;
+    use crate::fake_github::FakeGitHub;
+    use crate::state::OffboardingRecord;
+    use russh::client;
+    use russh::keys::ssh_key::rand_core::OsRng;
+    use russh::keys::ssh_key::Algorithm;
+    use russh::keys::{PrivateKey, PrivateKeyWithHashAlg};
+    use tokio::io::AsyncReadExt;
+
+    static NEXT_HARNESS_ID: AtomicU64 = AtomicU64::new(1);
+
+    #[tokio::test]
+    async fn test_load_host_key_from_command() {
+        use russh::keys::ssh_key::LineEnding;
+
+        let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let pem = key.to_openssh(LineEnding::LF).unwrap().to_string();
+        let config = GatewayConfig {
+            host_key_backend: HostKeyBackend::Command,
+            host_key_command: vec!["printf".to_string(), "%s".to_string(), pem],
+            host_key_path: PathBuf::from("/nonexistent/host_key"),
+            ..Default::default()
+        };
+        let loaded = load_host_key(&config).await.unwrap();
+        assert_eq!(loaded.public_key(), key.public_key());
+        assert!(!config.host_key_path.exists());
+
+        let err = load_host_key_from_command(&["sh".to_string(), "-c".to_string(), "echo denied >&2; exit 3".to_string()])
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("denied"));
+        assert!(load_host_key_from_command(&["echo".to_string(), "not a key".to_string()]).await.is_err());
+    }
+
+    /// A gateway SSH server on a loopback port, backed by [`MockBackend`].
+    struct Harness {
+        addr: SocketAddr,
+        backend: Arc<MockBackend>,
+        state: Arc<StateManager>,
+        dir: PathBuf,
+    }
+
+    impl Drop for Harness {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    impl Harness {
+        async fn start() -> Self {
+            Self::start_with(|_| {}).await
+        }
+
+        async fn start_with(configure: impl FnOnce(&mut GatewayConfig)) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "agentman-ssh-test-{}-{}",
+                std::process::id(),
+                NEXT_HARNESS_ID.fetch_add(1, Ordering::Relaxed)
+            ));
+            let mut config = GatewayConfig {
+                workspace_root: dir.join("workspaces"),
+                state_file: dir.join("state.json"),
+                host_key_path: dir.join("host_key"),
+                ..Default::default()
+            };
+            configure(&mut config);
+            let config = Arc::new(config);
+
+            let state = Arc::new(StateManager::load(config.state_file.clone()).await.unwrap());
+            let backend = Arc::new(MockBackend::new(state.clone()));
+            let server_state = Arc::new(ServerState {
+                config: config.clone(),
+                state: state.clone(),
+                container_manager: backend.clone(),
+                github_fetcher: Arc::new(GitHubKeyFetcher::new(&config.github)),
+                audit: Arc::new(AuditLogger::start(&LoggingConfig::default()).await.unwrap()),
+                auth_guard: AuthGuard::new(config.auth_guard.clone()),
+            });
+            let russh_config = Arc::new(russh::server::Config {
+                auth_rejection_time: Duration::from_millis(10),
+                ..server_config(
+                    &config,
+                    PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap(),
+                )
+            });
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(serve(listener, russh_config, server_state));
+
+            Self {
+                addr,
+                backend,
+                state,
+                dir,
+            }
+        }
+
+        /// A fresh client key already cached for `github_user` (no GitHub lookup needed).
+        async fn known_key(&self, github_user: &str) -> PrivateKey {
+            let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+            let fingerprint = compute_fingerprint_from_pubkey(key.public_key());
+            let entry = KeyCacheEntry {
+                github_username: github_user.to_string(),
+                verified_at: Utc::now(),
+                key_type: "ssh-ed25519".to_string(),
+            };
+            self.state.cache_key(fingerprint, entry).await.unwrap();
+            key
+        }
+
+        /// Connect and authenticate; returns `None` if authentication was rejected.
+        async fn connect(&self, user: &str, key: PrivateKey) -> Option<client::Handle<TestClient>> {
+            self.connect_with(client::Config::default(), user, key).await
+        }
+
+        async fn connect_with(
+            &self,
+            config: client::Config,
+            user: &str,
+            key: PrivateKey,
+        ) -> Option<client::Handle<TestClient>> {
+            let config = Arc::new(config);
+            let mut handle = client::connect(config, self.addr, TestClient).await.unwrap();
+            let auth = handle
+                .authenticate_publickey(user, PrivateKeyWithHashAlg::new(Arc::new(key), None))
+                .await
+                .unwrap();
+            auth.success().then_some(handle)
+        }
+    }
+
+    struct TestClient;
+
+    impl client::Handler for TestClient {
+        type Error = anyhow::Error;
+
+        async fn check_server_key(&mut self, _key: &PublicKey) -> Result<bool, Self::Error> {
+            Ok(true)
+        }
+
+        /// Remote forwards reach an echo service that says `bye` at EOF.
+        async fn server_channel_open_forwarded_tcpip(
+            &mut self,
+            channel: Channel<client::Msg>,
+            _connected_address: &str,
+            _connected_port: u32,
+            _originator_address: &str,
+            _originator_port: u32,
+            _session: &mut client::Session,
+        ) -> Result<(), Self::Error> {
+            tokio::spawn(echo(channel));
+            Ok(())
+        }
+
+        /// Remote socket forwards reach the same echo service.
+        async fn server_channel_open_forwarded_streamlocal(
+            &mut self,
+            channel: Channel<client::Msg>,
+            _socket_path: &str,
+            _session: &mut client::Session,
+        ) -> Result<(), Self::Error> {
+            tokio::spawn(echo(channel));
+            Ok(())
+        }
+    }
+
+    async fn echo(mut channel: Channel<client::Msg>) {
+        while let Some(msg) = channel.wait().await {
+            match msg {
+                ChannelMsg::Data { data } => channel.data(&data[..]).await.unwrap(),
+                ChannelMsg::Eof => {
+                    channel.data(&b"bye"[..]).await.unwrap();
+                    channel.eof().await.unwrap();
+                }
+                _ => {}
+            }
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct ExecResult {
+        stdout: String,
+        stderr: String,
+        exit_status: Option<u32>,
+    }
+
+    /// Collect a channel's output until it closes.
+    async fn collect(mut channel: Channel<client::Msg>) -> ExecResult {
+        let mut result = ExecResult::default();
+        while let Some(msg) = tokio::time::timeout(Duration::from_secs(5), channel.wait())
+            .await
+            .expect("channel timed out")
+        {
+            match msg {
+                ChannelMsg::Data { data } => result.stdout.push_str(&String::from_utf8_lossy(&data)),
+                ChannelMsg::ExtendedData { data, ext: 1 } => {
+                    result.stderr.push_str(&String::from_utf8_lossy(&data))
+                }
+                ChannelMsg::ExitStatus { exit_status } => result.exit_status = Some(exit_status),
+                _ => {}
+            }
+        }
+        result
+    }
+
+    async fn exec(handle: &client::Handle<TestClient>, command: &str) -> ExecResult {
+        let channel = handle.channel_open_session().await.unwrap();
+        channel.exec(true, command).await.unwrap();
+        collect(channel).await
+    }
+
+    #[tokio::test]
+    async fn test_login_refused_while_offboarded_by_admin() {
+        let harness = Harness::start().await;
+        let key = harness.known_key("octocat").await;
+        let record = |requested_by: Option<&str>| OffboardingRecord {
+            flagged_at: Utc::now(),
+            requested_by: requested_by.map(str::to_string),
+            archived_at: None,
+            keys_revoked_at: None,
+            destroyed_at: None,
+        };
+
+        // An inactivity flag is lifted by logging in.
+        harness.state.set_offboarding("octocat", record(None)).await.unwrap();
+        assert!(harness.connect("api", key.clone()).await.is_some());
+        assert!(harness.state.offboarding("octocat").await.is_none());
+        assert!(harness.state.last_login("octocat").await.is_some());
+
+        harness
+            .state
+            .set_offboarding("octocat", record(Some("admin")))
+            .await
+            .unwrap();
+        assert!(harness.connect("api", key).await.is_none());
+        assert!(harness.state.offboarding("octocat").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_login_verified_against_fake_github() {
+        let keys = std::env::temp_dir().join(format!(
+            "agentman-ssh-fake-github-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&keys).unwrap();
+        let github = FakeGitHub::start("127.0.0.1:0", keys.clone()).await.unwrap();
+        let harness = Harness::start_with(|c| c.github = github.config()).await;
+        harness.backend.script("make test", "ok\n", "", 0);
+
+        // Unknown to the gateway and not on (fake) GitHub yet.
+        let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        assert!(harness.connect("api+octocat", key.clone()).await.is_none());
+
+        let published = public_key_to_openssh(key.public_key());
+        std::fs::write(keys.join("octocat.keys"), format!("{published}\n")).unwrap();
+        let handle = harness.connect("api+octocat", key.clone()).await.expect("key verified");
+        let result = exec(&handle, "make test").await;
+        assert_eq!(result.stdout, "ok\n");
+        assert!(harness.backend.container("octocat", "api").is_some());
+
+        // The key is cached now, so the plain project login works without GitHub.
+        drop(github);
+        assert!(harness.connect("api", key).await.is_some());
+        std::fs::remove_dir_all(&keys).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_org_membership_gate() {
+        let dir = std::env::temp_dir().join(format!("agentman-ssh-orgs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("token"), "ghp_test\n").unwrap();
+        std::fs::write(dir.join("org-gate-member.orgs"), "acme\n").unwrap();
+        let github = FakeGitHub::start("127.0.0.1:0", dir.clone()).await.unwrap();
+        let harness = Harness::start_with(|c| {
+            c.github = github.config();
+            c.allowed_github_orgs = vec!["acme".to_string()];
+            c.github_org_token_file = Some(dir.join("token"));
+        })
+        .await;
+        harness.backend.script("true", "", "", 0);
+
+        let key = harness.known_key("org-gate-member").await;
+        let handle = harness.connect("api", key).await.unwrap();
+        assert_eq!(exec(&handle, "true").await.exit_status, Some(0));
+
+        // Outsiders are told why, and get nothing else.
+        let key = harness.known_key("org-gate-outsider").await;
+        let handle = harness.connect("api", key).await.unwrap();
+        let result = exec(&handle, "agentman status").await;
+        assert_eq!(result.exit_status, Some(1));
+        assert!(
+            result.stdout.contains("org-gate-outsider is not a member of acme on GitHub"),
+            "{}",
+            result.stdout
+        );
+        assert!(handle
+            .channel_open_direct_tcpip("localhost", 8080, "127.0.0.1", 50000)
+            .await
+            .is_err());
+        assert!(harness.backend.container("org-gate-outsider", "api").is_none());
+        assert_eq!(harness.backend.execs().len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_user_overrides_apply_at_login() {
+        let dir = std::env::temp_dir().join(format!("agentman-ssh-users-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let harness = Harness::start_with(|c| c.users_dir = Some(dir.clone())).await;
+        let key = harness.known_key("octocat").await;
+        // Read at login, so files written after the gateway started count.
+        std::fs::write(
+            dir.join("octocat.toml"),
+            "allowed_projects = [\"api\"]\n\n[port_forwarding]\nallow_remote = false\n",
+        )
+        .unwrap();
+
+        let handle = harness.connect("api", key.clone()).await.unwrap();
+        let result = exec(&handle, "agentman route").await;
+        assert!(result.stdout.contains("Remote forwards (ssh -R):  not allowed\n"));
+
+        let handle = harness.connect("web", key.clone()).await.unwrap();
+        let result = exec(&handle, "agentman status").await;
+        assert_eq!(result.exit_status, Some(1));
+        assert!(
+            result.stdout.contains("octocat may only use the projects api, not web"),
+            "{}",
+            result.stdout
+        );
+        assert!(harness.backend.container("octocat", "web").is_none());
+
+        // A broken file refuses the user rather than falling back to the global settings.
+        std::fs::write(dir.join("octocat.toml"), "allowed_projects = \"api\"\n").unwrap();
+        let handle = harness.connect("api", key).await.unwrap();
+        let result = exec(&handle, "agentman route").await;
+        assert_eq!(result.exit_status, Some(1));
+        assert!(result.stdout.contains("settings for octocat are invalid"), "{}", result.stdout);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_exec_happy_path() {
+        let harness = Harness::start().await;
+        harness.backend.script("make test", "ok\n", "warning: slow\n", 3);
+
+        let key = harness.known_key("octocat").await;
+        let handle = harness.connect("api", key).await.expect("cached key accepted");
+
+        let result = exec(&handle, "make test").await;
+        assert_eq!(result.stdout, "ok\n");
+        assert_eq!(result.stderr, "warning: slow\n");
+        assert_eq!(result.exit_status, Some(3));
+
+        let container = harness.backend.container("octocat", "api").expect("container provisioned");
+        let execs = harness.backend.execs();
+        assert_eq!(execs.len(), 1);
+        assert_eq!(execs[0].container_id, container);
+        assert_eq!(execs[0].cmd, vec!["/bin/bash", "-c", "make test"]);
+        assert!(!execs[0].tty);
+        assert!(execs[0].env.contains(&"HOME=/workspace".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_sftp_subsystem() {
+        let harness =
+            Harness::start_with(|c| c.ssh.sftp_server = Some("/opt/sftp-server".into())).await;
+        let key = harness.known_key("octocat").await;
+        let handle = harness.connect("api", key).await.unwrap();
+
+        // Unscripted commands echo stdin, standing in for sftp-server's replies.
+        let channel = handle.channel_open_session().await.unwrap();
+        channel.request_subsystem(true, "sftp").await.unwrap();
+        channel.data(&b"\0\0\0\x05\x01\0\0\0\x03"[..]).await.unwrap();
+        channel.eof().await.unwrap();
+        let result = collect(channel).await;
+        assert_eq!(result.stdout.as_bytes(), b"\0\0\0\x05\x01\0\0\0\x03");
+
+        let execs = harness.backend.execs();
+        assert_eq!(execs.len(), 1);
+        assert_eq!(&execs[0].cmd[3..], ["sftp", "/opt/sftp-server"]);
+        assert!(execs[0].cmd[2].contains("-d /workspace"));
+        assert!(execs[0].env.contains(&"HOME=/workspace".to_string()));
+
+        // Other subsystems are refused without starting anything.
+        let mut channel = handle.channel_open_session().await.unwrap();
+        channel.request_subsystem(true, "netconf").await.unwrap();
+        assert!(matches!(channel.wait().await, Some(ChannelMsg::Failure)));
+        assert_eq!(harness.backend.execs().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_run_timeout_kills_exec() {
+        let harness = Harness::start().await;
+        let key = harness.known_key("octocat").await;
+        let handle = harness.connect("api", key).await.unwrap();
+
+        // Unscripted commands echo stdin until EOF, i.e. they never finish on their own.
+        let channel = handle.channel_open_session().await.unwrap();
+        channel.exec(true, "agentman run --timeout 1 -- sleep 600").await.unwrap();
+        let result = collect(channel).await;
+        assert_eq!(result.exit_status, Some(EXEC_TIMEOUT_EXIT_STATUS));
+        assert!(result.stderr.contains("timed out after 1s"));
+
+        let execs = harness.backend.execs();
+        assert_eq!(execs[0].cmd, vec!["/bin/bash", "-c", "sleep 600"]);
+        let marker = execs[0]
+            .env
+            .iter()
+            .find_map(|e| e.strip_prefix(&format!("{EXEC_MARKER_ENV}=")))
+            .expect("exec is tagged")
+            .to_string();
+        assert_eq!(
+            harness.backend.signals(),
+            vec![(execs[0].container_id.clone(), marker, "KILL".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_exec_duration_caps_run_timeout() {
+        let harness = Harness::start_with(|c| c.exec.max_duration_secs = Some(1)).await;
+        let key = harness.known_key("octocat").await;
+        let handle = harness.connect("api", key).await.unwrap();
+
+        let result = exec(&handle, "agentman run --timeout 300 -- sleep 600").await;
+        assert_eq!(result.exit_status, Some(EXEC_TIMEOUT_EXIT_STATUS));
+        assert_eq!(harness.backend.signals().len(), 1);
+    }
+
+    fn template_spec() -> template::TemplateSpec {
+        template::parse_spec(
+            r#"{"project_name": "Demo", "slug": "{{ cookiecutter.project_name.lower() }}", "license": ["MIT", "BSD"]}"#,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_new_from_template_prompts_for_variables() {
+        let harness = Harness::start().await;
+        harness.backend.set_template(template_spec());
+        let key = harness.known_key("octocat").await;
+        let handle = harness.connect("api", key).await.unwrap();
+
+        let channel = handle.channel_open_session().await.unwrap();
+        channel
+            .exec(true, "agentman new web --from https://github.com/org/template.git")
+            .await
+            .unwrap();
+        // Second line takes the (rendered) default; the first choice answer is out of range.
+        channel.data(&b"My App
+
+3
+2
+"[..]).await.unwrap();
+        channel.eof().await.unwrap();
+        let result = collect(channel).await;
+
+        assert_eq!(result.exit_status, Some(0), "{}", result.stdout);
+        assert!(result.stdout.contains("slug [my app]: "));
+        assert!(result.stdout.contains("choose a number from 1 to 2"));
+        assert_eq!(
+            harness.backend.rendered(),
+            vec![(
+                "web".to_string(),
+                vec![
+                    ("project_name".to_string(), "My App".to_string()),
+                    ("slug".to_string(), "my app".to_string()),
+                    ("license".to_string(), "BSD".to_string()),
+                ]
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_new_from_template_defaults_without_input_and_cancels_on_ctrl_c() {
+        let harness = Harness::start().await;
+        harness.backend.set_template(template_spec());
+        let key = harness.known_key("octocat").await;
+        let handle = harness.connect("api", key).await.unwrap();
+
+        let channel = handle.channel_open_session().await.unwrap();
+        channel
+            .exec(true, "agentman new web --from https://github.com/org/template.git")
+            .await
+            .unwrap();
+        channel.eof().await.unwrap();
+        assert_eq!(collect(channel).await.exit_status, Some(0));
+        assert_eq!(harness.backend.rendered()[0].1[1].1, "demo");
+
+        let channel = handle.channel_open_session().await.unwrap();
+        channel.request_pty(true, "xterm", 80, 24, 0, 0, &[]).await.unwrap();
+        channel
+            .exec(true, "agentman new cli --from https://github.com/org/template.git")
+            .await
+            .unwrap();
+        channel.data(&b"x\x7fCLI\r\x03"[..]).await.unwrap();
+        let result = collect(channel).await;
+        assert_eq!(result.exit_status, Some(130));
+        assert!(result.stdout.contains("x\x08 \x08CLI\r\n"));
+        assert_eq!(harness.backend.rendered().len(), 1);
+        assert_eq!(harness.backend.discarded(), 1);
+    }
+
+    /// Wait until the backend has recorded `n` signals.
+    async fn wait_for_signals(backend: &MockBackend, n: usize) -> Vec<(String, String, String)> {
+        for _ in 0..100 {
+            let signals = backend.signals();
+            if signals.len() >= n {
+                return signals;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        backend.signals()
+    }
+
+    #[tokio::test]
+    async fn test_env_file_set_injects_into_execs() {
+        let harness =
+            Harness::start_with(|c| c.env_file.dir = c.workspace_root.with_file_name("env")).await;
+        harness.backend.script("printenv", "", "", 0);
+        let key = harness.known_key("octocat").await;
+        let handle = harness.connect("api", key).await.unwrap();
+
+        let channel = handle.channel_open_session().await.unwrap();
+        channel.exec(true, "agentman env-file set").await.unwrap();
+        channel.data(&b"TOKEN='t0k3n'\nHOME=/root\n"[..]).await.unwrap();
+        channel.eof().await.unwrap();
+        let result = collect(channel).await;
+        assert_eq!(result.exit_status, Some(0), "{}", result.stdout);
+        assert!(result.stdout.contains("2 variable(s)"), "{}", result.stdout);
+        assert!(harness.dir.join("env/octocat/api.env").exists());
+
+        assert_eq!(exec(&handle, "printenv").await.exit_status, Some(0));
+        let execs = harness.backend.execs();
+        let env = &execs.last().unwrap().env;
+        assert!(env.contains(&"TOKEN=t0k3n".to_string()));
+        // The gateway's own variables win.
+        assert!(env.contains(&"HOME=/workspace".to_string()));
+        assert!(!env.contains(&"HOME=/root".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_hangs_up_running_exec() {
+        let harness = Harness::start_with(|c| c.exec.kill_grace_secs = 0).await;
+        let key = harness.known_key("octocat").await;
+        let handle = harness.connect("api", key).await.unwrap();
+
+        let channel = handle.channel_open_session().await.unwrap();
+        channel.exec(true, "sleep 600").await.unwrap();
+        channel.close().await.unwrap();
+
+        let signals: Vec<_> = wait_for_signals(&harness.backend, 2)
+            .await
+            .into_iter()
+            .map(|(_, _, signal)| signal)
+            .collect();
+        assert_eq!(signals, vec!["HUP", "KILL"]);
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_leaves_finished_exec_and_tmux_alone() {
+        let harness = Harness::start_with(|c| c.exec.kill_grace_secs = 0).await;
+        harness.backend.script("make", "done\n", "", 0);
+        let key = harness.known_key("octocat").await;
+        let handle = harness.connect("api", key).await.unwrap();
+
+        // Finished before the channel closed: background processes it left are not touched.
+        assert_eq!(exec(&handle, "make").await.exit_status, Some(0));
+
+        // The tmux session is meant to outlive the connection.
+        let channel = handle.channel_open_session().await.unwrap();
+        channel.request_pty(true, "xterm", 80, 24, 0, 0, &[]).await.unwrap();
+        channel.request_shell(true).await.unwrap();
+        drop(handle);
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(harness.backend.signals().is_empty());
+        let execs = harness.backend.execs();
+        assert!(!execs[1].env.iter().any(|e| e.starts_with(EXEC_MARKER_ENV)));
+    }
+
+    #[tokio::test]
+    async fn test_messages_in_chosen_language() {
+        let harness = Harness::start().await;
+        let key = harness.known_key("octocat").await;
+        let handle = harness.connect("tmp", key).await.unwrap();
+
+        let result = exec(&handle, "agentman status").await;
+        assert!(result.stdout.contains("not available in an ephemeral sandbox"), "{}", result.stdout);
+        harness.state.set_language("octocat", Some(Lang::Tr)).await.unwrap();
+        let result = exec(&handle, "agentman status").await;
+        assert_eq!(
+            result.stdout,
+            "agentman: kontrol komutları geçici bir sanal ortamda kullanılamaz\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ephemeral_sandbox_removed_on_disconnect() {
+        let harness = Harness::start().await;
+        harness.backend.script("make", "done\n", "", 0);
+        let key = harness.known_key("octocat").await;
+
+        let handle = harness.connect("tmp", key.clone()).await.unwrap();
+        assert_eq!(exec(&handle, "make").await.exit_status, Some(0));
+        assert_eq!(exec(&handle, "make").await.exit_status, Some(0));
+        let sandboxes = harness.backend.ephemeral();
+        assert_eq!(sandboxes.len(), 1);
+        assert!(harness.backend.execs().iter().all(|e| e.container_id == sandboxes[0]));
+        assert!(harness.backend.container("octocat", "tmp").is_none());
+        assert_eq!(exec(&handle, "agentman status").await.exit_status, Some(1));
+
+        // `agentman ephemeral` switches a fresh connection to a throwaway sandbox.
+        let other = harness.connect("api", key).await.unwrap();
+        let channel = other.channel_open_session().await.unwrap();
+        channel.exec(true, "agentman ephemeral").await.unwrap();
+        channel.eof().await.unwrap();
+        assert_eq!(collect(channel).await.exit_status, Some(0));
+        assert_eq!(harness.backend.ephemeral().len(), 2);
+        assert!(harness.backend.container("octocat", "api").is_none());
+
+        drop((handle, other));
+        for _ in 0..50 {
+            if harness.backend.ephemeral().is_empty() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("ephemeral sandboxes not removed: {:?}", harness.backend.ephemeral());
+    }
+
+    #[tokio::test]
+    async fn test_compressed_connection() {
+        let harness = Harness::start_with(|c| c.compression.threshold_bytes = 1024).await;
+        let log = "Compiling agentman v0.1.0 (/workspace)\n".repeat(2000);
+        harness.backend.script("cargo build", &log, "", 0);
+        let key = harness.known_key("octocat").await;
+
+        let config = client::Config {
+            preferred: russh::Preferred {
+                compression: std::borrow::Cow::Borrowed(&[russh::compression::ZLIB_LEGACY]),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let handle = harness.connect_with(config, "api", key).await.unwrap();
+        let result = exec(&handle, "cargo build").await;
+        assert_eq!(result.stdout, log);
+        drop(handle);
+
+        let recorded = "agentman_ssh_compression_ratio_count{algorithm=\"zlib@openssh.com\"} 1";
+        for _ in 0..50 {
+            if metrics::render().contains(recorded) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("compressed connection not recorded:\n{}", metrics::render());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_timeout_closes_unauthenticated_connections() {
+        let harness = Harness::start_with(|c| c.ssh.handshake_timeout_secs = 1).await;
+        harness.backend.script("true", "", "", 0);
+        let config = Arc::new(client::Config::default());
+        let idle = client::connect(config, harness.addr, TestClient).await.unwrap();
+        let key = harness.known_key("octocat").await;
+        let authenticated = harness.connect("api", key).await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(idle.is_closed());
+        assert!(!authenticated.is_closed());
+        assert_eq!(exec(&authenticated, "true").await.exit_status, Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_shell_with_pty() {
+        let harness = Harness::start().await;
+        let key = harness.known_key("octocat").await;
+        let handle = harness.connect("api", key).await.unwrap();
+
+        let channel = handle.channel_open_session().await.unwrap();
+        channel.request_pty(true, "xterm", 120, 40, 0, 0, &[]).await.unwrap();
+        channel.request_shell(true).await.unwrap();
+        channel.data(&b"echo hi\n"[..]).await.unwrap();
+        channel.eof().await.unwrap();
+
+        let result = collect(channel).await;
+        assert_eq!(result.stdout, "echo hi\n");
+        assert_eq!(result.exit_status, Some(0));
+
+        // Default shell mode attaches to tmux when a PTY is requested.
+        let execs = harness.backend.execs();
+        assert_eq!(execs[0].cmd[..2], ["/bin/bash", "-lc"]);
+        assert!(execs[0].cmd[2].contains("tmux new-session -A -s 'agentman'"));
+        assert!(execs[0].tty);
+        assert!(execs[0].env.contains(&"TERM=xterm".to_string()));
+        assert_eq!(execs[0].resized_to, Some((120, 40)));
+    }
+
+    #[tokio::test]
+    async fn test_escape_sequences() {
+        let harness = Harness::start().await;
+        let key = harness.known_key("octocat").await;
+        let handle = harness.connect("api", key).await.unwrap();
+
+        let channel = handle.channel_open_session().await.unwrap();
+        channel.request_pty(true, "xterm", 80, 24, 0, 0, &[]).await.unwrap();
+        channel.request_shell(true).await.unwrap();
+        channel.data(&b"ls ~.\n~?~~x\n~C-l\r"[..]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        channel.data(&b"~."[..]).await.unwrap();
+
+        let result = collect(channel).await;
+        assert!(result.stdout.contains("ls ~.\n"), "{}", result.stdout);
+        assert!(result.stdout.contains("~x\n"), "{}", result.stdout);
+        assert!(result.stdout.contains("Supported gateway escape sequences"));
+        assert!(result.stdout.contains("agentman> -l\r\nagentman: no gateway forwards"));
+        assert!(result.stdout.contains("agentman: session closed"));
+        assert_eq!(result.exit_status, Some(255));
+    }
+
+    #[tokio::test]
+    async fn test_notices_shown_once_in_terminal_sessions() {
+        let harness = Harness::start().await;
+        harness
+            .state
+            .add_notice("octocat", "hubot transferred project web to you".to_string())
+            .await
+            .unwrap();
+        let key = harness.known_key("octocat").await;
+        let handle = harness.connect("api", key).await.unwrap();
+
+        // Non-PTY sessions (editors) leave notices queued.
+        harness.backend.script("true", "", "", 0);
+        let result = exec(&handle, "true").await;
+        assert!(!result.stdout.contains("transferred"));
+
+        for expected in [true, false] {
+            let channel = handle.channel_open_session().await.unwrap();
+            channel.request_pty(true, "xterm", 80, 24, 0, 0, &[]).await.unwrap();
+            channel.request_shell(true).await.unwrap();
+            channel.eof().await.unwrap();
+            let result = collect(channel).await;
+            assert_eq!(
+                result
+                    .stdout
+                    .contains("agentman: hubot transferred project web to you\r\n"),
+                expected
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_motd_shown_to_everyone_in_terminal_sessions() {
+        let harness = Harness::start_with(|c| c.guests.enabled = true).await;
+        let motd = crate::state::Motd {
+            text: "staging DB is at `db:5432`".to_string(),
+            set_at: Utc::now(),
+        };
+        harness.state.set_motd("octocat", "api", Some(motd)).await.unwrap();
+        let key = harness.known_key("octocat").await;
+        let owner = harness.connect("api", key).await.unwrap();
+        let (_, login) = invite_guest(&harness, false).await;
+        let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let guest = harness.connect(&login, key).await.expect("guest accepted");
+
+        harness.backend.script("true", "", "", 0);
+        assert!(!exec(&owner, "true").await.stdout.contains("staging"));
+        for handle in [&owner, &guest] {
+            let channel = handle.channel_open_session().await.unwrap();
+            channel.request_pty(true, "xterm", 80, 24, 0, 0, &[]).await.unwrap();
+            channel.request_shell(true).await.unwrap();
+            channel.eof().await.unwrap();
+            let result = collect(channel).await;
+            assert!(
+                result.stdout.contains("staging DB is at \x1b[36mdb:5432\x1b[0m\r\n"),
+                "{:?}",
+                result.stdout
+            );
+        }
+    }
+
+    /// Record an invite to octocat/api; returns its ID and guest login.
+    async fn invite_guest(harness: &Harness, read_only: bool) -> (String, String) {
+        let (id, secret) = guest::new_credentials().unwrap();
+        let invite = crate::state::GuestInvite {
+            github_user: "octocat".to_string(),
+            project: "api".to_string(),
+            read_only,
+            secret_sha256: guest::secret_sha256(&secret),
+            created_at: Utc::now(),
+            expires_at: Utc::now() + chrono::Duration::hours(1),
+            key_fingerprint: None,
+        };
+        harness.state.add_guest(id.clone(), invite).await.unwrap();
+        let login = guest::login(&id, &secret);
+        (id, login)
+    }
+
+    #[tokio::test]
+    async fn test_read_only_guest() {
+        let harness = Harness::start_with(|c| c.guests.enabled = true).await;
+        let (id, login) = invite_guest(&harness, true).await;
+        let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let handle = harness.connect(&login, key.clone()).await.expect("guest accepted");
+
+        // The first key is bound; others are refused, the bound one keeps working.
+        let other = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        assert!(harness.connect(&login, other).await.is_none());
+        assert!(harness.connect(&login, key.clone()).await.is_some());
+        let notices = harness.state.take_notices("octocat").await.unwrap();
+        assert_eq!(notices.len(), 1);
+        assert!(notices[0].starts_with(&format!("guest invite {id} was first used")));
+
+        let result = exec(&handle, "cat /etc/passwd").await;
+        assert_eq!(result.exit_status, Some(1));
+        assert!(result.stdout.contains("read-only guests can only open a terminal"));
+        assert!(
+            handle
+                .channel_open_direct_tcpip("localhost", 8080, "127.0.0.1", 50000)
+                .await
+                .is_err()
+        );
+
+        let channel = handle.channel_open_session().await.unwrap();
+        channel.request_pty(true, "xterm", 80, 24, 0, 0, &[]).await.unwrap();
+        channel.request_shell(true).await.unwrap();
+        channel.eof().await.unwrap();
+        collect(channel).await;
+        let execs = harness.backend.execs();
+        assert_eq!(execs.len(), 1);
+        assert_eq!(
+            harness.backend.container("octocat", "api").as_ref(),
+            Some(&execs[0].container_id)
+        );
+        assert!(execs[0].cmd[2].contains("tmux attach-session -t 'agentman' -f read-only"));
+
+        // Revoked invites no longer log in.
+        harness.state.remove_guests("octocat", Some(&id)).await.unwrap();
+        assert!(harness.connect(&login, key).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_full_access_guest() {
+        let harness = Harness::start_with(|c| c.guests.enabled = true).await;
+        harness.backend.script("make test", "ok\n", "", 0);
+        let (_, login) = invite_guest(&harness, false).await;
+        let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let handle = harness.connect(&login, key).await.unwrap();
+
+        let result = exec(&handle, "make test").await;
+        assert_eq!(result.stdout, "ok\n");
+        let result = exec(&handle, "agentman guest invite").await;
+        assert_eq!(result.exit_status, Some(1));
+        assert!(result.stdout.contains("control commands are not available to guests"));
+
+        // Wrong secrets, and invites on gateways with guests turned off, are refused.
+        let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let last = if login.ends_with('0') { '1' } else { '0' };
+        let forged = format!("{}{last}", &login[..login.len() - 1]);
+        assert!(harness.connect(&forged, key).await.is_none());
+        let harness = Harness::start().await;
+        let (_, login) = invite_guest(&harness, false).await;
+        let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        assert!(harness.connect(&login, key).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_new_devices_are_reported_and_revoked_ones_refused() {
+        let harness = Harness::start_with(|c| c.trust.enabled = true).await;
+        let laptop = harness.known_key("octocat").await;
+        let phone = harness.known_key("octocat").await;
+
+        // The first device tells nothing; the next one is reported.
+        assert!(harness.connect("api", laptop.clone()).await.is_some());
+        assert!(harness.state.take_notices("octocat").await.unwrap().is_empty());
+        assert!(harness.connect("api", phone.clone()).await.is_some());
+        let notices = harness.state.take_notices("octocat").await.unwrap();
+        assert_eq!(notices.len(), 1, "{notices:?}");
+        assert!(notices[0].starts_with("new device 2 logged in"), "{}", notices[0]);
+        assert!(harness.connect("api", laptop.clone()).await.is_some());
+        assert!(harness.state.take_notices("octocat").await.unwrap().is_empty());
+
+        harness
+            .state
+            .set_device_revoked("octocat", 2, true)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(harness.connect("api", phone).await.is_none());
+        assert!(harness.connect("api", laptop).await.is_some());
+        let devices = harness.state.devices("octocat").await;
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].addresses, vec!["127.0.0.1"]);
+    }
+
+    #[tokio::test]
+    async fn test_admin_access_logs_in_as_owner() {
+        let harness = Harness::start_with(|c| {
+            c.admin_github_users = vec!["hubot".to_string()];
+            c.impersonation.enabled = true;
+        })
+        .await;
+        harness.backend.script("whoami", "dev\n", "", 0);
+        let key = harness.known_key("hubot").await;
+
+        // Without a grant, the admin's key logs in to the admin's own project.
+        let handle = harness.connect("api+octocat", key.clone()).await.unwrap();
+        exec(&handle, "whoami").await;
+        assert!(harness.backend.container("hubot", "api").is_some());
+        assert!(harness.backend.container("octocat", "api").is_none());
+
+        let grant = crate::state::AdminAccessGrant {
+            github_user: "octocat".to_string(),
+            project: "api".to_string(),
+            reason: "ticket 42".to_string(),
+            granted_at: Utc::now(),
+            expires_at: Utc::now() + chrono::Duration::hours(1),
+        };
+        harness.state.set_admin_access("hubot", grant).await.unwrap();
+        let handle = harness.connect("api+octocat", key.clone()).await.unwrap();
+        assert_eq!(exec(&handle, "whoami").await.stdout, "dev\n");
+        assert!(harness.backend.container("octocat", "api").is_some());
+
+        let notices = harness.state.take_notices("octocat").await.unwrap();
+        assert_eq!(notices.len(), 1);
+        assert!(notices[0].starts_with("admin hubot accessed your project api on "));
+        assert!(notices[0].ends_with(": ticket 42"));
+        assert!(harness.state.last_login("octocat").await.is_none());
+
+        // The grant covers one project only.
+        let handle = harness.connect("web+octocat", key).await.unwrap();
+        exec(&handle, "whoami").await;
+        assert!(harness.backend.container("octocat", "web").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_local_forward() {
+        let harness = Harness::start().await;
+        let key = harness.known_key("octocat").await;
+        let handle = harness.connect("api", key).await.unwrap();
+
+        let mut channel = handle
+            .channel_open_direct_tcpip("localhost", 8080, "127.0.0.1", 50000)
+            .await
+            .unwrap();
+        channel.data(&b"ping"[..]).await.unwrap();
+        match tokio::time::timeout(Duration::from_secs(5), channel.wait()).await.unwrap() {
+            Some(ChannelMsg::Data { data }) => assert_eq!(&data[..], b"ping"),
+            other => panic!("unexpected message: {other:?}"),
+        }
+
+        let execs = harness.backend.execs();
+        assert_eq!(execs[0].cmd, tcp_bridge::command("127.0.0.1", 8080));
+    }
+
+    #[tokio::test]
+    async fn test_gateway_forward_from_escape_prompt() {
+        let harness = Harness::start().await;
+        let key = harness.known_key("octocat").await;
+        let handle = harness.connect("api", key).await.unwrap();
+
+        let mut channel = handle.channel_open_session().await.unwrap();
+        channel.request_pty(true, "xterm", 80, 24, 0, 0, &[]).await.unwrap();
+        channel.request_shell(true).await.unwrap();
+        channel.data(&b"~C-L 0:localhost:3000\r"[..]).await.unwrap();
+
+        let mut output = String::new();
+        let port = loop {
+            match tokio::time::timeout(Duration::from_secs(5), channel.wait()).await.unwrap() {
+                Some(ChannelMsg::Data { data }) => output.push_str(&String::from_utf8_lossy(&data)),
+                Some(ChannelMsg::Success) => {}
+                other => panic!("unexpected message: {other:?}"),
+            }
+            if let Some(rest) = output.split("forwarding 127.0.0.1:").nth(1)
+                && let Some((port, _)) = rest.split_once(' ')
+            {
+                break port.parse::<u16>().unwrap();
+            }
+        };
+
+        let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"ping");
+        assert!(harness
+            .backend
+            .execs()
+            .iter()
+            .any(|e| e.cmd == tcp_bridge::command("127.0.0.1", 3000)));
+    }
+
+    #[tokio::test]
+    async fn test_remote_forward_relays_both_ways() {
+        let harness = Harness::start().await;
+        let key = harness.known_key("octocat").await;
+        let mut handle = harness.connect("api", key).await.unwrap();
+        let port = handle.tcpip_forward("localhost", 0).await.unwrap();
+
+        let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port as u16)).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // Our EOF reaches the client, and its reply and EOF come back.
+        stream.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut rest))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(rest, b"bye");
+    }
+
+    #[tokio::test]
+    async fn test_unix_socket_forwards() {
+        let harness = Harness::start_with(|c| {
+            c.port_forwarding.streamlocal_paths =
+                vec!["/run/docker.sock".to_string(), "/workspace/".to_string()];
+        })
+        .await;
+        let key = harness.known_key("octocat").await;
+        let mut handle = harness.connect("api", key).await.unwrap();
+
+        let mut channel = handle.channel_open_direct_streamlocal("/run/docker.sock").await.unwrap();
+        channel.data(&b"ping"[..]).await.unwrap();
+        match tokio::time::timeout(Duration::from_secs(5), channel.wait()).await.unwrap() {
+            Some(ChannelMsg::Data { data }) => assert_eq!(&data[..], b"ping"),
+            other => panic!("unexpected message: {other:?}"),
+        }
+        assert_eq!(harness.backend.execs()[0].cmd, tcp_bridge::unix_command("/run/docker.sock"));
+        assert!(handle.channel_open_direct_streamlocal("/run/other.sock").await.is_err());
+
+        // Sockets the client serves are created in the workspace directory.
+        assert!(handle.streamlocal_forward("/workspace/.lsp/server.sock").await.is_err());
+        handle.streamlocal_forward("/workspace/S.gpg-agent").await.unwrap();
+        let socket = harness.dir.join("workspaces/octocat/api/S.gpg-agent");
+        let mut stream = tokio::net::UnixStream::connect(&socket).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut reply = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut reply))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply, b"pingbye");
+
+        let result = exec(&handle, "agentman route").await;
+        let policy = "Unix socket forwards:      /run/docker.sock, /workspace/\n";
+        assert!(result.stdout.contains(policy), "{}", result.stdout);
+        assert!(result.stdout.contains("  -R /workspace/S.gpg-agent\n"), "{}", result.stdout);
+        handle.cancel_streamlocal_forward("/workspace/S.gpg-agent").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!socket.exists());
+    }
+
+    #[tokio::test]
+    async fn test_route_shows_policy_and_forwards() {
+        let harness = Harness::start_with(|c| c.port_forwarding.allow_remote = false).await;
+        let key = harness.known_key("octocat").await;
+        let handle = harness.connect("api", key).await.unwrap();
+        let result = exec(&handle, "agentman route").await;
+        assert_eq!(result.exit_status, Some(0));
+        assert!(result.stdout.contains("Local forwards (ssh -L):   allowed\n"));
+        assert!(result.stdout.contains("Remote forwards (ssh -R):  not allowed\n"));
+        assert!(result.stdout.contains("Active forwards: none\n"));
+
+        let harness = Harness::start().await;
+        let key = harness.known_key("octocat").await;
+        let mut handle = harness.connect("api", key).await.unwrap();
+        let port = handle.tcpip_forward("localhost", 0).await.unwrap();
+        let result = exec(&handle, "agentman route").await;
+        assert!(result.stdout.contains("  ports: any free port\n"));
+        assert!(result.stdout.contains(&format!("Active forwards:\n  -R 127.0.0.1:{port}\n")));
+    }
+
+    #[tokio::test]
+    async fn test_local_forward_direct_connect() {
+        let harness = Harness::start_with(|c| c.port_forwarding.direct_connect = true).await;
+        *harness.backend.container_ip.lock().unwrap() = Some("127.0.0.1".parse().unwrap());
+        let key = harness.known_key("octocat").await;
+        let handle = harness.connect("api", key).await.unwrap();
+
+        // A service listening on the container's address is reached without an exec.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (mut r, mut w) = stream.split();
+            tokio::io::copy(&mut r, &mut w).await.unwrap();
+        });
+        let mut channel = handle
+            .channel_open_direct_tcpip("localhost", port as u32, "127.0.0.1", 50000)
+            .await
+            .unwrap();
+        channel.data(&b"ping"[..]).await.unwrap();
+        match tokio::time::timeout(Duration::from_secs(5), channel.wait()).await.unwrap() {
+            Some(ChannelMsg::Data { data }) => assert_eq!(&data[..], b"ping"),
+            other => panic!("unexpected message: {other:?}"),
+        }
+        assert!(harness.backend.execs().is_empty());
+
+        // Loopback-only services refuse the direct connection and go through the bridge.
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed_port = closed.local_addr().unwrap().port();
+        drop(closed);
+        handle
+            .channel_open_direct_tcpip("localhost", closed_port as u32, "127.0.0.1", 50001)
+            .await
+            .unwrap();
+        let execs = harness.backend.execs();
+        assert_eq!(execs.len(), 1);
+        assert_eq!(execs[0].cmd, tcp_bridge::command("127.0.0.1", closed_port as u32));
+    }
+
+    #[tokio::test]
+    async fn test_direct_connect_relays_large_payloads() {
+        let harness = Harness::start_with(|c| c.port_forwarding.direct_connect = true).await;
+        *harness.backend.container_ip.lock().unwrap() = Some("127.0.0.1".parse().unwrap());
+        let key = harness.known_key("octocat").await;
+        let handle = harness.connect("api", key).await.unwrap();
+
+        // An echo service that stops reading while its replies aren't read: the relay has to
+        // keep reading the socket while it waits to write to it. Small buffers make that show
+        // up within a few MB.
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.set_recv_buffer_size(16 * 1024).unwrap();
+        socket.set_send_buffer_size(16 * 1024).unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let listener = socket.listen(1).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (mut r, mut w) = stream.split();
+            tokio::io::copy(&mut r, &mut w).await.unwrap();
+            w.shutdown().await.unwrap();
+        });
+        let channel = handle
+            .channel_open_direct_tcpip("localhost", port as u32, "127.0.0.1", 50000)
+            .await
+            .unwrap();
+        let payload: Vec<u8> = (0..8 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let (mut rx, tx) = channel.split();
+        let sent = payload.clone();
+        let send = tokio::spawn(async move {
+            tx.make_writer().write_all(&sent).await.unwrap();
+            tx.eof().await.unwrap();
+        });
+        let mut echoed = Vec::new();
+        tokio::time::timeout(Duration::from_secs(30), rx.make_reader().read_to_end(&mut echoed))
+            .await
+            .expect("relay stalled")
+            .unwrap();
+        send.await.unwrap();
+        assert!(echoed == payload, "echoed {} of {} bytes", echoed.len(), payload.len());
+    }
+
+    #[tokio::test]
+    async fn test_local_forward_denied_by_policy() {
+        let harness = Harness::start_with(|c| c.port_forwarding.allow_local = false).await;
+        let key = harness.known_key("octocat").await;
+        let handle = harness.connect("api", key).await.unwrap();
+
+        assert!(handle
+            .channel_open_direct_tcpip("localhost", 8080, "127.0.0.1", 50000)
+            .await
+            .is_err());
+
+        // Non-local destinations are refused by default even when forwarding is allowed.
+        let harness = Harness::start().await;
+        let key = harness.known_key("octocat").await;
+        let handle = harness.connect("api", key).await.unwrap();
+        assert!(handle
+            .channel_open_direct_tcpip("example.com", 80, "127.0.0.1", 50000)
+            .await
+            .is_err());
+        assert!(harness.backend.execs().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_dynamic_forward() {
+        let harness = Harness::start_with(|c| c.port_forwarding.allow_dynamic = true).await;
+        let key = harness.known_key("octocat").await;
+        let handle = harness.connect("api", key).await.unwrap();
+
+        // What `ssh -D` opens for a SOCKS request to example.com:443.
+        handle
+            .channel_open_direct_tcpip("example.com", 443, "127.0.0.1", 50000)
+            .await
+            .unwrap();
+        assert!(handle
+            .channel_open_direct_tcpip("example.com,fork", 443, "127.0.0.1", 50001)
+            .await
+            .is_err());
+        assert!(handle
+            .channel_open_direct_tcpip("example.com", 0, "127.0.0.1", 50002)
+            .await
+            .is_err());
+
+        let execs = harness.backend.execs();
+        assert_eq!(execs.len(), 1);
+        assert_eq!(execs[0].cmd, tcp_bridge::command("example.com", 443));
+    }
+
+    #[tokio::test]
+    async fn test_destroy() {
+        let harness = Harness::start().await;
+        harness.backend.script("true", "", "", 0);
+        let key = harness.known_key("octocat").await;
+        let handle = harness.connect("api", key).await.unwrap();
+
+        exec(&handle, "true").await;
+        assert!(harness.backend.container("octocat", "api").is_some());
+
+        let refused = exec(&handle, "agentman destroy").await;
+        assert_eq!(refused.exit_status, Some(2));
+        assert!(harness.backend.container("octocat", "api").is_some());
+
+        let destroyed = exec(&handle, "agentman destroy --yes").await;
+        assert_eq!(destroyed.exit_status, Some(0));
+        assert!(harness.backend.container("octocat", "api").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_destroy_other_project() {
+        let harness = Harness::start().await;
+        harness.backend.script("true", "", "", 0);
+        let key = harness.known_key("octocat").await;
+        let old = harness.connect("old-experiment", key.clone()).await.unwrap();
+        exec(&old, "true").await;
+        let handle = harness.connect("api", key).await.unwrap();
+        exec(&handle, "true").await;
+
+        let refused = exec(&handle, "agentman destroy --project old-experiment").await;
+        assert_eq!(refused.exit_status, Some(2));
+        assert!(harness.backend.container("octocat", "old-experiment").is_some());
+
+        let destroyed = exec(&handle, "agentman destroy --project old-experiment --yes").await;
+        assert_eq!(destroyed.exit_status, Some(0));
+        assert!(harness.backend.container("octocat", "old-experiment").is_none());
+        assert!(harness.backend.container("octocat", "api").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_openssh_global_requests() {
+        let harness = Harness::start().await;
+        harness.backend.script("true", "", "", 0);
+        let key = harness.known_key("octocat").await;
+        let handle = harness.connect("api", key).await.unwrap();
+
+        // Sent by OpenSSH and automation; none of them may end or wedge the connection.
+        handle.no_more_sessions(false).await.unwrap();
+        handle.send_keepalive(true).await.unwrap();
+        handle.send_keepalive(false).await.unwrap();
+        handle.send_ping().await.unwrap();
+        assert_eq!(exec(&handle, "true").await.exit_status, Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_batch_client_sees_usable_methods() {
+        let harness = Harness::start().await;
+        let interactive =
+            MethodSet::from(&[MethodKind::PublicKey, MethodKind::KeyboardInteractive][..]);
+        let remaining = |auth: client::AuthResult| match auth {
+            client::AuthResult::Failure {
+                remaining_methods, ..
+            } => remaining_methods,
+            client::AuthResult::Success => panic!("unexpected success"),
+        };
+
+        let config = Arc::new(client::Config::default());
+        let mut handle = client::connect(config, harness.addr, TestClient).await.unwrap();
+        let none = handle.authenticate_none("api").await.unwrap();
+        assert_eq!(remaining(none), interactive);
+        let password = handle.authenticate_password("api", "hunter2").await.unwrap();
+        assert!(!remaining(password).contains(&MethodKind::Password));
+        let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let unknown = handle
+            .authenticate_publickey("api", PrivateKeyWithHashAlg::new(Arc::new(key), None))
+            .await
+            .unwrap();
+        assert_eq!(remaining(unknown), interactive);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_key_rejected() {
+        let harness = Harness::start().await;
+        let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        assert!(harness.connect("api", key).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_project_rejected() {
+        let harness = Harness::start().await;
+        let key = harness.known_key("octocat").await;
+        assert!(harness.connect("../etc", key).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_over_quota_is_told_why() {
+        let harness = Harness::start().await;
+        *harness.backend.over_quota.lock().unwrap() =
+            Some("you have 3 running sandboxes and may run at most 3".to_string());
+        let key = harness.known_key("octocat").await;
+        let handle = harness.connect("api", key).await.unwrap();
+
+        let channel = handle.channel_open_session().await.unwrap();
+        let _ = channel.exec(true, "make test").await;
+        let result = collect(channel).await;
+        assert_eq!(result.exit_status, Some(1));
+        assert!(
+            result.stdout.contains("quota exceeded: you have 3 running sandboxes"),
+            "{}",
+            result.stdout
+        );
+        assert!(harness.backend.execs().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_image_picked_in_username() {
+        let harness = Harness::start_with(|c| {
+            c.images
+                .named
+                .insert("python".to_string(), "python:agent-3.12".to_string());
+        })
+        .await;
+        harness.backend.script("true", "", "", 0);
+        let key = harness.known_key("octocat").await;
+
+        let handle = harness.connect("api.ruby", key.clone()).await.unwrap();
+        let result = exec(&handle, "true").await;
+        assert_eq!(result.exit_status, Some(1));
+        assert!(
+            result.stdout.contains("unknown image \"ruby\"; available: default, python"),
+            "{}",
+            result.stdout
+        );
+        assert_eq!(harness.backend.container("octocat", "api"), None);
+
+        let handle = harness.connect("api.python", key.clone()).await.unwrap();
+        assert_eq!(exec(&handle, "true").await.exit_status, Some(0));
+        assert_eq!(
+            harness.backend.image("octocat", "api").as_deref(),
+            Some("python:agent-3.12")
+        );
+        assert!(harness.backend.container("octocat", "api").is_some());
+
+        // The running container keeps its image; terminal sessions are told.
+        let handle = harness.connect("api.default", key).await.unwrap();
+        let channel = handle.channel_open_session().await.unwrap();
+        channel.request_pty(true, "xterm", 80, 24, 0, 0, &[]).await.unwrap();
+        channel.request_shell(true).await.unwrap();
+        channel.eof().await.unwrap();
+        let result = collect(channel).await;
+        assert!(
+            result.stdout.contains("agentman-base:dev is used after `agentman stop`"),
+            "{}",
+            result.stdout
+        );
+        assert_eq!(harness.backend.image("octocat", "api"), None);
+    }
+
+    #[tokio::test]
+    async fn test_clone_url_from_environment() {
+        let harness = Harness::start().await;
+        harness.backend.script("true", "", "", 0);
+        let key = harness.known_key("octocat").await;
+        let handle = harness.connect("api", key.clone()).await.unwrap();
+
+        let channel = handle.channel_open_session().await.unwrap();
+        channel
+            .set_env(true, "AGENTMAN_CLONE_URL", "file:///etc")
+            .await
+            .unwrap();
+        let _ = channel.exec(true, "true").await;
+        assert_eq!(collect(channel).await.exit_status, None);
+        assert_eq!(harness.backend.container("octocat", "api"), None);
+
+        let handle = harness.connect("api", key).await.unwrap();
+        let channel = handle.channel_open_session().await.unwrap();
+        let url = "https://github.com/octocat/api.git";
+        channel.set_env(true, "AGENTMAN_CLONE_URL", url).await.unwrap();
+        channel.exec(true, "true").await.unwrap();
+        assert_eq!(collect(channel).await.exit_status, Some(0));
+        assert_eq!(harness.backend.clone_url("octocat", "api").as_deref(), Some(url));
+    }
+
+    #[tokio::test]
+    async fn test_container_failure_closes_channel() {
+        let harness = Harness::start().await;
+        harness.backend.fail_create.store(true, Ordering::Relaxed);
+        let key = harness.known_key("octocat").await;
+        let handle = harness.connect("api", key).await.unwrap();
+
+        let channel = handle.channel_open_session().await.unwrap();
+        let _ = channel.exec(true, "make test").await;
+        let result = collect(channel).await;
+        assert_eq!(result.exit_status, None);
+        assert!(result.stdout.is_empty());
+        assert!(harness.backend.execs().is_empty());
+    }
+}
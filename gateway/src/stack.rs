@@ -0,0 +1,1374 @@
+//! Multi-container projects (`agentman stack`).
+//!
+//! A workspace declares sidecar services (a database, a cache, ...) in a compose file at
+//! `/workspace/.agentman/compose.yaml`, or inherits `[stack] default_compose_file`. The sandbox
+//! stays the SSH target: it is the file's primary service (named by
+//! `x-agentman: {primary: <service>}`, otherwise the one service without an `image`), and every
+//! other service runs as a sidecar container on the project network, reachable by its service
+//! name. Sidecars are brought up with the sandbox and carry the workspace's labels, so
+//! `agentman destroy` removes them with it.
+//!
+//! Only a safe subset of compose is accepted: no builds, published ports, host mounts or
+//! privilege settings. Sidecars get the sandbox's hardening and limits, and named volumes live
+//! under `[stack] volumes_dir`, outside the (user-writable) workspace.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow, bail};
+use bollard::errors::Error as BollardError;
+use bollard::models::{
+    ContainerCreateBody, ContainerSummaryStateEnum, EndpointSettings, HealthConfig,
+    HealthStatusEnum, HostConfig, NetworkingConfig, RestartPolicy, RestartPolicyNameEnum,
+};
+use bollard::query_parameters::{
+    CreateContainerOptionsBuilder, InspectContainerOptions, ListContainersOptionsBuilder,
+    StartContainerOptions,
+};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+use tracing::{info, warn};
+
+use crate::config::{GatewayConfig, StackConfig};
+use crate::docker::{ContainerManager, parse_memory_limit};
+use crate::ha;
+use crate::host_fs;
+use crate::yaml::{self, Node};
+
+/// Compose files the gateway looks for in a workspace, in order.
+const WORKSPACE_COMPOSE_FILES: [&str; 2] = [".agentman/compose.yaml", ".agentman/compose.yml"];
+
+/// Largest compose file the gateway reads.
+const MAX_COMPOSE_BYTES: u64 = 256 * 1024;
+
+/// How long to wait for a dependency with `condition: service_healthy`.
+const HEALTHY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Bringing the stack up while connecting gives up after this long (the shell still opens).
+const AUTO_UP_TIMEOUT: Duration = Duration::from_secs(120);
+
+const SERVICE_LABEL: &str = "agentman.stack_service";
+const HASH_LABEL: &str = "agentman.stack_hash";
+
+/// A parsed compose file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Compose {
+    /// The service the sandbox stands in for.
+    pub primary: Option<PrimaryService>,
+    /// Sidecars, in start order (dependencies first).
+    pub services: Vec<Service>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PrimaryService {
+    pub name: String,
+    /// Added to the sandbox's environment when its container is created.
+    pub environment: Vec<(String, String)>,
+    pub depends_on: Vec<Dependency>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Service {
+    pub name: String,
+    pub image: String,
+    pub command: Option<Vec<String>>,
+    pub entrypoint: Option<Vec<String>>,
+    pub environment: Vec<(String, String)>,
+    pub depends_on: Vec<Dependency>,
+    pub volumes: Vec<Volume>,
+    pub working_dir: Option<String>,
+    pub user: Option<String>,
+    pub healthcheck: Option<Healthcheck>,
+    pub restart: Option<RestartPolicyNameEnum>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Dependency {
+    pub service: String,
+    /// `condition: service_healthy`: wait for the dependency's healthcheck.
+    pub healthy: bool,
+}
+
+/// A named volume mounted into a sidecar.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Volume {
+    pub name: String,
+    pub target: String,
+    pub read_only: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Healthcheck {
+    pub test: Vec<String>,
+    pub interval: Option<Duration>,
+    pub timeout: Option<Duration>,
+    pub retries: Option<u32>,
+    pub start_period: Option<Duration>,
+}
+
+/// Parse and check a compose file.
+pub(crate) fn parse_compose(src: &str) -> Result<Compose> {
+    let Node::Map(entries) = yaml::parse(src)? else {
+        bail!("the compose file must be a mapping with `services`");
+    };
+    let mut services = None;
+    let mut primary_name = None;
+    for (key, value) in entries {
+        match key.as_str() {
+            "services" => services = Some(value),
+            "x-agentman" => {
+                for (key, value) in map_entries(value, "x-agentman")? {
+                    match key.as_str() {
+                        "primary" => primary_name = Some(scalar(value, "x-agentman.primary")?),
+                        other => bail!("x-agentman.{other} is not supported"),
+                    }
+                }
+            }
+            "volumes" => {
+                for (name, value) in map_entries(value, "volumes")? {
+                    if value != Node::Null && value != Node::Map(Vec::new()) {
+                        bail!("volumes.{name}: volume options are not supported");
+                    }
+                }
+            }
+            "version" | "name" => {}
+            other if other.starts_with("x-") => {}
+            other => bail!("`{other}` is not supported in agentman stacks"),
+        }
+    }
+    let services = map_entries(
+        services.ok_or_else(|| anyhow!("the compose file has no `services`"))?,
+        "services",
+    )?;
+
+    let mut parsed = Vec::new();
+    for (name, node) in services {
+        validate_service_name(&name)?;
+        parsed.push((name.clone(), parse_service(&name, node)?));
+    }
+
+    // The sandbox is the named primary, else the one service without an image.
+    let primary_name = match primary_name {
+        Some(name) => {
+            if !parsed.iter().any(|(n, _)| *n == name) {
+                bail!("x-agentman.primary: no service `{name}`");
+            }
+            Some(name)
+        }
+        None => {
+            let mut imageless = parsed.iter().filter(|(_, s)| s.image.is_none());
+            match (imageless.next(), imageless.next()) {
+                (Some((name, _)), None) => Some(name.clone()),
+                (None, _) => None,
+                (Some(_), Some(_)) => bail!(
+                    "only one service (the sandbox) may leave out `image`; name it with x-agentman.primary"
+                ),
+            }
+        }
+    };
+
+    let mut primary = None;
+    let mut sidecars = Vec::new();
+    for (name, raw) in parsed {
+        if Some(&name) == primary_name.as_ref() {
+            if let Some(key) = raw.sidecar_only_key() {
+                bail!(
+                    "services.{name}.{key}: the primary service is the sandbox; it can only set environment and depends_on"
+                );
+            }
+            primary = Some(PrimaryService {
+                name,
+                environment: raw.environment,
+                depends_on: raw.depends_on,
+            });
+            continue;
+        }
+        let image = raw
+            .image
+            .ok_or_else(|| anyhow!("services.{name}: `image` is required"))?;
+        sidecars.push(Service {
+            name,
+            image,
+            command: raw.command,
+            entrypoint: raw.entrypoint,
+            environment: raw.environment,
+            depends_on: raw.depends_on,
+            volumes: raw.volumes,
+            working_dir: raw.working_dir,
+            user: raw.user,
+            healthcheck: raw.healthcheck,
+            restart: raw.restart,
+        });
+    }
+
+    let deps = primary
+        .iter()
+        .map(|p| (&p.name, &p.depends_on))
+        .chain(sidecars.iter().map(|s| (&s.name, &s.depends_on)));
+    for (name, depends_on) in deps {
+        for dep in depends_on {
+            if dep.service == *name {
+                bail!("services.{name}: a service can't depend on itself");
+            }
+            if primary.as_ref().is_some_and(|p| p.name == dep.service) {
+                bail!(
+                    "services.{name}: sidecars can't depend on the sandbox ({})",
+                    dep.service
+                );
+            }
+            let Some(target) = sidecars.iter().find(|s| s.name == dep.service) else {
+                bail!("services.{name}.depends_on: no service `{}`", dep.service);
+            };
+            if dep.healthy && target.healthcheck.is_none() {
+                bail!(
+                    "services.{name}.depends_on: `{}` has no healthcheck to wait for",
+                    dep.service
+                );
+            }
+        }
+    }
+
+    Ok(Compose {
+        primary,
+        services: start_order(sidecars)?,
+    })
+}
+
+/// Fields as written, before deciding whether the service is the sandbox.
+#[derive(Default)]
+struct RawService {
+    image: Option<String>,
+    command: Option<Vec<String>>,
+    entrypoint: Option<Vec<String>>,
+    environment: Vec<(String, String)>,
+    depends_on: Vec<Dependency>,
+    volumes: Vec<Volume>,
+    working_dir: Option<String>,
+    user: Option<String>,
+    healthcheck: Option<Healthcheck>,
+    restart: Option<RestartPolicyNameEnum>,
+}
+
+impl RawService {
+    /// A key set that only makes sense for sidecars (`image` is allowed and ignored, so an
+    /// existing compose file can be reused).
+    fn sidecar_only_key(&self) -> Option<&'static str> {
+        [
+            ("command", self.command.is_some()),
+            ("entrypoint", self.entrypoint.is_some()),
+            ("volumes", !self.volumes.is_empty()),
+            ("working_dir", self.working_dir.is_some()),
+            ("user", self.user.is_some()),
+            ("healthcheck", self.healthcheck.is_some()),
+            ("restart", self.restart.is_some()),
+        ]
+        .into_iter()
+        .find(|(_, set)| *set)
+        .map(|(key, _)| key)
+    }
+}
+
+fn parse_service(name: &str, node: Node) -> Result<RawService> {
+    let mut service = RawService::default();
+    for (key, value) in map_entries(node, &format!("services.{name}"))? {
+        let path = format!("services.{name}.{key}");
+        match key.as_str() {
+            "image" => service.image = Some(scalar(value, &path)?),
+            "command" => service.command = Some(words(value, &path)?),
+            "entrypoint" => service.entrypoint = Some(words(value, &path)?),
+            "environment" => service.environment = environment(value, &path)?,
+            "depends_on" => service.depends_on = depends_on(value, &path)?,
+            "volumes" => {
+                service.volumes = seq_items(value, &path)?
+                    .into_iter()
+                    .map(|v| volume(&scalar(v, &path)?, &path))
+                    .collect::<Result<_>>()?
+            }
+            "working_dir" => service.working_dir = Some(scalar(value, &path)?),
+            "user" => service.user = Some(scalar(value, &path)?),
+            "healthcheck" => service.healthcheck = healthcheck(value, &path)?,
+            "restart" => {
+                service.restart = Some(match scalar(value, &path)?.as_str() {
+                    "no" => RestartPolicyNameEnum::NO,
+                    "always" => RestartPolicyNameEnum::ALWAYS,
+                    "unless-stopped" => RestartPolicyNameEnum::UNLESS_STOPPED,
+                    "on-failure" => RestartPolicyNameEnum::ON_FAILURE,
+                    other => bail!("{path}: unknown restart policy `{other}`"),
+                })
+            }
+            "ports" => bail!(
+                "{path} is not supported: sidecars are reachable from the sandbox as {name}:<port> (forward with ssh -L)"
+            ),
+            "build" => bail!("{path} is not supported: use a prebuilt image"),
+            _ => bail!(
+                "{path} is not supported (agentman stacks accept image, command, entrypoint, environment, depends_on, volumes, working_dir, user, healthcheck and restart)"
+            ),
+        }
+    }
+    Ok(service)
+}
+
+/// Service names are DNS labels (they become hostnames on the project network).
+fn validate_service_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= 32
+        && name.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+    if !valid {
+        bail!(
+            "services.{name}: service names must be lowercase letters, digits, `-` or `_` (at most 32)"
+        );
+    }
+    Ok(())
+}
+
+fn map_entries(node: Node, path: &str) -> Result<Vec<(String, Node)>> {
+    match node {
+        Node::Map(entries) => Ok(entries),
+        Node::Null => Ok(Vec::new()),
+        _ => bail!("{path} must be a mapping"),
+    }
+}
+
+fn seq_items(node: Node, path: &str) -> Result<Vec<Node>> {
+    match node {
+        Node::Seq(items) => Ok(items),
+        Node::Null => Ok(Vec::new()),
+        _ => bail!("{path} must be a list"),
+    }
+}
+
+fn scalar(node: Node, path: &str) -> Result<String> {
+    match node {
+        Node::Scalar(s) => Ok(s),
+        _ => bail!("{path} must be a string"),
+    }
+}
+
+/// A command as a list, or a string split like a shell would (quotes, backslashes).
+fn words(node: Node, path: &str) -> Result<Vec<String>> {
+    match node {
+        Node::Seq(items) => items.into_iter().map(|i| scalar(i, path)).collect(),
+        Node::Scalar(s) => split_words(&s).ok_or_else(|| anyhow!("{path}: unterminated quote")),
+        _ => bail!("{path} must be a string or a list"),
+    }
+}
+
+fn split_words(s: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut current: Option<String> = None;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(current.take()),
+            '\'' => {
+                let word = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()? {
+                        '\'' => break,
+                        c => word.push(c),
+                    }
+                }
+            }
+            '"' => {
+                let word = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => word.push(chars.next()?),
+                        c => word.push(c),
+                    }
+                }
+            }
+            '\\' => current.get_or_insert_with(String::new).push(chars.next()?),
+            c => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(current);
+    Some(words)
+}
+
+fn environment(node: Node, path: &str) -> Result<Vec<(String, String)>> {
+    let pairs = match node {
+        Node::Map(entries) => entries
+            .into_iter()
+            .map(|(k, v)| match v {
+                Node::Null => Ok((k, String::new())),
+                v => Ok((k.clone(), scalar(v, &format!("{path}.{k}"))?)),
+            })
+            .collect::<Result<Vec<_>>>()?,
+        node => seq_items(node, path)?
+            .into_iter()
+            .map(|item| {
+                let item = scalar(item, path)?;
+                item.split_once('=')
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .ok_or_else(|| anyhow!("{path}: `{item}` needs a value (KEY=VALUE)"))
+            })
+            .collect::<Result<Vec<_>>>()?,
+    };
+    for (key, _) in &pairs {
+        if key.is_empty() || key.contains(['=', '\0']) {
+            bail!("{path}: invalid variable name `{key}`");
+        }
+    }
+    Ok(pairs)
+}
+
+fn depends_on(node: Node, path: &str) -> Result<Vec<Dependency>> {
+    match node {
+        Node::Map(entries) => entries
+            .into_iter()
+            .map(|(service, options)| {
+                let mut healthy = false;
+                for (key, value) in map_entries(options, &format!("{path}.{service}"))? {
+                    match (
+                        key.as_str(),
+                        scalar(value, &format!("{path}.{service}.{key}"))?.as_str(),
+                    ) {
+                        ("condition", "service_started") => healthy = false,
+                        ("condition", "service_healthy") => healthy = true,
+                        // `required` and `restart` don't change how a stack starts here.
+                        ("required" | "restart", _) => {}
+                        (key, value) => {
+                            bail!("{path}.{service}: `{key}: {value}` is not supported")
+                        }
+                    }
+                }
+                Ok(Dependency { service, healthy })
+            })
+            .collect(),
+        node => seq_items(node, path)?
+            .into_iter()
+            .map(|item| {
+                Ok(Dependency {
+                    service: scalar(item, path)?,
+                    healthy: false,
+                })
+            })
+            .collect(),
+    }
+}
+
+/// `name:/target[:ro|:rw]`; only named volumes (host paths would escape the sandbox).
+fn volume(spec: &str, path: &str) -> Result<Volume> {
+    let parts: Vec<&str> = spec.split(':').collect();
+    let (name, target, read_only) = match parts.as_slice() {
+        [name, target] => (*name, *target, false),
+        [name, target, "ro"] => (*name, *target, true),
+        [name, target, "rw"] => (*name, *target, false),
+        _ => bail!("{path}: expected `<volume>:<container-path>[:ro]`, got `{spec}`"),
+    };
+    if name.starts_with(['.', '/', '~']) {
+        bail!("{path}: host paths are not supported (`{name}`); use a named volume");
+    }
+    let valid_name = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        && !name.is_empty();
+    if !valid_name {
+        bail!("{path}: invalid volume name `{name}`");
+    }
+    if !target.starts_with('/') {
+        bail!("{path}: `{target}` must be an absolute path in the container");
+    }
+    Ok(Volume {
+        name: name.to_string(),
+        target: target.to_string(),
+        read_only,
+    })
+}
+
+fn healthcheck(node: Node, path: &str) -> Result<Option<Healthcheck>> {
+    let mut check = Healthcheck {
+        test: Vec::new(),
+        interval: None,
+        timeout: None,
+        retries: None,
+        start_period: None,
+    };
+    for (key, value) in map_entries(node, path)? {
+        let path = format!("{path}.{key}");
+        match key.as_str() {
+            "test" => {
+                check.test = match value {
+                    Node::Scalar(s) => vec!["CMD-SHELL".to_string(), s],
+                    value => words(value, &path)?,
+                }
+            }
+            "disable" if scalar(value.clone(), &path)? == "true" => return Ok(None),
+            "disable" => {}
+            "interval" => check.interval = Some(duration(&scalar(value, &path)?, &path)?),
+            "timeout" => check.timeout = Some(duration(&scalar(value, &path)?, &path)?),
+            "start_period" => check.start_period = Some(duration(&scalar(value, &path)?, &path)?),
+            "retries" => {
+                check.retries = Some(
+                    scalar(value, &path)?
+                        .parse()
+                        .map_err(|_| anyhow!("{path} must be a number"))?,
+                )
+            }
+            _ => bail!("{path} is not supported"),
+        }
+    }
+    if !matches!(
+        check.test.first().map(String::as_str),
+        Some("CMD" | "CMD-SHELL" | "NONE")
+    ) {
+        bail!("{path}.test must start with CMD or CMD-SHELL (or be a shell command string)");
+    }
+    Ok(Some(check))
+}
+
+/// Compose durations: `1m30s`, `10s`, `500ms`, ...
+fn duration(s: &str, path: &str) -> Result<Duration> {
+    let invalid = || anyhow!("{path}: invalid duration `{s}` (e.g. 10s, 1m30s, 500ms)");
+    let mut total = Duration::ZERO;
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(invalid)?;
+        let value: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+        rest = &rest[digits..];
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let unit = match &rest[..unit_len] {
+            "ms" => Duration::from_millis(value),
+            "s" => Duration::from_secs(value),
+            "m" => Duration::from_secs(value * 60),
+            "h" => Duration::from_secs(value * 3600),
+            _ => return Err(invalid()),
+        };
+        total += unit;
+        rest = &rest[unit_len..];
+    }
+    Ok(total)
+}
+
+/// Order sidecars so dependencies start first (declaration order otherwise).
+fn start_order(mut pending: Vec<Service>) -> Result<Vec<Service>> {
+    let mut ordered: Vec<Service> = Vec::new();
+    while !pending.is_empty() {
+        let ready = pending.iter().position(|s| {
+            s.depends_on
+                .iter()
+                .all(|d| ordered.iter().any(|o| o.name == d.service))
+        });
+        match ready {
+            Some(i) => ordered.push(pending.remove(i)),
+            None => {
+                let names: Vec<&str> = pending.iter().map(|s| s.name.as_str()).collect();
+                bail!("depends_on has a cycle between {}", names.join(", "));
+            }
+        }
+    }
+    Ok(ordered)
+}
+
+/// Reject stacks the gateway's `[stack]` settings don't allow.
+fn check_allowed(config: &StackConfig, compose: &Compose) -> Result<()> {
+    if compose.services.len() > config.max_services {
+        bail!(
+            "the stack has {} sidecars; this gateway allows at most {}",
+            compose.services.len(),
+            config.max_services
+        );
+    }
+    for service in &compose.services {
+        if !config.allows_image(&service.image) {
+            bail!(
+                "services.{}: image `{}` is not allowed on this gateway",
+                service.name,
+                service.image
+            );
+        }
+    }
+    Ok(())
+}
+
+/// A workspace's compose file: its display path (as seen in the sandbox for workspace files)
+/// and host path.
+fn compose_source(
+    config: &GatewayConfig,
+    github_user: &str,
+    project: &str,
+) -> Option<(String, PathBuf)> {
+    let workspace = config.workspace_path(github_user, project);
+    for rel in WORKSPACE_COMPOSE_FILES {
+        let path = workspace.join(rel);
+        if path.symlink_metadata().is_ok() {
+            return Some((format!("/workspace/{rel}"), path));
+        }
+    }
+    config
+        .stack
+        .default_compose_file
+        .as_ref()
+        .map(|path| (path.display().to_string(), path.clone()))
+}
+
+/// Read a compose file. A workspace file is opened without following symlinks ([`host_fs`]):
+/// the sandbox user could otherwise point the gateway at any host file.
+async fn read_compose_file(
+    config: &GatewayConfig,
+    github_user: &str,
+    project: &str,
+    path: &Path,
+) -> Result<String> {
+    let refused = || {
+        anyhow!("the compose file must be a regular file of at most {MAX_COMPOSE_BYTES} bytes")
+    };
+    let workspace = config.workspace_path(github_user, project);
+    let file = match path.strip_prefix(&workspace) {
+        Ok(rel) => {
+            let rel = rel.to_path_buf();
+            let file = tokio::task::spawn_blocking(move || {
+                let file = host_fs::walk(&workspace, &rel)?;
+                let meta = file.metadata()?;
+                if meta.is_symlink() {
+                    bail!("the compose file can't be a symlink");
+                }
+                if !meta.is_file() {
+                    return Err(refused());
+                }
+                Ok(file.reopen()?)
+            })
+            .await
+            .context("open task failed")??;
+            tokio::fs::File::from_std(file)
+        }
+        // `[stack] default_compose_file`, set by the admin.
+        Err(_) => tokio::fs::File::open(path).await?,
+    };
+    let meta = file.metadata().await?;
+    if !meta.is_file() || meta.len() > MAX_COMPOSE_BYTES {
+        return Err(refused());
+    }
+    let mut content = String::new();
+    // Read one byte past the limit, so a file that grew after the check is still caught.
+    file.take(MAX_COMPOSE_BYTES + 1)
+        .read_to_string(&mut content)
+        .await?;
+    if content.len() as u64 > MAX_COMPOSE_BYTES {
+        return Err(refused());
+    }
+    Ok(content)
+}
+
+/// Load a workspace's stack: `Ok(None)` if stacks are disabled or the workspace has no compose
+/// file; otherwise the compose file's display path and its parsed contents.
+pub(crate) async fn load(
+    config: &GatewayConfig,
+    github_user: &str,
+    project: &str,
+) -> Result<Option<(String, Compose)>> {
+    if !config.stack.enabled {
+        return Ok(None);
+    }
+    let Some((display, path)) = compose_source(config, github_user, project) else {
+        return Ok(None);
+    };
+    let src = read_compose_file(config, github_user, project, &path)
+        .await
+        .with_context(|| format!("failed to read {display}"))?;
+    let compose = parse_compose(&src).with_context(|| format!("invalid {display}"))?;
+    check_allowed(&config.stack, &compose).with_context(|| format!("invalid {display}"))?;
+    Ok(Some((display, compose)))
+}
+
+/// Environment the compose file's primary service adds to a new sandbox container.
+pub(crate) async fn primary_env(
+    config: &GatewayConfig,
+    github_user: &str,
+    project: &str,
+) -> Vec<String> {
+    match load(config, github_user, project).await {
+        Ok(Some((
+            _,
+            Compose {
+                primary: Some(primary),
+                ..
+            },
+        ))) => primary
+            .environment
+            .iter()
+            .map(|(k, v)| format!("{k}={v}"))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Host directory of a workspace's sidecar volumes.
+pub(crate) fn volumes_path(config: &StackConfig, github_user: &str, project: &str) -> PathBuf {
+    config.volumes_dir.join(github_user).join(project)
+}
+
+/// Delete a workspace's sidecar volumes (on destroy).
+pub(crate) async fn remove_volumes(config: &StackConfig, github_user: &str, project: &str) {
+    let path = volumes_path(config, github_user, project);
+    match tokio::fs::remove_dir_all(&path).await {
+        Ok(()) => info!("Removed stack volumes {}", path.display()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => warn!("Failed to remove stack volumes {}: {}", path.display(), e),
+    }
+}
+
+/// Move a workspace's sidecar volumes to another owner (on transfer).
+pub(crate) async fn move_volumes(
+    config: &StackConfig,
+    from_user: &str,
+    project: &str,
+    to_user: &str,
+) -> Result<()> {
+    let from = volumes_path(config, from_user, project);
+    if !from.exists() {
+        return Ok(());
+    }
+    let to = volumes_path(config, to_user, project);
+    if let Some(parent) = to.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::rename(&from, &to)
+        .await
+        .with_context(|| format!("Failed to move {} to {}", from.display(), to.display()))
+}
+
+fn sidecar_name(config: &GatewayConfig, github_user: &str, project: &str, service: &str) -> String {
+    format!(
+        "{}-{}",
+        config.container_name(github_user, project),
+        service
+    )
+}
+
+/// Changes to any of a service's settings change its hash, which triggers a recreate.
+fn service_hash(service: &Service) -> String {
+    let digest = Sha256::digest(format!("{service:?}").as_bytes());
+    digest[..8].iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// A sidecar container that exists for the workspace.
+struct Sidecar {
+    id: String,
+    service: String,
+    hash: String,
+    running: bool,
+    status: String,
+}
+
+async fn list_sidecars(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+) -> Result<Vec<Sidecar>> {
+    let filters: HashMap<String, Vec<String>> = HashMap::from([(
+        "label".to_string(),
+        vec![
+            SERVICE_LABEL.to_string(),
+            format!("agentman.github_user={github_user}"),
+            format!("agentman.project={project}"),
+        ],
+    )]);
+    let options = ListContainersOptionsBuilder::new()
+        .all(true)
+        .filters(&filters)
+        .build();
+    let containers = container_manager
+        .docker()
+        .list_containers(Some(options))
+        .await
+        .context("Failed to list containers")?;
+    Ok(containers
+        .into_iter()
+        .filter_map(|c| {
+            let labels = c.labels.unwrap_or_default();
+            Some(Sidecar {
+                id: c.id?,
+                service: labels.get(SERVICE_LABEL)?.clone(),
+                hash: labels.get(HASH_LABEL).cloned().unwrap_or_default(),
+                running: c.state == Some(ContainerSummaryStateEnum::RUNNING),
+                status: c.status.unwrap_or_default(),
+            })
+        })
+        .collect())
+}
+
+/// Bring the stack in line with the compose file: remove sidecars no longer declared, create
+/// missing ones, recreate changed ones and start stopped ones, in dependency order. Returns a
+/// line per change.
+pub(crate) async fn up(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    compose: &Compose,
+) -> Result<Vec<String>> {
+    let network = container_manager
+        .ensure_project_network(github_user, project)
+        .await?;
+    let existing = list_sidecars(container_manager, github_user, project).await?;
+    let mut report = Vec::new();
+
+    for sidecar in &existing {
+        if !compose.services.iter().any(|s| s.name == sidecar.service) {
+            container_manager.retire_container(&sidecar.id).await?;
+            report.push(format!("removed {} (no longer declared)", sidecar.service));
+        }
+    }
+
+    let config = container_manager.config();
+    for service in &compose.services {
+        wait_for_dependencies(container_manager, github_user, project, &service.depends_on).await?;
+        let hash = service_hash(service);
+        match existing.iter().find(|s| s.service == service.name) {
+            Some(sidecar) if sidecar.hash == hash => {
+                if !sidecar.running {
+                    container_manager
+                        .docker()
+                        .start_container(&sidecar.id, None::<StartContainerOptions>)
+                        .await
+                        .with_context(|| format!("Failed to start {}", service.name))?;
+                    report.push(format!("started {}", service.name));
+                }
+            }
+            Some(sidecar) => {
+                container_manager.retire_container(&sidecar.id).await?;
+                create_sidecar(
+                    container_manager,
+                    github_user,
+                    project,
+                    &network,
+                    service,
+                    &hash,
+                )
+                .await?;
+                report.push(format!("recreated {} (definition changed)", service.name));
+            }
+            None => {
+                create_sidecar(
+                    container_manager,
+                    github_user,
+                    project,
+                    &network,
+                    service,
+                    &hash,
+                )
+                .await?;
+                report.push(format!(
+                    "created {} ({})",
+                    service.name,
+                    sidecar_name(config, github_user, project, &service.name)
+                ));
+            }
+        }
+    }
+
+    if let Some(ref primary) = compose.primary {
+        wait_for_dependencies(container_manager, github_user, project, &primary.depends_on).await?;
+    }
+    Ok(report)
+}
+
+/// Attach the sandbox to the project network so it reaches sidecars by service name.
+pub(crate) async fn attach_sandbox(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    container_id: &str,
+) -> Result<()> {
+    let network = container_manager
+        .ensure_project_network(github_user, project)
+        .await?;
+    container_manager
+        .connect_to_network(&network, container_id, project)
+        .await
+}
+
+/// Stop and remove every sidecar of the workspace (volumes are kept). Returns a line per
+/// removed sidecar.
+pub(crate) async fn down(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+) -> Result<Vec<String>> {
+    let mut report = Vec::new();
+    for sidecar in list_sidecars(container_manager, github_user, project).await? {
+        container_manager.retire_container(&sidecar.id).await?;
+        report.push(format!("removed {}", sidecar.service));
+    }
+    Ok(report)
+}
+
+/// Bring the stack up while the sandbox starts (on connect). Problems are logged, never
+/// fatal: a broken compose file shouldn't lock the user out of the shell that fixes it.
+pub(crate) async fn bring_up(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    sandbox: Option<&str>,
+) {
+    let compose = match load(container_manager.config(), github_user, project).await {
+        Ok(Some((_, compose))) => compose,
+        Ok(None) => return,
+        Err(e) => {
+            warn!(
+                "Not starting stack for {}/{}: {:#}",
+                github_user, project, e
+            );
+            return;
+        }
+    };
+    match tokio::time::timeout(
+        AUTO_UP_TIMEOUT,
+        up(container_manager, github_user, project, &compose),
+    )
+    .await
+    {
+        Ok(Ok(report)) => {
+            for line in report {
+                info!("Stack {}/{}: {}", github_user, project, line);
+            }
+        }
+        Ok(Err(e)) => warn!(
+            "Failed to bring up stack for {}/{}: {:#}",
+            github_user, project, e
+        ),
+        Err(_) => warn!(
+            "Stack for {}/{} not up after {}s; continuing",
+            github_user,
+            project,
+            AUTO_UP_TIMEOUT.as_secs()
+        ),
+    }
+    if let Some(id) = sandbox
+        && let Err(e) = attach_sandbox(container_manager, github_user, project, id).await
+    {
+        warn!(
+            "Failed to attach {}/{} to its stack network: {:#}",
+            github_user, project, e
+        );
+    }
+}
+
+/// `agentman stack status` output.
+pub(crate) async fn format_status(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+) -> (u32, String) {
+    let config = container_manager.config();
+    let (display, compose) = match load(config, github_user, project).await {
+        Ok(Some(loaded)) => loaded,
+        Ok(None) => {
+            return (
+                0,
+                format!(
+                    "agentman: no stack for {github_user}/{project} (declare one in /workspace/{})\n",
+                    WORKSPACE_COMPOSE_FILES[0]
+                ),
+            );
+        }
+        Err(e) => return (1, format!("agentman: {e:#}\n")),
+    };
+    let sidecars = match list_sidecars(container_manager, github_user, project).await {
+        Ok(sidecars) => sidecars,
+        Err(e) => return (1, format!("agentman: {e:#}\n")),
+    };
+
+    let mut out = format!("agentman: stack for {github_user}/{project} ({display})\n");
+    if let Some(ref primary) = compose.primary {
+        out.push_str(&format!("- {}: the sandbox (SSH target)\n", primary.name));
+    }
+    for service in &compose.services {
+        let state = match sidecars.iter().find(|s| s.service == service.name) {
+            Some(s) if s.hash != service_hash(service) => {
+                format!("{} (changed; run `agentman stack up`)", s.status)
+            }
+            Some(s) => s.status.clone(),
+            None => "not created".to_string(),
+        };
+        out.push_str(&format!(
+            "- {}: {}  [{}]\n",
+            service.name, state, service.image
+        ));
+    }
+    for orphan in sidecars
+        .iter()
+        .filter(|s| !compose.services.iter().any(|svc| svc.name == s.service))
+    {
+        out.push_str(&format!(
+            "- {}: {} (no longer declared; removed by `agentman stack up`)\n",
+            orphan.service, orphan.status
+        ));
+    }
+    (0, out)
+}
+
+async fn wait_for_dependencies(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    depends_on: &[Dependency],
+) -> Result<()> {
+    for dep in depends_on.iter().filter(|d| d.healthy) {
+        let name = sidecar_name(
+            container_manager.config(),
+            github_user,
+            project,
+            &dep.service,
+        );
+        let deadline = tokio::time::Instant::now() + HEALTHY_TIMEOUT;
+        loop {
+            let info = container_manager
+                .docker()
+                .inspect_container(&name, None::<InspectContainerOptions>)
+                .await
+                .with_context(|| format!("Failed to inspect {}", dep.service))?;
+            let health = info.state.and_then(|s| s.health).and_then(|h| h.status);
+            if health == Some(HealthStatusEnum::HEALTHY) {
+                break;
+            }
+            if tokio::time::Instant::now() >= deadline {
+                bail!(
+                    "{} not healthy after {}s",
+                    dep.service,
+                    HEALTHY_TIMEOUT.as_secs()
+                );
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+    Ok(())
+}
+
+async fn create_sidecar(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    network: &str,
+    service: &Service,
+    hash: &str,
+) -> Result<()> {
+    ha::ensure_active()?;
+    let config = container_manager.config();
+    let name = sidecar_name(config, github_user, project, &service.name);
+    match container_manager
+        .docker()
+        .inspect_image(&service.image)
+        .await
+    {
+        Ok(_) => {}
+        Err(BollardError::DockerResponseServerError {
+            status_code: 404, ..
+        }) => container_manager.pull_image(&service.image).await?,
+        Err(e) => return Err(e).context("Failed to inspect image"),
+    }
+
+    let mut binds = Vec::new();
+    for volume in &service.volumes {
+        let host = volumes_path(&config.stack, github_user, project).join(&volume.name);
+        tokio::fs::create_dir_all(&host)
+            .await
+            .with_context(|| format!("Failed to create {}", host.display()))?;
+        binds.push(format!(
+            "{}:{}{}",
+            host.display(),
+            volume.target,
+            if volume.read_only { ":ro" } else { "" }
+        ));
+    }
+
+    let labels = HashMap::from([
+        ("agentman.managed".to_string(), "true".to_string()),
+        ("agentman.github_user".to_string(), github_user.to_string()),
+        ("agentman.project".to_string(), project.to_string()),
+        (SERVICE_LABEL.to_string(), service.name.clone()),
+        (HASH_LABEL.to_string(), hash.to_string()),
+    ]);
+    let nanos = |d: Option<Duration>| d.map(|d| d.as_nanos() as i64);
+    let body = ContainerCreateBody {
+        image: Some(service.image.clone()),
+        hostname: Some(service.name.replace('_', "-")),
+        cmd: service.command.clone(),
+        entrypoint: service.entrypoint.clone(),
+        env: Some(
+            service
+                .environment
+                .iter()
+                .map(|(k, v)| format!("{k}={v}"))
+                .collect(),
+        ),
+        working_dir: service.working_dir.clone(),
+        user: service.user.clone(),
+        labels: Some(labels),
+        healthcheck: service.healthcheck.as_ref().map(|h| HealthConfig {
+            test: Some(h.test.clone()),
+            interval: nanos(h.interval),
+            timeout: nanos(h.timeout),
+            retries: h.retries.map(i64::from),
+            start_period: nanos(h.start_period),
+            ..Default::default()
+        }),
+        host_config: Some(sidecar_host_config(
+            config,
+            binds,
+            network,
+            service.restart,
+        )?),
+        networking_config: Some(NetworkingConfig {
+            endpoints_config: Some(HashMap::from([(
+                network.to_string(),
+                EndpointSettings {
+                    aliases: Some(vec![service.name.clone()]),
+                    ..Default::default()
+                },
+            )])),
+        }),
+        ..Default::default()
+    };
+    let options = CreateContainerOptionsBuilder::new().name(&name).build();
+    let created = container_manager
+        .docker()
+        .create_container(Some(options), body)
+        .await
+        .with_context(|| format!("Failed to create container {name}"))?;
+    container_manager
+        .docker()
+        .start_container(&created.id, None::<StartContainerOptions>)
+        .await
+        .with_context(|| format!("Failed to start container {name}"))?;
+    info!("Started sidecar {} for {}/{}", name, github_user, project);
+    Ok(())
+}
+
+/// The sandbox's hardening and limits, minus what only the sandbox needs (the workspace mount,
+/// devices, a read-only root that would break most service images).
+fn sidecar_host_config(
+    config: &GatewayConfig,
+    binds: Vec<String>,
+    network: &str,
+    restart: Option<RestartPolicyNameEnum>,
+) -> Result<HostConfig> {
+    let security = &config.container_security;
+    let mut host_config = HostConfig {
+        binds: Some(binds),
+        privileged: Some(false),
+        network_mode: Some(network.to_string()),
+        restart_policy: restart.map(|name| RestartPolicy {
+            name: Some(name),
+            maximum_retry_count: None,
+        }),
+        ..Default::default()
+    };
+    if security.cap_drop_all {
+        host_config.cap_drop = Some(vec!["ALL".to_string()]);
+        if !security.cap_add.is_empty() {
+            host_config.cap_add = Some(security.cap_add.clone());
+        }
+    }
+    if security.no_new_privileges {
+        host_config.security_opt = Some(vec!["no-new-privileges:true".to_string()]);
+    }
+    if let Some(ref memory) = security.memory_limit {
+        host_config.memory = Some(parse_memory_limit(memory)?);
+    }
+    if let Some(cpu) = security.cpu_limit {
+        host_config.nano_cpus = Some((cpu * 1_000_000_000.0) as i64);
+    }
+    Ok(host_config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMPOSE: &str = r#"
+x-agentman:
+  primary: app
+volumes:
+  pgdata:
+services:
+  app:
+    image: ignored-for-the-sandbox
+    environment:
+      DATABASE_URL: postgres://postgres:dev@db:5432/app
+    depends_on:
+      db:
+        condition: service_healthy
+  worker:
+    image: example/worker:1
+    command: run --queue "high prio"
+    depends_on: [cache, db]
+    restart: unless-stopped
+  db:
+    image: postgres:16
+    environment:
+      - POSTGRES_PASSWORD=dev
+    volumes:
+      - pgdata:/var/lib/postgresql/data
+    healthcheck:
+      test: pg_isready -U postgres
+      interval: 2s
+      retries: 30
+  cache:
+    image: redis:7
+"#;
+
+    #[tokio::test]
+    async fn test_read_compose_file_does_not_follow_symlinks() {
+        let dir = std::env::temp_dir().join(format!("agentman-stack-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = GatewayConfig {
+            workspace_root: dir.join("workspaces"),
+            ..Default::default()
+        };
+        let workspace = config.workspace_path("octocat", "web");
+        std::fs::create_dir_all(workspace.join(".agentman")).unwrap();
+        std::fs::write(dir.join("secret.yaml"), "services: {}\n").unwrap();
+        let compose = workspace.join(WORKSPACE_COMPOSE_FILES[0]);
+        let read = |path: PathBuf| {
+            let config = config.clone();
+            async move { read_compose_file(&config, "octocat", "web", &path).await }
+        };
+
+        std::fs::write(&compose, COMPOSE).unwrap();
+        assert_eq!(read(compose.clone()).await.unwrap(), COMPOSE);
+        std::fs::remove_file(&compose).unwrap();
+        std::os::unix::fs::symlink(dir.join("secret.yaml"), &compose).unwrap();
+        assert!(read(compose.clone()).await.is_err());
+        std::fs::remove_dir_all(workspace.join(".agentman")).unwrap();
+        std::os::unix::fs::symlink(&dir, workspace.join(".agentman")).unwrap();
+        assert!(read(workspace.join(".agentman/secret.yaml")).await.is_err());
+        // The admin's default compose file is outside the workspace.
+        assert_eq!(read(dir.join("secret.yaml")).await.unwrap(), "services: {}\n");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_compose() {
+        let compose = parse_compose(COMPOSE).unwrap();
+        let primary = compose.primary.unwrap();
+        assert_eq!(primary.name, "app");
+        assert_eq!(primary.environment[0].0, "DATABASE_URL");
+        assert!(primary.depends_on[0].healthy);
+
+        let order: Vec<&str> = compose.services.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(order, ["db", "cache", "worker"]);
+        let worker = &compose.services[2];
+        assert_eq!(
+            worker.command.as_deref().unwrap(),
+            ["run", "--queue", "high prio"]
+        );
+        assert_eq!(worker.restart, Some(RestartPolicyNameEnum::UNLESS_STOPPED));
+        let db = &compose.services[0];
+        assert_eq!(
+            db.environment,
+            [("POSTGRES_PASSWORD".to_string(), "dev".to_string())]
+        );
+        assert_eq!(
+            db.volumes,
+            [Volume {
+                name: "pgdata".to_string(),
+                target: "/var/lib/postgresql/data".to_string(),
+                read_only: false
+            }]
+        );
+        let check = db.healthcheck.as_ref().unwrap();
+        assert_eq!(check.test, ["CMD-SHELL", "pg_isready -U postgres"]);
+        assert_eq!(check.interval, Some(Duration::from_secs(2)));
+        assert_eq!(check.retries, Some(30));
+
+        // Without x-agentman, the one service without an image is the sandbox.
+        let compose =
+            parse_compose("services:\n  app: {}\n  db:\n    image: postgres:16\n").unwrap();
+        assert_eq!(compose.primary.unwrap().name, "app");
+        assert_eq!(compose.services.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_compose_rejects_unsafe_or_unsupported() {
+        for (compose, expected) in [
+            (
+                "services:\n  db:\n    image: x\n    privileged: true\n",
+                "privileged is not supported",
+            ),
+            (
+                "services:\n  db:\n    image: x\n    ports: [\"5432:5432\"]\n",
+                "ports is not supported",
+            ),
+            (
+                "services:\n  db:\n    image: x\n    volumes: [\"/etc:/host\"]\n",
+                "host paths",
+            ),
+            (
+                "services:\n  db:\n    image: x\n    volumes: [\"./data:/data\"]\n",
+                "host paths",
+            ),
+            ("services:\n  db:\n    build: .\n", "build is not supported"),
+            (
+                "networks:\n  default: {}\nservices: {}\n",
+                "`networks` is not supported",
+            ),
+            (
+                "services:\n  a:\n    image: x\n    depends_on: [b]\n  b:\n    image: x\n    depends_on: [a]\n",
+                "cycle",
+            ),
+            (
+                "services:\n  a:\n    image: x\n    depends_on: [nope]\n",
+                "no service `nope`",
+            ),
+            (
+                "services:\n  a:\n    image: x\n    depends_on:\n      b:\n        condition: service_healthy\n  b:\n    image: x\n",
+                "no healthcheck",
+            ),
+            ("services:\n  app: {}\n  other: {}\n", "only one service"),
+            (
+                "services:\n  app:\n    command: make\n  db:\n    image: x\n",
+                "primary service is the sandbox",
+            ),
+            (
+                "services:\n  app: {}\n  db:\n    image: x\n    depends_on: [app]\n",
+                "can't depend on the sandbox",
+            ),
+            ("services:\n  Bad.Name:\n    image: x\n", "service names"),
+        ] {
+            let err = format!("{:#}", parse_compose(compose).unwrap_err());
+            assert!(err.contains(expected), "{compose:?}: {err}");
+        }
+    }
+
+    #[test]
+    fn test_check_allowed() {
+        let compose = parse_compose(COMPOSE).unwrap();
+        let mut config = StackConfig::default();
+        assert!(check_allowed(&config, &compose).is_ok());
+        config.allowed_images = vec!["postgres:*".to_string(), "redis:7".to_string()];
+        let err = check_allowed(&config, &compose).unwrap_err().to_string();
+        assert!(err.contains("example/worker:1"), "{err}");
+        config.allowed_images.push("example/*".to_string());
+        assert!(check_allowed(&config, &compose).is_ok());
+        config.max_services = 2;
+        assert!(check_allowed(&config, &compose).is_err());
+    }
+
+    #[test]
+    fn test_duration_and_words() {
+        assert_eq!(duration("1m30s", "t").unwrap(), Duration::from_secs(90));
+        assert_eq!(duration("500ms", "t").unwrap(), Duration::from_millis(500));
+        assert!(duration("10", "t").is_err());
+        assert!(duration("fast", "t").is_err());
+        assert_eq!(
+            split_words(r#"sh -c 'echo "hi there"' a\ b"#).unwrap(),
+            ["sh", "-c", "echo \"hi there\"", "a b"]
+        );
+        assert!(split_words("echo 'open").is_none());
+    }
+}
@@ -7,10 +7,14 @@
 use anyhow::{Context, Result};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tokio::sync::RwLock;
 
+use crate::config::ShellMode;
+use crate::i18n::Lang;
+
 /// Persistent gateway state.
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct GatewayState {
@@ -22,6 +26,359 @@ pub struct GatewayState {
     /// Key format: "github_user/project"
     #[serde(default)]
     pub workspaces: HashMap<String, WorkspaceInfo>,
+
+    /// Host port block reserved for each workspace's remote forwards.
+    /// Key format: "github_user/project"
+    #[serde(default)]
+    pub port_reservations: HashMap<String, PortReservation>,
+
+    /// Last successful login per GitHub user.
+    #[serde(default)]
+    pub last_login: HashMap<String, DateTime<Utc>>,
+
+    /// Users being offboarded, keyed by GitHub user.
+    #[serde(default)]
+    pub offboarding: HashMap<String, OffboardingRecord>,
+
+    /// Messages shown to a GitHub user at their next interactive login.
+    #[serde(default)]
+    pub notices: HashMap<String, Vec<String>>,
+
+    /// Git author identity written into each new container, per GitHub user.
+    #[serde(default)]
+    pub git_identities: HashMap<String, GitIdentity>,
+
+    /// Workspaces moved to another gateway (`agentman admin migrate`).
+    /// Key format: "github_user/project"
+    #[serde(default)]
+    pub migrations: HashMap<String, MigrationRecord>,
+
+    /// Guest invites (`agentman guest invite`), keyed by invite ID.
+    #[serde(default)]
+    pub guests: HashMap<String, GuestInvite>,
+
+    /// Where `agentman notify` messages go, per GitHub user.
+    #[serde(default)]
+    pub notifiers: HashMap<String, Notifier>,
+
+    /// Access to another user's project (`agentman admin access`), keyed by admin.
+    #[serde(default)]
+    pub admin_access: HashMap<String, AdminAccessGrant>,
+
+    /// HTTP preview links (`agentman expose --preview`), keyed by link ID.
+    #[serde(default)]
+    pub previews: HashMap<String, PreviewLink>,
+
+    /// Language of the gateway's messages (`agentman lang set`), per GitHub user.
+    #[serde(default)]
+    pub languages: HashMap<String, Lang>,
+
+    /// Preferences set with `agentman config set`, per GitHub user.
+    #[serde(default)]
+    pub preferences: HashMap<String, UserPreferences>,
+
+    /// Login messages (`agentman motd set`).
+    /// Key format: "github_user/project"
+    #[serde(default)]
+    pub motds: HashMap<String, Motd>,
+
+    /// Projects each workspace may run commands in (`agentman mesh allow`).
+    /// Key format: "github_user/project"
+    #[serde(default)]
+    pub mesh_grants: HashMap<String, Vec<String>>,
+
+    /// Destructive operations in progress (destroy, recreate, migrate), so that one a crash
+    /// interrupted is found at the next start (`agentman admin operations`).
+    /// Key format: "github_user/project"
+    #[serde(default)]
+    pub operations: HashMap<String, Operation>,
+
+    /// Keys users logged in with, and from where (`agentman trust`), per GitHub user.
+    #[serde(default)]
+    pub devices: HashMap<String, Vec<Device>>,
+}
+
+/// A key a user logged in with (`agentman trust`).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Device {
+    /// Number the user refers to it by, unique per user.
+    pub id: u32,
+
+    pub key_fingerprint: String,
+
+    /// Client addresses it connected from, most recent last.
+    #[serde(default)]
+    pub addresses: Vec<String>,
+
+    pub first_seen: DateTime<Utc>,
+
+    pub last_seen: DateTime<Utc>,
+
+    /// Set by `agentman trust revoke`: logins with the key are refused.
+    #[serde(default)]
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// What [`StateManager::record_device`] found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceLogin {
+    pub device: Device,
+
+    /// The key wasn't known for the user.
+    pub new: bool,
+
+    /// It is the first key recorded for the user.
+    pub first: bool,
+}
+
+/// A project's login message, shown to everyone who opens a terminal on it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Motd {
+    /// As typed, markdown-lite (see [`crate::motd`]).
+    pub text: String,
+
+    pub set_at: DateTime<Utc>,
+}
+
+/// A user's preferences; unset ones follow the gateway's config.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UserPreferences {
+    /// Mount the shared editor server caches (`[editor_cache]`) into new containers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub editor_cache: Option<bool>,
+
+    /// How interactive shells start, instead of `[shell] mode`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shell: Option<ShellMode>,
+
+    /// Stop the user's sandboxes after this many seconds without connections; can only
+    /// shorten `[idle] timeout_secs`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idle_timeout_secs: Option<u64>,
+
+    /// Tell the user's notifier when one of their sandboxes is stopped for being idle.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notify_idle_stop: Option<bool>,
+}
+
+/// Unauthenticated HTTP access to a port in a user's sandbox until it expires.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PreviewLink {
+    pub github_user: String,
+
+    pub project: String,
+
+    /// Port in the sandbox (on its loopback) requests are relayed to.
+    pub port: u16,
+
+    pub created_at: DateTime<Utc>,
+
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Lets an admin log in to another user's project with their own key until it expires.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AdminAccessGrant {
+    /// The project's owner; the admin acts as them.
+    pub github_user: String,
+
+    pub project: String,
+
+    /// Why the admin needs access; shown to the owner.
+    pub reason: String,
+
+    pub granted_at: DateTime<Utc>,
+
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Time-limited access to a user's project for someone else.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GuestInvite {
+    /// The inviting user; the guest acts as (and is audited under) them.
+    pub github_user: String,
+
+    pub project: String,
+
+    /// Only a read-only view of the shared tmux session.
+    pub read_only: bool,
+
+    /// SHA-256 (hex) of the secret part of the guest login.
+    pub secret_sha256: String,
+
+    pub created_at: DateTime<Utc>,
+
+    pub expires_at: DateTime<Utc>,
+
+    /// Fingerprint of the key bound by the first login; other keys are refused after that.
+    #[serde(default)]
+    pub key_fingerprint: Option<String>,
+}
+
+/// A destructive operation on a workspace, recorded before its first step and removed once
+/// it has finished.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Operation {
+    pub kind: OperationKind,
+
+    pub started_at: DateTime<Utc>,
+
+    /// The step it reached, e.g. `removing containers`.
+    pub step: String,
+
+    /// Containers it created, which a rollback removes.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub created_containers: Vec<String>,
+
+    /// Why it stopped, when a step failed after something was already changed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+
+    /// Set at startup for operations an earlier gateway process didn't finish.
+    #[serde(default)]
+    pub interrupted: bool,
+}
+
+impl Operation {
+    /// Whether it has stopped half-way and waits for an admin to resume or roll it back.
+    pub fn stalled(&self) -> bool {
+        self.interrupted || self.error.is_some()
+    }
+}
+
+/// What an [`Operation`] does.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OperationKind {
+    Destroy { keep_workspace: bool },
+    Recreate,
+    Migrate { node: String },
+}
+
+impl std::fmt::Display for OperationKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OperationKind::Destroy {
+                keep_workspace: false,
+            } => write!(f, "destroy"),
+            OperationKind::Destroy {
+                keep_workspace: true,
+            } => write!(f, "destroy --keep-workspace"),
+            OperationKind::Recreate => write!(f, "recreate"),
+            OperationKind::Migrate { node } => write!(f, "migrate to {node}"),
+        }
+    }
+}
+
+/// Where a workspace went when it was migrated away from this gateway.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MigrationRecord {
+    /// Name of the gateway in `[migration] nodes`.
+    pub node: String,
+
+    /// Address users connect to there.
+    pub address: String,
+
+    pub migrated_at: DateTime<Utc>,
+
+    /// Admin who ran the migration.
+    pub requested_by: String,
+}
+
+/// `git config user.name` / `user.email` for a user's sandboxes (`agentman git-identity`).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GitIdentity {
+    pub name: String,
+    pub email: String,
+}
+
+/// A user's channel for `agentman notify` messages.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Notifier {
+    /// JSON POST (`{"text": ...}`, which Slack/Mattermost incoming webhooks accept).
+    Webhook { url: String },
+    /// Email through the gateway's `sendmail`.
+    Email { address: String },
+}
+
+/// Progress of a user through the offboarding stages.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OffboardingRecord {
+    /// When the user was flagged; later stages are scheduled from here.
+    pub flagged_at: DateTime<Utc>,
+
+    /// Admin who started it with `agentman admin offboard` (unset: flagged for inactivity).
+    /// Manual offboarding isn't cancelled by logging in; logins are refused instead.
+    #[serde(default)]
+    pub requested_by: Option<String>,
+
+    #[serde(default)]
+    pub archived_at: Option<DateTime<Utc>>,
+
+    #[serde(default)]
+    pub keys_revoked_at: Option<DateTime<Utc>>,
+
+    #[serde(default)]
+    pub destroyed_at: Option<DateTime<Utc>>,
+}
+
+/// A contiguous block of host ports reserved for one workspace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PortReservation {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortReservation {
+    pub fn contains(&self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+
+    pub fn ports(&self) -> impl Iterator<Item = u16> {
+        self.start..=self.end
+    }
+}
+
+impl std::fmt::Display for PortReservation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+/// Pick the block for `key` within `range`: start at a slot derived from a stable hash of the
+/// key (so a workspace gets the same ports across gateway restarts and state rebuilds), then
+/// probe forward past blocks already taken.
+fn allocate_port_block(
+    key: &str,
+    range: (u16, u16),
+    block_size: u16,
+    taken: &[PortReservation],
+) -> Option<PortReservation> {
+    let (start, end) = range;
+    let blocks = (u32::from(end) - u32::from(start) + 1) / u32::from(block_size);
+    if blocks == 0 {
+        return None;
+    }
+    let digest = Sha256::digest(key.as_bytes());
+    let hash = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]);
+    let preferred = hash % blocks;
+
+    (0..blocks)
+        .map(|i| (preferred + i) % blocks)
+        .map(|slot| {
+            let first = u32::from(start) + slot * u32::from(block_size);
+            PortReservation {
+                start: first as u16,
+                end: (first + u32::from(block_size) - 1) as u16,
+            }
+        })
+        .find(|candidate| {
+            !taken
+                .iter()
+                .any(|t| t.start <= candidate.end && candidate.start <= t.end)
+        })
 }
 
 /// Cached key-to-GitHub mapping entry.
@@ -57,6 +414,137 @@ pub struct WorkspaceInfo {
 
     /// Path to the persistent workspace on the host.
     pub host_workspace_path: PathBuf,
+
+    /// Other projects (same GitHub user) whose networks this container joins.
+    ///
+    /// Managed via `agentman link` / `agentman unlink` and re-applied whenever the
+    /// container is recreated.
+    #[serde(default)]
+    pub linked_projects: Vec<String>,
+
+    /// Per-workspace init command (overrides `init.command` from the gateway config).
+    #[serde(default)]
+    pub init_command: Option<String>,
+
+    /// Outcome of the most recent init command run.
+    #[serde(default)]
+    pub init_status: Option<InitStatus>,
+
+    /// Image picked with `agentman image set` (overrides `docker_image` from the gateway
+    /// config). Applied the next time the container is created.
+    #[serde(default)]
+    pub image: Option<String>,
+
+    /// Port in the container that TLS connections for this workspace's hostname are routed
+    /// to by the SNI router (`[sni_router]`). Managed via `agentman tls`; unset means the
+    /// workspace isn't reachable through the router.
+    #[serde(default)]
+    pub tls_port: Option<u16>,
+
+    /// Hand files that the container's root creates to the container user as they appear
+    /// (`agentman ownership watch`, see [`crate::ownership`]).
+    #[serde(default)]
+    pub watch_ownership: bool,
+
+    /// Repository cloned into the workspace when it was created (`[clone]`), until the clone
+    /// has succeeded or run out of attempts.
+    #[serde(default)]
+    pub clone: Option<CloneStatus>,
+
+    /// `/dev/shm` size and ulimits picked with `agentman resize` (override
+    /// `[container_security]`). Applied the next time the container is created.
+    #[serde(default)]
+    pub resources: WorkspaceResources,
+}
+
+/// Per-workspace overrides of `[container_security]`'s container sizes; unset ones come from
+/// the gateway config.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WorkspaceResources {
+    /// Size of `/dev/shm`, e.g. "2g".
+    pub shm_size: Option<String>,
+
+    /// Open files limit.
+    pub nofile: Option<u64>,
+
+    /// Processes limit.
+    pub nproc: Option<u64>,
+}
+
+/// Lifecycle of a workspace init command run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InitState {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl std::fmt::Display for InitState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            InitState::Running => "running",
+            InitState::Succeeded => "succeeded",
+            InitState::Failed => "failed",
+        })
+    }
+}
+
+/// Status of the most recent init command run for a workspace.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InitStatus {
+    pub state: InitState,
+
+    /// The command that was run.
+    pub command: String,
+
+    /// Exit code (once finished).
+    pub exit_code: Option<i64>,
+
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+
+    /// Failure reason that isn't captured by the exit code (e.g. timeout, exec error).
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// Progress of cloning a repository into a new workspace.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CloneStatus {
+    pub url: String,
+
+    pub state: CloneState,
+
+    /// Clones started so far, including one a restart cut short.
+    pub attempts: u32,
+
+    /// Why the last attempt failed.
+    #[serde(default)]
+    pub error: Option<String>,
+
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Lifecycle of a workspace clone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CloneState {
+    /// Not tried yet, or cut short; tried again when the sandbox starts.
+    Pending,
+    Succeeded,
+    Failed,
+}
+
+impl std::fmt::Display for CloneState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            CloneState::Pending => "pending",
+            CloneState::Succeeded => "succeeded",
+            CloneState::Failed => "failed",
+        })
+    }
 }
 
 impl WorkspaceInfo {
@@ -66,10 +554,21 @@ impl WorkspaceInfo {
     }
 }
 
+/// Recent use of a workspace, for stopping idle containers (`[idle]`). Kept in memory only.
+#[derive(Debug, Clone, Copy)]
+struct Activity {
+    /// Open SSH connections using the workspace's sandbox.
+    connections: usize,
+    /// When the last connection ended (or the workspace was first seen idle).
+    last_active: DateTime<Utc>,
+}
+
 /// Thread-safe state manager.
 pub struct StateManager {
     state: RwLock<GatewayState>,
     path: PathBuf,
+    /// Keyed by "github_user/project".
+    activity: std::sync::Mutex<HashMap<String, Activity>>,
 }
 
 impl StateManager {
@@ -88,9 +587,20 @@ impl StateManager {
         Ok(Self {
             state: RwLock::new(state),
             path,
+            activity: Default::default(),
         })
     }
 
+    /// A manager for `path` that starts out empty, ignoring the file (which may be damaged)
+    /// until the next save overwrites it.
+    pub fn empty(path: PathBuf) -> Self {
+        Self {
+            state: RwLock::new(GatewayState::default()),
+            path,
+            activity: Default::default(),
+        }
+    }
+
     /// Save state to disk.
     pub async fn save(&self) -> Result<()> {
         let state = self.state.read().await;
@@ -110,6 +620,23 @@ impl StateManager {
         Ok(())
     }
 
+    /// The state file this manager persists to.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The whole state as JSON (`agentman admin state export`).
+    pub async fn to_json(&self) -> Result<serde_json::Value> {
+        let state = self.state.read().await;
+        serde_json::to_value(&*state).context("Failed to serialize state")
+    }
+
+    /// Replace the whole state and persist it (`agentman admin state import`).
+    pub async fn replace(&self, new_state: GatewayState) -> Result<()> {
+        *self.state.write().await = new_state;
+        self.save().await
+    }
+
     /// Look up a GitHub username by SSH key fingerprint.
     pub async fn get_github_user(&self, fingerprint: &str) -> Option<KeyCacheEntry> {
         let state = self.state.read().await;
@@ -163,6 +690,25 @@ impl StateManager {
         self.save().await
     }
 
+    /// Apply an in-place update to an existing workspace entry and persist it.
+    ///
+    /// Returns `false` (without writing) if the workspace does not exist.
+    pub async fn update_workspace<F>(&self, github_user: &str, project: &str, f: F) -> Result<bool>
+    where
+        F: FnOnce(&mut WorkspaceInfo),
+    {
+        let key = WorkspaceInfo::key(github_user, project);
+        {
+            let mut state = self.state.write().await;
+            match state.workspaces.get_mut(&key) {
+                Some(info) => f(info),
+                None => return Ok(false),
+            }
+        }
+        self.save().await?;
+        Ok(true)
+    }
+
     /// List all workspaces for a given GitHub user.
     pub async fn list_workspaces(&self, github_user: &str) -> Vec<WorkspaceInfo> {
         let state = self.state.read().await;
@@ -189,20 +735,965 @@ impl StateManager {
             .collect()
     }
 
-    /// Remove a workspace mapping (and persist the state file).
-    ///
-    /// Returns the removed workspace info, if it existed.
-    pub async fn remove_workspace(
+    /// Every GitHub user the gateway knows of: cached keys, workspaces and recorded logins.
+    pub async fn known_github_users(&self) -> Vec<String> {
+        let state = self.state.read().await;
+        let mut users: Vec<String> = state
+            .key_to_github
+            .values()
+            .map(|e| e.github_username.clone())
+            .chain(state.workspaces.values().map(|w| w.github_user.clone()))
+            .chain(state.last_login.keys().cloned())
+            .collect();
+        users.sort();
+        users.dedup();
+        users
+    }
+
+    /// Number of cached SSH keys per GitHub user.
+    pub async fn key_counts(&self) -> HashMap<String, usize> {
+        let state = self.state.read().await;
+        let mut counts = HashMap::new();
+        for entry in state.key_to_github.values() {
+            *counts.entry(entry.github_username.clone()).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    /// Record a successful login. An inactivity flag is lifted (returned so it can be
+    /// audited); manual offboarding is left in place.
+    pub async fn record_login(&self, github_user: &str) -> Result<Option<OffboardingRecord>> {
+        let cancelled = {
+            let mut state = self.state.write().await;
+            state.last_login.insert(github_user.to_string(), Utc::now());
+            match state.offboarding.get(github_user) {
+                Some(record) if record.requested_by.is_none() => {
+                    state.offboarding.remove(github_user)
+                }
+                _ => None,
+            }
+        };
+        self.save().await?;
+        Ok(cancelled)
+    }
+
+    pub async fn last_login(&self, github_user: &str) -> Option<DateTime<Utc>> {
+        self.state.read().await.last_login.get(github_user).copied()
+    }
+
+    /// Start counting inactivity for users with no recorded login (e.g. right after
+    /// offboarding was enabled), rather than treating them as inactive forever.
+    pub async fn seed_last_login(&self, github_users: &[String], now: DateTime<Utc>) -> Result<()> {
+        let changed = {
+            let mut state = self.state.write().await;
+            let mut changed = false;
+            for user in github_users {
+                if !state.last_login.contains_key(user) {
+                    state.last_login.insert(user.clone(), now);
+                    changed = true;
+                }
+            }
+            changed
+        };
+        if changed {
+            self.save().await?;
+        }
+        Ok(())
+    }
+
+    pub async fn offboarding(&self, github_user: &str) -> Option<OffboardingRecord> {
+        self.state.read().await.offboarding.get(github_user).cloned()
+    }
+
+    pub async fn list_offboarding(&self) -> Vec<(String, OffboardingRecord)> {
+        let state = self.state.read().await;
+        let mut records: Vec<_> = state
+            .offboarding
+            .iter()
+            .map(|(user, record)| (user.clone(), record.clone()))
+            .collect();
+        records.sort_by(|a, b| a.0.cmp(&b.0));
+        records
+    }
+
+    pub async fn set_offboarding(&self, github_user: &str, record: OffboardingRecord) -> Result<()> {
+        {
+            let mut state = self.state.write().await;
+            state.offboarding.insert(github_user.to_string(), record);
+        }
+        self.save().await
+    }
+
+    /// Stop offboarding a user. With `forget`, also drop their login history (after their
+    /// workspaces were destroyed, they'd start over as a new user).
+    pub async fn remove_offboarding(
         &self,
         github_user: &str,
-        project: &str,
-    ) -> Result<Option<WorkspaceInfo>> {
-        let key = WorkspaceInfo::key(github_user, project);
+        forget: bool,
+    ) -> Result<Option<OffboardingRecord>> {
         let removed = {
             let mut state = self.state.write().await;
-            state.workspaces.remove(&key)
+            if forget {
+                state.last_login.remove(github_user);
+            }
+            state.offboarding.remove(github_user)
         };
         self.save().await?;
         Ok(removed)
     }
+
+    /// Drop every cached key of a GitHub user (and their guest invites and preview links);
+    /// returns how many keys were removed. Their next login needs a fresh GitHub verification.
+    pub async fn revoke_keys(&self, github_user: &str) -> Result<usize> {
+        let removed = {
+            let mut state = self.state.write().await;
+            let before = state.key_to_github.len();
+            state
+                .key_to_github
+                .retain(|_, e| !e.github_username.eq_ignore_ascii_case(github_user));
+            state
+                .guests
+                .retain(|_, g| !g.github_user.eq_ignore_ascii_case(github_user));
+            state
+                .previews
+                .retain(|_, p| !p.github_user.eq_ignore_ascii_case(github_user));
+            before - state.key_to_github.len()
+        };
+        self.save().await?;
+        Ok(removed)
+    }
+
+    /// Drop every cached key; returns how many were removed. Each user's next login needs a
+    /// fresh GitHub verification.
+    pub async fn flush_keys(&self) -> Result<usize> {
+        let removed = {
+            let mut state = self.state.write().await;
+            let removed = state.key_to_github.len();
+            state.key_to_github.clear();
+            removed
+        };
+        self.save().await?;
+        Ok(removed)
+    }
+
+    /// Queue a message for `github_user`'s next interactive login.
+    pub async fn add_notice(&self, github_user: &str, message: String) -> Result<()> {
+        {
+            let mut state = self.state.write().await;
+            state
+                .notices
+                .entry(github_user.to_string())
+                .or_default()
+                .push(message);
+        }
+        self.save().await
+    }
+
+    /// Remove and return `github_user`'s pending notices.
+    pub async fn take_notices(&self, github_user: &str) -> Result<Vec<String>> {
+        let notices = {
+            let mut state = self.state.write().await;
+            state.notices.remove(github_user).unwrap_or_default()
+        };
+        if !notices.is_empty() {
+            self.save().await?;
+        }
+        Ok(notices)
+    }
+
+    pub async fn git_identity(&self, github_user: &str) -> Option<GitIdentity> {
+        self.state.read().await.git_identities.get(github_user).cloned()
+    }
+
+    /// Set or (with `None`) forget `github_user`'s git identity; returns the previous one.
+    pub async fn set_git_identity(
+        &self,
+        github_user: &str,
+        identity: Option<GitIdentity>,
+    ) -> Result<Option<GitIdentity>> {
+        let previous = {
+            let mut state = self.state.write().await;
+            match identity {
+                Some(identity) => state
+                    .git_identities
+                    .insert(github_user.to_string(), identity),
+                None => state.git_identities.remove(github_user),
+            }
+        };
+        self.save().await?;
+        Ok(previous)
+    }
+
+    pub async fn notifier(&self, github_user: &str) -> Option<Notifier> {
+        self.state.read().await.notifiers.get(github_user).cloned()
+    }
+
+    /// Set or (with `None`) forget `github_user`'s notifier; returns the previous one.
+    pub async fn set_notifier(
+        &self,
+        github_user: &str,
+        notifier: Option<Notifier>,
+    ) -> Result<Option<Notifier>> {
+        let previous = {
+            let mut state = self.state.write().await;
+            match notifier {
+                Some(notifier) => state.notifiers.insert(github_user.to_string(), notifier),
+                None => state.notifiers.remove(github_user),
+            }
+        };
+        self.save().await?;
+        Ok(previous)
+    }
+
+    /// Get (or allocate) the port block reserved for a workspace.
+    ///
+    /// An existing reservation is kept as long as it still fits the configured range and block
+    /// size; otherwise a new block is allocated. Fails when the range is exhausted.
+    pub async fn reserve_port_block(
+        &self,
+        github_user: &str,
+        project: &str,
+        range: (u16, u16),
+        block_size: u16,
+    ) -> Result<PortReservation> {
+        let key = WorkspaceInfo::key(github_user, project);
+        let reservation = {
+            let mut state = self.state.write().await;
+            if let Some(existing) = state.port_reservations.get(&key)
+                && existing.start >= range.0
+                && existing.end <= range.1
+                && u32::from(existing.end) - u32::from(existing.start) + 1 == u32::from(block_size)
+            {
+                return Ok(*existing);
+            }
+            let taken: Vec<PortReservation> = state
+                .port_reservations
+                .iter()
+                .filter(|(k, _)| **k != key)
+                .map(|(_, r)| *r)
+                .collect();
+            let reservation = allocate_port_block(&key, range, block_size, &taken).with_context(|| {
+                format!(
+                    "No free port block left in {}-{} ({} workspaces already hold one)",
+                    range.0,
+                    range.1,
+                    taken.len()
+                )
+            })?;
+            state.port_reservations.insert(key, reservation);
+            reservation
+        };
+        self.save().await?;
+        Ok(reservation)
+    }
+
+    /// Where the workspace was migrated to, if it no longer lives on this gateway.
+    pub async fn migration(&self, github_user: &str, project: &str) -> Option<MigrationRecord> {
+        let key = WorkspaceInfo::key(github_user, project);
+        self.state.read().await.migrations.get(&key).cloned()
+    }
+
+    /// Record that a workspace moved to another gateway, or (with `None`) that it lives here.
+    pub async fn set_migration(
+        &self,
+        github_user: &str,
+        project: &str,
+        record: Option<MigrationRecord>,
+    ) -> Result<()> {
+        let key = WorkspaceInfo::key(github_user, project);
+        {
+            let mut state = self.state.write().await;
+            match record {
+                Some(record) => state.migrations.insert(key, record),
+                None => state.migrations.remove(&key),
+            };
+        }
+        self.save().await
+    }
+
+    /// The operation in progress on a workspace, if any.
+    pub async fn operation(&self, github_user: &str, project: &str) -> Option<Operation> {
+        let state = self.state.read().await;
+        state
+            .operations
+            .get(&WorkspaceInfo::key(github_user, project))
+            .cloned()
+    }
+
+    /// Every operation in progress, keyed "github_user/project", sorted by key.
+    pub async fn operations(&self) -> Vec<(String, Operation)> {
+        let state = self.state.read().await;
+        let mut operations: Vec<_> = state
+            .operations
+            .iter()
+            .map(|(key, op)| (key.clone(), op.clone()))
+            .collect();
+        operations.sort_by(|a, b| a.0.cmp(&b.0));
+        operations
+    }
+
+    /// Record that `kind` starts on a workspace; fails while another operation is recorded
+    /// for it.
+    pub async fn begin_operation(
+        &self,
+        github_user: &str,
+        project: &str,
+        kind: OperationKind,
+        step: &str,
+    ) -> Result<()> {
+        let key = WorkspaceInfo::key(github_user, project);
+        {
+            let mut state = self.state.write().await;
+            if let Some(op) = state.operations.get(&key) {
+                anyhow::bail!(
+                    "a {} of {key} is {} since {}",
+                    op.kind,
+                    if op.stalled() { "unfinished" } else { "in progress" },
+                    op.started_at.format("%Y-%m-%d %H:%M UTC")
+                );
+            }
+            let op = Operation {
+                kind,
+                started_at: Utc::now(),
+                step: step.to_string(),
+                created_containers: Vec::new(),
+                error: None,
+                interrupted: false,
+            };
+            state.operations.insert(key, op);
+        }
+        self.save().await
+    }
+
+    /// Update the operation recorded for a workspace (nothing if there is none).
+    pub async fn update_operation(
+        &self,
+        github_user: &str,
+        project: &str,
+        update: impl FnOnce(&mut Operation),
+    ) -> Result<()> {
+        {
+            let mut state = self.state.write().await;
+            let key = WorkspaceInfo::key(github_user, project);
+            let Some(op) = state.operations.get_mut(&key) else {
+                return Ok(());
+            };
+            update(op);
+        }
+        self.save().await
+    }
+
+    /// Remove the operation recorded for a workspace.
+    pub async fn finish_operation(&self, github_user: &str, project: &str) -> Result<()> {
+        let removed = {
+            let mut state = self.state.write().await;
+            state
+                .operations
+                .remove(&WorkspaceInfo::key(github_user, project))
+        };
+        if removed.is_some() {
+            self.save().await?;
+        }
+        Ok(())
+    }
+
+    /// Mark every recorded operation as interrupted (at startup, before any can run); returns
+    /// them.
+    pub async fn interrupt_operations(&self) -> Result<Vec<(String, Operation)>> {
+        {
+            let mut state = self.state.write().await;
+            if state.operations.is_empty() {
+                return Ok(Vec::new());
+            }
+            for op in state.operations.values_mut() {
+                op.interrupted = true;
+            }
+        }
+        self.save().await?;
+        Ok(self.operations().await)
+    }
+
+    /// An unexpired guest invite.
+    pub async fn guest(&self, id: &str) -> Option<GuestInvite> {
+        let state = self.state.read().await;
+        state
+            .guests
+            .get(id)
+            .filter(|invite| invite.expires_at > Utc::now())
+            .cloned()
+    }
+
+    /// `github_user`'s devices, by ID.
+    pub async fn devices(&self, github_user: &str) -> Vec<Device> {
+        let state = self.state.read().await;
+        let mut devices = state.devices.get(github_user).cloned().unwrap_or_default();
+        devices.sort_by_key(|d| d.id);
+        devices
+    }
+
+    /// Record a login of `github_user` with the key `fingerprint` from `address`. Revoked
+    /// devices are returned as they are; beyond `max_devices`, the device unused the longest
+    /// (and not revoked) is forgotten.
+    pub async fn record_device(
+        &self,
+        github_user: &str,
+        fingerprint: &str,
+        address: &str,
+        max_devices: usize,
+        max_addresses: usize,
+    ) -> Result<DeviceLogin> {
+        let login = {
+            let mut state = self.state.write().await;
+            let devices = state.devices.entry(github_user.to_string()).or_default();
+            let now = Utc::now();
+            if let Some(device) = devices.iter_mut().find(|d| d.key_fingerprint == fingerprint) {
+                if device.revoked_at.is_some() {
+                    return Ok(DeviceLogin {
+                        device: device.clone(),
+                        new: false,
+                        first: false,
+                    });
+                }
+                device.last_seen = now;
+                device.addresses.retain(|a| a != address);
+                device.addresses.push(address.to_string());
+                let excess = device.addresses.len().saturating_sub(max_addresses);
+                device.addresses.drain(..excess);
+                DeviceLogin {
+                    device: device.clone(),
+                    new: false,
+                    first: false,
+                }
+            } else {
+                let device = Device {
+                    id: devices.iter().map(|d| d.id).max().unwrap_or(0) + 1,
+                    key_fingerprint: fingerprint.to_string(),
+                    addresses: vec![address.to_string()],
+                    first_seen: now,
+                    last_seen: now,
+                    revoked_at: None,
+                };
+                let first = devices.is_empty();
+                devices.push(device.clone());
+                while devices.iter().filter(|d| d.revoked_at.is_none()).count() > max_devices {
+                    let Some(oldest) = devices
+                        .iter()
+                        .filter(|d| d.revoked_at.is_none())
+                        .min_by_key(|d| d.last_seen)
+                        .map(|d| d.id)
+                    else {
+                        break;
+                    };
+                    devices.retain(|d| d.id != oldest);
+                }
+                DeviceLogin {
+                    device,
+                    new: true,
+                    first,
+                }
+            }
+        };
+        self.save().await?;
+        Ok(login)
+    }
+
+    /// Revoke (or with `revoked = false`, trust again) `github_user`'s device `id`; returns it.
+    pub async fn set_device_revoked(
+        &self,
+        github_user: &str,
+        id: u32,
+        revoked: bool,
+    ) -> Result<Option<Device>> {
+        let device = {
+            let mut state = self.state.write().await;
+            let Some(device) = state
+                .devices
+                .get_mut(github_user)
+                .and_then(|devices| devices.iter_mut().find(|d| d.id == id))
+            else {
+                return Ok(None);
+            };
+            device.revoked_at = revoked.then(Utc::now);
+            device.clone()
+        };
+        self.save().await?;
+        Ok(Some(device))
+    }
+
+    /// `github_user`'s unexpired guest invites, oldest first.
+    pub async fn list_guests(&self, github_user: &str) -> Vec<(String, GuestInvite)> {
+        let now = Utc::now();
+        let state = self.state.read().await;
+        let mut invites: Vec<_> = state
+            .guests
+            .iter()
+            .filter(|(_, invite)| invite.github_user == github_user && invite.expires_at > now)
+            .map(|(id, invite)| (id.clone(), invite.clone()))
+            .collect();
+        invites.sort_by_key(|(_, invite)| invite.created_at);
+        invites
+    }
+
+    /// Record a new guest invite, dropping expired ones.
+    pub async fn add_guest(&self, id: String, invite: GuestInvite) -> Result<()> {
+        {
+            let mut state = self.state.write().await;
+            let now = Utc::now();
+            state.guests.retain(|_, invite| invite.expires_at > now);
+            state.guests.insert(id, invite);
+        }
+        self.save().await
+    }
+
+    /// Bind `fingerprint` to an unexpired invite that has no key yet. Returns whether the
+    /// invite is (now) bound to that key.
+    pub async fn bind_guest_key(&self, id: &str, fingerprint: &str) -> Result<bool> {
+        {
+            let mut state = self.state.write().await;
+            let Some(invite) = state.guests.get_mut(id) else {
+                return Ok(false);
+            };
+            if invite.expires_at <= Utc::now() {
+                return Ok(false);
+            }
+            match invite.key_fingerprint {
+                Some(ref bound) => return Ok(bound == fingerprint),
+                None => invite.key_fingerprint = Some(fingerprint.to_string()),
+            }
+        }
+        self.save().await?;
+        Ok(true)
+    }
+
+    /// Remove `github_user`'s invite `id`, or (with `None`) all of their invites, along with
+    /// any expired ones. Returns the IDs of the user's removed unexpired invites.
+    pub async fn remove_guests(&self, github_user: &str, id: Option<&str>) -> Result<Vec<String>> {
+        let now = Utc::now();
+        let removed = {
+            let mut state = self.state.write().await;
+            let mut removed = Vec::new();
+            state.guests.retain(|key, invite| {
+                let matches = invite.github_user == github_user
+                    && id.is_none_or(|id| id == key.as_str());
+                if matches && invite.expires_at > now {
+                    removed.push(key.clone());
+                }
+                !matches && invite.expires_at > now
+            });
+            removed.sort();
+            removed
+        };
+        self.save().await?;
+        Ok(removed)
+    }
+
+    /// The language `github_user` picked, if any.
+    pub async fn language(&self, github_user: &str) -> Option<Lang> {
+        self.state.read().await.languages.get(github_user).copied()
+    }
+
+    /// Record `github_user`'s language, or (with `None`) go back to the gateway default.
+    pub async fn set_language(&self, github_user: &str, lang: Option<Lang>) -> Result<()> {
+        {
+            let mut state = self.state.write().await;
+            match lang {
+                Some(lang) => state.languages.insert(github_user.to_string(), lang),
+                None => state.languages.remove(github_user),
+            };
+        }
+        self.save().await
+    }
+
+    /// `github_user`'s preferences (all unset if they have none).
+    pub async fn preferences(&self, github_user: &str) -> UserPreferences {
+        self.state
+            .read()
+            .await
+            .preferences
+            .get(github_user)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Replace `github_user`'s preferences; with none set, the user's entry is dropped.
+    pub async fn set_preferences(&self, github_user: &str, prefs: UserPreferences) -> Result<()> {
+        {
+            let mut state = self.state.write().await;
+            if prefs == UserPreferences::default() {
+                state.preferences.remove(github_user);
+            } else {
+                state.preferences.insert(github_user.to_string(), prefs);
+            }
+        }
+        self.save().await
+    }
+
+    /// The workspace's login message.
+    pub async fn motd(&self, github_user: &str, project: &str) -> Option<Motd> {
+        let key = WorkspaceInfo::key(github_user, project);
+        self.state.read().await.motds.get(&key).cloned()
+    }
+
+    /// Set the workspace's login message, or (with `None`) remove it.
+    pub async fn set_motd(&self, github_user: &str, project: &str, motd: Option<Motd>) -> Result<()> {
+        let key = WorkspaceInfo::key(github_user, project);
+        {
+            let mut state = self.state.write().await;
+            match motd {
+                Some(motd) => state.motds.insert(key, motd),
+                None => state.motds.remove(&key),
+            };
+        }
+        self.save().await
+    }
+
+    /// Projects the workspace may run commands in (`agentman mesh`), sorted.
+    pub async fn mesh_targets(&self, github_user: &str, project: &str) -> Vec<String> {
+        let key = WorkspaceInfo::key(github_user, project);
+        self.state
+            .read()
+            .await
+            .mesh_grants
+            .get(&key)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Allow or disallow the workspace to run commands in `target`.
+    pub async fn set_mesh_target(
+        &self,
+        github_user: &str,
+        project: &str,
+        target: &str,
+        allowed: bool,
+    ) -> Result<()> {
+        let key = WorkspaceInfo::key(github_user, project);
+        {
+            let mut state = self.state.write().await;
+            let targets = state.mesh_grants.entry(key.clone()).or_default();
+            targets.retain(|t| t != target);
+            if allowed {
+                targets.push(target.to_string());
+                targets.sort();
+            }
+            if targets.is_empty() {
+                state.mesh_grants.remove(&key);
+            }
+        }
+        self.save().await
+    }
+
+    /// Workspaces allowed to run commands elsewhere, as (github_user, project).
+    pub async fn mesh_sources(&self) -> Vec<(String, String)> {
+        let state = self.state.read().await;
+        state
+            .mesh_grants
+            .keys()
+            .filter_map(|key| key.split_once('/'))
+            .map(|(user, project)| (user.to_string(), project.to_string()))
+            .collect()
+    }
+
+    /// An unexpired preview link.
+    pub async fn preview(&self, id: &str) -> Option<PreviewLink> {
+        let state = self.state.read().await;
+        state
+            .previews
+            .get(id)
+            .filter(|link| link.expires_at > Utc::now())
+            .cloned()
+    }
+
+    /// `github_user`'s unexpired preview links, oldest first.
+    pub async fn list_previews(&self, github_user: &str) -> Vec<(String, PreviewLink)> {
+        let now = Utc::now();
+        let state = self.state.read().await;
+        let mut links: Vec<_> = state
+            .previews
+            .iter()
+            .filter(|(_, link)| link.github_user == github_user && link.expires_at > now)
+            .map(|(id, link)| (id.clone(), link.clone()))
+            .collect();
+        links.sort_by_key(|(_, link)| link.created_at);
+        links
+    }
+
+    /// Record a new preview link, dropping expired ones.
+    pub async fn add_preview(&self, id: String, link: PreviewLink) -> Result<()> {
+        {
+            let mut state = self.state.write().await;
+            let now = Utc::now();
+            state.previews.retain(|_, link| link.expires_at > now);
+            state.previews.insert(id, link);
+        }
+        self.save().await
+    }
+
+    /// Remove `github_user`'s link `id`, or (with `None`) all of their links, along with any
+    /// expired ones. Returns the IDs of the user's removed unexpired links.
+    pub async fn remove_previews(&self, github_user: &str, id: Option<&str>) -> Result<Vec<String>> {
+        let now = Utc::now();
+        let removed = {
+            let mut state = self.state.write().await;
+            let mut removed = Vec::new();
+            state.previews.retain(|key, link| {
+                let matches =
+                    link.github_user == github_user && id.is_none_or(|id| id == key.as_str());
+                if matches && link.expires_at > now {
+                    removed.push(key.clone());
+                }
+                !matches && link.expires_at > now
+            });
+            removed.sort();
+            removed
+        };
+        self.save().await?;
+        Ok(removed)
+    }
+
+    /// `admin`'s unexpired access grant.
+    pub async fn admin_access(&self, admin: &str) -> Option<AdminAccessGrant> {
+        let state = self.state.read().await;
+        state
+            .admin_access
+            .get(admin)
+            .filter(|grant| grant.expires_at > Utc::now())
+            .cloned()
+    }
+
+    /// Record `admin`'s access grant, replacing their previous one and dropping expired ones.
+    pub async fn set_admin_access(&self, admin: &str, grant: AdminAccessGrant) -> Result<()> {
+        {
+            let mut state = self.state.write().await;
+            let now = Utc::now();
+            state.admin_access.retain(|_, grant| grant.expires_at > now);
+            state.admin_access.insert(admin.to_string(), grant);
+        }
+        self.save().await
+    }
+
+    /// A connection started using the workspace's sandbox.
+    pub fn connection_opened(&self, github_user: &str, project: &str) {
+        let mut activity = self.activity.lock().unwrap();
+        let entry = activity
+            .entry(WorkspaceInfo::key(github_user, project))
+            .or_insert(Activity {
+                connections: 0,
+                last_active: Utc::now(),
+            });
+        entry.connections += 1;
+        entry.last_active = Utc::now();
+    }
+
+    /// A connection using the workspace's sandbox ended.
+    pub fn connection_closed(&self, github_user: &str, project: &str) {
+        let mut activity = self.activity.lock().unwrap();
+        if let Some(entry) = activity.get_mut(&WorkspaceInfo::key(github_user, project)) {
+            entry.connections = entry.connections.saturating_sub(1);
+            entry.last_active = Utc::now();
+        }
+    }
+
+    /// How long the workspace has had no connections as of `now`; `None` while one is open.
+    /// Workspaces not seen before (e.g. after a gateway restart) start counting at `now`.
+    pub fn idle_for(
+        &self,
+        github_user: &str,
+        project: &str,
+        now: DateTime<Utc>,
+    ) -> Option<chrono::Duration> {
+        let mut activity = self.activity.lock().unwrap();
+        let entry = activity
+            .entry(WorkspaceInfo::key(github_user, project))
+            .or_insert(Activity {
+                connections: 0,
+                last_active: now,
+            });
+        (entry.connections == 0).then(|| now - entry.last_active)
+    }
+
+    /// Remove a workspace mapping (and persist the state file).
+    ///
+    /// Returns the removed workspace info, if it existed.
+    pub async fn remove_workspace(
+        &self,
+        github_user: &str,
+        project: &str,
+    ) -> Result<Option<WorkspaceInfo>> {
+        let key = WorkspaceInfo::key(github_user, project);
+        let removed = {
+            let mut state = self.state.write().await;
+            let removed = state.workspaces.remove(&key);
+            state.port_reservations.remove(&key);
+            state.motds.remove(&key);
+            state.mesh_grants.remove(&key);
+            state
+                .guests
+                .retain(|_, g| g.github_user != github_user || g.project != project);
+            state
+                .previews
+                .retain(|_, p| p.github_user != github_user || p.project != project);
+            // Drop dangling links from the user's other workspaces.
+            for ws in state.workspaces.values_mut() {
+                if ws.github_user == github_user {
+                    ws.linked_projects.retain(|p| p != project);
+                }
+            }
+            let prefix = format!("{github_user}/");
+            for (key, targets) in state.mesh_grants.iter_mut() {
+                if key.starts_with(&prefix) {
+                    targets.retain(|p| p != project);
+                }
+            }
+            state.mesh_grants.retain(|_, targets| !targets.is_empty());
+            removed
+        };
+        self.save().await?;
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocate_port_block_is_deterministic() {
+        let a = allocate_port_block("octocat/web", (20000, 20999), 10, &[]).unwrap();
+        let b = allocate_port_block("octocat/web", (20000, 20999), 10, &[]).unwrap();
+        assert_eq!(a, b);
+        assert_eq!(a.end - a.start + 1, 10);
+        assert_eq!((a.start - 20000) % 10, 0);
+        assert!(a.end <= 20999);
+    }
+
+    #[tokio::test]
+    async fn test_login_lifts_inactivity_flag_only() {
+        let path = std::env::temp_dir().join(format!("agentman-state-test-{}.json", std::process::id()));
+        let state = StateManager::load(path.clone()).await.unwrap();
+        let record = |requested_by: Option<&str>| OffboardingRecord {
+            flagged_at: Utc::now(),
+            requested_by: requested_by.map(str::to_string),
+            archived_at: None,
+            keys_revoked_at: None,
+            destroyed_at: None,
+        };
+
+        state.set_offboarding("idle", record(None)).await.unwrap();
+        state.set_offboarding("gone", record(Some("admin"))).await.unwrap();
+        assert!(state.record_login("idle").await.unwrap().is_some());
+        assert!(state.record_login("gone").await.unwrap().is_none());
+        assert!(state.offboarding("idle").await.is_none());
+        assert!(state.offboarding("gone").await.is_some());
+        assert!(state.last_login("idle").await.is_some());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_allocate_port_block_skips_taken() {
+        let first = allocate_port_block("octocat/web", (20000, 20019), 10, &[]).unwrap();
+        let second = allocate_port_block("octocat/api", (20000, 20019), 10, &[first]).unwrap();
+        assert_ne!(first, second);
+        assert!(allocate_port_block("octocat/db", (20000, 20019), 10, &[first, second]).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_idle_for_counts_from_last_connection() {
+        let path = std::env::temp_dir().join(format!("agentman-state-idle-{}.json", std::process::id()));
+        let state = StateManager::load(path.clone()).await.unwrap();
+        let later = |secs| Utc::now() + chrono::Duration::seconds(secs);
+
+        // Unseen workspaces start counting when first checked.
+        let first = later(0);
+        assert_eq!(state.idle_for("octocat", "web", first), Some(chrono::Duration::zero()));
+
+        state.connection_opened("octocat", "web");
+        state.connection_opened("octocat", "web");
+        assert_eq!(state.idle_for("octocat", "web", later(600)), None);
+        state.connection_closed("octocat", "web");
+        assert_eq!(state.idle_for("octocat", "web", later(600)), None);
+        state.connection_closed("octocat", "web");
+        assert!(state.idle_for("octocat", "web", later(600)).unwrap() >= chrono::Duration::seconds(599));
+        let _ = std::fs::remove_file(path);
+    }
+    #[tokio::test]
+    async fn test_mesh_grants_go_with_workspaces() {
+        let path =
+            std::env::temp_dir().join(format!("agentman-state-mesh-{}.json", std::process::id()));
+        let state = StateManager::empty(path.clone());
+        state.set_mesh_target("octocat", "web", "db", true).await.unwrap();
+        state.set_mesh_target("octocat", "web", "api", true).await.unwrap();
+        state.set_mesh_target("octocat", "api", "db", true).await.unwrap();
+        assert_eq!(state.mesh_targets("octocat", "web").await, ["api", "db"]);
+
+        state.remove_workspace("octocat", "db").await.unwrap();
+        assert_eq!(state.mesh_targets("octocat", "web").await, ["api"]);
+        assert_eq!(state.mesh_sources().await, [("octocat".to_string(), "web".to_string())]);
+        state.set_mesh_target("octocat", "web", "api", false).await.unwrap();
+        assert!(state.mesh_sources().await.is_empty());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn test_record_device_limits() {
+        let state = StateManager::empty(std::env::temp_dir().join(format!(
+            "agentman-state-devices-{}.json",
+            std::process::id()
+        )));
+        let login = state.record_device("octocat", "SHA256:a", "10.0.0.1", 2, 2).await.unwrap();
+        assert!(login.new && login.first);
+        for address in ["10.0.0.2", "10.0.0.3", "10.0.0.2"] {
+            state.record_device("octocat", "SHA256:a", address, 2, 2).await.unwrap();
+        }
+        assert_eq!(state.devices("octocat").await[0].addresses, vec!["10.0.0.3", "10.0.0.2"]);
+
+        // Revoked devices are kept; the one unused the longest goes.
+        state.set_device_revoked("octocat", 1, true).await.unwrap();
+        state.record_device("octocat", "SHA256:b", "10.0.0.1", 2, 2).await.unwrap();
+        state.record_device("octocat", "SHA256:c", "10.0.0.1", 2, 2).await.unwrap();
+        let login = state.record_device("octocat", "SHA256:d", "10.0.0.1", 2, 2).await.unwrap();
+        assert_eq!(login.device.id, 4);
+        let ids: Vec<u32> = state.devices("octocat").await.iter().map(|d| d.id).collect();
+        assert_eq!(ids, vec![1, 3, 4]);
+        let login = state.record_device("octocat", "SHA256:a", "10.0.0.9", 2, 2).await.unwrap();
+        assert!(login.device.revoked_at.is_some());
+        assert_eq!(login.device.addresses, vec!["10.0.0.3", "10.0.0.2"]);
+        let _ = std::fs::remove_file(state.path.clone());
+    }
+
+    #[tokio::test]
+    async fn test_operations_survive_restarts() {
+        let path =
+            std::env::temp_dir().join(format!("agentman-state-ops-{}.json", std::process::id()));
+        let state = StateManager::empty(path.clone());
+        state
+            .begin_operation("octocat", "web", OperationKind::Recreate, "creating container")
+            .await
+            .unwrap();
+        // One operation per workspace at a time.
+        let destroy = OperationKind::Destroy {
+            keep_workspace: false,
+        };
+        assert!(state
+            .begin_operation("octocat", "web", destroy, "removing containers")
+            .await
+            .is_err());
+        state
+            .update_operation("octocat", "web", |op| op.created_containers.push("c1".into()))
+            .await
+            .unwrap();
+
+        let restarted = StateManager::load(path.clone()).await.unwrap();
+        let interrupted = restarted.interrupt_operations().await.unwrap();
+        assert_eq!(interrupted.len(), 1);
+        let (key, op) = &interrupted[0];
+        assert_eq!(key, "octocat/web");
+        assert!(op.stalled());
+        assert_eq!(op.step, "creating container");
+        assert_eq!(op.created_containers, ["c1"]);
+
+        restarted.finish_operation("octocat", "web").await.unwrap();
+        assert!(restarted.operation("octocat", "web").await.is_none());
+        let _ = std::fs::remove_file(path);
+    }
 }
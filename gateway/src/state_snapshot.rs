@@ -0,0 +1,307 @@
+//! Versioned snapshots of the state store (`agentman admin state export|import`,
+//! `--export-state`, `--import-state`).
+//!
+//! A snapshot wraps the state in an envelope with a format name, a version and a SHA-256 of
+//! the state, so that a truncated or hand-edited file is refused instead of silently replacing
+//! the gateway's key cache and workspace map. The state inside is independent of how the
+//! gateway stores it, which makes snapshots the way to move a gateway to another host or
+//! storage backend, and to rehearse disaster recovery. Importing validates everything first
+//! and keeps the previous state file next to the new one.
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::github::{validate_github_username, validate_project_name};
+use crate::state::{GatewayState, PortReservation, StateManager, WorkspaceInfo};
+
+/// Identifies agentman state snapshots.
+const FORMAT: &str = "agentman-state";
+
+/// Snapshot version written by this gateway; newer snapshots are refused.
+const VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Snapshot {
+    format: String,
+    version: u32,
+    exported_at: DateTime<Utc>,
+    /// Version of the gateway that wrote the snapshot, for the record.
+    gateway_version: String,
+    /// Hex SHA-256 of `state` serialized compactly (object keys sorted).
+    sha256: String,
+    state: serde_json::Value,
+}
+
+fn checksum(state: &serde_json::Value) -> String {
+    let digest = Sha256::digest(state.to_string().as_bytes());
+    digest.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// The gateway's state as a snapshot document.
+pub async fn export(state: &StateManager) -> Result<String> {
+    let state = state.to_json().await?;
+    let snapshot = Snapshot {
+        format: FORMAT.to_string(),
+        version: VERSION,
+        exported_at: Utc::now(),
+        gateway_version: env!("CARGO_PKG_VERSION").to_string(),
+        sha256: checksum(&state),
+        state,
+    };
+    serde_json::to_string_pretty(&snapshot).context("Failed to serialize snapshot")
+}
+
+/// Parse and check a snapshot document; fails with every problem found.
+pub fn parse(content: &str) -> Result<GatewayState> {
+    let snapshot: Snapshot = serde_json::from_str(content).context("Not a state snapshot")?;
+    if snapshot.format != FORMAT {
+        anyhow::bail!("Not a state snapshot (format {:?})", snapshot.format);
+    }
+    if snapshot.version == 0 || snapshot.version > VERSION {
+        anyhow::bail!(
+            "Snapshot version {} is not supported by this gateway (up to {VERSION})",
+            snapshot.version
+        );
+    }
+    if checksum(&snapshot.state) != snapshot.sha256 {
+        anyhow::bail!("Snapshot checksum mismatch: the file was modified or truncated");
+    }
+    let state: GatewayState =
+        serde_json::from_value(snapshot.state).context("Invalid state in snapshot")?;
+    let problems = problems(&state);
+    if !problems.is_empty() {
+        anyhow::bail!("Invalid state in snapshot:\n  {}", problems.join("\n  "));
+    }
+    Ok(state)
+}
+
+/// Inconsistencies the gateway would trip over: entries it can't address, and port blocks
+/// handed out twice.
+fn problems(state: &GatewayState) -> Vec<String> {
+    let mut problems = Vec::new();
+    let check_user = |problems: &mut Vec<String>, what: &str, user: &str| {
+        if let Err(e) = validate_github_username(user) {
+            problems.push(format!("{what}: {e}"));
+        }
+    };
+    for (fingerprint, entry) in &state.key_to_github {
+        check_user(
+            &mut problems,
+            &format!("key {fingerprint}"),
+            &entry.github_username,
+        );
+    }
+    for user in state
+        .last_login
+        .keys()
+        .chain(state.offboarding.keys())
+        .chain(state.notices.keys())
+        .chain(state.git_identities.keys())
+        .chain(state.notifiers.keys())
+        .chain(state.languages.keys())
+        .chain(state.preferences.keys())
+        .chain(state.admin_access.keys())
+        .chain(state.admin_access.values().map(|grant| &grant.github_user))
+    {
+        check_user(&mut problems, &format!("user {user}"), user);
+    }
+    for (key, ws) in &state.workspaces {
+        check_user(&mut problems, &format!("workspace {key}"), &ws.github_user);
+        if let Err(e) = validate_project_name(&ws.project) {
+            problems.push(format!("workspace {key}: {e}"));
+        }
+        if *key != WorkspaceInfo::key(&ws.github_user, &ws.project) {
+            problems.push(format!(
+                "workspace {key}: belongs to {}/{}",
+                ws.github_user, ws.project
+            ));
+        }
+    }
+    let mut blocks: Vec<_> = state.port_reservations.iter().collect();
+    blocks.sort_by_key(|(key, block)| (block.start, *key));
+    // The block reaching furthest so far; later blocks starting before its end overlap it.
+    let mut furthest: Option<(&String, &PortReservation)> = None;
+    for (key, block) in blocks {
+        if block.start > block.end {
+            problems.push(format!("port reservation of {key}: {block} is empty"));
+            continue;
+        }
+        if let Some((other, reach)) = furthest
+            && block.start <= reach.end
+        {
+            problems.push(format!(
+                "port reservations of {other} ({reach}) and {key} ({block}) overlap"
+            ));
+        }
+        if furthest.is_none_or(|(_, reach)| block.end > reach.end) {
+            furthest = Some((key, block));
+        }
+    }
+    problems
+}
+
+/// What a state holds, in a line.
+pub fn summary(state: &GatewayState) -> String {
+    let users = state
+        .workspaces
+        .values()
+        .map(|ws| ws.github_user.as_str())
+        .chain(
+            state
+                .key_to_github
+                .values()
+                .map(|e| e.github_username.as_str()),
+        )
+        .collect::<HashSet<_>>()
+        .len();
+    format!(
+        "{} users, {} cached keys, {} workspaces, {} port reservations, {} being offboarded",
+        users,
+        state.key_to_github.len(),
+        state.workspaces.len(),
+        state.port_reservations.len(),
+        state.offboarding.len()
+    )
+}
+
+/// Replace the gateway's state with `imported`. The previous state file is kept as
+/// `<state file>.pre-import-<time>`, whose path is returned.
+pub async fn import(state: &StateManager, imported: GatewayState) -> Result<Option<PathBuf>> {
+    let path = state.path();
+    let backup = if tokio::fs::try_exists(path).await.unwrap_or(false) {
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(format!(
+            ".pre-import-{}",
+            Utc::now().format("%Y%m%dT%H%M%SZ")
+        ));
+        let backup = PathBuf::from(backup);
+        tokio::fs::copy(path, &backup)
+            .await
+            .with_context(|| format!("Failed to back up {}", path.display()))?;
+        Some(backup)
+    } else {
+        None
+    };
+    state.replace(imported).await?;
+    Ok(backup)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::KeyCacheEntry;
+
+    fn workspace(github_user: &str, project: &str) -> WorkspaceInfo {
+        WorkspaceInfo {
+            github_user: github_user.to_string(),
+            project: project.to_string(),
+            container_name: format!("agentman-{github_user}-{project}"),
+            container_id: None,
+            created_at: Utc::now(),
+            host_workspace_path: PathBuf::from("/srv/workspaces")
+                .join(github_user)
+                .join(project),
+            linked_projects: Vec::new(),
+            init_command: None,
+            init_status: None,
+            image: None,
+            tls_port: None,
+            watch_ownership: false,
+            clone: None,
+            resources: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_export_import_roundtrip() {
+        let dir =
+            std::env::temp_dir().join(format!("agentman-snapshot-test-{}", std::process::id()));
+        let source = StateManager::load(dir.join("source.json")).await.unwrap();
+        source
+            .cache_key(
+                "SHA256:abc".to_string(),
+                KeyCacheEntry {
+                    github_username: "octocat".to_string(),
+                    verified_at: Utc::now(),
+                    key_type: "ssh-ed25519".to_string(),
+                },
+            )
+            .await
+            .unwrap();
+        source
+            .set_workspace(workspace("octocat", "web"))
+            .await
+            .unwrap();
+        let snapshot = export(&source).await.unwrap();
+
+        let imported = parse(&snapshot).unwrap();
+        assert_eq!(
+            summary(&imported),
+            "1 users, 1 cached keys, 1 workspaces, 0 port reservations, 0 being offboarded"
+        );
+        let target = StateManager::load(dir.join("target.json")).await.unwrap();
+        target.save().await.unwrap();
+        let backup = import(&target, imported).await.unwrap().unwrap();
+        assert!(backup.exists());
+        assert!(target.get_workspace("octocat", "web").await.is_some());
+        assert_eq!(
+            StateManager::load(dir.join("target.json"))
+                .await
+                .unwrap()
+                .get_github_user("SHA256:abc")
+                .await
+                .unwrap()
+                .github_username,
+            "octocat"
+        );
+
+        // Edited or truncated snapshots are refused.
+        let edited = snapshot.replace("octocat", "hubot");
+        assert!(parse(&edited).unwrap_err().to_string().contains("checksum"));
+        assert!(parse(&snapshot[..snapshot.len() / 2]).is_err());
+        let newer = snapshot.replace("\"version\": 1", "\"version\": 2");
+        assert!(parse(&newer).unwrap_err().to_string().contains("version 2"));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_problems() {
+        let mut state = GatewayState::default();
+        state
+            .workspaces
+            .insert("octocat/web".to_string(), workspace("octocat", "web"));
+        state
+            .workspaces
+            .insert("octocat/api".to_string(), workspace("hubot", "api"));
+        state
+            .workspaces
+            .insert("-bad/x".to_string(), workspace("-bad", "x"));
+        state.port_reservations.insert(
+            "octocat/web".to_string(),
+            PortReservation {
+                start: 20000,
+                end: 20009,
+            },
+        );
+        state.port_reservations.insert(
+            "hubot/api".to_string(),
+            PortReservation {
+                start: 20005,
+                end: 20014,
+            },
+        );
+        let problems = problems(&state);
+        assert_eq!(problems.len(), 3, "{problems:?}");
+        assert!(problems.contains(&"workspace octocat/api: belongs to hubot/api".to_string()));
+        assert!(problems.iter().any(|p| p.starts_with("workspace -bad/x:")));
+        assert!(problems.contains(
+            &"port reservations of octocat/web (20000-20009) and hubot/api (20005-20014) overlap"
+                .to_string()
+        ));
+    }
+}
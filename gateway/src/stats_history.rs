@@ -0,0 +1,387 @@
+//! Resource history for `agentman stats --history`.
+//!
+//! A background sampler records CPU and memory of every running workspace container into a
+//! fixed-size ring buffer file per workspace (`<dir>/<github_user>/<project>.stats`), so disk
+//! use is bounded no matter how long the gateway runs. The file is a small header followed by
+//! `capacity` fixed-width records; the oldest record is overwritten once it is full.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use futures::future::join_all;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom};
+use tracing::{debug, info, warn};
+
+use crate::config::StatsHistoryConfig;
+use crate::docker::ContainerManager;
+use crate::gateway_control::{container_stats_line, workspace_container_status_with_running};
+use crate::notify;
+
+const MAGIC: &[u8; 4] = b"AMSH";
+const VERSION: u32 = 1;
+/// magic, version, capacity, next slot, stored samples.
+const HEADER_LEN: u64 = 20;
+/// timestamp (i64), cpu in hundredths of a percent (u32), reserved (u32), mem, mem limit (u64).
+const RECORD_LEN: u64 = 32;
+
+/// Sparklines are at most this many characters wide.
+const SPARKLINE_WIDTH: usize = 60;
+const SPARK_CHARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// One resource sample.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    /// Unix timestamp (seconds).
+    pub ts: i64,
+    /// CPU usage in percent of one core (may exceed 100 on multi-core hosts).
+    pub cpu_percent: f64,
+    pub mem_bytes: u64,
+    pub mem_limit: u64,
+}
+
+impl Sample {
+    fn encode(&self) -> [u8; RECORD_LEN as usize] {
+        let mut buf = [0u8; RECORD_LEN as usize];
+        buf[0..8].copy_from_slice(&self.ts.to_le_bytes());
+        let cpu = (self.cpu_percent.max(0.0) * 100.0).round().min(u32::MAX as f64) as u32;
+        buf[8..12].copy_from_slice(&cpu.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.mem_bytes.to_le_bytes());
+        buf[24..32].copy_from_slice(&self.mem_limit.to_le_bytes());
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Self {
+        let u64_at = |i: usize| u64::from_le_bytes(buf[i..i + 8].try_into().unwrap());
+        Self {
+            ts: u64_at(0) as i64,
+            cpu_percent: u32::from_le_bytes(buf[8..12].try_into().unwrap()) as f64 / 100.0,
+            mem_bytes: u64_at(16),
+            mem_limit: u64_at(24),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Header {
+    capacity: u32,
+    next: u32,
+    len: u32,
+}
+
+impl Header {
+    fn encode(&self) -> [u8; HEADER_LEN as usize] {
+        let mut buf = [0u8; HEADER_LEN as usize];
+        buf[0..4].copy_from_slice(MAGIC);
+        buf[4..8].copy_from_slice(&VERSION.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.capacity.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.next.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.len.to_le_bytes());
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let u32_at = |i: usize| u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+        if buf.len() < HEADER_LEN as usize || &buf[0..4] != MAGIC || u32_at(4) != VERSION {
+            return None;
+        }
+        let header = Self {
+            capacity: u32_at(8),
+            next: u32_at(12),
+            len: u32_at(16),
+        };
+        (header.capacity > 0 && header.next < header.capacity && header.len <= header.capacity)
+            .then_some(header)
+    }
+}
+
+/// Ring buffer file for a workspace.
+pub fn history_path(config: &StatsHistoryConfig, github_user: &str, project: &str) -> PathBuf {
+    config.dir.join(github_user).join(format!("{project}.stats"))
+}
+
+/// Append a sample, creating the file (or resetting it if the capacity changed).
+pub async fn append(path: &Path, capacity: u32, sample: &Sample) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    let mut file = tokio::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .await
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+
+    let mut buf = [0u8; HEADER_LEN as usize];
+    let existing = match file.read_exact(&mut buf).await {
+        Ok(_) => Header::decode(&buf).filter(|h| h.capacity == capacity),
+        Err(_) => None,
+    };
+    let mut header = match existing {
+        Some(header) => header,
+        None => {
+            debug!("Initializing stats history {}", path.display());
+            file.set_len(0).await?;
+            Header {
+                capacity,
+                next: 0,
+                len: 0,
+            }
+        }
+    };
+
+    file.seek(SeekFrom::Start(HEADER_LEN + header.next as u64 * RECORD_LEN))
+        .await?;
+    file.write_all(&sample.encode()).await?;
+
+    header.next = (header.next + 1) % header.capacity;
+    header.len = (header.len + 1).min(header.capacity);
+    file.seek(SeekFrom::Start(0)).await?;
+    file.write_all(&header.encode()).await?;
+    file.flush().await?;
+    Ok(())
+}
+
+/// Read all samples, oldest first. A missing file has no samples.
+pub async fn read(path: &Path) -> Result<Vec<Sample>> {
+    let bytes = match tokio::fs::read(path).await {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    Ok(decode_samples(&bytes))
+}
+
+fn decode_samples(bytes: &[u8]) -> Vec<Sample> {
+    let Some(header) = Header::decode(bytes) else {
+        return Vec::new();
+    };
+    // Oldest sample: slot 0 until the buffer wraps, then the next slot to be overwritten.
+    let start = if header.len < header.capacity { 0 } else { header.next };
+    (0..header.len)
+        .filter_map(|i| {
+            let slot = (start + i) % header.capacity;
+            let offset = (HEADER_LEN + slot as u64 * RECORD_LEN) as usize;
+            bytes
+                .get(offset..offset + RECORD_LEN as usize)
+                .map(Sample::decode)
+        })
+        .collect()
+}
+
+/// Delete a workspace's history (on destroy).
+pub async fn remove(config: &StatsHistoryConfig, github_user: &str, project: &str) {
+    let path = history_path(config, github_user, project);
+    match tokio::fs::remove_file(&path).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => warn!("Failed to remove {}: {}", path.display(), e),
+    }
+}
+
+/// Parse a history window like `90s`, `30m`, `1h` or `2d` into seconds.
+pub fn parse_window(s: &str) -> Option<u64> {
+    let (num, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let n: u64 = num.parse().ok()?;
+    let secs = match unit {
+        "" | "s" => n,
+        "m" => n.checked_mul(60)?,
+        "h" => n.checked_mul(3600)?,
+        "d" => n.checked_mul(86400)?,
+        _ => return None,
+    };
+    (secs > 0).then_some(secs)
+}
+
+/// Format a window in seconds the way users type it.
+pub fn format_window(secs: u64) -> String {
+    match secs {
+        s if s % 86400 == 0 => format!("{}d", s / 86400),
+        s if s % 3600 == 0 => format!("{}h", s / 3600),
+        s if s % 60 == 0 => format!("{}m", s / 60),
+        s => format!("{s}s"),
+    }
+}
+
+/// Min / average / max of a series.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Summary {
+    pub min: f64,
+    pub avg: f64,
+    pub max: f64,
+}
+
+pub fn summarize(values: impl IntoIterator<Item = f64>) -> Option<Summary> {
+    let (mut min, mut max, mut sum, mut n) = (f64::INFINITY, f64::NEG_INFINITY, 0.0, 0usize);
+    for v in values {
+        min = min.min(v);
+        max = max.max(v);
+        sum += v;
+        n += 1;
+    }
+    (n > 0).then(|| Summary {
+        min,
+        avg: sum / n as f64,
+        max,
+    })
+}
+
+/// Sparkline over `[since, until)`: one character per time bucket showing the bucket's peak,
+/// scaled to the series maximum. Buckets without samples (container stopped) are blank.
+pub fn sparkline(points: &[(i64, f64)], since: i64, until: i64, width: usize) -> String {
+    let width = width.clamp(1, SPARKLINE_WIDTH);
+    let span = (until - since).max(1) as f64;
+    let mut buckets: Vec<Option<f64>> = vec![None; width];
+    for &(ts, v) in points {
+        if ts < since || ts >= until {
+            continue;
+        }
+        let i = (((ts - since) as f64 / span) * width as f64) as usize;
+        let bucket = &mut buckets[i.min(width - 1)];
+        *bucket = Some(bucket.map_or(v, |b: f64| b.max(v)));
+    }
+
+    let peak = buckets.iter().flatten().copied().fold(0.0, f64::max);
+    buckets
+        .iter()
+        .map(|b| match b {
+            None => ' ',
+            Some(_) if peak <= 0.0 => SPARK_CHARS[0],
+            Some(v) => {
+                let level = ((v / peak) * (SPARK_CHARS.len() - 1) as f64).round() as usize;
+                SPARK_CHARS[level.min(SPARK_CHARS.len() - 1)]
+            }
+        })
+        .collect()
+}
+
+/// Sample every running workspace container every `interval_secs` (no-op when disabled).
+pub fn spawn_sampler(container_manager: Arc<ContainerManager>) {
+    let config = container_manager.config().stats_history.clone();
+    if !config.enabled {
+        return;
+    }
+    info!(
+        "Recording stats history every {}s ({}h retention)",
+        config.interval_secs, config.retention_hours
+    );
+
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(Duration::from_secs(config.interval_secs));
+        tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            tick.tick().await;
+            let state = container_manager.state();
+            let mut workspaces = Vec::new();
+            for github_user in state.list_github_users().await {
+                workspaces.extend(state.list_workspaces(&github_user).await);
+            }
+
+            let cm = container_manager.as_ref();
+            let config = &config;
+            let errors = join_all(workspaces.into_iter().map(|ws| async move {
+                let target = ws.container_id.clone().unwrap_or(ws.container_name.clone());
+                let (_, _, running) = workspace_container_status_with_running(cm, &target).await;
+                if !running {
+                    return None;
+                }
+                let (cpu_percent, mem) = container_stats_line(cm, &target).await?;
+                let (mem_bytes, mem_limit) = mem.unwrap_or((0, 0));
+                let sample = Sample {
+                    ts: Utc::now().timestamp(),
+                    cpu_percent,
+                    mem_bytes,
+                    mem_limit,
+                };
+                let path = history_path(config, &ws.github_user, &ws.project);
+                let result = append(&path, config.capacity(), &sample).await;
+                if let Err(ref e) = result {
+                    warn!("Failed to record stats for {}/{}: {:#}", ws.github_user, ws.project, e);
+                }
+                result.err()
+            }))
+            .await;
+            let notify_config = &cm.config().notify;
+            match errors.into_iter().flatten().last() {
+                Some(e) => {
+                    notify::task_failed(notify_config, "stats_history", &format!("{e:#}")).await
+                }
+                None => notify::task_succeeded(notify_config, "stats_history").await,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(ts: i64) -> Sample {
+        Sample {
+            ts,
+            cpu_percent: ts as f64 / 2.0,
+            mem_bytes: ts as u64 * 1024,
+            mem_limit: 1 << 30,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ring_buffer_wraps() {
+        let path = std::env::temp_dir().join(format!("agentman-stats-{}.stats", std::process::id()));
+        let _ = tokio::fs::remove_file(&path).await;
+
+        for ts in 1..=3 {
+            append(&path, 5, &sample(ts)).await.unwrap();
+        }
+        let ts: Vec<_> = read(&path).await.unwrap().iter().map(|s| s.ts).collect();
+        assert_eq!(ts, vec![1, 2, 3]);
+
+        for ts in 4..=12 {
+            append(&path, 5, &sample(ts)).await.unwrap();
+        }
+        let samples = read(&path).await.unwrap();
+        assert_eq!(samples.iter().map(|s| s.ts).collect::<Vec<_>>(), vec![8, 9, 10, 11, 12]);
+        assert_eq!(samples[0], sample(8));
+        let len = tokio::fs::metadata(&path).await.unwrap().len();
+        assert_eq!(len, HEADER_LEN + 5 * RECORD_LEN);
+
+        // A capacity change starts over rather than misreading old slots.
+        append(&path, 3, &sample(13)).await.unwrap();
+        let ts: Vec<_> = read(&path).await.unwrap().iter().map(|s| s.ts).collect();
+        assert_eq!(ts, vec![13]);
+
+        let _ = tokio::fs::remove_file(&path).await;
+    }
+
+    #[test]
+    fn test_parse_window() {
+        assert_eq!(parse_window("1h"), Some(3600));
+        assert_eq!(parse_window("30m"), Some(1800));
+        assert_eq!(parse_window("2d"), Some(172800));
+        assert_eq!(parse_window("90"), Some(90));
+        assert_eq!(parse_window("0h"), None);
+        assert_eq!(parse_window("1w"), None);
+        assert_eq!(parse_window("h"), None);
+        assert_eq!(format_window(3600), "1h");
+        assert_eq!(format_window(90), "90s");
+    }
+
+    #[test]
+    fn test_sparkline_and_summary() {
+        let points = [(0, 1.0), (1, 2.0), (2, 8.0), (3, 4.0)];
+        assert_eq!(sparkline(&points, 0, 4, 4), "▂▃█▅");
+        // Gaps stay blank; each bucket shows its peak.
+        assert_eq!(sparkline(&points[..2], 0, 4, 2), "█ ");
+        assert_eq!(sparkline(&[(0, 0.0)], 0, 2, 2), "▁ ");
+
+        let s = summarize(points.iter().map(|p| p.1)).unwrap();
+        assert_eq!((s.min, s.avg, s.max), (1.0, 3.75, 8.0));
+        assert!(summarize(std::iter::empty()).is_none());
+    }
+}
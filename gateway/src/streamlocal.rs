@@ -0,0 +1,136 @@
+//! Unix socket forwarding (`direct-streamlocal@openssh.com`, `streamlocal-forward@openssh.com`).
+//!
+//! `ssh -L <local>:<socket>` connects the client to a Unix socket in the sandbox (a language
+//! server's, a Docker socket stand-in), through a bridge exec like TCP forwards (see
+//! [`crate::tcp_bridge`]). `ssh -R <socket>:<local>` creates a socket in the sandbox whose
+//! connections the client serves (a gpg-agent, a local Docker daemon). The gateway creates
+//! that socket in the workspace directory on the host, like the agent forwarding one, so it
+//! can only be a direct child of `/workspace`: deeper paths go through directories the sandbox
+//! controls. Both directions are limited to `[port_forwarding] streamlocal_paths`.
+
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use tokio::net::UnixListener;
+
+use crate::config::{GatewayConfig, PortForwardingConfig};
+use crate::host_fs;
+
+/// Where the workspace directory is mounted in sandboxes.
+const WORKSPACE_MOUNT: &str = "/workspace";
+
+/// Longest socket path (`sun_path` holds 108 bytes, including the terminating NUL).
+const MAX_PATH_LEN: usize = 107;
+
+/// Why `path` can't be a socket path (or, ending in `/`, a directory of them).
+pub fn check_path(path: &str) -> Result<(), &'static str> {
+    if !path.starts_with('/') {
+        return Err("must be an absolute path");
+    }
+    if path.len() > MAX_PATH_LEN {
+        return Err("is longer than a Unix socket path can be");
+    }
+    // Bridge tools read options after `,` (socat) or `-` (nc), so stick to plain names.
+    let plain = |c: char| c.is_ascii_alphanumeric() || "/._-+@".contains(c);
+    if !path.chars().all(plain) {
+        return Err("may only contain letters, digits and / . _ - + @");
+    }
+    if path.split('/').any(|part| part == "..") {
+        return Err("can't contain ..");
+    }
+    Ok(())
+}
+
+/// Whether clients may forward to or create the socket `path`.
+pub fn allowed(config: &PortForwardingConfig, path: &str) -> bool {
+    check_path(path).is_ok()
+        && !path.ends_with('/')
+        && config.streamlocal_paths.iter().any(|entry| {
+            if entry.ends_with('/') {
+                path.starts_with(entry.as_str())
+            } else {
+                path == entry
+            }
+        })
+}
+
+/// The host path of a socket a remote forward creates at `path` in the sandbox, if it is
+/// directly in `/workspace`.
+pub fn host_path(
+    config: &GatewayConfig,
+    github_user: &str,
+    project: &str,
+    path: &str,
+) -> Option<PathBuf> {
+    let name = path.strip_prefix(WORKSPACE_MOUNT)?.strip_prefix('/')?;
+    if name.is_empty() || name.contains('/') || name == "." {
+        return None;
+    }
+    Some(config.workspace_path(github_user, project).join(name))
+}
+
+/// Listen on `host_path`, replacing a socket a previous connection left behind, and let the
+/// workspace's owner (the container user) connect.
+pub async fn bind(host_path: &Path) -> Result<UnixListener> {
+    let dir = host_path.parent().context("Socket path has no directory")?;
+    tokio::fs::create_dir_all(dir)
+        .await
+        .with_context(|| format!("Failed to create {}", dir.display()))?;
+    match tokio::fs::symlink_metadata(host_path).await {
+        Ok(md) if md.file_type().is_socket() => remove(host_path),
+        Ok(_) => bail!("{} exists and is not a socket", host_path.display()),
+        Err(_) => {}
+    }
+    let listener = UnixListener::bind(host_path)
+        .with_context(|| format!("Failed to bind {}", host_path.display()))?;
+    // The sandbox can swap the socket for a symlink right after the bind.
+    let name = host_path.file_name().context("Socket path has no file name")?;
+    host_fs::share_socket(dir, name)
+        .with_context(|| format!("Failed to share {}", host_path.display()))?;
+    Ok(listener)
+}
+
+/// Remove a socket created by [`bind`], unless the sandbox replaced it with something else.
+pub fn remove(host_path: &Path) {
+    if std::fs::symlink_metadata(host_path).is_ok_and(|md| md.file_type().is_socket()) {
+        let _ = std::fs::remove_file(host_path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowed() {
+        let config = PortForwardingConfig {
+            streamlocal_paths: vec!["/run/docker.sock".to_string(), "/workspace/".to_string()],
+            ..Default::default()
+        };
+        assert!(allowed(&config, "/run/docker.sock"));
+        assert!(allowed(&config, "/workspace/S.gpg-agent"));
+        assert!(allowed(&config, "/workspace/.lsp/server.sock"));
+        assert!(!allowed(&config, "/run/docker.sock.bak"));
+        assert!(!allowed(&config, "/workspace/../etc/x.sock"));
+        assert!(!allowed(&config, "/workspace/a,fork.sock"));
+        assert!(!allowed(&config, "/workspace/"));
+        assert!(!allowed(&PortForwardingConfig::default(), "/run/docker.sock"));
+    }
+
+    #[test]
+    fn test_host_path() {
+        let config = GatewayConfig {
+            workspace_root: PathBuf::from("/srv/workspaces"),
+            ..Default::default()
+        };
+        let workspace = config.workspace_path("octocat", "web");
+        assert_eq!(
+            host_path(&config, "octocat", "web", "/workspace/S.gpg-agent"),
+            Some(workspace.join("S.gpg-agent"))
+        );
+        assert_eq!(host_path(&config, "octocat", "web", "/workspace/.lsp/server.sock"), None);
+        assert_eq!(host_path(&config, "octocat", "web", "/workspacex/agent.sock"), None);
+        assert_eq!(host_path(&config, "octocat", "web", "/tmp/agent.sock"), None);
+    }
+}
@@ -0,0 +1,104 @@
+//! Minimal tar support for the Docker archive API (copying single files in and out of
+//! containers).
+
+/// A tar archive holding a single root-owned regular file.
+pub fn single_file(name: &str, mode: u32, contents: &[u8]) -> Vec<u8> {
+    fn field(header: &mut [u8], offset: usize, len: usize, value: &[u8]) {
+        header[offset..offset + value.len().min(len)]
+            .copy_from_slice(&value[..value.len().min(len)]);
+    }
+    fn octal(header: &mut [u8], offset: usize, len: usize, value: u64) {
+        field(
+            header,
+            offset,
+            len,
+            format!("{value:0width$o}\0", width = len - 1).as_bytes(),
+        );
+    }
+
+    let mut header = [0u8; 512];
+    field(&mut header, 0, 100, name.as_bytes());
+    octal(&mut header, 100, 8, mode.into());
+    octal(&mut header, 108, 8, 0);
+    octal(&mut header, 116, 8, 0);
+    octal(&mut header, 124, 12, contents.len() as u64);
+    let mtime = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    octal(&mut header, 136, 12, mtime);
+    header[156] = b'0';
+    field(&mut header, 257, 8, b"ustar\x0000");
+    // The checksum is computed with its own field filled with spaces.
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|&b| u32::from(b)).sum();
+    field(&mut header, 148, 8, format!("{checksum:06o}\0 ").as_bytes());
+
+    let padded = contents.len().div_ceil(512) * 512;
+    let mut archive = Vec::with_capacity(512 + padded + 1024);
+    archive.extend_from_slice(&header);
+    archive.extend_from_slice(contents);
+    archive.resize(512 + padded + 1024, 0);
+    archive
+}
+
+/// Contents of the first regular file in a tar archive (as returned by Docker's archive API for a
+/// single file).
+pub fn first_file(archive: &[u8]) -> Option<&[u8]> {
+    let mut offset = 0;
+    while offset + 512 <= archive.len() {
+        let header = &archive[offset..offset + 512];
+        if header.iter().all(|&b| b == 0) {
+            return None;
+        }
+        let size = std::str::from_utf8(&header[124..136])
+            .ok()
+            .map(|s| s.trim_matches(|c: char| c == '\0' || c == ' '))
+            .and_then(|s| u64::from_str_radix(s, 8).ok())? as usize;
+        let data = offset + 512;
+        if matches!(header[156], b'0' | 0) {
+            return archive.get(data..data + size);
+        }
+        offset = data + size.div_ceil(512) * 512;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_single_file() {
+        let contents = vec![7u8; 1000];
+        let archive = single_file("usr/local/bin/agentman-helper", 0o755, &contents);
+        assert_eq!(archive.len(), 512 + 1024 + 1024);
+        assert_eq!(first_file(&archive), Some(&contents[..]));
+
+        // Readable by a real tar, when the host has one.
+        let dir = std::env::temp_dir().join(format!("agentman-tar-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.tar"), &archive).unwrap();
+        let listed = std::process::Command::new("tar")
+            .args(["-tvf", "a.tar"])
+            .current_dir(&dir)
+            .output();
+        if let Ok(listed) = listed {
+            let listing = String::from_utf8_lossy(&listed.stdout);
+            assert!(
+                listed.status.success(),
+                "{}",
+                String::from_utf8_lossy(&listed.stderr)
+            );
+            assert!(listing.starts_with("-rwxr-xr-x"), "{listing}");
+            assert!(listing.contains(" 1000 "), "{listing}");
+            assert!(
+                listing
+                    .trim_end()
+                    .ends_with("usr/local/bin/agentman-helper"),
+                "{listing}"
+            );
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,206 @@
+//! In-container TCP bridge for forwarded connections.
+//!
+//! Forwarding channels are served by an exec inside the container that connects to the
+//! destination and copies bytes between its stdin/stdout and the socket. `socat` does this best,
+//! but minimal images often lack it, so the exec is a small `sh` script that picks the first tool
+//! available: the `agentman-helper` the gateway installs (see `helper.rs`), then whatever the
+//! image has: socat, nc, bash's `/dev/tcp`, or a python3 one-liner. Unix socket forwards
+//! (`crate::streamlocal`) are bridged the same way, without bash.
+
+use crate::helper;
+
+/// Picks a bridge. Arguments: `$1` host, `$2` port, `$3` the python bridge, `$4` the bash one,
+/// `$5` the helper's path.
+const DISPATCH: &str = r#"h=$1 p=$2
+if [ -x "$5" ]; then exec "$5" tcp "$h" "$p"; fi
+if command -v socat >/dev/null 2>&1; then exec socat - "TCP:$h:$p"; fi
+if command -v nc >/dev/null 2>&1; then exec nc "$h" "$p"; fi
+if command -v bash >/dev/null 2>&1; then exec bash -c "$4" bash "$h" "$p"; fi
+if command -v python3 >/dev/null 2>&1; then exec python3 -c "$3" "$h" "$p"; fi
+echo "agentman: no TCP bridge in this image (install socat, nc, bash or python3)" >&2
+exit 127"#;
+
+/// Picks a Unix socket bridge. Arguments: `$1` socket path, `$2` the python bridge, `$3` the
+/// helper's path. Helpers from before `unix` existed exit with a usage error without reading
+/// stdin, so the image's tools are tried next.
+const UNIX_DISPATCH: &str = r#"s=$1
+if [ -x "$3" ]; then "$3" unix "$s"; r=$?; [ "$r" -eq 2 ] || exit "$r"; fi
+if command -v socat >/dev/null 2>&1; then exec socat - "UNIX-CONNECT:$s"; fi
+if command -v nc >/dev/null 2>&1; then exec nc -U "$s"; fi
+if command -v python3 >/dev/null 2>&1; then exec python3 -c "$2" "$s"; fi
+echo "agentman: no Unix socket bridge in this image (install socat, nc or python3)" >&2
+exit 127"#;
+
+/// Bash's `/dev/tcp` redirection (only missing if bash was built without network redirections).
+const BASH_BRIDGE: &str = r#"exec 3<>"/dev/tcp/$1/$2" || exit 1
+cat <&3 &
+cat >&3
+wait"#;
+
+/// Copies stdin to the socket (half-closing it on EOF) and the socket to stdout.
+const PYTHON_BRIDGE: &str = r#"import socket, sys, threading
+s = socket.create_connection((sys.argv[1], int(sys.argv[2])))
+"#;
+
+/// [`PYTHON_BRIDGE`] for a Unix socket.
+const PYTHON_UNIX_BRIDGE: &str = r#"import socket, sys, threading
+s = socket.socket(socket.AF_UNIX)
+s.connect(sys.argv[1])
+"#;
+
+/// The copying part of the python bridges, with `s` connected.
+const PYTHON_RELAY: &str = r#"def upload():
+    while True:
+        data = sys.stdin.buffer.read1(65536)
+        if not data:
+            break
+        s.sendall(data)
+    s.shutdown(socket.SHUT_WR)
+threading.Thread(target=upload, daemon=True).start()
+while True:
+    data = s.recv(65536)
+    if not data:
+        break
+    sys.stdout.buffer.write(data)
+    sys.stdout.buffer.flush()"#;
+
+/// Exec command bridging stdin/stdout to `host:port` from inside the container.
+pub fn command(host: &str, port: u32) -> Vec<String> {
+    vec![
+        "/bin/sh".to_string(),
+        "-c".to_string(),
+        DISPATCH.to_string(),
+        "agentman-bridge".to_string(),
+        host.to_string(),
+        port.to_string(),
+        format!("{PYTHON_BRIDGE}{PYTHON_RELAY}"),
+        BASH_BRIDGE.to_string(),
+        helper::CONTAINER_PATH.to_string(),
+    ]
+}
+
+/// Exec command bridging stdin/stdout to the Unix socket `path` inside the container.
+pub fn unix_command(path: &str) -> Vec<String> {
+    vec![
+        "/bin/sh".to_string(),
+        "-c".to_string(),
+        UNIX_DISPATCH.to_string(),
+        "agentman-bridge".to_string(),
+        path.to_string(),
+        format!("{PYTHON_UNIX_BRIDGE}{PYTHON_RELAY}"),
+        helper::CONTAINER_PATH.to_string(),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process::{Command, Output, Stdio};
+
+    /// Run `cmd` with `stdin` as input and return its output.
+    fn run(cmd: &[String], path: Option<&std::path::Path>, stdin: &[u8]) -> Output {
+        let mut command = Command::new(&cmd[0]);
+        command
+            .args(&cmd[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped());
+        if let Some(path) = path {
+            command.env("PATH", path);
+        }
+        let mut child = command.spawn().unwrap();
+        child.stdin.take().unwrap().write_all(stdin).unwrap();
+        child.wait_with_output().unwrap()
+    }
+
+    #[test]
+    fn test_dispatch_order() {
+        let dir = std::env::temp_dir().join(format!("agentman-bridge-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let stub = |name: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, format!("#!/bin/sh\necho {name} \"$@\"\n")).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        };
+        let cmd = command("127.0.0.1", 8080);
+
+        let out = run(&cmd, Some(&dir), b"");
+        assert_eq!(out.status.code(), Some(127));
+
+        stub("nc");
+        let out = run(&cmd, Some(&dir), b"");
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "nc 127.0.0.1 8080\n");
+
+        stub("socat");
+        let out = run(&cmd, Some(&dir), b"");
+        assert_eq!(
+            String::from_utf8_lossy(&out.stdout),
+            "socat - TCP:127.0.0.1:8080\n"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_unix_dispatch_order() {
+        let dir = std::env::temp_dir().join(format!("agentman-unix-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let stub = |name: &str, exit: u8| {
+            let path = dir.join(name);
+            let script = format!("#!/bin/sh\necho {name} \"$@\"\nexit {exit}\n");
+            std::fs::write(&path, script).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        };
+        let mut cmd = unix_command("/run/lsp.sock");
+        *cmd.last_mut().unwrap() = dir.join("helper").display().to_string();
+
+        stub("nc", 0);
+        let out = run(&cmd, Some(&dir), b"");
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "nc -U /run/lsp.sock\n");
+
+        // A helper without `unix` leaves the work to the image's tools.
+        stub("helper", 2);
+        stub("socat", 0);
+        let out = run(&cmd, Some(&dir), b"");
+        assert_eq!(
+            String::from_utf8_lossy(&out.stdout),
+            "helper unix /run/lsp.sock\nsocat - UNIX-CONNECT:/run/lsp.sock\n"
+        );
+
+        stub("helper", 1);
+        let out = run(&cmd, Some(&dir), b"");
+        assert_eq!(out.status.code(), Some(1));
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "helper unix /run/lsp.sock\n");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_python_bridge() {
+        if Command::new("python3").arg("--version").output().is_err() {
+            return;
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut buf = Vec::new();
+            conn.read_to_end(&mut buf).unwrap();
+            conn.write_all(&buf).unwrap();
+        });
+
+        let cmd = [
+            "python3".to_string(),
+            "-c".to_string(),
+            format!("{PYTHON_BRIDGE}{PYTHON_RELAY}"),
+            "127.0.0.1".to_string(),
+            port.to_string(),
+        ];
+        let out = run(&cmd, None, b"ping");
+        server.join().unwrap();
+        assert_eq!(out.stdout, b"ping");
+    }
+}
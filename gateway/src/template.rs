@@ -0,0 +1,809 @@
+//! Project templates for `agentman new <project> --from <git-url>`.
+//!
+//! Templates follow the cookiecutter layout: a `cookiecutter.json` with the variables to
+//! prompt for, next to a single directory whose name is itself templated (e.g.
+//! `{{cookiecutter.project_slug}}/`). The template is cloned inside the new project's container
+//! (so it uses the sandbox's network and git setup, never the gateway's), the answers are
+//! collected over the SSH channel, and the directory's contents are rendered into the fresh
+//! workspace before the user's first shell.
+//!
+//! Rendering supports the common subset of cookiecutter's Jinja: `{{ cookiecutter.<name> }}`
+//! with `lower`, `upper`, `title`, `capitalize`, `strip`/`trim` and `replace(a, b)` as methods
+//! or filters. Anything else (`{% if %}`, other expressions) is left untouched and reported.
+
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::docker::ContainerManager;
+use crate::github::validate_project_name;
+use crate::host_fs::{self, Handle};
+
+/// Where the template is cloned, relative to the workspace root.
+const CLONE_DIR: &str = ".agentman/template";
+
+/// Text files larger than this are copied without rendering.
+const MAX_RENDER_BYTES: u64 = 1024 * 1024;
+
+/// Answers collected so far, in prompt order.
+pub type Answers = Vec<(String, String)>;
+
+/// A variable from `cookiecutter.json`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Variable {
+    /// Free text; the default may reference earlier answers.
+    Text { name: String, default: String },
+    /// One of a list of values; the first is the default.
+    Choice { name: String, options: Vec<String> },
+}
+
+impl Variable {
+    pub fn name(&self) -> &str {
+        match self {
+            Variable::Text { name, .. } | Variable::Choice { name, .. } => name,
+        }
+    }
+}
+
+/// Parsed `cookiecutter.json`.
+#[derive(Debug, Clone, Default)]
+pub struct TemplateSpec {
+    pub variables: Vec<Variable>,
+    /// `_copy_without_render` globs (relative to the template directory).
+    pub copy_without_render: Vec<String>,
+}
+
+/// A template cloned into a new project's workspace, ready to render.
+#[derive(Debug, Clone)]
+pub struct Template {
+    pub url: String,
+    pub container_id: String,
+    /// Host path of the templated directory inside the clone.
+    pub root: PathBuf,
+    pub spec: TemplateSpec,
+}
+
+/// JSON object with its keys in file order (prompts follow it, and defaults may reference
+/// earlier answers).
+struct OrderedObject(Vec<(String, serde_json::Value)>);
+
+impl<'de> Deserialize<'de> for OrderedObject {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = OrderedObject;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a JSON object")
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(
+                self,
+                mut map: A,
+            ) -> Result<Self::Value, A::Error> {
+                let mut entries = Vec::new();
+                while let Some(entry) = map.next_entry()? {
+                    entries.push(entry);
+                }
+                Ok(OrderedObject(entries))
+            }
+        }
+        deserializer.deserialize_map(Visitor)
+    }
+}
+
+/// Parse `cookiecutter.json`. Keys starting with `_` are settings, not prompts.
+pub fn parse_spec(json: &str) -> Result<TemplateSpec> {
+    let OrderedObject(entries) =
+        serde_json::from_str(json).context("cookiecutter.json is not a JSON object")?;
+    let mut spec = TemplateSpec::default();
+    for (name, value) in entries {
+        if name == "_copy_without_render" {
+            spec.copy_without_render = serde_json::from_value(value)
+                .context("_copy_without_render must be a list of strings")?;
+            continue;
+        }
+        if name.starts_with('_') {
+            continue;
+        }
+        let variable = match value {
+            serde_json::Value::String(default) => Variable::Text { name, default },
+            serde_json::Value::Bool(b) => Variable::Text {
+                name,
+                // What Jinja renders for Python booleans.
+                default: if b { "True" } else { "False" }.to_string(),
+            },
+            serde_json::Value::Number(n) => Variable::Text {
+                name,
+                default: n.to_string(),
+            },
+            serde_json::Value::Array(items) => {
+                let options: Vec<String> = items
+                    .into_iter()
+                    .map(|v| match v {
+                        serde_json::Value::String(s) => s,
+                        other => other.to_string(),
+                    })
+                    .collect();
+                if options.is_empty() {
+                    anyhow::bail!("choice variable {name} has no options");
+                }
+                Variable::Choice { name, options }
+            }
+            _ => {
+                warn!("Ignoring template variable {} with unsupported type", name);
+                continue;
+            }
+        };
+        spec.variables.push(variable);
+    }
+    Ok(spec)
+}
+
+/// The default answer for `variable`, given earlier answers.
+pub fn default_for(variable: &Variable, answers: &Answers) -> String {
+    match variable {
+        Variable::Text { default, .. } => render_str(default, answers).0,
+        Variable::Choice { options, .. } => options[0].clone(),
+    }
+}
+
+/// Turn what the user typed into an answer. Empty input takes the default; choices accept the
+/// option number or the option itself.
+pub fn resolve_answer(
+    variable: &Variable,
+    input: &str,
+    answers: &Answers,
+) -> Result<String, String> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Ok(default_for(variable, answers));
+    }
+    match variable {
+        Variable::Text { .. } => Ok(input.to_string()),
+        Variable::Choice { options, .. } => {
+            if let Ok(n) = input.parse::<usize>()
+                && (1..=options.len()).contains(&n)
+            {
+                return Ok(options[n - 1].clone());
+            }
+            options
+                .iter()
+                .find(|o| o.as_str() == input)
+                .cloned()
+                .ok_or_else(|| format!("choose a number from 1 to {}", options.len()))
+        }
+    }
+}
+
+/// Only clone over the network; `file://`, `ext::` and option-like URLs are refused.
+pub fn validate_url(url: &str) -> Result<()> {
+    let ok = url.starts_with("https://")
+        || url.starts_with("ssh://")
+        || (url.starts_with("git@") && url.contains(':'));
+    if !ok || url.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        anyhow::bail!("template URL must be an https://, ssh:// or git@host:path git URL");
+    }
+    Ok(())
+}
+
+/// Create the new project's sandbox, clone the template into it, and read its variables.
+pub async fn prepare(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    url: &str,
+) -> Result<Template> {
+    validate_project_name(project)?;
+    validate_url(url)?;
+
+    if container_manager
+        .state()
+        .get_workspace(github_user, project)
+        .await
+        .is_some()
+    {
+        anyhow::bail!("project {project} already exists; templates only render into new projects");
+    }
+    let workspace = container_manager
+        .config()
+        .workspace_path(github_user, project);
+    if has_user_files(&workspace).await? {
+        anyhow::bail!(
+            "workspace directory for {project} already has files; templates only render into new projects"
+        );
+    }
+
+    let container_id = container_manager
+        .get_or_create_container(github_user, project)
+        .await?;
+
+    let clone_target = format!("/workspace/{CLONE_DIR}");
+    let output = container_manager
+        .run_exec(
+            &container_id,
+            [
+                "env",
+                // Never block on credential or host key prompts nobody can answer.
+                "GIT_TERMINAL_PROMPT=0",
+                "GIT_SSH_COMMAND=ssh -o BatchMode=yes -o StrictHostKeyChecking=accept-new",
+                "sh",
+                "-c",
+                "rm -rf \"$2\" && git clone --quiet --depth 1 -- \"$1\" \"$2\"",
+                "agentman-template",
+                url,
+                &clone_target,
+            ]
+            .map(str::to_string)
+            .to_vec(),
+        )
+        .await?;
+    if output.exit_code != 0 {
+        anyhow::bail!(
+            "git clone {} failed: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    let clone_dir = workspace.join(CLONE_DIR);
+    let spec_rel = Path::new(CLONE_DIR).join("cookiecutter.json");
+    let spec_root = workspace.clone();
+    let spec = tokio::task::spawn_blocking(move || {
+        let handle =
+            host_fs::walk(&spec_root, &spec_rel).context("template has no cookiecutter.json")?;
+        if !handle.metadata()?.is_file() {
+            anyhow::bail!("cookiecutter.json must be a regular file");
+        }
+        let mut spec = String::new();
+        handle.reopen()?.read_to_string(&mut spec)?;
+        Ok(spec)
+    })
+    .await
+    .context("Template read task panicked")??;
+    let spec = parse_spec(&spec)?;
+
+    let mut roots = Vec::new();
+    let mut entries = tokio::fs::read_dir(&clone_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.contains("{{") && entry.file_type().await?.is_dir() {
+            roots.push(entry.path());
+        }
+    }
+    let [root] = roots.as_slice() else {
+        anyhow::bail!(
+            "expected exactly one templated directory (like {{{{cookiecutter.project_slug}}}}) next to cookiecutter.json, found {}",
+            roots.len()
+        );
+    };
+
+    Ok(Template {
+        url: url.to_string(),
+        container_id,
+        root: root.clone(),
+        spec,
+    })
+}
+
+/// Whether a workspace directory has anything besides gateway bookkeeping in `.agentman/`.
+pub async fn has_user_files(workspace: &Path) -> Result<bool> {
+    let mut entries = match tokio::fs::read_dir(workspace).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", workspace.display())),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_name() != ".agentman" {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Render `template` into the workspace and remove the clone. Returns a summary for the user.
+pub async fn render(
+    container_manager: &ContainerManager,
+    github_user: &str,
+    project: &str,
+    template: Template,
+    answers: Answers,
+) -> Result<String> {
+    let workspace = container_manager
+        .config()
+        .workspace_path(github_user, project);
+    let root = template.root.clone();
+    let copy_without_render = template.spec.copy_without_render.clone();
+    let report = tokio::task::spawn_blocking(move || {
+        let rel = root.strip_prefix(&workspace).context("Template is outside the workspace")?;
+        let src = host_fs::walk(&workspace, rel)?;
+        if !src.metadata()?.is_dir() {
+            anyhow::bail!("{} is not a directory", rel.display());
+        }
+        let dest = Handle::open_dir(&workspace)?;
+        let mut report = RenderReport::default();
+        render_tree(
+            &src,
+            &dest,
+            Path::new(""),
+            &answers,
+            &copy_without_render,
+            &mut report,
+        )
+        .map(|()| report)
+    })
+    .await
+    .context("Template render task panicked")??;
+
+    discard(container_manager, &template).await;
+    info!(
+        "Rendered template {} into {}/{} ({} files)",
+        template.url, github_user, project, report.files
+    );
+
+    let mut out = format!(
+        "agentman: created {project} from {} ({} files)\n",
+        template.url, report.files
+    );
+    if !report.skipped.is_empty() {
+        out.push_str("- skipped:\n");
+        for s in &report.skipped {
+            out.push_str(&format!("  - {s}\n"));
+        }
+    }
+    if report.unsupported > 0 {
+        out.push_str(&format!(
+            "- {} template expressions were left as-is (only {{{{ cookiecutter.<name> }}}} with simple filters is supported)\n",
+            report.unsupported
+        ));
+    }
+    out.push_str(&format!("Connect with: ssh {project}@<this gateway>\n"));
+    Ok(out)
+}
+
+/// Remove the template clone from the workspace (from inside the container, which owns it).
+pub async fn discard(container_manager: &ContainerManager, template: &Template) {
+    let target = format!("/workspace/{CLONE_DIR}");
+    if let Err(e) = container_manager
+        .run_exec(
+            &template.container_id,
+            vec!["rm".to_string(), "-rf".to_string(), target],
+        )
+        .await
+    {
+        warn!("Failed to remove template clone: {:#}", e);
+    }
+}
+
+#[derive(Debug, Default)]
+struct RenderReport {
+    files: usize,
+    skipped: Vec<String>,
+    unsupported: usize,
+}
+
+/// Render every entry of `src` into `dest`. `rel` is the source path relative to the template
+/// root (for `_copy_without_render`). Symlinks are never followed or copied: both trees are in
+/// the workspace, which the running sandbox can change at any time, so everything goes through
+/// [`Handle`]s, and files are created without replacing or following what is already there.
+fn render_tree(
+    src: &Handle,
+    dest: &Handle,
+    rel: &Path,
+    answers: &Answers,
+    copy_without_render: &[String],
+    report: &mut RenderReport,
+) -> Result<()> {
+    let mut names = src
+        .entries()
+        .with_context(|| format!("Failed to read {}", rel.display()))?;
+    names.sort();
+
+    for source_name in names {
+        let rel = rel.join(&source_name);
+        let (name, unsupported) = render_str(&source_name.to_string_lossy(), answers);
+        report.unsupported += unsupported;
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') || name == ".git" {
+            report
+                .skipped
+                .push(format!("{} (invalid name {name:?})", rel.display()));
+            continue;
+        }
+        if rel.parent() == Some(Path::new("")) && name == ".agentman" {
+            report.skipped.push(format!("{} (reserved)", rel.display()));
+            continue;
+        }
+
+        let name = OsStr::new(&name);
+        let source = src
+            .child(&source_name)
+            .with_context(|| format!("Failed to open {}", rel.display()))?;
+        let md = source.metadata()?;
+        if md.file_type().is_symlink() {
+            report.skipped.push(format!("{} (symlink)", rel.display()));
+        } else if md.is_dir() {
+            match dest.create_dir(name, 0o755) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to create {}", rel.display()));
+                }
+            }
+            let target = dest.child(name)?;
+            if !target.metadata()?.is_dir() {
+                report
+                    .skipped
+                    .push(format!("{} (already exists)", rel.display()));
+                continue;
+            }
+            set_owner(&target, &md);
+            render_tree(
+                &source,
+                &target,
+                &rel,
+                answers,
+                copy_without_render,
+                report,
+            )?;
+        } else if md.is_file() {
+            let mut bytes = Vec::new();
+            source.reopen()?.read_to_end(&mut bytes)?;
+            let verbatim = md.len() > MAX_RENDER_BYTES
+                || copy_without_render
+                    .iter()
+                    .any(|pattern| glob_match(pattern, &rel.to_string_lossy()));
+            let contents = match std::str::from_utf8(&bytes) {
+                Ok(text) if !verbatim => {
+                    let (rendered, unsupported) = render_str(text, answers);
+                    report.unsupported += unsupported;
+                    rendered.into_bytes()
+                }
+                _ => bytes,
+            };
+            let mut file = match dest.create_file(name, 0o600) {
+                Ok(file) => file,
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    report
+                        .skipped
+                        .push(format!("{} (already exists)", rel.display()));
+                    continue;
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to create {}", rel.display()));
+                }
+            };
+            file.write_all(&contents)
+                .with_context(|| format!("Failed to write {}", rel.display()))?;
+            set_mode(&file, &md);
+            set_file_owner(&file, &md);
+            report.files += 1;
+        }
+    }
+    Ok(())
+}
+
+fn set_mode(file: &File, source: &std::fs::Metadata) {
+    let mode = source.permissions().mode() & 0o777;
+    let _ = file.set_permissions(std::fs::Permissions::from_mode(mode));
+}
+
+/// Hand rendered files to the container user, who owns the clone they come from; best effort,
+/// like the workspace root itself (only possible when the gateway runs as root).
+fn set_file_owner(file: &File, source: &std::fs::Metadata) {
+    let _ = std::os::unix::fs::fchown(file, Some(source.uid()), Some(source.gid()));
+}
+
+/// Like [`set_file_owner`], for a directory just checked not to be a symlink.
+fn set_owner(dir: &Handle, source: &std::fs::Metadata) {
+    let _ = dir.chown(source.uid(), source.gid());
+}
+
+/// Minimal glob: `*` matches any run of characters (including `/`), `?` a single one.
+fn glob_match(pattern: &str, path: &str) -> bool {
+    fn go(p: &[char], s: &[char]) -> bool {
+        match p.split_first() {
+            None => s.is_empty(),
+            Some(('*', rest)) => (0..=s.len()).any(|i| go(rest, &s[i..])),
+            Some(('?', rest)) => !s.is_empty() && go(rest, &s[1..]),
+            Some((c, rest)) => s.first() == Some(c) && go(rest, &s[1..]),
+        }
+    }
+    let p: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = path.chars().collect();
+    go(&p, &s)
+}
+
+/// Render `{{ cookiecutter.* }}` expressions. Returns the output and the number of template
+/// constructs that were left untouched because they aren't supported.
+pub fn render_str(input: &str, answers: &Answers) -> (String, usize) {
+    let mut out = String::with_capacity(input.len());
+    let mut unsupported = 0;
+    let mut rest = input;
+
+    while let Some(start) = rest.find('{') {
+        let close = match rest[start..].chars().nth(1) {
+            Some('{') => "}}",
+            Some('%') => "%}",
+            Some('#') => "#}",
+            _ => {
+                out.push_str(&rest[..=start]);
+                rest = &rest[start + 1..];
+                continue;
+            }
+        };
+        out.push_str(&rest[..start]);
+        let Some(len) = rest[start + 2..].find(close) else {
+            out.push_str(&rest[start..]);
+            return (out, unsupported);
+        };
+        let end = start + 2 + len + 2;
+        let evaluated = (close == "}}")
+            .then(|| eval(&rest[start + 2..start + 2 + len], answers))
+            .flatten();
+        match evaluated {
+            Some(value) => out.push_str(&value),
+            None => {
+                out.push_str(&rest[start..end]);
+                unsupported += 1;
+            }
+        }
+        rest = &rest[end..];
+    }
+    out.push_str(rest);
+    (out, unsupported)
+}
+
+/// Evaluate `cookiecutter.<name>` followed by supported methods/filters.
+fn eval(expr: &str, answers: &Answers) -> Option<String> {
+    let mut c = Cursor { s: expr, pos: 0 };
+    c.skip_ws();
+    if c.ident()? != "cookiecutter" || !c.eat('.') {
+        return None;
+    }
+    let name = c.ident()?;
+    let mut value = answers.iter().find(|(k, _)| k == name)?.1.clone();
+    loop {
+        c.skip_ws();
+        if c.eat('.') {
+            let method = c.ident()?;
+            if !c.eat('(') {
+                return None;
+            }
+            value = apply(method, &c.args()?, value)?;
+        } else if c.eat('|') {
+            c.skip_ws();
+            let filter = c.ident()?;
+            c.skip_ws();
+            let args = if c.eat('(') { c.args()? } else { Vec::new() };
+            value = apply(filter, &args, value)?;
+        } else {
+            break;
+        }
+    }
+    c.skip_ws();
+    (c.pos == c.s.len()).then_some(value)
+}
+
+fn apply(name: &str, args: &[String], value: String) -> Option<String> {
+    Some(match (name, args) {
+        ("lower", []) => value.to_lowercase(),
+        ("upper", []) => value.to_uppercase(),
+        ("strip" | "trim", []) => value.trim().to_string(),
+        ("title", []) => value
+            .split(' ')
+            .map(capitalize)
+            .collect::<Vec<_>>()
+            .join(" "),
+        ("capitalize", []) => capitalize(&value),
+        ("replace", [from, to]) => value.replace(from.as_str(), to),
+        _ => return None,
+    })
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first
+            .to_uppercase()
+            .chain(chars.flat_map(char::to_lowercase))
+            .collect(),
+        None => String::new(),
+    }
+}
+
+struct Cursor<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn peek(&self) -> Option<char> {
+        self.s[self.pos..].chars().next()
+    }
+
+    fn skip_ws(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn ident(&mut self) -> Option<&'a str> {
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            self.pos += 1;
+        }
+        (self.pos > start).then(|| &self.s[start..self.pos])
+    }
+
+    /// Quoted string arguments up to the closing `)` (the `(` is already consumed).
+    fn args(&mut self) -> Option<Vec<String>> {
+        let mut args = Vec::new();
+        loop {
+            self.skip_ws();
+            if self.eat(')') {
+                return Some(args);
+            }
+            if !args.is_empty() && !self.eat(',') {
+                return None;
+            }
+            self.skip_ws();
+            let quote = self.peek().filter(|&q| q == '\'' || q == '"')?;
+            self.pos += 1;
+            let len = self.s[self.pos..].find(quote)?;
+            args.push(self.s[self.pos..self.pos + len].to_string());
+            self.pos += len + 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn answers(pairs: &[(&str, &str)]) -> Answers {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_spec_keeps_order() {
+        let spec = parse_spec(
+            r#"{
+                "project_name": "My Project",
+                "project_slug": "{{ cookiecutter.project_name.lower().replace(' ', '-') }}",
+                "license": ["MIT", "Apache-2.0"],
+                "use_ci": true,
+                "_copy_without_render": ["*.png"],
+                "_extensions": []
+            }"#,
+        )
+        .unwrap();
+        let names: Vec<_> = spec.variables.iter().map(Variable::name).collect();
+        assert_eq!(names, ["project_name", "project_slug", "license", "use_ci"]);
+        assert_eq!(spec.copy_without_render, ["*.png"]);
+
+        let a = answers(&[("project_name", "Hello World")]);
+        assert_eq!(default_for(&spec.variables[1], &a), "hello-world");
+        assert_eq!(default_for(&spec.variables[3], &a), "True");
+        assert_eq!(
+            resolve_answer(&spec.variables[2], "2", &a),
+            Ok("Apache-2.0".to_string())
+        );
+        assert_eq!(
+            resolve_answer(&spec.variables[2], "MIT", &a),
+            Ok("MIT".to_string())
+        );
+        assert!(resolve_answer(&spec.variables[2], "3", &a).is_err());
+        assert_eq!(
+            resolve_answer(&spec.variables[0], "  ", &a),
+            Ok("My Project".to_string())
+        );
+    }
+
+    #[test]
+    fn test_render_str() {
+        let a = answers(&[("name", "Demo App"), ("slug", "demo_app")]);
+        assert_eq!(
+            render_str("# {{cookiecutter.name}}", &a),
+            ("# Demo App".to_string(), 0)
+        );
+        assert_eq!(
+            render_str("{{ cookiecutter.name | lower | replace(' ', '-') }}", &a),
+            ("demo-app".to_string(), 0)
+        );
+        assert_eq!(
+            render_str(
+                "fn main() { println!(\"{{ cookiecutter.slug.upper() }}\"); }",
+                &a
+            ),
+            ("fn main() { println!(\"DEMO_APP\"); }".to_string(), 0)
+        );
+        let (out, unsupported) = render_str("{% if cookiecutter.x %}a{% endif %}{{ other }}", &a);
+        assert_eq!(out, "{% if cookiecutter.x %}a{% endif %}{{ other }}");
+        assert_eq!(unsupported, 3);
+        assert_eq!(render_str("unterminated {{ x", &a).0, "unterminated {{ x");
+    }
+
+    #[test]
+    fn test_validate_url() {
+        assert!(validate_url("https://github.com/org/template.git").is_ok());
+        assert!(validate_url("git@github.com:org/template.git").is_ok());
+        assert!(validate_url("ssh://git@host/org/template").is_ok());
+        assert!(validate_url("file:///etc").is_err());
+        assert!(validate_url("ext::sh -c touch% /tmp/pwned").is_err());
+        assert!(validate_url("--upload-pack=touch /tmp/x").is_err());
+    }
+
+    #[test]
+    fn test_render_tree() {
+        let dir =
+            std::env::temp_dir().join(format!("agentman-template-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let src = dir.join("src");
+        let dest = dir.join("dest");
+        let outside = dir.join("outside");
+        std::fs::create_dir_all(src.join("{{cookiecutter.slug}}")).unwrap();
+        std::fs::create_dir_all(src.join("etc")).unwrap();
+        std::fs::create_dir_all(&dest).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(src.join("README.md"), "# {{ cookiecutter.name }}\n").unwrap();
+        std::fs::write(src.join("{{cookiecutter.slug}}/__init__.py"), "").unwrap();
+        std::fs::write(src.join("logo.png"), "{{ cookiecutter.name }}").unwrap();
+        std::fs::write(src.join("etc/passwd"), "root::0:0::/:/bin/sh\n").unwrap();
+        std::fs::write(src.join("notes.txt"), "notes\n").unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", src.join("passwd")).unwrap();
+        // The sandbox planted symlinks where the template writes: a directory and a file.
+        std::os::unix::fs::symlink(&outside, dest.join("etc")).unwrap();
+        std::os::unix::fs::symlink(outside.join("notes.txt"), dest.join("notes.txt")).unwrap();
+
+        let a = answers(&[("name", "Demo"), ("slug", "demo")]);
+        let mut report = RenderReport::default();
+        render_tree(
+            &Handle::open_dir(&src).unwrap(),
+            &Handle::open_dir(&dest).unwrap(),
+            Path::new(""),
+            &a,
+            &["*.png".to_string()],
+            &mut report,
+        )
+        .unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(dest.join("README.md")).unwrap(),
+            "# Demo\n"
+        );
+        assert!(dest.join("demo/__init__.py").is_file());
+        assert_eq!(
+            std::fs::read_to_string(dest.join("logo.png")).unwrap(),
+            "{{ cookiecutter.name }}"
+        );
+        assert!(dest.join("passwd").symlink_metadata().is_err());
+        assert_eq!(report.files, 3);
+        assert_eq!(std::fs::read_dir(&outside).unwrap().count(), 0);
+        assert_eq!(
+            report.skipped,
+            ["etc (already exists)", "notes.txt (already exists)", "passwd (symlink)"]
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
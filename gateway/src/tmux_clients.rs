@@ -0,0 +1,152 @@
+//! Terminals attached to the shared tmux session of each workspace.
+//!
+//! Every PTY shell in tmux mode attaches to the same tmux session, so one workspace can have
+//! several clients at once (a laptop and a tablet, or a teammate looking over a shoulder). tmux
+//! sizes the shared window with its `window-size` option (`[shell] tmux_window_size`); the
+//! gateway only keeps track of who is attached, and at what size, for `agentman sessions`.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+
+use chrono::{DateTime, Utc};
+
+use crate::config::TmuxWindowSize;
+
+/// A terminal attached to a workspace's tmux session.
+#[derive(Debug, Clone)]
+pub struct Client {
+    id: u64,
+    pub peer_addr: SocketAddr,
+    pub attached_at: DateTime<Utc>,
+    pub cols: u32,
+    pub rows: u32,
+    /// Last attach or resize (tmux also counts keystrokes, which the gateway doesn't see).
+    resized_at: DateTime<Utc>,
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Attached clients by `(github_user, project)`.
+type Registry = HashMap<(String, String), Vec<Client>>;
+
+static ATTACHED: LazyLock<Mutex<Registry>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn attached() -> std::sync::MutexGuard<'static, Registry> {
+    ATTACHED.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Registration of an attached client; detaches it when dropped.
+#[derive(Debug)]
+pub struct Attachment {
+    key: (String, String),
+    id: u64,
+}
+
+impl Attachment {
+    /// Record a new terminal size for this client.
+    pub fn resize(&self, cols: u32, rows: u32) {
+        if let Some(client) = attached()
+            .get_mut(&self.key)
+            .and_then(|clients| clients.iter_mut().find(|c| c.id == self.id))
+        {
+            client.cols = cols;
+            client.rows = rows;
+            client.resized_at = Utc::now();
+        }
+    }
+}
+
+impl Drop for Attachment {
+    fn drop(&mut self) {
+        let mut attached = attached();
+        if let Some(clients) = attached.get_mut(&self.key) {
+            clients.retain(|c| c.id != self.id);
+            if clients.is_empty() {
+                attached.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// Record a terminal attaching to the tmux session of `github_user/project`.
+pub fn attach(
+    github_user: &str,
+    project: &str,
+    peer_addr: SocketAddr,
+    cols: u32,
+    rows: u32,
+) -> Attachment {
+    let key = (github_user.to_string(), project.to_string());
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    attached().entry(key.clone()).or_default().push(Client {
+        id,
+        peer_addr,
+        attached_at: Utc::now(),
+        cols,
+        rows,
+        resized_at: Utc::now(),
+    });
+    Attachment { key, id }
+}
+
+/// Clients attached to each of `github_user`'s workspaces, by project, oldest first.
+pub fn list(github_user: &str) -> Vec<(String, Vec<Client>)> {
+    let mut out: Vec<_> = attached()
+        .iter()
+        .filter(|((user, _), _)| user == github_user)
+        .map(|((_, project), clients)| (project.clone(), clients.clone()))
+        .collect();
+    out.sort_by(|a, b| a.0.cmp(&b.0));
+    out
+}
+
+/// The window size tmux picks for `clients` under `policy` (`None` without clients). For
+/// `latest` this is an estimate: the client that attached or resized last.
+pub fn effective_size(policy: TmuxWindowSize, clients: &[Client]) -> Option<(u32, u32)> {
+    match policy {
+        TmuxWindowSize::Smallest => Some((
+            clients.iter().map(|c| c.cols).min()?,
+            clients.iter().map(|c| c.rows).min()?,
+        )),
+        TmuxWindowSize::Latest => clients
+            .iter()
+            .max_by_key(|c| c.resized_at)
+            .map(|c| (c.cols, c.rows)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attach_resize_detach() {
+        let peer: SocketAddr = "203.0.113.5:50000".parse().unwrap();
+        let first = attach("tmux-test", "api", peer, 200, 50);
+        let second = attach("tmux-test", "api", peer, 80, 24);
+        second.resize(100, 30);
+
+        let listed = list("tmux-test");
+        assert_eq!(listed.len(), 1);
+        let clients = &listed[0].1;
+        assert_eq!(
+            effective_size(TmuxWindowSize::Smallest, clients),
+            Some((100, 30))
+        );
+        assert_eq!(
+            effective_size(TmuxWindowSize::Latest, clients),
+            Some((100, 30))
+        );
+
+        drop(second);
+        let clients = &list("tmux-test")[0].1;
+        assert_eq!(
+            effective_size(TmuxWindowSize::Smallest, clients),
+            Some((200, 50))
+        );
+        drop(first);
+        assert!(list("tmux-test").is_empty());
+    }
+}
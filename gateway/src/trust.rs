@@ -0,0 +1,85 @@
+//! Devices users log in from (`[trust]`, `agentman trust`).
+//!
+//! Keys come from GitHub, so a key that leaked (or a laptop that was lost) keeps working until
+//! its owner notices. With `[trust]` enabled, each key a user logs in with is recorded as a
+//! device, with the addresses it connected from. A login with a key the user never used here
+//! queues a notice for the login (shown as the session starts) and goes to the user's notifier
+//! (`agentman notify`), so they learn about it wherever they are. `agentman trust list` shows
+//! the devices, and `agentman trust revoke <id>` has the gateway refuse a device's key until the
+//! user trusts it again.
+//!
+//! A user's very first device is recorded without a notice: it tells them nothing.
+
+use std::net::IpAddr;
+
+use tracing::{info, warn};
+
+use crate::config::GatewayConfig;
+use crate::state::{Device, StateManager};
+use crate::user_notify;
+
+/// Record a login of `github_user` with the key `fingerprint`; returns why it is refused if
+/// the user revoked the device.
+pub async fn on_login(
+    config: &GatewayConfig,
+    state: &StateManager,
+    github_user: &str,
+    project: &str,
+    fingerprint: &str,
+    ip: IpAddr,
+) -> Result<(), String> {
+    let trust = &config.trust;
+    if !trust.enabled {
+        return Ok(());
+    }
+    let address = ip.to_string();
+    let login = match state
+        .record_device(github_user, fingerprint, &address, trust.max_devices, trust.max_addresses)
+        .await
+    {
+        Ok(login) => login,
+        Err(e) => {
+            warn!("Failed to record the device of {}: {:#}", github_user, e);
+            return Ok(());
+        }
+    };
+    let device = login.device;
+    if let Some(revoked_at) = device.revoked_at {
+        return Err(format!(
+            "device {} of {github_user} was revoked on {}",
+            device.id,
+            revoked_at.format("%Y-%m-%d %H:%M UTC")
+        ));
+    }
+    if !login.new || login.first {
+        return Ok(());
+    }
+    info!("{} logged in from new device {} ({})", github_user, device.id, address);
+    if !trust.notify {
+        return Ok(());
+    }
+    let text = message(&device);
+    if let Err(e) = state.add_notice(github_user, text.clone()).await {
+        warn!("Failed to queue a notice for {}: {:#}", github_user, e);
+    }
+    if config.user_notify.enabled
+        && state.notifier(github_user).await.is_some()
+        && let Err(e) = user_notify::send(config, state, github_user, project, &text).await
+    {
+        warn!("Failed to notify {} of a new device: {:#}", github_user, e);
+    }
+    Ok(())
+}
+
+/// What a user is told about a login from a new device.
+pub fn message(device: &Device) -> String {
+    format!(
+        "new device {} logged in to your account from {} on {} with key {}; if it wasn't you, \
+         run `agentman trust revoke {}` and remove the key from GitHub",
+        device.id,
+        device.addresses.last().map(String::as_str).unwrap_or("an unknown address"),
+        device.first_seen.format("%Y-%m-%d %H:%M UTC"),
+        device.key_fingerprint,
+        device.id
+    )
+}
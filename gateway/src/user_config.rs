@@ -0,0 +1,172 @@
+//! Per-user overrides of the config (`users_dir`).
+//!
+//! Some users need a bigger sandbox, another image, fewer projects or a different forwarding
+//! policy than the gateway's defaults. Admins put those in `<users_dir>/<github_user>.toml`
+//! (the user's name in lowercase), holding the [`UserOverrides`] subset of the config. The file
+//! is read whenever the user's settings are needed: when they log in, for the connection's port
+//! forwards, and when one of their containers is created. Edits therefore apply to the next
+//! connection and the next container without restarting the gateway; running containers keep
+//! the image and limits they were created with. `[security_profiles]` still apply on top.
+//!
+//! Every file is checked when the gateway starts. A file that breaks later refuses its user's
+//! logins until it is fixed, rather than letting them in with the global settings.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{Context, Result, bail};
+use tracing::warn;
+
+use crate::config::{GatewayConfig, UserOverrides};
+use crate::docker::parse_memory_limit;
+use crate::github::validate_github_username;
+
+/// The file with `github_user`'s overrides, if the gateway has a `users_dir`.
+pub fn path(config: &GatewayConfig, github_user: &str) -> Option<PathBuf> {
+    let dir = config.users_dir.as_ref()?;
+    Some(dir.join(format!("{}.toml", github_user.to_ascii_lowercase())))
+}
+
+/// `github_user`'s config: `config` with their overrides applied, or `config` itself when they
+/// have none. Fails if their file can't be read or isn't valid.
+pub async fn resolve(
+    config: &Arc<GatewayConfig>,
+    github_user: &str,
+) -> Result<Arc<GatewayConfig>> {
+    let Some(path) = path(config, github_user) else {
+        return Ok(config.clone());
+    };
+    let content = match tokio::fs::read_to_string(&path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(config.clone()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    let resolved = parse(config, &content).with_context(|| format!("In {}", path.display()))?;
+    Ok(Arc::new(resolved))
+}
+
+/// Like [`resolve`], but with the global config when the user's file is broken (logged), for
+/// work that has no one to refuse.
+pub async fn resolve_or_global(
+    config: &Arc<GatewayConfig>,
+    github_user: &str,
+) -> Arc<GatewayConfig> {
+    resolve(config, github_user).await.unwrap_or_else(|e| {
+        warn!("Ignoring the overrides of {}: {:#}", github_user, e);
+        config.clone()
+    })
+}
+
+/// Why `github_user` may not use `project` under `config` (`allowed_projects`), if they may not.
+pub fn project_refusal(config: &GatewayConfig, github_user: &str, project: &str) -> Option<String> {
+    (!config.project_allowed(project)).then(|| {
+        format!(
+            "access denied: {github_user} may only use the projects {}, not {project}",
+            config.allowed_projects.join(", ")
+        )
+    })
+}
+
+/// `config` with the overrides in `content` applied, if they are valid.
+fn parse(config: &GatewayConfig, content: &str) -> Result<GatewayConfig> {
+    let overrides: UserOverrides = toml::from_str(content)?;
+    if overrides.docker_image.as_deref().is_some_and(|image| image.trim().is_empty()) {
+        bail!("docker_image can't be empty");
+    }
+    let security = &overrides.container_security;
+    if let Some(memory) = &security.memory_limit {
+        parse_memory_limit(memory)
+            .map_err(|_| anyhow::anyhow!("container_security: memory_limit must be like \"4g\""))?;
+    }
+    if security.cpu_limit.is_some_and(|cpus| cpus <= 0.0) {
+        bail!("container_security: cpu_limit must be positive");
+    }
+    let resolved = overrides.apply(config);
+    resolved.port_forwarding.validate()?;
+    resolved.validate_allowed_projects()?;
+    Ok(resolved)
+}
+
+/// Check every file in `users_dir`; files not ending in `.toml` are ignored.
+pub fn validate_dir(config: &GatewayConfig) -> Result<()> {
+    let Some(dir) = &config.users_dir else {
+        return Ok(());
+    };
+    let entries = std::fs::read_dir(dir)
+        .with_context(|| format!("users_dir: failed to read {}", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "toml") {
+            continue;
+        }
+        let name = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+        if validate_github_username(name).is_err() || name != name.to_ascii_lowercase() {
+            bail!("users_dir: {} isn't named after a GitHub user in lowercase", path.display());
+        }
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("users_dir: failed to read {}", path.display()))?;
+        parse(config, &content).with_context(|| format!("users_dir: in {}", path.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let dir = std::env::temp_dir().join(format!("agentman-users-d-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("octocat.toml"),
+            r#"
+docker_image = "ghcr.io/acme/ml:latest"
+allowed_projects = ["ml-*", "notes"]
+
+[container_security]
+memory_limit = "16g"
+
+[port_forwarding]
+allow_remote = false
+"#,
+        )
+        .unwrap();
+        let config = Arc::new(GatewayConfig {
+            users_dir: Some(dir.clone()),
+            ..Default::default()
+        });
+        assert!(config.validate().is_ok());
+
+        let own = resolve(&config, "OctoCat").await.unwrap();
+        assert_eq!(own.docker_image, "ghcr.io/acme/ml:latest");
+        assert_eq!(own.container_security.memory_limit.as_deref(), Some("16g"));
+        assert!(!own.port_forwarding.allow_remote);
+        // What the file leaves out keeps the global value.
+        assert!(own.port_forwarding.allow_local);
+        assert!(own.project_allowed("ml-train") && own.project_allowed("notes"));
+        assert!(!own.project_allowed("web"));
+        assert_eq!(
+            project_refusal(&own, "octocat", "web").as_deref(),
+            Some("access denied: octocat may only use the projects ml-*, notes, not web")
+        );
+
+        let other = resolve(&config, "hubot").await.unwrap();
+        assert!(Arc::ptr_eq(&other, &config));
+
+        std::fs::write(dir.join("hubot.toml"), "[port_forwarding]\nallow_everything = true\n")
+            .unwrap();
+        assert!(resolve(&config, "hubot").await.is_err());
+        assert!(Arc::ptr_eq(&resolve_or_global(&config, "hubot").await, &config));
+        assert!(config.validate().is_err());
+
+        std::fs::write(dir.join("hubot.toml"), "allowed_projects = [\"../x\"]\n").unwrap();
+        assert!(config.validate().is_err());
+        std::fs::remove_file(dir.join("hubot.toml")).unwrap();
+        std::fs::write(dir.join("Hubot.toml"), "").unwrap();
+        assert!(config.validate().is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
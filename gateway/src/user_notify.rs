@@ -0,0 +1,590 @@
+//! Messages from sandboxes to their owners (`agentman notify`).
+//!
+//! Each user picks one channel, stored in the gateway state: a webhook (JSON POST that
+//! Slack/Mattermost incoming webhooks accept) or an email address (sent through the gateway's
+//! `sendmail`). Messages come from `agentman notify <message>` over SSH, or from scripts inside
+//! the sandbox through a Unix socket the gateway serves in the workspace
+//! (`/workspace/.agentman-notify.sock`, the same way agent forwarding exposes its socket): a
+//! client writes the message, shuts down its side, and reads back `ok` or `error: <reason>`.
+//! The socket a message arrives on is what identifies the workspace.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, anyhow, bail};
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::process::Command;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use crate::config::{GatewayConfig, UserNotifyConfig};
+use crate::github_metadata::{self, UserMetadata};
+use crate::host_fs;
+use crate::metrics;
+use crate::state::{Notifier, StateManager};
+
+/// The notify socket in the workspace directory.
+pub const SOCKET_NAME: &str = ".agentman-notify.sock";
+
+/// Where the socket is inside containers.
+pub const CONTAINER_SOCKET: &str = "/workspace/.agentman-notify.sock";
+
+/// How long a socket client may take to send its message.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long delivery (webhook or sendmail) may take.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// A served socket and the task accepting on it.
+type Listener = (PathBuf, JoinHandle<()>);
+
+/// Notify sockets being served, by workspace (`github_user/project`).
+static LISTENERS: LazyLock<Mutex<HashMap<String, Listener>>> = LazyLock::new(Default::default);
+
+/// Recent sends per workspace, for `max_per_hour`.
+static SENT: LazyLock<Mutex<HashMap<String, VecDeque<Instant>>>> = LazyLock::new(Default::default);
+
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    text: &'a str,
+    event: &'a str,
+    github_user: &'a str,
+    project: &'a str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    display_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avatar_url: Option<String>,
+}
+
+/// `AGENTMAN_NOTIFY_SOCK` for containers, when the socket is served.
+pub fn container_env(config: &UserNotifyConfig) -> Option<String> {
+    (config.enabled && config.socket).then(|| format!("AGENTMAN_NOTIFY_SOCK={CONTAINER_SOCKET}"))
+}
+
+/// Check a notifier a user wants to set.
+pub fn validate(config: &UserNotifyConfig, notifier: &Notifier) -> Result<()> {
+    match notifier {
+        Notifier::Webhook { url } => {
+            let parsed = reqwest::Url::parse(url).map_err(|_| anyhow!("invalid URL {url:?}"))?;
+            if !matches!(parsed.scheme(), "http" | "https") || parsed.host_str().is_none() {
+                bail!("the webhook must be an http(s) URL");
+            }
+        }
+        Notifier::Email { address } => {
+            if config.sendmail.is_none() {
+                bail!("email notifications are not available on this gateway; use a webhook");
+            }
+            let valid = address.split_once('@').is_some_and(|(local, domain)| {
+                !local.is_empty() && domain.contains('.') && !domain.contains('@')
+            }) && !address.starts_with('-')
+                && address
+                    .chars()
+                    .all(|c| c.is_ascii_graphic() && !"<>,;\"\\".contains(c));
+            if !valid {
+                bail!("invalid email address {address:?}");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `webhook https://hooks.slack.com/…` (the URL path stays hidden: it is the secret).
+pub fn describe(notifier: &Notifier) -> String {
+    match notifier {
+        Notifier::Webhook { url } => match reqwest::Url::parse(url) {
+            Ok(parsed) => format!(
+                "webhook {}://{}/…",
+                parsed.scheme(),
+                parsed.host_str().unwrap_or_default()
+            ),
+            Err(_) => "webhook".to_string(),
+        },
+        Notifier::Email { address } => format!("email {address}"),
+    }
+}
+
+/// The message of `agentman notify "some text"`: the words, without surrounding quotes.
+pub fn message_from_args(args: &[&str]) -> String {
+    let joined = args.join(" ");
+    for quote in ['"', '\''] {
+        if let Some(inner) = joined
+            .strip_prefix(quote)
+            .and_then(|rest| rest.strip_suffix(quote))
+        {
+            return inner.to_string();
+        }
+    }
+    joined
+}
+
+/// Relay `message` from a workspace to its owner's notifier.
+pub async fn send(
+    config: &GatewayConfig,
+    state: &StateManager,
+    github_user: &str,
+    project: &str,
+    message: &str,
+) -> Result<()> {
+    let notify = &config.user_notify;
+    let message = message.trim();
+    if message.is_empty() {
+        bail!("empty message");
+    }
+    if message.len() > notify.max_message_bytes {
+        bail!(
+            "the message is longer than {} bytes",
+            notify.max_message_bytes
+        );
+    }
+    let Some(notifier) = state.notifier(github_user).await else {
+        bail!(
+            "no notifier set; pick one with `agentman notify set webhook <url>` or \
+             `agentman notify set email <address>`"
+        );
+    };
+    take_slot(notify, &format!("{github_user}/{project}"), Instant::now())?;
+    // Never waits on GitHub; a missing profile is fetched for the next message.
+    let metadata = github_metadata::cached(&config.github_metadata, github_user);
+    github_metadata::prefetch(&config.github_metadata, github_user);
+
+    let channel = match notifier {
+        Notifier::Webhook { .. } => "webhook",
+        Notifier::Email { .. } => "email",
+    };
+    let result = match tokio::time::timeout(
+        DELIVERY_TIMEOUT,
+        deliver(notify, &notifier, github_user, project, message, metadata),
+    )
+    .await
+    {
+        Ok(result) => result,
+        Err(_) => Err(anyhow!("timed out after {}s", DELIVERY_TIMEOUT.as_secs())),
+    };
+    metrics::inc(
+        "agentman_user_notifications_total",
+        "Messages relayed by agentman notify, by channel and outcome.",
+        &[
+            ("channel", channel),
+            ("outcome", if result.is_ok() { "sent" } else { "failed" }),
+        ],
+    );
+    match result {
+        Ok(()) => {
+            info!(
+                "Relayed a notification from {}/{} ({})",
+                github_user, project, channel
+            );
+            Ok(())
+        }
+        Err(e) => {
+            warn!("Failed to notify {} ({}): {:#}", github_user, channel, e);
+            Err(e.context(format!("{channel} delivery failed")))
+        }
+    }
+}
+
+/// Count a send against the workspace's hourly budget.
+fn take_slot(config: &UserNotifyConfig, workspace: &str, now: Instant) -> Result<()> {
+    let mut sent = SENT.lock().unwrap();
+    let recent = sent.entry(workspace.to_string()).or_default();
+    while recent
+        .front()
+        .is_some_and(|t| now.duration_since(*t) >= Duration::from_secs(3600))
+    {
+        recent.pop_front();
+    }
+    if recent.len() >= config.max_per_hour as usize {
+        bail!(
+            "more than {} messages in the last hour; try again later",
+            config.max_per_hour
+        );
+    }
+    recent.push_back(now);
+    Ok(())
+}
+
+async fn deliver(
+    config: &UserNotifyConfig,
+    notifier: &Notifier,
+    github_user: &str,
+    project: &str,
+    message: &str,
+    metadata: Option<UserMetadata>,
+) -> Result<()> {
+    match notifier {
+        Notifier::Webhook { url } => {
+            if !config.allow_private_webhooks {
+                check_public(url).await?;
+            }
+            let text = format!("[{github_user}/{project}] {message}");
+            let payload = Payload {
+                text: &text,
+                event: "sandbox.notify",
+                github_user,
+                project,
+                message,
+                display_name: metadata.as_ref().and_then(|m| m.name.clone()),
+                avatar_url: metadata.and_then(|m| m.avatar_url),
+            };
+            let client = reqwest::Client::builder()
+                .user_agent("agentman-gateway/0.1")
+                .redirect(reqwest::redirect::Policy::none())
+                .build()
+                .context("Failed to create HTTP client")?;
+            let response = client
+                .post(url)
+                .header("Content-Type", "application/json")
+                .body(serde_json::to_vec(&payload)?)
+                .send()
+                .await
+                .context("Failed to POST to the webhook")?;
+            if !response.status().is_success() {
+                bail!("the webhook returned {}", response.status());
+            }
+            Ok(())
+        }
+        Notifier::Email { address } => {
+            let sendmail = config
+                .sendmail
+                .as_ref()
+                .ok_or_else(|| anyhow!("email notifications are not available on this gateway"))?;
+            let mail = email(&config.from, address, project, message);
+            let mut child = Command::new(sendmail)
+                .args(["-t", "-i"])
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()
+                .with_context(|| format!("Failed to run {}", sendmail.display()))?;
+            let mut stdin = child.stdin.take().context("sendmail has no stdin")?;
+            stdin.write_all(mail.as_bytes()).await?;
+            drop(stdin);
+            let output = child.wait_with_output().await?;
+            if !output.status.success() {
+                bail!(
+                    "sendmail exited with {}: {}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+            Ok(())
+        }
+    }
+}
+
+/// The email handed to `sendmail -t`; the subject is the message's first line, shortened.
+fn email(from: &str, to: &str, project: &str, message: &str) -> String {
+    let first_line: String = message
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(60)
+        .collect();
+    format!(
+        "From: {from}\nTo: {to}\nSubject: [agentman] {project}: {first_line}\n\
+         Content-Type: text/plain; charset=utf-8\n\n{message}\n"
+    )
+}
+
+/// Refuse webhooks pointing into the gateway's own network.
+async fn check_public(url: &str) -> Result<()> {
+    let parsed = reqwest::Url::parse(url)?;
+    let host = parsed.host_str().unwrap_or_default();
+    let port = parsed.port_or_known_default().unwrap_or(443);
+    let addrs = tokio::net::lookup_host((host.trim_matches(['[', ']']), port))
+        .await
+        .with_context(|| format!("Failed to resolve {host}"))?;
+    for addr in addrs {
+        if !is_public(addr.ip()) {
+            bail!("the webhook host {host} resolves to a non-public address");
+        }
+    }
+    Ok(())
+}
+
+/// Whether `ip` is outside the gateway's own network: not loopback, private, link-local,
+/// unspecified or carrier-grade NAT.
+pub(crate) fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                // 100.64.0.0/10 (carrier-grade NAT)
+                || (ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(v4) => is_public(IpAddr::V4(v4)),
+            None => {
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local())
+            }
+        },
+    }
+}
+
+/// Serve the workspace's notify socket unless it already is (the socket file is recreated if
+/// someone deleted it). No-op when the socket is disabled.
+pub fn ensure_listener(
+    config: &Arc<GatewayConfig>,
+    state: &Arc<StateManager>,
+    github_user: &str,
+    project: &str,
+    workspace_dir: &Path,
+) {
+    let notify = &config.user_notify;
+    if !notify.enabled || !notify.socket {
+        return;
+    }
+    let key = format!("{github_user}/{project}");
+    let path = workspace_dir.join(SOCKET_NAME);
+    let mut listeners = LISTENERS.lock().unwrap();
+    if let Some((served, task)) = listeners.get(&key)
+        && *served == path
+        && !task.is_finished()
+        && std::fs::symlink_metadata(&path).is_ok()
+    {
+        return;
+    }
+    if let Some((_, task)) = listeners.remove(&key) {
+        task.abort();
+    }
+    let listener = match bind(&path, workspace_dir) {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("Failed to serve the notify socket of {}: {:#}", key, e);
+            return;
+        }
+    };
+    debug!("Serving {}", path.display());
+    let config = config.clone();
+    let state = state.clone();
+    let (user, project) = (github_user.to_string(), project.to_string());
+    let task = tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Notify socket of {}/{} failed: {}", user, project, e);
+                    return;
+                }
+            };
+            let (config, state) = (config.clone(), state.clone());
+            let (user, project) = (user.clone(), project.clone());
+            tokio::spawn(async move {
+                handle_client(stream, &config, &state, &user, &project).await;
+            });
+        }
+    });
+    listeners.insert(key, (path, task));
+}
+
+/// Serve the notify sockets of the workspaces in the state (at startup; others get theirs when
+/// their container starts).
+pub async fn serve_existing(config: &Arc<GatewayConfig>, state: &Arc<StateManager>) {
+    for github_user in state.list_github_users().await {
+        for ws in state.list_workspaces(&github_user).await {
+            if ws.host_workspace_path.is_dir() {
+                ensure_listener(
+                    config,
+                    state,
+                    &github_user,
+                    &ws.project,
+                    &ws.host_workspace_path,
+                );
+            }
+        }
+    }
+}
+
+/// Stop serving the workspace's notify socket (on destroy).
+pub fn stop_listener(github_user: &str, project: &str) {
+    let key = format!("{github_user}/{project}");
+    if let Some((path, task)) = LISTENERS.lock().unwrap().remove(&key) {
+        task.abort();
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+/// Bind the socket, owned by the workspace directory's owner (the container user).
+pub(crate) fn bind(path: &Path, workspace_dir: &Path) -> Result<UnixListener> {
+    // A stale socket from a previous run; anything else is not ours to remove.
+    if std::fs::symlink_metadata(path)
+        .is_ok_and(|md| std::os::unix::fs::FileTypeExt::is_socket(&md.file_type()))
+    {
+        let _ = std::fs::remove_file(path);
+    }
+    let listener =
+        UnixListener::bind(path).with_context(|| format!("Failed to bind {}", path.display()))?;
+    let name = path.file_name().context("Socket path has no file name")?;
+    host_fs::share_socket(workspace_dir, name)
+        .with_context(|| format!("Failed to share {}", path.display()))?;
+    Ok(listener)
+}
+
+async fn handle_client(
+    mut stream: UnixStream,
+    config: &GatewayConfig,
+    state: &StateManager,
+    github_user: &str,
+    project: &str,
+) {
+    let max = config.user_notify.max_message_bytes;
+    let mut message = Vec::new();
+    let read = tokio::time::timeout(
+        READ_TIMEOUT,
+        (&mut stream).take(max as u64 + 1).read_to_end(&mut message),
+    )
+    .await;
+    let result = match read {
+        Ok(Ok(_)) if message.len() > max => Err(anyhow!("the message is longer than {max} bytes")),
+        Ok(Ok(_)) => match String::from_utf8(message) {
+            Ok(message) => send(config, state, github_user, project, &message).await,
+            Err(_) => Err(anyhow!("the message is not UTF-8")),
+        },
+        Ok(Err(e)) => Err(e.into()),
+        Err(_) => Err(anyhow!("no message within {}s", READ_TIMEOUT.as_secs())),
+    };
+    let reply = match result {
+        Ok(()) => "ok\n".to_string(),
+        Err(e) => format!("error: {e:#}\n"),
+    };
+    let _ = stream.write_all(reply.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_and_describe() {
+        let mut config = UserNotifyConfig::default();
+        let webhook = |url: &str| Notifier::Webhook {
+            url: url.to_string(),
+        };
+        let email = |address: &str| Notifier::Email {
+            address: address.to_string(),
+        };
+        assert!(
+            validate(
+                &config,
+                &webhook("https://hooks.slack.com/services/T0/B0/x")
+            )
+            .is_ok()
+        );
+        assert!(validate(&config, &webhook("ftp://example.com/")).is_err());
+        assert!(validate(&config, &webhook("hooks.slack.com/x")).is_err());
+        assert!(validate(&config, &email("mona@example.com")).is_err());
+        config.sendmail = Some(PathBuf::from("/usr/sbin/sendmail"));
+        assert!(validate(&config, &email("mona@example.com")).is_ok());
+        assert!(validate(&config, &email("mona@example.com\nBcc: x@y.z")).is_err());
+        assert!(validate(&config, &email("-oQ/tmp@x.y")).is_err());
+        assert!(validate(&config, &email("mona")).is_err());
+
+        assert_eq!(
+            describe(&webhook("https://hooks.slack.com/services/T0/B0/secret")),
+            "webhook https://hooks.slack.com/…"
+        );
+        assert_eq!(
+            describe(&email("mona@example.com")),
+            "email mona@example.com"
+        );
+    }
+
+    #[test]
+    fn test_message_and_email() {
+        assert_eq!(
+            message_from_args(&["\"training", "finished\""]),
+            "training finished"
+        );
+        assert_eq!(message_from_args(&["'done'"]), "done");
+        assert_eq!(message_from_args(&["build", "ok"]), "build ok");
+        assert_eq!(message_from_args(&["\"unbalanced"]), "\"unbalanced");
+
+        let mail = email(
+            "gw@example.com",
+            "mona@example.com",
+            "web",
+            "done\rBcc: x\nmore",
+        );
+        assert!(mail.starts_with("From: gw@example.com\nTo: mona@example.com\n"));
+        assert!(mail.contains("Subject: [agentman] web: doneBcc: x\n"));
+    }
+
+    #[test]
+    fn test_rate_limit_and_addresses() {
+        let config = UserNotifyConfig {
+            max_per_hour: 2,
+            ..UserNotifyConfig::default()
+        };
+        let now = Instant::now();
+        let workspace = format!("rate-limit-test-{}/web", std::process::id());
+        assert!(take_slot(&config, &workspace, now).is_ok());
+        assert!(take_slot(&config, &workspace, now).is_ok());
+        assert!(take_slot(&config, &workspace, now).is_err());
+        assert!(take_slot(&config, &workspace, now + Duration::from_secs(3600)).is_ok());
+
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "192.168.0.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "::1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in ["fd00::1", "::ffff:10.0.0.1", "fe80::1"] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+        assert!(is_public("140.82.112.3".parse().unwrap()));
+        assert!(is_public("2606:4700::1111".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_socket_replies() {
+        let dir = std::env::temp_dir().join(format!("agentman-notify-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = Arc::new(GatewayConfig::default());
+        let state = Arc::new(StateManager::empty(dir.join("state.json")));
+        ensure_listener(&config, &state, "octocat", "web", &dir);
+
+        let ask = |message: &'static str| {
+            let path = dir.join(SOCKET_NAME);
+            async move {
+                let mut stream = UnixStream::connect(path).await.unwrap();
+                stream.write_all(message.as_bytes()).await.unwrap();
+                stream.shutdown().await.unwrap();
+                let mut reply = String::new();
+                stream.read_to_string(&mut reply).await.unwrap();
+                reply
+            }
+        };
+        assert!(ask("done").await.starts_with("error: no notifier set"));
+        assert_eq!(ask("  \n").await, "error: empty message\n");
+
+        // A deleted socket is served again.
+        std::fs::remove_file(dir.join(SOCKET_NAME)).unwrap();
+        ensure_listener(&config, &state, "octocat", "web", &dir);
+        assert!(ask("done").await.starts_with("error: no notifier set"));
+
+        stop_listener("octocat", "web");
+        assert!(!dir.join(SOCKET_NAME).exists());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
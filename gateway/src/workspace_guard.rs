@@ -0,0 +1,182 @@
+//! Sanity checks before a workspace directory is deleted.
+//!
+//! `destroy_workspace` ends in `remove_dir_all`, and the path it is given comes from the config
+//! and the state file, both of which can be edited by hand, restored from an old snapshot or
+//! point through symlinks. Deleting the wrong tree can't be undone, so the directory is only
+//! removed when everything agrees: it is a real directory exactly `<user>/<project>` below
+//! `workspace_root` (after resolving symlinks in the root), the state records the same path for
+//! the workspace, and the resolved path isn't suspiciously close to `/`.
+
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+
+use crate::github::{validate_github_username, validate_project_name};
+
+/// Fewest components a resolved workspace directory has: `/`, at least one for the root, then
+/// the user and the project.
+const MIN_COMPONENTS: usize = 4;
+
+/// The resolved directory of `github_user/project` under `workspace_root`, if it is safe to
+/// delete; `recorded` is the path the state file has for the workspace.
+pub async fn check_deletable(
+    workspace_root: &Path,
+    github_user: &str,
+    project: &str,
+    recorded: Option<&Path>,
+) -> Result<PathBuf> {
+    validate_github_username(github_user)?;
+    validate_project_name(project)?;
+    let path = workspace_root.join(github_user).join(project);
+
+    let meta = tokio::fs::symlink_metadata(&path)
+        .await
+        .with_context(|| format!("Failed to stat {}", path.display()))?;
+    if meta.file_type().is_symlink() {
+        bail!(
+            "refusing to delete {}: it is a symlink, not a workspace directory",
+            path.display()
+        );
+    }
+    if !meta.is_dir() {
+        bail!("refusing to delete {}: not a directory", path.display());
+    }
+
+    let root = tokio::fs::canonicalize(workspace_root)
+        .await
+        .with_context(|| format!("Failed to resolve {}", workspace_root.display()))?;
+    let resolved = tokio::fs::canonicalize(&path)
+        .await
+        .with_context(|| format!("Failed to resolve {}", path.display()))?;
+    // A symlinked user directory could lead anywhere.
+    let expected = Path::new(github_user).join(project);
+    if resolved.strip_prefix(&root).ok() != Some(expected.as_path()) {
+        bail!(
+            "refusing to delete {}: it resolves to {}, outside the workspace root {}",
+            path.display(),
+            resolved.display(),
+            root.display()
+        );
+    }
+    if resolved
+        .components()
+        .filter(|c| matches!(c, Component::RootDir | Component::Normal(_)))
+        .count()
+        < MIN_COMPONENTS
+    {
+        bail!(
+            "refusing to delete {}: path is too short to be a workspace",
+            resolved.display()
+        );
+    }
+
+    let Some(recorded) = recorded else {
+        bail!(
+            "refusing to delete {}: the gateway has no record of workspace {github_user}/{project} \
+             (connect to it once to recreate the record, then destroy it)",
+            path.display()
+        );
+    };
+    let recorded_resolved = tokio::fs::canonicalize(recorded).await.ok();
+    if recorded_resolved.as_deref() != Some(resolved.as_path()) {
+        bail!(
+            "refusing to delete {}: the state records {} for {github_user}/{project}",
+            path.display(),
+            recorded.display()
+        );
+    }
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[tokio::test]
+    async fn test_check_deletable() {
+        let base =
+            std::env::temp_dir().join(format!("agentman-guard-test-{}", std::process::id()));
+        let root = base.join("workspaces");
+        let ws = root.join("octocat").join("web");
+        std::fs::create_dir_all(&ws).unwrap();
+        std::fs::create_dir_all(base.join("elsewhere").join("web")).unwrap();
+
+        let resolved = check_deletable(&root, "octocat", "web", Some(&ws))
+            .await
+            .unwrap();
+        assert_eq!(resolved, std::fs::canonicalize(&ws).unwrap());
+
+        // No record, or a record of another path.
+        assert!(check_deletable(&root, "octocat", "web", None).await.is_err());
+        let other = base.join("elsewhere").join("web");
+        assert!(
+            check_deletable(&root, "octocat", "web", Some(&other))
+                .await
+                .is_err()
+        );
+        // Names that would leave the user's directory.
+        assert!(
+            check_deletable(&root, "octocat", "..", Some(&ws))
+                .await
+                .is_err()
+        );
+        assert!(check_deletable(&root, "", "web", Some(&ws)).await.is_err());
+
+        // A workspace directory that is a symlink, and a user directory that is one.
+        symlink(base.join("elsewhere").join("web"), root.join("octocat").join("api")).unwrap();
+        let api = root.join("octocat").join("api");
+        assert!(
+            check_deletable(&root, "octocat", "api", Some(&api))
+                .await
+                .is_err()
+        );
+        symlink(base.join("elsewhere"), root.join("hubot")).unwrap();
+        let hubot = root.join("hubot").join("web");
+        assert!(
+            check_deletable(&root, "hubot", "web", Some(&hubot))
+                .await
+                .is_err()
+        );
+
+        let _ = std::fs::remove_dir_all(&base);
+    }
+
+    #[tokio::test]
+    async fn test_check_deletable_symlinked_root() {
+        let base = std::env::temp_dir().join(format!(
+            "agentman-guard-root-test-{}",
+            std::process::id()
+        ));
+        let real = base.join("data");
+        std::fs::create_dir_all(real.join("octocat").join("web")).unwrap();
+        let root = base.join("workspaces");
+        symlink(&real, &root).unwrap();
+
+        // Workspaces on a symlinked root are fine, whichever form the state recorded.
+        let via_link = root.join("octocat").join("web");
+        let resolved = check_deletable(&root, "octocat", "web", Some(&via_link))
+            .await
+            .unwrap();
+        assert_eq!(
+            resolved,
+            std::fs::canonicalize(real.join("octocat").join("web")).unwrap()
+        );
+        let direct = real.join("octocat").join("web");
+        assert!(
+            check_deletable(&root, "octocat", "web", Some(&direct))
+                .await
+                .is_ok()
+        );
+
+        // A root that resolves to `/` leaves paths too short to trust.
+        let slash = base.join("slash");
+        symlink("/", &slash).unwrap();
+        let err = check_deletable(&slash, "usr", "bin", Some(Path::new("/usr/bin")))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("too short"), "{err}");
+
+        let _ = std::fs::remove_dir_all(&base);
+    }
+}
@@ -0,0 +1,267 @@
+//! Cached workspace disk usage.
+//!
+//! `du` over a large workspace can take a long time, so the sizes shown by `agentman stats` and
+//! `agentman admin users` come from a cache. A background indexer re-measures each workspace once
+//! its size is `[workspace_size] interval_secs` old; a workspace that was never measured is
+//! measured on demand. Measurements of one workspace are serialized and skipped while the cached
+//! size is fresh, so `du` runs at most once per interval per workspace. With `source = "btrfs"`
+//! or `"zfs"`, sizes come from the filesystem's own accounting instead of a tree walk.
+
+use std::collections::{HashMap, HashSet};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::process::Command;
+use tracing::{debug, info};
+
+use crate::config::{WorkspaceSizeConfig, WorkspaceSizeSource};
+use crate::docker::ContainerManager;
+use crate::metrics;
+
+/// How often the indexer looks for sizes that are due.
+const INDEX_TICK: Duration = Duration::from_secs(60);
+
+/// Inode number of a btrfs subvolume's root directory.
+const BTRFS_SUBVOLUME_ROOT_INO: u64 = 256;
+
+#[derive(Debug, Clone, Copy)]
+struct Measurement {
+    bytes: Option<u64>,
+    at: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Slot {
+    last: Mutex<Option<Measurement>>,
+    /// Held while measuring, so concurrent callers wait for one measurement.
+    measuring: tokio::sync::Mutex<()>,
+}
+
+static CACHE: LazyLock<Mutex<HashMap<PathBuf, Arc<Slot>>>> = LazyLock::new(Default::default);
+
+fn slot(path: &Path) -> Arc<Slot> {
+    CACHE
+        .lock()
+        .unwrap()
+        .entry(path.to_path_buf())
+        .or_default()
+        .clone()
+}
+
+/// Disk usage of the workspace at `path`.
+///
+/// With the indexer running, any cached size is returned right away (the indexer keeps it
+/// fresh); otherwise a size older than `interval_secs` is measured again.
+pub async fn get(config: &WorkspaceSizeConfig, path: &Path) -> Option<u64> {
+    let slot = slot(path);
+    let max_age = Duration::from_secs(config.interval_secs);
+    let cached = *slot.last.lock().unwrap();
+    match cached {
+        Some(m) if config.index || m.at.elapsed() < max_age => m.bytes,
+        _ => refresh(config, path, &slot, max_age).await,
+    }
+}
+
+/// Measure `path` unless another caller did so within `max_age`.
+async fn refresh(
+    config: &WorkspaceSizeConfig,
+    path: &Path,
+    slot: &Slot,
+    max_age: Duration,
+) -> Option<u64> {
+    let _measuring = slot.measuring.lock().await;
+    if let Some(m) = *slot.last.lock().unwrap()
+        && m.at.elapsed() < max_age
+    {
+        return m.bytes;
+    }
+    let bytes = measure(config.source, path).await;
+    *slot.last.lock().unwrap() = Some(Measurement {
+        bytes,
+        at: Instant::now(),
+    });
+    bytes
+}
+
+/// Keep the sizes of all known workspaces fresh (no-op unless `[workspace_size] index`).
+pub fn spawn_indexer(container_manager: Arc<ContainerManager>) {
+    let config = container_manager.config().workspace_size.clone();
+    if !config.index {
+        return;
+    }
+    info!(
+        "Indexing workspace sizes every {}s ({})",
+        config.interval_secs,
+        source_name(config.source)
+    );
+
+    tokio::spawn(async move {
+        let max_age = Duration::from_secs(config.interval_secs);
+        let mut tick = tokio::time::interval(INDEX_TICK);
+        tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            tick.tick().await;
+            let state = container_manager.state();
+            let mut paths = HashSet::new();
+            for github_user in state.list_github_users().await {
+                for ws in state.list_workspaces(&github_user).await {
+                    paths.insert(ws.host_workspace_path);
+                }
+            }
+            // Forget destroyed and transferred workspaces.
+            CACHE.lock().unwrap().retain(|path, _| paths.contains(path));
+
+            // One at a time, to keep the I/O load of `du` down.
+            for path in paths {
+                refresh(&config, &path, &slot(&path), max_age).await;
+            }
+        }
+    });
+}
+
+fn source_name(source: WorkspaceSizeSource) -> &'static str {
+    match source {
+        WorkspaceSizeSource::Du => "du",
+        WorkspaceSizeSource::Btrfs => "btrfs",
+        WorkspaceSizeSource::Zfs => "zfs",
+    }
+}
+
+async fn measure(source: WorkspaceSizeSource, path: &Path) -> Option<u64> {
+    let quota = match source {
+        WorkspaceSizeSource::Du => None,
+        WorkspaceSizeSource::Btrfs => btrfs_bytes(path).await,
+        WorkspaceSizeSource::Zfs => zfs_bytes(path).await,
+    };
+    let (bytes, used) = match quota {
+        Some(bytes) => (Some(bytes), source),
+        None => {
+            if source != WorkspaceSizeSource::Du {
+                debug!(
+                    "No {} accounting for {}, falling back to du",
+                    source_name(source),
+                    path.display()
+                );
+            }
+            (du_bytes(path).await, WorkspaceSizeSource::Du)
+        }
+    };
+    metrics::inc(
+        "agentman_workspace_size_scans_total",
+        "Workspace size measurements, by source.",
+        &[("source", source_name(used))],
+    );
+    bytes
+}
+
+/// Apparent disk usage of a directory tree, via `du`.
+pub(crate) async fn du_bytes(path: &Path) -> Option<u64> {
+    let out = Command::new("du")
+        .arg("-s")
+        .arg("--block-size=1")
+        .arg(path)
+        .output()
+        .await
+        .ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    let first = stdout.split_whitespace().next()?;
+    first.parse::<u64>().ok()
+}
+
+/// Referenced bytes of the btrfs subvolume rooted at `path` (needs quotas enabled).
+async fn btrfs_bytes(path: &Path) -> Option<u64> {
+    // `qgroup show -f` reports the subvolume containing `path`, which is only the workspace's
+    // own when `path` is a subvolume root.
+    let meta = tokio::fs::metadata(path).await.ok()?;
+    if meta.ino() != BTRFS_SUBVOLUME_ROOT_INO {
+        return None;
+    }
+    let out = Command::new("btrfs")
+        .args(["qgroup", "show", "-f", "--raw"])
+        .arg(path)
+        .output()
+        .await
+        .ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    parse_btrfs_qgroup(&String::from_utf8_lossy(&out.stdout))
+}
+
+/// `rfer` of the level-0 qgroup in `btrfs qgroup show -f --raw` output.
+fn parse_btrfs_qgroup(output: &str) -> Option<u64> {
+    output.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let qgroup = fields.next()?;
+        if !qgroup.starts_with("0/") {
+            return None;
+        }
+        fields.next()?.parse().ok()
+    })
+}
+
+/// Space used by the ZFS dataset mounted at `path`.
+async fn zfs_bytes(path: &Path) -> Option<u64> {
+    let out = Command::new("zfs")
+        .args(["list", "-H", "-p", "-o", "used,mountpoint"])
+        .arg(path)
+        .output()
+        .await
+        .ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    parse_zfs_list(&String::from_utf8_lossy(&out.stdout), path)
+}
+
+/// `used` from `zfs list -Hp -o used,mountpoint`, if the dataset is mounted at `path` (for any
+/// other path, `zfs list` reports the enclosing dataset).
+fn parse_zfs_list(output: &str, path: &Path) -> Option<u64> {
+    let (used, mountpoint) = output.lines().next()?.split_once('\t')?;
+    if Path::new(mountpoint.trim()) != path {
+        return None;
+    }
+    used.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_quota_output() {
+        let btrfs = "qgroupid         rfer         excl \n\
+                     --------         ----         ---- \n\
+                     0/257       1073741824     16384 \n";
+        assert_eq!(parse_btrfs_qgroup(btrfs), Some(1073741824));
+        assert_eq!(parse_btrfs_qgroup("qgroupid rfer excl\n"), None);
+
+        let path = Path::new("/srv/workspaces/octocat/api");
+        assert_eq!(
+            parse_zfs_list("52428800\t/srv/workspaces/octocat/api\n", path),
+            Some(52428800)
+        );
+        assert_eq!(parse_zfs_list("52428800\t/srv/workspaces\n", path), None);
+    }
+
+    #[tokio::test]
+    async fn test_cached_between_measurements() {
+        let dir = std::env::temp_dir().join(format!("agentman-size-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = WorkspaceSizeConfig {
+            index: false,
+            ..WorkspaceSizeConfig::default()
+        };
+
+        let before = get(&config, &dir).await.unwrap();
+        std::fs::write(dir.join("blob"), vec![0u8; 1 << 20]).unwrap();
+        assert_eq!(get(&config, &dir).await, Some(before));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
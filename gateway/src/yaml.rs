@@ -0,0 +1,566 @@
+//! A small YAML reader for the subset compose files use.
+//!
+//! Supports block mappings and sequences (including `- key: value` items), single-line flow
+//! collections (`[a, b]`, `{a: b}`), plain, single- and double-quoted scalars, `|`/`>` block
+//! scalars and comments. Anchors, aliases, tags and multiple documents are rejected rather than
+//! misread. Scalars are kept as strings; callers decide how to interpret them.
+
+use anyhow::{Result, anyhow, bail};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Node {
+    Null,
+    Scalar(String),
+    Seq(Vec<Node>),
+    Map(Vec<(String, Node)>),
+}
+
+struct Line {
+    number: usize,
+    indent: usize,
+    /// The line after its indentation, comments included (block scalars keep them).
+    text: String,
+}
+
+/// Parse a single YAML document.
+pub(crate) fn parse(src: &str) -> Result<Node> {
+    let mut lines = Vec::new();
+    for (i, raw) in src.lines().enumerate() {
+        let text = raw.trim_start_matches(' ');
+        if text.starts_with('\t') && !text.trim().is_empty() {
+            bail!("line {}: tabs can't be used for indentation", i + 1);
+        }
+        lines.push(Line {
+            number: i + 1,
+            indent: raw.len() - text.len(),
+            text: text.trim_end().to_string(),
+        });
+    }
+    let mut parser = Parser { lines, pos: 0 };
+    if parser
+        .peek()
+        .is_some_and(|(_, _, content)| content == "---")
+    {
+        parser.pos += 1;
+    }
+    let node = match parser.peek() {
+        Some((_, indent, _)) => parser.block(indent)?,
+        None => Node::Null,
+    };
+    if let Some((idx, _, _)) = parser.peek() {
+        bail!(
+            "line {}: unexpected content (bad indentation or a second document)",
+            parser.lines[idx].number
+        );
+    }
+    Ok(node)
+}
+
+struct Parser {
+    lines: Vec<Line>,
+    pos: usize,
+}
+
+impl Parser {
+    /// The next line with content: `(index, indent, content without comment)`. Skips blank and
+    /// comment-only lines.
+    fn peek(&mut self) -> Option<(usize, usize, String)> {
+        while self.pos < self.lines.len() {
+            let line = &self.lines[self.pos];
+            let content = strip_comment(&line.text);
+            if !content.is_empty() {
+                return Some((self.pos, line.indent, content.to_string()));
+            }
+            self.pos += 1;
+        }
+        None
+    }
+
+    fn block(&mut self, indent: usize) -> Result<Node> {
+        match self.peek() {
+            Some((_, _, content)) if is_seq_item(&content) => self.seq(indent),
+            _ => self.map(indent),
+        }
+    }
+
+    fn map(&mut self, indent: usize) -> Result<Node> {
+        let mut entries: Vec<(String, Node)> = Vec::new();
+        while let Some((idx, line_indent, content)) = self.peek() {
+            let number = self.lines[idx].number;
+            if line_indent < indent {
+                break;
+            }
+            if line_indent > indent {
+                bail!("line {number}: unexpected indentation");
+            }
+            if is_seq_item(&content) {
+                bail!("line {number}: expected `key: value`, found a list item");
+            }
+            let (key, rest) = split_key(&content, number)?
+                .ok_or_else(|| anyhow!("line {number}: expected `key: value`"))?;
+            if entries.iter().any(|(k, _)| *k == key) {
+                bail!("line {number}: duplicate key `{key}`");
+            }
+            self.pos = idx + 1;
+            let value = self.value_after_key(indent, &rest, number)?;
+            entries.push((key, value));
+        }
+        Ok(Node::Map(entries))
+    }
+
+    fn value_after_key(&mut self, indent: usize, rest: &str, number: usize) -> Result<Node> {
+        if rest.is_empty() {
+            return match self.peek() {
+                Some((_, next_indent, _)) if next_indent > indent => self.block(next_indent),
+                // Compose files often don't indent a list under its key.
+                Some((_, next_indent, content))
+                    if next_indent == indent && is_seq_item(&content) =>
+                {
+                    self.seq(indent)
+                }
+                _ => Ok(Node::Null),
+            };
+        }
+        if let Some(folded) = block_scalar_style(rest) {
+            return Ok(Node::Scalar(self.block_scalar(
+                indent,
+                folded,
+                rest.ends_with('-'),
+            )));
+        }
+        let node = inline(rest, number)?;
+        self.expect_no_nested(indent)?;
+        Ok(node)
+    }
+
+    fn seq(&mut self, indent: usize) -> Result<Node> {
+        let mut items = Vec::new();
+        while let Some((idx, line_indent, content)) = self.peek() {
+            let number = self.lines[idx].number;
+            if line_indent < indent || (line_indent == indent && !is_seq_item(&content)) {
+                break;
+            }
+            if line_indent > indent {
+                bail!("line {number}: unexpected indentation");
+            }
+            let rest = content[1..].trim_start();
+            let column = indent + content.len() - rest.len();
+            if rest.is_empty() {
+                self.pos = idx + 1;
+                items.push(match self.peek() {
+                    Some((_, next_indent, _)) if next_indent > indent => self.block(next_indent)?,
+                    _ => Node::Null,
+                });
+            } else if is_seq_item(rest) || split_key(rest, number)?.is_some() {
+                // `- key: value` (or `- - item`): the item is a block starting at `column`.
+                self.lines[idx].indent = column;
+                self.lines[idx].text = rest.to_string();
+                items.push(self.block(column)?);
+            } else {
+                self.pos = idx + 1;
+                items.push(inline(rest, number)?);
+                self.expect_no_nested(indent)?;
+            }
+        }
+        Ok(Node::Seq(items))
+    }
+
+    fn expect_no_nested(&mut self, indent: usize) -> Result<()> {
+        match self.peek() {
+            Some((idx, next_indent, _)) if next_indent > indent => bail!(
+                "line {}: unexpected indentation (multi-line plain strings aren't supported; use `|` or quotes)",
+                self.lines[idx].number
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Read a `|` (literal) or `>` (folded) block scalar following a key at `indent`.
+    fn block_scalar(&mut self, indent: usize, folded: bool, strip: bool) -> String {
+        let mut lines: Vec<String> = Vec::new();
+        let mut block_indent = None;
+        while self.pos < self.lines.len() {
+            let line = &self.lines[self.pos];
+            if line.text.is_empty() {
+                lines.push(String::new());
+                self.pos += 1;
+                continue;
+            }
+            let base = *block_indent.get_or_insert(line.indent);
+            if line.indent <= indent || line.indent < base {
+                break;
+            }
+            lines.push(format!("{}{}", " ".repeat(line.indent - base), line.text));
+            self.pos += 1;
+        }
+        while lines.last().is_some_and(|l| l.is_empty()) {
+            lines.pop();
+        }
+        let mut out = if folded {
+            let mut out = String::new();
+            for (i, line) in lines.iter().enumerate() {
+                if line.is_empty() {
+                    out.push('\n');
+                } else {
+                    if i > 0 && !lines[i - 1].is_empty() {
+                        out.push(' ');
+                    }
+                    out.push_str(line);
+                }
+            }
+            out
+        } else {
+            lines.join("\n")
+        };
+        if !strip && !out.is_empty() {
+            out.push('\n');
+        }
+        out
+    }
+}
+
+fn is_seq_item(content: &str) -> bool {
+    content == "-" || content.starts_with("- ")
+}
+
+/// `Some(folded)` if `rest` is a block scalar indicator (`|`, `|-`, `>`, ...).
+fn block_scalar_style(rest: &str) -> Option<bool> {
+    match rest {
+        "|" | "|-" | "|+" => Some(false),
+        ">" | ">-" | ">+" => Some(true),
+        _ => None,
+    }
+}
+
+/// Cut a trailing comment (`#` at the start or after whitespace, outside quotes).
+fn strip_comment(text: &str) -> &str {
+    let mut quote = None;
+    let mut prev = ' ';
+    for (i, c) in text.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            // Quotes only open a string at the start of a token (`it's` is plain text).
+            None if (c == '"' || c == '\'')
+                && matches!(prev, ' ' | '[' | '{' | ',' | ':' | '-') =>
+            {
+                quote = Some(c)
+            }
+            None if c == '#' && prev == ' ' => return text[..i].trim_end(),
+            None => {}
+        }
+        prev = c;
+    }
+    text.trim_end()
+}
+
+/// Split `key: value` (the value may be empty). `None` if `content` is not a mapping entry.
+fn split_key(content: &str, number: usize) -> Result<Option<(String, String)>> {
+    if content.starts_with(['[', '{']) {
+        return Ok(None);
+    }
+    let (key, after) = if content.starts_with(['"', '\'']) {
+        let mut cursor = Cursor::new(content, number);
+        let key = cursor.quoted()?;
+        (key, &content[cursor.pos..])
+    } else {
+        let Some(colon) = content
+            .char_indices()
+            .find(|&(i, c)| c == ':' && content[i + 1..].chars().next().is_none_or(|n| n == ' '))
+            .map(|(i, _)| i)
+        else {
+            return Ok(None);
+        };
+        (content[..colon].trim_end().to_string(), &content[colon..])
+    };
+    let after = after.trim_start();
+    match after.strip_prefix(':') {
+        Some(rest) if rest.is_empty() || rest.starts_with(' ') => {
+            if key.is_empty() {
+                bail!("line {number}: empty key");
+            }
+            Ok(Some((key, rest.trim().to_string())))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Parse a value written on one line: a scalar or a flow collection.
+fn inline(text: &str, number: usize) -> Result<Node> {
+    let mut cursor = Cursor::new(text, number);
+    let node = cursor.value(false)?;
+    cursor.skip_ws();
+    if cursor.pos < text.len() {
+        bail!("line {number}: unexpected characters after value");
+    }
+    Ok(node)
+}
+
+struct Cursor<'a> {
+    s: &'a str,
+    pos: usize,
+    number: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(s: &'a str, number: usize) -> Self {
+        Self { s, pos: 0, number }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.s[self.pos..].chars().next()
+    }
+
+    fn skip_ws(&mut self) {
+        while self.peek() == Some(' ') {
+            self.pos += 1;
+        }
+    }
+
+    fn value(&mut self, in_flow: bool) -> Result<Node> {
+        self.skip_ws();
+        match self.peek() {
+            None => Ok(Node::Null),
+            Some('[') => self.flow_seq(),
+            Some('{') => self.flow_map(),
+            Some('"' | '\'') => Ok(Node::Scalar(self.quoted()?)),
+            Some('&' | '*' | '!') => bail!(
+                "line {}: anchors, aliases and tags are not supported",
+                self.number
+            ),
+            Some(_) => {
+                let plain = self.plain(if in_flow { ",]}" } else { "" });
+                Ok(match plain.as_str() {
+                    "~" | "null" | "Null" | "NULL" => Node::Null,
+                    _ => Node::Scalar(plain),
+                })
+            }
+        }
+    }
+
+    /// A plain scalar, up to the end or one of `stop`.
+    fn plain(&mut self, stop: &str) -> String {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if stop.contains(c) {
+                break;
+            }
+            self.pos += c.len_utf8();
+        }
+        self.s[start..self.pos].trim().to_string()
+    }
+
+    fn flow_seq(&mut self) -> Result<Node> {
+        self.pos += 1;
+        let mut items = Vec::new();
+        loop {
+            self.skip_ws();
+            match self.peek() {
+                Some(']') => {
+                    self.pos += 1;
+                    return Ok(Node::Seq(items));
+                }
+                None => bail!("line {}: unterminated `[`", self.number),
+                _ => {}
+            }
+            items.push(self.value(true)?);
+            self.skip_ws();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some(']') => {}
+                _ => bail!("line {}: expected `,` or `]`", self.number),
+            }
+        }
+    }
+
+    fn flow_map(&mut self) -> Result<Node> {
+        self.pos += 1;
+        let mut entries = Vec::new();
+        loop {
+            self.skip_ws();
+            let key = match self.peek() {
+                Some('}') => {
+                    self.pos += 1;
+                    return Ok(Node::Map(entries));
+                }
+                None => bail!("line {}: unterminated `{{`", self.number),
+                Some('"' | '\'') => self.quoted()?,
+                Some(_) => self.plain(":,}"),
+            };
+            self.skip_ws();
+            if self.peek() != Some(':') {
+                bail!("line {}: expected `:` after `{key}`", self.number);
+            }
+            self.pos += 1;
+            entries.push((key, self.value(true)?));
+            self.skip_ws();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some('}') => {}
+                _ => bail!("line {}: expected `,` or `}}`", self.number),
+            }
+        }
+    }
+
+    fn quoted(&mut self) -> Result<String> {
+        let quote = self.peek().unwrap_or('"');
+        self.pos += 1;
+        let mut out = String::new();
+        let mut chars = self.s[self.pos..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                c if c == quote => {
+                    // `''` is an escaped quote inside single quotes.
+                    if quote == '\'' && self.s[self.pos + i + 1..].starts_with('\'') {
+                        chars.next();
+                        out.push('\'');
+                        continue;
+                    }
+                    self.pos += i + 1;
+                    return Ok(out);
+                }
+                '\\' if quote == '"' => {
+                    let escaped = match chars.next().map(|(_, e)| e) {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('0') => '\0',
+                        Some(e @ ('"' | '\\' | '/')) => e,
+                        Some('u') => {
+                            let hex: String = (0..4)
+                                .filter_map(|_| chars.next().map(|(_, h)| h))
+                                .collect();
+                            u32::from_str_radix(&hex, 16)
+                                .ok()
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| anyhow!("line {}: bad \\u escape", self.number))?
+                        }
+                        _ => bail!("line {}: unsupported escape in string", self.number),
+                    };
+                    out.push(escaped);
+                }
+                c => out.push(c),
+            }
+        }
+        bail!("line {}: unterminated string", self.number)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s(v: &str) -> Node {
+        Node::Scalar(v.to_string())
+    }
+
+    fn map(entries: Vec<(&str, Node)>) -> Node {
+        Node::Map(
+            entries
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_parse_compose_shapes() {
+        let doc = parse(
+            r##"---
+# a stack
+services:
+  db:
+    image: "postgres:16"   # pinned
+    environment:
+      POSTGRES_PASSWORD: dev
+      EMPTY:
+    healthcheck:
+      test: ["CMD-SHELL", "pg_isready -U postgres"]
+  worker:
+    command: >
+      run --fast
+      --verbose
+    volumes:
+    - data:/var/lib/data
+    - 'it''s:/x'
+    depends_on:
+      - db
+    labels: {a: 1, b: "two"}
+    script: |
+      echo "# not a comment"
+        indented
+"##,
+        )
+        .unwrap();
+        let expected = map(vec![(
+            "services",
+            map(vec![
+                (
+                    "db",
+                    map(vec![
+                        ("image", s("postgres:16")),
+                        (
+                            "environment",
+                            map(vec![("POSTGRES_PASSWORD", s("dev")), ("EMPTY", Node::Null)]),
+                        ),
+                        (
+                            "healthcheck",
+                            map(vec![(
+                                "test",
+                                Node::Seq(vec![s("CMD-SHELL"), s("pg_isready -U postgres")]),
+                            )]),
+                        ),
+                    ]),
+                ),
+                (
+                    "worker",
+                    map(vec![
+                        ("command", s("run --fast --verbose\n")),
+                        (
+                            "volumes",
+                            Node::Seq(vec![s("data:/var/lib/data"), s("it's:/x")]),
+                        ),
+                        ("depends_on", Node::Seq(vec![s("db")])),
+                        ("labels", map(vec![("a", s("1")), ("b", s("two"))])),
+                        ("script", s("echo \"# not a comment\"\n  indented\n")),
+                    ]),
+                ),
+            ]),
+        )]);
+        assert_eq!(doc, expected);
+    }
+
+    #[test]
+    fn test_parse_sequence_of_maps() {
+        let doc =
+            parse("items:\n  - name: a\n    value: 1\n  - - nested\n  - http://x:80/y\n").unwrap();
+        assert_eq!(
+            doc,
+            map(vec![(
+                "items",
+                Node::Seq(vec![
+                    map(vec![("name", s("a")), ("value", s("1"))]),
+                    Node::Seq(vec![s("nested")]),
+                    s("http://x:80/y"),
+                ]),
+            )])
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_unsupported() {
+        for bad in [
+            "a: &anchor 1\n",
+            "a: *alias\n",
+            "a: !!str 1\n",
+            "a: 1\na: 2\n",
+            "a:\n\t- b\n",
+            "a: 1\n  b: 2\n",
+            "a: \"open\n",
+            "a: 1\n---\nb: 2\n",
+            "a: [1, 2\n",
+        ] {
+            assert!(parse(bad).is_err(), "accepted {bad:?}");
+        }
+    }
+}